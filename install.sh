@@ -20,6 +20,16 @@ SERVICE_FILE="${SCRIPT_DIR}/wimesh.service"
 SYSTEMD_DIR="/etc/systemd/system"
 SERVICE_NAME="wimesh.service"
 
+# --hardened: install the binary system-wide and generate a locked-down unit
+# (dynamic user, read-only system, /etc/wimesh config) with `wimesh service`
+HARDENED=false
+for arg in "$@"; do
+    case "$arg" in
+        --hardened) HARDENED=true ;;
+        *) error "Unknown option: $arg"; exit 1 ;;
+    esac
+done
+
 info "==================================="
 info "Wimesh Service Installer"
 info "==================================="
@@ -45,24 +55,52 @@ if [ ! -f "$SERVICE_TEMPLATE" ]; then
     exit 1
 fi
 
-# Get current user info
-CURRENT_USER="${SUDO_USER:-$USER}"
-CURRENT_GROUP=$(id -gn "$CURRENT_USER")
+if [ "$HARDENED" = true ]; then
+    INSTALLED_BINARY="/usr/local/bin/wimesh"
+    CONFIG_DIR="/etc/wimesh"
 
-info "Configuration:"
-echo "   User:        $CURRENT_USER"
-echo "   Group:       $CURRENT_GROUP"
-echo "   Working Dir: $SCRIPT_DIR"
-echo "   Binary:      $WIMESH_BINARY"
-echo ""
+    info "Configuration (hardened):"
+    echo "   User:        dynamic"
+    echo "   Config:      $CONFIG_DIR/config.toml"
+    echo "   Binary:      $INSTALLED_BINARY"
+    echo ""
+
+    info "Installing binary..."
+    install -m 755 "$WIMESH_BINARY" "$INSTALLED_BINARY"
+
+    mkdir -p "$CONFIG_DIR"
+    if [ ! -f "$CONFIG_DIR/config.toml" ]; then
+        if [ -f "${SCRIPT_DIR}/config.toml" ]; then
+            install -m 644 "${SCRIPT_DIR}/config.toml" "$CONFIG_DIR/config.toml"
+        else
+            warn "No config.toml found, installing the example to $CONFIG_DIR"
+            install -m 644 "${SCRIPT_DIR}/config.example.toml" "$CONFIG_DIR/config.toml"
+        fi
+    fi
 
-# Generate service file from template
-info "Generating service file..."
-sed -e "s|WIMESH_BINARY_PATH|${WIMESH_BINARY}|g" \
-    -e "s|WIMESH_USER|${CURRENT_USER}|g" \
-    -e "s|WIMESH_GROUP|${CURRENT_GROUP}|g" \
-    -e "s|WIMESH_WORKDIR|${SCRIPT_DIR}|g" \
-    "$SERVICE_TEMPLATE" > "$SERVICE_FILE"
+    # Generated from the installed config, so capabilities match what is enabled
+    info "Generating hardened service file..."
+    (cd "$CONFIG_DIR" && "$INSTALLED_BINARY" service systemd) > "$SERVICE_FILE"
+else
+    # Get current user info
+    CURRENT_USER="${SUDO_USER:-$USER}"
+    CURRENT_GROUP=$(id -gn "$CURRENT_USER")
+
+    info "Configuration:"
+    echo "   User:        $CURRENT_USER"
+    echo "   Group:       $CURRENT_GROUP"
+    echo "   Working Dir: $SCRIPT_DIR"
+    echo "   Binary:      $WIMESH_BINARY"
+    echo ""
+
+    # Generate service file from template
+    info "Generating service file..."
+    sed -e "s|WIMESH_BINARY_PATH|${WIMESH_BINARY}|g" \
+        -e "s|WIMESH_USER|${CURRENT_USER}|g" \
+        -e "s|WIMESH_GROUP|${CURRENT_GROUP}|g" \
+        -e "s|WIMESH_WORKDIR|${SCRIPT_DIR}|g" \
+        "$SERVICE_TEMPLATE" > "$SERVICE_FILE"
+fi
 
 # Copy service file to systemd
 info "Installing service file..."
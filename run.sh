@@ -13,4 +13,4 @@ if [ ! -f "$WIMESH_BIN" ]; then
 fi
 
 # Run in daemon mode
-cd "$SCRIPT_DIR" && exec "$WIMESH_BIN" --daemon
\ No newline at end of file
+cd "$SCRIPT_DIR" && exec "$WIMESH_BIN" daemon
\ No newline at end of file
@@ -0,0 +1,171 @@
+//! Embedding API for frontends (feature `api`)
+//!
+//! The surface a separate GUI crate builds on, kept stable across releases
+//! where the rest of the library is free to change: the re-exports below,
+//! the `Handle` to run commands with, and the event stream it hands out.
+//! Nothing on these paths writes to stdout or stderr (the library denies
+//! `print!` and friends); diagnostics go through `tracing`, so the
+//! frontend decides where they end up.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! let handle = wimesh::api::Handle::new(wimesh::api::Config::load()?)?;
+//! let mut events = handle.subscribe();
+//! tokio::spawn(async move {
+//!     while let Ok(record) = events.recv().await {
+//!         println!("{}", record.event.describe());
+//!     }
+//! });
+//! handle.login_connected().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::config::{Config, PortalConfig};
+pub use crate::events::{Event, EventRecord};
+pub use crate::portal::{CaptivePortal, NoPortalForSsid, PortalCapabilities};
+pub use crate::report::error_kind;
+pub use crate::state::{Action, NextAction, State};
+pub use crate::status::{NetworkState, NetworkStatus};
+pub use tokio::sync::broadcast;
+
+use crate::events::EventLog;
+use crate::identity::IdentityManager;
+use crate::lock::LoginLocks;
+use crate::portal::PortalRegistry;
+use crate::state;
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Runs wimesh commands on behalf of a frontend
+///
+/// Cheap to clone, `Send + Sync`; clones share the portals, the login
+/// locks and the event stream, so logins started from several places
+/// never overlap on one interface.
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: Config,
+    registry: Mutex<PortalRegistry>,
+    locks: LoginLocks,
+    events: EventLog,
+}
+
+impl Handle {
+    /// Set up the portals of `config`, with the event log it configures
+    pub fn new(config: Config) -> Result<Self> {
+        let registry = PortalRegistry::from_config(&config, &IdentityManager::load())?;
+        let events = EventLog::new(&config.events);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                config,
+                registry: Mutex::new(registry),
+                locks: LoginLocks::new(),
+                events,
+            }),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    /// Events of every command run through this handle (and its clones)
+    /// from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.inner.events.subscribe()
+    }
+
+    /// Configured SSIDs, in config order
+    pub async fn ssids(&self) -> Vec<String> {
+        let registry = self.inner.registry.lock().await;
+        registry.all_ssids().iter().map(|s| s.to_string()).collect()
+    }
+
+    /// WiFi association and internet access right now
+    pub async fn status(&self) -> Result<NetworkStatus> {
+        Ok(NetworkStatus::probe(&self.inner.config).await)
+    }
+
+    /// What the state file remembers: last logins, backoff, next action
+    pub fn state(&self) -> State {
+        State::load()
+    }
+
+    /// Log in on `ssid`, out of `interface` if given
+    pub async fn login(&self, ssid: &str, interface: Option<&str>) -> Result<()> {
+        let inner = &self.inner;
+        let mut registry = inner.registry.lock().await;
+        let portal = registry
+            .find_for_ssid(ssid)
+            .ok_or_else(|| NoPortalForSsid(ssid.to_string()))?;
+        crate::login::locked_connect(
+            &inner.config,
+            &inner.locks,
+            &inner.events,
+            ssid,
+            interface,
+            portal,
+        )
+        .await
+    }
+
+    /// Log in on the first configured SSID a WiFi adapter is associated to
+    pub async fn login_connected(&self) -> Result<()> {
+        let (interface, ssid) = self.connected().await?;
+        let interface = Some(interface.as_str()).filter(|i| !i.is_empty());
+        self.login(&ssid, interface).await
+    }
+
+    /// End the portal session on `ssid`
+    pub async fn logout(&self, ssid: &str) -> Result<()> {
+        let mut registry = self.inner.registry.lock().await;
+        let portal = registry
+            .find_for_ssid(ssid)
+            .ok_or_else(|| NoPortalForSsid(ssid.to_string()))?;
+        if !portal.capabilities().supports_logout {
+            anyhow::bail!("Portal '{}' does not support logout", portal.name());
+        }
+        portal.logout().await?;
+        state::nonblocking::forget_session(ssid).await;
+        Ok(())
+    }
+
+    /// Clear the login failure backoff of `ssid`, or of every SSID;
+    /// returns how many schedules were cleared
+    pub fn reset_backoff(&self, ssid: Option<&str>) -> Result<usize> {
+        State::reset_backoff(ssid)
+    }
+
+    /// Stop (`Some(true)`) or resume the running daemon's logins, or leave
+    /// it to the config (`None`)
+    pub fn set_read_only(&self, read_only: Option<bool>) -> Result<()> {
+        State::set_read_only(read_only)
+    }
+
+    /// `(interface, ssid)` of the first adapter on a configured SSID
+    async fn connected(&self) -> Result<(String, String)> {
+        let ssids = self.ssids().await;
+        let active = crate::utils::nonblocking::active_wifi().await?;
+        active
+            .into_iter()
+            .find(|(_, ssid)| ssids.contains(ssid))
+            .context("Not connected to any configured WiFi network")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + Clone + 'static>() {}
+        assert_send_sync::<Handle>();
+        assert_send_sync::<EventRecord>();
+    }
+}
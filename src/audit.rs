@@ -0,0 +1,289 @@
+//! Tamper-evident audit log of the logins performed
+//!
+//! On a machine several people share (the dorm Pi), "what did wimesh log
+//! in, as whom, when?" has to have an answer when the venue complains about
+//! abuse. With `[audit] enabled` every login attempt is appended to
+//! `audit.jsonl` in the state directory: SSID, portal, the MAC the venue
+//! saw, who ran it, and whether it worked. Each line carries the SHA-256 of
+//! the previous line's hash and its own content, so editing, removing or
+//! reordering entries breaks the chain, which `wimesh audit verify` checks.
+//! The file is never rotated or rewritten.
+
+use crate::config::AuditConfig;
+use crate::state::{state_dirs, unix_now};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the audit file in the state directory
+pub const AUDIT_FILE: &str = "audit.jsonl";
+
+/// `prev` of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One login attempt, as written
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Position in the file, from 1
+    pub seq: u64,
+    pub ts: u64,
+    pub ssid: String,
+    pub portal: String,
+    /// MAC address the portal authenticated, when it reports one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Account that ran wimesh (`$SUDO_USER`, else `$USER`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+    /// A cached session was resumed rather than a full login run
+    pub resumed: bool,
+    pub ok: bool,
+    /// `hash` of the previous entry
+    pub prev: String,
+}
+
+/// A login attempt to record; `append` numbers and chains it
+#[derive(Debug, Clone, Default)]
+pub struct Attempt<'a> {
+    pub ssid: &'a str,
+    pub portal: &'a str,
+    pub mac: Option<String>,
+    pub interface: Option<&'a str>,
+    pub resumed: bool,
+    pub ok: bool,
+}
+
+/// Appends to the audit file, if enabled
+pub struct AuditLog {
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    pub fn new(cfg: &AuditConfig) -> Self {
+        let path = if !cfg.enabled {
+            None
+        } else if cfg.path.is_empty() {
+            state_dirs()
+                .into_iter()
+                .next()
+                .map(|dir| dir.join(AUDIT_FILE))
+        } else {
+            Some(PathBuf::from(&cfg.path))
+        };
+        Self { path }
+    }
+
+    /// Where the audit file is, when enabled
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Append `attempt`; failures are logged, never returned, so a full
+    /// disk cannot stop a login
+    pub fn record(&self, attempt: Attempt) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        if let Err(e) = append(path, attempt, unix_now()) {
+            tracing::warn!("Failed to write audit entry to {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+/// Append `attempt` at `ts` to the audit file at `path`
+pub fn append(path: &Path, attempt: Attempt, ts: u64) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let (seq, prev) = match last_line(path)? {
+        Some(line) => {
+            let last: serde_json::Value = serde_json::from_str(&line)
+                .with_context(|| format!("Last line of {} does not parse", path.display()))?;
+            let seq = last["seq"]
+                .as_u64()
+                .context("Last audit entry has no seq")?;
+            let hash = last["hash"]
+                .as_str()
+                .context("Last audit entry has no hash")?;
+            (seq + 1, hash.to_string())
+        }
+        None => (1, GENESIS.to_string()),
+    };
+
+    let entry = Entry {
+        seq,
+        ts,
+        ssid: attempt.ssid.to_string(),
+        portal: attempt.portal.to_string(),
+        mac: attempt.mac,
+        interface: attempt.interface.map(str::to_string),
+        by: std::env::var("SUDO_USER")
+            .or_else(|_| std::env::var("USER"))
+            .ok(),
+        resumed: attempt.resumed,
+        ok: attempt.ok,
+        prev,
+    };
+    let mut value = serde_json::to_value(&entry)?;
+    let hash = hash(&value)?;
+    value["hash"] = serde_json::Value::String(hash);
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    let mut line = serde_json::to_string(&value)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// SHA-256 of an entry (without its `hash`), hex
+///
+/// `prev` is part of the entry, which is what chains them.
+fn hash(entry: &serde_json::Value) -> Result<String> {
+    let digest = Sha256::digest(serde_json::to_string(entry)?.as_bytes());
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn last_line(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content
+            .lines()
+            .rev()
+            .find(|l| !l.trim().is_empty())
+            .map(str::to_string)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Result of checking an audit file
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    /// Entries read before the first problem, or all of them
+    pub entries: u64,
+    /// 1-based line of the first broken entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broken_at: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_ts: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_ts: Option<u64>,
+}
+
+impl Verification {
+    pub fn ok(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// Walk the hash chain of the audit file `content`
+pub fn verify(content: &str) -> Verification {
+    let mut result = Verification {
+        entries: 0,
+        broken_at: None,
+        problem: None,
+        first_ts: None,
+        last_ts: None,
+    };
+    let mut prev = GENESIS.to_string();
+    for (n, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Err(problem) = check_line(line, &prev, result.entries + 1) {
+            result.broken_at = Some(n + 1);
+            result.problem = Some(problem);
+            return result;
+        }
+        let value: serde_json::Value = serde_json::from_str(line).unwrap_or_default();
+        prev = value["hash"].as_str().unwrap_or_default().to_string();
+        let ts = value["ts"].as_u64();
+        result.entries += 1;
+        result.first_ts = result.first_ts.or(ts);
+        result.last_ts = ts;
+    }
+    result
+}
+
+/// Why `line` is not the entry number `seq` following the hash `prev`
+fn check_line(line: &str, prev: &str, seq: u64) -> std::result::Result<(), String> {
+    let mut value: serde_json::Value =
+        serde_json::from_str(line).map_err(|e| format!("does not parse: {}", e))?;
+    let Some(serde_json::Value::String(claimed)) =
+        value.as_object_mut().and_then(|o| o.remove("hash"))
+    else {
+        return Err("has no hash".to_string());
+    };
+    let actual = hash(&value).map_err(|e| e.to_string())?;
+    if claimed != actual {
+        return Err("does not match its hash: it was edited".to_string());
+    }
+    if value["prev"].as_str() != Some(prev) {
+        return Err(
+            "does not follow the entry before it: entries were removed or reordered".to_string(),
+        );
+    }
+    if value["seq"].as_u64() != Some(seq) {
+        return Err(format!(
+            "is numbered {} where {} was expected: entries were removed",
+            value["seq"], seq
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        let dir = std::env::temp_dir().join(format!("wimesh-audit-{}", std::process::id()));
+        let path = dir.join(AUDIT_FILE);
+        let _ = std::fs::remove_dir_all(&dir);
+        for (n, ok) in [true, false, true].into_iter().enumerate() {
+            let attempt = Attempt {
+                ssid: "1.Free Wi-MESH",
+                portal: "KTX Khu B",
+                mac: Some("02:00:00:AA:BB:01".to_string()),
+                ok,
+                ..Attempt::default()
+            };
+            append(&path, attempt, 1_760_000_000 + n as u64).unwrap();
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let verification = verify(&content);
+        assert!(verification.ok(), "{:?}", verification.problem);
+        assert_eq!(verification.entries, 3);
+        assert_eq!(verification.last_ts, Some(1_760_000_002));
+
+        let edited = content.replacen("\"ok\":false", "\"ok\":true", 1);
+        let verification = verify(&edited);
+        assert_eq!(verification.broken_at, Some(2));
+        assert!(verification.problem.unwrap().contains("edited"));
+
+        let lines: Vec<&str> = content.lines().collect();
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        assert_eq!(verify(&removed).broken_at, Some(2));
+        // Dropping the newest entries cannot be told from the file alone
+        assert!(verify(lines[0]).ok());
+    }
+}
@@ -0,0 +1,70 @@
+//! Running without a console window (`--background`)
+//!
+//! Started at login from a shortcut or the Startup folder, `wimesh.exe
+//! --background daemon` lets go of its console at once, and what it logs
+//! goes to `[logging] log_file` alone, `wimesh.log` in the state directory
+//! when that is not set, where `wimesh logs` finds it. Windows still shows
+//! the console a moment before the program gets to let go of it; the build
+//! with the `windowless` feature is a GUI-subsystem program that never has
+//! one and always runs this way. Elsewhere `--background` only moves the
+//! log: detaching from a terminal is the service manager's job there.
+
+use std::path::{Path, PathBuf};
+
+/// Name of the log in the state directory
+pub const LOG_FILE: &str = "wimesh.log";
+
+/// Whether this build never has a console
+pub const WINDOWLESS: bool = cfg!(all(windows, feature = "windowless"));
+
+/// Where a background run logs: `[logging] log_file`, else `wimesh.log` in
+/// the primary state directory
+pub fn log_file(configured: &str) -> Option<PathBuf> {
+    resolve(configured, crate::state::state_dirs().first().map(PathBuf::as_path))
+}
+
+fn resolve(configured: &str, state_dir: Option<&Path>) -> Option<PathBuf> {
+    if !configured.is_empty() {
+        return Some(PathBuf::from(configured));
+    }
+    state_dir.map(|dir| dir.join(LOG_FILE))
+}
+
+/// The log a background run with no `[logging] log_file` left behind, if
+/// there is one
+pub fn default_log() -> Option<PathBuf> {
+    log_file("").filter(|path| path.exists())
+}
+
+/// Let go of the console, closing its window if nothing else uses it
+pub fn detach() {
+    #[cfg(windows)]
+    {
+        // SAFETY: no arguments; stdio written afterwards goes nowhere
+        if unsafe { windows_sys::Win32::System::Console::FreeConsole() } == 0 {
+            tracing::debug!(
+                "Failed to detach from the console: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_background_log_file() {
+        let state = Path::new("/var/lib/wimesh");
+        assert_eq!(
+            resolve("", Some(state)),
+            Some(PathBuf::from("/var/lib/wimesh/wimesh.log"))
+        );
+        assert_eq!(
+            resolve("/tmp/w.log", Some(state)),
+            Some(PathBuf::from("/tmp/w.log"))
+        );
+        assert_eq!(resolve("", None), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! Login latency benchmarking
+//!
+//! `wimesh bench` runs a portal's flow repeatedly and reports latency
+//! percentiles per step, plus a DNS / TCP connect / time-to-first-byte
+//! breakdown for every server the flow talks to. The numbers show where a
+//! venue's login time goes before tuning it.
+
+use crate::portal::CaptivePortal;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Give up on one network phase after this long
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Percentiles of one series of measurements, in milliseconds
+#[derive(Debug, Clone, Serialize)]
+pub struct Percentiles {
+    pub samples: usize,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    pub fn from_samples(samples: &[Duration]) -> Self {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        Self {
+            samples: sorted.len(),
+            p50: ms(percentile(&sorted, 50)),
+            p90: ms(percentile(&sorted, 90)),
+            p99: ms(percentile(&sorted, 99)),
+            max: ms(sorted.last().copied().unwrap_or_default()),
+        }
+    }
+}
+
+/// Nearest-rank percentile of an ascending series
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// A named row of the report
+#[derive(Debug, Clone, Serialize)]
+pub struct Row {
+    pub name: String,
+    #[serde(flatten)]
+    pub latency: Percentiles,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub portal: String,
+    pub iterations: usize,
+    pub failures: usize,
+    /// Per-step latency, in flow order, then the whole flow as `total`
+    pub steps: Vec<Row>,
+    /// `<host:port> dns|connect|ttfb` timings of every endpoint
+    pub network: Vec<Row>,
+}
+
+impl BenchReport {
+    /// Aligned text table
+    pub fn table(&self) -> String {
+        let rows = self.steps.iter().chain(&self.network);
+        let width = rows.clone().map(|r| r.name.len()).max().unwrap_or(0).max(4);
+
+        let mut out = format!(
+            "{} iteration(s) of '{}', {} failed\n\n",
+            self.iterations, self.portal, self.failures
+        );
+        let header = format!(
+            "{:width$}  {:>5}  {:>9}  {:>9}  {:>9}  {:>9}\n",
+            "",
+            "n",
+            "p50",
+            "p90",
+            "p99",
+            "max",
+            width = width
+        );
+        for (i, section) in [&self.steps, &self.network].into_iter().enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            out.push_str(&header);
+            for row in section {
+                let l = &row.latency;
+                out.push_str(&format!(
+                    "{:width$}  {:>5}  {:>7.1}ms  {:>7.1}ms  {:>7.1}ms  {:>7.1}ms\n",
+                    row.name,
+                    l.samples,
+                    l.p50,
+                    l.p90,
+                    l.p99,
+                    l.max,
+                    width = width
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// Raw measurements, keyed by row name in first-seen order
+#[derive(Default)]
+struct Series {
+    order: Vec<String>,
+    samples: HashMap<String, Vec<Duration>>,
+}
+
+impl Series {
+    fn push(&mut self, name: &str, sample: Duration) {
+        if !self.samples.contains_key(name) {
+            self.order.push(name.to_string());
+        }
+        self.samples
+            .entry(name.to_string())
+            .or_default()
+            .push(sample);
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        self.order
+            .iter()
+            .map(|name| Row {
+                name: name.clone(),
+                latency: Percentiles::from_samples(&self.samples[name]),
+            })
+            .collect()
+    }
+}
+
+/// Run `portal`'s full flow `iterations` times
+pub async fn run(portal: &mut dyn CaptivePortal, iterations: usize) -> BenchReport {
+    let mut steps = Series::default();
+    let mut network = Series::default();
+    let mut failures = 0;
+
+    for i in 0..iterations {
+        let started = Instant::now();
+        let result = portal.connect().await;
+        let total = started.elapsed();
+
+        for step in portal.last_steps() {
+            steps.push(step.name, Duration::from_millis(step.duration_ms));
+        }
+        match result {
+            Ok(()) => steps.push("total", total),
+            Err(e) => {
+                failures += 1;
+                tracing::warn!("Iteration {}/{} failed: {:#}", i + 1, iterations, e);
+            }
+        }
+
+        // Several endpoints often share one server; probe each once
+        let mut origins = HashSet::new();
+        for endpoint in portal.endpoints() {
+            let origin = reqwest::Url::parse(&endpoint)
+                .map(|u| u.origin().ascii_serialization())
+                .unwrap_or_else(|_| endpoint.clone());
+            if !origins.insert(origin) {
+                continue;
+            }
+            if let Err(e) = probe(&endpoint, &mut network).await {
+                tracing::debug!("Probing {} failed: {:#}", endpoint, e);
+            }
+        }
+    }
+
+    BenchReport {
+        portal: portal.name().to_string(),
+        iterations,
+        failures,
+        steps: steps.rows(),
+        network: network.rows(),
+    }
+}
+
+/// Time DNS resolution, TCP connect and first response byte of a HEAD
+/// request to `url`; TTFB is only measured for plain HTTP
+async fn probe(url: &str, series: &mut Series) -> Result<()> {
+    let url = reqwest::Url::parse(url)?;
+    let host = url.host_str().context("URL has no host")?;
+    let port = url.port_or_known_default().context("URL has no port")?;
+    let label = format!("{}:{}", host, port);
+
+    let started = Instant::now();
+    let addr = tokio::time::timeout(PROBE_TIMEOUT, tokio::net::lookup_host((host, port)))
+        .await
+        .context("DNS lookup timed out")??
+        .next()
+        .context("Host did not resolve")?;
+    series.push(&format!("{} dns", label), started.elapsed());
+
+    let started = Instant::now();
+    let mut stream = tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .context("Connect timed out")??;
+    series.push(&format!("{} connect", label), started.elapsed());
+
+    if url.scheme() != "http" {
+        return Ok(());
+    }
+    let request = format!(
+        "HEAD {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        url.path(),
+        host
+    );
+    let started = Instant::now();
+    stream.write_all(request.as_bytes()).await?;
+    let mut byte = [0u8; 1];
+    tokio::time::timeout(PROBE_TIMEOUT, stream.read_exact(&mut byte))
+        .await
+        .context("No response in time")??;
+    series.push(&format!("{} ttfb", label), started.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let samples: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&samples, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&samples, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+}
@@ -0,0 +1,166 @@
+//! Circuit breaker for portal hosts
+//!
+//! When a portal host stops accepting connections, every flow step would
+//! otherwise run its own retries with growing delays, and a login of six
+//! steps takes minutes to fail. After a burst of connection failures to a
+//! host the breaker opens: requests to it fail at once with `CircuitOpen`
+//! for a cooldown, then a single trial request decides whether it closes
+//! again. All `HttpClient`s share one breaker, so concurrent flows to the
+//! same host count together.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Connection failures to one host that open the circuit...
+const FAILURE_THRESHOLD: u32 = 5;
+/// ...when they happen within this long
+const FAILURE_WINDOW: Duration = Duration::from_secs(30);
+/// How long an open circuit fails requests before letting a trial through
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+/// A request was not sent because its host's circuit is open
+#[derive(Debug, thiserror::Error)]
+#[error("Circuit open for {host} after repeated connection failures, retry in {retry_in:?}")]
+pub struct CircuitOpen {
+    pub host: String,
+    pub retry_in: Duration,
+}
+
+/// Connection health of each host
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    /// Failures since `first_failure`
+    failures: u32,
+    first_failure: Option<Instant>,
+    /// Set while open
+    open_until: Option<Instant>,
+    /// A trial request is in flight after the cooldown
+    trial: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The breaker every `HttpClient` shares
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<CircuitBreaker>> = OnceLock::new();
+        GLOBAL
+            .get_or_init(|| Arc::new(Self::new(FAILURE_THRESHOLD, FAILURE_WINDOW, COOLDOWN)))
+            .clone()
+    }
+
+    /// Whether a request to `host` may go out now
+    pub fn check(&self, host: &str) -> Result<(), CircuitOpen> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        if now < open_until || state.trial {
+            return Err(CircuitOpen {
+                host: host.to_string(),
+                retry_in: open_until.saturating_duration_since(now),
+            });
+        }
+        // Half-open: this request is the trial
+        state.trial = true;
+        Ok(())
+    }
+
+    /// `host` answered: close its circuit
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(state) = hosts.remove(host) {
+            if state.open_until.is_some() {
+                tracing::info!("Circuit for {} closed, the host answers again", host);
+            }
+        }
+    }
+
+    /// A connection to `host` failed; returns whether its circuit is open
+    /// now
+    pub fn record_failure(&self, host: &str) -> bool {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let state = hosts.entry(host.to_string()).or_default();
+        let now = Instant::now();
+
+        if state.trial {
+            state.trial = false;
+            state.open_until = Some(now + self.cooldown);
+            tracing::warn!("Trial request to {} failed, circuit stays open", host);
+            return true;
+        }
+        if state.open_until.is_some() {
+            return true;
+        }
+
+        match state.first_failure {
+            Some(first) if now.duration_since(first) <= self.window => state.failures += 1,
+            _ => {
+                state.first_failure = Some(now);
+                state.failures = 1;
+            }
+        }
+        if state.failures >= self.threshold {
+            state.open_until = Some(now + self.cooldown);
+            tracing::warn!(
+                "{} connection failures to {} within {:?}, failing fast for {:?}",
+                state.failures,
+                host,
+                self.window,
+                self.cooldown
+            );
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_burst_and_closes_after_trial() {
+        let cooldown = Duration::from_millis(50);
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(10), cooldown);
+
+        assert!(!breaker.record_failure("portal.test"));
+        assert!(!breaker.record_failure("portal.test"));
+        assert!(breaker.record_failure("portal.test"));
+        assert!(breaker.check("portal.test").is_err());
+        assert!(breaker.check("other.test").is_ok());
+
+        std::thread::sleep(cooldown);
+        // One trial goes through, concurrent requests still fail fast
+        assert!(breaker.check("portal.test").is_ok());
+        assert!(breaker.check("portal.test").is_err());
+        assert!(breaker.record_failure("portal.test"));
+        assert!(breaker.check("portal.test").is_err());
+
+        std::thread::sleep(cooldown);
+        assert!(breaker.check("portal.test").is_ok());
+        breaker.record_success("portal.test");
+        assert!(breaker.check("portal.test").is_ok());
+        assert!(!breaker.record_failure("portal.test"));
+    }
+}
@@ -0,0 +1,187 @@
+//! What this build supports and what works on this machine
+//!
+//! `wimesh capabilities` answers "can it do X here?" without reading the
+//! source: every portal type, WiFi backend, notifier, optional subsystem
+//! and Cargo feature, whether it was compiled in, and whether what it needs
+//! at runtime (a program in PATH, the D-Bus system bus) is present.
+
+use crate::utils::find_in_path;
+use serde::Serialize;
+
+/// One row of the support matrix
+#[derive(Debug, Clone, Serialize)]
+pub struct Capability {
+    /// `portal`, `wifi_backend`, `notifier`, `subsystem` or `feature`
+    pub kind: &'static str,
+    pub name: &'static str,
+    /// Built into this binary
+    pub compiled: bool,
+    /// Compiled in, and everything it needs at runtime is there
+    pub usable: bool,
+    /// What is missing, or what it runs on
+    pub detail: String,
+}
+
+/// Every capability, in the order `wimesh capabilities` prints them
+pub fn matrix() -> Vec<Capability> {
+    let dbus = system_bus_reachable();
+    let mut rows = Vec::new();
+
+    for &(portal, compiled) in crate::portal::PORTAL_TYPES {
+        rows.push(Capability {
+            kind: "portal",
+            name: portal,
+            compiled,
+            usable: compiled,
+            detail: if compiled {
+                "built in".to_string()
+            } else {
+                format!("build with --features portal-{}", portal)
+            },
+        });
+    }
+
+    let freebsd = cfg!(target_os = "freebsd");
+    let windows = cfg!(windows);
+    let macos = cfg!(target_os = "macos");
+    let networkmanager = !freebsd && !windows && !macos;
+    let mut nm = tools("wifi_backend", "networkmanager", networkmanager, &["nmcli"]);
+    if networkmanager {
+        // Spoken over D-Bus; nmcli, which needs the bus too, is the fallback
+        nm.usable = dbus;
+        nm.detail = match (dbus, nm.detail.as_str()) {
+            (false, _) => "the D-Bus system bus is not reachable".to_string(),
+            (true, "nmcli") => "D-Bus, nmcli as fallback".to_string(),
+            (true, _) => "D-Bus".to_string(),
+        };
+    }
+    rows.push(nm);
+    rows.push(tools(
+        "wifi_backend",
+        "wpa_cli",
+        freebsd,
+        &["wpa_cli", "ifconfig"],
+    ));
+    rows.push(tools("wifi_backend", "netsh", windows, &["netsh"]));
+    rows.push(tools(
+        "wifi_backend",
+        "networksetup",
+        macos,
+        &["networksetup"],
+    ));
+
+    rows.push(available("notifier", "event_log", "events.jsonl"));
+    rows.push(available("notifier", "widget", "wimesh widget"));
+
+    rows.push(tools("subsystem", "gateway_ping", true, &["ping"]));
+    let probes = format!("{} probe URLs, in process", crate::probe::targets().len());
+    rows.push(available("subsystem", "internet_check", &probes));
+    rows.push(tools("subsystem", "remote", true, &["ssh"]));
+    let (route, neighbors, renew) = if freebsd {
+        (&["route"][..], &["arp"][..], &["dhclient"][..])
+    } else {
+        (&["ip"][..], &["ip"][..], &["nmcli"][..])
+    };
+    rows.push(tools("subsystem", "routes", true, route));
+    rows.push(tools("subsystem", "authorize_device", true, neighbors));
+    rows.push(tools("subsystem", "renew_dhcp", true, renew));
+    rows.push(available("subsystem", "coop", "mDNS on UDP 5353"));
+    rows.push(available(
+        "subsystem",
+        "responder",
+        "HTTP and DNS probe answers",
+    ));
+
+    rows.push(feature("api", cfg!(feature = "api")));
+    rows.push(feature("sqlite", cfg!(feature = "sqlite")));
+    rows.push(feature("tls", cfg!(feature = "tls")));
+    rows.push(feature("tray", cfg!(feature = "tray")));
+    rows.push(feature("embedded", cfg!(feature = "embedded")));
+    rows
+}
+
+/// Whether the D-Bus system bus, which NetworkManager answers on, is there
+fn system_bus_reachable() -> bool {
+    crate::dbus::system_bus_path().is_some_and(|path| path.exists())
+}
+
+fn available(kind: &'static str, name: &'static str, detail: &str) -> Capability {
+    Capability {
+        kind,
+        name,
+        compiled: true,
+        usable: true,
+        detail: detail.to_string(),
+    }
+}
+
+/// A capability needing `programs` in PATH; `compiled` false for ones
+/// another platform uses
+fn tools(kind: &'static str, name: &'static str, compiled: bool, programs: &[&str]) -> Capability {
+    if !compiled {
+        return Capability {
+            kind,
+            name,
+            compiled,
+            usable: false,
+            detail: "not used on this platform".to_string(),
+        };
+    }
+    let missing: Vec<&str> = programs
+        .iter()
+        .copied()
+        .filter(|program| find_in_path(program).is_none())
+        .collect();
+    Capability {
+        kind,
+        name,
+        compiled,
+        usable: missing.is_empty(),
+        detail: if missing.is_empty() {
+            programs.join(", ")
+        } else {
+            format!("{} not found in PATH", missing.join(", "))
+        },
+    }
+}
+
+fn feature(name: &'static str, compiled: bool) -> Capability {
+    Capability {
+        kind: "feature",
+        name,
+        compiled,
+        usable: compiled,
+        detail: if compiled {
+            "enabled".to_string()
+        } else {
+            format!("build with --features {}", name)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix() {
+        let rows = matrix();
+        let row = |kind: &str, name: &str| {
+            rows.iter()
+                .find(|r| r.kind == kind && r.name == name)
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(row("portal", "awing").usable, cfg!(feature = "portal-awing"));
+        assert_eq!(row("feature", "sqlite").compiled, cfg!(feature = "sqlite"));
+        // Exactly one WiFi backend per platform
+        let backends = rows
+            .iter()
+            .filter(|r| r.kind == "wifi_backend" && r.compiled)
+            .count();
+        assert_eq!(backends, 1);
+        let missing = tools("subsystem", "test", true, &["wimesh-no-such-program"]);
+        assert!(!missing.usable);
+        assert_eq!(missing.detail, "wimesh-no-such-program not found in PATH");
+    }
+}
@@ -0,0 +1,167 @@
+//! The Captive Portal API (RFC 8908)
+//!
+//! Networks following RFC 8910 hand out the URI of an API in DHCP option
+//! 114 that says, as JSON, whether this client is held by the portal and
+//! how long its session has left. Where there is one, the daemon asks it
+//! before the probe URLs: `captive: true` goes straight to the login,
+//! `false` is online, and `seconds-remaining` becomes the end of the
+//! session, checked as it comes instead of an interval later. The URI is
+//! `[capport] url`, else the lease's as NetworkManager keeps it (the
+//! router advertisement option is not read); the API has to answer over
+//! HTTPS, which builds without TLS cannot do.
+
+use crate::config::CapportConfig;
+use anyhow::{Context, Result};
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// The media type of an API answer
+const MEDIA_TYPE: &str = "application/captive+json";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static CONFIG: OnceLock<CapportConfig> = OnceLock::new();
+
+/// Use `[capport]` from now on, instead of the defaults
+pub fn configure(cfg: &CapportConfig) {
+    let _ = CONFIG.set(cfg.clone());
+}
+
+fn config() -> &'static CapportConfig {
+    CONFIG.get_or_init(CapportConfig::default)
+}
+
+/// What the API says about this client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CaptiveStatus {
+    /// Whether the portal holds this client's traffic
+    pub captive: bool,
+    /// The login page to show the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_portal_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub venue_info_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seconds_remaining: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_remaining: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub can_extend_session: Option<bool>,
+}
+
+impl CaptiveStatus {
+    pub fn parse(body: &str) -> Result<Self> {
+        serde_json::from_str(body).context("Not a Captive Portal API answer")
+    }
+
+    /// Unix time the session ends, counted from `now`
+    pub fn expires_at(&self, now: u64) -> Option<u64> {
+        Some(now + self.seconds_remaining?)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut line = match self.captive {
+            true => "captive".to_string(),
+            false => "not captive".to_string(),
+        };
+        if let Some(secs) = self.seconds_remaining {
+            line.push_str(&format!(", {}m of session left", secs / 60));
+        }
+        if let Some(url) = self.user_portal_url.as_ref().filter(|_| self.captive) {
+            line.push_str(&format!(", log in at {}", url));
+        }
+        line
+    }
+}
+
+/// The API of the network `interface` is on: `[capport] url`, else the
+/// one of its DHCP lease; `None` when disabled or there is none
+pub async fn api_uri(interface: Option<&str>) -> Option<String> {
+    let cfg = config();
+    if !cfg.enabled {
+        return None;
+    }
+    if !cfg.url.is_empty() {
+        return Some(cfg.url.clone());
+    }
+    crate::utils::nonblocking::capport_uri(interface?).await
+}
+
+/// Ask the API at `uri`, out of `interface` if given (Linux only)
+pub async fn query(uri: &str, interface: Option<&str>) -> Result<CaptiveStatus> {
+    if !uri.starts_with("https://") {
+        anyhow::bail!("not https, which RFC 8908 requires");
+    }
+    let builder = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .timeout(TIMEOUT);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let builder = match interface {
+        Some(interface) => builder.interface(interface),
+        None => builder,
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = interface;
+    let response = builder
+        .build()?
+        .get(uri)
+        .header(reqwest::header::ACCEPT, MEDIA_TYPE)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("answered {}", status);
+    }
+    CaptiveStatus::parse(&response.text().await?)
+}
+
+/// What the API of the network `interface` is on says; `None` without
+/// one, or when it does not answer
+pub async fn status(interface: Option<&str>) -> Option<CaptiveStatus> {
+    let uri = api_uri(interface).await?;
+    match query(&uri, interface).await {
+        Ok(status) => Some(status),
+        Err(e) => {
+            tracing::debug!("Captive Portal API {} failed: {:#}", uri, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let held = CaptiveStatus::parse(
+            r#"{"captive": true, "user-portal-url": "https://login.example/portal"}"#,
+        )
+        .unwrap();
+        assert!(held.captive);
+        assert_eq!(held.expires_at(1000), None);
+        assert_eq!(
+            held.describe(),
+            "captive, log in at https://login.example/portal"
+        );
+
+        let body = r#"{
+            "captive": false,
+            "user-portal-url": "https://login.example/portal",
+            "venue-info-url": "https://venue.example/",
+            "can-extend-session": true,
+            "seconds-remaining": 326,
+            "bytes-remaining": 65536
+        }"#;
+        let open = CaptiveStatus::parse(body).unwrap();
+        assert!(!open.captive);
+        assert_eq!(open.expires_at(1000), Some(1326));
+        assert_eq!(open.can_extend_session, Some(true));
+        assert_eq!(open.describe(), "not captive, 5m of session left");
+
+        assert!(CaptiveStatus::parse(r#"{"user-portal-url": "x"}"#).is_err());
+        assert!(CaptiveStatus::parse("<html>").is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Timing and stressing a portal's login flow
+
+use crate::OutputFormat;
+use anyhow::{Context, Result};
+use std::time::Duration;
+use wimesh::bench;
+use wimesh::identity::IdentityManager;
+use wimesh::mock::{Faults, MockPortal};
+use wimesh::portal;
+use wimesh::state;
+use wimesh::stress::{self, StressOptions};
+use wimesh::{config, utils};
+
+/// Benchmark a portal's login flow against the local mock, or the real
+/// venue after confirmation
+pub(crate) async fn bench(
+    cfg: &config::Config,
+    name: &str,
+    iterations: usize,
+    live: bool,
+    yes: bool,
+    output: OutputFormat,
+) -> Result<()> {
+    let names: Vec<&str> = cfg.portals.iter().map(|p| p.name.as_str()).collect();
+    let portal_cfg = cfg
+        .portals
+        .iter()
+        .find(|p| p.name == name)
+        .with_context(|| {
+            format!(
+                "No portal named '{}' (configured: {})",
+                name,
+                names.join(", ")
+            )
+        })?;
+
+    let mock = if live {
+        if !yes && !confirm_live(name, iterations)? {
+            anyhow::bail!("Live benchmark cancelled");
+        }
+        None
+    } else {
+        Some(MockPortal::start(Duration::ZERO).await?)
+    };
+    let portal_cfg = match mock {
+        Some(ref mock) => {
+            tracing::info!("Benchmarking against mock portal at {}", mock.url());
+            mock.portal_config(portal_cfg)
+        }
+        None => portal_cfg.clone(),
+    };
+
+    let mut portal = portal::build(cfg, &portal_cfg, &IdentityManager::load())?
+        .with_context(|| portal::missing_type(&portal_cfg.portal_type))?;
+    let report = bench::run(portal.as_mut(), iterations).await;
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&report)?),
+        OutputFormat::Text => print!("{}", report.table()),
+    }
+    Ok(())
+}
+
+pub(crate) async fn stress(
+    cfg: &config::Config,
+    name: &str,
+    duration: u64,
+    fault_rate: f64,
+    seed: Option<u64>,
+    output: OutputFormat,
+) -> Result<()> {
+    let names: Vec<&str> = cfg.portals.iter().map(|p| p.name.as_str()).collect();
+    let portal_cfg = cfg
+        .portals
+        .iter()
+        .find(|p| p.name == name)
+        .with_context(|| {
+            format!(
+                "No portal named '{}' (configured: {})",
+                name,
+                names.join(", ")
+            )
+        })?;
+
+    // The passes write the state file; keep the real one out of it
+    let dir = std::env::temp_dir().join(format!("wimesh-stress-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    state::isolate_in(&dir)?;
+
+    let options = StressOptions {
+        duration: Duration::from_secs(duration),
+        faults: Faults::uniform(fault_rate.clamp(0.0, 1.0), Duration::from_secs(3)),
+        seed: seed.unwrap_or_else(utils::random_u64),
+    };
+    tracing::info!(
+        "Stressing '{}' for {}s, faults at {} (seed {})",
+        name,
+        duration,
+        options.faults.server_errors,
+        options.seed
+    );
+    let report = stress::run(cfg, portal_cfg, &options).await;
+    std::fs::remove_dir_all(&dir).ok();
+    let report = report?;
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&report)?),
+        OutputFormat::Text => print!("{}", report.table()),
+    }
+    if !report.ok() {
+        anyhow::bail!("{} invariant(s) violated", report.violations.len());
+    }
+    Ok(())
+}
+
+/// Ask on the terminal before logging in to a real venue repeatedly
+fn confirm_live(name: &str, iterations: usize) -> Result<bool> {
+    eprint!(
+        "This logs in to '{}' {} time(s) on the real network. Type 'yes' to continue: ",
+        name, iterations
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
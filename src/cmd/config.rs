@@ -0,0 +1,341 @@
+//! Writing, checking and printing the config file
+
+use crate::OutputFormat;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use wimesh::identity::IdentityManager;
+use wimesh::portal;
+use wimesh::{config, utils};
+
+/// Print `cfg` as it is in effect: where it came from, what overrode it,
+/// then the merged values
+pub(crate) fn print_config(
+    cfg: &mut config::Config,
+    config_path: Option<&Path>,
+    log_level: Option<&str>,
+) -> Result<()> {
+    println!("# Effective wimesh configuration");
+    match config_path {
+        Some(path) => println!("# Loaded from {} (--config)", path.display()),
+        None => {
+            let existing: Vec<PathBuf> = config::Config::search_paths()
+                .into_iter()
+                .filter(|path| path.exists())
+                .collect();
+            match existing.split_first() {
+                Some((loaded, shadowed)) => {
+                    println!("# Loaded from {}", loaded.display());
+                    for path in shadowed {
+                        println!(
+                            "# Ignored {} (found later in the search path)",
+                            path.display()
+                        );
+                    }
+                }
+                None => println!("# No config file found, built-in defaults only"),
+            }
+        }
+    }
+
+    let env_level = std::env::var("RUST_LOG")
+        .ok()
+        .filter(|level| !level.is_empty());
+    if let Some(level) = log_level {
+        println!("# logging.level overridden by --log-level");
+        cfg.logging.level = level.to_string();
+    } else if let Some(level) = env_level {
+        println!("# logging.level overridden by RUST_LOG");
+        cfg.logging.level = level;
+    }
+    println!();
+    print!("{}", cfg.to_masked_toml()?);
+    Ok(())
+}
+
+/// Report config problems and unknown portal types, at their lines in
+/// the file; fails if there are any
+pub(crate) fn validate(
+    cfg: &config::Config,
+    config_path: Option<&Path>,
+    output: OutputFormat,
+) -> Result<()> {
+    let mut problems = cfg.diagnostics();
+    for (i, portal_cfg) in cfg.portals.iter().enumerate() {
+        // A throwaway manager, so validating generates no identities;
+        // unknown types are among the diagnostics already
+        if let Err(e) = portal::build(cfg, portal_cfg, &IdentityManager::default()) {
+            problems.push(config::Diagnostic {
+                message: format!("Portal '{}': {:#}", portal_cfg.name, e),
+                field: Some(format!("portals[{}]", i)),
+                line: None,
+                column: None,
+            });
+        }
+    }
+    let path = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find);
+    let toml = path
+        .as_deref()
+        .filter(|path| config::ConfigFormat::from_path(path) == config::ConfigFormat::Toml)
+        .and_then(|path| std::fs::read_to_string(path).ok());
+    if let Some(ref contents) = toml {
+        for problem in &mut problems {
+            problem.locate(contents);
+        }
+        problems.sort_by_key(|problem| problem.line.unwrap_or(usize::MAX));
+    }
+    report_diagnostics(path.as_deref(), &problems, output)?;
+    if problems.is_empty() && output == OutputFormat::Text {
+        println!("✓ {} portal(s), no problems found", cfg.portals.len());
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problem(s) in the config", problems.len());
+    }
+    Ok(())
+}
+
+/// `validate` for a config file that does not even load: where it stopped
+pub(crate) fn validate_unloadable(
+    config_path: Option<&Path>,
+    error: &anyhow::Error,
+    output: OutputFormat,
+) -> Result<()> {
+    let path = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find);
+    let contents = path
+        .as_deref()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    let problem = config::Diagnostic::parse_error(error, &contents);
+    report_diagnostics(path.as_deref(), std::slice::from_ref(&problem), output)?;
+    anyhow::bail!("The config does not load")
+}
+
+/// Print `problems` in `path`, as `path:line:column: message` lines or JSON
+fn report_diagnostics(
+    path: Option<&Path>,
+    problems: &[config::Diagnostic],
+    output: OutputFormat,
+) -> Result<()> {
+    if output == OutputFormat::Json {
+        let messages: Vec<&str> = problems.iter().map(|p| p.message.as_str()).collect();
+        let json = serde_json::json!({
+            "ok": problems.is_empty(),
+            "file": path,
+            "problems": messages,
+            "diagnostics": problems,
+        });
+        println!("{}", json);
+        return Ok(());
+    }
+    for problem in problems {
+        let mut at = String::new();
+        if let (Some(path), Some(line)) = (path, problem.line) {
+            at = format!("{}:{}:", path.display(), line);
+            if let Some(column) = problem.column {
+                at.push_str(&format!("{}:", column));
+            }
+            at.push(' ');
+        }
+        match problem.field {
+            // Without a line, the setting says where
+            Some(ref field) if problem.line.is_none() => {
+                println!("✗ {} ({})", problem.message, field)
+            }
+            _ => println!("✗ {}{}", at, problem.message),
+        }
+    }
+    Ok(())
+}
+
+/// Ask for a first portal and write a config with it to `config_path`, else
+/// `~/.config/wimesh/config.toml`
+pub(crate) async fn init(config_path: Option<&Path>, force: bool) -> Result<()> {
+    let path = match config_path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            config::Config::user_path().context("No home directory, name a file with --config")?
+        }
+    };
+    if path.exists() && !force {
+        anyhow::bail!("{} is there already, --force replaces it", path.display());
+    }
+    let active = utils::nonblocking::active_wifi().await.unwrap_or_default();
+    let (interface, connected) = active.into_iter().next().unzip();
+
+    let types = portal::compiled_types();
+    let first = types
+        .iter()
+        .find(|&&t| t == "awing")
+        .or(types.first())
+        .copied();
+    let question = format!("Portal type ({})", types.join(", "));
+    let portal_type = loop {
+        let answer = ask(&question, first)?;
+        if types.contains(&answer.as_str()) {
+            break answer;
+        }
+        eprintln!("  '{}' is not a portal type of this build", answer);
+    };
+    let ssid = ask_required("SSID", connected.as_deref())?;
+    let name = ask_required("Name for the portal", Some(&ssid))?;
+
+    let detected = utils::nonblocking::get_interface_mac(interface.as_deref()).await;
+    eprintln!("  A MAC address pins the one logged in with; '-' uses the interface's own");
+    let mac_address = loop {
+        let answer = ask("MAC address", detected.as_deref())?;
+        if answer.is_empty() || answer == "-" {
+            break String::new();
+        }
+        match utils::normalize_mac(&answer) {
+            Some(mac) => break mac,
+            None => eprintln!(
+                "  '{}' is not a MAC address, like AA:BB:CC:DD:EE:FF",
+                answer
+            ),
+        }
+    };
+
+    // What the type needs to log in; passwords go in the keyring
+    let keyring = format!(
+        "wimesh/{}",
+        name.to_lowercase().replace(char::is_whitespace, "-")
+    );
+    let mut settings = toml::Table::new();
+    let mut wants_secret = false;
+    let mut setting = |key: &str, value: String| {
+        settings.insert(key.to_string(), toml::Value::String(value));
+    };
+    match portal_type.as_str() {
+        "generic" => setting("form_url", ask_required("URL of the login form", None)?),
+        "mikrotik" => {
+            setting(
+                "login_url",
+                ask_required("Login URL, e.g. http://10.5.50.1/login", None)?,
+            );
+            setting("username", ask_required("Username", None)?);
+            wants_secret = true;
+        }
+        "wispr" => {
+            setting("username", ask_required("Username", None)?);
+            wants_secret = true;
+        }
+        _ => {}
+    }
+    if wants_secret {
+        let mut reference = toml::Table::new();
+        reference.insert("keyring".into(), toml::Value::String(keyring.clone()));
+        settings.insert("password".into(), toml::Value::Table(reference));
+    }
+
+    let mut extra = std::collections::HashMap::new();
+    if !settings.is_empty() {
+        extra.insert(portal_type.clone(), toml::Value::Table(settings));
+    }
+    let portal = config::PortalConfig {
+        name,
+        group: None,
+        portal_type,
+        ssids: vec![ssid],
+        mac_address,
+        session_minutes: None,
+        schedule: Vec::new(),
+        identity: Default::default(),
+        extra,
+    };
+    let contents = config::starter_toml(&portal);
+    // Not written unless it loads back without problems
+    let cfg = config::ConfigFormat::Toml
+        .parse(&contents)
+        .context("The new config does not load")?;
+    if let Some(problem) = cfg.problems().into_iter().next() {
+        anyhow::bail!("The new config has a problem: {}", problem);
+    }
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(&path, contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!("✓ Wrote {}", path.display());
+    if wants_secret {
+        println!("  Store the password with `wimesh secret set {}`", keyring);
+    }
+    println!("  `wimesh validate` checks it, `wimesh login` tries it");
+    Ok(())
+}
+
+/// Ask `question` on stderr and read the answer from stdin, `default` when
+/// it is left empty
+fn ask(question: &str, default: Option<&str>) -> Result<String> {
+    let default = default.unwrap_or_default();
+    match default {
+        "" => eprint!("{}: ", question),
+        default => eprint!("{} [{}]: ", question, default),
+    }
+    let mut line = String::new();
+    if std::io::stdin()
+        .read_line(&mut line)
+        .context("Failed to read the answer")?
+        == 0
+    {
+        anyhow::bail!("No answer to '{}'", question);
+    }
+    Ok(match line.trim() {
+        "" => default.to_string(),
+        answer => answer.to_string(),
+    })
+}
+
+/// `ask`, again until the answer is not empty
+fn ask_required(question: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        let answer = ask(question, default)?;
+        if !answer.is_empty() {
+            return Ok(answer);
+        }
+        eprintln!("  An answer is needed");
+    }
+}
+
+/// Bring the config file up to the current format, or print it so
+pub(crate) fn migrate_config(config_path: Option<&Path>, dry_run: bool) -> Result<()> {
+    let path = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find)
+        .context("No config file to migrate")?;
+
+    if dry_run {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        match config::migrate(&contents, config::ConfigFormat::from_path(&path))? {
+            Some(migration) => print!("{}", migration.contents),
+            None => println!("{} is already in the current format", path.display()),
+        }
+        return Ok(());
+    }
+
+    match config::Config::migrate_file(&path)? {
+        Some((migration, backup)) => {
+            println!(
+                "Migrated {} from format version {} to {}, the original is in {}",
+                path.display(),
+                migration.from,
+                config::CONFIG_VERSION,
+                backup.display()
+            );
+            if migration.comments_lost {
+                println!("Its comments could not be kept, copy any you need from the backup");
+            }
+        }
+        None => println!(
+            "{} is already in the current format (version {})",
+            path.display(),
+            config::CONFIG_VERSION
+        ),
+    }
+    Ok(())
+}
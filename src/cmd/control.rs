@@ -0,0 +1,44 @@
+//! Steering the daemon: over its control socket, or through the state file
+
+use crate::OutputFormat;
+use anyhow::Result;
+use wimesh::config;
+use wimesh::control::{self, Request};
+use wimesh::state::State;
+
+/// Send `request` to the running daemon and print its answer
+pub(crate) async fn send(
+    cfg: &config::Config,
+    request: Request,
+    output: OutputFormat,
+) -> Result<()> {
+    let reply = control::send(&control::socket_paths(&cfg.control), request).await?;
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&reply)?);
+    } else {
+        println!("{}", reply.message);
+    }
+    if !reply.ok {
+        anyhow::bail!("The daemon refused: {}", reply.message);
+    }
+    Ok(())
+}
+
+/// Override read-only mode (None: back to the config) and say what the
+/// daemon does now
+pub(crate) fn read_only(cfg: &config::Config, read_only: Option<bool>) -> Result<()> {
+    State::set_read_only(read_only)?;
+    if State::load().is_read_only(cfg.global.read_only) {
+        println!("Read-only: the daemon keeps checking, but does not log in");
+    } else {
+        println!("The daemon logs in again when needed");
+    }
+    Ok(())
+}
+
+/// Clear the backoff of `ssid`, or of every SSID
+pub(crate) fn reset_backoff(ssid: Option<&str>) -> Result<()> {
+    let cleared = State::reset_backoff(ssid)?;
+    println!("Cleared the backoff of {} SSID(s)", cleared);
+    Ok(())
+}
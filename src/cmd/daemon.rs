@@ -0,0 +1,814 @@
+//! The daemon: checking the connection and logging in, until stopped
+
+use super::login::warn_unknown_ssid;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use wimesh::congestion::{self, Congestion};
+use wimesh::control::{self, Control, Incoming, Reply, Request};
+use wimesh::coop::{Coop, PeerState};
+use wimesh::daemon::{self, Pass, ProbeUrls};
+use wimesh::events::{Event, EventLog};
+use wimesh::identity::IdentityManager;
+use wimesh::lock::LoginLocks;
+use wimesh::portal::{self, PortalRegistry};
+use wimesh::recovery::{Recovery, Step};
+use wimesh::responder::Responder;
+use wimesh::rotation;
+use wimesh::state::{self, unix_now, Action, NextAction, State};
+use wimesh::status::NetworkState;
+use wimesh::supervisor::{Health, Supervised};
+use wimesh::systemd;
+use wimesh::tasks::{self, Tasks};
+use wimesh::watch::NetworkWatch;
+use wimesh::{config, status, utils};
+
+/// How often a daemon pass checks that its adapter is still on the same SSID
+const SSID_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Clone the identity MACs onto the WiFi connections of the portals that
+/// ask for it
+pub(crate) fn apply_wifi_identities(cfg: &config::Config, identities: &IdentityManager) {
+    for portal_cfg in cfg.portals.iter().filter(|p| p.identity.apply_to_wifi) {
+        let identity = identities.resolve(portal_cfg);
+        if identity.mac_address.is_empty() {
+            continue;
+        }
+        for ssid in &portal_cfg.ssids {
+            match utils::set_cloned_mac(ssid, &identity.mac_address) {
+                Ok(()) => tracing::info!(
+                    "'{}' connects with the '{}' identity MAC",
+                    ssid,
+                    portal_cfg.name
+                ),
+                Err(e) => tracing::warn!("{:#}", e),
+            }
+        }
+    }
+}
+
+/// Run in daemon mode - continuous monitoring
+///
+/// With `batch`, at most that many passes and no peers or probe answers,
+/// which need a process that stays: the first pass runs at once, a login
+/// due within the next check interval is waited for, and otherwise the run
+/// ends, leaving the rest to the next one (cron's).
+pub(crate) async fn run_daemon(
+    mut cfg: config::Config,
+    config_path: Option<&Path>,
+    mut registry: PortalRegistry,
+    locks: &LoginLocks,
+    events: &EventLog,
+    batch: Option<u32>,
+) -> Result<()> {
+    let all_ssids: Vec<String> = registry.all_ssids().iter().map(|s| s.to_string()).collect();
+
+    match batch {
+        Some(cycles) => tracing::info!("Running a batch of up to {} checks...", cycles),
+        None => tracing::info!("Starting daemon mode..."),
+    }
+    tracing::info!("Monitoring SSIDs: {}", all_ssids.join(", "));
+    tracing::info!("Portal types: {}", portal::compiled_types().join(", "));
+    tracing::info!("Check interval: {}s", cfg.global.check_interval);
+    tracing::info!("---");
+
+    let mut check_interval = std::time::Duration::from_secs(cfg.global.check_interval);
+    let mut last_check = std::time::Instant::now();
+    // Last state seen on each interface associated to a configured SSID
+    let mut last_states: HashMap<String, (String, NetworkState)> = HashMap::new();
+    let mut last_plan: Option<NextAction> = None;
+    // Unconfigured SSIDs already warned about
+    let mut warned: HashSet<String> = HashSet::new();
+    // Denied or metered SSIDs already said to be left alone
+    let mut left_alone: HashSet<String> = HashSet::new();
+    let mut was_read_only = None;
+    // The next pass is due now: the last was cut short by an SSID change,
+    // or `wimesh control` asked for one
+    let mut due_now = false;
+    let mut next_check = unix_now();
+    let mut nudges = Nudges::new(config_path).await;
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::channel::<Incoming>(8);
+    let mut control: Supervised<Control> = Supervised::new("control");
+    let mut coop: Supervised<Coop> = Supervised::new("coop");
+    let mut responder: Supervised<Responder> = Supervised::new("responder");
+    let mut store: Supervised<()> = Supervised::new("state_store");
+    let mut last_health = None;
+    // Ctrl-C and SIGTERM stop the checks, then everything else
+    let mut tasks = Tasks::new("daemon");
+    let stop = tasks.cancel().clone();
+    tasks.spawn("signals", |cancel| async move {
+        tokio::select! {
+            signal = tasks::stop_signal() => {
+                tracing::info!("{}, stopping...", signal);
+                cancel.cancel();
+            }
+            _ = cancel.cancelled() => {}
+        }
+    });
+    let watch = (cfg.global.watch_network && batch.is_none()).then(NetworkWatch::start);
+    // Under a `Type=notify` unit: ready after the first pass, then watched
+    let watchdog = systemd::Watchdog::start(tasks.child("watchdog"));
+    let mut notified: Option<String> = None;
+    let mut recovery = Recovery::new(
+        cfg.recovery.window,
+        cfg.recovery.step_interval,
+        cfg.recovery.rotate_mac,
+    );
+
+    // A backoff saved before a crash or restart still applies
+    let state = state::nonblocking::load().await;
+    for ssid in &all_ssids {
+        if let Some(wait) = state.backoff_remaining(ssid) {
+            tracing::info!("Still backing off on '{}' for {}s", ssid, wait);
+        }
+    }
+    // What the last run planned and did not get to
+    let mut batch_left = batch;
+    let mut batch_wait = Duration::ZERO;
+    let overdue = |next: &NextAction| batch.is_some() && next.is_overdue(unix_now());
+    if let Some(missed) = state.next_action.filter(overdue) {
+        tracing::info!("Catching up: {}", missed.describe(unix_now()));
+    }
+
+    loop {
+        watchdog.idle();
+        // Optional parts that failed to start get another go now and then
+        if batch.is_none() {
+            let now = unix_now();
+            if cfg.coop.enabled && coop.is_due(now) {
+                let part = tasks.child(coop.name());
+                let started = Coop::start(cfg.coop.stagger, part);
+                if started.is_err() {
+                    part.cancel().cancel();
+                }
+                coop.started(started, now);
+            }
+            if cfg.responder.enabled && responder.is_due(now) {
+                let part = tasks.child(responder.name());
+                let started = Responder::start(&cfg.responder, part).await;
+                if started.is_err() {
+                    part.cancel().cancel();
+                }
+                responder.started(started, now);
+            }
+            if cfg.control.enabled && control.is_due(now) {
+                let part = tasks.child(control.name());
+                let started = match control::socket_path(&cfg.control) {
+                    Some(path) => Control::start(&path, control_tx.clone(), part),
+                    None => Err(anyhow::anyhow!("No state directory for the control socket")),
+                };
+                if started.is_err() {
+                    part.cancel().cancel();
+                }
+                control.started(started, now);
+            }
+            if store.is_due(now) {
+                store.started(state::nonblocking::check_store().await, now);
+            }
+            let health: HashMap<String, Health> = [
+                (control.name(), control.health()),
+                (coop.name(), coop.health()),
+                (responder.name(), responder.health()),
+                (store.name(), store.health()),
+            ]
+            .into_iter()
+            .filter_map(|(name, health)| Some((name.to_string(), health?)))
+            .collect();
+            if last_health.as_ref() != Some(&health) {
+                state::nonblocking::record_subsystems(health.clone()).await;
+                last_health = Some(health);
+            }
+        }
+
+        // Rate limiting, slower while the portal is congested
+        let interval = if Congestion::global().is_active() {
+            check_interval * congestion::SPACING_FACTOR
+        } else {
+            check_interval
+        };
+        // A session known to end before then is checked as it ends
+        let interval = match session_end(&state::nonblocking::load().await, &last_states) {
+            Some(left) => interval.min(left),
+            None => interval,
+        };
+        let elapsed = last_check.elapsed();
+        if batch.is_some() {
+            if !due_now {
+                tokio::select! {
+                    _ = tokio::time::sleep(batch_wait) => {}
+                    _ = stop.cancelled() => {}
+                }
+            }
+        } else if elapsed < interval && !due_now {
+            let changed = async {
+                match &watch {
+                    Some(watch) => watch.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::pin!(changed);
+            let wake = tokio::time::Instant::now() + (interval - elapsed);
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(wake) => break,
+                    _ = &mut changed => {
+                        tracing::debug!("NetworkManager reported a change, checking now");
+                        break;
+                    }
+                    _ = stop.cancelled() => break,
+                    Some(incoming) = control_rx.recv() => {
+                        if nudges.handle(incoming, &last_states, Some(next_check)).await {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if stop.is_cancelled() {
+            break;
+        }
+        last_check = std::time::Instant::now();
+        due_now = false;
+        if let Some((fresh, portals)) = nudges.reloaded.take() {
+            cfg = *fresh;
+            registry = portals;
+            check_interval = Duration::from_secs(cfg.global.check_interval);
+        }
+        if nudges.paused && nudges.waiting.is_empty() {
+            continue;
+        }
+        watchdog.busy();
+
+        // Every adapter associated to a configured WiFi is handled on its own
+        let active = match utils::nonblocking::active_wifi().await {
+            Ok(active) => active,
+            // A batch has no next check to wait for
+            Err(e) if batch.is_some() => return Err(e.context("Failed to check WiFi status")),
+            Err(e) => {
+                tracing::warn!("Failed to check WiFi status: {}", e);
+                continue;
+            }
+        };
+
+        // Denied and metered networks get nothing past the association check
+        let (active, refused) = daemon::partition_refused(&cfg, active).await;
+        left_alone.retain(|ssid| refused.iter().any(|(s, _)| s == ssid));
+        for (ssid, why) in refused {
+            if left_alone.insert(ssid.clone()) {
+                tracing::info!("Leaving '{}' alone: {}", ssid, why);
+            }
+        }
+        let (active, unknown): (Vec<_>, Vec<_>) = active
+            .into_iter()
+            .partition(|(_, ssid)| registry.has_ssid(ssid));
+
+        // Warn once per association about networks no portal handles
+        warned.retain(|ssid| unknown.iter().any(|(_, s)| s == ssid));
+        for (_, ssid) in unknown {
+            if active.is_empty() && !warned.contains(&ssid) {
+                warn_unknown_ssid(&registry, events, &ssid);
+                warned.insert(ssid);
+            }
+        }
+
+        let gone: Vec<String> = last_states
+            .keys()
+            .filter(|iface| !active.iter().any(|(i, _)| i == *iface))
+            .cloned()
+            .collect();
+        for iface in gone {
+            if let Some((ssid, state)) = last_states.remove(&iface) {
+                // The outage starts over when the network comes back
+                recovery.recovered(&ssid, unix_now());
+                if state != NetworkState::Offline {
+                    events.record(Event::StateChange {
+                        ssid: Some(ssid),
+                        from: Some(state),
+                        to: NetworkState::Offline,
+                    });
+                }
+            }
+        }
+        if active.is_empty() {
+            tracing::debug!("Not connected to any configured WiFi");
+        }
+        let pass = Pass {
+            cfg: &cfg,
+            locks,
+            events,
+            coop: coop.get(),
+            connectivity: &ProbeUrls,
+            settle: daemon::LOGIN_SETTLE,
+        };
+
+        let read_only = state::nonblocking::load().await.is_read_only(cfg.global.read_only);
+        if was_read_only != Some(read_only) {
+            if read_only {
+                tracing::info!("Read-only mode: checking and reporting, not logging in");
+            } else if was_read_only.is_some() {
+                tracing::info!("Read-only mode off, logging in again when needed");
+            }
+            was_read_only = Some(read_only);
+        }
+
+        for (iface, ssid) in &active {
+            // A session limited per MAC ends; a new MAC starts another
+            let portal_cfg = cfg.portals.iter().find(|p| p.ssids.contains(ssid));
+            let state = state::nonblocking::load().await;
+            let rotate = portal_cfg.filter(|p| {
+                !read_only && rotation::is_due(&cfg.mac_rotation, p, &state, ssid, unix_now())
+            });
+            if let Some(portal_cfg) = rotate {
+                tracing::info!("Session on '{}' is up, rotating the MAC...", ssid);
+                let identities = IdentityManager::load();
+                match rotation::rotate(portal_cfg, &identities, ssid, iface).await {
+                    Ok(mac) => {
+                        tracing::info!("'{}' now presents {}", ssid, mac);
+                        state::nonblocking::clear_backoff(ssid).await;
+                        match PortalRegistry::from_config(&cfg, &identities) {
+                            Ok(fresh) => registry = fresh,
+                            Err(e) => tracing::warn!("Failed to rebuild the portals: {:#}", e),
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to rotate the MAC on '{}': {:#}", ssid, e),
+                }
+            }
+
+            // Walking between buildings, the adapter can roam to another
+            // configured SSID in the middle of a login
+            let state = {
+                let check = pass.check(&mut registry, iface, ssid);
+                let moved = ssid_change(iface, ssid);
+                tokio::pin!(check, moved);
+                loop {
+                    tokio::select! {
+                        state = &mut check => break Ok(state),
+                        now = &mut moved => break Err(Some(now)),
+                        _ = stop.cancelled() => break Err(None),
+                        // Anything but `status` waits for the pass
+                        Some(incoming) = control_rx.recv() => {
+                            nudges.defer(incoming, &last_states).await
+                        }
+                    }
+                }
+            };
+            let state = match state {
+                Ok(state) => state,
+                Err(now) => {
+                    match now {
+                        Some(Some(now)) => tracing::info!(
+                            "{} moved from '{}' to '{}' mid-pass, starting over",
+                            iface,
+                            ssid,
+                            now
+                        ),
+                        Some(None) => tracing::info!("{} left '{}' mid-pass", iface, ssid),
+                        None => tracing::info!("Stopped mid-pass on '{}'", ssid),
+                    }
+                    if let Some(portal) = registry.find_for_ssid(ssid) {
+                        if let Err(e) = portal.reset() {
+                            tracing::warn!("Failed to reset '{}': {:#}", portal.name(), e);
+                        }
+                    }
+                    due_now = true;
+                    break;
+                }
+            };
+            track_state(events, &mut last_states, iface, ssid, state);
+            if let Some(coop) = coop.get() {
+                let peer_state = match state {
+                    NetworkState::Online => PeerState::Online,
+                    _ => PeerState::Captive,
+                };
+                let gateway = utils::nonblocking::default_gateway(Some(iface)).await;
+                coop.announce(ssid, peer_state, gateway).await;
+            }
+
+            let now = unix_now();
+            if state == NetworkState::Online {
+                if let Some(outage) = recovery.recovered(ssid, now) {
+                    tracing::info!("'{}' recovered after {}s without internet", ssid, outage);
+                }
+            } else if !read_only {
+                if let Some(step) = recovery.failing(ssid, now) {
+                    let outage = recovery.outage(ssid, now).unwrap_or_default();
+                    recover(&cfg, &mut registry, events, iface, ssid, step, outage).await;
+                }
+            }
+        }
+
+        if stop.is_cancelled() {
+            break;
+        }
+        let status = systemd_status(&last_states);
+        if notified.as_ref() != Some(&status) {
+            let ready = if notified.is_none() { "READY=1\n" } else { "" };
+            systemd::notify(&format!("{}STATUS={}", ready, status));
+            notified = Some(status);
+        }
+
+        if let Some(responder) = responder.get() {
+            responder.set_online(
+                last_states
+                    .values()
+                    .any(|(_, s)| *s == NetworkState::Online),
+            );
+        }
+
+        next_check = unix_now() + interval.saturating_sub(last_check.elapsed()).as_secs();
+        nudges.pass_done(&last_states, next_check).await;
+        for incoming in std::mem::take(&mut nudges.deferred) {
+            due_now |= nudges.handle(incoming, &last_states, Some(next_check)).await;
+        }
+        publish_next_action(&cfg, &last_states, next_check, &mut last_plan).await;
+
+        if let Some(ref mut left) = batch_left {
+            *left -= 1;
+            let next = last_plan.as_ref().filter(|_| *left > 0);
+            let within = cfg.global.check_interval;
+            match next.and_then(|next| next.login_within(unix_now(), within)) {
+                Some(wait) => batch_wait = Duration::from_secs(wait),
+                None => {
+                    let next = last_plan.as_ref().map(|next| next.describe(unix_now()));
+                    tracing::info!("Batch done, next: {}", next.unwrap_or_default());
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    systemd::notify("STOPPING=1");
+    // The cookies renewed since each login are what the next start resumes
+    for (ssid, state) in last_states.values() {
+        let Some(portal) = registry.find_for_ssid(ssid) else {
+            continue;
+        };
+        if let Some(session) = portal.session().filter(|_| *state == NetworkState::Online) {
+            state::nonblocking::refresh_session(ssid, portal.name(), session).await;
+        }
+    }
+    if last_health
+        .as_ref()
+        .is_some_and(|health| !health.is_empty())
+    {
+        state::nonblocking::record_subsystems(HashMap::new()).await;
+    }
+    state::nonblocking::record_paused(None).await;
+    for task in tasks.shutdown(tasks::GRACE).await {
+        tracing::warn!(
+            "{} did not stop within {}s, aborted",
+            task,
+            tasks::GRACE.as_secs()
+        );
+    }
+    tracing::info!("Stopped");
+    Ok(())
+}
+
+/// What `wimesh control` asked of the running daemon
+struct Nudges {
+    config_path: Option<PathBuf>,
+    /// Checks stopped by `pause`
+    paused: bool,
+    /// `login-now` clients, answered once the pass they asked for is done
+    waiting: Vec<tokio::sync::oneshot::Sender<Reply>>,
+    /// Requests that came during a pass, for after it
+    deferred: Vec<Incoming>,
+    /// What `reload` read, for the next pass to use
+    reloaded: Option<(Box<config::Config>, PortalRegistry)>,
+}
+
+impl Nudges {
+    async fn new(config_path: Option<&Path>) -> Self {
+        // A pause does not outlive the daemon that was paused
+        state::nonblocking::record_paused(None).await;
+        Self {
+            config_path: config_path.map(Path::to_path_buf),
+            paused: false,
+            waiting: Vec::new(),
+            deferred: Vec::new(),
+            reloaded: None,
+        }
+    }
+
+    /// Answer `incoming` between passes; whether a pass is due now
+    async fn handle(
+        &mut self,
+        incoming: Incoming,
+        last_states: &HashMap<String, (String, NetworkState)>,
+        next_check: Option<u64>,
+    ) -> bool {
+        let Incoming { request, reply } = incoming;
+        let (answer, due) = match request {
+            Request::Status => {
+                let answer = status_reply(last_states, self.paused, next_check).await;
+                (answer, false)
+            }
+            Request::Pause => {
+                if !self.paused {
+                    tracing::info!("Paused by `wimesh control`");
+                    state::nonblocking::record_paused(Some(unix_now())).await;
+                }
+                self.paused = true;
+                (
+                    Reply::ok("Paused, `wimesh control resume` checks again"),
+                    false,
+                )
+            }
+            Request::Resume => {
+                if self.paused {
+                    tracing::info!("Resumed by `wimesh control`");
+                    state::nonblocking::record_paused(None).await;
+                }
+                self.paused = false;
+                (Reply::ok("Checking again, starting now"), true)
+            }
+            Request::LoginNow => {
+                tracing::info!("`wimesh control login-now`, checking now");
+                if let Err(e) = state::nonblocking::reset_backoff(None).await {
+                    tracing::warn!("Failed to clear the backoff: {:#}", e);
+                }
+                self.waiting.push(reply);
+                return true;
+            }
+            Request::Reload => match self.reload() {
+                Ok(message) => (Reply::ok(message), true),
+                Err(e) => (
+                    Reply::error(format!("Kept the running config: {:#}", e)),
+                    false,
+                ),
+            },
+        };
+        let _ = reply.send(answer);
+        due
+    }
+
+    /// Take `incoming` during a pass: `status` at once, the rest after it
+    async fn defer(
+        &mut self,
+        incoming: Incoming,
+        last_states: &HashMap<String, (String, NetworkState)>,
+    ) {
+        match incoming.request {
+            Request::Status => {
+                let answer = status_reply(last_states, self.paused, None).await;
+                let _ = incoming.reply.send(answer);
+            }
+            _ => self.deferred.push(incoming),
+        }
+    }
+
+    /// Tell the `login-now` clients what the pass found
+    async fn pass_done(
+        &mut self,
+        last_states: &HashMap<String, (String, NetworkState)>,
+        next_check: u64,
+    ) {
+        for waiting in self.waiting.drain(..) {
+            let answer = status_reply(last_states, self.paused, Some(next_check)).await;
+            let _ = waiting.send(answer);
+        }
+    }
+
+    /// Read the config file again and build its portals, for the next pass
+    fn reload(&mut self) -> Result<String> {
+        let fresh = config::Config::load_from(self.config_path.as_deref())?;
+        let identities = IdentityManager::load();
+        let portals = PortalRegistry::from_config(&fresh, &identities)?;
+        apply_wifi_identities(&fresh, &identities);
+        for problem in fresh.problems() {
+            tracing::warn!("{}", problem);
+        }
+        tracing::info!("Reloaded the config, {} portal(s)", fresh.portals.len());
+        let message = format!(
+            "Reloaded, {} portal(s); changes to [logging], [storage], [dns], [connectivity], \
+             [capport], [responder], [coop] and [control] take a restart",
+            fresh.portals.len()
+        );
+        self.reloaded = Some((Box::new(fresh), portals));
+        Ok(message)
+    }
+}
+
+/// What the daemon sees, as a `STATUS=` line for systemd
+fn systemd_status(last_states: &HashMap<String, (String, NetworkState)>) -> String {
+    let mut networks: Vec<_> = last_states.iter().collect();
+    networks.sort_by(|a, b| a.0.cmp(b.0));
+    let networks: Vec<String> = networks
+        .iter()
+        .map(|(iface, (ssid, state))| format!("{}: '{}' {}", iface, ssid, state.as_str()))
+        .collect();
+    match networks.is_empty() {
+        true => "Not on any configured WiFi".to_string(),
+        false => networks.join(", "),
+    }
+}
+
+/// The daemon's answer to `status`: what each interface is on, and when it
+/// checks next (`None`: it is checking now)
+async fn status_reply(
+    last_states: &HashMap<String, (String, NetworkState)>,
+    paused: bool,
+    next_check: Option<u64>,
+) -> Reply {
+    let mut networks: Vec<_> = last_states.iter().collect();
+    networks.sort_by(|a, b| a.0.cmp(b.0));
+    let mut lines = vec![match next_check {
+        _ if paused => "Paused (`wimesh control resume`)".to_string(),
+        Some(at) => format!("Next check in {}s", at.saturating_sub(unix_now())),
+        None => "Checking now".to_string(),
+    }];
+    if networks.is_empty() {
+        lines.push("Not on any configured WiFi".to_string());
+    }
+    for (iface, (ssid, state)) in &networks {
+        lines.push(format!("{}: '{}' {}", iface, ssid, state.as_str()));
+    }
+    let last_login = state::nonblocking::load().await.last_login;
+    let networks: Vec<_> = networks
+        .iter()
+        .map(|(iface, (ssid, state))| {
+            serde_json::json!({
+                "interface": iface,
+                "ssid": ssid,
+                "state": state,
+                "last_login": last_login.get(ssid.as_str()),
+            })
+        })
+        .collect();
+    Reply {
+        status: Some(serde_json::json!({
+            "paused": paused,
+            "next_check": next_check.filter(|_| !paused),
+            "networks": networks,
+        })),
+        ..Reply::ok(lines.join("\n"))
+    }
+}
+
+/// Take the recovery `step` on `ssid`, associated through `iface` and
+/// without internet for `outage` seconds
+async fn recover(
+    cfg: &config::Config,
+    registry: &mut PortalRegistry,
+    events: &EventLog,
+    iface: &str,
+    ssid: &str,
+    step: Step,
+    outage: u64,
+) {
+    events.record(Event::Recovery {
+        ssid: ssid.to_string(),
+        step,
+        outage_secs: outage,
+    });
+    let rebuild = |registry: &mut PortalRegistry| match PortalRegistry::from_config(
+        cfg,
+        &IdentityManager::load(),
+    ) {
+        Ok(fresh) => *registry = fresh,
+        Err(e) => tracing::warn!("Failed to rebuild the portals: {:#}", e),
+    };
+    match step {
+        Step::FreshGateway => {
+            tracing::warn!(
+                "Recovery: '{}' captive for {}s, dropping its cached session so the next login \
+                 scans the gateway afresh",
+                ssid,
+                outage
+            );
+            state::nonblocking::forget_session(ssid).await;
+        }
+        Step::ClearCookies => {
+            tracing::warn!(
+                "Recovery: '{}' still captive, starting over with no cookies",
+                ssid
+            );
+            rebuild(registry);
+        }
+        Step::RotateMac => {
+            let portal_cfg = cfg
+                .portals
+                .iter()
+                .find(|p| p.ssids.iter().any(|s| s == ssid));
+            let rotated = portal_cfg.and_then(|p| Some((p, IdentityManager::load().rotate(p)?)));
+            let Some((portal_cfg, identity)) = rotated else {
+                tracing::info!(
+                    "Recovery: not rotating the MAC on '{}', its portal has no identity.auto",
+                    ssid
+                );
+                return;
+            };
+            tracing::warn!("Recovery: '{}' still captive, presenting a new MAC", ssid);
+            if portal_cfg.identity.apply_to_wifi {
+                let applied = utils::nonblocking::set_cloned_mac(ssid, &identity.mac_address);
+                if let Err(e) = applied.await {
+                    tracing::warn!("{:#}", e);
+                }
+            }
+            rebuild(registry);
+        }
+        Step::BounceInterface => {
+            tracing::warn!("Recovery: '{}' still captive, bouncing {}", ssid, iface);
+            if let Err(e) = utils::nonblocking::bounce_interface(iface).await {
+                tracing::warn!("{:#}", e);
+            }
+        }
+        Step::NeedsAttention => {
+            tracing::error!(
+                "'{}' has been captive for {}s and recovery ran out of steps: this needs a \
+                 human (portal changed? device blocked? check `wimesh doctor`). Retrying as \
+                 usual meanwhile",
+                ssid,
+                outage
+            );
+            return;
+        }
+    }
+    // Give the fresh start a login attempt right away
+    state::nonblocking::clear_backoff(ssid).await;
+}
+
+/// Plan the daemon's next action, log it when it changes, and publish it in
+/// the state file for `wimesh status`
+async fn publish_next_action(
+    cfg: &config::Config,
+    last_states: &HashMap<String, (String, NetworkState)>,
+    next_check: u64,
+    last_plan: &mut Option<NextAction>,
+) {
+    let state = state::nonblocking::load().await;
+    // Read-only, captive networks only get checked again
+    let read_only = state.is_read_only(cfg.global.read_only);
+    let networks: Vec<(String, NetworkState)> = last_states
+        .values()
+        .filter(|(_, network)| !(read_only && *network == NetworkState::Captive))
+        .cloned()
+        .collect();
+    let next = status::plan_next_action(cfg, &state, &networks, next_check);
+
+    let changed = last_plan
+        .as_ref()
+        .is_none_or(|last| (last.action, &last.ssid) != (next.action, &next.ssid));
+    if changed {
+        let line = next.describe(unix_now());
+        match next.action {
+            Action::Probe => tracing::debug!("Next: {}", line),
+            _ => tracing::info!("Next: {}", line),
+        }
+    }
+    state::nonblocking::record_next_action(next.clone()).await;
+    *last_plan = Some(next);
+}
+
+/// Time until the first session on an online SSID of `last_states` ends,
+/// as its portal or Captive Portal API reported it
+fn session_end(
+    state: &State,
+    last_states: &HashMap<String, (String, NetworkState)>,
+) -> Option<Duration> {
+    let now = unix_now();
+    last_states
+        .values()
+        .filter(|(_, network)| *network == NetworkState::Online)
+        .filter_map(|(ssid, _)| state.session_info.get(ssid)?.expires_at)
+        .filter(|&at| at > now)
+        .min()
+        .map(|at| Duration::from_secs(at - now + 1))
+}
+
+/// Wait until `iface` is no longer associated to `ssid`, returning what it
+/// is associated to now
+async fn ssid_change(iface: &str, ssid: &str) -> Option<String> {
+    loop {
+        tokio::time::sleep(SSID_WATCH_INTERVAL).await;
+        let Ok(active) = utils::nonblocking::active_wifi().await else {
+            continue;
+        };
+        let now = active.into_iter().find(|(i, _)| i == iface).map(|(_, s)| s);
+        if now.as_deref() != Some(ssid) {
+            return now;
+        }
+    }
+}
+
+/// Record a state change event when the state of `interface` differs from
+/// the last one seen there
+fn track_state(
+    events: &EventLog,
+    last: &mut HashMap<String, (String, NetworkState)>,
+    interface: &str,
+    ssid: &str,
+    state: NetworkState,
+) {
+    let from = last.get(interface).map(|(_, state)| *state);
+    if from != Some(state) {
+        events.record(Event::StateChange {
+            ssid: Some(ssid.to_string()),
+            from,
+            to: state,
+        });
+    }
+    last.insert(interface.to_string(), (ssid.to_string(), state));
+}
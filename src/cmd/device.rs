@@ -0,0 +1,236 @@
+//! Logging in another device on the connected WiFi
+
+use anyhow::{Context, Result};
+use std::io::IsTerminal;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use wimesh::audit::{self, AuditLog};
+use wimesh::companion::{Companion, Device};
+use wimesh::identity::IdentityManager;
+use wimesh::lock::LoginLocks;
+use wimesh::portal::{self, NoPortalForSsid};
+use wimesh::qr::QrCode;
+use wimesh::{config, utils};
+
+/// Where `authorize-device` logs devices in
+struct DeviceTarget<'a> {
+    portal: &'a config::PortalConfig,
+    interface: Option<&'a str>,
+    ssid: String,
+}
+
+/// Log in another device on the connected WiFi: run the portal flow with
+/// its MAC, picked from the neighbor table unless given, then have the
+/// user confirm it got online. With `phone` (listen address, minutes) the
+/// MAC is picked on a page served for a phone instead
+pub(crate) async fn authorize_device(
+    cfg: &config::Config,
+    locks: &LoginLocks,
+    mac: Option<String>,
+    portal: Option<&str>,
+    phone: Option<(&str, u64)>,
+) -> Result<()> {
+    let active = utils::nonblocking::active_wifi().await?;
+    let association = active
+        .iter()
+        .find(|(_, ssid)| cfg.portals.iter().any(|p| p.ssids.contains(ssid)));
+    let portal_cfg = match portal {
+        Some(name) => cfg
+            .portals
+            .iter()
+            .find(|p| p.name == name)
+            .with_context(|| {
+                let names: Vec<&str> = cfg.portals.iter().map(|p| p.name.as_str()).collect();
+                format!(
+                    "No portal named '{}' (configured: {})",
+                    name,
+                    names.join(", ")
+                )
+            })?,
+        None => {
+            let (_, ssid) = association.context(
+                "Not connected to any configured WiFi network, connect or name one with --portal",
+            )?;
+            cfg.portals
+                .iter()
+                .find(|p| p.ssids.contains(ssid))
+                .ok_or_else(|| NoPortalForSsid(ssid.clone()))?
+        }
+    };
+    let (interface, ssid) = match association {
+        Some((interface, ssid)) if portal_cfg.ssids.contains(ssid) => {
+            (Some(interface.as_str()), ssid.clone())
+        }
+        _ => (None, portal_cfg.ssids.first().cloned().unwrap_or_default()),
+    };
+    let target = DeviceTarget {
+        portal: portal_cfg,
+        interface,
+        ssid,
+    };
+    let mac = match mac {
+        Some(mac) => Some(
+            utils::normalize_mac(&mac)
+                .with_context(|| format!("'{}' is not a MAC address", mac))?,
+        ),
+        None => None,
+    };
+    if let Some((listen, minutes)) = phone {
+        return authorize_from_phone(cfg, locks, &target, mac, listen, minutes).await;
+    }
+
+    let mac = match mac {
+        Some(mac) => mac,
+        None => pick_device(interface).await?,
+    };
+    println!("Logging in {} through '{}'...", mac, portal_cfg.name);
+    login_device(cfg, locks, &target, &mac).await?;
+
+    println!("The portal accepted {}", mac);
+    if !std::io::stdin().is_terminal() {
+        return Ok(());
+    }
+    eprint!("Open a page on the device. Is it online now? [Y/n] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("n") {
+        anyhow::bail!(
+            "{} is still offline: reconnect its WiFi so it asks for a new address, \
+             then run this again",
+            mac
+        );
+    }
+    println!("{} is online", mac);
+    Ok(())
+}
+
+/// Run the portal flow of `target` with `mac` in place of ours, and record
+/// it in the audit log
+async fn login_device(
+    cfg: &config::Config,
+    locks: &LoginLocks,
+    target: &DeviceTarget<'_>,
+    mac: &str,
+) -> Result<()> {
+    // The device's MAC as the serial, and none of our own identity
+    let mut device_cfg = target.portal.clone();
+    device_cfg.mac_address = mac.to_string();
+    device_cfg.identity.auto = false;
+    device_cfg.identity.apply_to_wifi = false;
+    let mut portal = portal::build(cfg, &device_cfg, &IdentityManager::default())?
+        .with_context(|| portal::missing_type(&device_cfg.portal_type))?;
+
+    let key = target.interface.unwrap_or(wimesh::lock::DEFAULT_KEY);
+    let timeout = Duration::from_secs(cfg.global.login_lock_timeout);
+    let result = {
+        let _guard = locks.acquire(key, timeout).await?;
+        portal.bind_interface(target.interface)?;
+        portal.connect().await
+    };
+    AuditLog::new(&cfg.audit).record(audit::Attempt {
+        ssid: &target.ssid,
+        portal: portal.name(),
+        mac: Some(mac.to_string()),
+        interface: target.interface,
+        resumed: false,
+        ok: result.is_ok(),
+    });
+    result.with_context(|| format!("The portal did not log in {}", mac))
+}
+
+/// Serve the companion page for `minutes` and log in the device picked on
+/// it (only `mac` when given)
+async fn authorize_from_phone(
+    cfg: &config::Config,
+    locks: &LoginLocks,
+    target: &DeviceTarget<'_>,
+    mac: Option<String>,
+    listen: &str,
+    minutes: u64,
+) -> Result<()> {
+    let interface = target
+        .interface
+        .context("Not connected to the portal's WiFi, which the phone has to be on too")?;
+    let address = utils::nonblocking::interface_addresses(interface)
+        .await
+        .and_then(|addrs| addrs.first().copied())
+        .with_context(|| format!("{} has no IPv4 address to serve the page on", interface))?;
+    let companion = Companion::bind(listen, address, Duration::from_secs(minutes * 60)).await?;
+
+    print!(
+        "{}",
+        QrCode::encode(companion.url().as_bytes())?.to_terminal()
+    );
+    println!(
+        "Scan this with a phone on '{}', or open {}",
+        target.ssid,
+        companion.url()
+    );
+    println!(
+        "The page closes in {} minutes, or once a device is authorized",
+        minutes
+    );
+
+    let devices = || async {
+        match mac {
+            Some(ref mac) => vec![Device {
+                mac: mac.clone(),
+                address: None,
+            }],
+            None => lan_devices(Some(interface))
+                .await
+                .into_iter()
+                .map(|(address, mac)| Device {
+                    mac,
+                    address: Some(address),
+                })
+                .collect(),
+        }
+    };
+    let authorize = |mac: String| async move { login_device(cfg, locks, target, &mac).await };
+    match companion.serve(devices, authorize).await? {
+        Some(mac) => {
+            println!("The portal accepted {}", mac);
+            Ok(())
+        }
+        None => anyhow::bail!("No device was authorized within {} minutes", minutes),
+    }
+}
+
+/// Devices seen on `interface`'s network, other than the gateway
+async fn lan_devices(interface: Option<&str>) -> Vec<(Ipv4Addr, String)> {
+    let Some(interface) = interface else {
+        return Vec::new();
+    };
+    let gateway = utils::nonblocking::default_gateway(Some(interface)).await;
+    utils::nonblocking::neighbors(interface)
+        .await
+        .into_iter()
+        .filter(|(addr, _)| gateway != Some(IpAddr::V4(*addr)))
+        .collect()
+}
+
+/// Ask which of the devices seen on `interface` to log in, or for a MAC
+async fn pick_device(interface: Option<&str>) -> Result<String> {
+    let devices = lan_devices(interface).await;
+    if devices.is_empty() {
+        eprintln!("No other devices seen on the WiFi yet (connect the device, or ping it)");
+    } else {
+        eprintln!("Devices seen on the WiFi:");
+        for (n, (addr, mac)) in devices.iter().enumerate() {
+            eprintln!("  {}) {}  {}", n + 1, mac, addr);
+        }
+    }
+    eprint!("Number or MAC address of the device (it is on the device's network settings): ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    if let Some((_, mac)) = answer
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| devices.get(n.checked_sub(1)?))
+    {
+        return Ok(mac.clone());
+    }
+    utils::normalize_mac(answer).with_context(|| format!("'{}' is not a MAC address", answer))
+}
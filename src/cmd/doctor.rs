@@ -0,0 +1,224 @@
+//! Checking the machine: `probe`, `doctor`, `capabilities`
+
+use crate::OutputFormat;
+use anyhow::Result;
+use std::net::IpAddr;
+use wimesh::daemon::GATEWAY_PROBE_TIMEOUT;
+use wimesh::{config, utils};
+
+/// Show what every connectivity check sees, and what it makes of it
+pub(crate) async fn probe(
+    cfg: &config::Config,
+    interface: Option<String>,
+    output: OutputFormat,
+) -> Result<()> {
+    let ssids: Vec<String> = cfg.all_ssids().iter().map(|s| s.to_string()).collect();
+    let active = utils::nonblocking::active_wifi().await.unwrap_or_default();
+    let association = active.into_iter().find(|(iface, ssid)| match interface {
+        Some(ref wanted) => iface == wanted,
+        None => ssids.contains(ssid),
+    });
+    let interface = interface.or_else(|| association.as_ref().map(|(iface, _)| iface.clone()));
+
+    let gateway = utils::nonblocking::default_gateway(interface.as_deref()).await;
+    let gateway_ok = match gateway {
+        Some(gateway) => Some(utils::gateway_reachable(gateway, GATEWAY_PROBE_TIMEOUT).await),
+        None => None,
+    };
+
+    let mut results = Vec::new();
+    for target in wimesh::probe::targets() {
+        results.push(wimesh::probe::run(target, interface.as_deref()).await);
+    }
+    let verdict = wimesh::probe::verdict(&results);
+    let daemon_check = utils::nonblocking::has_internet_connectivity_on(interface.as_deref()).await;
+    let capport_uri = wimesh::capport::api_uri(interface.as_deref()).await;
+    let capport = match capport_uri {
+        Some(ref uri) => Some(wimesh::capport::query(uri, interface.as_deref()).await),
+        None => None,
+    };
+
+    if output == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "interface": interface,
+                "ssid": association.as_ref().map(|(_, ssid)| ssid),
+                "gateway": gateway,
+                "gateway_reachable": gateway_ok,
+                "probes": results,
+                "verdict": verdict,
+                "daemon_check_online": daemon_check,
+                "capport_uri": capport_uri,
+                "capport": capport.as_ref().and_then(|status| status.as_ref().ok()),
+            })
+        );
+        return Ok(());
+    }
+
+    match association {
+        Some((ref iface, ref ssid)) => println!("WiFi      {} on '{}'", iface, ssid),
+        None => println!("WiFi      not on a configured SSID"),
+    }
+    match (gateway, gateway_ok) {
+        (Some(gateway), Some(true)) => println!("Gateway   {} answers", gateway),
+        (Some(gateway), _) => println!("Gateway   {} does not answer", gateway),
+        (None, _) => println!("Gateway   no default route"),
+    }
+    match (&capport_uri, &capport) {
+        (Some(uri), Some(Ok(status))) => println!("API       {}: {}", uri, status.describe()),
+        (Some(uri), Some(Err(e))) => println!("API       {} failed: {:#}", uri, e),
+        _ => println!("API       none advertised (RFC 8908)"),
+    }
+    for result in &results {
+        println!();
+        println!("{}", result.url);
+        let dns: Vec<String> = result.dns.iter().map(IpAddr::to_string).collect();
+        println!(
+            "  dns      {}",
+            if dns.is_empty() {
+                "-".to_string()
+            } else {
+                dns.join(", ")
+            }
+        );
+        if let Some(status) = result.status {
+            match result.redirect {
+                Some(ref location) => println!("  status   {} -> {}", status, location),
+                None => println!("  status   {}", status),
+            }
+            println!("  latency  {}ms", result.latency_ms);
+        }
+        if let Some(ref error) = result.error {
+            println!("  error    {}", error);
+        }
+        println!("  result   {}", result.classification.as_str());
+    }
+    println!();
+    println!(
+        "Verdict: {} (the daemon's check says {})",
+        verdict.as_str(),
+        if daemon_check { "online" } else { "offline" }
+    );
+    Ok(())
+}
+
+/// One line of `wimesh doctor`
+#[derive(serde::Serialize)]
+struct Check {
+    check: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+/// Print the support matrix of this build on this machine
+pub(crate) fn capabilities(output: OutputFormat) -> Result<()> {
+    let matrix = wimesh::capabilities::matrix();
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&matrix)?);
+        return Ok(());
+    }
+    for c in &matrix {
+        let mark = match (c.compiled, c.usable) {
+            (_, true) => "✓",
+            (true, false) => "✗",
+            (false, false) => "-",
+        };
+        println!("{} {:12}  {:16}  {}", mark, c.kind, c.name, c.detail);
+    }
+    Ok(())
+}
+
+/// Run the checks a login depends on, in the order they build on each other
+pub(crate) async fn doctor(cfg: &config::Config, output: OutputFormat) -> Result<()> {
+    let mut checks = Vec::new();
+    let mut check = |check, ok, detail: String| checks.push(Check { check, ok, detail });
+
+    match config::Config::find() {
+        Some(path) => check("config", true, path.display().to_string()),
+        None => check("config", true, "no file found, using defaults".to_string()),
+    }
+    let problems = cfg.problems();
+    check(
+        "portals",
+        problems.is_empty(),
+        if problems.is_empty() {
+            format!("{} configured", cfg.portals.len())
+        } else {
+            problems.join("; ")
+        },
+    );
+
+    for tool in utils::wifi_tools() {
+        match utils::find_in_path(tool) {
+            Some(path) => check("tools", true, path.display().to_string()),
+            None => check("tools", false, format!("{} not found in PATH", tool)),
+        }
+    }
+
+    let state_dir = wimesh::state::state_dirs().into_iter().next();
+    let writable = state_dir.as_deref().is_some_and(|dir| {
+        let probe = dir.join(".doctor");
+        std::fs::create_dir_all(dir).is_ok()
+            && std::fs::write(&probe, b"").is_ok()
+            && std::fs::remove_file(&probe).is_ok()
+    });
+    let dir_name = state_dir
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_else(|| "none".to_string());
+    check("state", writable, dir_name);
+
+    let ssids: Vec<String> = cfg.all_ssids().iter().map(|s| s.to_string()).collect();
+    let connected = match utils::nonblocking::is_connected_to_wifi(&ssids).await {
+        Ok(Some(ssid)) => {
+            check("wifi", true, format!("connected to {}", ssid));
+            Some(ssid)
+        }
+        Ok(None) => {
+            check("wifi", false, "not on a configured SSID".to_string());
+            None
+        }
+        Err(e) => {
+            check("wifi", false, format!("{:#}", e));
+            None
+        }
+    };
+
+    if let Some(ref ssid) = connected {
+        let interface = utils::nonblocking::wifi_interface_for_ssid(ssid).await;
+        match utils::nonblocking::default_gateway(interface.as_deref()).await {
+            Some(gateway) => {
+                let ok = utils::gateway_reachable(gateway, GATEWAY_PROBE_TIMEOUT).await;
+                let verb = if ok { "answers" } else { "does not answer" };
+                check("gateway", ok, format!("{} {}", gateway, verb));
+            }
+            None => check("gateway", false, "no default route".to_string()),
+        }
+        let online = utils::nonblocking::has_internet_connectivity().await;
+        let detail = if online {
+            "reachable"
+        } else {
+            "not reachable, the portal is holding traffic"
+        };
+        check("internet", online, detail.to_string());
+    }
+
+    let failed = checks.iter().filter(|c| !c.ok).count();
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&checks)?);
+    } else {
+        for c in &checks {
+            println!(
+                "{} {:8}  {}",
+                if c.ok { "✓" } else { "✗" },
+                c.check,
+                c.detail
+            );
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! What happened: the event history, the daemon's log and the audit log
+
+use super::ago;
+use crate::OutputFormat;
+use anyhow::{Context, Result};
+use std::path::Path;
+use wimesh::audit::{self, AuditLog};
+use wimesh::events::{read_all as read_events, EventLog, EventRecord};
+use wimesh::logs::{LogFilter, LogLine, LogSource};
+use wimesh::state::unix_now;
+use wimesh::{config, utils};
+
+/// Print the last `limit` events, optionally only those about `ssid`
+pub(crate) fn history(
+    cfg: &config::Config,
+    limit: usize,
+    ssid: Option<&str>,
+    output: OutputFormat,
+) -> Result<()> {
+    let events = EventLog::new(&cfg.events);
+    let path = events
+        .path()
+        .context("The event log is disabled ([events] enabled)")?;
+
+    let mut records: Vec<EventRecord> = read_events(path)
+        .into_iter()
+        .filter(|r| ssid.is_none() || r.event.ssid() == ssid)
+        .collect();
+    records.drain(..records.len().saturating_sub(limit));
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&records)?);
+        return Ok(());
+    }
+    if records.is_empty() {
+        println!("No events in {}", path.display());
+    }
+    for record in records {
+        println!(
+            "{:>8}  {}",
+            format!("{} ago", ago(record.ts)),
+            record.event.describe()
+        );
+    }
+    Ok(())
+}
+
+/// What `wimesh logs` keeps: `portal` stands for its name and SSIDs
+pub(crate) fn log_filter(
+    cfg: &config::Config,
+    level: Option<tracing::Level>,
+    portal: Option<&str>,
+    since: Option<&str>,
+) -> Result<LogFilter> {
+    let mut mentions = Vec::new();
+    if let Some(name) = portal {
+        mentions.push(name.to_string());
+        if let Some(portal) = cfg.portals.iter().find(|p| p.name == name) {
+            mentions.extend(portal.ssids.iter().cloned());
+        }
+    }
+    let since = match since {
+        Some(since) => Some(wimesh::logs::parse_since(
+            since,
+            unix_now(),
+            utils::utc_offset(),
+        )?),
+        None => None,
+    };
+    Ok(LogFilter {
+        level,
+        mentions,
+        since,
+    })
+}
+
+/// Print the daemon's log lines kept by `filter`, the last `limit` of them,
+/// then with `follow` the new ones as they come
+pub(crate) async fn logs(
+    source: &LogSource,
+    filter: &LogFilter,
+    limit: usize,
+    follow: bool,
+    output: OutputFormat,
+) -> Result<()> {
+    let utc_offset = utils::utc_offset();
+    let print = |line: &LogLine| match output {
+        OutputFormat::Json => match serde_json::to_string(line) {
+            Ok(json) => println!("{}", json),
+            Err(e) => tracing::warn!("Failed to encode a log line: {}", e),
+        },
+        OutputFormat::Text => println!("{}", line.describe(utc_offset)),
+    };
+    let lines = wimesh::logs::read(source, filter, limit)?;
+    if lines.is_empty() && !follow && output == OutputFormat::Text {
+        println!("No matching lines in {}", source.describe());
+    }
+    lines.iter().for_each(print);
+    if follow {
+        wimesh::logs::follow(source, filter, print).await?;
+    }
+    Ok(())
+}
+
+/// Walk the hash chain of the audit log, failing at the first broken entry
+pub(crate) fn verify_audit(
+    cfg: &config::Config,
+    file: Option<&Path>,
+    output: OutputFormat,
+) -> Result<()> {
+    let audit = AuditLog::new(&cfg.audit);
+    let path = match file {
+        Some(file) => file,
+        None => audit
+            .path()
+            .context("The audit log is disabled ([audit] enabled)")?,
+    };
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let verification = audit::verify(&content);
+
+    if output == OutputFormat::Json {
+        let mut json = serde_json::to_value(&verification)?;
+        json["ok"] = serde_json::json!(verification.ok());
+        println!("{}", json);
+    } else if verification.ok() {
+        let span = match (verification.first_ts, verification.last_ts) {
+            (Some(first), Some(last)) => format!(", {} ago to {} ago", ago(first), ago(last)),
+            _ => String::new(),
+        };
+        println!(
+            "{}: {} entries, chain intact{}",
+            path.display(),
+            verification.entries,
+            span
+        );
+    }
+    if let Some(ref problem) = verification.problem {
+        anyhow::bail!(
+            "{}: entry on line {} {} ({} intact before it)",
+            path.display(),
+            verification.broken_at.unwrap_or_default(),
+            problem,
+            verification.entries
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Logging in once, out, and with a new MAC
+
+use crate::OutputFormat;
+use anyhow::{Context, Result};
+use wimesh::daemon;
+use wimesh::events::{Event, EventLog};
+use wimesh::identity::IdentityManager;
+use wimesh::lock::LoginLocks;
+use wimesh::login;
+use wimesh::portal::{NoPortalForSsid, PortalRegistry};
+use wimesh::progress::Progress;
+use wimesh::report::{Outcome, RunReport};
+use wimesh::rotation;
+use wimesh::state;
+use wimesh::suggest::SsidSuggestion;
+use wimesh::{config, utils};
+
+/// Run once - try to connect using the first available portal, or the one
+/// named `portal`
+pub(crate) async fn run_once(
+    cfg: &config::Config,
+    registry: &mut PortalRegistry,
+    locks: &LoginLocks,
+    events: &EventLog,
+    portal: Option<&str>,
+    output: OutputFormat,
+    progress: Option<&Progress>,
+) -> Result<()> {
+    let mut report = RunReport::new();
+    let result = login_once(cfg, registry, locks, events, portal, &mut report).await;
+    report.finish(&result);
+    if let Some(progress) = progress {
+        progress.finish(&result, &report);
+    }
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&report)?);
+    }
+
+    result.map(|_| ())
+}
+
+/// One detection + login pass, filling `report` as it goes
+async fn login_once(
+    cfg: &config::Config,
+    registry: &mut PortalRegistry,
+    locks: &LoginLocks,
+    events: &EventLog,
+    portal_name: Option<&str>,
+    report: &mut RunReport,
+) -> Result<Outcome> {
+    // Check current WiFi and find matching portal
+    let all_ssids: Vec<String> = registry.all_ssids().iter().map(|s| s.to_string()).collect();
+    let forced = match portal_name {
+        Some(name) => Some(
+            cfg.portals
+                .iter()
+                .find(|p| p.name == name)
+                .with_context(|| {
+                    let names: Vec<&str> = cfg.portals.iter().map(|p| p.name.as_str()).collect();
+                    format!(
+                        "No portal named '{}' (configured: {})",
+                        name,
+                        names.join(", ")
+                    )
+                })?,
+        ),
+        None => None,
+    };
+
+    let active = match utils::nonblocking::active_wifi().await {
+        Ok(active) => active,
+        // A named portal needs no SSID to log in
+        Err(e) if forced.is_some() => {
+            tracing::warn!("Failed to check WiFi status: {}", e);
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to check WiFi status: {}", e);
+            return Err(e);
+        }
+    };
+    let (active, refused) = daemon::partition_refused(cfg, active).await;
+    let association = match forced {
+        // Only denied or metered networks around: still refused
+        Some(_) if active.is_empty() && !refused.is_empty() => None,
+        Some(portal_cfg) => Some(forced_association(portal_cfg, &active)),
+        None => active
+            .iter()
+            .find(|(_, ssid)| registry.has_ssid(ssid))
+            .cloned(),
+    };
+    let Some((interface, connected_ssid)) = association else {
+        if let Some((ssid, why)) = refused.into_iter().next() {
+            report.ssid = Some(ssid.clone());
+            anyhow::bail!("Refusing to log in on '{}': {}", ssid, why);
+        }
+        tracing::warn!("Not connected to any configured WiFi network");
+        tracing::info!("Configured SSIDs: {}", all_ssids.join(", "));
+        if let Some((_, ssid)) = active.first() {
+            report.ssid = Some(ssid.clone());
+            report.suggestion = warn_unknown_ssid(registry, events, ssid);
+        }
+        return Ok(Outcome::NotConnected);
+    };
+
+    if !interface.is_empty() {
+        tracing::info!("Connected to: {} ({})", connected_ssid, interface);
+    }
+    report.ssid = Some(connected_ssid.clone());
+
+    let portal = match portal_name {
+        Some(name) => registry
+            .find_by_name(name)
+            .with_context(|| format!("Portal '{}' is not built into this binary", name))?,
+        None => registry
+            .find_for_ssid(&connected_ssid)
+            .ok_or_else(|| NoPortalForSsid(connected_ssid.clone()))?,
+    };
+    tracing::info!("Using portal: {}", portal.name());
+    report.portal = Some(portal.name().to_string());
+
+    let interface = Some(interface.as_str()).filter(|i| !i.is_empty());
+    let problem = match interface {
+        Some(interface) => utils::nonblocking::interface_address_problem(interface).await,
+        None => None,
+    };
+    if let Some(problem) = problem {
+        tracing::warn!(
+            "No usable IP on {}: {}, not attempting login",
+            connected_ssid,
+            problem
+        );
+        return Ok(Outcome::NoAddress);
+    }
+    if let Some(gateway) = daemon::unreachable_gateway(cfg, events, interface).await {
+        tracing::warn!(
+            "Gateway {} does not answer, not attempting login (AP uplink down?)",
+            gateway
+        );
+        return Ok(Outcome::GatewayUnreachable);
+    }
+
+    let result =
+        login::locked_connect(cfg, locks, events, &connected_ssid, interface, portal).await;
+    report.steps = portal.last_steps().to_vec();
+
+    match result {
+        Ok(_) => {
+            tracing::info!("Connection established!");
+            if let Some(interface) = interface {
+                report.route_warning = daemon::check_default_route(interface).await;
+            }
+            Ok(Outcome::connected(&report.steps))
+        }
+        Err(e) => {
+            tracing::error!("Connection failed: {:#}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Where `login --portal` logs in through `portal_cfg`, whatever SSID the
+/// adapters report: the adapter on one of its SSIDs, else the first one
+/// associated (none when there is none), under the SSID reported if the
+/// portal has it and the portal's first one otherwise
+fn forced_association(
+    portal_cfg: &config::PortalConfig,
+    active: &[(String, String)],
+) -> (String, String) {
+    let (interface, reported) = active
+        .iter()
+        .find(|(_, ssid)| portal_cfg.ssids.contains(ssid))
+        .or_else(|| active.first())
+        .cloned()
+        .unwrap_or_default();
+    if portal_cfg.ssids.contains(&reported) {
+        return (interface, reported);
+    }
+    match interface.is_empty() {
+        true => tracing::warn!("No WiFi association reported, logging in anyway"),
+        false => tracing::warn!(
+            "{} reports '{}', logging in through '{}' anyway",
+            interface,
+            reported,
+            portal_cfg.name
+        ),
+    }
+    let ssid = portal_cfg.ssids.first().cloned().unwrap_or(reported);
+    (interface, ssid)
+}
+
+/// Warn about being associated to `ssid`, which no portal handles, naming
+/// the configured SSID it was probably meant to be
+pub(crate) fn warn_unknown_ssid(
+    registry: &PortalRegistry,
+    events: &EventLog,
+    ssid: &str,
+) -> Option<SsidSuggestion> {
+    let suggestion = registry.suggest_ssid(ssid);
+    match suggestion {
+        Some(ref suggestion) => {
+            tracing::warn!(
+                ssid,
+                suggestion = %suggestion.ssid,
+                hint = suggestion.hint,
+                "{}",
+                suggestion
+            );
+            if let Some(ref portal) = suggestion.portal {
+                tracing::info!(
+                    "If '{}' is the same network, run: wimesh adopt --portal '{}'",
+                    ssid,
+                    portal
+                );
+            }
+        }
+        None => tracing::warn!(ssid, "No portal is configured for '{}'", ssid),
+    }
+    events.record(Event::UnknownSsid {
+        ssid: ssid.to_string(),
+        suggestion: suggestion.as_ref().map(|s| s.ssid.clone()),
+        hint: suggestion.as_ref().and_then(|s| s.hint).map(str::to_string),
+    });
+    suggestion
+}
+
+/// Log out of the portal of the connected SSID
+pub(crate) async fn logout(registry: &mut PortalRegistry) -> Result<()> {
+    let all_ssids: Vec<String> = registry.all_ssids().iter().map(|s| s.to_string()).collect();
+    let ssid = utils::nonblocking::is_connected_to_wifi(&all_ssids)
+        .await?
+        .context("Not connected to any configured WiFi network")?;
+    let portal = registry
+        .find_for_ssid(&ssid)
+        .ok_or_else(|| NoPortalForSsid(ssid.clone()))?;
+    if !portal.capabilities().supports_logout {
+        anyhow::bail!("Portal '{}' does not support logout", portal.name());
+    }
+
+    portal.logout().await?;
+    state::nonblocking::forget_session(&ssid).await;
+    println!("Logged out of '{}' on {}", portal.name(), ssid);
+    Ok(())
+}
+
+/// Rotate the MAC on `ssid`, or the connected configured network, then log
+/// in with the new one
+pub(crate) async fn rotate_mac(
+    cfg: &config::Config,
+    ssid: Option<&str>,
+    output: OutputFormat,
+) -> Result<()> {
+    let registry = PortalRegistry::from_config(cfg, &IdentityManager::load())?;
+    let active = utils::nonblocking::active_wifi().await?;
+    let (iface, ssid) = active
+        .into_iter()
+        .find(|(_, current)| match ssid {
+            Some(ssid) => current == ssid,
+            None => registry.has_ssid(current),
+        })
+        .with_context(|| match ssid {
+            Some(ssid) => format!("Not connected to '{}'", ssid),
+            None => "Not connected to any configured WiFi".to_string(),
+        })?;
+    let portal_cfg = cfg
+        .portals
+        .iter()
+        .find(|p| p.ssids.contains(&ssid))
+        .with_context(|| format!("No portal configured for '{}'", ssid))?;
+
+    let identities = IdentityManager::load();
+    tracing::info!("Rotating the MAC on '{}', reconnecting {}...", ssid, iface);
+    let mac = rotation::rotate(portal_cfg, &identities, &ssid, &iface).await?;
+    tracing::info!("'{}' now presents {}", ssid, mac);
+    state::nonblocking::clear_backoff(&ssid).await;
+
+    let mut registry = PortalRegistry::from_config(cfg, &identities)?;
+    let events = EventLog::new(&cfg.events);
+    run_once(
+        cfg,
+        &mut registry,
+        &LoginLocks::new(),
+        &events,
+        None,
+        output,
+        None,
+    )
+    .await
+}
@@ -0,0 +1,33 @@
+//! The subcommands, one module per group of them
+
+pub(crate) mod bench;
+pub(crate) mod config;
+pub(crate) mod control;
+pub(crate) mod daemon;
+pub(crate) mod device;
+pub(crate) mod doctor;
+pub(crate) mod history;
+pub(crate) mod login;
+pub(crate) mod portal;
+pub(crate) mod remote;
+pub(crate) mod secret;
+pub(crate) mod service;
+pub(crate) mod status;
+
+use wimesh::state::unix_now;
+
+pub(crate) fn banner() {
+    tracing::info!("Wimesh v0.2.0 - Captive Portal Auto Login");
+    tracing::info!("==========================================");
+}
+
+/// Compact time since the Unix time `ts`: 45s, 12m, 3h, 2d
+fn ago(ts: u64) -> String {
+    let secs = unix_now().saturating_sub(ts);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
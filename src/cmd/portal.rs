@@ -0,0 +1,157 @@
+//! Portal entries: testing their parsers, sharing them, adopting SSIDs
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use wimesh::portal::{self, PortalRegistry};
+use wimesh::{config, utils};
+
+/// Print the fields a portal's parser stages extract, without logging in
+pub(crate) async fn test_portal(
+    registry: &mut PortalRegistry,
+    name: &str,
+    file: Option<&Path>,
+) -> Result<()> {
+    let names = registry.names().join(", ");
+    let portal = registry
+        .find_by_name(name)
+        .with_context(|| format!("No portal named '{}' (configured: {})", name, names))?;
+    if !portal.capabilities().supports_inspect {
+        anyhow::bail!("Portal '{}' does not support test-portal", name);
+    }
+
+    let fixture = file
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+        })
+        .transpose()?;
+
+    let fields = portal.inspect(fixture.as_deref()).await?;
+    let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in fields {
+        println!("{:width$}  {}", key, value, width = width);
+    }
+
+    Ok(())
+}
+
+/// Add `ssid`, or the unconfigured SSID associated to, to `portal`, or to
+/// the portal of the configured SSID it resembles
+pub(crate) fn adopt(
+    registry: &PortalRegistry,
+    config_path: Option<&Path>,
+    portal: Option<String>,
+    ssid: Option<String>,
+) -> Result<()> {
+    let path = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find)
+        .context("No config file to add the SSID to, create config.toml first")?;
+
+    let ssid = match ssid {
+        Some(ssid) => ssid,
+        None => {
+            let unknown: Vec<String> = utils::active_wifi()?
+                .into_iter()
+                .map(|(_, ssid)| ssid)
+                .filter(|ssid| !registry.has_ssid(ssid))
+                .collect();
+            match unknown.as_slice() {
+                [ssid] => ssid.clone(),
+                [] => anyhow::bail!("Not connected to any WiFi network no portal handles"),
+                _ => anyhow::bail!(
+                    "Connected to several unconfigured networks ({}), name one",
+                    unknown.join(", ")
+                ),
+            }
+        }
+    };
+    if registry.has_ssid(&ssid) {
+        anyhow::bail!("'{}' is already configured", ssid);
+    }
+
+    let portal = match portal {
+        Some(portal) => portal,
+        None => registry
+            .suggest_ssid(&ssid)
+            .and_then(|suggestion| suggestion.portal)
+            .with_context(|| {
+                format!(
+                    "'{}' resembles no configured SSID, pick one with --portal",
+                    ssid
+                )
+            })?,
+    };
+    if !registry.names().contains(&portal.as_str()) {
+        anyhow::bail!(
+            "No portal named '{}' (configured: {})",
+            portal,
+            registry.names().join(", ")
+        );
+    }
+
+    config::Config::add_ssid_to_file(&path, &portal, &ssid)?;
+    println!(
+        "Added '{}' to portal '{}' in {}",
+        ssid,
+        portal,
+        path.display()
+    );
+    println!("Restart the daemon for it to pick the change up");
+    Ok(())
+}
+
+/// Add the portals shared in `file` to the config file
+pub(crate) fn import_portals(config_path: Option<&Path>, file: &Path) -> Result<()> {
+    let path = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find)
+        .context("No config file to add the portals to, create config.toml first")?;
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let portals = config::read_shared_portals(&contents)
+        .with_context(|| format!("{} is not a shared portal file", file.display()))?;
+    for portal in &portals {
+        if !portal::PORTAL_TYPES
+            .iter()
+            .any(|(name, _)| *name == portal.portal_type)
+        {
+            anyhow::bail!(
+                "Portal '{}': {}",
+                portal.name,
+                portal::missing_type(&portal.portal_type)
+            );
+        }
+    }
+
+    config::Config::add_portals_to_file(&path, &portals)?;
+    for portal in &portals {
+        println!(
+            "Added portal '{}' ({}, SSIDs: {}) to {}",
+            portal.name,
+            portal.portal_type,
+            portal.ssids.join(", "),
+            path.display()
+        );
+    }
+    let imported = |problem: &String| portals.iter().any(|p| problem.contains(&p.name));
+    for problem in config::Config::load_file(&path)?
+        .problems()
+        .iter()
+        .filter(|p| imported(p))
+    {
+        println!("! {}", problem);
+    }
+    Ok(())
+}
+
+/// Print the portal `name` as a file others can import
+pub(crate) fn export(cfg: &config::Config, name: &str) -> Result<()> {
+    let portal = cfg
+        .portals
+        .iter()
+        .find(|p| p.name == name)
+        .with_context(|| format!("No portal named '{}'", name))?;
+    print!("{}", portal.to_shareable_toml()?);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Logging in a machine reached over ssh
+
+use crate::OutputFormat;
+use anyhow::Result;
+use wimesh::portal::{NoPortalForSsid, PortalRegistry};
+use wimesh::remote::Remote;
+use wimesh::report::{Outcome, RunReport};
+
+/// Log in the machine at the other end of `remote`: check its WiFi and
+/// connectivity over ssh, then run the portal flow through a SOCKS tunnel
+/// out of it
+pub(crate) async fn remote(
+    registry: &mut PortalRegistry,
+    remote: &Remote,
+    output: OutputFormat,
+) -> Result<()> {
+    let mut report = RunReport::new();
+    let result = remote_login(registry, remote, &mut report).await;
+    report.finish(&result);
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&report)?);
+    }
+
+    result.map(|_| ())
+}
+
+async fn remote_login(
+    registry: &mut PortalRegistry,
+    remote: &Remote,
+    report: &mut RunReport,
+) -> Result<Outcome> {
+    let active = remote.active_wifi().await?;
+    let Some((interface, ssid)) = active.into_iter().find(|(_, ssid)| registry.has_ssid(ssid))
+    else {
+        tracing::warn!(
+            "{} is not connected to any configured WiFi network",
+            remote.host()
+        );
+        return Ok(Outcome::NotConnected);
+    };
+    tracing::info!(
+        "{} is connected to: {} ({})",
+        remote.host(),
+        ssid,
+        interface
+    );
+    report.ssid = Some(ssid.clone());
+
+    if remote.has_internet_connectivity().await? {
+        tracing::info!("{} is already online", remote.host());
+        return Ok(Outcome::Online);
+    }
+
+    let portal = registry
+        .find_for_ssid(&ssid)
+        .ok_or_else(|| NoPortalForSsid(ssid.clone()))?;
+    tracing::info!("Using portal: {}", portal.name());
+    report.portal = Some(portal.name().to_string());
+
+    let tunnel = remote.tunnel().await?;
+    portal.use_proxy(Some(&tunnel.proxy_url()))?;
+    let result = portal.connect().await;
+    report.steps = portal.last_steps().to_vec();
+    result?;
+
+    if !remote.has_internet_connectivity().await? {
+        anyhow::bail!(
+            "The portal flow completed but {} is still offline",
+            remote.host()
+        );
+    }
+    tracing::info!("{} is online", remote.host());
+    Ok(Outcome::connected(&report.steps))
+}
@@ -0,0 +1,52 @@
+//! Keyring entries for the config's secrets
+
+use anyhow::{Context, Result};
+use std::io::IsTerminal;
+use wimesh::secrets;
+
+/// The secret to store as `name`: a line of stdin, prompted for without
+/// echo on a terminal
+fn read_secret(name: &str) -> Result<String> {
+    let stdin = std::io::stdin();
+    let terminal = stdin.is_terminal();
+    // Echo off around the prompt; elsewhere the secret shows as typed
+    let stty = |mode: &str| {
+        if terminal && cfg!(unix) {
+            let _ = std::process::Command::new("stty")
+                .arg(mode)
+                .stdin(std::process::Stdio::inherit())
+                .status();
+        }
+    };
+    if terminal {
+        eprint!("Secret for '{}': ", name);
+    }
+    stty("-echo");
+    let mut line = String::new();
+    let read = stdin.read_line(&mut line);
+    stty("echo");
+    if terminal {
+        eprintln!();
+    }
+    read.context("Failed to read the secret")?;
+    let secret = line.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        anyhow::bail!("No secret given for '{}'", name);
+    }
+    Ok(secret.to_string())
+}
+
+/// Store the secret read from stdin as `name`
+pub(crate) fn set(name: &str) -> Result<()> {
+    let secret = read_secret(name)?;
+    secrets::set(name, &secret)?;
+    println!("Stored '{}' in the keyring", name);
+    Ok(())
+}
+
+/// Remove the entry `name`
+pub(crate) fn delete(name: &str) -> Result<()> {
+    secrets::delete(name)?;
+    println!("Removed '{}' from the keyring", name);
+    Ok(())
+}
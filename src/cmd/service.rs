@@ -0,0 +1,200 @@
+//! Running as a service: unit files, installing them, the tray
+
+use super::daemon::{apply_wifi_identities, run_daemon};
+use crate::ServiceAction;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use wimesh::events::EventLog;
+use wimesh::identity::IdentityManager;
+use wimesh::lock::LoginLocks;
+use wimesh::portal::PortalRegistry;
+use wimesh::{config, service, winservice};
+
+/// Write a unit running the daemon with this binary and the config in use,
+/// then enable it
+pub(crate) fn install_service(
+    cfg: &config::Config,
+    config_path: Option<&Path>,
+    user: bool,
+) -> Result<()> {
+    let config = service_config(config_path)?;
+    let binary = std::env::current_exe()?.display().to_string();
+    if cfg!(target_os = "macos") {
+        return install_launch_agent(&binary, &config);
+    }
+    let (dir, unit) = match user {
+        true => (
+            dirs::config_dir()
+                .context("No config directory for a user unit")?
+                .join("systemd/user"),
+            service::installed_user_unit(cfg, &binary, &config),
+        ),
+        false => (
+            PathBuf::from("/etc/systemd/system"),
+            service::installed_unit(cfg, &binary, &config),
+        ),
+    };
+    let path = dir.join("wimesh.service");
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(&path, unit))
+        .with_context(|| match user {
+            true => format!("Failed to write {}", path.display()),
+            false => format!("Failed to write {}, as root?", path.display()),
+        })?;
+    println!("✓ Wrote {}", path.display());
+
+    let scope = if user { " --user" } else { "" };
+    for args in [&["daemon-reload"][..], &["enable", "wimesh.service"]] {
+        let mut command = std::process::Command::new("systemctl");
+        if user {
+            command.arg("--user");
+        }
+        let output = command
+            .args(args)
+            .output()
+            .context("Failed to run systemctl")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "systemctl{} {} failed: {}",
+                scope,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    println!(
+        "✓ Enabled wimesh.service, start it with `systemctl{} start wimesh`",
+        scope
+    );
+    if !user
+        && ["/home", "/root"]
+            .iter()
+            .any(|home| config.starts_with(home))
+    {
+        println!(
+            "  The service runs as a dynamic user: {} must be readable by others, or move it \
+             to /etc/wimesh",
+            config.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tray")]
+pub(crate) async fn tray(cfg: &config::Config, config_path: Option<&Path>) -> Result<()> {
+    wimesh::tray::run(cfg, config_path).await
+}
+
+#[cfg(not(feature = "tray"))]
+pub(crate) async fn tray(_: &config::Config, _: Option<&Path>) -> Result<()> {
+    anyhow::bail!("This build has no tray icon: build with --features tray")
+}
+
+/// `install-service` on macOS: a LaunchAgent of the user's, loaded at once
+fn install_launch_agent(binary: &str, config: &Path) -> Result<()> {
+    let home = dirs::home_dir().context("No home directory for the LaunchAgent")?;
+    let log = launchd_log(&home);
+    let path = home
+        .join("Library/LaunchAgents")
+        .join(format!("{}.plist", service::LAUNCHD_LABEL));
+    for dir in [path.parent(), log.parent()].into_iter().flatten() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(&path, service::launchd_agent(binary, config, &log))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("✓ Wrote {}", path.display());
+
+    // An agent loaded before keeps running the old definition otherwise
+    let launchctl = |args: &[&str]| {
+        std::process::Command::new("launchctl")
+            .args(args)
+            .arg(&path)
+            .output()
+            .context("Failed to run launchctl")
+    };
+    let _ = launchctl(&["unload"]);
+    let output = launchctl(&["load", "-w"])?;
+    // launchctl load reports some failures with a zero exit status
+    let error = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !output.status.success() || !error.is_empty() {
+        anyhow::bail!("launchctl load failed: {}", error);
+    }
+    println!(
+        "✓ Loaded {}, logging to {}",
+        service::LAUNCHD_LABEL,
+        log.display()
+    );
+    Ok(())
+}
+
+/// Where the LaunchAgent's output goes
+fn launchd_log(home: &Path) -> PathBuf {
+    home.join("Library/Logs/wimesh/wimesh.log")
+}
+
+/// The config file a service is to run with: `config_path`, else the one
+/// found, as an absolute path
+fn service_config(config_path: Option<&Path>) -> Result<PathBuf> {
+    let config = config_path
+        .map(Path::to_path_buf)
+        .or_else(config::Config::find)
+        .context("No config file for the service to run with; `wimesh init` writes one")?;
+    std::fs::canonicalize(&config).with_context(|| format!("Failed to find {}", config.display()))
+}
+
+/// Print the service definition `action` asks for, or manage the Windows
+/// service with it
+pub(crate) async fn run(
+    cfg: config::Config,
+    config_path: Option<&Path>,
+    action: ServiceAction,
+) -> Result<()> {
+    let definition = match action {
+        ServiceAction::Systemd => {
+            let binary = std::env::current_exe()?;
+            service::systemd_unit(&cfg, &binary.display().to_string())
+        }
+        ServiceAction::Nixos => service::nixos_module(&cfg),
+        ServiceAction::HomeManager => service::home_manager_module(&cfg),
+        ServiceAction::Rcd => {
+            let binary = std::env::current_exe()?;
+            service::rcd_script(&binary.display().to_string())
+        }
+        ServiceAction::Launchd => {
+            let binary = std::env::current_exe()?.display().to_string();
+            let home = dirs::home_dir().context("No home directory for the log")?;
+            let config = service_config(config_path)?;
+            service::launchd_agent(&binary, &config, &launchd_log(&home))
+        }
+        ServiceAction::Install => {
+            let config = service_config(config_path)?;
+            winservice::install(&std::env::current_exe()?, &config)?;
+            println!(
+                "✓ Installed the {} service with {}, `wimesh service start` starts it",
+                winservice::NAME,
+                config.display()
+            );
+            return Ok(());
+        }
+        ServiceAction::Uninstall => {
+            winservice::uninstall()?;
+            println!("✓ Removed the {} service", winservice::NAME);
+            return Ok(());
+        }
+        ServiceAction::Start => return winservice::start(),
+        ServiceAction::Stop => return winservice::stop(),
+        ServiceAction::Run => {
+            winservice::windows_only()?;
+            let identities = IdentityManager::load();
+            apply_wifi_identities(&cfg, &identities);
+            let registry = PortalRegistry::from_config(&cfg, &identities)?;
+            let events = EventLog::new(&cfg.events);
+            let locks = LoginLocks::new();
+            let daemon = run_daemon(cfg, config_path, registry, &locks, &events, None);
+            return winservice::run(daemon).await;
+        }
+    };
+    print!("{}", definition);
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! What the network and the daemon are up to: `status`, `widget`, `why`
+
+use super::ago;
+use crate::OutputFormat;
+use anyhow::Result;
+use std::time::Duration;
+use wimesh::diagnose;
+use wimesh::state::{self, unix_now};
+use wimesh::status::NetworkStatus;
+use wimesh::{config, status, utils};
+
+/// Print the network status for a status bar, once or continuously
+pub(crate) async fn widget(
+    cfg: &config::Config,
+    json: bool,
+    once: bool,
+    interval: u64,
+) -> Result<()> {
+    loop {
+        let status = NetworkStatus::probe(cfg).await;
+        if json {
+            println!("{}", status.waybar_json());
+        } else {
+            println!("{}", status.line());
+        }
+
+        if once {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Print the network state plus what the state file knows about it
+pub(crate) async fn status(cfg: &config::Config, output: OutputFormat) -> Result<()> {
+    let status = NetworkStatus::probe(cfg).await;
+    let state = state::nonblocking::load().await;
+    let ssid = status.ssid.as_deref();
+    let last_login = ssid.and_then(|ssid| state.last_login.get(ssid)).copied();
+    let backoff = ssid.and_then(|ssid| state.backoff.get(ssid)).copied();
+    let now = unix_now();
+    let next = state.next_action.as_ref();
+    let stale = next.is_some_and(|next| status::is_stale(next, cfg, now));
+    let interface = match ssid {
+        Some(ssid) => utils::nonblocking::wifi_interface_for_ssid(ssid).await,
+        None => None,
+    };
+    let (bssid, radio) = match interface.as_deref() {
+        Some(interface) => (
+            utils::nonblocking::active_bssid(interface).await,
+            utils::nonblocking::active_radio(interface).await,
+        ),
+        None => (None, None),
+    };
+
+    if output == OutputFormat::Json {
+        let mut json = status.json();
+        json["bssid"] = serde_json::json!(bssid);
+        json["radio"] = serde_json::json!(radio);
+        json["last_login"] = serde_json::json!(last_login);
+        json["backoff"] = serde_json::json!(backoff);
+        json["next_action"] = serde_json::json!(next);
+        json["daemon_stale"] = serde_json::json!(stale);
+        json["read_only"] = serde_json::json!(state.is_read_only(cfg.global.read_only));
+        json["paused_at"] = serde_json::json!(state.paused_at);
+        json["subsystems"] = serde_json::json!(state.subsystems);
+        println!("{}", json);
+        return Ok(());
+    }
+
+    println!("{}", status.line());
+    if let Some(radio) = radio {
+        println!(
+            "Radio: {} via {}",
+            radio.describe(),
+            bssid.as_deref().unwrap_or("-")
+        );
+    }
+    if state.is_read_only(cfg.global.read_only) {
+        println!("Read-only: the daemon does not log in (`wimesh read-only off`)");
+    }
+    if let Some(at) = state.paused_at {
+        println!(
+            "Paused {} ago: the daemon does not check (`wimesh control resume`)",
+            ago(at)
+        );
+    }
+    if let Some(at) = last_login {
+        println!("Last login: {} ago", ago(at));
+    }
+    if let Some(wait) = ssid.and_then(|ssid| state.backoff_remaining(ssid)) {
+        let failures = backoff.map(|b| b.failures).unwrap_or_default();
+        println!("Backing off: {}s left after {} failure(s)", wait, failures);
+    }
+    match next {
+        Some(next) if stale => println!(
+            "Next: {} (planned {} ago; is the daemon running?)",
+            next.describe(now),
+            ago(next.planned_at)
+        ),
+        Some(next) => println!("Next: {}", next.describe(now)),
+        None => println!("Next: nothing planned, the daemon has not run yet"),
+    }
+    let mut degraded: Vec<_> = state.subsystems.iter().filter(|(_, h)| !h.up).collect();
+    degraded.sort_by_key(|(name, _)| name.as_str());
+    for (name, health) in degraded {
+        println!("Degraded: {} ({})", name, health.describe(now));
+    }
+    Ok(())
+}
+
+/// Explain the network state from the WiFi, probes, state file and event log
+pub(crate) async fn why(cfg: &config::Config, output: OutputFormat) -> Result<()> {
+    let evidence = diagnose::gather(cfg).await;
+    let diagnosis = diagnose::explain(&evidence);
+    if output == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::json!({ "diagnosis": diagnosis, "evidence": evidence })
+        );
+        return Ok(());
+    }
+
+    println!("{}", diagnosis.summary);
+    println!();
+    for fact in &diagnosis.evidence {
+        println!("  {}", fact);
+    }
+    if let Some(ref advice) = diagnosis.advice {
+        println!();
+        println!("{}", advice);
+    }
+    Ok(())
+}
@@ -0,0 +1,349 @@
+//! Authorizing a device from a phone (`authorize-device --qr`)
+//!
+//! Picking a TV box out of a list on a laptop works, but the person holding
+//! the remote usually has a phone, not the laptop. With `--qr` wimesh
+//! serves a one-page site on the LAN for a few minutes and prints a QR code
+//! of its address: scanning it shows the devices seen on the WiFi (from the
+//! ARP table, refreshed on every load) with a button each, plus a field for
+//! a MAC that is not listed, and pressing one runs the portal login with
+//! that MAC. Only the path with the random token answers, only a POST
+//! logs anything in (link previews fetch with GET), and the page is gone
+//! once a device is authorized or the time is up.
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+/// Largest request (head and form) read
+const MAX_REQUEST: usize = 8 * 1024;
+/// How long one phone may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A device the page offers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub mac: String,
+    pub address: Option<Ipv4Addr>,
+}
+
+/// What a request asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Route {
+    Page,
+    Authorize(String),
+    BadMac(String),
+    NotFound,
+}
+
+fn route(method: &str, path: &str, body: &str, token: &str) -> Route {
+    let path = path.split('?').next().unwrap_or(path);
+    if path.strip_prefix('/') != Some(token) {
+        return Route::NotFound;
+    }
+    if method != "POST" {
+        return Route::Page;
+    }
+    let mac = body
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, value)| *key == "mac" && !value.is_empty())
+        .map(|(_, value)| percent_decode(value))
+        .next_back()
+        .unwrap_or_default();
+    match crate::utils::normalize_mac(&mac) {
+        Some(mac) => Route::Authorize(mac),
+        None => Route::BadMac(mac),
+    }
+}
+
+/// A form value, `+` and `%XX` decoded
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The page: `message` on top, then a button per device and the MAC field
+fn page(devices: &[Device], message: Option<&str>) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>wimesh: authorize a device</title>\
+         <style>body{font-family:sans-serif;margin:1.5em;max-width:30em}\
+         button,input{font-size:1.1em;padding:.6em;margin:.3em 0;width:100%}</style>\
+         </head><body><h1>Authorize a device</h1>",
+    );
+    if let Some(message) = message {
+        html.push_str(&format!("<p><b>{}</b></p>", escape(message)));
+    }
+    html.push_str("<form method=\"post\">");
+    if devices.is_empty() {
+        html.push_str("<p>No devices seen on the WiFi yet; connect the device, then reload.</p>");
+    }
+    for device in devices {
+        let address = device
+            .address
+            .map(|a| format!(" ({})", a))
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<button name=\"mac\" value=\"{0}\">{0}{1}</button>",
+            escape(&device.mac),
+            address
+        ));
+    }
+    html.push_str(
+        "</form><form method=\"post\"><p>Not listed? Its MAC address is in the device's \
+         network settings:</p><input name=\"mac\" placeholder=\"AA:BB:CC:DD:EE:FF\">\
+         <button>Authorize</button></form></body></html>",
+    );
+    html
+}
+
+/// A random token for the page's path, from the OS
+fn token() -> String {
+    let mut bytes = [0u8; 8];
+    let random = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if random.is_err() {
+        bytes = crate::utils::random_u64().to_be_bytes();
+    }
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The companion page, bound and waiting for a phone
+pub struct Companion {
+    listener: TcpListener,
+    token: String,
+    url: String,
+    ttl: Duration,
+}
+
+impl Companion {
+    /// Listen on `listen` (`host:port`); the QR code points phones at
+    /// `address`, the machine's address on the WiFi
+    pub async fn bind(listen: &str, address: Ipv4Addr, ttl: Duration) -> Result<Self> {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} for the companion page", listen))?;
+        let port = listener.local_addr()?.port();
+        let token = token();
+        let url = format!("http://{}/{}", SocketAddr::from((address, port)), token);
+        Ok(Self {
+            listener,
+            token,
+            url,
+            ttl,
+        })
+    }
+
+    /// What the QR code encodes
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Answer phones until `authorize` succeeds for a MAC, which is then
+    /// returned, or the time is up (None). `devices` lists what the page
+    /// offers; a failed attempt is shown on the page, which stays up
+    pub async fn serve<D, G, A, F>(self, devices: D, authorize: A) -> Result<Option<String>>
+    where
+        D: Fn() -> G,
+        G: Future<Output = Vec<Device>>,
+        A: Fn(String) -> F,
+        F: Future<Output = Result<()>>,
+    {
+        let deadline = Instant::now() + self.ttl;
+        loop {
+            let accepted = tokio::time::timeout_at(deadline, self.listener.accept()).await;
+            let Ok(accepted) = accepted else {
+                return Ok(None);
+            };
+            let (mut stream, from) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::debug!("Companion page accept failed: {}", e);
+                    continue;
+                }
+            };
+            let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await;
+            let (method, path, body) = match request {
+                Ok(Ok(request)) => request,
+                Ok(Err(e)) => {
+                    tracing::debug!("Bad request from {}: {:#}", from, e);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            let (status, html, done) = match route(&method, &path, &body, &self.token) {
+                Route::NotFound => (404, "Not found".to_string(), None),
+                Route::Page => (200, page(&devices().await, None), None),
+                Route::BadMac(mac) => {
+                    let message = format!("'{}' is not a MAC address", mac);
+                    (400, page(&devices().await, Some(&message)), None)
+                }
+                Route::Authorize(mac) => {
+                    tracing::info!("{} asked to authorize {}", from.ip(), mac);
+                    match authorize(mac.clone()).await {
+                        Ok(()) => {
+                            let message = format!(
+                                "The portal accepted {}. Open a page on the device to check.",
+                                mac
+                            );
+                            (200, page(&[], Some(&message)), Some(mac))
+                        }
+                        Err(e) => {
+                            tracing::warn!("Failed to authorize {}: {:#}", mac, e);
+                            let message = format!("The portal did not log in {}: {:#}", mac, e);
+                            (502, page(&devices().await, Some(&message)), None)
+                        }
+                    }
+                }
+            };
+            if let Err(e) = respond(&mut stream, status, &html).await {
+                tracing::debug!("Failed to answer {}: {}", from, e);
+            }
+            if done.is_some() {
+                return Ok(done);
+            }
+        }
+    }
+}
+
+/// Method, path and body of one request
+async fn read_request(stream: &mut TcpStream) -> Result<(String, String, String)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 2048];
+    let head_end = loop {
+        if let Some(at) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed mid-request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST {
+            anyhow::bail!("Request too large");
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or_default();
+    if head_end + length > MAX_REQUEST {
+        anyhow::bail!("Request too large");
+    }
+    while buf.len() < head_end + length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(&buf[head_end..buf.len().min(head_end + length)]);
+
+    let mut start = head.split_whitespace();
+    let method = start.next().unwrap_or_default().to_string();
+    let path = start.next().unwrap_or("/").to_string();
+    Ok((method, path, body.into_owned()))
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, html: &str) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Bad Gateway",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        html.len(),
+        html
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let token = "0123456789abcdef";
+        assert_eq!(route("GET", "/0123456789abcdef", "", token), Route::Page);
+        assert_eq!(route("GET", "/", "", token), Route::NotFound);
+        assert_eq!(
+            route("POST", "/guess", "mac=02:00:00:AA:BB:57", token),
+            Route::NotFound
+        );
+        assert_eq!(
+            route(
+                "POST",
+                "/0123456789abcdef",
+                "mac=02%3a00%3A00%3Aaa%3Abb%3A57",
+                token
+            ),
+            Route::Authorize("02:00:00:AA:BB:57".to_string())
+        );
+        // The button of a listed device and an empty manual field
+        assert_eq!(
+            route(
+                "POST",
+                "/0123456789abcdef?x=1",
+                "mac=&mac=0200.00aa.bb57",
+                token
+            ),
+            Route::Authorize("02:00:00:AA:BB:57".to_string())
+        );
+        assert_eq!(
+            route("POST", "/0123456789abcdef", "mac=my+tv", token),
+            Route::BadMac("my tv".to_string())
+        );
+
+        let html = page(
+            &[Device {
+                mac: "02:00:00:AA:BB:57".to_string(),
+                address: "10.20.30.57".parse().ok(),
+            }],
+            Some("<oops>"),
+        );
+        assert!(html.contains("value=\"02:00:00:AA:BB:57\">02:00:00:AA:BB:57 (10.20.30.57)"));
+        assert!(html.contains("&lt;oops&gt;"));
+    }
+}
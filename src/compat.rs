@@ -0,0 +1,410 @@
+//! Lenient HTTP for broken gateways
+//!
+//! Some embedded gateways answer with HTTP/1.0 status lines, no
+//! Content-Length, bare LF line endings or header lines that are not headers
+//! at all, and reqwest rightly refuses them. `CompatClient` speaks just
+//! enough HTTP/1.0 over a raw socket to get through such a gateway: one
+//! request per connection, body read until the server closes, anything
+//! unparseable in the head skipped. It has no TLS and no cookies, so only
+//! the plain-HTTP gateway steps use it.
+
+use crate::http::{truncate_chars, HttpError};
+use anyhow::{Context, Result};
+use reqwest::{StatusCode, Url};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 5;
+/// Refuse responses larger than this; gateway pages are a few KiB
+const MAX_RESPONSE: usize = 2 * 1024 * 1024;
+
+/// A response as far as it could be made sense of
+#[derive(Debug, Clone)]
+pub struct CompatResponse {
+    pub status: u16,
+    /// Headers in order received, names lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl CompatResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct CompatClient {
+    user_agent: String,
+    interface: Option<String>,
+    /// `host:port` of a SOCKS5 proxy to connect through
+    socks: Option<String>,
+}
+
+impl CompatClient {
+    pub fn new(user_agent: &str) -> Self {
+        Self {
+            user_agent: user_agent.to_string(),
+            interface: None,
+            socks: None,
+        }
+    }
+
+    /// Connect out of `interface` (Linux only), like `HttpOptions::interface`
+    pub fn with_interface(mut self, interface: Option<&str>) -> Self {
+        self.interface = interface.map(str::to_string);
+        self
+    }
+
+    /// Connect through the SOCKS5 proxy `proxy` (`socks5://` or
+    /// `socks5h://`), like `HttpOptions::proxy`; names always resolve on
+    /// the proxy side
+    pub fn with_proxy(mut self, proxy: Option<&str>) -> Result<Self> {
+        self.socks = match proxy {
+            Some(proxy) => {
+                let url = Url::parse(proxy)?;
+                if !url.scheme().starts_with("socks5") {
+                    anyhow::bail!("Compat mode only speaks SOCKS5 to proxies, not '{}'", proxy);
+                }
+                let host = url.host_str().context("Proxy URL has no host")?;
+                Some(format!("{}:{}", host, url.port().unwrap_or(1080)))
+            }
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// GET `url`, following redirects
+    pub async fn get(&self, url: &str) -> Result<CompatResponse> {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let resp = self.send("GET", &url, None).await?;
+            match resp.header("location") {
+                Some(location) if (300..400).contains(&resp.status) => {
+                    url = url
+                        .join(location.trim())
+                        .with_context(|| format!("Invalid redirect to '{}'", location))?;
+                }
+                _ => return check(resp),
+            }
+        }
+        anyhow::bail!("Too many redirects")
+    }
+
+    /// POST `form` URL-encoded to `url`; redirects after the post are not
+    /// followed, the gateway has accepted the login by then
+    pub async fn post_form(&self, url: &str, form: &[(String, String)]) -> Result<CompatResponse> {
+        let body = form
+            .iter()
+            .map(|(k, v)| format!("{}={}", urlencoding::encode(k), urlencoding::encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let resp = self.send("POST", &Url::parse(url)?, Some(&body)).await?;
+        if (300..400).contains(&resp.status) {
+            return Ok(resp);
+        }
+        check(resp)
+    }
+
+    async fn send(&self, method: &str, url: &Url, body: Option<&str>) -> Result<CompatResponse> {
+        if url.scheme() != "http" {
+            anyhow::bail!("Compat mode only speaks plain HTTP, not '{}'", url);
+        }
+        let host = url.host_str().context("URL has no host")?;
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let mut target = url.path().to_string();
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let mut request = format!(
+            "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: {}\r\nAccept: */*\r\nConnection: close\r\n",
+            method, target, host, self.user_agent
+        );
+        if let Some(body) = body {
+            request.push_str("Content-Type: application/x-www-form-urlencoded\r\n");
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body.unwrap_or_default());
+
+        let exchange = async {
+            let mut stream = self.connect(host, port).await?;
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut raw = Vec::new();
+            let mut chunk = [0u8; 8192];
+            loop {
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                raw.extend_from_slice(&chunk[..n]);
+                if raw.len() > MAX_RESPONSE {
+                    anyhow::bail!("Response larger than {} bytes", MAX_RESPONSE);
+                }
+            }
+            Ok(raw)
+        };
+
+        let raw = tokio::time::timeout(DEFAULT_TIMEOUT, exchange)
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))
+            .with_context(|| format!("{} {} timed out", method, url))??;
+        parse_response(&raw).with_context(|| format!("{} {}", method, url))
+    }
+}
+
+impl CompatClient {
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        if let Some(ref proxy) = self.socks {
+            let mut stream = TcpStream::connect(proxy.as_str())
+                .await
+                .with_context(|| format!("Failed to reach proxy {}", proxy))?;
+            socks5_connect(&mut stream, host, port).await?;
+            return Ok(stream);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(ref interface) = self.interface {
+            let addr = tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .with_context(|| format!("{} did not resolve", host))?;
+            let socket = if addr.is_ipv4() {
+                tokio::net::TcpSocket::new_v4()?
+            } else {
+                tokio::net::TcpSocket::new_v6()?
+            };
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .with_context(|| format!("Failed to bind to {}", interface))?;
+            return Ok(socket.connect(addr).await?);
+        }
+        Ok(TcpStream::connect((host, port)).await?)
+    }
+}
+
+/// Ask the SOCKS5 proxy on `stream` to connect to `host:port` (RFC 1928,
+/// no authentication)
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        anyhow::bail!("SOCKS proxy wants authentication");
+    }
+
+    let name = host.as_bytes();
+    let len = u8::try_from(name.len()).context("Host name too long for SOCKS")?;
+    let mut request = vec![5, 1, 0, 3, len];
+    request.extend_from_slice(name);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!(
+            "SOCKS proxy could not reach {}:{} (code {})",
+            host,
+            port,
+            reply[1]
+        );
+    }
+    // Skip the bound address the reply carries
+    let skip = match reply[3] {
+        1 => 4 + 2,
+        4 => 16 + 2,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize + 2
+        }
+        other => anyhow::bail!("SOCKS reply with unknown address type {}", other),
+    };
+    let mut bound = vec![0u8; skip];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Accept only success statuses, like `HttpClient` does
+fn check(resp: CompatResponse) -> Result<CompatResponse> {
+    if (200..300).contains(&resp.status) {
+        return Ok(resp);
+    }
+    let status = StatusCode::from_u16(resp.status).unwrap_or(StatusCode::BAD_GATEWAY);
+    Err(HttpError::Status {
+        status,
+        body: truncate_chars(&resp.body, 50).to_string(),
+    }
+    .into())
+}
+
+/// Make what sense there is of a complete raw response
+pub fn parse_response(raw: &[u8]) -> Result<CompatResponse> {
+    let (head, body) = match find(raw, b"\r\n\r\n") {
+        Some(pos) => (&raw[..pos], &raw[pos + 4..]),
+        None => match find(raw, b"\n\n") {
+            Some(pos) => (&raw[..pos], &raw[pos + 2..]),
+            None => (raw, &[][..]),
+        },
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+
+    // "HTTP/1.0 200 OK", "HTTP/1.1 200", or even "HTTP 200"
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .with_context(|| format!("Not an HTTP status line: '{}'", status_line))?;
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((name.to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+
+    let resp = CompatResponse {
+        status,
+        headers,
+        body: String::new(),
+    };
+    let chunked = resp
+        .header("transfer-encoding")
+        .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+    let content_length = resp
+        .header("content-length")
+        .and_then(|v| v.trim().parse::<usize>().ok());
+
+    let body = if chunked {
+        dechunk(body)
+    } else {
+        match content_length {
+            Some(len) if len < body.len() => body[..len].to_vec(),
+            _ => body.to_vec(),
+        }
+    };
+
+    let body = crate::decode::decompress(body, resp.header("content-encoding"))?;
+    Ok(CompatResponse {
+        body: crate::decode::text(&body, resp.header("content-type")),
+        ..resp
+    })
+}
+
+/// Decode a chunked body, keeping whatever arrived if it is malformed
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let Some(eol) = find(body, b"\n") else {
+            out.extend_from_slice(body);
+            return out;
+        };
+        let size_line = String::from_utf8_lossy(&body[..eol]);
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size_hex, 16) else {
+            out.extend_from_slice(body);
+            return out;
+        };
+        body = &body[eol + 1..];
+        if size == 0 {
+            return out;
+        }
+        let take = size.min(body.len());
+        out.extend_from_slice(&body[..take]);
+        body = &body[take..];
+        body = body
+            .strip_prefix(b"\r\n")
+            .or(body.strip_prefix(b"\n"))
+            .unwrap_or(body);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http10_without_content_length() {
+        let resp = parse_response(b"HTTP/1.0 200 OK\r\nServer: gw\r\n\r\n<html>hi</html>").unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.header("Server"), Some("gw"));
+        assert_eq!(resp.body, "<html>hi</html>");
+    }
+
+    #[test]
+    fn test_bare_lf_and_invalid_headers() {
+        let raw = b"HTTP/1.1 302\nLocation: /login\ngarbage line\nX Bad: 1\n\nbody";
+        let resp = parse_response(raw).unwrap();
+        assert_eq!(resp.status, 302);
+        assert_eq!(
+            resp.headers,
+            [("location".to_string(), "/login".to_string())]
+        );
+        assert_eq!(resp.body, "body");
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert_eq!(parse_response(raw).unwrap().body, "hello world");
+    }
+
+    #[test]
+    fn test_not_http() {
+        assert!(parse_response(b"<html>no status line</html>").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_through_socks_proxy() {
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut head = [0u8; 5];
+            stream.read_exact(&mut head).await.unwrap();
+            let mut target = vec![0u8; head[4] as usize + 2];
+            stream.read_exact(&mut target).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut request = [0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            assert!(request[..n].starts_with(b"GET /status HTTP/1.0"));
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\nup")
+                .await
+                .unwrap();
+            String::from_utf8(target[..target.len() - 2].to_vec()).unwrap()
+        });
+
+        let client = CompatClient::new("test")
+            .with_proxy(Some(&format!("socks5h://{}", addr)))
+            .unwrap();
+        let resp = client.get("http://gw.test/status").await.unwrap();
+        assert_eq!(resp.body, "up");
+        assert_eq!(server.await.unwrap(), "gw.test");
+    }
+}
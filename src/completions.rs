@@ -0,0 +1,437 @@
+//! Shell completion scripts (`wimesh completions`)
+//!
+//! The scripts are written from the clap definition of the command line, so
+//! a subcommand or flag added to it completes without touching this file.
+//! Each script tracks the subcommands typed so far (`wimesh config` then
+//! `migrate`) and offers the subcommands, flags and positional values of
+//! the deepest one; after a flag taking a value it offers that flag's
+//! values, or files when the value is free-form. Bash and zsh complete
+//! words only, fish and PowerShell show each word's help too.
+
+use clap::ValueEnum;
+
+/// Shells a script is written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    #[value(name = "powershell")]
+    PowerShell,
+}
+
+/// A word to offer, with its help
+struct Word {
+    text: String,
+    help: String,
+}
+
+/// A flag taking a value: its spellings, and the values it takes (empty:
+/// anything, completed as a file)
+struct Valued {
+    flags: Vec<String>,
+    values: Vec<String>,
+}
+
+/// One command of the tree, named by the words leading to it
+struct Node {
+    path: String,
+    words: Vec<Word>,
+    valued: Vec<Valued>,
+    /// Words switching to a subcommand (its name and aliases), and its path
+    children: Vec<(String, String)>,
+}
+
+/// The completion script of `command` for `shell`
+pub fn generate(shell: Shell, mut command: clap::Command) -> String {
+    // Propagates the global flags and adds --help, --version and `help`
+    command.build();
+    let name = command.get_name().to_string();
+    let mut nodes = Vec::new();
+    collect(&command, name.clone(), &mut nodes);
+    match shell {
+        Shell::Bash => bash(&name, &nodes),
+        Shell::Zsh => zsh(&name, &nodes),
+        Shell::Fish => fish(&name, &nodes),
+        Shell::PowerShell => powershell(&name, &nodes),
+    }
+}
+
+fn collect(command: &clap::Command, path: String, nodes: &mut Vec<Node>) {
+    let mut node = Node {
+        path: path.clone(),
+        words: Vec::new(),
+        valued: Vec::new(),
+        children: Vec::new(),
+    };
+    let mut subcommands = Vec::new();
+    for sub in command.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let child = format!("{} {}", path, sub.get_name());
+        node.words.push(Word {
+            text: sub.get_name().to_string(),
+            help: help(sub.get_about()),
+        });
+        for alias in std::iter::once(sub.get_name()).chain(sub.get_visible_aliases()) {
+            node.children.push((alias.to_string(), child.clone()));
+        }
+        // `help` takes the other subcommands' names, not their flags
+        if sub.get_name() != "help" {
+            subcommands.push((sub, child));
+        }
+    }
+    for arg in command.get_arguments().filter(|arg| !arg.is_hide_set()) {
+        let values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        if arg.is_positional() {
+            for value in values {
+                node.words.push(Word {
+                    text: value,
+                    help: help(arg.get_help()),
+                });
+            }
+            continue;
+        }
+        let mut flags: Vec<String> = Vec::new();
+        flags.extend(arg.get_long().map(|long| format!("--{}", long)));
+        flags.extend(arg.get_short().map(|short| format!("-{}", short)));
+        for flag in &flags {
+            node.words.push(Word {
+                text: flag.clone(),
+                help: help(arg.get_help()),
+            });
+        }
+        if arg.get_action().takes_values() && !flags.is_empty() {
+            node.valued.push(Valued { flags, values });
+        }
+    }
+    nodes.push(node);
+    for (sub, child) in subcommands {
+        collect(sub, child, nodes);
+    }
+}
+
+/// The first line of a help text
+fn help(text: Option<&clap::builder::StyledStr>) -> String {
+    let text = text.map(ToString::to_string).unwrap_or_default();
+    text.lines().next().unwrap_or_default().trim().to_string()
+}
+
+/// `text` in single quotes, for zsh
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// `text` in single quotes, for fish, which escapes within them
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// `text` in single quotes, for PowerShell
+fn ps_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// Function name for `name`, as shells allow
+fn ident(name: &str) -> String {
+    name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+fn bash(name: &str, nodes: &[Node]) -> String {
+    let function = format!("_{}", ident(name));
+    let mut out = format!(
+        "# bash completion for {name}\n\
+         {function}() {{\n\
+         \x20   local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\
+         \x20   local cmd=\"{name}\" i\n\
+         \x20   for ((i = 1; i < COMP_CWORD; i++)); do\n\
+         \x20       case \"$cmd:${{COMP_WORDS[i]}}\" in\n"
+    );
+    for node in nodes {
+        for (word, child) in &node.children {
+            out.push_str(&format!(
+                "            \"{}:{}\") cmd=\"{}\" ;;\n",
+                node.path, word, child
+            ));
+        }
+    }
+    out.push_str("        esac\n    done\n    case \"$cmd:$prev\" in\n");
+    for node in nodes {
+        for valued in &node.valued {
+            let cases: Vec<String> = valued
+                .flags
+                .iter()
+                .map(|flag| format!("\"{}:{}\"", node.path, flag))
+                .collect();
+            let reply = match valued.values.is_empty() {
+                true => "COMPREPLY=($(compgen -f -- \"$cur\")); return ;;".to_string(),
+                false => format!(
+                    "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;",
+                    valued.values.join(" ")
+                ),
+            };
+            out.push_str(&format!(
+                "        {})\n            {}\n",
+                cases.join("|"),
+                reply
+            ));
+        }
+    }
+    out.push_str("    esac\n    local words\n    case \"$cmd\" in\n");
+    for node in nodes {
+        let words: Vec<&str> = node.words.iter().map(|word| word.text.as_str()).collect();
+        out.push_str(&format!(
+            "        \"{}\") words=\"{}\" ;;\n",
+            node.path,
+            words.join(" ")
+        ));
+    }
+    out.push_str(&format!(
+        "    esac\n\
+         \x20   COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n\
+         }}\n\
+         complete -F {function} -o bashdefault -o default {name}\n"
+    ));
+    out
+}
+
+fn zsh(name: &str, nodes: &[Node]) -> String {
+    let function = format!("_{}", ident(name));
+    let mut out = format!(
+        "#compdef {name}\n\n\
+         {function}() {{\n\
+         \x20   local cmd=\"{name}\" i\n\
+         \x20   for ((i = 2; i < CURRENT; i++)); do\n\
+         \x20       case \"$cmd:${{words[i]}}\" in\n"
+    );
+    for node in nodes {
+        for (word, child) in &node.children {
+            out.push_str(&format!(
+                "            ({}) cmd={} ;;\n",
+                quote(&format!("{}:{}", node.path, word)),
+                quote(child)
+            ));
+        }
+    }
+    out.push_str("        esac\n    done\n    case \"$cmd:${words[CURRENT-1]}\" in\n");
+    for node in nodes {
+        for valued in &node.valued {
+            let cases: Vec<String> = valued
+                .flags
+                .iter()
+                .map(|flag| quote(&format!("{}:{}", node.path, flag)))
+                .collect();
+            let reply = match valued.values.is_empty() {
+                true => "_files; return ;;".to_string(),
+                false => format!("compadd -- {}; return ;;", valued.values.join(" ")),
+            };
+            out.push_str(&format!(
+                "        ({})\n            {}\n",
+                cases.join("|"),
+                reply
+            ));
+        }
+    }
+    out.push_str("    esac\n    local -a items\n    case \"$cmd\" in\n");
+    for node in nodes {
+        let items: Vec<String> = node
+            .words
+            .iter()
+            .map(|word| match word.help.as_str() {
+                "" => quote(&word.text),
+                help => quote(&format!("{}:{}", word.text, help)),
+            })
+            .collect();
+        out.push_str(&format!(
+            "        ({}) items=({}) ;;\n",
+            quote(&node.path),
+            items.join(" ")
+        ));
+    }
+    out.push_str(&format!(
+        "    esac\n\
+         \x20   _describe {name} items\n\
+         }}\n\n\
+         if [ \"$funcstack[1]\" = \"{function}\" ]; then\n\
+         \x20   {function} \"$@\"\n\
+         else\n\
+         \x20   compdef {function} {name}\n\
+         fi\n"
+    ));
+    out
+}
+
+fn fish(name: &str, nodes: &[Node]) -> String {
+    let function = format!("__{}_path", ident(name));
+    let mut out = format!(
+        "# fish completion for {name}\n\
+         function {function}\n\
+         \x20   set -l cmd {name}\n\
+         \x20   for word in (commandline -opc)[2..-1]\n\
+         \x20       switch \"$cmd:$word\"\n"
+    );
+    for node in nodes {
+        for (word, child) in &node.children {
+            out.push_str(&format!(
+                "            case {}\n                set cmd {}\n",
+                fish_quote(&format!("{}:{}", node.path, word)),
+                fish_quote(child)
+            ));
+        }
+    }
+    out.push_str(&format!(
+        "        end\n    end\n    test \"$cmd\" = \"$argv[1]\"\nend\n\ncomplete -c {name} -f\n"
+    ));
+    for node in nodes {
+        let condition = fish_quote(&format!("{} {}", function, fish_quote(&node.path)));
+        for word in &node.words {
+            let spelling = match word.text.strip_prefix("--") {
+                Some(long) => format!("-l {}", long),
+                None => match word.text.strip_prefix('-') {
+                    Some(short) => format!("-s {}", short),
+                    None => format!("-a {}", fish_quote(&word.text)),
+                },
+            };
+            let values = node
+                .valued
+                .iter()
+                .find(|valued| valued.flags.contains(&word.text))
+                .map(|valued| match valued.values.is_empty() {
+                    true => " -r -F".to_string(),
+                    false => format!(" -x -a {}", fish_quote(&valued.values.join(" "))),
+                })
+                .unwrap_or_default();
+            out.push_str(&format!(
+                "complete -c {} -n {} {}{} -d {}\n",
+                name,
+                condition,
+                spelling,
+                values,
+                fish_quote(&word.help)
+            ));
+        }
+    }
+    out
+}
+
+fn powershell(name: &str, nodes: &[Node]) -> String {
+    let mut out = format!(
+        "# PowerShell completion for {name}\n\
+         Register-ArgumentCompleter -Native -CommandName {name} -ScriptBlock {{\n\
+         \x20   param($wordToComplete, $commandAst, $cursorPosition)\n\
+         \x20   $typed = @($commandAst.CommandElements | Select-Object -Skip 1 |\n\
+         \x20       Where-Object {{ $_.Extent.EndOffset -lt $cursorPosition }} |\n\
+         \x20       ForEach-Object {{ \"$_\" }})\n\
+         \x20   $cmd = {}\n\
+         \x20   foreach ($word in $typed) {{\n\
+         \x20       switch (\"${{cmd}}:$word\") {{\n",
+        ps_quote(name)
+    );
+    for node in nodes {
+        for (word, child) in &node.children {
+            out.push_str(&format!(
+                "            {} {{ $cmd = {} }}\n",
+                ps_quote(&format!("{}:{}", node.path, word)),
+                ps_quote(child)
+            ));
+        }
+    }
+    out.push_str(
+        "        }\n    }\n    $prev = if ($typed.Count) { $typed[-1] } else { '' }\n    \
+         $values = switch (\"${cmd}:$prev\") {\n",
+    );
+    for node in nodes {
+        for valued in &node.valued {
+            let values: Vec<String> = valued.values.iter().map(|v| ps_quote(v)).collect();
+            // No values to offer: leave it to PowerShell's file completion
+            let values = match values.is_empty() {
+                true => "return".to_string(),
+                false => format!("@({})", values.join(", ")),
+            };
+            for flag in &valued.flags {
+                out.push_str(&format!(
+                    "        {} {{ {} }}\n",
+                    ps_quote(&format!("{}:{}", node.path, flag)),
+                    values
+                ));
+            }
+        }
+    }
+    out.push_str("    }\n    if ($values) {\n        $values | Where-Object { $_ -like \"$wordToComplete*\" } |\n            ForEach-Object { [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_) }\n        return\n    }\n    $words = switch ($cmd) {\n");
+    for node in nodes {
+        let words: Vec<String> = node
+            .words
+            .iter()
+            .map(|word| format!("@({}, {})", ps_quote(&word.text), ps_quote(&word.help)))
+            .collect();
+        out.push_str(&format!(
+            "        {} {{ @({}) }}\n",
+            ps_quote(&node.path),
+            words.join(", ")
+        ));
+    }
+    out.push_str(
+        "    }\n    $words | Where-Object { $_[0] -like \"$wordToComplete*\" } | ForEach-Object {\n        \
+         $help = if ($_[1]) { $_[1] } else { $_[0] }\n        \
+         [System.Management.Automation.CompletionResult]::new($_[0], $_[0], 'ParameterName', $help)\n    \
+         }\n}\n",
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction, Command};
+
+    fn command() -> Command {
+        Command::new("wimesh")
+            .arg(Arg::new("config").short('c').long("config").global(true))
+            .subcommand(
+                Command::new("config")
+                    .about("Work on the config file")
+                    .subcommand(
+                        Command::new("migrate").about("Rewrite it").arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .action(ArgAction::SetTrue),
+                        ),
+                    ),
+            )
+            .subcommand(
+                Command::new("control")
+                    .about("Nudge the daemon")
+                    .arg(Arg::new("action").value_parser(["status", "pause"])),
+            )
+            .subcommand(Command::new("secret-dump").hide(true))
+    }
+
+    #[test]
+    fn test_scripts_cover_the_tree() {
+        let bash = generate(Shell::Bash, command());
+        assert!(bash.contains("\"wimesh:config\") cmd=\"wimesh config\" ;;"));
+        assert!(bash.contains("\"wimesh config:migrate\") cmd=\"wimesh config migrate\" ;;"));
+        assert!(bash.contains("\"wimesh config migrate\") words=\"--dry-run --config -c"));
+        assert!(bash.contains("\"wimesh control\") words=\"status pause"));
+        // A free-form value completes as a file, in every subcommand
+        assert!(bash.contains("\"wimesh control:--config\"|\"wimesh control:-c\")"));
+        assert!(!bash.contains("secret-dump"));
+        assert!(bash.ends_with("complete -F _wimesh -o bashdefault -o default wimesh\n"));
+
+        let zsh = generate(Shell::Zsh, command());
+        assert!(zsh.starts_with("#compdef wimesh\n"));
+        assert!(zsh.contains("'config:Work on the config file'"));
+
+        let fish = generate(Shell::Fish, command());
+        assert!(fish.contains(
+            r"complete -c wimesh -n '__wimesh_path \'wimesh control\'' -s c -r -F -d ''"
+        ));
+
+        let powershell = generate(Shell::PowerShell, command());
+        assert!(powershell.contains("'wimesh:control' { $cmd = 'wimesh control' }"));
+        assert!(powershell.contains("@('migrate', 'Rewrite it')"));
+    }
+}
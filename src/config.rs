@@ -1,15 +1,82 @@
 //! Configuration management
 //!
-//! This module handles loading and validating configuration from TOML files.
-//! The config supports multiple portal types with their specific settings.
+//! This module handles loading and validating configuration from TOML files,
+//! or YAML and JSON ones for people generating their configs (told apart by
+//! extension, same keys). The config supports multiple portal types with
+//! their specific settings.
 
+use crate::policy::{PolicyOverrides, RetryPolicy};
+use crate::secrets::Secret;
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File names searched for in each config directory, in order
+const CONFIG_FILE_NAMES: &[&str] = &["config.toml", "config.yaml", "config.yml", "config.json"];
+
+/// Format version of the configs this build writes; `migrate` brings older
+/// files up to it
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Keys every portal type shares; anything else in a `[[portals]]` entry
+/// or group belongs to the portal type
+const PORTAL_KEYS: &[&str] = &[
+    "name",
+    "group",
+    "type",
+    "ssids",
+    "mac_address",
+    "session_minutes",
+    "schedule",
+    "identity",
+];
+
+/// Syntax of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format of `path` by its extension; anything unknown is TOML
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    pub fn parse(self, contents: &str) -> Result<Config> {
+        let mut document: serde_json::Value = match self {
+            Self::Toml => toml::from_str(contents)?,
+            Self::Yaml => serde_yaml::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents)?,
+        };
+        if document.get("groups").is_some() {
+            apply_groups(&mut document)?;
+            return Ok(serde_json::from_value(document)?);
+        }
+        // Straight from the text, for errors with line numbers
+        Ok(match self {
+            Self::Toml => toml::from_str(contents)?,
+            Self::Yaml => serde_yaml::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents)?,
+        })
+    }
+}
 
 /// Root configuration structure
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Format version of the file; files without one are version 1
+    #[serde(default = "default_config_version")]
+    pub version: u32,
+
     /// Global settings
     #[serde(default)]
     pub global: GlobalConfig,
@@ -21,6 +88,62 @@ pub struct Config {
     /// Logging settings
     #[serde(default)]
     pub logging: LoggingConfig,
+
+    /// Privacy settings
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+
+    /// Machine-readable event log
+    #[serde(default)]
+    pub events: EventsConfig,
+
+    /// Where the runtime state is kept
+    #[serde(default)]
+    pub storage: StorageConfig,
+
+    /// Escalation after a long outage
+    #[serde(default)]
+    pub recovery: RecoveryConfig,
+
+    /// Hash-chained log of the logins performed
+    #[serde(default)]
+    pub audit: AuditConfig,
+
+    /// Taking turns with other instances on the LAN
+    #[serde(default)]
+    pub coop: CoopConfig,
+
+    /// Answering the LAN's connectivity probes
+    #[serde(default)]
+    pub responder: ResponderConfig,
+
+    /// In-process DNS cache
+    #[serde(default)]
+    pub dns: DnsConfig,
+
+    /// How the internet is told from a portal holding traffic
+    #[serde(default)]
+    pub connectivity: ConnectivityConfig,
+
+    /// The network's Captive Portal API (RFC 8908)
+    #[serde(default)]
+    pub capport: CapportConfig,
+
+    /// New MACs for sessions limited per MAC
+    #[serde(default)]
+    pub mac_rotation: MacRotationConfig,
+
+    /// The socket `wimesh control` talks to the daemon on
+    #[serde(default)]
+    pub control: ControlConfig,
+
+    /// Retry policies of requests, flow steps and the daemon
+    #[serde(default)]
+    pub policy: PolicyConfig,
+
+    /// OpenTelemetry traces of the login flows
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
     
     /// Portal configurations (multiple portals supported)
     #[serde(default)]
@@ -28,27 +151,110 @@ pub struct Config {
 }
 
 /// Global daemon settings
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GlobalConfig {
     /// Check interval in seconds for daemon mode
     #[serde(default = "default_check_interval")]
     pub check_interval: u64,
+
+    /// Maximum time in seconds to wait for another login flow on the same
+    /// interface to finish before giving up
+    #[serde(default = "default_login_lock_timeout")]
+    pub login_lock_timeout: u64,
+
+    /// How long in seconds a cached portal session may be re-validated
+    /// instead of running the full login flow (0 disables the cache)
+    #[serde(default = "default_session_cache_ttl")]
+    pub session_cache_ttl: u64,
+
+    /// Check that the WiFi gateway answers before running a login flow, and
+    /// skip the attempt if it does not
+    #[serde(default = "default_probe_gateway")]
+    pub probe_gateway: bool,
+
+    /// Seconds the daemon waits after repeated login failures on an SSID;
+    /// doubles with every further failure
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base: u64,
+
+    /// Upper bound in seconds of the failure backoff
+    #[serde(default = "default_backoff_max")]
+    pub backoff_max: u64,
+
+    /// Logins failing on timeouts in a row that turn on congestion mode
+    /// (0 disables it)
+    #[serde(default = "default_congestion_threshold")]
+    pub congestion_threshold: u32,
+
+    /// Ask for a fresh DHCP lease when the daemon finds the WiFi without a
+    /// usable IP address, instead of only reporting it
+    #[serde(default)]
+    pub renew_dhcp: bool,
+
+    /// Detect and report, but never log in (monitoring, or while someone
+    /// debugs the portal by hand); `wimesh read-only` overrides it at
+    /// runtime
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// SSIDs never logged in on or probed, beyond seeing the association
+    /// (a phone hotspot, eduroam)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_ssids: Vec<String>,
+
+    /// Also leave alone connections NetworkManager marks metered, as it
+    /// does for phone tethering
+    #[serde(default = "default_skip_metered")]
+    pub skip_metered: bool,
+
+    /// Check as soon as NetworkManager reports a change on the system bus,
+    /// besides every `check_interval`
+    #[serde(default = "default_watch_network")]
+    pub watch_network: bool,
+
+    /// Let portals accept a venue's terms of use on the user's behalf
+    /// (`fpt`); without it they are never logged in through
+    #[serde(default = "default_accept_terms")]
+    pub accept_terms: bool,
+}
+
+impl GlobalConfig {
+    /// Whether `ssid` is in `deny_ssids`
+    pub fn denies(&self, ssid: &str) -> bool {
+        self.deny_ssids.iter().any(|denied| denied == ssid)
+    }
 }
 
 impl Default for GlobalConfig {
     fn default() -> Self {
         Self {
             check_interval: default_check_interval(),
+            login_lock_timeout: default_login_lock_timeout(),
+            session_cache_ttl: default_session_cache_ttl(),
+            probe_gateway: default_probe_gateway(),
+            backoff_base: default_backoff_base(),
+            backoff_max: default_backoff_max(),
+            congestion_threshold: default_congestion_threshold(),
+            renew_dhcp: false,
+            read_only: false,
+            deny_ssids: Vec::new(),
+            skip_metered: default_skip_metered(),
+            watch_network: default_watch_network(),
+            accept_terms: default_accept_terms(),
         }
     }
 }
 
 /// Configuration for a single portal
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PortalConfig {
     /// Human-readable name for this portal
     pub name: String,
     
+    /// `[groups.<name>]` this portal takes its defaults from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+
     /// Portal type: "awing", "fpt", etc.
     #[serde(rename = "type")]
     pub portal_type: String,
@@ -56,16 +262,122 @@ pub struct PortalConfig {
     /// SSIDs that this portal handles
     pub ssids: Vec<String>,
     
-    /// MAC address for authentication (optional, auto-detect if empty)
+    /// MAC address for authentication (optional; the WiFi interface's own
+    /// if empty, see `utils::get_interface_mac`)
     #[serde(default)]
     pub mac_address: String,
+
+    /// Length of a portal session in minutes, if the venue limits it
+    #[serde(default)]
+    pub session_minutes: Option<u64>,
+
+    /// Local time windows this entry handles its SSIDs in, like
+    /// `"mon-fri 08:00-22:00"`; always when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<String>,
+
+    /// What the device presents to this venue
+    #[serde(default)]
+    pub identity: IdentityConfig,
     
     /// Additional portal-specific settings (for future extensibility)
     #[serde(flatten)]
     pub extra: std::collections::HashMap<String, toml::Value>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl PortalConfig {
+    /// A setting of this portal's type: from the table named after the
+    /// type (`awing.quirks`), or straight from the entry in version 1
+    /// configs
+    pub fn setting(&self, key: &str) -> Option<&toml::Value> {
+        self.extra
+            .get(&self.portal_type)
+            .and_then(|table| table.get(key))
+            .or_else(|| self.extra.get(key))
+    }
+
+    /// The credential setting `key` holds, written out or in the keyring;
+    /// empty when unset
+    pub fn secret(&self, key: &str) -> Secret {
+        self.setting(key)
+            .and_then(Secret::from_value)
+            .unwrap_or_default()
+    }
+
+    /// Keys of `extra` this portal's type does not read, as dotted paths
+    /// (`awing.quirk`); none for types this build does not know the
+    /// settings of
+    pub fn unused_settings(&self) -> Vec<String> {
+        let Some(settings) = crate::portal::settings(&self.portal_type) else {
+            return Vec::new();
+        };
+        let mut unused = Vec::new();
+        for (key, value) in &self.extra {
+            if *key == self.portal_type {
+                let table = value.as_table().into_iter().flatten();
+                unused.extend(
+                    table
+                        .filter(|(key, _)| !settings.contains(&key.as_str()))
+                        .map(|(inner, _)| format!("{}.{}", key, inner)),
+                );
+            } else if !settings.contains(&key.as_str()) {
+                // Version 1 configs have the settings on the entry itself
+                unused.push(key.clone());
+            }
+        }
+        unused.sort();
+        unused
+    }
+
+    /// This entry as a `[[portals]]` table fit to hand to someone else:
+    /// what the venue needs, without the group it leans on, the device's
+    /// MAC and identity, secrets or paths on this machine
+    pub fn shareable(&self) -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(self)?;
+        if let Some(table) = value.as_table_mut() {
+            for key in ["group", "mac_address", "identity"] {
+                table.remove(key);
+            }
+        }
+        drop_private(&mut value);
+        Ok(value)
+    }
+
+    /// `shareable` as a file `wimesh portal import` reads
+    pub fn to_shareable_toml(&self) -> Result<String> {
+        let mut document = toml::Table::new();
+        document.insert("portals".into(), toml::Value::Array(vec![self.shareable()?]));
+        Ok(format!(
+            "# Portal '{}' for wimesh, add it with `wimesh portal import <file>`\n{}",
+            self.name,
+            toml::to_string_pretty(&document)?
+        ))
+    }
+}
+
+/// Per-portal identity, so venues cannot correlate the device across them
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct IdentityConfig {
+    /// Generate a random MAC and User-Agent for this portal once, and keep
+    /// presenting them; explicit values below and `mac_address` win
+    #[serde(default)]
+    pub auto: bool,
+
+    /// Fixed User-Agent for this portal
+    #[serde(default)]
+    pub user_agent: Option<String>,
+
+    /// Name filled into the portal's customer form
+    #[serde(default)]
+    pub customer_name: String,
+
+    /// Also make the identity's MAC the WiFi connection's cloned MAC, so
+    /// the hotspot sees the same address the portal is told about
+    #[serde(default)]
+    pub apply_to_wifi: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HttpConfig {
     /// Request timeout in seconds
     #[serde(default = "default_timeout")]
@@ -75,7 +387,7 @@ pub struct HttpConfig {
     #[serde(default = "default_connect_timeout")]
     pub connect_timeout: u64,
 
-    /// Maximum number of retries
+    /// Attempts per request (`[policy.http] max_attempts` wins)
     #[serde(default = "default_max_retries")]
     pub max_retries: u32,
 }
@@ -90,7 +402,7 @@ impl Default for HttpConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoggingConfig {
     /// Log level
     #[serde(default = "default_log_level")]
@@ -99,6 +411,32 @@ pub struct LoggingConfig {
     /// Optional log file path
     #[serde(default)]
     pub log_file: String,
+
+    /// Seconds a run of identical log lines is collapsed into one "repeated
+    /// N times" line at most; 0 keeps every line
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+
+    /// How lines are written, to the terminal and the log file alike
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// When `log_file` is moved aside for a new one
+    #[serde(default)]
+    pub rotation: LogRotation,
+
+    /// Size in KiB `rotation = "size"` moves the file aside at
+    #[serde(default = "default_log_max_size_kb")]
+    pub max_size_kb: u64,
+
+    /// Number of rotated files to keep, `wimesh.log.1` the newest
+    #[serde(default = "default_log_keep")]
+    pub keep: u32,
+
+    /// Log to the terminal (or journal) too; with `log_file` set, off
+    /// leaves the file as the only output
+    #[serde(default = "default_log_console")]
+    pub console: bool,
 }
 
 impl Default for LoggingConfig {
@@ -106,84 +444,1938 @@ impl Default for LoggingConfig {
         Self {
             level: default_log_level(),
             log_file: String::new(),
+            dedup_window: default_dedup_window(),
+            format: LogFormat::default(),
+            rotation: LogRotation::default(),
+            max_size_kb: default_log_max_size_kb(),
+            keep: default_log_keep(),
+            console: default_log_console(),
         }
     }
 }
 
-// Default value functions
-fn default_check_interval() -> u64 {
-    5
+/// Formats of the log lines
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `2024-05-01T12:34:56.789012Z  INFO wimesh::daemon: Login successful`
+    #[default]
+    Text,
+    /// One JSON object per line (see `jsonlog`)
+    Json,
 }
 
-fn default_timeout() -> u64 {
-    10
+/// When the log file is rotated (see `logs::LogFile`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// Never: the file grows for as long as it is written
+    Never,
+    /// At the first line of a day, local time
+    Daily,
+    /// Once it has grown to `max_size_kb`
+    #[default]
+    Size,
 }
 
-fn default_connect_timeout() -> u64 {
-    5
+/// What the portal gets to learn about this device
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PrivacyConfig {
+    /// Skip the Awing analytics call (the ad network's tracking beacon)
+    #[serde(default)]
+    pub skip_analytics: bool,
+
+    /// Send no OS/platform or locale hints in HTTP headers
+    #[serde(default)]
+    pub strip_device_hints: bool,
+
+    /// Present a different common User-Agent for every login session
+    #[serde(default)]
+    pub randomize_user_agent: bool,
+
+    /// Never write the real MAC address to the logs
+    #[serde(default)]
+    pub redact_mac: bool,
 }
 
-fn default_max_retries() -> u32 {
-    3
+/// Persistence of sessions, backoff and the other runtime state
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StorageConfig {
+    /// "json" (default) or "sqlite" (builds with the `sqlite` feature)
+    #[serde(default)]
+    pub backend: StorageBackend,
 }
 
-fn default_log_level() -> String {
-    "info".to_string()
+/// When and how the daemon escalates after a long outage
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecoveryConfig {
+    /// Seconds an SSID has to be captive before recovery mode starts
+    /// (0 disables it)
+    #[serde(default = "default_recovery_window")]
+    pub window: u64,
+
+    /// Seconds between two rungs of the ladder, so each gets a login
+    /// attempt of its own
+    #[serde(default = "default_recovery_step_interval")]
+    pub step_interval: u64,
+
+    /// Include the "rotate MAC" rung; needs `identity.auto` on the portal
+    #[serde(default)]
+    pub rotate_mac: bool,
 }
 
-impl Config {
-    /// Load configuration from file, or use defaults if not found
-    pub fn load() -> Result<Self> {
-        let config_paths = vec![
-            PathBuf::from("config.toml"),
-            PathBuf::from("wimesh-rs/config.toml"),
-            PathBuf::from("/etc/wimesh/config.toml"),
-            dirs::home_dir()
-                .map(|h| h.join(".config/wimesh/config.toml"))
-                .unwrap_or_default(),
-        ];
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            window: default_recovery_window(),
+            step_interval: default_recovery_step_interval(),
+            rotate_mac: false,
+        }
+    }
+}
 
-        // Try to find config file
-        for path in &config_paths {
-            if path.exists() {
-                tracing::debug!("Loading config from: {}", path.display());
-                let contents = std::fs::read_to_string(path)
-                    .context("Failed to read config file")?;
-                
-                let config: Config = toml::from_str(&contents)
-                    .context("Failed to parse config file")?;
-                
-                return Ok(config);
-            }
+/// Tamper-evident `audit.jsonl`, for machines several people share
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AuditConfig {
+    /// Record every login attempt
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Audit file path (default: `audit.jsonl` in the state directory)
+    #[serde(default)]
+    pub path: String,
+}
+
+/// mDNS discovery of other wimesh daemons on the same network
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CoopConfig {
+    /// Announce this daemon and take turns logging in with its peers
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Seconds to leave a peer that is logging in on the same SSID before
+    /// trying ourselves
+    #[serde(default = "default_coop_stagger")]
+    pub stagger: u64,
+}
+
+impl Default for CoopConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stagger: default_coop_stagger(),
         }
+    }
+}
+
+/// Connectivity-probe responder for devices behind a wimesh router
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponderConfig {
+    /// Answer probes while the daemon runs
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Address and port of the HTTP probe server
+    #[serde(default = "default_responder_http_listen")]
+    pub http_listen: String,
 
-        // No config file found, use defaults
-        tracing::debug!("No config file found, using defaults");
-        Ok(Self::default())
+    /// Address and port of the DNS server for the probe hostnames (empty:
+    /// no DNS)
+    #[serde(default)]
+    pub dns_listen: String,
+
+    /// IPv4 address the probe hostnames resolve to: this machine's LAN
+    /// address, where `http_listen` serves on port 80
+    #[serde(default)]
+    pub address: String,
+}
+
+impl Default for ResponderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            http_listen: default_responder_http_listen(),
+            dns_listen: String::new(),
+            address: String::new(),
+        }
     }
+}
 
-    /// Get all SSIDs from all configured portals
-    pub fn all_ssids(&self) -> Vec<&str> {
-        self.portals
-            .iter()
-            .flat_map(|p| p.ssids.iter().map(|s| s.as_str()))
-            .collect()
+/// Caching resolver for the portal and probe hostnames
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsConfig {
+    /// Resolve in-process and keep the answers, instead of asking the
+    /// system resolver every time
+    #[serde(default)]
+    pub cache: bool,
+
+    /// Longest an answer is kept, in seconds, whatever its TTL
+    #[serde(default = "default_dns_max_ttl")]
+    pub max_ttl: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            cache: false,
+            max_ttl: default_dns_max_ttl(),
+        }
     }
 }
 
-impl Default for Config {
+/// The check URLs asked whether the internet is reachable
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectivityConfig {
+    /// Tried in order until one answers; the first to answer decides
+    #[serde(default = "default_connectivity_probes")]
+    pub probes: Vec<ProbeConfig>,
+
+    /// Seconds each probe gets, lookup and request
+    #[serde(default = "default_connectivity_timeout")]
+    pub timeout: u64,
+}
+
+/// Where the Captive Portal API is, if the network has one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CapportConfig {
+    /// Ask the API, when one is known, before the probe URLs
+    #[serde(default = "default_capport_enabled")]
+    pub enabled: bool,
+
+    /// The API's URI; empty takes the one of the DHCP lease (option 114)
+    #[serde(default)]
+    pub url: String,
+}
+
+/// When the daemon presents a new MAC to start a new session
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MacRotationConfig {
+    /// Rotate the MAC once a login is as old as the session
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Minutes after a login to rotate at (0: the portal's
+    /// `session_minutes`)
+    #[serde(default)]
+    pub after_minutes: u64,
+}
+
+/// The daemon's control socket (see `control`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ControlConfig {
+    /// Listen for `wimesh control` while the daemon runs
+    #[serde(default = "default_control_enabled")]
+    pub enabled: bool,
+
+    /// Socket to listen on; empty is `control.sock` in the state directory
+    /// (the pipe `\\.\pipe\wimesh` on Windows)
+    #[serde(default)]
+    pub path: String,
+}
+
+impl Default for ControlConfig {
     fn default() -> Self {
         Self {
-            global: GlobalConfig::default(),
-            http: HttpConfig::default(),
-            logging: LoggingConfig::default(),
-            portals: vec![PortalConfig {
-                name: "KTX Khu B".to_string(),
-                portal_type: "awing".to_string(),
-                ssids: vec!["1.Free Wi-MESH".to_string()],
-                mac_address: String::new(),
-                extra: std::collections::HashMap::new(),
-            }],
+            enabled: default_control_enabled(),
+            path: String::new(),
+        }
+    }
+}
+
+impl Default for CapportConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_capport_enabled(),
+            url: String::new(),
+        }
+    }
+}
+
+impl Default for ConnectivityConfig {
+    fn default() -> Self {
+        Self {
+            probes: default_connectivity_probes(),
+            timeout: default_connectivity_timeout(),
+        }
+    }
+}
+
+/// One check URL, and what it answers when nothing is in the way
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProbeConfig {
+    pub url: String,
+
+    /// The exact status; 200 when only `body` is given, any below 400 when
+    /// neither is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+
+    /// What the body starts with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+/// What `[policy.http]`, `[policy.step]` and `[policy.daemon]` change
+/// of the defaults of each use (see `policy`)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PolicyConfig {
+    /// Every HTTP request; defaults to `[http]`'s timeout and max_retries
+    #[serde(default)]
+    pub http: PolicyOverrides,
+
+    /// Every step of a portal flow; defaults to a single try
+    #[serde(default)]
+    pub step: PolicyOverrides,
+
+    /// Waiting after failed logins on an SSID; defaults to `[global]`'s
+    /// backoff_base and backoff_max after 3 failures
+    #[serde(default)]
+    pub daemon: PolicyOverrides,
+
+    /// Seconds one login flow may take in all; each step gets what is
+    /// left as its timeout. No limit when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_budget: Option<f64>,
+}
+
+/// Where the traces of login flows are exported (see `telemetry`)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TelemetryConfig {
+    /// Export the spans of logins and their steps over OTLP
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Base URL of the collector's OTLP/HTTP endpoint; traces go to
+    /// `/v1/traces` under it
+    #[serde(default = "default_telemetry_endpoint")]
+    pub endpoint: String,
+
+    /// `service.name` of the exported traces
+    #[serde(default = "default_telemetry_service_name")]
+    pub service_name: String,
+
+    /// Extra request headers, e.g. the API key of a hosted backend; masked
+    /// by `--print-config`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: default_telemetry_endpoint(),
+            service_name: default_telemetry_service_name(),
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+/// Implementations of `store::StateStore`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Json,
+    Sqlite,
+}
+
+/// Append-only JSONL event file, for the stats command and dashboards
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventsConfig {
+    /// Write the event file at all
+    #[serde(default = "default_events_enabled")]
+    pub enabled: bool,
+
+    /// Event file path (default: `events.jsonl` in the state directory)
+    #[serde(default)]
+    pub path: String,
+
+    /// Rotate the file once it grows past this many KiB
+    #[serde(default = "default_events_max_size_kb")]
+    pub max_size_kb: u64,
+
+    /// Number of rotated files to keep
+    #[serde(default = "default_events_keep")]
+    pub keep: u32,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_events_enabled(),
+            path: String::new(),
+            max_size_kb: default_events_max_size_kb(),
+            keep: default_events_keep(),
         }
     }
 }
+
+// Default value functions
+fn default_config_version() -> u32 {
+    1
+}
+
+fn default_check_interval() -> u64 {
+    5
+}
+
+fn default_login_lock_timeout() -> u64 {
+    60
+}
+
+fn default_session_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_probe_gateway() -> bool {
+    true
+}
+
+fn default_skip_metered() -> bool {
+    true
+}
+
+fn default_watch_network() -> bool {
+    true
+}
+
+fn default_accept_terms() -> bool {
+    true
+}
+
+fn default_backoff_base() -> u64 {
+    60
+}
+
+fn default_backoff_max() -> u64 {
+    3600
+}
+
+fn default_congestion_threshold() -> u32 {
+    3
+}
+
+fn default_connectivity_probes() -> Vec<ProbeConfig> {
+    let probe = |url: &str, status, body: Option<&str>| ProbeConfig {
+        url: url.to_string(),
+        status,
+        body: body.map(str::to_string),
+    };
+    vec![
+        probe("https://www.google.com", None, None),
+        probe("http://connectivitycheck.gstatic.com/generate_204", Some(204), None),
+        probe("http://detectportal.firefox.com/success.txt", None, Some("success")),
+    ]
+}
+
+fn default_connectivity_timeout() -> u64 {
+    5
+}
+
+fn default_capport_enabled() -> bool {
+    true
+}
+
+fn default_control_enabled() -> bool {
+    true
+}
+
+fn default_dns_max_ttl() -> u64 {
+    3600
+}
+
+fn default_dedup_window() -> u64 {
+    900
+}
+
+fn default_coop_stagger() -> u64 {
+    15
+}
+
+fn default_responder_http_listen() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+fn default_recovery_window() -> u64 {
+    1800
+}
+
+fn default_recovery_step_interval() -> u64 {
+    300
+}
+
+fn default_events_enabled() -> bool {
+    true
+}
+
+fn default_events_max_size_kb() -> u64 {
+    1024
+}
+
+fn default_events_keep() -> u32 {
+    3
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_connect_timeout() -> u64 {
+    5
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_max_size_kb() -> u64 {
+    10 * 1024
+}
+
+fn default_log_keep() -> u32 {
+    5
+}
+
+fn default_log_console() -> bool {
+    true
+}
+
+fn default_telemetry_endpoint() -> String {
+    "http://localhost:4318".to_string()
+}
+
+fn default_telemetry_service_name() -> String {
+    "wimesh".to_string()
+}
+
+impl Config {
+    /// Load configuration from file, or use defaults if not found
+    pub fn load() -> Result<Self> {
+        match Self::find() {
+            Some(path) => Self::load_file(&path),
+            None => {
+                // No config file found, use defaults
+                tracing::debug!("No config file found, using defaults");
+                Ok(Self::default())
+            }
+        }
+    }
+
+    /// Load `path` if given, which must then exist, else search the usual
+    /// locations like `load`
+    pub fn load_from(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::load_file(path),
+            None => Self::load(),
+        }
+    }
+
+    /// Every path a config file is looked for at, in order
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut config_dirs = vec![
+            PathBuf::from("."),
+            PathBuf::from("wimesh-rs"),
+            PathBuf::from("/etc/wimesh"),
+            PathBuf::from("/usr/local/etc/wimesh"),
+        ];
+        config_dirs.extend(dirs::home_dir().map(|h| h.join(".config/wimesh")));
+
+        config_dirs
+            .into_iter()
+            .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+            .collect()
+    }
+
+    /// Where `wimesh init` writes a new config: the user's
+    /// `~/.config/wimesh`, which `search_paths` covers
+    pub fn user_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".config/wimesh").join(CONFIG_FILE_NAMES[0]))
+    }
+
+    /// The first existing config file in the search path
+    pub fn find() -> Option<PathBuf> {
+        Self::search_paths().into_iter().find(|path| path.exists())
+    }
+
+    pub fn load_file(path: &Path) -> Result<Self> {
+        tracing::debug!("Loading config from: {}", path.display());
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        ConfigFormat::from_path(path)
+            .parse(&contents)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Problems that make the config not do what was probably meant, e.g.
+    /// an SSID claimed by two portals without schedules (the first one
+    /// always wins)
+    pub fn problems(&self) -> Vec<String> {
+        self.diagnostics().into_iter().map(|d| d.message).collect()
+    }
+
+    /// `problems`, each with the setting it is about
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut problems = Vec::new();
+        let mut problem = |field: String, message: String| {
+            problems.push(Diagnostic::at(&field, message));
+        };
+        if self.portals.is_empty() {
+            problem("portals".into(), "No portals configured".to_string());
+        }
+
+        let mut names = std::collections::HashSet::new();
+        let mut ssids: std::collections::HashMap<&str, &str> = std::collections::HashMap::new();
+        for (i, portal) in self.portals.iter().enumerate() {
+            let field = |key: &str| format!("portals[{}].{}", i, key);
+            if !names.insert(portal.name.as_str()) {
+                problem(field("name"), format!("Portal name '{}' is used twice", portal.name));
+            }
+            let known = crate::portal::PORTAL_TYPES
+                .iter()
+                .any(|(name, _)| *name == portal.portal_type);
+            if !known {
+                problem(
+                    field("type"),
+                    format!(
+                        "Portal '{}': {}",
+                        portal.name,
+                        crate::portal::missing_type(&portal.portal_type)
+                    ),
+                );
+            }
+            if portal.ssids.is_empty() {
+                problem(field("ssids"), format!("Portal '{}' has no SSIDs", portal.name));
+            }
+            let mac = &portal.mac_address;
+            if !mac.is_empty() && crate::utils::normalize_mac(mac).is_none() {
+                problem(
+                    field("mac_address"),
+                    format!("Portal '{}': mac_address '{}' is not a MAC address", portal.name, mac),
+                );
+            }
+            for key in portal.unused_settings() {
+                problem(
+                    field(&key),
+                    format!(
+                        "Portal '{}': {} is not a setting of {} portals, it does nothing",
+                        portal.name, key, portal.portal_type
+                    ),
+                );
+            }
+            let needs_account = crate::portal::capabilities(&portal.portal_type)
+                .is_some_and(|c| c.needs_credentials);
+            let username = portal.setting("username").and_then(|v| v.as_str());
+            if needs_account && username.is_none_or(str::is_empty) {
+                problem(
+                    field(&format!("{}.username", portal.portal_type)),
+                    format!(
+                        "Portal '{}' has no {}.username, {} portals log in with an account",
+                        portal.name, portal.portal_type, portal.portal_type
+                    ),
+                );
+            }
+            if let Err(e) = crate::schedule::Schedule::parse(&portal.schedule) {
+                problem(field("schedule"), format!("Portal '{}': {:#}", portal.name, e));
+            }
+            for ssid in portal.ssids.iter().filter(|ssid| self.global.denies(ssid)) {
+                problem(
+                    field("ssids"),
+                    format!(
+                        "SSID '{}' of '{}' is in global.deny_ssids, which wins",
+                        ssid, portal.name
+                    ),
+                );
+            }
+            // Entries with schedules share SSIDs by design
+            if !portal.schedule.is_empty() {
+                continue;
+            }
+            for ssid in &portal.ssids {
+                if let Some(first) = ssids.insert(ssid, &portal.name) {
+                    problem(
+                        field("ssids"),
+                        format!(
+                            "SSID '{}' is claimed by both '{}' and '{}'",
+                            ssid, first, portal.name
+                        ),
+                    );
+                }
+            }
+        }
+        if self.version > CONFIG_VERSION {
+            problem(
+                "version".into(),
+                format!(
+                    "Config format version {} is newer than this build reads ({})",
+                    self.version, CONFIG_VERSION
+                ),
+            );
+        }
+        if self.global.check_interval == 0 {
+            problem(
+                "global.check_interval".into(),
+                "global.check_interval must be at least 1".to_string(),
+            );
+        }
+        if self.storage.backend == StorageBackend::Sqlite && !cfg!(feature = "sqlite") {
+            problem(
+                "storage.backend".into(),
+                "storage.backend = \"sqlite\" needs a build with the `sqlite` feature".to_string(),
+            );
+        }
+        if self.responder.enabled
+            && !self.responder.dns_listen.is_empty()
+            && self.responder.address.parse::<std::net::Ipv4Addr>().is_err()
+        {
+            problem(
+                "responder.address".into(),
+                "responder.dns_listen needs responder.address, the IPv4 address to resolve \
+                 probe hostnames to"
+                    .to_string(),
+            );
+        }
+        if self.connectivity.probes.is_empty() {
+            problem(
+                "connectivity.probes".into(),
+                "connectivity.probes is empty, nothing tells online".to_string(),
+            );
+        }
+        for (i, probe) in self.connectivity.probes.iter().enumerate() {
+            if !probe.url.starts_with("http://") && !probe.url.starts_with("https://") {
+                problem(
+                    format!("connectivity.probes[{}].url", i),
+                    format!("Connectivity probe '{}' is not an http(s) URL", probe.url),
+                );
+            }
+        }
+        if !self.capport.url.is_empty() && !self.capport.url.starts_with("https://") {
+            problem(
+                "capport.url".into(),
+                format!(
+                    "capport.url '{}' is not an https URL, which RFC 8908 requires",
+                    self.capport.url
+                ),
+            );
+        }
+        problems
+    }
+
+    /// Add `ssid` to the SSIDs of `portal` in the config file at `path`,
+    /// editing the file in place so its comments and layout survive
+    pub fn add_ssid_to_file(path: &Path, portal: &str, ssid: &str) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let format = ConfigFormat::from_path(path);
+        let updated = match format {
+            ConfigFormat::Toml => insert_ssid(&contents, portal, ssid)?,
+            ConfigFormat::Json => insert_ssid_json(&contents, portal, ssid)?,
+            // Rewriting YAML would drop its comments
+            ConfigFormat::Yaml => anyhow::bail!(
+                "Only TOML and JSON configs are edited automatically, add '{}' to {} by hand",
+                ssid,
+                path.display()
+            ),
+        };
+
+        // The edit is textual; make sure it did what was asked
+        let config = format.parse(&updated).context("Edited config does not parse")?;
+        let added = config
+            .portals
+            .iter()
+            .any(|p| p.name == portal && p.ssids.iter().any(|s| s == ssid));
+        if !added {
+            anyhow::bail!(
+                "Could not add '{}' to portal '{}' automatically, edit {} by hand",
+                ssid,
+                portal,
+                path.display()
+            );
+        }
+
+        std::fs::write(path, updated)
+            .with_context(|| format!("Failed to write config file {}", path.display()))
+    }
+
+    /// Append `portals`, as `shareable` has them, to the config file at
+    /// `path`; none of their names may be taken already
+    pub fn add_portals_to_file(path: &Path, portals: &[PortalConfig]) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let format = ConfigFormat::from_path(path);
+        let current = format.parse(&contents)?;
+        if let Some(taken) = portals
+            .iter()
+            .find(|new| current.portals.iter().any(|p| p.name == new.name))
+        {
+            anyhow::bail!(
+                "{} already has a portal named '{}', rename one of them first",
+                path.display(),
+                taken.name
+            );
+        }
+
+        let entries = portals
+            .iter()
+            .map(PortalConfig::shareable)
+            .collect::<Result<Vec<_>>>()?;
+        let updated = match format {
+            ConfigFormat::Toml => {
+                let mut document = toml::Table::new();
+                document.insert("portals".into(), toml::Value::Array(entries));
+                format!(
+                    "{}\n\n{}",
+                    contents.trim_end(),
+                    toml::to_string_pretty(&document)?
+                )
+            }
+            ConfigFormat::Json => {
+                let mut config: serde_json::Value = serde_json::from_str(&contents)?;
+                let list = config
+                    .as_object_mut()
+                    .context("Config is not an object")?
+                    .entry("portals")
+                    .or_insert_with(|| serde_json::json!([]))
+                    .as_array_mut()
+                    .context("portals is not an array")?;
+                for entry in entries {
+                    list.push(serde_json::to_value(entry)?);
+                }
+                serde_json::to_string_pretty(&config)? + "\n"
+            }
+            // Rewriting YAML would drop its comments
+            ConfigFormat::Yaml => anyhow::bail!(
+                "Only TOML and JSON configs are edited automatically, add the portals to {} by \
+                 hand",
+                path.display()
+            ),
+        };
+
+        let config = format.parse(&updated).context("Edited config does not parse")?;
+        if config.portals.len() != current.portals.len() + portals.len() {
+            anyhow::bail!(
+                "Could not add the portals automatically, edit {} by hand",
+                path.display()
+            );
+        }
+
+        std::fs::write(path, updated)
+            .with_context(|| format!("Failed to write config file {}", path.display()))
+    }
+
+    /// Rewrite the config file at `path` in the current format, after
+    /// copying it to `<path>.v<N>.bak`; None when it already is current
+    pub fn migrate_file(path: &Path) -> Result<Option<(Migration, PathBuf)>> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let Some(migration) = migrate(&contents, ConfigFormat::from_path(path))? else {
+            return Ok(None);
+        };
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", migration.from));
+        let backup = PathBuf::from(backup);
+        if backup.exists() {
+            anyhow::bail!("{} already exists, move it away first", backup.display());
+        }
+        std::fs::copy(path, &backup)
+            .with_context(|| format!("Failed to back up the config to {}", backup.display()))?;
+        std::fs::write(path, &migration.contents)
+            .with_context(|| format!("Failed to write config file {}", path.display()))?;
+        Ok(Some((migration, backup)))
+    }
+
+    /// The whole configuration as TOML, defaults filled in and secrets
+    /// masked
+    pub fn to_masked_toml(&self) -> Result<String> {
+        let mut value = toml::Value::try_from(self)?;
+        mask_secrets(&mut value);
+        // Header names say nothing of which values are secret
+        let headers = value
+            .get_mut("telemetry")
+            .and_then(|telemetry| telemetry.get_mut("headers"))
+            .and_then(toml::Value::as_table_mut);
+        for (_, header) in headers.into_iter().flat_map(|headers| headers.iter_mut()) {
+            *header = toml::Value::String("********".to_string());
+        }
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    /// Get all SSIDs from all configured portals
+    pub fn all_ssids(&self) -> Vec<&str> {
+        self.portals
+            .iter()
+            .flat_map(|p| p.ssids.iter().map(|s| s.as_str()))
+            .collect()
+    }
+
+    /// How every HTTP request is retried
+    pub fn http_policy(&self) -> RetryPolicy {
+        let timeout = Duration::from_secs(self.http.timeout);
+        self.policy
+            .http
+            .over(RetryPolicy::http(timeout, self.http.max_retries))
+    }
+
+    /// How every step of a portal flow is retried
+    pub fn step_policy(&self) -> RetryPolicy {
+        self.policy.step.over(RetryPolicy::step())
+    }
+
+    /// Time a whole login flow may take
+    pub fn flow_budget(&self) -> Option<Duration> {
+        let secs = self.policy.flow_budget.filter(|secs| *secs > 0.0)?;
+        Duration::try_from_secs_f64(secs).ok()
+    }
+
+    /// When the daemon stops trying an SSID after failed logins
+    pub fn daemon_policy(&self) -> RetryPolicy {
+        let base = Duration::from_secs(self.global.backoff_base);
+        let max = Duration::from_secs(self.global.backoff_max);
+        self.policy.daemon.over(RetryPolicy::daemon(3, base, max))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            global: GlobalConfig::default(),
+            http: HttpConfig::default(),
+            logging: LoggingConfig::default(),
+            privacy: PrivacyConfig::default(),
+            events: EventsConfig::default(),
+            storage: StorageConfig::default(),
+            recovery: RecoveryConfig::default(),
+            audit: AuditConfig::default(),
+            coop: CoopConfig::default(),
+            responder: ResponderConfig::default(),
+            dns: DnsConfig::default(),
+            connectivity: ConnectivityConfig::default(),
+            capport: CapportConfig::default(),
+            mac_rotation: MacRotationConfig::default(),
+            control: ControlConfig::default(),
+            policy: PolicyConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            portals: vec![PortalConfig {
+                name: "KTX Khu B".to_string(),
+                group: None,
+                portal_type: "awing".to_string(),
+                ssids: vec!["1.Free Wi-MESH".to_string()],
+                mac_address: String::new(),
+                session_minutes: None,
+                schedule: Vec::new(),
+                identity: IdentityConfig::default(),
+                extra: std::collections::HashMap::new(),
+            }],
+        }
+    }
+}
+
+/// `contents` with `ssid` appended to the `ssids` array of the
+/// `[[portals]]` entry named `portal`
+fn insert_ssid(contents: &str, portal: &str, ssid: &str) -> Result<String> {
+    let quoted = toml::Value::String(ssid.to_string()).to_string();
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+
+    // Line ranges of each [[portals]] entry, up to the next table header
+    let mut entries: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            if let Some(last) = entries.last_mut() {
+                if last.1 == usize::MAX {
+                    last.1 = i;
+                }
+            }
+            if line.starts_with("[[portals]]") {
+                entries.push((i, usize::MAX));
+            }
+        }
+    }
+    if let Some(last) = entries.last_mut() {
+        if last.1 == usize::MAX {
+            last.1 = lines.len();
+        }
+    }
+
+    let key_value = |line: &str, key: &str| -> Option<String> {
+        let (k, v) = line.split_once('=')?;
+        (k.trim() == key).then(|| v.trim().to_string())
+    };
+    let (start, end) = entries
+        .into_iter()
+        .find(|&(start, end)| {
+            lines[start..end].iter().any(|line| {
+                key_value(line, "name")
+                    .and_then(|v| toml::from_str::<toml::Table>(&format!("v = {}", v)).ok())
+                    .and_then(|t| t.get("v")?.as_str().map(|n| n == portal))
+                    .unwrap_or(false)
+            })
+        })
+        .with_context(|| format!("No [[portals]] entry named '{}'", portal))?;
+
+    let Some(at) = (start..end).find(|&i| key_value(&lines[i], "ssids").is_some()) else {
+        lines.insert(start + 1, format!("ssids = [{}]", quoted));
+        return Ok(lines.join("\n") + "\n");
+    };
+
+    let value = key_value(&lines[at], "ssids").unwrap_or_default();
+    if let Some(close) = lines[at].rfind(']').filter(|_| value.starts_with('[')) {
+        // ssids = ["a", "b"]
+        let inner = value.trim_start_matches('[').trim_end_matches(']').trim();
+        let separator = if inner.is_empty() || inner.ends_with(',') { "" } else { ", " };
+        lines[at].insert_str(close, &format!("{}{}", separator, quoted));
+    } else {
+        // ssids = [
+        //     "a",
+        // ]
+        let close = (at + 1..end)
+            .find(|&i| lines[i].trim_start().starts_with(']'))
+            .context("Unterminated ssids array")?;
+        if let Some(prev) = (at + 1..close).rev().find(|&i| !lines[i].trim().is_empty()) {
+            if !lines[prev].trim_end().ends_with(',') && !lines[prev].trim_end().ends_with('[') {
+                lines[prev].push(',');
+            }
+        }
+        lines.insert(close, format!("    {},", quoted));
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Fold `[groups.<name>]` into the portals naming it in `group`, and drop
+/// the `groups` table: the portal's own keys win, tables like `identity`
+/// are merged key by key
+fn apply_groups(document: &mut serde_json::Value) -> Result<()> {
+    let groups = match document
+        .as_object_mut()
+        .and_then(|root| root.remove("groups"))
+    {
+        Some(serde_json::Value::Object(groups)) => groups,
+        Some(_) => anyhow::bail!("groups must be a table of named groups"),
+        None => return Ok(()),
+    };
+    let Some(portals) = document.get_mut("portals").and_then(|p| p.as_array_mut()) else {
+        return Ok(());
+    };
+    for portal in portals {
+        let Some(name) = portal.get("group").and_then(|g| g.as_str()) else {
+            continue;
+        };
+        let group = groups.get(name).with_context(|| {
+            format!(
+                "Portal '{}' is in group '{}', which is not defined",
+                portal["name"].as_str().unwrap_or("?"),
+                name
+            )
+        })?;
+        let mut merged = group.clone();
+        merge(&mut merged, std::mem::take(portal));
+        *portal = merged;
+    }
+    Ok(())
+}
+
+/// `overlay` on top of `base`, recursing into tables
+fn merge(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// A first config file with `portal` and the defaults for the rest, as
+/// `wimesh init` writes it
+pub fn starter_toml(portal: &PortalConfig) -> String {
+    let quote = |text: &str| toml::Value::String(text.to_string()).to_string();
+    let ssids: Vec<String> = portal.ssids.iter().map(|ssid| quote(ssid)).collect();
+    let mut toml = format!(
+        "# Written by `wimesh init`; every setting left out has its default.\n\
+         # config.example.toml lists them all, `wimesh --print-config` shows them\n\
+         version = {}\n\n\
+         [[portals]]\n\
+         name = {}\n\
+         type = {}\n\
+         ssids = [{}]\n\
+         # Empty: the WiFi interface's own, read at each login\n\
+         mac_address = {}\n",
+        CONFIG_VERSION,
+        quote(&portal.name),
+        quote(&portal.portal_type),
+        ssids.join(", "),
+        quote(&portal.mac_address)
+    );
+    let settings = portal.extra.get(&portal.portal_type).and_then(|v| v.as_table());
+    if let Some(settings) = settings.filter(|settings| !settings.is_empty()) {
+        toml.push_str(&format!("\n[portals.{}]\n", portal.portal_type));
+        for (key, value) in settings {
+            toml.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+    toml
+}
+
+/// A problem with the config, and where it is
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub message: String,
+    /// The setting it is about, as a path like `portals[1].mac_address`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    /// Where in the file, 1-based, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl Diagnostic {
+    fn at(field: &str, message: String) -> Self {
+        Self {
+            message,
+            field: Some(field.to_string()),
+            line: None,
+            column: None,
+        }
+    }
+
+    /// Why a file with `contents` did not load, where the parser stopped
+    pub fn parse_error(e: &anyhow::Error, contents: &str) -> Self {
+        let mut diagnostic = Self {
+            message: format!("{:#}", e),
+            field: None,
+            line: None,
+            column: None,
+        };
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<toml::de::Error>() {
+                let lines: Vec<&str> = e.message().lines().map(str::trim).collect();
+                diagnostic.message = lines.join("; ");
+                if let Some(span) = e.span() {
+                    let before = &contents[..span.start.min(contents.len())];
+                    let line_start = before.rfind('\n').map_or(0, |at| at + 1);
+                    diagnostic.line = Some(before.matches('\n').count() + 1);
+                    diagnostic.column = Some(before[line_start..].chars().count() + 1);
+                }
+                break;
+            }
+            let location = match cause.downcast_ref::<serde_json::Error>() {
+                Some(e) => Some((e.line(), e.column())),
+                None => cause
+                    .downcast_ref::<serde_yaml::Error>()
+                    .and_then(|e| e.location())
+                    .map(|at| (at.line(), at.column())),
+            };
+            if let Some((line, column)) = location.filter(|(line, _)| *line > 0) {
+                // Both say where once more at the end
+                let message = cause.to_string();
+                let suffix = format!(" at line {} column {}", line, column);
+                diagnostic.message = message.trim_end_matches(&suffix).to_string();
+                diagnostic.line = Some(line);
+                diagnostic.column = Some(column);
+                break;
+            }
+        }
+        diagnostic
+    }
+
+    /// Find the line of `field` in TOML `contents`
+    pub fn locate(&mut self, contents: &str) {
+        if let Some(ref field) = self.field {
+            self.line = locate_toml(contents, field);
+        }
+    }
+}
+
+/// The line `field` (`portals[1].awing.quirks`) is set on in TOML
+/// `contents`, else the line of the nearest table or key holding it; a
+/// textual scan, like `insert_ssid`
+fn locate_toml(contents: &str, field: &str) -> Option<usize> {
+    let target: Vec<String> = field
+        .replace('[', ".")
+        .replace(']', "")
+        .split('.')
+        .map(str::to_string)
+        .collect();
+    // `[[portals]]` seen so far, per array; a `[portals.awing]` or key
+    // after one is in its last element
+    let mut arrays: std::collections::HashMap<Vec<String>, usize> = Default::default();
+    let resolve = |arrays: &std::collections::HashMap<Vec<String>, usize>, keys: Vec<String>| {
+        let mut path = Vec::new();
+        for key in keys {
+            path.push(key);
+            if let Some(count) = arrays.get(&path) {
+                path.push((count - 1).to_string());
+            }
+        }
+        path
+    };
+    let mut table: Vec<String> = Vec::new();
+    let mut best: Option<(usize, usize)> = None;
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        let path = if let Some(header) = line.strip_prefix("[[") {
+            let Some(mut keys) = header.split("]]").next().and_then(key_path) else {
+                continue;
+            };
+            let last = keys.pop().unwrap_or_default();
+            let mut array = resolve(&arrays, keys);
+            array.push(last);
+            let count = arrays.entry(array.clone()).or_default();
+            array.push(count.to_string());
+            *count += 1;
+            table = array.clone();
+            array
+        } else if let Some(header) = line.strip_prefix('[') {
+            let Some(keys) = header.split(']').next().and_then(key_path) else {
+                continue;
+            };
+            table = resolve(&arrays, keys);
+            table.clone()
+        } else if let Some((key, _)) = line.split_once('=') {
+            let Some(keys) = key_path(key) else {
+                continue;
+            };
+            table.iter().cloned().chain(keys).collect()
+        } else {
+            continue;
+        };
+        if path == target {
+            return Some(n + 1);
+        }
+        let holds = path.len() < target.len() && target.starts_with(&path);
+        if holds && best.is_none_or(|(len, _)| path.len() > len) {
+            best = Some((path.len(), n + 1));
+        }
+    }
+    best.map(|(_, line)| line)
+}
+
+/// `a."b".c` as its keys; `None` for what is not a key
+fn key_path(key: &str) -> Option<Vec<String>> {
+    let mut keys = Vec::new();
+    for part in key.split('.') {
+        let part = part.trim();
+        let bare = part.trim_matches('"');
+        let valid = !bare.is_empty()
+            && (part.starts_with('"')
+                || part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+        if !valid {
+            return None;
+        }
+        keys.push(bare.to_string());
+    }
+    Some(keys)
+}
+
+/// Key fragments of values `to_masked_toml` never prints
+const SECRET_KEYS: &[&str] = &["password", "passphrase", "secret", "token", "voucher", "api_key"];
+
+fn mask_secrets(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let key = key.to_lowercase();
+                // Where the secret is, not the secret
+                if crate::secrets::reference(value).is_some() {
+                    continue;
+                }
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = toml::Value::String("********".to_string());
+                } else {
+                    mask_secrets(value);
+                }
+            }
+        }
+        toml::Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+/// Settings naming files on this machine, meaningless anywhere else
+const LOCAL_KEYS: &[&str] = &["har_file"];
+
+/// Remove what `mask_secrets` masks, and `LOCAL_KEYS`
+fn drop_private(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            table.retain(|key, _| {
+                let key = key.to_lowercase();
+                !SECRET_KEYS.iter().any(|secret| key.contains(secret))
+                    && !LOCAL_KEYS.contains(&key.as_str())
+            });
+            table.iter_mut().for_each(|(_, value)| drop_private(value));
+        }
+        toml::Value::Array(items) => items.iter_mut().for_each(drop_private),
+        _ => {}
+    }
+}
+
+/// The `[[portals]]` entries of a file written by `wimesh portal export`
+pub fn read_shared_portals(contents: &str) -> Result<Vec<PortalConfig>> {
+    #[derive(Deserialize)]
+    struct Shared {
+        #[serde(default)]
+        portals: Vec<PortalConfig>,
+    }
+    let shared: Shared = toml::from_str(contents)?;
+    if shared.portals.is_empty() {
+        anyhow::bail!("No [[portals]] entry in it");
+    }
+    Ok(shared.portals)
+}
+
+/// `contents`, a JSON config, with `ssid` appended to the `ssids` of the
+/// portal named `portal`
+fn insert_ssid_json(contents: &str, portal: &str, ssid: &str) -> Result<String> {
+    let mut config: serde_json::Value = serde_json::from_str(contents)?;
+    let ssids = config["portals"]
+        .as_array_mut()
+        .and_then(|portals| portals.iter_mut().find(|p| p["name"] == portal))
+        .with_context(|| format!("No portal named '{}'", portal))?
+        .as_object_mut()
+        .context("Portal entry is not an object")?
+        .entry("ssids")
+        .or_insert_with(|| serde_json::json!([]));
+    ssids
+        .as_array_mut()
+        .context("ssids is not an array")?
+        .push(ssid.into());
+    Ok(serde_json::to_string_pretty(&config)? + "\n")
+}
+
+/// A config file brought up to `CONFIG_VERSION`
+#[derive(Debug, Clone)]
+pub struct Migration {
+    /// Format version the file was in
+    pub from: u32,
+    /// The migrated file
+    pub contents: String,
+    /// Its comments could not be carried over (YAML, or TOML too unusual
+    /// to edit line by line)
+    pub comments_lost: bool,
+}
+
+/// Rewrites of a document from one format version to the next, the first
+/// from version 1 to 2
+const MIGRATIONS: &[fn(&mut serde_json::Value)] = &[nest_type_settings];
+
+/// `contents`, a config in `format`, in the current format; None when it
+/// already is. TOML is edited line by line, so its comments survive
+pub fn migrate(contents: &str, format: ConfigFormat) -> Result<Option<Migration>> {
+    let mut document: serde_json::Value = match format {
+        ConfigFormat::Toml => toml::from_str(contents)?,
+        ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+        ConfigFormat::Json => serde_json::from_str(contents)?,
+    };
+    let from = match document.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|v| *v >= 1)
+            .context("version must be a format version number")?,
+    };
+    if from > CONFIG_VERSION {
+        anyhow::bail!(
+            "Config format version {} is newer than this build reads ({})",
+            from,
+            CONFIG_VERSION
+        );
+    }
+    if from == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let original = document.clone();
+    for migration in &MIGRATIONS[from as usize - 1..] {
+        migration(&mut document);
+    }
+    document
+        .as_object_mut()
+        .context("The config is not a table of settings")?
+        .insert("version".to_string(), CONFIG_VERSION.into());
+
+    let (contents, comments_lost) = match format {
+        // The textual edit is version 1 to 2, the only migration so far;
+        // make sure it means what the structured one does
+        ConfigFormat::Toml => match nest_type_settings_toml(contents, &original) {
+            Some(text)
+                if toml::from_str::<serde_json::Value>(&text).ok().as_ref() == Some(&document) =>
+            {
+                (text, false)
+            }
+            _ => (toml::to_string_pretty(&document)?, true),
+        },
+        ConfigFormat::Yaml => (serde_yaml::to_string(&document)?, true),
+        ConfigFormat::Json => (serde_json::to_string_pretty(&document)? + "\n", false),
+    };
+    Ok(Some(Migration {
+        from,
+        contents,
+        comments_lost,
+    }))
+}
+
+/// The portal type of each `[[portals]]` entry, and of each group by name;
+/// a portal may take it from its group, a group from its portals
+fn entry_types(
+    document: &serde_json::Value,
+) -> (Vec<Option<String>>, std::collections::HashMap<String, String>) {
+    let portals = document
+        .get("portals")
+        .and_then(|p| p.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let own_type = |entry: &serde_json::Value| {
+        entry
+            .get("type")
+            .and_then(|t| t.as_str())
+            .map(str::to_string)
+    };
+    let group_of = |portal: &serde_json::Value| {
+        portal
+            .get("group")
+            .and_then(|g| g.as_str())
+            .map(str::to_string)
+    };
+
+    let mut groups = std::collections::HashMap::new();
+    for (name, group) in document
+        .get("groups")
+        .and_then(|g| g.as_object())
+        .into_iter()
+        .flatten()
+    {
+        let portal_type = own_type(group).or_else(|| {
+            portals
+                .iter()
+                .filter(|p| group_of(p).as_deref() == Some(name))
+                .find_map(own_type)
+        });
+        if let Some(portal_type) = portal_type {
+            groups.insert(name.clone(), portal_type);
+        }
+    }
+    let portals = portals
+        .iter()
+        .map(|p| own_type(p).or_else(|| groups.get(&group_of(p)?).cloned()))
+        .collect();
+    (portals, groups)
+}
+
+/// Keys of a portal entry or group that belong to `portal_type`
+fn type_settings(entry: &serde_json::Value, portal_type: &str) -> Vec<String> {
+    entry
+        .as_object()
+        .into_iter()
+        .flat_map(|entry| entry.keys())
+        .filter(|key| !PORTAL_KEYS.contains(&key.as_str()) && *key != portal_type)
+        .cloned()
+        .collect()
+}
+
+/// Version 1 to 2: the settings of the portal type move from the
+/// `[[portals]]` entry or group into a table named after the type, where
+/// they cannot collide with keys every type shares
+fn nest_type_settings(document: &mut serde_json::Value) {
+    fn nest(entry: &mut serde_json::Value, portal_type: &str) {
+        let keys = type_settings(entry, portal_type);
+        let Some(entry) = entry.as_object_mut() else {
+            return;
+        };
+        let mut table = match entry.remove(portal_type) {
+            Some(serde_json::Value::Object(table)) => table,
+            Some(other) => {
+                entry.insert(portal_type.to_string(), other);
+                return;
+            }
+            None => serde_json::Map::new(),
+        };
+        for key in keys {
+            if let Some(value) = entry.remove(&key) {
+                // A setting already in the table is the one in effect
+                table.entry(key).or_insert(value);
+            }
+        }
+        if !table.is_empty() {
+            entry.insert(portal_type.to_string(), table.into());
+        }
+    }
+
+    let (portal_types, group_types) = entry_types(document);
+    if let Some(portals) = document.get_mut("portals").and_then(|p| p.as_array_mut()) {
+        for (portal, portal_type) in portals.iter_mut().zip(&portal_types) {
+            if let Some(portal_type) = portal_type {
+                nest(portal, portal_type);
+            }
+        }
+    }
+    if let Some(groups) = document.get_mut("groups").and_then(|g| g.as_object_mut()) {
+        for (name, group) in groups.iter_mut() {
+            if let Some(portal_type) = group_types.get(name) {
+                nest(group, portal_type);
+            }
+        }
+    }
+}
+
+/// `nest_type_settings` done on the text of `contents`, parsed as
+/// `document`: settings of the type get it as a dotted-key prefix
+/// (`awing.quirks = [...]`), tables of them a longer header, and the
+/// version goes above the first table
+fn nest_type_settings_toml(contents: &str, document: &serde_json::Value) -> Option<String> {
+    let (portal_types, group_types) = entry_types(document);
+    let portals = document.get("portals").and_then(|p| p.as_array());
+    let groups = document.get("groups");
+    // The type of a portal entry or group, and its keys to move
+    let moves = |entry: Option<&serde_json::Value>, portal_type: Option<&String>| {
+        let portal_type = portal_type?;
+        Some((portal_type.clone(), type_settings(entry?, portal_type)))
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    // What moves in the table the current line is in
+    let mut current: Option<(String, Vec<String>)> = None;
+    let mut top_level = true;
+    let mut version_at = None;
+    let mut portal_count = 0;
+    for line in contents.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        if trimmed.starts_with('[') {
+            if top_level {
+                top_level = false;
+                version_at.get_or_insert(lines.len());
+            }
+            let array = trimmed.starts_with("[[");
+            let inner = trimmed.trim_start_matches('[');
+            let inner = &inner[..inner.find(']')?];
+            let path: Vec<&str> = inner.split('.').map(str::trim).collect();
+            current = None;
+            match path.as_slice() {
+                ["portals"] if array => {
+                    current = moves(
+                        portals.and_then(|p| p.get(portal_count)),
+                        portal_types.get(portal_count).and_then(Option::as_ref),
+                    );
+                    portal_count += 1;
+                }
+                ["groups", name] => {
+                    let name = name.trim_matches('"');
+                    current = moves(groups.and_then(|g| g.get(name)), group_types.get(name));
+                }
+                ["portals", key, ..] => {
+                    let last = portal_count.checked_sub(1)?;
+                    let entry = moves(
+                        portals.and_then(|p| p.get(last)),
+                        portal_types.get(last).and_then(Option::as_ref),
+                    );
+                    if let Some((portal_type, keys)) = entry {
+                        if keys.iter().any(|k| k == key) {
+                            let nested = format!("portals.{}.", portal_type);
+                            lines.push(line.replacen("portals.", &nested, 1));
+                            continue;
+                        }
+                    }
+                }
+                ["groups", name, key, ..] => {
+                    let name = name.trim_matches('"');
+                    let entry = moves(groups.and_then(|g| g.get(name)), group_types.get(name));
+                    if let Some((portal_type, keys)) = entry {
+                        if keys.iter().any(|k| k == key) {
+                            let at = line.find(name)? + name.len();
+                            let at = at + line[at..].find('.')? + 1;
+                            lines.push(format!("{}{}.{}", &line[..at], portal_type, &line[at..]));
+                            continue;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let key = (!trimmed.starts_with('#'))
+            .then(|| trimmed.split_once('='))
+            .flatten()
+            .map(|(key, _)| key.trim());
+        if top_level {
+            if key == Some("version") {
+                version_at = Some(lines.len());
+                continue;
+            }
+            if key.is_some() {
+                version_at.get_or_insert(lines.len());
+            }
+        }
+        match (&current, key) {
+            (Some((portal_type, keys)), Some(key)) if keys.iter().any(|k| k == key) => {
+                lines.push(format!("{}{}.{}", indent, portal_type, trimmed));
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    // Above the comments of the first setting, unless they head the file
+    let mut at = version_at.unwrap_or(lines.len());
+    let comment = |i: usize| lines[i].trim_start().starts_with('#');
+    let mut start = at;
+    while start > 0 && comment(start - 1) {
+        start -= 1;
+    }
+    if start > 0 {
+        at = start;
+    }
+    lines.insert(at, format!("version = {}", CONFIG_VERSION));
+    if lines.get(at + 1).is_some_and(|l| !l.trim().is_empty()) {
+        lines.insert(at + 1, String::new());
+    }
+    Some(lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yaml_and_json_configs_share_the_toml_keys() {
+        let toml = concat!(
+            "[global]\n",
+            "check_interval = 15\n",
+            "[[portals]]\n",
+            "name = \"KTX Khu B\"\n",
+            "type = \"awing\"\n",
+            "ssids = [\"1.Free Wi-MESH\"]\n",
+            "captive_dns = false\n",
+        );
+        let yaml = concat!(
+            "global:\n",
+            "  check_interval: 15\n",
+            "portals:\n",
+            "  - name: KTX Khu B\n",
+            "    type: awing\n",
+            "    ssids: [\"1.Free Wi-MESH\"]\n",
+            "    captive_dns: false\n",
+        );
+        let json = r#"{"global": {"check_interval": 15}, "portals": [{"name": "KTX Khu B",
+            "type": "awing", "ssids": ["1.Free Wi-MESH"], "captive_dns": false}]}"#;
+
+        for (path, contents) in [("c.toml", toml), ("c.yml", yaml), ("c.json", json)] {
+            let config = ConfigFormat::from_path(Path::new(path)).parse(contents).unwrap();
+            assert_eq!(config.global.check_interval, 15, "{}", path);
+            assert_eq!(config.portals[0].ssids, ["1.Free Wi-MESH"], "{}", path);
+            assert_eq!(
+                config.portals[0].extra.get("captive_dns"),
+                Some(&toml::Value::Boolean(false)),
+                "{}",
+                path
+            );
+        }
+
+        let updated = insert_ssid_json(json, "KTX Khu B", "1.Free Wi-MESH ").unwrap();
+        let config = ConfigFormat::Json.parse(&updated).unwrap();
+        assert_eq!(config.portals[0].ssids, ["1.Free Wi-MESH", "1.Free Wi-MESH "]);
+    }
+
+    #[test]
+    fn test_portals_inherit_their_group() {
+        let contents = concat!(
+            "[groups.ktx]\n",
+            "type = \"awing\"\n",
+            "mac_address = \"02:00:00:00:00:01\"\n",
+            "quirks = [\"delay-between-steps\"]\n",
+            "identity = { customer_name = \"SV\", apply_to_wifi = true }\n",
+            "\n",
+            "[[portals]]\n",
+            "name = \"KTX Khu A\"\n",
+            "group = \"ktx\"\n",
+            "ssids = [\"KTX-A\"]\n",
+            "\n",
+            "[[portals]]\n",
+            "name = \"KTX Khu B\"\n",
+            "group = \"ktx\"\n",
+            "ssids = [\"KTX-B\"]\n",
+            "mac_address = \"02:00:00:00:00:02\"\n",
+            "identity = { customer_name = \"Khu B\" }\n",
+        );
+        let config = ConfigFormat::Toml.parse(contents).unwrap();
+        let (a, b) = (&config.portals[0], &config.portals[1]);
+        assert_eq!((a.portal_type.as_str(), b.portal_type.as_str()), ("awing", "awing"));
+        assert_eq!(a.mac_address, "02:00:00:00:00:01");
+        assert_eq!(b.mac_address, "02:00:00:00:00:02");
+        assert_eq!(a.extra["quirks"], b.extra["quirks"]);
+        assert_eq!(b.identity.customer_name, "Khu B");
+        assert!(b.identity.apply_to_wifi);
+        assert_eq!(b.group.as_deref(), Some("ktx"));
+
+        let unknown = contents.replace("group = \"ktx\"\nssids = [\"KTX-B\"]", "group = \"ktz\"");
+        let err = ConfigFormat::Toml.parse(&unknown).unwrap_err();
+        assert!(format!("{:#}", err).contains("group 'ktz'"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_masked_toml_has_defaults_and_no_secrets() {
+        let mut config = Config::default();
+        config.portals[0]
+            .extra
+            .insert("voucher_code".into(), toml::Value::String("123456".into()));
+        let stored: toml::Value = toml::from_str("keyring = \"wimesh/ktx\"").unwrap();
+        config.portals[0].extra.insert("password".into(), stored);
+
+        let printed = config.to_masked_toml().unwrap();
+        assert!(printed.contains("check_interval = "), "{}", printed);
+        assert!(printed.contains("voucher_code = \"********\""), "{}", printed);
+        assert!(!printed.contains("123456"));
+        assert!(printed.contains("keyring = \"wimesh/ktx\""), "{}", printed);
+        config
+            .telemetry
+            .headers
+            .insert("x-honeycomb-team".into(), "hc-key".into());
+        let printed = config.to_masked_toml().unwrap();
+        assert!(printed.contains("x-honeycomb-team = \"********\""), "{}", printed);
+        assert!(!printed.contains("hc-key"));
+        let secret = Secret::Keyring("wimesh/ktx".into());
+        assert_eq!(config.portals[0].secret("password"), secret);
+        let reparsed: Config = toml::from_str(&printed).unwrap();
+        assert_eq!(reparsed.portals[0].name, "KTX Khu B");
+    }
+
+    #[test]
+    fn test_shared_portals_round_trip() {
+        let mut config = Config::default();
+        let portal = &mut config.portals[0];
+        portal.mac_address = "aa:bb:cc:dd:ee:ff".into();
+        portal.identity.customer_name = "Nguyen Van A".into();
+        portal.extra.insert(
+            "awing".into(),
+            toml::toml! { quirks = ["no_ad_view"] voucher = "123456" har_file = "/tmp/a.har" }
+                .into(),
+        );
+        let shared = portal.to_shareable_toml().unwrap();
+        for private in ["aa:bb", "Nguyen", "123456", "a.har", "identity"] {
+            assert!(!shared.contains(private), "{}", shared);
+        }
+
+        let portals = read_shared_portals(&shared).unwrap();
+        assert_eq!(portals[0].setting("quirks").unwrap()[0].as_str(), Some("no_ad_view"));
+        assert!(read_shared_portals("[global]\n").is_err());
+
+        let dir = std::env::temp_dir().join(format!("wimesh-share-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let mine = "# mine\n[[portals]]\nname = \"Home\"\ntype = \"fpt\"\nssids = [\"H\"]\n";
+        std::fs::write(&path, mine).unwrap();
+        Config::add_portals_to_file(&path, &portals).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("# mine\n"));
+        let loaded = ConfigFormat::Toml.parse(&written).unwrap();
+        assert_eq!(loaded.portals[1].name, "KTX Khu B");
+        assert!(Config::add_portals_to_file(&path, &portals).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_insert_ssid_keeps_layout() {
+        let contents = concat!(
+            "# venues\n",
+            "[[portals]]\n",
+            "name = \"Home\"\n",
+            "type = \"awing\"\n",
+            "ssids = [\"HomeNet\"]\n",
+            "\n",
+            "[[portals]]\n",
+            "name = \"KTX Khu B\"  # dorm\n",
+            "type = \"awing\"\n",
+            "ssids = [\n",
+            "    \"1.Free Wi-MESH\"\n",
+            "]\n",
+            "\n",
+            "[portals.identity]\n",
+            "auto = true\n",
+        );
+
+        let updated = insert_ssid(contents, "KTX Khu B", "1.Free Wi-MESH ").unwrap();
+        assert!(updated.starts_with("# venues\n"));
+        let config: Config = toml::from_str(&updated).unwrap();
+        assert_eq!(config.portals[0].ssids, ["HomeNet"]);
+        assert_eq!(config.portals[1].ssids, ["1.Free Wi-MESH", "1.Free Wi-MESH "]);
+
+        let updated = insert_ssid(contents, "Home", "Home \"5G\"").unwrap();
+        let config: Config = toml::from_str(&updated).unwrap();
+        assert_eq!(config.portals[0].ssids, ["HomeNet", "Home \"5G\""]);
+
+        assert!(insert_ssid(contents, "Nowhere", "x").is_err());
+    }
+
+    #[test]
+    fn test_migrate_nests_type_settings() {
+        let contents = concat!(
+            "# dorm\n",
+            "\n",
+            "[groups.ktx]\n",
+            "type = \"awing\"\n",
+            "quirks = [\"delay-between-steps\"]  # slow backend\n",
+            "\n",
+            "[[portals]]\n",
+            "name = \"KTX Khu A\"\n",
+            "group = \"ktx\"\n",
+            "ssids = [\n",
+            "    \"KTX-A\",\n",
+            "]\n",
+            "# broken gateway\n",
+            "compat = true\n",
+            "\n",
+            "[portals.phrases]\n",
+            "login_rejected = [\"nope\"]\n",
+        );
+        let migration = migrate(contents, ConfigFormat::Toml).unwrap().unwrap();
+        assert_eq!(migration.from, 1);
+        assert!(!migration.comments_lost);
+        assert!(migration.contents.starts_with("# dorm\n\nversion = 2\n"));
+        assert!(migration.contents.contains("awing.quirks = [\"delay-between-steps\"]  # slow"));
+        assert!(migration.contents.contains("# broken gateway\nawing.compat = true\n"));
+        assert!(migration.contents.contains("[portals.awing.phrases]\n"));
+
+        let (old, new) = (
+            ConfigFormat::Toml.parse(contents).unwrap(),
+            ConfigFormat::Toml.parse(&migration.contents).unwrap(),
+        );
+        assert_eq!((old.version, new.version), (1, CONFIG_VERSION));
+        for key in ["quirks", "compat", "phrases"] {
+            assert!(old.portals[0].setting(key).is_some(), "{}", key);
+            assert_eq!(old.portals[0].setting(key), new.portals[0].setting(key), "{}", key);
+        }
+        assert!(!new.portals[0].extra.contains_key("compat"));
+        assert!(migrate(&migration.contents, ConfigFormat::Toml).unwrap().is_none());
+
+        let yaml = concat!(
+            "# venue\n",
+            "portals:\n",
+            "  - name: Cafe\n",
+            "    type: awing\n",
+            "    ssids: [Cafe]\n",
+            "    compat: true\n",
+        );
+        let migration = migrate(yaml, ConfigFormat::Yaml).unwrap().unwrap();
+        assert!(migration.comments_lost);
+        let config = ConfigFormat::Yaml.parse(&migration.contents).unwrap();
+        assert_eq!(config.portals[0].extra["awing"]["compat"], toml::Value::Boolean(true));
+
+        assert!(migrate("version = 3\n", ConfigFormat::Toml).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics_point_at_lines() {
+        let contents = r#"version = 2
+
+[global]
+check_interval = 0
+
+[[portals]]
+name = "A"
+type = "awing"
+ssids = ["X"]
+awing.quirk = ["popup-true"]
+
+[[portals]]
+name = "B"
+type = "fpt"
+ssids = ["X"]
+mac_address = "02:00:00:00:00"
+
+[portals.fpt]
+splash_url = "http://wifi.fpt.vn"
+field = { phone = "0900000000" }
+"#;
+        let config = ConfigFormat::Toml.parse(contents).unwrap();
+        let mut problems = config.diagnostics();
+        problems.iter_mut().for_each(|p| p.locate(contents));
+        let at = |field: &str| {
+            let problem = problems.iter().find(|p| p.field.as_deref() == Some(field));
+            problem.and_then(|p| p.line)
+        };
+        assert_eq!(at("global.check_interval"), Some(4));
+        assert_eq!(at("portals[0].awing.quirk"), Some(10));
+        assert_eq!(at("portals[1].mac_address"), Some(16));
+        assert_eq!(at("portals[1].ssids"), Some(15));
+        assert_eq!(at("portals[1].fpt.field"), Some(20));
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].message.contains("awing.quirk"));
+
+        let broken = "version = 2\n[global]\ncheck_interval = \"x\"\n";
+        let error = ConfigFormat::Toml.parse(broken).unwrap_err();
+        let problem = Diagnostic::parse_error(&error, broken);
+        assert_eq!((problem.line, problem.column), (Some(3), Some(18)));
+        assert_eq!(problem.message, "invalid type: string \"x\", expected u64");
+        let error = ConfigFormat::Json.parse("{\n  \"global\": [\n}").unwrap_err();
+        assert_eq!(Diagnostic::parse_error(&error, "").line, Some(3));
+    }
+
+    #[test]
+    fn test_account_portal_without_username() {
+        let config = ConfigFormat::Toml
+            .parse(
+                r#"version = 2
+
+[[portals]]
+name = "Campus"
+type = "wispr"
+ssids = ["Campus"]
+wispr.password = { keyring = "wimesh/campus" }
+"#,
+            )
+            .unwrap();
+        let problems = config.diagnostics();
+        if cfg!(feature = "portal-wispr") {
+            assert_eq!(problems.len(), 1);
+            assert_eq!(problems[0].field.as_deref(), Some("portals[0].wispr.username"));
+        } else {
+            assert!(problems.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_starter_toml_loads() {
+        let mut portal = Config::default().portals.remove(0);
+        portal.name = "Cafe \"Mây\"".into();
+        portal.portal_type = "mikrotik".into();
+        portal.ssids = vec!["Cafe Guest".into()];
+        portal.mac_address = "02:00:00:00:00:01".into();
+        let settings: toml::Value = toml::toml! {
+            login_url = "http://10.5.50.1/login"
+            password = { keyring = "wimesh/cafe" }
+        }
+        .into();
+        portal.extra.insert("mikrotik".into(), settings);
+
+        let config = ConfigFormat::Toml.parse(&starter_toml(&portal)).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        let loaded = &config.portals[0];
+        assert_eq!(loaded.name, portal.name);
+        assert_eq!(loaded.ssids, portal.ssids);
+        assert_eq!(loaded.mac_address, portal.mac_address);
+        assert_eq!(loaded.secret("password"), Secret::Keyring("wimesh/cafe".into()));
+        assert_eq!(
+            loaded.setting("login_url").and_then(|v| v.as_str()),
+            Some("http://10.5.50.1/login")
+        );
+    }
+}
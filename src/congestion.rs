@@ -0,0 +1,85 @@
+//! Peak-hours congestion mode
+//!
+//! At 9pm the whole dorm comes back and the portal answers in 20 seconds,
+//! if at all. Hammering it with the usual timeouts and retries only adds to
+//! the load and fills the log with the same error every check. After a few
+//! logins in a row fail on timeouts, congestion mode stretches the HTTP
+//! timeouts, drops to a single retry, spaces the daemon's checks out, and
+//! says so once; it ends with the next successful login or when the
+//! internet works again.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Request timeouts are multiplied by this while congested
+pub const TIMEOUT_FACTOR: u32 = 3;
+/// Attempts per request while congested (one retry)
+pub const MAX_ATTEMPTS: u32 = 2;
+/// The daemon's check interval is multiplied by this while congested
+pub const SPACING_FACTOR: u32 = 3;
+
+/// Login timeout streak, shared by the daemon and every `HttpClient`
+#[derive(Debug, Default)]
+pub struct Congestion {
+    timeouts: AtomicU32,
+    active: AtomicBool,
+}
+
+impl Congestion {
+    /// The state every `HttpClient` follows
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<Congestion>> = OnceLock::new();
+        GLOBAL.get_or_init(Default::default).clone()
+    }
+
+    /// Whether congestion mode is on
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Count a failed login; returns true when this failure turned
+    /// congestion mode on. Only timeouts count, anything else breaks the
+    /// streak. `threshold` 0 never turns it on.
+    pub fn record_failure(&self, timed_out: bool, threshold: u32) -> bool {
+        if !timed_out {
+            self.timeouts.store(0, Ordering::Relaxed);
+            return false;
+        }
+        let timeouts = self.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+        threshold > 0 && timeouts >= threshold && !self.active.swap(true, Ordering::Relaxed)
+    }
+
+    /// The portal answered, or is not needed; returns true when this
+    /// turned congestion mode off
+    pub fn record_success(&self) -> bool {
+        self.timeouts.store(0, Ordering::Relaxed);
+        self.active.swap(false, Ordering::Relaxed)
+    }
+
+    /// Consecutive timed-out logins so far
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streak_of_timeouts() {
+        let congestion = Congestion::default();
+        assert!(!congestion.record_failure(true, 3));
+        assert!(!congestion.record_failure(false, 3));
+        assert!(!congestion.record_failure(true, 3));
+        assert!(!congestion.record_failure(true, 3));
+        assert!(!congestion.is_active());
+        // Turns on once, and says so once
+        assert!(congestion.record_failure(true, 3));
+        assert!(!congestion.record_failure(true, 3));
+        assert!(congestion.is_active());
+        assert!(congestion.record_success());
+        assert!(!congestion.record_success());
+        assert!(!congestion.is_active());
+    }
+}
@@ -0,0 +1,406 @@
+//! Talking to the running daemon (`[control]`)
+//!
+//! The daemon listens on a Unix socket, `control.sock` in the state
+//! directory (the named pipe `\\.\pipe\wimesh` on Windows), so `wimesh
+//! control` can nudge it without a restart: `status` asks what it sees
+//! right now, `login-now` clears the backoff and runs a pass at once,
+//! `pause` and `resume` stop and restart its checks, and `reload` reads
+//! the config file again. A request is a JSON line, `{"command":
+//! "login-now"}`, answered by one `Reply` line once the daemon is done
+//! with it. The socket is the owner's only (0600), and the pipe the owner's
+//! and SYSTEM's; a socket left behind by a daemon that died is replaced,
+//! one another daemon answers on is not.
+
+use crate::config::ControlConfig;
+use crate::tasks::Tasks;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+/// Name of the socket in a state directory
+pub const SOCKET_FILE: &str = "control.sock";
+/// The daemon's pipe on Windows
+pub const PIPE_NAME: &str = r"\\.\pipe\wimesh";
+/// Longest request line read
+const MAX_REQUEST: u64 = 4096;
+/// How long a client waits for the daemon: `login-now` lasts a whole pass
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// What a client asks the daemon
+///
+/// Names are part of the protocol: add new ones, never rename.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Request {
+    /// What it sees right now
+    Status,
+    /// Clear the backoff and run a pass at once, even while paused
+    LoginNow,
+    /// Stop checking until resumed
+    Pause,
+    /// Check again, starting now
+    Resume,
+    /// Read the config file again
+    Reload,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    command: Request,
+}
+
+/// The daemon's answer to a `Request`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    pub message: String,
+    /// What `status` reports, as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<serde_json::Value>,
+}
+
+impl Reply {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+            status: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            ..Self::ok(message)
+        }
+    }
+}
+
+/// A request from a client, and where its reply goes
+pub struct Incoming {
+    pub request: Request,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+/// Where the daemon listens: `path`, else the socket in the primary state
+/// directory (the pipe on Windows)
+pub fn socket_path(cfg: &ControlConfig) -> Option<PathBuf> {
+    socket_paths(cfg).into_iter().next()
+}
+
+/// Where a daemon may be listening, the one it would pick first
+pub fn socket_paths(cfg: &ControlConfig) -> Vec<PathBuf> {
+    if !cfg.path.is_empty() {
+        return vec![PathBuf::from(&cfg.path)];
+    }
+    if cfg!(windows) {
+        return vec![PathBuf::from(PIPE_NAME)];
+    }
+    crate::state::state_dirs()
+        .into_iter()
+        .map(|dir| dir.join(SOCKET_FILE))
+        .collect()
+}
+
+/// The daemon's listener; the socket goes away with it
+pub struct Control {
+    path: PathBuf,
+}
+
+impl Control {
+    /// Listen on `path`, handing each request to `requests`
+    pub fn start(path: &Path, requests: mpsc::Sender<Incoming>, tasks: &mut Tasks) -> Result<Self> {
+        listen(path, requests, tasks)?;
+        tracing::info!("Listening for `wimesh control` on {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        if cfg!(unix) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &Path, requests: mpsc::Sender<Incoming>, tasks: &mut Tasks) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{} is taken, by another running daemon?", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the stale {}", path.display()))?;
+    }
+    let dir = path.parent().unwrap_or(Path::new(""));
+    std::fs::create_dir_all(dir)?;
+    // Bound in a directory only we can enter, and moved into place once it
+    // is the owner's only, so no one connects while it is still open to all
+    let private = dir.join(format!(".{}.{}", SOCKET_FILE, std::process::id()));
+    let _ = std::fs::remove_dir_all(&private);
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("Failed to create {}", private.display()))?;
+    let bound = private.join(SOCKET_FILE);
+    let listener = UnixListener::bind(&bound)
+        .and_then(|listener| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&bound, path)?;
+            Ok(listener)
+        })
+        .with_context(|| format!("Failed to listen on {} for [control]", path.display()));
+    let _ = std::fs::remove_dir_all(&private);
+    let listener = listener?;
+    tasks.spawn("accept", |cancel| async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = cancel.cancelled() => return,
+            };
+            let Ok((stream, _)) = accepted else {
+                return;
+            };
+            tokio::spawn(serve(stream, requests.clone()));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &Path, requests: mpsc::Sender<Incoming>, tasks: &mut Tasks) -> Result<()> {
+    let name = path.as_os_str().to_os_string();
+    let mut server = owner_only_pipe(&name, true)
+        .with_context(|| format!("Failed to listen on {} for [control]", path.display()))?;
+    tasks.spawn("accept", |cancel| async move {
+        loop {
+            let connected = tokio::select! {
+                connected = server.connect() => connected,
+                _ = cancel.cancelled() => return,
+            };
+            if connected.is_err() {
+                return;
+            }
+            // The next client needs an instance of its own
+            let next = match owner_only_pipe(&name, false) {
+                Ok(next) => next,
+                Err(e) => {
+                    tracing::warn!("Stopped listening for `wimesh control`: {}", e);
+                    return;
+                }
+            };
+            tokio::spawn(serve(
+                std::mem::replace(&mut server, next),
+                requests.clone(),
+            ));
+        }
+    });
+    Ok(())
+}
+
+/// An instance of the pipe `name` that only its owner and SYSTEM may open
+#[cfg(windows)]
+fn owner_only_pipe(
+    name: &std::ffi::OsStr,
+    first: bool,
+) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows_sys::Win32::Security::SECURITY_ATTRIBUTES;
+
+    // A protected DACL, so nothing is inherited: full access for the owner
+    // and SYSTEM, none for anyone else
+    let sddl: Vec<u16> = "D:P(A;;GA;;;OW)(A;;GA;;;SY)\0".encode_utf16().collect();
+    let mut descriptor = std::ptr::null_mut();
+    // SAFETY: `sddl` is NUL-terminated, and the descriptor is freed below
+    let converted = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        )
+    };
+    if converted == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor,
+        bInheritHandle: 0,
+    };
+    // SAFETY: the attributes and their descriptor outlive the call, which
+    // copies what it needs
+    let server = unsafe {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .create_with_security_attributes_raw(name, std::ptr::addr_of_mut!(attributes).cast())
+    };
+    unsafe { LocalFree(descriptor) };
+    server
+}
+
+#[cfg(not(any(unix, windows)))]
+fn listen(_: &Path, _: mpsc::Sender<Incoming>, _: &mut Tasks) -> Result<()> {
+    anyhow::bail!("No control socket on this platform")
+}
+
+/// Answer the one request of a connection
+async fn serve<S>(stream: S, requests: mpsc::Sender<Incoming>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut line = String::new();
+    let reply = match BufReader::new(read.take(MAX_REQUEST))
+        .read_line(&mut line)
+        .await
+    {
+        Ok(_) => match parse_request(&line) {
+            Ok(request) => ask(&requests, request).await,
+            Err(e) => Reply::error(format!("{:#}", e)),
+        },
+        Err(e) => Reply::error(format!("Failed to read the request: {}", e)),
+    };
+    if let Err(e) = write.write_all(&encode(&reply)).await {
+        tracing::debug!("Control client left before the reply: {}", e);
+    }
+}
+
+/// Hand `request` to the daemon and wait for its reply
+async fn ask(requests: &mpsc::Sender<Incoming>, request: Request) -> Reply {
+    let (reply, answer) = oneshot::channel();
+    if requests.send(Incoming { request, reply }).await.is_err() {
+        return Reply::error("The daemon is stopping");
+    }
+    match tokio::time::timeout(REPLY_TIMEOUT, answer).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(_)) => Reply::error("The daemon stopped before answering"),
+        Err(_) => Reply::error("The daemon did not answer in time"),
+    }
+}
+
+fn parse_request(line: &str) -> Result<Request> {
+    let message: Message = serde_json::from_str(line.trim()).context("Not a control request")?;
+    Ok(message.command)
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(message).unwrap_or_default();
+    line.push(b'\n');
+    line
+}
+
+/// Ask the daemon listening at one of `paths`
+pub async fn send(paths: &[PathBuf], request: Request) -> Result<Reply> {
+    // The primary location's error says the most
+    let mut first = None;
+    for path in paths {
+        match exchange(path, request).await {
+            Ok(reply) => return Ok(reply),
+            Err(e) => {
+                first.get_or_insert(e);
+            }
+        }
+    }
+    match first {
+        Some(e) => Err(e.context("Is the daemon running, with [control] enabled?")),
+        None => anyhow::bail!("No control socket to try"),
+    }
+}
+
+async fn exchange(path: &Path, request: Request) -> Result<Reply> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path).await;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(path);
+    #[cfg(not(any(unix, windows)))]
+    let stream: std::io::Result<tokio::io::DuplexStream> =
+        Err(std::io::ErrorKind::Unsupported.into());
+    let stream = stream.with_context(|| format!("Failed to connect to {}", path.display()))?;
+
+    let (read, mut write) = tokio::io::split(stream);
+    write
+        .write_all(&encode(&Message { command: request }))
+        .await?;
+    let mut line = String::new();
+    let mut read = BufReader::new(read);
+    match tokio::time::timeout(REPLY_TIMEOUT, read.read_line(&mut line)).await {
+        Ok(Ok(0)) => anyhow::bail!("{} closed without a reply", path.display()),
+        Ok(read) => {
+            read?;
+        }
+        Err(_) => anyhow::bail!("No reply on {} in time", path.display()),
+    }
+    serde_json::from_str(&line).context("Not a control reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_lines() {
+        assert_eq!(
+            parse_request("{\"command\": \"login-now\"}\n").unwrap(),
+            Request::LoginNow
+        );
+        assert_eq!(
+            encode(&Message {
+                command: Request::Reload
+            }),
+            b"{\"command\":\"reload\"}\n"
+        );
+        assert!(parse_request("{\"command\": \"reboot\"}").is_err());
+        assert!(parse_request("status").is_err());
+
+        let reply: Reply = serde_json::from_slice(&encode(&Reply::error("no"))).unwrap();
+        assert_eq!(reply, Reply::error("no"));
+        assert_eq!(
+            encode(&Reply::ok("done")),
+            b"{\"ok\":true,\"message\":\"done\"}\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_is_the_owners() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wimesh-control-{}", std::process::id()));
+        let path = dir.join(SOCKET_FILE);
+        let (sender, mut requests) = mpsc::channel(1);
+        let mut tasks = Tasks::new("test");
+        let control = Control::start(&path, sender, &mut tasks).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        tokio::spawn(async move {
+            let incoming = requests.recv().await.unwrap();
+            let _ = incoming.reply.send(Reply::ok("here"));
+        });
+        let reply = send(std::slice::from_ref(&path), Request::Status)
+            .await
+            .unwrap();
+        drop(control);
+        tasks.shutdown(Duration::from_secs(1)).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing left of where it was bound
+        assert_eq!(entries, 1);
+        assert_eq!(reply, Reply::ok("here"));
+    }
+}
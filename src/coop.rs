@@ -0,0 +1,345 @@
+//! Cooperation between wimesh instances on one LAN (`[coop]`)
+//!
+//! Roommates each running wimesh on the same WiFi lose connectivity at the
+//! same moment, and every daemon then hits the portal at once, which gets
+//! the whole room rate-limited. With `[coop] enabled` the daemons find each
+//! other over mDNS (`_wimesh._udp.local`, multicast on the local segment
+//! only) and take turns: one announces it is logging in, the others wait
+//! `stagger` seconds before their own attempt. Announcements also carry
+//! the network state and the gateway each instance detected, so a peer
+//! behind a different gateway on the same SSID shows up in the logs.
+//!
+//! Nothing here is authenticated; a peer can only make others wait.
+
+use crate::state::unix_now;
+use crate::tasks::Tasks;
+use anyhow::{Context, Result};
+use hickory_proto::op::{Message, MessageType};
+use hickory_proto::rr::rdata::{PTR, TXT};
+use hickory_proto::rr::{Name, RData, Record};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// DNS-SD service type instances announce under
+pub const SERVICE: &str = "_wimesh._udp.local.";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// TTL of announcements; peers silent for longer are forgotten
+const PEER_TTL: u64 = 120;
+/// How long two claims count as simultaneous
+const CLAIM_SETTLE: Duration = Duration::from_secs(1);
+/// An unchanged state is announced again this often
+const REANNOUNCE: u64 = 60;
+
+/// What an instance says it is doing on an SSID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Online,
+    Captive,
+    LoggingIn,
+}
+
+impl PeerState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Captive => "captive",
+            Self::LoggingIn => "logging_in",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "online" => Some(Self::Online),
+            "captive" => Some(Self::Captive),
+            "logging_in" => Some(Self::LoggingIn),
+            _ => None,
+        }
+    }
+}
+
+/// One instance's announcement about one SSID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// Random per daemon run
+    pub id: String,
+    pub ssid: String,
+    pub state: PeerState,
+    /// Default gateway the instance sees on that SSID
+    pub gateway: Option<IpAddr>,
+    /// Unix time it was sent (receivers use their own clock)
+    pub at: u64,
+}
+
+impl Announcement {
+    /// As an unsolicited mDNS response: a PTR from the service type to the
+    /// instance, and a TXT record with the fields
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let service = Name::from_ascii(SERVICE)?;
+        let instance = Name::from_ascii(&self.id)?.append_domain(&service)?;
+        let mut txt = vec![
+            format!("id={}", self.id),
+            format!("ssid={}", self.ssid),
+            format!("state={}", self.state.as_str()),
+        ];
+        if let Some(gateway) = self.gateway {
+            txt.push(format!("gateway={}", gateway));
+        }
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true)
+            .add_answer(Record::from_rdata(
+                service,
+                PEER_TTL as u32,
+                RData::PTR(PTR(instance.clone())),
+            ))
+            .add_answer(Record::from_rdata(
+                instance,
+                PEER_TTL as u32,
+                RData::TXT(TXT::new(txt)),
+            ));
+        Ok(message.to_vec()?)
+    }
+
+    /// The announcement in an mDNS packet, if it is one of ours; `at` is
+    /// the time it was received
+    pub fn decode(packet: &[u8], at: u64) -> Option<Self> {
+        let message = Message::from_vec(packet).ok()?;
+        if message.message_type() != MessageType::Response {
+            return None;
+        }
+        let service = Name::from_ascii(SERVICE).ok()?;
+        let txt = message.answers().iter().find_map(|record| {
+            if !service.zone_of(record.name()) || record.name() == &service {
+                return None;
+            }
+            match record.data() {
+                Some(RData::TXT(txt)) => Some(txt),
+                _ => None,
+            }
+        })?;
+        let fields: HashMap<&str, &str> = txt
+            .txt_data()
+            .iter()
+            .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
+            .collect();
+        Some(Self {
+            id: fields.get("id")?.to_string(),
+            ssid: fields.get("ssid")?.to_string(),
+            state: PeerState::parse(fields.get("state")?)?,
+            gateway: fields.get("gateway").and_then(|g| g.parse().ok()),
+            at,
+        })
+    }
+}
+
+/// Seconds to wait before logging in on `ssid`, when a peer other than
+/// `me` announced a login there less than `stagger` seconds before `now`
+pub fn wait_for_turn(
+    peers: &[Announcement],
+    me: &str,
+    ssid: &str,
+    now: u64,
+    stagger: u64,
+) -> Option<u64> {
+    peers
+        .iter()
+        .filter(|p| p.id != me && p.ssid == ssid && p.state == PeerState::LoggingIn)
+        .map(|p| (p.at + stagger).saturating_sub(now))
+        .filter(|wait| *wait > 0)
+        .max()
+}
+
+/// This daemon's presence on the LAN
+#[derive(Clone)]
+pub struct Coop {
+    id: String,
+    stagger: u64,
+    socket: Arc<UdpSocket>,
+    /// Latest announcement per (peer, SSID)
+    peers: Arc<Mutex<HashMap<(String, String), Announcement>>>,
+    /// What this daemon last announced per SSID, and when
+    announced: Arc<Mutex<HashMap<String, (PeerState, u64)>>>,
+}
+
+impl Coop {
+    /// Join the mDNS group and start listening for peers, in `tasks`
+    pub fn start(stagger: u64, tasks: &mut Tasks) -> Result<Self> {
+        let socket = bind().context("Failed to join the mDNS group for [coop]")?;
+        let id = format!("wimesh-{:08x}", crate::utils::random_u64() as u32);
+        let coop = Self {
+            id,
+            stagger,
+            socket: Arc::new(socket),
+            peers: Arc::default(),
+            announced: Arc::default(),
+        };
+
+        let listener = coop.clone();
+        tasks.spawn("listen", |cancel| async move {
+            let mut buf = vec![0; 9000];
+            loop {
+                let received = tokio::select! {
+                    received = listener.socket.recv_from(&mut buf) => received,
+                    _ = cancel.cancelled() => return,
+                };
+                let Ok((len, _)) = received else {
+                    continue;
+                };
+                if let Some(announcement) = Announcement::decode(&buf[..len], unix_now()) {
+                    listener.heard(announcement);
+                }
+            }
+        });
+        tracing::info!("Cooperating with wimesh peers on the LAN as {}", coop.id);
+        Ok(coop)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn heard(&self, announcement: Announcement) {
+        if announcement.id == self.id {
+            return;
+        }
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (announcement.id.clone(), announcement.ssid.clone());
+        if !peers.contains_key(&key) {
+            tracing::info!(
+                "Peer {} on '{}' ({}, gateway {})",
+                announcement.id,
+                announcement.ssid,
+                announcement.state.as_str(),
+                announcement
+                    .gateway
+                    .map(|g| g.to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            );
+        }
+        peers.insert(key, announcement);
+    }
+
+    /// Peers heard from recently on `ssid`
+    pub fn peers_on(&self, ssid: &str) -> Vec<Announcement> {
+        let now = unix_now();
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        peers.retain(|_, p| now.saturating_sub(p.at) < PEER_TTL);
+        peers.values().filter(|p| p.ssid == ssid).cloned().collect()
+    }
+
+    /// Tell the peers what this daemon is doing on `ssid`; an unchanged
+    /// state only once a minute
+    pub async fn announce(&self, ssid: &str, state: PeerState, gateway: Option<IpAddr>) {
+        let now = unix_now();
+        {
+            let mut announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+            let repeat = announced
+                .get(ssid)
+                .is_some_and(|(last, at)| *last == state && now.saturating_sub(*at) < REANNOUNCE);
+            if repeat && state != PeerState::LoggingIn {
+                return;
+            }
+            announced.insert(ssid.to_string(), (state, now));
+        }
+        let announcement = Announcement {
+            id: self.id.clone(),
+            ssid: ssid.to_string(),
+            state,
+            gateway,
+            at: now,
+        };
+        let sent = match announcement.encode() {
+            Ok(packet) => self
+                .socket
+                .send_to(&packet, SocketAddr::from((MDNS_GROUP, MDNS_PORT)))
+                .await
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            tracing::debug!("Failed to announce to peers: {:#}", e);
+        }
+    }
+
+    /// Claim the next login on `ssid`; returns how long to wait instead
+    /// when a peer is logging in there, or claimed it at the same moment
+    /// with a lower id
+    pub async fn claim_login(&self, ssid: &str, gateway: Option<IpAddr>) -> Option<u64> {
+        let wait = wait_for_turn(&self.peers_on(ssid), &self.id, ssid, unix_now(), self.stagger);
+        if wait.is_some() {
+            return wait;
+        }
+        self.announce(ssid, PeerState::LoggingIn, gateway).await;
+        let claimed = unix_now();
+        tokio::time::sleep(CLAIM_SETTLE).await;
+        let contested = self.peers_on(ssid).into_iter().any(|p| {
+            p.state == PeerState::LoggingIn && p.at + 1 >= claimed && p.id < self.id
+        });
+        contested.then_some(self.stagger)
+    }
+}
+
+/// A UDP socket on the mDNS port, shared with any responder already there
+fn bind() -> Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement_round_trip() {
+        let announcement = Announcement {
+            id: "wimesh-0a1b2c3d".to_string(),
+            ssid: "1.Free Wi-MESH".to_string(),
+            state: PeerState::LoggingIn,
+            gateway: "10.20.30.1".parse().ok(),
+            at: 1_760_000_000,
+        };
+        let packet = announcement.encode().unwrap();
+        assert_eq!(
+            Announcement::decode(&packet, 1_760_000_000),
+            Some(announcement)
+        );
+        assert_eq!(Announcement::decode(b"not dns", 0), None);
+    }
+
+    #[test]
+    fn test_wait_for_turn() {
+        let peer = |id: &str, ssid: &str, state, at| Announcement {
+            id: id.to_string(),
+            ssid: ssid.to_string(),
+            state,
+            gateway: None,
+            at,
+        };
+        let peers = [
+            peer("a", "Cafe", PeerState::LoggingIn, 100),
+            peer("b", "Other", PeerState::LoggingIn, 110),
+            peer("c", "Cafe", PeerState::Captive, 110),
+        ];
+        assert_eq!(wait_for_turn(&peers, "me", "Cafe", 105, 15), Some(10));
+        assert_eq!(wait_for_turn(&peers, "me", "Cafe", 115, 15), None);
+        // Our own claim does not hold us up
+        assert_eq!(wait_for_turn(&peers, "a", "Cafe", 105, 15), None);
+    }
+}
@@ -0,0 +1,350 @@
+//! One pass of the daemon over an adapter
+//!
+//! `wimesh daemon` loops over the adapters associated to a configured SSID;
+//! what it does on each of them lives here: check the internet, skip the
+//! login when the adapter has no address, the SSID is backing off or the
+//! gateway does not answer, otherwise log in and book the outcome in the
+//! state file, the congestion tracker and the event log. Whether traffic
+//! flows is asked of the network's Captive Portal API, then of the portal
+//! where it can tell (a MikroTik hotspot's status page), then of the
+//! `[connectivity]` probe URLs. The API and the probe URLs sit behind a
+//! `Connectivity`, so the stress harness (`stress`) can run the very same
+//! pass against the mock venue.
+
+use crate::capport::{self, CaptiveStatus};
+use crate::config::Config;
+use crate::congestion::{self, Congestion};
+use crate::coop::Coop;
+use crate::events::{Event, EventLog};
+use crate::lock::LoginLocks;
+use crate::login;
+use crate::portal::PortalRegistry;
+use crate::report;
+use crate::state::{self, unix_now, State};
+use crate::status::NetworkState;
+use crate::utils;
+use crate::utils::nonblocking::offload;
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// How long the WiFi gateway gets to answer before a login is skipped
+pub const GATEWAY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a fresh login gets to settle before the next check
+pub const LOGIN_SETTLE: Duration = Duration::from_secs(10);
+
+/// Whether traffic flows out of an interface
+#[async_trait]
+pub trait Connectivity: Send + Sync {
+    async fn online(&self, interface: Option<&str>) -> bool;
+
+    /// What the network's Captive Portal API says, where it has one
+    async fn captive_status(&self, _interface: Option<&str>) -> Option<CaptiveStatus> {
+        None
+    }
+}
+
+/// The daemon's check: fetching the `[connectivity]` probe URLs
+pub struct ProbeUrls;
+
+#[async_trait]
+impl Connectivity for ProbeUrls {
+    async fn online(&self, interface: Option<&str>) -> bool {
+        utils::nonblocking::has_internet_connectivity_on(interface).await
+    }
+
+    async fn captive_status(&self, interface: Option<&str>) -> Option<CaptiveStatus> {
+        capport::status(interface).await
+    }
+}
+
+/// What every pass of one daemon run shares
+pub struct Pass<'a> {
+    pub cfg: &'a Config,
+    pub locks: &'a LoginLocks,
+    pub events: &'a EventLog,
+    pub coop: Option<&'a Coop>,
+    pub connectivity: &'a dyn Connectivity,
+    /// Wait after a successful login, `LOGIN_SETTLE` for the daemon
+    pub settle: Duration,
+}
+
+impl Pass<'_> {
+    /// One daemon pass over the adapter `iface` associated to `ssid`: log in
+    /// if it has no internet, returning the state it was found in
+    pub async fn check(
+        &self,
+        registry: &mut PortalRegistry,
+        iface: &str,
+        ssid: &str,
+    ) -> NetworkState {
+        let cfg = self.cfg;
+        let events = self.events;
+        let interface = Some(iface).filter(|i| !i.is_empty());
+
+        // Check internet connectivity: a Captive Portal API knows, so does a
+        // portal that can be asked, the probe URLs guess
+        let online = match self.connectivity.captive_status(interface).await {
+            Some(status) => {
+                tracing::debug!("Captive Portal API on '{}': {}", ssid, status.describe());
+                if let Some(expires_at) = status.expires_at(unix_now()) {
+                    let ssid = ssid.to_string();
+                    offload(move || record_session_end(&ssid, expires_at)).await;
+                }
+                !status.captive
+            }
+            None => match portal_authenticated(registry, ssid, interface).await {
+                Some(authenticated) => authenticated,
+                None => self.connectivity.online(interface).await,
+            },
+        };
+        if online {
+            end_congestion(events);
+            if state::nonblocking::load().await.backoff.contains_key(ssid) {
+                tracing::debug!("Internet restored on '{}'", ssid);
+                state::nonblocking::clear_backoff(ssid).await;
+            }
+            return NetworkState::Online;
+        }
+
+        let problem = match interface {
+            Some(interface) => utils::nonblocking::interface_address_problem(interface).await,
+            None => None,
+        };
+        if let Some(problem) = problem {
+            tracing::warn!(
+                "No usable IP on '{}' ({}): {}, skipping login",
+                ssid,
+                iface,
+                problem
+            );
+            if cfg.global.renew_dhcp {
+                match utils::nonblocking::renew_dhcp(iface).await {
+                    Ok(()) => tracing::info!("Asked for a new DHCP lease on {}", iface),
+                    Err(e) => tracing::warn!("{:#}", e),
+                }
+            } else {
+                tracing::info!(
+                    "Renew it with `nmcli device connect {}`, or set renew_dhcp = true",
+                    iface
+                );
+            }
+            return NetworkState::NoAddress;
+        }
+
+        if state::nonblocking::load().await.is_read_only(cfg.global.read_only) {
+            tracing::warn!(
+                "No internet on '{}' ({}), read-only: not logging in",
+                ssid,
+                iface
+            );
+            return NetworkState::Captive;
+        }
+
+        tracing::warn!("No internet on '{}' ({}), attempting login...", ssid, iface);
+
+        if let Some(wait) = state::nonblocking::load().await.backoff_remaining(ssid) {
+            tracing::debug!("Backing off on '{}' for {}s more", ssid, wait);
+            return NetworkState::Captive;
+        }
+
+        if let Some(gateway) = unreachable_gateway(cfg, events, interface).await {
+            tracing::warn!(
+                "Gateway {} does not answer, skipping login attempt (AP uplink down?)",
+                gateway
+            );
+            return NetworkState::Captive;
+        }
+
+        // Find the portal for this SSID
+        let Some(portal) = registry.find_for_ssid(ssid) else {
+            tracing::warn!("No portal configured for SSID: {}", ssid);
+            return NetworkState::Captive;
+        };
+        if let Some(coop) = self.coop {
+            let gateway = utils::nonblocking::default_gateway(interface).await;
+            if let Some(wait) = coop.claim_login(ssid, gateway).await {
+                tracing::info!("A peer is logging in on '{}', my turn in {}s", ssid, wait);
+                return NetworkState::Captive;
+            }
+        }
+        match login::locked_connect(cfg, self.locks, events, ssid, interface, portal).await {
+            Ok(_) => {
+                tracing::info!("Login successful via '{}'", portal.name());
+                end_congestion(events);
+                if let Some(interface) = interface {
+                    check_default_route(interface).await;
+                }
+
+                // Wait for connection to stabilize
+                tokio::time::sleep(self.settle).await;
+            }
+            Err(e) => {
+                let policy = cfg.daemon_policy();
+                let backoff = state::nonblocking::record_failure(ssid, &policy, &e).await;
+                let congestion = Congestion::global();
+                let timed_out = report::error_kind(&e) == "timeout";
+                if congestion.record_failure(timed_out, cfg.global.congestion_threshold) {
+                    tracing::warn!(
+                        "Portal congested ({} logins timed out in a row): stretching timeouts, \
+                         retrying less and checking every {}s until a login succeeds; \
+                         further failures are logged at debug level. Last error: {:#}",
+                        congestion.timeouts(),
+                        cfg.global.check_interval * congestion::SPACING_FACTOR as u64,
+                        e
+                    );
+                    events.record(Event::Congestion {
+                        active: true,
+                        timeouts: congestion.timeouts(),
+                    });
+                }
+                if congestion.is_active() {
+                    tracing::debug!(
+                        "Login failed via '{}' (attempt {}): {:#}",
+                        portal.name(),
+                        backoff.failures,
+                        e
+                    );
+                    return NetworkState::Captive;
+                }
+                tracing::error!(
+                    "Login failed via '{}' (attempt {}): {:#}",
+                    portal.name(),
+                    backoff.failures,
+                    e
+                );
+
+                if backoff.until > unix_now() {
+                    tracing::error!(
+                        "Too many failures, backing off for {}s...",
+                        backoff.until - unix_now()
+                    );
+                }
+            }
+        }
+        NetworkState::Captive
+    }
+}
+
+/// Whether the portal for `ssid` has this machine logged in, when it
+/// supports being asked and answers
+async fn portal_authenticated(
+    registry: &mut PortalRegistry,
+    ssid: &str,
+    interface: Option<&str>,
+) -> Option<bool> {
+    let portal = registry.find_for_ssid(ssid)?;
+    if !portal.capabilities().supports_is_authenticated {
+        return None;
+    }
+    portal.bind_interface(interface).ok()?;
+    match portal.is_authenticated().await {
+        Ok(authenticated) => {
+            tracing::debug!(
+                "Portal '{}' on '{}': logged in {}",
+                portal.name(),
+                ssid,
+                authenticated
+            );
+            Some(authenticated)
+        }
+        Err(e) => {
+            tracing::debug!("Portal '{}' could not say, probing: {:#}", portal.name(), e);
+            None
+        }
+    }
+}
+
+/// The gateway of `interface` (or of the default route), if it is known
+/// and does not answer the reachability probe
+pub async fn unreachable_gateway(
+    cfg: &Config,
+    events: &EventLog,
+    interface: Option<&str>,
+) -> Option<IpAddr> {
+    if !cfg.global.probe_gateway {
+        return None;
+    }
+    let gateway = utils::nonblocking::default_gateway(interface).await?;
+    let reachable = utils::gateway_reachable(gateway, GATEWAY_PROBE_TIMEOUT).await;
+    events.record(Event::probe(
+        "gateway",
+        Some(gateway.to_string()),
+        reachable,
+    ));
+    (!reachable).then_some(gateway)
+}
+
+/// Why the association of `interface` to `ssid` is left alone, if it is:
+/// the SSID is denied, or the connection metered
+pub async fn refusal(cfg: &Config, interface: &str, ssid: &str) -> Option<&'static str> {
+    if cfg.global.denies(ssid) {
+        return Some("it is in global.deny_ssids");
+    }
+    if cfg.global.skip_metered
+        && !interface.is_empty()
+        && utils::nonblocking::is_metered(interface).await
+    {
+        return Some("the connection is metered (set global.skip_metered = false to log in)");
+    }
+    None
+}
+
+/// `active` associations split into the ones wimesh may handle and the
+/// refused ones, with why
+pub async fn partition_refused(
+    cfg: &Config,
+    active: Vec<(String, String)>,
+) -> (Vec<(String, String)>, Vec<(String, &'static str)>) {
+    let mut allowed = Vec::new();
+    let mut refused = Vec::new();
+    for (interface, ssid) in active {
+        match refusal(cfg, &interface, &ssid).await {
+            Some(why) => refused.push((ssid, why)),
+            None => allowed.push((interface, ssid)),
+        }
+    }
+    (allowed, refused)
+}
+
+/// Warn when traffic would not leave through `interface`, just logged in
+/// on, returning the warning
+pub async fn check_default_route(interface: &str) -> Option<String> {
+    let wifi = utils::nonblocking::active_wifi().await.unwrap_or_default();
+    let wifi: Vec<&str> = wifi.iter().map(|(iface, _)| iface.as_str()).collect();
+    let route = utils::nonblocking::default_route_interface().await;
+    let problem = utils::route_problem(interface, route.as_deref(), &wifi)?;
+    tracing::warn!("{}", problem);
+    Some(problem)
+}
+
+/// Leave congestion mode, if on, once the portal answers again
+pub fn end_congestion(events: &EventLog) {
+    let congestion = Congestion::global();
+    if congestion.record_success() {
+        tracing::info!("Portal congestion over, back to normal timeouts and checks");
+        events.record(Event::Congestion {
+            active: false,
+            timeouts: 0,
+        });
+    }
+}
+
+/// Remember that the session on `ssid` ends at `expires_at`, as a Captive
+/// Portal API said; small drifts of its countdown are not worth a write
+fn record_session_end(ssid: &str, expires_at: u64) {
+    let mut info = State::load()
+        .session_info
+        .get(ssid)
+        .cloned()
+        .unwrap_or_default();
+    if info
+        .expires_at
+        .is_some_and(|known| known.abs_diff(expires_at) < 5)
+    {
+        return;
+    }
+    info.expires_at = Some(expires_at);
+    State::record_session_info(ssid, info);
+}
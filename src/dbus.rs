@@ -0,0 +1,893 @@
+//! NetworkManager over D-Bus
+//!
+//! Which access point each WiFi device is associated to is a few property
+//! reads on the system bus, where `nmcli` costs a process per check, prints
+//! whatever its version and locale make of it, and is missing from minimal
+//! containers. This speaks just enough of the D-Bus wire protocol for those
+//! reads: EXTERNAL authentication, method calls with string arguments, and
+//! the replies. A D-Bus crate would bring an executor of its own and a
+//! dozen dependencies for them. `utils` asks nmcli when the bus or
+//! NetworkManager is not there. The tray (feature `tray`) takes the other
+//! side on the session bus: it answers method calls and sends signals,
+//! with bodies marshalled through `Writer`.
+
+use crate::utils::Radio;
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Socket of the system bus when `DBUS_SYSTEM_BUS_ADDRESS` is unset
+pub const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+/// How long the bus and NetworkManager get for each reply
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Replies larger than this are not what was asked for
+const MAX_MESSAGE: usize = 1 << 20;
+
+const NM: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_DEVICE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_WIRELESS: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT: &str = "org.freedesktop.NetworkManager.AccessPoint";
+/// `NM_DEVICE_TYPE_WIFI`
+const DEVICE_TYPE_WIFI: u64 = 2;
+/// `NM_METERED_YES` and `NM_METERED_GUESS_YES`
+const METERED: [u64; 2] = [1, 3];
+
+/// Message types
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+
+/// Header fields
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// A value read from a message body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    /// `y`, `q`, `u`, `t` and `h`
+    UInt(u64),
+    /// `n`, `i` and `x`
+    Int(i64),
+    Double(f64),
+    /// `s`, `o` and `g`
+    Str(String),
+    /// Arrays; a dict is an array of two-field structs
+    Array(Vec<Value>),
+    Struct(Vec<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    /// The value inside any variants
+    pub fn inner(&self) -> &Value {
+        match self {
+            Value::Variant(value) => value.inner(),
+            value => value,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.inner() {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.inner() {
+            Value::UInt(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self.inner() {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self.inner() {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// An `ay` as bytes
+    fn as_bytes(&self) -> Option<Vec<u8>> {
+        self.as_array()?
+            .iter()
+            .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect()
+    }
+}
+
+/// Path of the system bus socket, from `DBUS_SYSTEM_BUS_ADDRESS`
+/// (`unix:path=/run/dbus/system_bus_socket`) if set
+pub fn system_bus_path() -> Option<PathBuf> {
+    match std::env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+        Ok(address) => parse_address(&address),
+        Err(_) => Some(PathBuf::from(SYSTEM_BUS_SOCKET)),
+    }
+}
+
+/// Path of the session bus socket, from `DBUS_SESSION_BUS_ADDRESS`, else
+/// `bus` in `XDG_RUNTIME_DIR`
+pub fn session_bus_path() -> Option<PathBuf> {
+    match std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(address) => parse_address(&address),
+        Err(_) => std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("bus")),
+    }
+}
+
+/// The first `unix:path=` of a bus address; other transports are not
+/// spoken here
+fn parse_address(address: &str) -> Option<PathBuf> {
+    address.split(';').find_map(|entry| {
+        entry
+            .strip_prefix("unix:")?
+            .split(',')
+            .find_map(|kv| kv.strip_prefix("path="))
+            .map(PathBuf::from)
+    })
+}
+
+/// A connection to a bus
+pub struct Bus<S> {
+    stream: S,
+    serial: u32,
+}
+
+fn system_bus() -> Result<Bus<impl Read + Write>> {
+    system_bus_waiting(Some(TIMEOUT))
+}
+
+/// The system bus, with reads giving up after `timeout`; `None` waits for
+/// signals as long as it takes
+#[cfg(unix)]
+fn system_bus_waiting(
+    timeout: Option<Duration>,
+) -> Result<Bus<std::os::unix::net::UnixStream>> {
+    let path = system_bus_path().context("DBUS_SYSTEM_BUS_ADDRESS has no unix:path")?;
+    let stream = std::os::unix::net::UnixStream::connect(&path)
+        .with_context(|| format!("Failed to connect to the system bus at {}", path.display()))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let bus = Bus::open(stream)?;
+    bus.stream.set_read_timeout(timeout)?;
+    Ok(bus)
+}
+
+#[cfg(not(unix))]
+fn system_bus_waiting(_timeout: Option<Duration>) -> Result<Bus<std::io::Empty>> {
+    bail!("D-Bus is only spoken over Unix sockets")
+}
+
+/// The session bus of the logged-in user, reads waiting as long as it takes
+#[cfg(unix)]
+pub fn session_bus() -> Result<Bus<std::os::unix::net::UnixStream>> {
+    let path = session_bus_path()
+        .context("No session bus: DBUS_SESSION_BUS_ADDRESS has no unix:path")?;
+    let stream = std::os::unix::net::UnixStream::connect(&path)
+        .with_context(|| format!("Failed to connect to the session bus at {}", path.display()))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let bus = Bus::open(stream)?;
+    bus.stream.set_read_timeout(None)?;
+    Ok(bus)
+}
+
+#[cfg(unix)]
+impl Bus<std::os::unix::net::UnixStream> {
+    /// Another handle on the connection, for writing from another thread
+    /// while this one reads
+    pub fn try_clone_stream(&self) -> std::io::Result<std::os::unix::net::UnixStream> {
+        self.stream.try_clone()
+    }
+}
+
+impl<S: Read + Write> Bus<S> {
+    /// Authenticate on `stream` as the user running wimesh, and say hello
+    pub fn open(stream: S) -> Result<Self> {
+        let mut bus = Self { stream, serial: 0 };
+        bus.authenticate()?;
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            &[],
+        )
+        .context("The bus did not answer Hello")?;
+        Ok(bus)
+    }
+
+    /// EXTERNAL, with the identity the bus reads off the socket
+    fn authenticate(&mut self) -> Result<()> {
+        self.stream.write_all(b"\0AUTH EXTERNAL\r\n")?;
+        let mut line = self.read_line()?;
+        if line == "DATA" {
+            self.stream.write_all(b"DATA\r\n")?;
+            line = self.read_line()?;
+        }
+        if !line.starts_with("OK ") {
+            bail!("The bus refused EXTERNAL authentication: {}", line);
+        }
+        self.stream.write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            self.stream
+                .read_exact(&mut byte)
+                .context("The bus hung up")?;
+            line.push(byte[0]);
+            if line.len() > 512 {
+                bail!("Overlong line from the bus");
+            }
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    /// Call `member` with string arguments and wait for its reply
+    pub fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[&str],
+    ) -> Result<Vec<Value>> {
+        let mut body = Writer::default();
+        for arg in args {
+            body.string(arg);
+        }
+        let signature = "s".repeat(args.len());
+        self.call_with(destination, path, interface, member, &signature, body)
+    }
+
+    /// Call `member` with the arguments in `body`, of types `signature`, and
+    /// wait for its reply; other messages meanwhile are dropped
+    pub fn call_with(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: Writer,
+    ) -> Result<Vec<Value>> {
+        self.serial += 1;
+        let serial = self.serial;
+        let fields = [
+            Field::Path(path),
+            Field::Interface(interface),
+            Field::Member(member),
+            Field::Destination(destination),
+        ];
+        let message = message(METHOD_CALL, serial, &fields, signature, body);
+        self.stream.write_all(&message)?;
+        loop {
+            let reply = self.read_message()?;
+            // Signals (NameAcquired) and the like
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            return match reply.kind {
+                METHOD_RETURN => Ok(reply.body),
+                ERROR => bail!(
+                    "{}.{}: {}{}",
+                    interface,
+                    member,
+                    reply.error_name.unwrap_or_default(),
+                    match reply.body.first().and_then(Value::as_str) {
+                        Some(message) => format!(" ({})", message),
+                        None => String::new(),
+                    }
+                ),
+                kind => bail!("Unexpected message type {} in reply", kind),
+            };
+        }
+    }
+
+    /// The property `name` of `interface` on the object `path`
+    pub fn get(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        name: &str,
+    ) -> Result<Value> {
+        let reply = self.call(
+            destination,
+            path,
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            &[interface, name],
+        )?;
+        reply
+            .into_iter()
+            .next()
+            .with_context(|| format!("No value for {}.{}", interface, name))
+    }
+
+    /// Serial of the last message sent
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// The next message from the bus, waiting for it
+    pub fn read_message(&mut self) -> Result<Message> {
+        let mut fixed = [0u8; 16];
+        self.stream
+            .read_exact(&mut fixed)
+            .context("The bus hung up")?;
+        let big = match fixed[0] {
+            b'l' => false,
+            b'B' => true,
+            other => bail!("Bad endianness byte {:#x} from the bus", other),
+        };
+        let word = |at: usize| {
+            let bytes = [fixed[at], fixed[at + 1], fixed[at + 2], fixed[at + 3]];
+            if big {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let (body_len, fields_len) = (word(4) as usize, word(12) as usize);
+        let total = padded(16 + fields_len, 8) + body_len;
+        if total > MAX_MESSAGE {
+            bail!("A {} byte message from the bus", total);
+        }
+        let mut message = fixed.to_vec();
+        message.resize(total, 0);
+        self.stream
+            .read_exact(&mut message[16..])
+            .context("The bus hung up")?;
+        Message::parse(&message)
+    }
+}
+
+fn padded(n: usize, to: usize) -> usize {
+    n.div_ceil(to) * to
+}
+
+/// A message, as far as a caller or the called cares
+#[derive(Debug)]
+pub struct Message {
+    /// `METHOD_CALL`, `METHOD_RETURN`, `ERROR` or `SIGNAL`
+    pub kind: u8,
+    pub serial: u32,
+    pub sender: Option<String>,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub error_name: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    /// A whole message, header and body
+    pub fn parse(message: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(message, message[0] == b'B');
+        reader.pos = 12;
+        let fields = reader.value(b"a(yv)")?;
+        reader.align(8)?;
+        let field = |code: u64| {
+            fields.as_array()?.iter().find_map(|field| match field {
+                Value::Struct(pair) if pair[0].as_u64() == Some(code) => {
+                    Some(pair[1].inner().clone())
+                }
+                _ => None,
+            })
+        };
+        let signature = field(u64::from(FIELD_SIGNATURE));
+        let signature = signature.as_ref().and_then(Value::as_str).unwrap_or("");
+        let mut body = Vec::new();
+        let mut types = signature.as_bytes();
+        while !types.is_empty() {
+            let (first, rest) = split_type(types)?;
+            body.push(reader.value(first)?);
+            types = rest;
+        }
+        let text = |code: u8| field(u64::from(code)).and_then(|v| v.as_str().map(str::to_string));
+        let mut serial = [0u8; 4];
+        serial.copy_from_slice(&message[8..12]);
+        Ok(Self {
+            kind: message[1],
+            serial: match message[0] {
+                b'B' => u32::from_be_bytes(serial),
+                _ => u32::from_le_bytes(serial),
+            },
+            sender: text(FIELD_SENDER),
+            path: text(FIELD_PATH),
+            interface: text(FIELD_INTERFACE),
+            member: text(FIELD_MEMBER),
+            reply_serial: field(u64::from(FIELD_REPLY_SERIAL))
+                .and_then(|v| v.as_u64())
+                .and_then(|v| u32::try_from(v).ok()),
+            error_name: text(FIELD_ERROR_NAME),
+            body,
+        })
+    }
+}
+
+/// A header field of an outgoing message
+pub enum Field<'a> {
+    Path(&'a str),
+    Interface(&'a str),
+    Member(&'a str),
+    ErrorName(&'a str),
+    ReplySerial(u32),
+    Destination(&'a str),
+}
+
+/// A message, little-endian, with the arguments in `body` of types
+/// `signature`
+pub fn message(kind: u8, serial: u32, fields: &[Field], signature: &str, body: Writer) -> Vec<u8> {
+    let mut m = Writer::default();
+    m.buf.extend([b'l', kind, 0, 1]);
+    m.u32(body.buf.len() as u32);
+    m.u32(serial);
+    m.u32(0);
+    let fields_start = m.buf.len();
+    fn field<'w>(m: &'w mut Writer, code: u8, kind: &str) -> &'w mut Writer {
+        m.align(8);
+        m.buf.push(code);
+        m.signature(kind);
+        m
+    }
+    for f in fields {
+        match *f {
+            Field::Path(path) => field(&mut m, FIELD_PATH, "o").string(path),
+            Field::Interface(interface) => field(&mut m, FIELD_INTERFACE, "s").string(interface),
+            Field::Member(member) => field(&mut m, FIELD_MEMBER, "s").string(member),
+            Field::ErrorName(name) => field(&mut m, FIELD_ERROR_NAME, "s").string(name),
+            Field::ReplySerial(serial) => field(&mut m, FIELD_REPLY_SERIAL, "u").u32(serial),
+            Field::Destination(name) => field(&mut m, FIELD_DESTINATION, "s").string(name),
+        }
+    }
+    if !signature.is_empty() {
+        field(&mut m, FIELD_SIGNATURE, "g").signature(signature);
+    }
+    let fields_len = (m.buf.len() - fields_start) as u32;
+    m.buf[12..16].copy_from_slice(&fields_len.to_le_bytes());
+    m.align(8);
+    m.buf.extend(body.buf);
+    m.buf
+}
+
+/// Little-endian marshalling, aligned from the start of the message body
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, to: usize) {
+        self.buf.resize(padded(self.buf.len(), to), 0);
+    }
+
+    pub fn byte(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    pub fn bool(&mut self, b: bool) {
+        self.u32(u32::from(b));
+    }
+
+    pub fn i32(&mut self, n: i32) {
+        self.align(4);
+        self.buf.extend(n.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.align(4);
+        self.buf.extend(n.to_le_bytes());
+    }
+
+    /// An `s` or an `o`
+    pub fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub fn signature(&mut self, s: &str) {
+        self.buf.push(s.len() as u8);
+        self.buf.extend(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// An array of elements of type `element`, written by `items`
+    pub fn array(&mut self, element: &str, items: impl FnOnce(&mut Self)) {
+        self.align(4);
+        let len_at = self.buf.len();
+        self.buf.extend([0; 4]);
+        // Padding to the first element is not counted
+        self.align(alignment(element.as_bytes()[0]));
+        let start = self.buf.len();
+        items(self);
+        let len = (self.buf.len() - start) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// An `ay`
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.array("y", |w| w.buf.extend(bytes));
+    }
+
+    /// A struct or dict entry, its fields written by `fields`
+    pub fn structure(&mut self, fields: impl FnOnce(&mut Self)) {
+        self.align(8);
+        fields(self);
+    }
+
+    /// A variant holding a `signature`, written by `value`
+    pub fn variant(&mut self, signature: &str, value: impl FnOnce(&mut Self)) {
+        self.signature(signature);
+        value(self);
+    }
+}
+
+/// The first complete type of a signature, and the rest
+fn split_type(signature: &[u8]) -> Result<(&[u8], &[u8])> {
+    let end = match signature.first() {
+        None => bail!("Empty signature"),
+        Some(b'a') => 1 + split_type(&signature[1..])?.0.len(),
+        Some(&open @ (b'(' | b'{')) => {
+            let close = if open == b'(' { b')' } else { b'}' };
+            let mut at = 1;
+            while signature.get(at) != Some(&close) {
+                if at >= signature.len() {
+                    bail!("Unclosed {} in signature", open as char);
+                }
+                at += split_type(&signature[at..])?.0.len();
+            }
+            at + 1
+        }
+        Some(_) => 1,
+    };
+    Ok(signature.split_at(end))
+}
+
+fn alignment(code: u8) -> usize {
+    match code {
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a' => 4,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 1,
+    }
+}
+
+/// Unmarshalling of a whole message
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], big: bool) -> Self {
+        Self { buf, pos: 0, big }
+    }
+
+    fn align(&mut self, to: usize) -> Result<()> {
+        self.pos = padded(self.pos, to);
+        if self.pos > self.buf.len() {
+            bail!("Truncated message");
+        }
+        Ok(())
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        self.align(N)?;
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + N)
+            .context("Truncated message")?;
+        self.pos += N;
+        let mut out: [u8; N] = bytes.try_into()?;
+        if self.big {
+            out.reverse();
+        }
+        Ok(out)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .context("Truncated message")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        let s = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.bytes(1)?;
+        Ok(s)
+    }
+
+    /// A value of the single complete type `signature`
+    fn value(&mut self, signature: &[u8]) -> Result<Value> {
+        Ok(match signature[0] {
+            b'y' => Value::UInt(self.take::<1>()?[0].into()),
+            b'b' => Value::Bool(u32::from_le_bytes(self.take()?) != 0),
+            b'n' => Value::Int(i16::from_le_bytes(self.take()?).into()),
+            b'q' => Value::UInt(u16::from_le_bytes(self.take()?).into()),
+            b'i' => Value::Int(i32::from_le_bytes(self.take()?).into()),
+            b'u' | b'h' => Value::UInt(u32::from_le_bytes(self.take()?).into()),
+            b'x' => Value::Int(i64::from_le_bytes(self.take()?)),
+            b't' => Value::UInt(u64::from_le_bytes(self.take()?)),
+            b'd' => Value::Double(f64::from_le_bytes(self.take()?)),
+            b's' | b'o' => {
+                let len = u32::from_le_bytes(self.take()?) as usize;
+                Value::Str(self.string(len)?)
+            }
+            b'g' => {
+                let len = self.take::<1>()?[0] as usize;
+                Value::Str(self.string(len)?)
+            }
+            b'v' => {
+                let len = self.take::<1>()?[0] as usize;
+                let inner = self.string(len)?;
+                let (first, _) = split_type(inner.as_bytes())?;
+                Value::Variant(Box::new(self.value(first)?))
+            }
+            b'a' => {
+                let len = u32::from_le_bytes(self.take()?) as usize;
+                let (element, _) = split_type(&signature[1..])?;
+                // Padding to the first element counts even when it is empty
+                self.align(alignment(element[0]))?;
+                let end = self.pos + len;
+                if end > self.buf.len() {
+                    bail!("Truncated message");
+                }
+                let mut values = Vec::new();
+                while self.pos < end {
+                    values.push(self.value(element)?);
+                }
+                Value::Array(values)
+            }
+            b'(' | b'{' => {
+                self.align(8)?;
+                let mut fields = Vec::new();
+                let mut types = &signature[1..signature.len() - 1];
+                while !types.is_empty() {
+                    let (first, rest) = split_type(types)?;
+                    fields.push(self.value(first)?);
+                    types = rest;
+                }
+                Value::Struct(fields)
+            }
+            other => bail!("Unsupported type '{}' in message", other as char),
+        })
+    }
+}
+
+/// A NetworkManager device: its object path and interface name
+struct Device {
+    path: String,
+    interface: String,
+}
+
+fn devices<S: Read + Write>(bus: &mut Bus<S>, wifi_only: bool) -> Result<Vec<Device>> {
+    let paths = bus.get(NM, NM_PATH, NM, "Devices")?;
+    let mut devices = Vec::new();
+    for path in paths
+        .as_array()
+        .context("NetworkManager Devices is not a list")?
+    {
+        let Some(path) = path.as_str() else {
+            continue;
+        };
+        if wifi_only {
+            let kind = bus.get(NM, path, NM_DEVICE, "DeviceType")?.as_u64();
+            if kind != Some(DEVICE_TYPE_WIFI) {
+                continue;
+            }
+        }
+        let interface = bus.get(NM, path, NM_DEVICE, "Interface")?;
+        devices.push(Device {
+            path: path.to_string(),
+            interface: interface.as_str().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(devices)
+}
+
+/// Object path of the access point `device` is associated to
+fn active_access_point<S: Read + Write>(
+    bus: &mut Bus<S>,
+    device: &Device,
+) -> Result<Option<String>> {
+    let ap = bus.get(NM, &device.path, NM_WIRELESS, "ActiveAccessPoint")?;
+    Ok(ap.as_str().filter(|path| *path != "/").map(str::to_string))
+}
+
+/// `(interface, ssid)` of every WiFi device associated to an access point
+pub fn active_wifi() -> Result<Vec<(String, String)>> {
+    let mut bus = system_bus()?;
+    let mut active = Vec::new();
+    for device in devices(&mut bus, true)? {
+        let Some(ap) = active_access_point(&mut bus, &device)? else {
+            continue;
+        };
+        let ssid = bus.get(NM, &ap, NM_ACCESS_POINT, "Ssid")?;
+        let ssid = ssid.as_bytes().context("Ssid is not a byte array")?;
+        active.push((
+            device.interface,
+            String::from_utf8_lossy(&ssid).into_owned(),
+        ));
+    }
+    Ok(active)
+}
+
+/// BSSID of the access point `interface` is associated to
+pub fn active_bssid(interface: &str) -> Result<Option<String>> {
+    let mut bus = system_bus()?;
+    let Some(device) = devices(&mut bus, true)?
+        .into_iter()
+        .find(|d| d.interface == interface)
+    else {
+        return Ok(None);
+    };
+    let Some(ap) = active_access_point(&mut bus, &device)? else {
+        return Ok(None);
+    };
+    let bssid = bus.get(NM, &ap, NM_ACCESS_POINT, "HwAddress")?;
+    Ok(bssid.as_str().and_then(crate::utils::normalize_mac))
+}
+
+/// Band and channel of the access point `interface` is associated to, and
+/// the device's current bit rate
+pub fn radio(interface: &str) -> Result<Option<Radio>> {
+    let mut bus = system_bus()?;
+    let Some(device) = devices(&mut bus, true)?
+        .into_iter()
+        .find(|d| d.interface == interface)
+    else {
+        return Ok(None);
+    };
+    let Some(ap) = active_access_point(&mut bus, &device)? else {
+        return Ok(None);
+    };
+    let frequency = bus.get(NM, &ap, NM_ACCESS_POINT, "Frequency")?.as_u64();
+    // Kbit/s
+    let bitrate = bus.get(NM, &device.path, NM_WIRELESS, "Bitrate")?.as_u64();
+    let rate_mbps = bitrate
+        .filter(|&kbits| kbits > 0)
+        .map(|kbits| (kbits / 1000) as u32);
+    Ok(frequency.and_then(|mhz| Radio::from_frequency(mhz as u32, rate_mbps)))
+}
+
+/// Signals of NetworkManager that may mean a new association or another
+/// connectivity: its own and each device's `StateChanged`, and changes of
+/// its properties (`Connectivity`, `PrimaryConnection`); access points
+/// changing their signal strength are left out
+const WATCHED: &[&str] = &[
+    "type='signal',sender='org.freedesktop.NetworkManager',member='StateChanged'",
+    "type='signal',sender='org.freedesktop.NetworkManager',\
+     interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',\
+     path='/org/freedesktop/NetworkManager'",
+];
+
+/// Block on the system bus, calling `changed` on each signal of `WATCHED`
+/// until it returns false; an error when the bus or NetworkManager goes
+/// away
+pub fn watch(mut changed: impl FnMut() -> bool) -> Result<()> {
+    let mut bus = system_bus_waiting(None)?;
+    // Fails when nothing owns the name, rather than waiting forever
+    bus.get(NM, NM_PATH, NM, "State")?;
+    for rule in WATCHED {
+        bus.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "AddMatch",
+            &[rule],
+        )?;
+    }
+    loop {
+        let message = bus.read_message()?;
+        let watched = matches!(
+            message.member.as_deref(),
+            Some("StateChanged" | "PropertiesChanged")
+        );
+        if message.kind == SIGNAL && watched && !changed() {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether NetworkManager has the connection of `interface` metered, set
+/// or guessed
+pub fn is_metered(interface: &str) -> Result<bool> {
+    let mut bus = system_bus()?;
+    let Some(device) = devices(&mut bus, false)?
+        .into_iter()
+        .find(|d| d.interface == interface)
+    else {
+        bail!("NetworkManager has no device {}", interface);
+    };
+    let metered = bus.get(NM, &device.path, NM_DEVICE, "Metered")?;
+    Ok(metered.as_u64().is_some_and(|m| METERED.contains(&m)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marshalling() {
+        let mut body = Writer::default();
+        body.string(NM);
+        body.string("Devices");
+        let fields = [
+            Field::Path(NM_PATH),
+            Field::Interface("org.freedesktop.DBus.Properties"),
+            Field::Member("Get"),
+            Field::Destination(NM),
+        ];
+        let call = message(METHOD_CALL, 7, &fields, "ss", body);
+        let message = Message::parse(&call).unwrap();
+        assert_eq!(message.kind, METHOD_CALL);
+        assert_eq!(message.serial, 7);
+        assert_eq!(message.path.as_deref(), Some(NM_PATH));
+        assert_eq!(message.member.as_deref(), Some("Get"));
+        assert_eq!(
+            message.body,
+            [
+                Value::Str(NM.to_string()),
+                Value::Str("Devices".to_string())
+            ]
+        );
+
+        // A variant holding an `ay` SSID, then a dict, after a `y`
+        let body = [
+            &[5u8, 2, b'a', b'y', 0, 0, 0, 0][..],
+            &[3, 0, 0, 0, b'K', b'T', b'X', 0],
+            &[7, 0, 0, 0, 0, 0, 0, 0],
+            &[1, 0, 0, 0, b'a', 0, b'x', 0],
+        ]
+        .concat();
+        let mut reader = Reader::new(&body, false);
+        assert_eq!(reader.value(b"y").unwrap(), Value::UInt(5));
+        let ssid = reader.value(b"v").unwrap();
+        assert_eq!(ssid.as_bytes().as_deref(), Some(&b"KTX"[..]));
+        reader.pos = 16;
+        let dict = reader.value(b"a{sy}").unwrap();
+        assert_eq!(
+            dict,
+            Value::Array(vec![Value::Struct(vec![
+                Value::Str("a".to_string()),
+                Value::UInt(b'x'.into())
+            ])])
+        );
+
+        assert_eq!(split_type(b"a{sv}as").unwrap(), (&b"a{sv}"[..], &b"as"[..]));
+        assert!(split_type(b"(su").is_err());
+        assert_eq!(
+            parse_address("unix:abstract=/tmp/x;unix:path=/run/dbus/sock,guid=1"),
+            Some(PathBuf::from("/run/dbus/sock"))
+        );
+    }
+}
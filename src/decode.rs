@@ -0,0 +1,157 @@
+//! Response bodies to text
+//!
+//! Gateway pages are not always UTF-8: older venue routers serve
+//! Windows-1258 (Vietnamese) and only say so in a `<meta>` tag, if at all,
+//! and some compress the page whether asked to or not. `text` finds the
+//! charset the way a browser would (byte order mark, then the
+//! `Content-Type` header, then `<meta>` in the first KiB) and decodes with
+//! it, so the parsers see the same characters a browser shows.
+
+use anyhow::{Context, Result};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1258};
+use std::io::Read;
+
+/// How much of a page is searched for a `<meta>` charset
+const META_SCAN: usize = 1024;
+
+/// `body` as text, in the charset it declares; undeclared bodies that are
+/// not valid UTF-8 are taken as Windows-1258
+pub fn text(body: &[u8], content_type: Option<&str>) -> String {
+    let declared = Encoding::for_bom(body)
+        .map(|(encoding, _)| encoding)
+        .or_else(|| content_type.and_then(header_charset))
+        .or_else(|| meta_charset(body));
+    let encoding = match declared {
+        Some(encoding) => encoding,
+        None if std::str::from_utf8(body).is_ok() => UTF_8,
+        None => WINDOWS_1258,
+    };
+    // Strips a BOM matching `encoding`, replaces undecodable bytes
+    let (text, _, _) = encoding.decode(body);
+    text.into_owned()
+}
+
+/// Undo a `Content-Encoding` reqwest did not handle (the compat client
+/// never asks for compression, some gateways send it anyway)
+pub fn decompress(body: Vec<u8>, content_encoding: Option<&str>) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match content_encoding
+        .map(|e| e.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("gzip" | "x-gzip") => {
+            flate2::read::GzDecoder::new(&body[..])
+                .read_to_end(&mut out)
+                .context("Bad gzip body")?;
+        }
+        // Supposed to be zlib-wrapped, often raw deflate in practice
+        Some("deflate") => {
+            if flate2::read::ZlibDecoder::new(&body[..])
+                .read_to_end(&mut out)
+                .is_err()
+            {
+                out.clear();
+                flate2::read::DeflateDecoder::new(&body[..])
+                    .read_to_end(&mut out)
+                    .context("Bad deflate body")?;
+            }
+        }
+        Some("br") => {
+            brotli_decompressor::Decompressor::new(&body[..], 4096)
+                .read_to_end(&mut out)
+                .context("Bad brotli body")?;
+        }
+        _ => return Ok(body),
+    }
+    Ok(out)
+}
+
+/// `charset=` of a `Content-Type` value
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    let lower = content_type.to_ascii_lowercase();
+    let (_, rest) = lower.split_once("charset=")?;
+    let label = rest
+        .split(';')
+        .next()?
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'');
+    Encoding::for_label(label.as_bytes())
+}
+
+/// `<meta charset="...">` or `<meta http-equiv="Content-Type"
+/// content="text/html; charset=...">` near the top of an HTML page
+fn meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    // Every charset a gateway would use is ASCII-compatible up to here
+    let head = String::from_utf8_lossy(&body[..body.len().min(META_SCAN)]).to_ascii_lowercase();
+    let mut rest = head.as_str();
+    while let Some(at) = rest.find("<meta") {
+        rest = &rest[at + 5..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        if let Some(at) = tag.find("charset=") {
+            let label: String = tag[at + 8..]
+                .trim_start_matches(['"', '\''])
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+                .collect();
+            if let Some(encoding) = Encoding::for_label(label.as_bytes()) {
+                return Some(encoding);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_charset_sources() {
+        // "Đăng nhập" (log in) in Windows-1258, where the dot below is a
+        // combining mark after "â"
+        let cp1258 = b"\xd0\xe3ng nh\xe2\xf2p";
+        let expected = "Đăng nh\u{e2}\u{323}p";
+        let page = [b"<meta charset=\"windows-1258\"><p>".as_slice(), cp1258].concat();
+        assert_eq!(
+            text(&page, None),
+            format!("<meta charset=\"windows-1258\"><p>{}", expected)
+        );
+        assert_eq!(
+            text(cp1258, Some("text/html; charset=windows-1258")),
+            expected
+        );
+        // Undeclared and not UTF-8
+        assert_eq!(text(cp1258, None), expected);
+
+        let legacy = [
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\">"
+                .as_slice(),
+            b"caf\xe9",
+        ]
+        .concat();
+        assert!(text(&legacy, None).ends_with("café"));
+        // The header wins over the page, a BOM over both
+        assert_eq!(
+            text("é".as_bytes(), Some("text/html; charset=\"UTF-8\"")),
+            "é"
+        );
+        assert_eq!(text(b"\xef\xbb\xbfok", Some("charset=windows-1258")), "ok");
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(b"<html>hi</html>").unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(decompress(gz, Some("gzip")).unwrap(), b"<html>hi</html>");
+
+        let mut raw = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        raw.write_all(b"hi").unwrap();
+        assert_eq!(
+            decompress(raw.finish().unwrap(), Some("deflate")).unwrap(),
+            b"hi"
+        );
+        assert_eq!(decompress(b"hi".to_vec(), None).unwrap(), b"hi");
+    }
+}
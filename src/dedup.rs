@@ -0,0 +1,290 @@
+//! Collapsing repeated log lines
+//!
+//! A daemon left running on a Pi for weeks logs the same warning every
+//! check interval for as long as the condition lasts ("Failed to check
+//! WiFi status", say), which fills small SD cards. `Dedup` wraps the log
+//! output layer and lets only the first of a run of identical events
+//! through; the next different event (or `window` after the run started)
+//! is preceded by "previous message repeated N times over M minutes".
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::callsite::{Callsite, Identifier};
+use tracing::field::{Field, FieldSet, Visit};
+use tracing::metadata::Kind;
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// `inner`, minus consecutive repeats of an event
+pub struct Dedup<L> {
+    inner: L,
+    /// Longest a run is kept quiet; zero passes everything through
+    window: Duration,
+    last: Mutex<Option<Run>>,
+}
+
+/// The event last let through, and its repeats since
+struct Run {
+    key: String,
+    level: Level,
+    started: Instant,
+    repeats: u64,
+}
+
+impl<L> Dedup<L> {
+    pub fn new(inner: L, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            last: Mutex::new(None),
+        }
+    }
+}
+
+/// An event's level, target and fields, which make it a repeat
+fn key(event: &Event<'_>) -> String {
+    struct Fields(String);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?};", field.name(), value));
+        }
+    }
+    let metadata = event.metadata();
+    let mut fields = Fields(format!("{} {} ", metadata.level(), metadata.target()));
+    event.record(&mut fields);
+    fields.0
+}
+
+/// "over 40 seconds" / "over 12 minutes"
+fn duration_text(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 120 {
+        format!("{} seconds", secs)
+    } else {
+        format!("{} minutes", secs / 60)
+    }
+}
+
+impl<S, L> Layer<S> for Dedup<L>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    L: Layer<S>,
+{
+    fn on_register_dispatch(&self, subscriber: &tracing::Dispatch) {
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.inner.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        self.inner.register_callsite(metadata)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        self.inner.enabled(metadata, ctx)
+    }
+
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        self.inner.on_new_span(attrs, id, ctx);
+    }
+
+    fn on_record(
+        &self,
+        span: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: Context<'_, S>,
+    ) {
+        self.inner.on_record(span, values, ctx);
+    }
+
+    fn on_follows_from(
+        &self,
+        span: &tracing::span::Id,
+        follows: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        self.inner.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.window.is_zero() {
+            self.inner.on_event(event, ctx);
+            return;
+        }
+        let key = key(event);
+        let now = Instant::now();
+        let finished = {
+            let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+            match last.as_mut() {
+                Some(run) if run.key == key && now.duration_since(run.started) < self.window => {
+                    run.repeats += 1;
+                    return;
+                }
+                _ => last.replace(Run {
+                    key,
+                    level: *event.metadata().level(),
+                    started: now,
+                    repeats: 0,
+                }),
+            }
+        };
+        if let Some(run) = finished.filter(|run| run.repeats > 0) {
+            let message = format!(
+                "previous message repeated {} times over {}",
+                run.repeats,
+                duration_text(now.duration_since(run.started))
+            );
+            summary(&self.inner, run.level, &message, ctx.clone());
+        }
+        self.inner.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &tracing::span::Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &tracing::span::Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &tracing::span::Id, new: &tracing::span::Id, ctx: Context<'_, S>) {
+        self.inner.on_id_change(old, new, ctx);
+    }
+}
+
+/// Callsites of the summary lines, one per level the collapsed events had
+///
+/// Events logged from inside a layer never reach the subscriber, so the
+/// summary is handed to the inner layer directly, which needs metadata of
+/// its own.
+struct SummaryCallsite(Level);
+
+static SUMMARY_FIELDS: &[&str] = &["message"];
+
+macro_rules! summary_metadata {
+    ($callsite:ident, $meta:ident, $level:expr) => {
+        static $callsite: SummaryCallsite = SummaryCallsite($level);
+        static $meta: Metadata<'static> = Metadata::new(
+            "repeated",
+            "wimesh::dedup",
+            $level,
+            Some(file!()),
+            Some(line!()),
+            Some(module_path!()),
+            FieldSet::new(SUMMARY_FIELDS, Identifier(&$callsite)),
+            Kind::EVENT,
+        );
+    };
+}
+
+summary_metadata!(ERROR_CALLSITE, ERROR_METADATA, Level::ERROR);
+summary_metadata!(WARN_CALLSITE, WARN_METADATA, Level::WARN);
+summary_metadata!(INFO_CALLSITE, INFO_METADATA, Level::INFO);
+summary_metadata!(DEBUG_CALLSITE, DEBUG_METADATA, Level::DEBUG);
+summary_metadata!(TRACE_CALLSITE, TRACE_METADATA, Level::TRACE);
+
+fn summary_metadata(level: Level) -> &'static Metadata<'static> {
+    match level {
+        Level::ERROR => &ERROR_METADATA,
+        Level::WARN => &WARN_METADATA,
+        Level::INFO => &INFO_METADATA,
+        Level::DEBUG => &DEBUG_METADATA,
+        _ => &TRACE_METADATA,
+    }
+}
+
+impl Callsite for SummaryCallsite {
+    fn set_interest(&self, _: Interest) {}
+
+    fn metadata(&self) -> &Metadata<'_> {
+        summary_metadata(self.0)
+    }
+}
+
+/// Hand `message` at `level` to `inner` as if it had been logged
+fn summary<S, L: Layer<S>>(inner: &L, level: Level, message: &str, ctx: Context<'_, S>)
+where
+    S: Subscriber,
+{
+    let metadata = summary_metadata(level);
+    let fields = metadata.fields();
+    let Some(field) = fields.field("message") else {
+        return;
+    };
+    let message = format_args!("{}", message);
+    let values = [(&field, Some(&message as &dyn tracing::Value))];
+    let values = fields.value_set(&values);
+    inner.on_event(&Event::new(metadata, &values), ctx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Collects the message of every event it gets
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Messages {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            struct Message(String);
+            impl Visit for Message {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+    }
+
+    #[test]
+    fn test_collapses_repeats() {
+        let messages = Messages::default();
+        let dedup = Dedup::new(messages.clone(), Duration::from_secs(3600));
+        let subscriber = tracing_subscriber::registry().with(dedup);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                tracing::warn!("Failed to check WiFi status: {}", "nmcli not found");
+            }
+            tracing::warn!("Failed to check WiFi status: {}", "timed out");
+            tracing::info!("Connected");
+        });
+        assert_eq!(
+            *messages.0.lock().unwrap(),
+            [
+                "Failed to check WiFi status: nmcli not found",
+                "previous message repeated 2 times over 0 seconds",
+                "Failed to check WiFi status: timed out",
+                "Connected",
+            ]
+        );
+
+        let messages = Messages::default();
+        let subscriber =
+            tracing_subscriber::registry().with(Dedup::new(messages.clone(), Duration::ZERO));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("Again");
+            tracing::warn!("Again");
+        });
+        assert_eq!(messages.0.lock().unwrap().len(), 2);
+    }
+}
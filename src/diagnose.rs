@@ -0,0 +1,450 @@
+//! Explaining why the machine is offline
+//!
+//! `wimesh why` turns what wimesh knows into one plain sentence with the
+//! evidence behind it: the WiFi association and address, whether the
+//! gateway answers, what the connectivity probes saw, and what the state
+//! file and event log say about recent logins ("portal login succeeded 3m
+//! ago, but the probes get no answer: the venue's internet appears down").
+//! `gather` collects the evidence; `explain` only reasons about it, so it
+//! works on evidence from anywhere.
+
+use crate::config::Config;
+use crate::events::{read_all as read_events, Event, EventLog};
+use crate::probe::{self, Classification, ProbeResult};
+use crate::state::{self, unix_now};
+use crate::status::{self, NetworkState};
+use crate::utils;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+
+const GATEWAY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Everything `explain` reasons about
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Evidence {
+    /// Unix time the evidence was gathered
+    pub at: u64,
+    /// Configured SSID the machine is associated to
+    pub ssid: Option<String>,
+    pub interface: Option<String>,
+    /// SSID associated to that no portal is configured for
+    pub unconfigured_ssid: Option<String>,
+    /// Why the interface has no usable IP address
+    pub address_problem: Option<String>,
+    pub gateway: Option<IpAddr>,
+    pub gateway_reachable: Option<bool>,
+    pub probes: Vec<ProbeResult>,
+    pub verdict: Option<Classification>,
+    /// Unix time of the last successful login on the SSID
+    pub last_login: Option<u64>,
+    /// The last login attempt in the event log on the SSID
+    pub last_attempt: Option<Attempt>,
+    /// When the daemon last saw the SSID change state, and to what
+    pub state_since: Option<(NetworkState, u64)>,
+    /// Consecutive failed logins, and seconds until the next is allowed
+    pub backoff: Option<(u32, u64)>,
+    pub read_only: bool,
+    /// The daemon stopped updating its plan, so it is probably not running
+    pub daemon_stale: bool,
+}
+
+/// A login attempt from the event log
+#[derive(Debug, Clone, Serialize)]
+pub struct Attempt {
+    pub ts: u64,
+    pub portal: String,
+    pub ok: bool,
+    pub error_kind: Option<String>,
+    pub error: Option<String>,
+}
+
+/// The explanation `wimesh why` prints
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnosis {
+    pub online: bool,
+    /// One sentence: what is going on
+    pub summary: String,
+    /// The facts it rests on, one per line
+    pub evidence: Vec<String>,
+    /// What to do about it, when anything helps
+    pub advice: Option<String>,
+}
+
+/// Look at the WiFi, the gateway, the internet, the state file and the
+/// event log
+pub async fn gather(cfg: &Config) -> Evidence {
+    let now = unix_now();
+    let mut evidence = Evidence {
+        at: now,
+        ..Evidence::default()
+    };
+
+    let ssids = cfg.all_ssids();
+    let active = utils::nonblocking::active_wifi().await.unwrap_or_default();
+    match active
+        .iter()
+        .find(|(_, ssid)| ssids.contains(&ssid.as_str()))
+    {
+        Some((interface, ssid)) => {
+            evidence.interface = Some(interface.clone());
+            evidence.ssid = Some(ssid.clone());
+        }
+        None => evidence.unconfigured_ssid = active.first().map(|(_, ssid)| ssid.clone()),
+    }
+    let Some(ssid) = evidence.ssid.clone() else {
+        return evidence;
+    };
+    let interface = evidence.interface.clone();
+
+    if let Some(ref interface) = interface {
+        evidence.address_problem = utils::nonblocking::interface_address_problem(interface).await;
+    }
+    evidence.gateway = utils::nonblocking::default_gateway(interface.as_deref()).await;
+    if let Some(gateway) = evidence.gateway {
+        evidence.gateway_reachable = Some(utils::gateway_reachable(gateway, GATEWAY_TIMEOUT).await);
+    }
+    if evidence.address_problem.is_none() {
+        for target in probe::targets() {
+            evidence
+                .probes
+                .push(probe::run(target, interface.as_deref()).await);
+        }
+        evidence.verdict = Some(probe::verdict(&evidence.probes));
+    }
+
+    let state = state::nonblocking::load().await;
+    evidence.last_login = state.last_login.get(&ssid).copied();
+    evidence.backoff = state.backoff_remaining(&ssid).map(|wait| {
+        let failures = state.backoff.get(&ssid).map(|b| b.failures);
+        (failures.unwrap_or_default(), wait)
+    });
+    evidence.read_only = state.is_read_only(cfg.global.read_only);
+    evidence.daemon_stale = state
+        .next_action
+        .as_ref()
+        .is_none_or(|next| status::is_stale(next, cfg, now));
+
+    let events = EventLog::new(&cfg.events);
+    for record in events.path().map(read_events).unwrap_or_default() {
+        if record.event.ssid() != Some(ssid.as_str()) {
+            continue;
+        }
+        match record.event {
+            Event::Login {
+                portal,
+                ok,
+                error_kind,
+                error,
+                ..
+            } => {
+                evidence.last_attempt = Some(Attempt {
+                    ts: record.ts,
+                    portal,
+                    ok,
+                    error_kind,
+                    error,
+                })
+            }
+            Event::StateChange { to, .. } => evidence.state_since = Some((to, record.ts)),
+            _ => {}
+        }
+    }
+    evidence
+}
+
+/// "45s", "12m", "3h", "2d"
+fn span(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// What `evidence` means
+pub fn explain(evidence: &Evidence) -> Diagnosis {
+    let ago = |ts: u64| span(evidence.at.saturating_sub(ts));
+    let mut facts = Vec::new();
+    let diagnosis = |online, summary: String, advice: Option<&str>, facts| Diagnosis {
+        online,
+        summary,
+        evidence: facts,
+        advice: advice.map(str::to_string),
+    };
+
+    let Some(ref ssid) = evidence.ssid else {
+        return match evidence.unconfigured_ssid {
+            Some(ref other) => {
+                facts.push(format!(
+                    "wifi      associated to '{}', not configured",
+                    other
+                ));
+                diagnosis(
+                    false,
+                    format!(
+                        "Associated to '{}', which no portal is configured for",
+                        other
+                    ),
+                    Some("`wimesh adopt` adds it to the portal it resembles"),
+                    facts,
+                )
+            }
+            None => {
+                facts.push("wifi      not associated to any network".to_string());
+                diagnosis(
+                    false,
+                    "Not connected to any WiFi network".to_string(),
+                    Some("Connect to one of the configured SSIDs"),
+                    facts,
+                )
+            }
+        };
+    };
+    facts.push(format!(
+        "wifi      associated to '{}'{}",
+        ssid,
+        evidence
+            .interface
+            .as_deref()
+            .map(|i| format!(" on {}", i))
+            .unwrap_or_default()
+    ));
+    if let Some(ref problem) = evidence.address_problem {
+        facts.push(format!("address   {}", problem));
+    }
+    match (evidence.gateway, evidence.gateway_reachable) {
+        (Some(gateway), Some(true)) => facts.push(format!("gateway   {} answers", gateway)),
+        (Some(gateway), _) => facts.push(format!("gateway   {} does not answer", gateway)),
+        (None, _) => facts.push("gateway   no default route".to_string()),
+    }
+    if let Some(verdict) = evidence.verdict {
+        let agree = evidence
+            .probes
+            .iter()
+            .filter(|p| p.classification == verdict)
+            .count();
+        facts.push(format!(
+            "probes    {} ({} of {})",
+            verdict.as_str(),
+            agree,
+            evidence.probes.len()
+        ));
+    }
+    let login_ok = evidence
+        .last_login
+        .map(|ts| format!("portal login succeeded {} ago", ago(ts)));
+    if let Some(ref attempt) = evidence.last_attempt {
+        let result = match (attempt.ok, &attempt.error_kind) {
+            (true, _) => "ok".to_string(),
+            (false, Some(kind)) => format!("failed ({})", kind),
+            (false, None) => "failed".to_string(),
+        };
+        facts.push(format!(
+            "login     {} {} ago via '{}'",
+            result,
+            ago(attempt.ts),
+            attempt.portal
+        ));
+    } else if let Some(ts) = evidence.last_login {
+        facts.push(format!("login     ok {} ago", ago(ts)));
+    } else {
+        facts.push("login     none recorded".to_string());
+    }
+    if let Some((state, since)) = evidence.state_since {
+        facts.push(format!("state     {} for {}", state.as_str(), ago(since)));
+    }
+    if let Some((failures, wait)) = evidence.backoff {
+        facts.push(format!(
+            "backoff   {} failure(s), next login in {}s",
+            failures, wait
+        ));
+    }
+    if evidence.read_only {
+        facts.push("daemon    read-only, not logging in".to_string());
+    } else if evidence.daemon_stale {
+        facts.push("daemon    not running (its plan is stale)".to_string());
+    }
+
+    let associated = format!("Associated to '{}'", ssid);
+    if let Some(ref problem) = evidence.address_problem {
+        return diagnosis(
+            false,
+            format!(
+                "{}, but without a usable IP address ({}): no portal can help",
+                associated, problem
+            ),
+            Some("Reconnect to the WiFi, or set `global.renew_dhcp` for the daemon to do it"),
+            facts,
+        );
+    }
+    if evidence.gateway.is_none() {
+        return diagnosis(
+            false,
+            format!("{}, but there is no default route", associated),
+            Some("Reconnect to the WiFi so DHCP sets up the route"),
+            facts,
+        );
+    }
+
+    let failed_attempt = evidence.last_attempt.as_ref().filter(|a| !a.ok);
+    match evidence.verdict {
+        Some(Classification::Online) => diagnosis(
+            true,
+            format!("Online: associated to '{}' and the internet answers", ssid),
+            None,
+            facts,
+        ),
+        Some(Classification::Captive) => {
+            let why_not = if evidence.read_only {
+                (
+                    "the daemon is read-only and will not log in".to_string(),
+                    Some("`wimesh read-only off` lets it log in again"),
+                )
+            } else if let Some((failures, wait)) = evidence.backoff {
+                (
+                    format!(
+                        "{} login(s) failed in a row, the next is in {}s",
+                        failures, wait
+                    ),
+                    Some("`wimesh reset-backoff` retries now; `wimesh history` shows the errors"),
+                )
+            } else if let Some(attempt) = failed_attempt {
+                (
+                    format!(
+                        "the last login {} ago failed: {}",
+                        ago(attempt.ts),
+                        attempt.error.as_deref().unwrap_or("no error recorded")
+                    ),
+                    Some("`wimesh login` retries with the full log"),
+                )
+            } else if let Some(ref login) = login_ok {
+                (
+                    format!(
+                        "{}, so the session probably expired or the venue ended it",
+                        login
+                    ),
+                    Some("`wimesh login` logs in again"),
+                )
+            } else if evidence.daemon_stale {
+                (
+                    "the daemon is not running to log in".to_string(),
+                    Some("Start it, or run `wimesh login` once"),
+                )
+            } else {
+                ("the daemon logs in at its next check".to_string(), None)
+            };
+            diagnosis(
+                false,
+                format!(
+                    "{}, but the portal is holding traffic: {}",
+                    associated, why_not.0
+                ),
+                why_not.1,
+                facts,
+            )
+        }
+        Some(Classification::DnsFailure) => diagnosis(
+            false,
+            match login_ok {
+                Some(login) => format!(
+                    "{}, {}, but names do not resolve: the venue's DNS appears down",
+                    associated, login
+                ),
+                None => format!(
+                    "{}, but names do not resolve and no login is recorded",
+                    associated
+                ),
+            },
+            Some("Wait it out or tell the venue; logging in again will not help"),
+            facts,
+        ),
+        Some(Classification::Unreachable) | None => {
+            let gateway_down = evidence.gateway_reachable == Some(false);
+            let (what, advice) = match login_ok {
+                _ if gateway_down => (
+                    "but neither the gateway nor the internet answers: the hotspot itself \
+                     appears down"
+                        .to_string(),
+                    "Reconnect, or try another access point of the venue",
+                ),
+                Some(login) => (
+                    format!(
+                        "{}, but the probes get no answer: the venue's internet appears down",
+                        login
+                    ),
+                    "Wait it out or tell the venue; logging in again will not help",
+                ),
+                None => (
+                    "but the probes get no answer and no login is recorded; the portal may \
+                     block without redirecting"
+                        .to_string(),
+                    "`wimesh login` tries the portal anyway",
+                ),
+            };
+            diagnosis(
+                false,
+                format!("{}, {}", associated, what),
+                Some(advice),
+                facts,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn associated() -> Evidence {
+        Evidence {
+            at: 1_760_000_000,
+            ssid: Some("1.Free Wi-MESH".to_string()),
+            interface: Some("wlan0".to_string()),
+            gateway: "10.20.30.1".parse().ok(),
+            gateway_reachable: Some(true),
+            ..Evidence::default()
+        }
+    }
+
+    #[test]
+    fn test_explain() {
+        let upstream_down = Evidence {
+            verdict: Some(Classification::Unreachable),
+            last_login: Some(1_760_000_000 - 180),
+            ..associated()
+        };
+        let diagnosis = explain(&upstream_down);
+        assert!(!diagnosis.online);
+        assert_eq!(
+            diagnosis.summary,
+            "Associated to '1.Free Wi-MESH', portal login succeeded 3m ago, but the probes get \
+             no answer: the venue's internet appears down"
+        );
+        assert!(diagnosis
+            .evidence
+            .contains(&"gateway   10.20.30.1 answers".to_string()));
+
+        let backing_off = Evidence {
+            verdict: Some(Classification::Captive),
+            backoff: Some((5, 240)),
+            ..associated()
+        };
+        let diagnosis = explain(&backing_off);
+        assert!(diagnosis.summary.contains("5 login(s) failed in a row"));
+        assert!(diagnosis.advice.unwrap().contains("reset-backoff"));
+
+        let online = Evidence {
+            verdict: Some(Classification::Online),
+            ..associated()
+        };
+        assert!(explain(&online).online);
+
+        let elsewhere = Evidence {
+            unconfigured_ssid: Some("Cafe".to_string()),
+            ..Evidence::default()
+        };
+        assert!(explain(&elsewhere).summary.contains("no portal is configured"));
+    }
+}
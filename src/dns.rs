@@ -0,0 +1,248 @@
+//! Name resolution while captive
+//!
+//! Behind some venues' gateways the system resolver cannot resolve the
+//! portal API host until the login is done; only the gateway's own DNS
+//! answers for it. `captive_overrides` finds those names and resolves them
+//! through the servers the captive network does offer, for `HttpClient` to
+//! use for the rest of one login flow.
+//!
+//! Captive networks' DNS is also often slow, seconds per name. With
+//! `[dns] cache` the login flow and `wimesh probe` resolve through an
+//! in-process resolver (the servers of `/etc/resolv.conf`) that keeps
+//! answers for their TTL, up to `max_ttl`. Either way every lookup is
+//! timed, and each login records what its lookups cost as a `dns` event;
+//! the counters belong to the login's task (`counting`), so logins running
+//! side by side each report their own.
+
+use crate::config::DnsConfig;
+use anyhow::{Context, Result};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a lookup may take before the name counts as unresolvable
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether `host` resolves in time
+pub async fn resolves(host: &str) -> bool {
+    match tokio::time::timeout(LOOKUP_TIMEOUT, lookup(host)).await {
+        Ok(Ok(addrs)) => !addrs.is_empty(),
+        _ => false,
+    }
+}
+
+/// What the lookups of one `counting` run cost
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsStats {
+    pub lookups: u32,
+    /// Answered from the cache, without asking a server
+    pub cache_hits: u32,
+    pub failures: u32,
+    /// Total time spent waiting for servers
+    pub total_ms: u64,
+    pub max_ms: u64,
+    /// Name of the slowest lookup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slowest: Option<String>,
+}
+
+impl DnsStats {
+    fn record(&mut self, host: &str, elapsed: Option<Duration>, ok: bool) {
+        self.lookups += 1;
+        if !ok {
+            self.failures += 1;
+        }
+        let Some(elapsed) = elapsed else {
+            self.cache_hits += 1;
+            return;
+        };
+        let ms = elapsed.as_millis() as u64;
+        self.total_ms += ms;
+        if ms >= self.max_ms {
+            self.max_ms = ms;
+            self.slowest = Some(host.to_string());
+        }
+    }
+
+    /// Mean time of the lookups that went to a server
+    pub fn avg_ms(&self) -> u64 {
+        let asked = self.lookups - self.cache_hits;
+        self.total_ms.checked_div(asked as u64).unwrap_or_default()
+    }
+}
+
+/// Cached answers, and the in-process resolver filling them
+struct Cache {
+    resolver: TokioAsyncResolver,
+    max_ttl: Duration,
+    answers: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+tokio::task_local! {
+    /// Counters of the `counting` run this task is in
+    static STATS: RefCell<DnsStats>;
+}
+
+/// Turn the cache on for this process, as `[dns]` says; the system
+/// resolver is used when off or when `/etc/resolv.conf` is unreadable
+pub fn configure(cfg: &DnsConfig) {
+    if !cfg.cache {
+        return;
+    }
+    let (config, mut opts) = match hickory_resolver::system_conf::read_system_conf() {
+        Ok(conf) => conf,
+        Err(e) => {
+            tracing::warn!("No DNS cache, cannot read the system resolver config: {}", e);
+            return;
+        }
+    };
+    // Answers are kept here, where hits can be counted
+    opts.cache_size = 0;
+    let _ = CACHE.set(Cache {
+        resolver: TokioAsyncResolver::tokio(config, opts),
+        max_ttl: Duration::from_secs(cfg.max_ttl),
+        answers: Mutex::new(HashMap::new()),
+    });
+}
+
+/// Run `login`, counting the lookups it makes; lookups outside of any
+/// `counting` run are not counted
+pub async fn counting<F: Future>(login: F) -> (F::Output, DnsStats) {
+    STATS
+        .scope(RefCell::default(), async move {
+            let output = login.await;
+            (output, STATS.with(RefCell::take))
+        })
+        .await
+}
+
+/// Addresses of `host`: from the cache when on and fresh, else from a
+/// server; timed into the stats either way
+pub async fn lookup(host: &str) -> std::io::Result<Vec<IpAddr>> {
+    let cache = CACHE.get();
+    if let Some(cache) = cache {
+        let answers = cache.answers.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((ips, until)) = answers.get(host) {
+            if *until > Instant::now() {
+                let ips = ips.clone();
+                drop(answers);
+                record(host, None, true);
+                return Ok(ips);
+            }
+        }
+    }
+
+    let started = Instant::now();
+    let result = match cache {
+        Some(cache) => match cache.resolver.lookup_ip(host).await {
+            Ok(lookup) => {
+                let ips: Vec<IpAddr> = lookup.iter().collect();
+                let until = lookup.valid_until().min(started + cache.max_ttl);
+                let mut answers = cache.answers.lock().unwrap_or_else(|e| e.into_inner());
+                answers.insert(host.to_string(), (ips.clone(), until));
+                Ok(ips)
+            }
+            Err(e) => Err(std::io::Error::other(e)),
+        },
+        None => tokio::net::lookup_host((host, 0))
+            .await
+            .map(|addrs| addrs.map(|addr| addr.ip()).collect()),
+    };
+    let elapsed = started.elapsed();
+    if elapsed >= LOOKUP_TIMEOUT {
+        tracing::debug!("   -> Resolving {} took {}ms", host, elapsed.as_millis());
+    }
+    record(host, Some(elapsed), result.is_ok());
+    result
+}
+
+fn record(host: &str, elapsed: Option<Duration>, ok: bool) {
+    let _ = STATS.try_with(|stats| stats.borrow_mut().record(host, elapsed, ok));
+}
+
+/// Resolve `host` by asking `server` directly
+pub async fn resolve_via(server: IpAddr, host: &str) -> Result<Vec<IpAddr>> {
+    let servers = NameServerConfigGroup::from_ips_clear(&[server], 53, true);
+    let config = ResolverConfig::from_parts(None, Vec::new(), servers);
+    let mut opts = ResolverOpts::default();
+    opts.timeout = LOOKUP_TIMEOUT;
+    opts.attempts = 1;
+
+    let resolver = TokioAsyncResolver::tokio(config, opts);
+    let lookup = resolver
+        .lookup_ip(host)
+        .await
+        .with_context(|| format!("{} did not resolve {}", server, host))?;
+    Ok(lookup.iter().collect())
+}
+
+/// For each of `hosts` the system cannot resolve, the addresses one of
+/// `servers` gives for it, trying the servers in order
+pub async fn captive_overrides(hosts: &[String], servers: &[IpAddr]) -> Vec<(String, Vec<IpAddr>)> {
+    let mut overrides = Vec::new();
+    for host in hosts {
+        if host.parse::<IpAddr>().is_ok() || resolves(host).await {
+            continue;
+        }
+        for &server in servers {
+            match resolve_via(server, host).await {
+                Ok(ips) if !ips.is_empty() => {
+                    tracing::info!("   -> Resolved {} via captive DNS {}", host, server);
+                    overrides.push((host.clone(), ips));
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::debug!("   -> {:#}", e),
+            }
+        }
+    }
+    overrides
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = DnsStats::default();
+        stats.record("api.awing.vn", Some(Duration::from_millis(1200)), true);
+        stats.record("api.awing.vn", None, true);
+        stats.record("connectivitycheck.gstatic.com", Some(Duration::from_millis(300)), true);
+        stats.record("v1.awingconnect.vn", Some(Duration::from_millis(0)), false);
+        assert_eq!(stats.lookups, 4);
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.avg_ms(), 500);
+        assert_eq!(stats.max_ms, 1200);
+        assert_eq!(stats.slowest.as_deref(), Some("api.awing.vn"));
+        assert_eq!(DnsStats::default().avg_ms(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_counting_keeps_logins_apart() {
+        let login = |hosts: &'static [&'static str]| {
+            counting(async move {
+                for host in hosts {
+                    tokio::task::yield_now().await;
+                    record(host, Some(Duration::from_millis(10)), true);
+                }
+            })
+        };
+        let (first, second) = tokio::join!(login(&["a.test", "b.test"]), login(&["c.test"]));
+        assert_eq!(first.1.lookups, 2);
+        assert_eq!(second.1.lookups, 1);
+        assert_eq!(second.1.slowest.as_deref(), Some("c.test"));
+
+        // Nobody counting
+        record("d.test", None, true);
+    }
+}
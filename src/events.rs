@@ -0,0 +1,527 @@
+//! Structured event log
+//!
+//! One JSON object per line, appended as things happen: network state
+//! transitions, login attempts, probe results. Unlike the tracing output it
+//! is meant for programs (the stats command, external dashboards), so the
+//! format is stable and the file is kept whatever the log level. It rotates
+//! by size, keeping a few old files as `events.jsonl.1`, `.2`, ...
+
+use crate::config::EventsConfig;
+use crate::dns::DnsStats;
+use crate::portal::{soft_failures, StepReport};
+use crate::report::error_kind;
+use crate::state::{state_dirs, unix_now};
+use crate::recovery::Step;
+use crate::status::NetworkState;
+use crate::utils::Radio;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+const EVENTS_FILE: &str = "events.jsonl";
+/// Events a slow subscriber may lag behind before missing some
+const STREAM_CAPACITY: usize = 256;
+
+/// Something worth recording
+///
+/// Variant and field names are part of the file format: add new ones, never
+/// rename.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The daemon saw the network change state
+    StateChange {
+        ssid: Option<String>,
+        from: Option<NetworkState>,
+        to: NetworkState,
+    },
+    /// A login flow ran, or a cached session was resumed
+    Login {
+        ssid: String,
+        portal: String,
+        ok: bool,
+        resumed: bool,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_kind: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// Non-critical steps that failed on the way to a successful login
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        degraded: Vec<String>,
+        /// Access point the login went through
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bssid: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        radio: Option<Radio>,
+    },
+    /// A reachability check ran, e.g. `gateway` or `internet`
+    Probe {
+        probe: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        ok: bool,
+    },
+    /// Associated to an SSID no portal is configured for
+    UnknownSsid {
+        ssid: String,
+        /// Configured SSID it is probably meant to be
+        #[serde(skip_serializing_if = "Option::is_none")]
+        suggestion: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hint: Option<String>,
+    },
+    /// Congestion mode turned on after `timeouts` timed-out logins in a
+    /// row, or off again
+    Congestion { active: bool, timeouts: u32 },
+    /// Recovery mode took `step` on `ssid`, captive for `outage_secs`
+    Recovery {
+        ssid: String,
+        step: Step,
+        outage_secs: u64,
+    },
+    /// The routers behind `bssid` on `ssid` are not the ones seen there
+    /// before, e.g. a replaced mesh node; cached sessions were dropped
+    VenueChanged {
+        ssid: String,
+        bssid: String,
+        change: String,
+    },
+    /// What the name lookups of one login on `ssid` cost
+    Dns {
+        ssid: String,
+        lookups: u32,
+        cache_hits: u32,
+        failures: u32,
+        avg_ms: u64,
+        max_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        slowest: Option<String>,
+    },
+}
+
+impl Event {
+    pub fn login(
+        ssid: &str,
+        portal: &str,
+        resumed: bool,
+        elapsed: Duration,
+        result: &Result<()>,
+        steps: &[StepReport],
+    ) -> Self {
+        let degraded = match result {
+            Ok(()) => soft_failures(steps).into_iter().map(str::to_string).collect(),
+            Err(_) => Vec::new(),
+        };
+        Self::Login {
+            ssid: ssid.to_string(),
+            portal: portal.to_string(),
+            ok: result.is_ok(),
+            resumed,
+            duration_ms: elapsed.as_millis() as u64,
+            error_kind: result.as_ref().err().map(|e| error_kind(e).to_string()),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            degraded,
+            bssid: None,
+            radio: None,
+        }
+    }
+
+    /// This `login` event, with the access point it went through
+    pub fn via(mut self, ap: Option<&str>, ap_radio: Option<Radio>) -> Self {
+        if let Self::Login { bssid, radio, .. } = &mut self {
+            *bssid = ap.map(str::to_string);
+            *radio = ap_radio;
+        }
+        self
+    }
+
+    /// What a login's lookups cost (see `dns::counting`), if it made any
+    pub fn dns(ssid: &str, stats: DnsStats) -> Option<Self> {
+        (stats.lookups > 0).then(|| Self::Dns {
+            ssid: ssid.to_string(),
+            lookups: stats.lookups,
+            cache_hits: stats.cache_hits,
+            failures: stats.failures,
+            avg_ms: stats.avg_ms(),
+            max_ms: stats.max_ms,
+            slowest: stats.slowest,
+        })
+    }
+
+    pub fn venue_changed(ssid: &str, bssid: &str, change: &str) -> Self {
+        Self::VenueChanged {
+            ssid: ssid.to_string(),
+            bssid: bssid.to_string(),
+            change: change.to_string(),
+        }
+    }
+
+    pub fn probe(probe: &str, target: Option<String>, ok: bool) -> Self {
+        Self::Probe {
+            probe: probe.to_string(),
+            target,
+            ok,
+        }
+    }
+
+    /// The SSID the event is about, if it is about one
+    pub fn ssid(&self) -> Option<&str> {
+        match self {
+            Self::StateChange { ssid, .. } => ssid.as_deref(),
+            Self::Login { ssid, .. } => Some(ssid),
+            Self::Probe { .. } | Self::Congestion { .. } => None,
+            Self::UnknownSsid { ssid, .. }
+            | Self::Recovery { ssid, .. }
+            | Self::VenueChanged { ssid, .. }
+            | Self::Dns { ssid, .. } => Some(ssid),
+        }
+    }
+
+    /// One-line human description
+    pub fn describe(&self) -> String {
+        match self {
+            Self::StateChange { ssid, from, to } => format!(
+                "{}: {} -> {}",
+                ssid.as_deref().unwrap_or("-"),
+                from.map(NetworkState::as_str).unwrap_or("?"),
+                to.as_str()
+            ),
+            Self::Login {
+                ssid,
+                portal,
+                ok,
+                resumed,
+                duration_ms,
+                error_kind,
+                degraded,
+                radio,
+                ..
+            } => {
+                let how = if *resumed { "resume" } else { "login" };
+                let result = match (ok, error_kind) {
+                    (true, _) if !degraded.is_empty() => {
+                        format!("ok without {}", degraded.join(", "))
+                    }
+                    (true, _) => "ok".to_string(),
+                    (false, Some(kind)) => format!("failed ({})", kind),
+                    (false, None) => "failed".to_string(),
+                };
+                let on = match radio {
+                    Some(radio) => format!(" on {}", radio.describe()),
+                    None => String::new(),
+                };
+                format!(
+                    "{}: {} via '{}' {} in {}ms{}",
+                    ssid, how, portal, result, duration_ms, on
+                )
+            }
+            Self::Probe { probe, target, ok } => format!(
+                "{} probe{}{} {}",
+                probe,
+                if target.is_some() { " of " } else { "" },
+                target.as_deref().unwrap_or_default(),
+                if *ok { "ok" } else { "failed" }
+            ),
+            Self::UnknownSsid {
+                ssid, suggestion, ..
+            } => match suggestion {
+                Some(suggestion) => {
+                    format!("{}: not configured, did you mean '{}'?", ssid, suggestion)
+                }
+                None => format!("{}: not configured", ssid),
+            },
+            Self::Congestion {
+                active: true,
+                timeouts,
+            } => format!("congestion mode on after {} timeouts", timeouts),
+            Self::Congestion { active: false, .. } => "congestion mode off".to_string(),
+            Self::Recovery {
+                ssid,
+                step,
+                outage_secs,
+            } => format!(
+                "{}: recovery {} after {}s captive",
+                ssid,
+                step.as_str(),
+                outage_secs
+            ),
+            Self::VenueChanged {
+                ssid,
+                bssid,
+                change,
+            } => format!(
+                "{}: venue infrastructure changed at {} ({})",
+                ssid, bssid, change
+            ),
+            Self::Dns {
+                ssid,
+                lookups,
+                cache_hits,
+                failures,
+                avg_ms,
+                max_ms,
+                slowest,
+            } => format!(
+                "{}: {} DNS lookups ({} cached, {} failed), avg {}ms, slowest {} {}ms",
+                ssid,
+                lookups,
+                cache_hits,
+                failures,
+                avg_ms,
+                slowest.as_deref().unwrap_or("-"),
+                max_ms
+            ),
+        }
+    }
+}
+
+/// One line of the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRecord {
+    /// Unix time the event was recorded
+    pub ts: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Appends events to the configured file, if enabled, and hands them to
+/// every subscriber
+pub struct EventLog {
+    path: Option<PathBuf>,
+    max_bytes: u64,
+    keep: u32,
+    stream: broadcast::Sender<EventRecord>,
+}
+
+impl EventLog {
+    pub fn new(cfg: &EventsConfig) -> Self {
+        let path = if !cfg.enabled {
+            None
+        } else if cfg.path.is_empty() {
+            default_path()
+        } else {
+            Some(PathBuf::from(&cfg.path))
+        };
+
+        Self {
+            path,
+            max_bytes: cfg.max_size_kb * 1024,
+            keep: cfg.keep,
+            stream: broadcast::channel(STREAM_CAPACITY).0,
+        }
+    }
+
+    /// An event log that writes no file; subscribers still get the events
+    pub fn disabled() -> Self {
+        Self {
+            path: None,
+            max_bytes: 0,
+            keep: 0,
+            stream: broadcast::channel(STREAM_CAPACITY).0,
+        }
+    }
+
+    /// Every event recorded from now on, e.g. for a GUI; a subscriber that
+    /// falls more than a few hundred events behind misses the oldest
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.stream.subscribe()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Append `event`; failures are logged, never returned, so a full disk
+    /// cannot stop a login
+    pub fn record(&self, event: Event) {
+        let record = EventRecord {
+            ts: unix_now(),
+            event,
+        };
+        // Fails only when nobody subscribed
+        let _ = self.stream.send(record.clone());
+        let Some(ref path) = self.path else {
+            return;
+        };
+        if let Err(e) = self.append(path, &record) {
+            tracing::warn!("Failed to write event to {}: {:#}", path.display(), e);
+        }
+    }
+
+    fn append(&self, path: &Path, record: &EventRecord) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if self.max_bytes > 0 && size >= self.max_bytes {
+            rotate(path, self.keep)?;
+        }
+
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        // One write per line keeps concurrent appenders from interleaving
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Shift `path` to `path.1`, `path.1` to `path.2`, ..., dropping the
+/// oldest beyond `keep`
+pub fn rotate(path: &Path, keep: u32) -> std::io::Result<()> {
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        let from = rotated(path, n);
+        if from.exists() {
+            std::fs::rename(&from, rotated(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(path, 1))
+}
+
+/// `events.jsonl` in the primary state directory
+pub fn default_path() -> Option<PathBuf> {
+    state_dirs()
+        .into_iter()
+        .next()
+        .map(|dir| dir.join(EVENTS_FILE))
+}
+
+/// Every readable record in `path` and its rotated files, oldest first;
+/// lines that do not parse (e.g. a torn last line) are skipped
+pub fn read_all(path: &Path) -> Vec<EventRecord> {
+    let mut files = vec![path.to_path_buf()];
+    for n in 1.. {
+        let old = rotated(path, n);
+        if !old.exists() {
+            break;
+        }
+        files.push(old);
+    }
+
+    files
+        .iter()
+        .rev()
+        .filter_map(|file| std::fs::read_to_string(file).ok())
+        .flat_map(|content| {
+            content
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect::<Vec<EventRecord>>()
+        })
+        .collect()
+}
+
+/// The `n`th rotated file of `path`
+pub fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_in(dir: &Path, max_bytes: u64, keep: u32) -> EventLog {
+        EventLog {
+            path: Some(dir.join(EVENTS_FILE)),
+            max_bytes,
+            keep,
+            stream: broadcast::channel(STREAM_CAPACITY).0,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wimesh-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_lines_round_trip() {
+        let dir = temp_dir("events-round-trip");
+        let log = log_in(&dir, 0, 0);
+        let mut stream = log.subscribe();
+        log.record(Event::probe("gateway", Some("10.0.0.1".into()), false));
+        log.record(Event::StateChange {
+            ssid: Some("Free".into()),
+            from: None,
+            to: NetworkState::Captive,
+        });
+
+        let content = std::fs::read_to_string(dir.join(EVENTS_FILE)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""event":"probe""#));
+        let record: EventRecord = serde_json::from_str(lines[1]).unwrap();
+        assert!(matches!(
+            record.event,
+            Event::StateChange {
+                to: NetworkState::Captive,
+                ..
+            }
+        ));
+        assert!(matches!(
+            stream.try_recv().unwrap().event,
+            Event::Probe { .. }
+        ));
+        assert!(matches!(
+            stream.try_recv().unwrap().event,
+            Event::StateChange { .. }
+        ));
+
+        let login = Event::login("Free", "KTX", false, Duration::from_millis(800), &Ok(()), &[])
+            .via(Some("02:00:00:AA:BB:01"), Radio::from_frequency(5180, Some(866)));
+        assert_eq!(
+            login.describe(),
+            "Free: login via 'KTX' ok in 800ms on 5GHz ch 36, 866 Mbit/s"
+        );
+        let line = serde_json::to_string(&login).unwrap();
+        assert!(line.contains(r#""radio":{"band":"5GHz","channel":36,"rate_mbps":866}"#));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_all_spans_rotated_files() {
+        let dir = temp_dir("events-read-all");
+        let log = log_in(&dir, 1, 3);
+        for ok in [false, true, true] {
+            log.record(Event::probe("internet", None, ok));
+        }
+
+        let records = read_all(&dir.join(EVENTS_FILE));
+        let oks: Vec<bool> = records
+            .iter()
+            .map(|r| matches!(r.event, Event::Probe { ok: true, .. }))
+            .collect();
+        assert_eq!(oks, [false, true, true]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_keeps_limited_files() {
+        let dir = temp_dir("events-rotation");
+        let log = log_in(&dir, 1, 2);
+        for _ in 0..5 {
+            log.record(Event::probe("internet", None, true));
+        }
+
+        let path = dir.join(EVENTS_FILE);
+        assert!(path.exists());
+        assert!(rotated(&path, 1).exists());
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+//! HAR capture of a login flow
+//!
+//! With `har_file` set on a portal, every request its `HttpClient` makes is
+//! recorded and the flow is written out as a HAR 1.2 file, one page per
+//! flow step, for opening in a browser's network inspector. Request and
+//! response bodies are not kept, and cookie headers are masked: the file is
+//! meant to be attached to bug reports.
+
+use anyhow::{Context, Result};
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode, Url};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Headers whose values never go into the file
+const MASKED_HEADERS: &[&str] = &["cookie", "set-cookie", "authorization"];
+
+/// Requests recorded so far, grouped by the step that made them
+#[derive(Debug, Default)]
+pub struct HarLog {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    pages: Vec<Value>,
+    entries: Vec<Value>,
+}
+
+impl HarLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attribute the following requests to `step`
+    pub fn start_page(&self, step: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.pages.push(json!({
+            "id": step,
+            "title": step,
+            "startedDateTime": iso8601(SystemTime::now()),
+            "pageTimings": {},
+        }));
+    }
+
+    /// Record one request and its response, or `None` if it failed to get
+    /// one
+    pub fn record(
+        &self,
+        method: &Method,
+        url: &Url,
+        request_headers: &HeaderMap,
+        started: SystemTime,
+        elapsed: Duration,
+        response: Option<(StatusCode, &HeaderMap)>,
+    ) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let pageref = inner.pages.last().map(|p| p["id"].clone());
+
+        let (status, status_text, response_headers) = match response {
+            Some((status, headers)) => (
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default(),
+                headers_json(headers),
+            ),
+            None => (0, "", Vec::new()),
+        };
+        let ms = elapsed.as_secs_f64() * 1000.0;
+
+        let mut entry = json!({
+            "startedDateTime": iso8601(started),
+            "time": ms,
+            "request": {
+                "method": method.as_str(),
+                "url": url.as_str(),
+                "httpVersion": "HTTP/1.1",
+                "headers": headers_json(request_headers),
+                "queryString": url
+                    .query_pairs()
+                    .map(|(name, value)| json!({ "name": name, "value": value }))
+                    .collect::<Vec<_>>(),
+                "cookies": [],
+                "headersSize": -1,
+                "bodySize": -1,
+            },
+            "response": {
+                "status": status,
+                "statusText": status_text,
+                "httpVersion": "HTTP/1.1",
+                "headers": response_headers,
+                "cookies": [],
+                "content": { "size": -1, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            },
+            "cache": {},
+            "timings": { "send": 0, "wait": ms, "receive": 0 },
+        });
+        if let Some(pageref) = pageref {
+            entry["pageref"] = pageref;
+        }
+        inner.entries.push(entry);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "wimesh", "version": env!("CARGO_PKG_VERSION") },
+                "pages": inner.pages,
+                "entries": inner.entries,
+            }
+        })
+    }
+
+    /// Write the capture to `path` and start over
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let har = serde_json::to_string_pretty(&self.to_json())?;
+        std::fs::write(path, har).with_context(|| format!("Failed to write {}", path.display()))?;
+        *self.inner.lock().unwrap_or_else(|e| e.into_inner()) = Inner::default();
+        Ok(())
+    }
+}
+
+fn headers_json(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if MASKED_HEADERS.contains(&name.as_str()) {
+                "[masked]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            json!({ "name": name.as_str(), "value": value })
+        })
+        .collect()
+}
+
+/// `2024-05-01T12:34:56.789Z`
+fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Days since 1970-01-01 to (year, month, day), proleptic Gregorian
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iso8601() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_714_566_896_789);
+        assert_eq!(iso8601(time), "2024-05-01T12:34:56.789Z");
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_entries_reference_step_and_mask_cookies() {
+        let log = HarLog::new();
+        log.start_page("handshake");
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "session=secret".parse().unwrap());
+        let url = Url::parse("http://portal.test/login?mac=1").unwrap();
+        log.record(
+            &Method::GET,
+            &url,
+            &headers,
+            SystemTime::now(),
+            Duration::from_millis(12),
+            None,
+        );
+
+        let har = log.to_json();
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["pageref"], "handshake");
+        assert_eq!(entry["request"]["headers"][0]["value"], "[masked]");
+        assert_eq!(entry["request"]["queryString"][0]["name"], "mac");
+        assert_eq!(entry["response"]["status"], 0);
+    }
+}
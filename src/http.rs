@@ -1,65 +1,303 @@
 //! HTTP client with retry logic, timeouts, and cookie support
 
-use anyhow::{bail, Result};
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, USER_AGENT};
-use reqwest::{Client, Response};
-use std::time::Duration;
+use crate::breaker::CircuitBreaker;
+use crate::congestion::{self, Congestion};
+use crate::har::HarLog;
+use crate::policy::RetryPolicy;
+use anyhow::Result;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Client, Method, Proxy, Request, RequestBuilder, Response, StatusCode};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
-const MAX_RETRIES: u32 = 3;
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/120.0";
+
+/// Browser-like UA without a platform token
+const GENERIC_USER_AGENT: &str =
+    "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+/// Common real-world UAs to blend in with when randomizing
+const USER_AGENT_POOL: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+    "Mozilla/5.0 (Linux; Android 14; SM-A546E) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+];
+
+/// Behaviour switches for an `HttpClient`
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Send no OS/platform or locale hints in headers
+    pub strip_device_hints: bool,
+    /// Pick a different common User-Agent for every session
+    pub randomize_user_agent: bool,
+    /// Always present this User-Agent; overrides the two switches above
+    pub user_agent: Option<String>,
+    /// Send every request out of this network interface, whatever the
+    /// routing table says (Linux only)
+    pub interface: Option<String>,
+    /// Send every request through this proxy, e.g. `socks5h://127.0.0.1:1080`
+    pub proxy: Option<String>,
+    /// How every request is retried
+    pub retry: RetryPolicy,
+}
+
+/// Which response statuses count as an answer to a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatusPolicy {
+    /// 2xx only
+    #[default]
+    Success,
+    /// Anything but a server error, for requests made for their side
+    /// effects: Awing's `/login` handshake sets the session cookie even when
+    /// it answers 302 or 403
+    NotServerError,
+}
+
+impl StatusPolicy {
+    pub fn accepts(self, status: StatusCode) -> bool {
+        match self {
+            Self::Success => status.is_success(),
+            Self::NotServerError => !status.is_server_error(),
+        }
+    }
+}
+
+/// A request that completed with an unacceptable status
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    #[error("Request failed: {status} - {body}")]
+    Status { status: StatusCode, body: String },
+}
 
 pub struct HttpClient {
     inner: Client,
+    options: HttpOptions,
+    user_agent: Mutex<HeaderValue>,
+    resolver: Arc<FlowResolver>,
+    capture: Mutex<Option<Arc<HarLog>>>,
+    breaker: Arc<CircuitBreaker>,
+    congestion: Arc<Congestion>,
+}
+
+/// `dns::lookup` (system DNS, or the cache), except for names given fixed
+/// addresses for the current flow
+#[derive(Default)]
+struct FlowResolver {
+    overrides: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl Resolve for FlowResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let fixed = self
+            .overrides
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name.as_str())
+            .cloned();
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            // Port 0 is replaced by the URL's port
+            let addrs: Vec<SocketAddr> = match fixed {
+                Some(ips) => ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+                None => crate::dns::lookup(&host)
+                    .await?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect(),
+            };
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
 }
 
 impl HttpClient {
     pub fn new() -> Result<Self> {
+        Self::with_options(HttpOptions::default())
+    }
+
+    pub fn with_options(options: HttpOptions) -> Result<Self> {
         let mut headers = HeaderMap::new();
-        headers.insert(
-            USER_AGENT,
-            HeaderValue::from_static("Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/120.0"),
-        );
         headers.insert(
             ACCEPT,
             HeaderValue::from_static("application/json, text/plain, */*"),
         );
-        headers.insert(
-            ACCEPT_LANGUAGE,
-            HeaderValue::from_static("en-US,en;q=0.9,vi;q=0.8"),
-        );
+        if !options.strip_device_hints {
+            headers.insert(
+                ACCEPT_LANGUAGE,
+                HeaderValue::from_static("en-US,en;q=0.9,vi;q=0.8"),
+            );
+        }
 
-        let client = Client::builder()
+        let resolver = Arc::new(FlowResolver::default());
+        let builder = Client::builder()
+            .dns_resolver(resolver.clone())
             .cookie_store(true)
             .timeout(DEFAULT_TIMEOUT)
             .connect_timeout(CONNECT_TIMEOUT)
-            .default_headers(headers)
-            .build()?;
+            .default_headers(headers);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let builder = match options.interface {
+            Some(ref interface) => builder.interface(interface),
+            None => builder,
+        };
+        let builder = match options.proxy {
+            Some(ref proxy) => builder.proxy(Proxy::all(proxy)?),
+            None => builder,
+        };
+        let client = builder.build()?;
+
+        let user_agent = Mutex::new(pick_user_agent(&options));
+        Ok(Self {
+            inner: client,
+            options,
+            user_agent,
+            resolver,
+            capture: Mutex::new(None),
+            breaker: CircuitBreaker::global(),
+            congestion: Congestion::global(),
+        })
+    }
+
+    /// Resolve `host` to `ips` until `clear_dns_overrides`
+    pub fn override_dns(&self, host: &str, ips: Vec<IpAddr>) {
+        let mut overrides = self
+            .resolver
+            .overrides
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        overrides.insert(host.to_string(), ips);
+    }
+
+    /// Go back to plain system DNS
+    pub fn clear_dns_overrides(&self) {
+        let mut overrides = self
+            .resolver
+            .overrides
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        overrides.clear();
+    }
+
+    /// Record every request into `log`, or stop recording with `None`
+    pub fn set_capture(&self, log: Option<Arc<HarLog>>) {
+        *self.capture.lock().unwrap_or_else(|e| e.into_inner()) = log;
+    }
+
+    /// Start a new login session: with `randomize_user_agent`, the
+    /// following requests present a freshly picked User-Agent
+    pub fn new_session(&self) {
+        if self.options.randomize_user_agent && self.options.user_agent.is_none() {
+            let ua = pick_user_agent(&self.options);
+            *self.user_agent.lock().unwrap_or_else(|e| e.into_inner()) = ua;
+        }
+    }
+
+    /// Interface requests are bound to, if any
+    pub fn interface(&self) -> Option<&str> {
+        self.options.interface.as_deref()
+    }
+
+    /// Proxy requests go through, if any
+    pub fn proxy(&self) -> Option<&str> {
+        self.options.proxy.as_deref()
+    }
 
-        Ok(Self { inner: client })
+    /// User-Agent of the current session, for requests made outside this
+    /// client
+    pub fn user_agent(&self) -> String {
+        let ua = self.user_agent.lock().unwrap_or_else(|e| e.into_inner());
+        ua.to_str().unwrap_or(DEFAULT_USER_AGENT).to_string()
+    }
+
+    /// Start a request carrying the current session's User-Agent
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let ua = self
+            .user_agent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        self.inner.request(method, url).header(USER_AGENT, ua)
     }
 
     pub async fn get(&self, url: &str) -> Result<Response> {
-        self.with_retry(|| self.inner.get(url).send()).await
+        self.with_retry(StatusPolicy::Success, || self.request(Method::GET, url))
+            .await
+    }
+
+    /// GET a page as text, decoded in the charset it declares (see
+    /// `decode::text`)
+    pub async fn get_text(&self, url: &str) -> Result<String> {
+        let resp = self.get(url).await?;
+        Self::text(resp).await
+    }
+
+    /// `get_text`, with the URL the page ended up at after redirects
+    pub async fn get_page(&self, url: &str) -> Result<(reqwest::Url, String)> {
+        let resp = self.get(url).await?;
+        let url = resp.url().clone();
+        Ok((url, Self::text(resp).await?))
+    }
+
+    /// `post_form`, then the response body decoded like `get_text`
+    pub async fn post_form_text<T: serde::Serialize + ?Sized>(
+        &self,
+        url: &str,
+        form: &T,
+    ) -> Result<String> {
+        let resp = self.post_form(url, form).await?;
+        Self::text(resp).await
+    }
+
+    /// Body of `resp` in the charset it declares
+    pub async fn text(resp: Response) -> Result<String> {
+        let content_type = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let body = resp.bytes().await?;
+        Ok(crate::decode::text(&body, content_type.as_deref()))
     }
 
     pub async fn get_with_headers(&self, url: &str, headers: HeaderMap) -> Result<Response> {
-        self.with_retry(|| self.inner.get(url).headers(headers.clone()).send())
+        self.get_accepting(url, headers, StatusPolicy::Success)
             .await
     }
 
+    /// GET that succeeds on every status `policy` accepts; cookies the
+    /// response sets are kept either way
+    pub async fn get_accepting(
+        &self,
+        url: &str,
+        headers: HeaderMap,
+        policy: StatusPolicy,
+    ) -> Result<Response> {
+        self.with_retry(policy, || {
+            self.request(Method::GET, url).headers(headers.clone())
+        })
+        .await
+    }
+
     pub async fn post_json<T: serde::Serialize + ?Sized>(
         &self,
         url: &str,
         body: &T,
     ) -> Result<Response> {
-        self.with_retry(|| {
-            self.inner
-                .post(url)
+        self.with_retry(StatusPolicy::Success, || {
+            self.request(Method::POST, url)
                 .header("Content-Type", "application/json")
                 .header("X-Requested-With", "XMLHttpRequest")
                 .json(body)
-                .send()
         })
         .await
     }
@@ -70,14 +308,12 @@ impl HttpClient {
         body: &T,
         headers: HeaderMap,
     ) -> Result<Response> {
-        self.with_retry(|| {
-            self.inner
-                .post(url)
+        self.with_retry(StatusPolicy::Success, || {
+            self.request(Method::POST, url)
                 .header("Content-Type", "application/json")
                 .header("X-Requested-With", "XMLHttpRequest")
                 .headers(headers.clone())
                 .json(body)
-                .send()
         })
         .await
     }
@@ -87,52 +323,115 @@ impl HttpClient {
         url: &str,
         form: &T,
     ) -> Result<Response> {
-        self.with_retry(|| self.inner.post(url).form(form).send())
-            .await
+        self.with_retry(StatusPolicy::Success, || {
+            self.request(Method::POST, url).form(form)
+        })
+        .await
+    }
+
+    /// Send one request, recording it when capturing
+    async fn send(&self, request: Request) -> reqwest::Result<Response> {
+        let capture = self
+            .capture
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let Some(capture) = capture else {
+            return self.inner.execute(request).await;
+        };
+
+        let (method, url, headers) = (
+            request.method().clone(),
+            request.url().clone(),
+            request.headers().clone(),
+        );
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let result = self.inner.execute(request).await;
+        let response = result.as_ref().ok().map(|r| (r.status(), r.headers()));
+        capture.record(&method, &url, &headers, started, timer.elapsed(), response);
+        result
     }
 
-    /// Retry up to MAX_RETRIES times with exponential backoff
-    async fn with_retry<F, Fut>(&self, request_fn: F) -> Result<Response>
+    /// Retry as the `retry` option says, failing fast while the host's
+    /// circuit is open; in congestion mode with longer timeouts and fewer
+    /// attempts. Statuses `policy` rejects are errors.
+    async fn with_retry<F>(&self, policy: StatusPolicy, request_fn: F) -> Result<Response>
     where
-        F: Fn() -> Fut,
-        Fut: std::future::Future<Output = reqwest::Result<Response>>,
+        F: Fn() -> RequestBuilder,
     {
+        let retry = &self.options.retry;
         let mut last_err = None;
+        let timeout = retry.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let (attempts, timeout) = if self.congestion.is_active() {
+            (
+                retry.max_attempts.min(congestion::MAX_ATTEMPTS),
+                timeout * congestion::TIMEOUT_FACTOR,
+            )
+        } else {
+            (retry.max_attempts.max(1), timeout)
+        };
+
+        for attempt in 0..attempts {
+            let request = request_fn().timeout(timeout).build()?;
+            let host = request.url().host_str().unwrap_or_default().to_string();
+            self.breaker.check(&host)?;
 
-        for attempt in 0..MAX_RETRIES {
-            match request_fn().await {
-                Ok(resp) if resp.status().is_success() => return Ok(resp),
-                Ok(resp) if resp.status().is_server_error() && attempt < MAX_RETRIES - 1 => {
-                    let delay = Duration::from_secs(1 << attempt);
+            let result = self.send(request).await;
+            let tripped = match result {
+                Ok(_) => {
+                    self.breaker.record_success(&host);
+                    false
+                }
+                Err(ref e) => {
+                    (e.is_connect() || e.is_timeout()) && self.breaker.record_failure(&host)
+                }
+            };
+
+            match result {
+                // The circuit just opened, retrying would only fail again
+                Err(e) if tripped => return Err(e.into()),
+                Ok(resp) if policy.accepts(resp.status()) => {
+                    if !resp.status().is_success() {
+                        tracing::debug!("Accepting status {} from {}", resp.status(), host);
+                    }
+                    return Ok(resp);
+                }
+                Ok(resp)
+                    if resp.status().is_server_error()
+                        && retry.retries_server_errors()
+                        && attempt < attempts - 1 =>
+                {
+                    let delay = retry.jittered_delay(attempt + 1);
                     let status = resp.status();
                     let body = resp.text().await.unwrap_or_default();
                     tracing::warn!(
                         "Server error {}, body: '{}', retrying in {:?}... (attempt {}/{})",
                         status,
-                        &body[..body.len().min(200)],
+                        truncate_chars(&body, 200),
                         delay,
                         attempt + 1,
-                        MAX_RETRIES
+                        attempts
                     );
                     tokio::time::sleep(delay).await;
                 }
                 Ok(resp) => {
                     let status = resp.status();
                     let text = resp.text().await.unwrap_or_default();
-                    bail!(
-                        "Request failed: {} - {}",
+                    return Err(HttpError::Status {
                         status,
-                        &text[..50.min(text.len())]
-                    );
+                        body: truncate_chars(&text, 50).to_string(),
+                    }
+                    .into());
                 }
-                Err(e) if attempt < MAX_RETRIES - 1 => {
-                    let delay = Duration::from_secs(1 << attempt);
+                Err(e) if attempt < attempts - 1 && retry.retries_request(&e) => {
+                    let delay = retry.jittered_delay(attempt + 1);
                     tracing::warn!(
                         "Request error: {}, retrying in {:?}... (attempt {}/{})",
                         e,
                         delay,
                         attempt + 1,
-                        MAX_RETRIES
+                        attempts
                     );
                     last_err = Some(e);
                     tokio::time::sleep(delay).await;
@@ -146,3 +445,97 @@ impl HttpClient {
             .unwrap_or_else(|| anyhow::anyhow!("Max retries exceeded")))
     }
 }
+
+/// At most `max` characters of `text`, cut on a character boundary
+pub(crate) fn truncate_chars(text: &str, max: usize) -> &str {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+fn pick_user_agent(options: &HttpOptions) -> HeaderValue {
+    if let Some(fixed) = options
+        .user_agent
+        .as_deref()
+        .and_then(|ua| HeaderValue::from_str(ua).ok())
+    {
+        return fixed;
+    }
+    HeaderValue::from_static(if options.randomize_user_agent {
+        let index = crate::utils::random_u64() as usize % USER_AGENT_POOL.len();
+        USER_AGENT_POOL[index]
+    } else if options.strip_device_hints {
+        GENERIC_USER_AGENT
+    } else {
+        DEFAULT_USER_AGENT
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve `response` to every connection on a local port
+    async fn serve(response: String) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    #[test]
+    fn test_truncate_chars() {
+        assert_eq!(truncate_chars("abc", 5), "abc");
+        assert_eq!(truncate_chars("abcdef", 3), "abc");
+        assert_eq!(truncate_chars("Đăng nhập", 4), "Đăng");
+    }
+
+    #[tokio::test]
+    async fn test_retry_server_error_with_non_ascii_body() {
+        let url = serve(format!(
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n{}",
+            "Hệ thống đang bảo trì. ".repeat(10)
+        ))
+        .await;
+        let retry = RetryPolicy {
+            delay: Duration::from_millis(1),
+            ..RetryPolicy::http(Duration::from_secs(5), 2)
+        };
+        let client = HttpClient::with_options(HttpOptions {
+            retry,
+            ..Default::default()
+        })
+        .unwrap();
+        let err = client.get(&url).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HttpError>(),
+            Some(HttpError::Status { status, .. }) if *status == StatusCode::SERVICE_UNAVAILABLE
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_status_error_with_non_ascii_body() {
+        let url = serve(
+            "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Connection: close\r\n\r\n\
+             Tài khoản của bạn đã hết hạn sử dụng, vui lòng liên hệ quầy lễ tân"
+                .to_string(),
+        )
+        .await;
+        let err = HttpClient::new().unwrap().get(&url).await.unwrap_err();
+        let Some(HttpError::Status { status, body }) = err.downcast_ref::<HttpError>() else {
+            panic!("expected a status error, got {:#}", err);
+        };
+        assert_eq!(*status, StatusCode::FORBIDDEN);
+        assert_eq!(body.chars().count(), 50);
+        assert!(body.starts_with("Tài khoản của bạn"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Per-venue device identities
+//!
+//! By default every portal sees the same MAC address and User-Agent, so
+//! the campus network and the café around the corner can tell it is the
+//! same laptop. With `[portals.identity]` each portal entry gets its own:
+//! fixed values from the config, or with `auto = true` random ones
+//! generated on first use and remembered in the state file, so a venue
+//! keeps recognising its returning device. `IdentityManager` resolves them
+//! for the portals and for the WiFi backend, which can clone the MAC onto
+//! the connection.
+
+use crate::config::PortalConfig;
+use crate::state::State;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// User-Agents an auto identity picks from, one per identity
+const AUTO_USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Linux; Android 14; SM-A546E) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+];
+
+/// What the device presents to one portal
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// MAC address sent to the portal; empty means the configured default
+    pub mac_address: String,
+    /// User-Agent of every request; `None` keeps the privacy settings'
+    /// choice
+    pub user_agent: Option<String>,
+    /// Name filled into the portal's customer form
+    #[serde(default)]
+    pub customer_name: String,
+}
+
+/// Resolves the identity of each portal, generating auto identities once
+#[derive(Default)]
+pub struct IdentityManager {
+    generated: Mutex<HashMap<String, Identity>>,
+    /// Save newly generated identities in the state file
+    persist: bool,
+}
+
+impl IdentityManager {
+    /// Manager remembering auto identities in the state file
+    pub fn load() -> Self {
+        Self {
+            generated: Mutex::new(State::load().identities),
+            persist: true,
+        }
+    }
+
+    /// The identity `portal` presents: its configured values, completed by
+    /// the remembered auto identity when `identity.auto` is set
+    pub fn resolve(&self, portal: &PortalConfig) -> Identity {
+        let mut identity = Identity {
+            mac_address: portal.mac_address.clone(),
+            user_agent: portal
+                .identity
+                .user_agent
+                .clone()
+                .filter(|ua| !ua.is_empty()),
+            customer_name: portal.identity.customer_name.clone(),
+        };
+        if !portal.identity.auto {
+            return identity;
+        }
+
+        let auto = self.auto_identity(&portal.name);
+        if identity.mac_address.is_empty() {
+            identity.mac_address = auto.mac_address;
+        }
+        if identity.user_agent.is_none() {
+            identity.user_agent = auto.user_agent;
+        }
+        identity
+    }
+
+    /// Replace the auto identity of `portal` with a newly generated one;
+    /// None unless `identity.auto` is set
+    pub fn rotate(&self, portal: &PortalConfig) -> Option<Identity> {
+        if !portal.identity.auto {
+            return None;
+        }
+        self.generated
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&portal.name);
+        Some(self.resolve(portal))
+    }
+
+    fn auto_identity(&self, portal: &str) -> Identity {
+        let mut generated = self.generated.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(identity) = generated.get(portal) {
+            return identity.clone();
+        }
+
+        let identity = Identity {
+            mac_address: random_mac(crate::utils::random_u64()),
+            user_agent: Some(
+                AUTO_USER_AGENTS[crate::utils::random_u64() as usize % AUTO_USER_AGENTS.len()]
+                    .to_string(),
+            ),
+            customer_name: String::new(),
+        };
+        tracing::info!("[{}] Generated a new identity for this venue", portal);
+        if self.persist {
+            State::record_identity(portal, identity.clone());
+        }
+        generated.insert(portal.to_string(), identity.clone());
+        identity
+    }
+}
+
+/// A locally administered unicast MAC from `seed`, which cannot collide
+/// with a vendor-assigned address
+pub fn random_mac(seed: u64) -> String {
+    let mut bytes = seed.to_le_bytes();
+    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+    bytes[..6]
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_random_mac_is_local_unicast() {
+        for seed in [0, 1, u64::MAX, 0x0123_4567_89ab_cdef] {
+            let mac = random_mac(seed);
+            let first = u8::from_str_radix(&mac[..2], 16).unwrap();
+            assert_eq!(first & 0x03, 0x02, "{}", mac);
+            assert_eq!(mac.len(), 17);
+        }
+    }
+
+    #[test]
+    fn test_auto_identity_is_stable_and_explicit_values_win() {
+        let manager = IdentityManager::default();
+        let mut portal = Config::default().portals.remove(0);
+        portal.identity.auto = true;
+
+        let first = manager.resolve(&portal);
+        assert!(!first.mac_address.is_empty());
+        assert_eq!(manager.resolve(&portal), first);
+        let rotated = manager.rotate(&portal).unwrap();
+        assert_ne!(rotated.mac_address, first.mac_address);
+        assert_eq!(manager.resolve(&portal), rotated);
+
+        portal.mac_address = "AA:BB:CC:DD:EE:FF".into();
+        portal.identity.user_agent = Some("UA".into());
+        let explicit = manager.resolve(&portal);
+        assert_eq!(explicit.mac_address, "AA:BB:CC:DD:EE:FF");
+        assert_eq!(explicit.user_agent.as_deref(), Some("UA"));
+    }
+}
@@ -0,0 +1,186 @@
+//! JSON log lines (`[logging] format = "json"`)
+//!
+//! For shipping the log to Loki, Elasticsearch and the like: every line is
+//! one JSON object with `ts`, `level`, `target` and `message`, the fields
+//! of the event, and those of `SPAN_FIELDS` set by the spans it happened
+//! in, so a line logged during a login step says which portal, SSID and
+//! step without a regex over the message. Retries add `attempt` to the
+//! line saying so. `wimesh logs` reads these lines back like text ones.
+
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::Record;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Span fields every line under the span carries: those of a login's span
+/// (see `login`) and of its steps' (see `portal::flow`)
+pub const SPAN_FIELDS: &[&str] = &["portal", "ssid", "interface", "step"];
+
+/// Lines as JSON objects; spans need `JsonFields` for theirs to be seen
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'w> FormatFields<'w> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        // Innermost span first, so its value wins
+        for span in ctx.event_scope().into_iter().flatten() {
+            let extensions = span.extensions();
+            let Some(formatted) = extensions.get::<FormattedFields<N>>() else {
+                continue;
+            };
+            let Ok(span_fields) = serde_json::from_str::<Map<String, Value>>(formatted) else {
+                continue;
+            };
+            for name in SPAN_FIELDS {
+                if let Some(value) = span_fields.get(*name) {
+                    fields.0.entry(*name).or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        let mut ts = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut ts))?;
+        let metadata = event.metadata();
+        let mut line = fields.0;
+        let message = line.remove("message").unwrap_or_default();
+        // Events of the `log` crate (reqwest's, say) name their origin in
+        // `log.*` fields, their target being `log`
+        let target = match line.remove("log.target") {
+            Some(target) => target,
+            None => metadata.target().into(),
+        };
+        line.retain(|name, _| !name.starts_with("log."));
+        line.insert("ts".into(), ts.into());
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), target);
+        line.insert("message".into(), message);
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/// Span fields kept as a JSON object, for `JsonFormat` to read
+#[derive(Default)]
+pub struct JsonFields;
+
+impl<'w> FormatFields<'w> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'w>, fields: R) -> fmt::Result {
+        let mut visitor = Fields::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = Fields(serde_json::from_str(current).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// Field values as JSON
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_json_lines_carry_span_fields() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink = written.clone();
+        let layer = tracing_subscriber::fmt::layer()
+            .event_format(JsonFormat)
+            .fmt_fields(JsonFields)
+            .with_writer(move || Sink(sink.clone()));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let login = tracing::info_span!("login", ssid = "1.Free Wi-MESH", portal = "KTX Khu B");
+            let _login = login.enter();
+            let step = tracing::info_span!("step", step = "handshake", critical = true);
+            step.in_scope(|| tracing::warn!(attempt = 1u32, "Step handshake failed, retrying"));
+            tracing::info!("Connected successfully!");
+        });
+
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2, "{}", written);
+        assert_eq!(lines[0]["level"], "WARN");
+        assert_eq!(lines[0]["message"], "Step handshake failed, retrying");
+        assert_eq!(lines[0]["portal"], "KTX Khu B");
+        assert_eq!(lines[0]["ssid"], "1.Free Wi-MESH");
+        assert_eq!(lines[0]["step"], "handshake");
+        assert_eq!(lines[0]["attempt"], 1);
+        // Not one of SPAN_FIELDS
+        assert!(lines[0].get("critical").is_none());
+        assert!(lines[1].get("step").is_none());
+        assert_eq!(lines[1]["portal"], "KTX Khu B");
+        assert_eq!(lines[1]["target"], module_path!());
+        assert!(lines[1]["ts"].as_str().unwrap().ends_with('Z'));
+    }
+
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
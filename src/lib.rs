@@ -0,0 +1,72 @@
+//! Wimesh - Auto-login client for captive portals
+//!
+//! Library half of the `wimesh` binary: configuration, HTTP client, parsers
+//! and portal implementations. The CLI (`main.rs`, with one module per group
+//! of commands under `cmd/`) is a thin layer on top.
+//! Frontends embedding it should stick to `api` (feature `api`), the part
+//! kept stable.
+
+// Output belongs to the frontend: the library only logs through `tracing`
+#![deny(clippy::print_stdout, clippy::print_stderr)]
+
+#[cfg(feature = "api")]
+pub mod api;
+
+pub mod audit;
+pub mod background;
+pub mod bench;
+pub mod breaker;
+pub mod capabilities;
+pub mod capport;
+pub mod companion;
+pub mod compat;
+pub mod completions;
+pub mod config;
+pub mod congestion;
+pub mod control;
+pub mod coop;
+pub mod daemon;
+pub mod dbus;
+pub mod decode;
+pub mod dedup;
+pub mod diagnose;
+pub mod dns;
+pub mod events;
+pub mod har;
+pub mod http;
+pub mod identity;
+pub mod jsonlog;
+pub mod lock;
+pub mod logs;
+pub mod login;
+pub mod mock;
+pub mod models;
+pub mod parser;
+pub mod phrases;
+pub mod policy;
+pub mod portal;
+pub mod probe;
+pub mod progress;
+pub mod qr;
+pub mod recovery;
+pub mod remote;
+pub mod report;
+pub mod responder;
+pub mod rotation;
+pub mod schedule;
+pub mod secrets;
+pub mod service;
+pub mod state;
+pub mod status;
+pub mod store;
+pub mod stress;
+pub mod suggest;
+pub mod supervisor;
+pub mod systemd;
+pub mod tasks;
+pub mod telemetry;
+#[cfg(feature = "tray")]
+pub mod tray;
+pub mod utils;
+pub mod watch;
+pub mod winservice;
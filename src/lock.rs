@@ -0,0 +1,87 @@
+//! Login flow locking
+//!
+//! Two portals matching the same network must never run their login flows
+//! at the same time: the gateway sees interleaved handshakes and each flow
+//! clobbers the other's session cookies. `LoginLocks` hands out one async
+//! mutex per interface, acquired with a timeout so a wedged flow cannot
+//! block every later attempt forever.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+
+/// Key used when the interface of a connection could not be determined
+pub const DEFAULT_KEY: &str = "default";
+
+/// Guard held for the duration of one login flow
+pub type LoginGuard = OwnedMutexGuard<()>;
+
+/// The login lock of an interface could not be acquired in time
+#[derive(Debug, thiserror::Error)]
+#[error("Another login flow is still running on '{key}' (waited {waited:?})")]
+pub struct LockTimeout {
+    pub key: String,
+    pub waited: Duration,
+}
+
+/// Keyed async mutex, one lock per network interface
+#[derive(Default)]
+pub struct LoginLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl LoginLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Acquire the login lock for `key`, waiting at most `timeout`
+    pub async fn acquire(&self, key: &str, timeout: Duration) -> Result<LoginGuard> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+            locks.entry(key.to_string()).or_default().clone()
+        };
+
+        match tokio::time::timeout(timeout, lock.lock_owned()).await {
+            Ok(guard) => Ok(guard),
+            Err(_) => Err(LockTimeout {
+                key: key.to_string(),
+                waited: timeout,
+            }
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_same_key_times_out() {
+        let locks = LoginLocks::new();
+        let _held = locks
+            .acquire("wlan0", Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        let second = locks.acquire("wlan0", Duration::from_millis(10)).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_different_keys_are_independent() {
+        let locks = LoginLocks::new();
+        let _wlan0 = locks
+            .acquire("wlan0", Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert!(locks
+            .acquire("wlan1", Duration::from_millis(10))
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,246 @@
+//! One login, the way every caller runs it
+//!
+//! Takes the interface's login lock, resumes the cached session when the
+//! portal supports it, falls back to the full flow, and records the
+//! outcome in the state file and the event log. A gateway MAC or router
+//! login URL other than the one last seen behind the same access point
+//! means the venue replaced its router (mesh nodes get swapped): the
+//! cached session and cookies are dropped before they cause confusing
+//! failures. The CLI commands, the daemon and embedders (`api::Handle`)
+//! all log in through here.
+
+use crate::audit::{Attempt, AuditLog};
+use crate::config::Config;
+use crate::events::{Event, EventLog};
+use crate::lock::{self, LoginLocks};
+use crate::portal::{soft_failures, CaptivePortal, VenueChanged};
+use crate::state::{self, Venue};
+use crate::utils::nonblocking;
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// Run a portal's login flow out of `interface` while holding its login
+/// lock
+pub async fn locked_connect(
+    cfg: &Config,
+    locks: &LoginLocks,
+    events: &EventLog,
+    ssid: &str,
+    interface: Option<&str>,
+    portal: &mut Box<dyn CaptivePortal>,
+) -> Result<()> {
+    let key = interface.unwrap_or(lock::DEFAULT_KEY);
+    let timeout = Duration::from_secs(cfg.global.login_lock_timeout);
+
+    let _guard = locks.acquire(key, timeout).await?;
+    // The root of the login's trace (see `telemetry`)
+    let span = tracing::info_span!(
+        "login",
+        ssid,
+        portal = portal.name(),
+        interface,
+        error = tracing::field::Empty
+    );
+    // Only this login's lookups go into its `dns` event
+    let login = connect(cfg, events, ssid, interface, portal).instrument(span.clone());
+    let (result, dns) = crate::dns::counting(login).await;
+    if let Err(e) = &result {
+        span.record("error", format!("{:#}", e));
+    }
+    if let Some(event) = Event::dns(ssid, dns) {
+        events.record(event);
+    }
+    result
+}
+
+/// `locked_connect` once the lock is held
+async fn connect(
+    cfg: &Config,
+    events: &EventLog,
+    ssid: &str,
+    interface: Option<&str>,
+    portal: &mut Box<dyn CaptivePortal>,
+) -> Result<()> {
+    if portal.capabilities().needs_consent && !cfg.global.accept_terms {
+        anyhow::bail!(
+            "Portal '{}' accepts the venue's terms on your behalf, which global.accept_terms \
+             = false forbids",
+            portal.name()
+        );
+    }
+    portal.bind_interface(interface)?;
+    let bssid = match interface {
+        Some(interface) => nonblocking::active_bssid(interface).await,
+        None => None,
+    };
+    let radio = match interface {
+        Some(interface) => nonblocking::active_radio(interface).await,
+        None => None,
+    };
+    let gateway_mac = gateway_mac(interface).await;
+    if let Some(ref bssid) = bssid {
+        let seen = Venue {
+            gateway_mac: gateway_mac.clone(),
+            login_url: None,
+        };
+        if let Some(change) = state::nonblocking::observe_venue(bssid, seen).await {
+            venue_changed(events, ssid, bssid, &change, portal).await?;
+        }
+    }
+    let audit = AuditLog::new(&cfg.audit);
+    let record_attempt = |portal: &dyn CaptivePortal, resumed, ok| {
+        audit.record(Attempt {
+            ssid,
+            portal: portal.name(),
+            mac: portal.client_mac(),
+            interface,
+            resumed,
+            ok,
+        })
+    };
+
+    let ttl = if portal.capabilities().supports_resume {
+        cfg.global.session_cache_ttl
+    } else {
+        0
+    };
+    let cached = state::nonblocking::load()
+        .await
+        .session(ssid, portal.name(), ttl)
+        .map(|s| s.data.clone());
+    if let Some(session) = cached {
+        let started = Instant::now();
+        let result = portal.resume(&session).await;
+        events.record(
+            Event::login(
+                ssid,
+                portal.name(),
+                true,
+                started.elapsed(),
+                &result,
+                portal.last_steps(),
+            )
+            .via(bssid.as_deref(), radio),
+        );
+        record_attempt(portal.as_ref(), true, result.is_ok());
+        match result {
+            Ok(()) => {
+                state::nonblocking::record_login(ssid, portal.name(), portal.session()).await;
+                observe_login_url(events, ssid, bssid.as_deref(), portal.as_ref()).await;
+                record_session_info(ssid, portal.as_ref()).await;
+                return Ok(());
+            }
+            Err(e) => match e.downcast_ref::<VenueChanged>() {
+                Some(VenueChanged(change)) => {
+                    let bssid = bssid.as_deref().unwrap_or("-");
+                    venue_changed(events, ssid, bssid, change, portal).await?;
+                }
+                None => {
+                    tracing::info!("Cached session unusable, running full login: {:#}", e);
+                    state::nonblocking::forget_session(ssid).await;
+                }
+            },
+        }
+    }
+
+    let started = Instant::now();
+    let result = portal.connect().await;
+    events.record(
+        Event::login(
+            ssid,
+            portal.name(),
+            false,
+            started.elapsed(),
+            &result,
+            portal.last_steps(),
+        )
+        .via(bssid.as_deref(), radio),
+    );
+    record_attempt(portal.as_ref(), false, result.is_ok());
+    result?;
+    let degraded = soft_failures(portal.last_steps());
+    if !degraded.is_empty() {
+        tracing::warn!(
+            "Logged in via '{}', but without {}",
+            portal.name(),
+            degraded.join(", ")
+        );
+    }
+
+    let session = if ttl > 0 { portal.session() } else { None };
+    state::nonblocking::record_login(ssid, portal.name(), session).await;
+    observe_login_url(events, ssid, bssid.as_deref(), portal.as_ref()).await;
+    record_session_info(ssid, portal.as_ref()).await;
+    Ok(())
+}
+
+/// MAC of the default gateway out of `interface`, from the neighbor table
+async fn gateway_mac(interface: Option<&str>) -> Option<String> {
+    let interface = interface?;
+    let gateway = nonblocking::default_gateway(Some(interface)).await?;
+    nonblocking::neighbors(interface)
+        .await
+        .into_iter()
+        .find(|(addr, _)| gateway == *addr)
+        .map(|(_, mac)| mac)
+}
+
+/// Drop what was cached for the venue's old router: the session on `ssid`
+/// and the portal's cookies
+async fn venue_changed(
+    events: &EventLog,
+    ssid: &str,
+    bssid: &str,
+    change: &str,
+    portal: &mut Box<dyn CaptivePortal>,
+) -> Result<()> {
+    tracing::warn!(
+        "Venue infrastructure changed on '{}' ({}): {}, dropping the cached session",
+        ssid,
+        bssid,
+        change
+    );
+    events.record(Event::venue_changed(ssid, bssid, change));
+    state::nonblocking::forget_session(ssid).await;
+    portal.reset()
+}
+
+/// Remember the router login URL the flow used behind `bssid`; a new one
+/// is only reported, the login that saw it worked
+async fn observe_login_url(
+    events: &EventLog,
+    ssid: &str,
+    bssid: Option<&str>,
+    portal: &dyn CaptivePortal,
+) {
+    let (Some(bssid), Some(login_url)) = (bssid, portal.login_url()) else {
+        return;
+    };
+    let seen = Venue {
+        gateway_mac: None,
+        login_url: Some(login_url),
+    };
+    if let Some(change) = state::nonblocking::observe_venue(bssid, seen).await {
+        tracing::info!(
+            "Venue infrastructure changed on '{}' ({}): {}",
+            ssid,
+            bssid,
+            change
+        );
+        events.record(Event::venue_changed(ssid, bssid, &change));
+    }
+}
+
+/// Ask the portal about the fresh session and keep the answer for the
+/// status displays
+pub async fn record_session_info(ssid: &str, portal: &dyn CaptivePortal) {
+    if !portal.capabilities().supports_session_info {
+        return;
+    }
+    match portal.session_info().await {
+        Ok(Some(info)) => state::nonblocking::record_session_info(ssid, info).await,
+        Ok(None) => {}
+        Err(e) => tracing::debug!("No session info from '{}': {:#}", portal.name(), e),
+    }
+}
@@ -0,0 +1,571 @@
+//! Reading the daemon's log back
+//!
+//! Under systemd the daemon's output lands in the journal. Run as a Windows
+//! or macOS service, or anywhere without journald, it is lost unless
+//! `[logging] log_file` names a file, which every run then appends its log
+//! lines to as well, moving it aside daily or by size (`LogFile`).
+//! `wimesh logs` reads either, the file with the rotated ones: it splits the lines the
+//! log output writes, text or JSON (`jsonlog`), into time, level, target
+//! and message, keeps those at a level or worse, mentioning a portal or
+//! newer than a point in time, and with `--follow` goes on with the ones
+//! logged after. Lines that are not log lines (a panic's backtrace, say)
+//! are skipped.
+
+use crate::config::{LogRotation, LoggingConfig};
+use crate::state::unix_now;
+use crate::utils::utc_offset;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::AsyncBufReadExt;
+use tracing::Level;
+
+/// The systemd unit `install.sh` and `wimesh service` install
+pub const JOURNAL_UNIT: &str = "wimesh";
+
+/// How often `follow` looks at a log file for new lines
+const FOLLOW_POLL: Duration = Duration::from_millis(500);
+
+/// Open `[logging] log_file` for appending, creating its directory; `None`
+/// when it is not set
+pub fn open(cfg: &LoggingConfig) -> Result<Option<LogFile>> {
+    if cfg.log_file.is_empty() {
+        return Ok(None);
+    }
+    let path = Path::new(&cfg.log_file);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let log = LogFile {
+        path: path.to_path_buf(),
+        rotation: cfg.rotation,
+        max_bytes: cfg.max_size_kb * 1024,
+        keep: cfg.keep,
+        // Once, not per line: only daily rotation needs it
+        utc_offset: match cfg.rotation {
+            LogRotation::Daily => utc_offset(),
+            _ => 0,
+        },
+    };
+    log.append()
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(Some(log))
+}
+
+/// The log file, opened for every line like the event log, so the runs
+/// logging to it at once (the daemon, a `wimesh login`) all see it rotated;
+/// the rotated files are `wimesh.log.1` (the newest), `.2`, ...
+pub struct LogFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_bytes: u64,
+    keep: u32,
+    /// Where days start, for daily rotation; as when the file was opened
+    utc_offset: i64,
+}
+
+impl LogFile {
+    /// The file to append the next line to, moving the current one aside
+    /// first when it is due
+    fn append(&self) -> std::io::Result<File> {
+        let due = std::fs::metadata(&self.path)
+            .map(|metadata| self.due(&metadata, unix_now()))
+            .unwrap_or(false);
+        if due {
+            // Going on with the old file beats losing the line
+            let _ = crate::events::rotate(&self.path, self.keep);
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+
+    /// Whether the file described by `metadata` is to be rotated at `now`
+    fn due(&self, metadata: &Metadata, now: u64) -> bool {
+        match self.rotation {
+            LogRotation::Never => false,
+            LogRotation::Size => self.max_bytes > 0 && metadata.len() >= self.max_bytes,
+            LogRotation::Daily => {
+                let day = |secs: u64| (secs as i64 + self.utc_offset).div_euclid(86400);
+                let written = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+                metadata.len() > 0 && written.is_some_and(|at| day(at.as_secs()) < day(now))
+            }
+        }
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // A whole line at a time, so concurrent appenders do not interleave
+        self.append()?.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `path` and its rotated files, oldest first
+fn with_rotated(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    for n in 1.. {
+        let old = crate::events::rotated(path, n);
+        if !old.exists() {
+            break;
+        }
+        files.push(old);
+    }
+    files.reverse();
+    files
+}
+
+/// Where the daemon's log is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogSource {
+    File(PathBuf),
+    /// `journalctl -u wimesh`
+    Journal,
+}
+
+impl LogSource {
+    /// `file`, else `[logging] log_file`, else the journal where there is
+    /// journalctl, else the log of a `--background` run
+    pub fn find(file: Option<&Path>, log_file: &str) -> Result<Self> {
+        if let Some(file) = file {
+            return Ok(Self::File(file.to_path_buf()));
+        }
+        if !log_file.is_empty() {
+            return Ok(Self::File(PathBuf::from(log_file)));
+        }
+        if crate::utils::find_in_path("journalctl").is_some() {
+            return Ok(Self::Journal);
+        }
+        if let Some(path) = crate::background::default_log() {
+            return Ok(Self::File(path));
+        }
+        anyhow::bail!(
+            "No log to read: set [logging] log_file for the daemon to write one, or pass --file"
+        )
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::File(path) => path.display().to_string(),
+            Self::Journal => format!("the journal of {}", JOURNAL_UNIT),
+        }
+    }
+}
+
+/// One line of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogLine {
+    /// Unix time
+    pub ts: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+impl LogLine {
+    /// A line as the log output writes it,
+    /// `2024-05-01T12:34:56.789012Z  INFO wimesh::daemon: Login successful`,
+    /// colored or not, or as a JSON object
+    pub fn parse(line: &str) -> Option<Self> {
+        if line.trim_start().starts_with('{') {
+            return Self::parse_json(line);
+        }
+        let line = strip_ansi(line);
+        let (time, rest) = line.trim().split_once(' ')?;
+        let ts = parse_timestamp(time)?;
+        let (level, rest) = rest.trim_start().split_once(' ')?;
+        level.parse::<Level>().ok()?;
+        let (target, message) = rest.split_once(": ").unwrap_or(("", rest));
+        Some(Self {
+            ts,
+            level: level.to_string(),
+            target: target.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    fn parse_json(line: &str) -> Option<Self> {
+        let line: serde_json::Value = serde_json::from_str(line).ok()?;
+        let text = |key: &str| line.get(key).and_then(|value| value.as_str());
+        let level = text("level")?;
+        level.parse::<Level>().ok()?;
+        Some(Self {
+            ts: parse_timestamp(text("ts")?)?,
+            level: level.to_string(),
+            target: text("target").unwrap_or_default().to_string(),
+            message: text("message").unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Local time, level and message
+    pub fn describe(&self, utc_offset: i64) -> String {
+        let local = self.ts as i64 + utc_offset;
+        let (year, month, day) = crate::har::civil_from_days(local.div_euclid(86400));
+        let secs = local.rem_euclid(86400);
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {:>5}  {}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            self.level,
+            self.message
+        )
+    }
+}
+
+/// The log lines in `text`
+pub fn parse(text: &str) -> Vec<LogLine> {
+    text.lines().filter_map(LogLine::parse).collect()
+}
+
+/// Which lines `wimesh logs` shows
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    /// This level or worse
+    pub level: Option<Level>,
+    /// Any of these in the message, whatever the case: a portal's name and
+    /// SSIDs, say
+    pub mentions: Vec<String>,
+    /// Unix time of the oldest line
+    pub since: Option<u64>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, line: &LogLine) -> bool {
+        let level = match (self.level, line.level.parse::<Level>()) {
+            // More verbose levels compare greater
+            (Some(worst), Ok(level)) => level <= worst,
+            _ => true,
+        };
+        let message = line.message.to_lowercase();
+        let mentions = self.mentions.is_empty()
+            || self
+                .mentions
+                .iter()
+                .any(|m| message.contains(&m.to_lowercase()));
+        level && mentions && self.since.is_none_or(|since| line.ts >= since)
+    }
+}
+
+/// The last `limit` lines of `source` kept by `filter`
+pub fn read(source: &LogSource, filter: &LogFilter, limit: usize) -> Result<Vec<LogLine>> {
+    let text = match source {
+        LogSource::File(path) => {
+            let mut text = String::new();
+            for file in with_rotated(path) {
+                let bytes = std::fs::read(&file)
+                    .with_context(|| format!("Failed to read {}", file.display()))?;
+                text.push_str(&String::from_utf8_lossy(&bytes));
+            }
+            text
+        }
+        LogSource::Journal => {
+            let mut args = journal_args(filter.since);
+            args.push("--no-pager".to_string());
+            let output = std::process::Command::new("journalctl")
+                .args(&args)
+                .output()
+                .context("Failed to run journalctl")?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "journalctl failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+    };
+    let mut lines: Vec<LogLine> = parse(&text)
+        .into_iter()
+        .filter(|line| filter.matches(line))
+        .collect();
+    lines.drain(..lines.len().saturating_sub(limit));
+    Ok(lines)
+}
+
+/// Call `each` with the lines logged to `source` from now on that `filter`
+/// keeps; returns only on failure, or when journalctl exits
+pub async fn follow(
+    source: &LogSource,
+    filter: &LogFilter,
+    mut each: impl FnMut(&LogLine),
+) -> Result<()> {
+    let mut emit = |text: &str| {
+        parse(text)
+            .iter()
+            .filter(|line| filter.matches(line))
+            .for_each(&mut each)
+    };
+    match source {
+        LogSource::File(path) => {
+            let mut tail = FileTail::new(path)?;
+            loop {
+                tokio::time::sleep(FOLLOW_POLL).await;
+                emit(&tail.read_new()?);
+            }
+        }
+        LogSource::Journal => {
+            let mut args = journal_args(None);
+            args.extend(["--follow", "--lines", "0"].map(String::from));
+            let mut child = tokio::process::Command::new("journalctl")
+                .args(&args)
+                .stdout(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .context("Failed to run journalctl")?;
+            let stdout = child.stdout.take().context("No output from journalctl")?;
+            let mut lines = tokio::io::BufReader::new(stdout).lines();
+            while let Some(line) = lines.next_line().await? {
+                emit(&line);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The daemon's messages as written, from `since` (Unix time) on
+fn journal_args(since: Option<u64>) -> Vec<String> {
+    let mut args = ["--unit", JOURNAL_UNIT, "--output", "cat"]
+        .map(String::from)
+        .to_vec();
+    if let Some(since) = since {
+        args.extend(["--since".to_string(), format!("@{}", since)]);
+    }
+    args
+}
+
+/// What was appended to a log file since the last look, whole lines only
+struct FileTail {
+    path: PathBuf,
+    offset: u64,
+    partial: String,
+}
+
+impl FileTail {
+    /// Starting at the current end of `path`
+    fn new(path: &Path) -> Result<Self> {
+        let offset = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            offset,
+            partial: String::new(),
+        })
+    }
+
+    fn read_new(&mut self) -> Result<String> {
+        let Ok(mut file) = File::open(&self.path) else {
+            // Rotated away, and not created again yet
+            return Ok(String::new());
+        };
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            // Truncated or replaced: start over
+            self.offset = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        self.offset += bytes.len() as u64;
+        self.partial.push_str(&String::from_utf8_lossy(&bytes));
+        let complete = self.partial.rfind('\n').map_or(0, |end| end + 1);
+        Ok(self.partial.drain(..complete).collect())
+    }
+}
+
+/// `--since`: a time ago (`90s`, `30m`, `2h`, `1d`) or a local date and
+/// time (`2024-05-01`, `2024-05-01 08:00`), as a Unix time
+pub fn parse_since(s: &str, now: u64, utc_offset: i64) -> Result<u64> {
+    let s = s.trim();
+    let unit = match s.chars().last() {
+        Some('s') => Some(1),
+        Some('m') => Some(60),
+        Some('h') => Some(3600),
+        Some('d') => Some(86400),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        if let Ok(n) = s[..s.len() - 1].parse::<u64>() {
+            return Ok(now.saturating_sub(n.saturating_mul(unit)));
+        }
+    }
+    let (date, time) = s.split_once([' ', 'T']).unwrap_or((s, "00:00"));
+    let local = days_from_civil(date)
+        .zip(seconds_of_day(time))
+        .map(|(days, secs)| days * 86400 + secs)
+        .with_context(|| {
+            format!(
+                "'{}' is neither a time ago like 30m or 2h nor a date like 2024-05-01 08:00",
+                s
+            )
+        })?;
+    Ok((local - utc_offset).max(0) as u64)
+}
+
+/// `2024-05-01T12:34:56.789012Z` as a Unix time
+fn parse_timestamp(s: &str) -> Option<u64> {
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+    let secs = days_from_civil(date)? * 86400 + seconds_of_day(time)?;
+    u64::try_from(secs).ok()
+}
+
+/// Days since 1970-01-01 of `YYYY-MM-DD`, proleptic Gregorian
+fn days_from_civil(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// `HH:MM`, `HH:MM:SS` or `HH:MM:SS.ffffff` as seconds after midnight
+fn seconds_of_day(time: &str) -> Option<i64> {
+    let mut parts = time.split(':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: i64 = match parts.next() {
+        Some(seconds) => seconds.split('.').next()?.parse().ok()?,
+        None => 0,
+    };
+    (parts.next().is_none() && hours < 24 && minutes < 60 && seconds < 61)
+        .then_some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// `line` without the color codes of a terminal log
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI: ESC [ parameters, then a final byte in @..~
+            if chars.next() == Some('[') {
+                chars.by_ref().find(|c| ('@'..='~').contains(c));
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_filter() {
+        let text = concat!(
+            "2024-05-01T12:34:56.789012Z  INFO wimesh::daemon: Login successful via 'KTX Khu B'\n",
+            "\x1b[2m2024-05-01T12:40:00.000001Z\x1b[0m \x1b[33m WARN\x1b[0m ",
+            "\x1b[2mwimesh::daemon\x1b[0m\x1b[2m:\x1b[0m ",
+            "No internet on '1.Free Wi-MESH' (wlan0), attempting login...\n",
+            "thread 'main' panicked at src/main.rs:1:1\n",
+            "2024-05-01T13:00:00Z DEBUG wimesh::probe: Probe unreachable\n",
+        );
+        let lines = parse(text);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].ts, 1_714_566_896);
+        assert_eq!(lines[0].level, "INFO");
+        assert_eq!(lines[0].target, "wimesh::daemon");
+        assert_eq!(lines[0].message, "Login successful via 'KTX Khu B'");
+        assert_eq!(lines[1].level, "WARN");
+        assert!(lines[1]
+            .message
+            .starts_with("No internet on '1.Free Wi-MESH'"));
+        assert_eq!(
+            lines[0].describe(7 * 3600),
+            "2024-05-01 19:34:56  INFO  Login successful via 'KTX Khu B'"
+        );
+        let json = LogLine::parse(concat!(
+            r#"{"ts":"2024-05-01T12:34:56.789012Z","level":"INFO","#,
+            r#""target":"wimesh::daemon","message":"Login successful via 'KTX Khu B'","#,
+            r#""portal":"KTX Khu B"}"#
+        ));
+        assert_eq!(json.as_ref(), Some(&lines[0]));
+
+        let kept = |filter: &LogFilter| lines.iter().filter(|l| filter.matches(l)).count();
+        let info = LogFilter {
+            level: Some(Level::INFO),
+            ..Default::default()
+        };
+        assert_eq!(kept(&info), 2);
+        let portal = LogFilter {
+            mentions: vec!["ktx khu b".into(), "1.Free Wi-MESH".into()],
+            ..Default::default()
+        };
+        assert_eq!(kept(&portal), 2);
+        let recent = LogFilter {
+            since: Some(1_714_567_000),
+            ..Default::default()
+        };
+        assert_eq!(kept(&recent), 2);
+
+        let now = 1_714_566_896;
+        assert_eq!(parse_since("30m", now, 0).unwrap(), now - 1800);
+        assert_eq!(parse_since("1d", now, 0).unwrap(), now - 86400);
+        assert_eq!(parse_since("2024-05-01", now, 0).unwrap(), 1_714_521_600);
+        assert_eq!(
+            parse_since("2024-05-01 08:00", now, 3600).unwrap(),
+            1_714_546_800
+        );
+        assert!(parse_since("yesterday", now, 0).is_err());
+        assert_eq!(days_from_civil("1970-01-01"), Some(0));
+    }
+
+    #[test]
+    fn test_log_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("wimesh-logs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = LoggingConfig {
+            log_file: dir.join("wimesh.log").display().to_string(),
+            rotation: LogRotation::Size,
+            max_size_kb: 1,
+            keep: 2,
+            ..Default::default()
+        };
+        let mut log = open(&cfg).unwrap().unwrap();
+        let line = |n: usize| format!("{} {}\n", n, "x".repeat(1024));
+        for n in 1..=4 {
+            log.write_all(line(n).as_bytes()).unwrap();
+        }
+        // The first line went with the third rotation
+        let files = with_rotated(&log.path);
+        assert_eq!(files.len(), 3);
+        let read = |file: &Path| std::fs::read_to_string(file).unwrap();
+        assert_eq!(read(&files[0]), line(2));
+        assert_eq!(read(&files[2]), line(4));
+
+        log.rotation = LogRotation::Daily;
+        let metadata = std::fs::metadata(&log.path).unwrap();
+        let written = metadata.modified().unwrap();
+        let written = written.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        log.utc_offset = 0;
+        assert!(!log.due(&metadata, written));
+        assert!(log.due(&metadata, written + 86400));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -2,201 +2,724 @@
 //!
 //! Supports multiple captive portal types through a trait-based plugin system.
 
-mod config;
-mod http;
-mod models;
-mod parser;
-mod portal;
-mod utils;
+// The windowless build never opens a console; see `wimesh::background`
+#![cfg_attr(all(windows, feature = "windowless"), windows_subsystem = "windows")]
+
+mod cmd;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use portal::{AwingPortal, CaptivePortal, PortalRegistry};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use wimesh::background;
+use wimesh::control::Request;
+use wimesh::dedup::Dedup;
+use wimesh::events::EventLog;
+use wimesh::identity::IdentityManager;
+use wimesh::jsonlog::{JsonFields, JsonFormat};
+use wimesh::lock::LoginLocks;
+use wimesh::logs::LogSource;
+use wimesh::portal::PortalRegistry;
+use wimesh::progress::{self, Progress};
+use wimesh::remote::Remote;
+use wimesh::config::LogFormat;
+use wimesh::{completions, config};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::filter::{filter_fn, FilterExt};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(name = "wimesh")]
 #[command(about = "Captive Portal Auto Login Client", long_about = None)]
 struct Args {
-    /// Run in daemon mode (continuous monitoring)
-    #[arg(short, long)]
+    /// Config file path (default: search config.toml/.yaml/.json in ., /etc/wimesh, ...)
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Log level or tracing filter; overrides RUST_LOG and the config
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Result format; logs always go to stderr
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Print the effective configuration (defaults, file and overrides
+    /// merged, secrets masked) as TOML, and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Let go of the console (Windows) and log only to [logging] log_file,
+    /// by default wimesh.log in the state directory
+    #[arg(long, global = true)]
+    background: bool,
+
+    /// Same as the `daemon` command, for units written before it existed
+    #[arg(short, long, hide = true)]
     daemon: bool,
 
-    /// Config file path (default: config.toml)
-    #[arg(short, long)]
-    config: Option<String>,
+    /// What to do (default: login)
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human-readable log lines only
+    Text,
+    /// A single JSON object on stdout
+    Json,
+}
 
-    // Load configuration
-    let cfg = config::Config::load()?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Keep the connection alive, logging in whenever the portal holds traffic
+    Daemon {
+        /// Run a few checks and exit, for cron on systems without a service
+        /// manager: a plan missed while nothing ran is caught up first
+        #[arg(long)]
+        oneshot_batch: bool,
 
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| EnvFilter::new(&cfg.logging.level)),
-        )
-        .init();
+        /// Most checks one --oneshot-batch run makes
+        #[arg(long, default_value_t = 3, requires = "oneshot_batch")]
+        cycles: u32,
+    },
+
+    /// Log in once on the connected network
+    Login {
+        /// Log in through this portal, whatever SSID is reported
+        #[arg(long, value_name = "NAME")]
+        portal: Option<String>,
+    },
+
+    /// Show the network state, last login and backoff
+    Status,
+
+    /// End the portal session on the connected network
+    Logout,
+
+    /// Present a new MAC on the connected network, reconnect and log in
+    /// again, for sessions limited per MAC
+    RotateMac {
+        /// Network to rotate on (default: the connected configured one)
+        #[arg(long)]
+        ssid: Option<String>,
+    },
+
+    /// Check the config file for mistakes, with the line of each
+    #[command(alias = "validate-config")]
+    Validate,
+
+    /// Write a first config file, asking for the portal, SSID and MAC: to
+    /// ~/.config/wimesh/config.toml, or the --config file
+    Init {
+        /// Replace a file that is there already
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check the config, tools, WiFi, gateway and internet one by one
+    Doctor,
+
+    /// Run the connectivity checks verbosely, without touching any portal
+    Probe {
+        /// Probe out of this interface (default: the one on a configured SSID)
+        #[arg(long)]
+        interface: Option<String>,
+    },
+
+    /// Explain in plain words why the network is not working, with evidence
+    Why,
+
+    /// Show recent state changes, logins and probes from the event log
+    History {
+        /// Number of events to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Only events about this SSID
+        #[arg(long)]
+        ssid: Option<String>,
+    },
+
+    /// Show the daemon's log, from [logging] log_file or the journal
+    Logs {
+        /// Number of lines to show
+        #[arg(short = 'n', long, default_value_t = 50)]
+        limit: usize,
+
+        /// Only lines at this level or worse: error, warn, info, debug
+        #[arg(long)]
+        level: Option<tracing::Level>,
+
+        /// Only lines mentioning this portal or one of its SSIDs
+        #[arg(long)]
+        portal: Option<String>,
+
+        /// Only lines since then: 30m, 2h, 1d, or a local time like "2024-05-01 08:00"
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Keep printing lines as they are logged
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Log file to read instead of [logging] log_file or the journal
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Run a portal's parsers against a saved page or the live portal
+    TestPortal {
+        /// Portal name as configured in config.toml
+        portal: String,
+
+        /// Saved gateway page, authentication form, or portal API response
+        #[arg(required_unless_present = "live")]
+        file: Option<PathBuf>,
+
+        /// Fetch the pages from the live portal instead of a file
+        #[arg(long, conflicts_with = "file")]
+        live: bool,
+    },
+
+    /// Print a status line for Waybar/Polybar
+    Widget {
+        /// Print Waybar custom-module JSON instead of plain text
+        #[arg(long)]
+        json: bool,
+
+        /// Print once and exit instead of updating continuously
+        #[arg(long)]
+        once: bool,
+
+        /// Seconds between updates, at least 1
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+
+    /// Run a portal's login flow repeatedly and report where time goes
+    Bench {
+        /// Portal name as configured in config.toml
+        #[arg(long)]
+        portal: String,
+
+        /// Number of complete login flows to run
+        #[arg(long, default_value_t = 10)]
+        iterations: usize,
+
+        /// Log in to the real venue instead of a local mock
+        #[arg(long)]
+        live: bool,
+
+        /// Skip the confirmation prompt of --live
+        #[arg(long, requires = "live")]
+        yes: bool,
+    },
+
+    /// Run the daemon's checks against a local mock venue that injects faults
+    Stress {
+        /// Awing portal as configured in config.toml
+        #[arg(long)]
+        portal: String,
+
+        /// How long to keep it up, in seconds
+        #[arg(long, default_value_t = 300)]
+        duration: u64,
+
+        /// How often each fault hits: 500s, slow answers, cut-off bodies,
+        /// forgotten cookies and dropped sessions (0 to 1)
+        #[arg(long, default_value_t = 0.1)]
+        fault_rate: f64,
+
+        /// Seed of the faults, to replay a run (default: random)
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+
+    /// Log in another machine, using its network through an SSH tunnel
+    Remote {
+        /// Machine to log in, as ssh takes it (user@host or a ~/.ssh/config alias)
+        #[arg(long)]
+        host: String,
+    },
+
+    /// Log in a TV, console or other device that cannot show the portal
+    AuthorizeDevice {
+        /// The device's MAC address (default: pick from the devices seen on
+        /// the WiFi)
+        #[arg(long)]
+        mac: Option<String>,
+
+        /// Portal to log it in to (default: the one of the connected SSID)
+        #[arg(long)]
+        portal: Option<String>,
+
+        /// Serve a page to pick the device on from a phone, and print a QR
+        /// code of its address
+        #[arg(long)]
+        qr: bool,
+
+        /// Address the --qr page listens on
+        #[arg(long, default_value = "0.0.0.0:8787", requires = "qr")]
+        listen: String,
+
+        /// Minutes the --qr page stays up
+        #[arg(long, default_value_t = 5, requires = "qr")]
+        minutes: u64,
+    },
+
+    /// Add the connected SSID (or the given one) to a portal in the config
+    Adopt {
+        /// Portal to add it to (default: the one whose SSID it resembles)
+        #[arg(long)]
+        portal: Option<String>,
+
+        /// SSID to add instead of the connected one
+        ssid: Option<String>,
+    },
+
+    /// Maintain the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
 
-    tracing::info!("Wimesh v0.2.0 - Captive Portal Auto Login");
-    tracing::info!("==========================================");
+    /// Share portal definitions as files, without secrets
+    Portal {
+        #[command(subcommand)]
+        action: PortalAction,
+    },
 
-    // Build portal registry from config
-    let mut registry = build_portal_registry(&cfg)?;
+    /// Check the audit log of logins performed
+    Audit {
+        #[command(subcommand)]
+        action: AuditAction,
+    },
 
-    if args.daemon {
-        run_daemon(cfg, registry).await
+    /// Keep portal credentials in the OS keyring, for `{ keyring = "NAME" }`
+    Secret {
+        #[command(subcommand)]
+        action: SecretAction,
+    },
+
+    /// Clear the daemon's login failure backoff
+    ResetBackoff {
+        /// Only clear the backoff of this SSID
+        ssid: Option<String>,
+    },
+
+    /// Ask the running daemon to do something now, through its control socket
+    Control {
+        #[arg(value_enum)]
+        action: ControlAction,
+    },
+
+    /// Show the running daemon's status in the system tray, with Login now,
+    /// Pause monitoring and Open logs (feature `tray`)
+    Tray,
+
+    /// Stop or resume the daemon's logins, keeping its checks and reports
+    ReadOnly {
+        #[arg(value_enum)]
+        mode: ReadOnlyMode,
+    },
+
+    /// Show which portals, backends and features this build has and can use here
+    Capabilities,
+
+    /// Print a hardened service definition for this build and config, or
+    /// on Windows install, uninstall, start or stop the service
+    Service {
+        #[arg(value_enum, default_value_t = default_service_action())]
+        action: ServiceAction,
+    },
+
+    /// Write a systemd unit running the daemon with this binary and config,
+    /// and enable it; on macOS, write and load a LaunchAgent (always a user's)
+    InstallService {
+        /// A user service (~/.config/systemd/user) instead of a system one
+        #[arg(long)]
+        user: bool,
+    },
+
+    /// Print the shell completion script for bash, zsh, fish or PowerShell
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditAction {
+    /// Check that no entry was edited, removed or reordered
+    Verify {
+        /// Audit file to check instead of the configured one
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SecretAction {
+    /// Store a secret, read from stdin (prompted for on a terminal)
+    Set {
+        /// Entry name, as in `password = { keyring = "NAME" }`
+        name: String,
+    },
+    /// Remove a stored secret
+    Delete { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Rewrite the config file in the current format, keeping a backup
+    Migrate {
+        /// Print the migrated config instead of writing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PortalAction {
+    /// Print a portal's entry as a file others can import
+    Export {
+        /// Name of the portal
+        name: String,
+    },
+    /// Add the portals of an exported file to the config file
+    Import {
+        /// File written by `wimesh portal export`
+        file: PathBuf,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ControlAction {
+    /// What the daemon sees right now
+    Status,
+    /// Clear the backoff and check (and log in) at once
+    LoginNow,
+    /// Stop checking until resumed
+    Pause,
+    /// Check again, starting now
+    Resume,
+    /// Read the config file again
+    Reload,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadOnlyMode {
+    /// Detect and report only
+    On,
+    /// Log in again
+    Off,
+    /// Follow `global.read_only` in the config
+    Config,
+}
+
+fn default_service_action() -> ServiceAction {
+    if cfg!(target_os = "freebsd") {
+        ServiceAction::Rcd
+    } else if cfg!(target_os = "macos") {
+        ServiceAction::Launchd
     } else {
-        run_once(&mut registry).await
+        ServiceAction::Systemd
     }
 }
 
-/// Build a portal registry from configuration
-fn build_portal_registry(cfg: &config::Config) -> Result<PortalRegistry> {
-    let mut registry = PortalRegistry::new();
-
-    for portal_cfg in &cfg.portals {
-        match portal_cfg.portal_type.as_str() {
-            "awing" => {
-                let awing_config = portal::awing::AwingConfig {
-                    name: portal_cfg.name.clone(),
-                    ssids: portal_cfg.ssids.clone(),
-                    mac_address: portal_cfg.mac_address.clone(),
-                };
-                let portal = AwingPortal::new(awing_config)?;
-                registry.register(Box::new(portal));
-            }
-            unknown => {
-                tracing::warn!("Unknown portal type '{}', skipping: {}", unknown, portal_cfg.name);
-            }
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ServiceAction {
+    /// System unit for /etc/systemd/system
+    Systemd,
+    /// NixOS module providing services.wimesh
+    Nixos,
+    /// Home Manager module running a user service
+    HomeManager,
+    /// FreeBSD rc.d script for /usr/local/etc/rc.d
+    Rcd,
+    /// macOS LaunchAgent for ~/Library/LaunchAgents
+    Launchd,
+    /// Windows: register the service, started at boot, with this binary and
+    /// config
+    Install,
+    /// Windows: stop and remove the service
+    Uninstall,
+    /// Windows: start the service
+    Start,
+    /// Windows: stop the service
+    Stop,
+    /// What the Windows service runs: the daemon, for the service manager
+    #[value(hide = true)]
+    Run,
+}
+
+// The embedded build runs everything on the main thread
+#[cfg_attr(feature = "embedded", tokio::main(flavor = "current_thread"))]
+#[cfg_attr(not(feature = "embedded"), tokio::main)]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Load configuration; `validate` says where a file that does not load
+    // goes wrong
+    let mut cfg = match config::Config::load_from(args.config.as_deref()) {
+        Ok(cfg) => cfg,
+        Err(e) if matches!(args.command, Some(Command::Validate)) => {
+            return cmd::config::validate_unloadable(args.config.as_deref(), &e, args.output);
         }
+        // The file `init` is about to write need not load, or be there, and
+        // completions need no config at all
+        Err(_)
+            if matches!(
+                args.command,
+                Some(Command::Init { .. } | Command::Completions { .. })
+            ) =>
+        {
+            config::Config::default()
+        }
+        Err(e) => return Err(e),
+    };
+
+    let command = match args.command {
+        Some(command) => command,
+        None if args.daemon => Command::Daemon {
+            oneshot_batch: false,
+            cycles: 0,
+        },
+        None => Command::Login { portal: None },
+    };
+
+    // Initialize logging; the progress display of an interactive login
+    // stands in for the info lines
+    let level = match interactive(&command, args.output) {
+        true => "warn",
+        false => &cfg.logging.level,
+    };
+    let filter = match args.log_level {
+        Some(ref level) => EnvFilter::try_new(level)
+            .with_context(|| format!("Invalid log level '{}'", level))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level)),
+    };
+
+    if args.print_config {
+        let log_level = args.log_level.as_deref();
+        return cmd::config::print_config(&mut cfg, args.config.as_deref(), log_level);
     }
+    // In the background the log file is all the output there is
+    let background = args.background || background::WINDOWLESS;
+    if background {
+        if let Some(path) = background::log_file(&cfg.logging.log_file) {
+            cfg.logging.log_file = path.display().to_string();
+        }
+        background::detach();
+    }
+    let (log_file, log_file_error) = match wimesh::logs::open(&cfg.logging) {
+        Ok(file) => (file, None),
+        Err(e) => (None, Some(e)),
+    };
+    // Colours only for a terminal, not a journal or a launchd log file; the
+    // terminal goes quiet only for a log file that could be opened
+    let format = cfg.logging.format;
+    let console = !background && (cfg.logging.console || log_file.is_none());
+    let output = console
+        .then(|| log_output(format, std::io::stderr().is_terminal(), progress::stderr));
+    let file_output =
+        log_file.map(|file| log_output(format, false, std::sync::Mutex::new(file)));
+    let dedup_window = Duration::from_secs(cfg.logging.dedup_window);
+    // Text lines would show the spans they are in, which are for the traces;
+    // JSON lines take some of their fields
+    let spans = format == LogFormat::Json;
+    let log = Dedup::new(Layer::and_then(output, file_output), dedup_window)
+        .with_filter(filter.and(filter_fn(move |metadata| spans || metadata.is_event())));
+    tracing_subscriber::registry()
+        .with(log)
+        .with(wimesh::telemetry::layer(&cfg.telemetry)?)
+        .init();
+    if let Some(e) = log_file_error {
+        tracing::warn!("Not writing the log file: {:#}", e);
+    }
+    wimesh::store::select(cfg.storage.backend)?;
+    wimesh::dns::configure(&cfg.dns);
+    wimesh::probe::configure(&cfg.connectivity);
+    wimesh::capport::configure(&cfg.capport);
 
-    if registry.all_ssids().is_empty() {
-        tracing::warn!("No portals configured! Add portal configurations to config.toml");
+    let result = run_command(command, cfg, args.config.as_deref(), args.output).await;
+    wimesh::telemetry::flush().await;
+    result
+}
+
+/// Log lines to `writer` in `format`, coloured if `ansi`
+fn log_output<S, W>(format: LogFormat, ansi: bool, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Json => layer
+            .with_ansi(false)
+            .event_format(JsonFormat)
+            .fmt_fields(JsonFields)
+            .boxed(),
     }
+}
 
-    Ok(registry)
+/// Whether `command` shows step-by-step progress: a login with text output,
+/// run on a terminal
+fn interactive(command: &Command, output: OutputFormat) -> bool {
+    matches!(command, Command::Login { .. })
+        && output == OutputFormat::Text
+        && std::io::stdout().is_terminal()
+        && std::io::stderr().is_terminal()
 }
 
-/// Run once - try to connect using the first available portal
-async fn run_once(registry: &mut PortalRegistry) -> Result<()> {
-    // Check current WiFi and find matching portal
-    let all_ssids: Vec<String> = registry.all_ssids().iter().map(|s| s.to_string()).collect();
-    
-    match utils::is_connected_to_wifi(&all_ssids) {
-        Ok(Some(connected_ssid)) => {
-            tracing::info!("Connected to: {}", connected_ssid);
-            
-            if let Some(portal) = registry.find_for_ssid(&connected_ssid) {
-                tracing::info!("Using portal: {}", portal.name());
-                match portal.connect().await {
-                    Ok(_) => {
-                        tracing::info!("Connection established!");
-                        Ok(())
-                    }
-                    Err(e) => {
-                        tracing::error!("Connection failed: {:#}", e);
-                        Err(e)
-                    }
-                }
-            } else {
-                anyhow::bail!("No portal configured for SSID: {}", connected_ssid)
-            }
+/// Dispatch one subcommand; `config_path` is the `--config` given, if any
+async fn run_command(
+    command: Command,
+    cfg: config::Config,
+    config_path: Option<&Path>,
+    output: OutputFormat,
+) -> Result<()> {
+    match command {
+        Command::Daemon {
+            oneshot_batch,
+            cycles,
+        } => {
+            cmd::banner();
+            let identities = IdentityManager::load();
+            cmd::daemon::apply_wifi_identities(&cfg, &identities);
+            let registry = PortalRegistry::from_config(&cfg, &identities)?;
+            let events = EventLog::new(&cfg.events);
+            let batch = oneshot_batch.then_some(cycles.max(1));
+            let locks = LoginLocks::new();
+            cmd::daemon::run_daemon(cfg, config_path, registry, &locks, &events, batch).await
         }
-        Ok(None) => {
-            tracing::warn!("Not connected to any configured WiFi network");
-            tracing::info!("Configured SSIDs: {}", all_ssids.join(", "));
-            Ok(())
+        Command::Login { ref portal } => {
+            let progress = interactive(&command, output).then(Progress::start);
+            cmd::banner();
+            let mut registry = PortalRegistry::from_config(&cfg, &IdentityManager::load())?;
+            let events = EventLog::new(&cfg.events);
+            let locks = LoginLocks::new();
+            let (portal, progress) = (portal.as_deref(), progress.as_deref());
+            cmd::login::run_once(&cfg, &mut registry, &locks, &events, portal, output, progress)
+                .await
         }
-        Err(e) => {
-            tracing::error!("Failed to check WiFi status: {}", e);
-            Err(e)
+        Command::Status => cmd::status::status(&cfg, output).await,
+        Command::Logout => {
+            let mut registry = PortalRegistry::from_config(&cfg, &IdentityManager::load())?;
+            cmd::login::logout(&mut registry).await
         }
-    }
-}
-
-/// Run in daemon mode - continuous monitoring
-async fn run_daemon(cfg: config::Config, mut registry: PortalRegistry) -> Result<()> {
-    let all_ssids: Vec<String> = registry.all_ssids().iter().map(|s| s.to_string()).collect();
-    
-    tracing::info!("Starting daemon mode...");
-    tracing::info!("Monitoring SSIDs: {}", all_ssids.join(", "));
-    tracing::info!("Check interval: {}s", cfg.global.check_interval);
-    tracing::info!("---");
-
-    let check_interval = std::time::Duration::from_secs(cfg.global.check_interval);
-    let mut last_check = std::time::Instant::now();
-    let mut consecutive_failures = 0;
-    const MAX_CONSECUTIVE_FAILURES: u32 = 3;
-
-    loop {
-        // Rate limiting
-        let elapsed = last_check.elapsed();
-        if elapsed < check_interval {
-            tokio::time::sleep(check_interval - elapsed).await;
+        Command::RotateMac { ssid } => cmd::login::rotate_mac(&cfg, ssid.as_deref(), output).await,
+        Command::Validate => cmd::config::validate(&cfg, config_path, output),
+        Command::Init { force } => cmd::config::init(config_path, force).await,
+        Command::Doctor => cmd::doctor::doctor(&cfg, output).await,
+        Command::Probe { interface } => cmd::doctor::probe(&cfg, interface, output).await,
+        Command::Why => cmd::status::why(&cfg, output).await,
+        Command::History { limit, ssid } => {
+            cmd::history::history(&cfg, limit, ssid.as_deref(), output)
+        }
+        Command::Logs {
+            limit,
+            level,
+            portal,
+            since,
+            follow,
+            file,
+        } => {
+            let (portal, since) = (portal.as_deref(), since.as_deref());
+            let filter = cmd::history::log_filter(&cfg, level, portal, since)?;
+            let source = LogSource::find(file.as_deref(), &cfg.logging.log_file)?;
+            cmd::history::logs(&source, &filter, limit, follow, output).await
+        }
+        Command::Audit {
+            action: AuditAction::Verify { file },
+        } => cmd::history::verify_audit(&cfg, file.as_deref(), output),
+        Command::TestPortal { portal, file, .. } => {
+            let mut registry = PortalRegistry::from_config(&cfg, &IdentityManager::load())?;
+            cmd::portal::test_portal(&mut registry, &portal, file.as_deref()).await
         }
-        last_check = std::time::Instant::now();
-
-        // Check if connected to any configured WiFi
-        match utils::is_connected_to_wifi(&all_ssids) {
-            Ok(Some(connected_ssid)) => {
-                // Check internet connectivity
-                if !utils::has_internet_connectivity() {
-                    tracing::warn!(
-                        "No internet on '{}', attempting login...",
-                        connected_ssid
-                    );
-
-                    // Find the portal for this SSID
-                    if let Some(portal) = registry.find_for_ssid(&connected_ssid) {
-                        match portal.connect().await {
-                            Ok(_) => {
-                                tracing::info!("Login successful via '{}'", portal.name());
-                                consecutive_failures = 0;
-
-                                // Wait for connection to stabilize
-                                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                            }
-                            Err(e) => {
-                                consecutive_failures += 1;
-                                tracing::error!(
-                                    "Login failed via '{}' (attempt {}/{}): {:#}",
-                                    portal.name(),
-                                    consecutive_failures,
-                                    MAX_CONSECUTIVE_FAILURES,
-                                    e
-                                );
-
-                                if consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
-                                    tracing::error!("Too many failures, backing off...");
-                                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                                    consecutive_failures = 0;
-                                }
-                            }
-                        }
-                    } else {
-                        tracing::warn!("No portal configured for SSID: {}", connected_ssid);
-                    }
-                } else {
-                    // Internet is working
-                    if consecutive_failures > 0 {
-                        tracing::debug!("Internet restored on '{}'", connected_ssid);
-                        consecutive_failures = 0;
-                    }
-                }
-            }
-            Ok(None) => {
-                tracing::debug!("Not connected to any configured WiFi");
-                consecutive_failures = 0;
-            }
-            Err(e) => {
-                tracing::warn!("Failed to check WiFi status: {}", e);
-            }
+        Command::Widget {
+            json,
+            once,
+            interval,
+        } => cmd::status::widget(&cfg, json, once, interval).await,
+        Command::Bench {
+            portal,
+            iterations,
+            live,
+            yes,
+        } => cmd::bench::bench(&cfg, &portal, iterations, live, yes, output).await,
+        Command::Stress {
+            portal,
+            duration,
+            fault_rate,
+            seed,
+        } => cmd::bench::stress(&cfg, &portal, duration, fault_rate, seed, output).await,
+        Command::Remote { host } => {
+            let mut registry = PortalRegistry::from_config(&cfg, &IdentityManager::load())?;
+            cmd::remote::remote(&mut registry, &Remote::new(&host), output).await
+        }
+        Command::AuthorizeDevice {
+            mac,
+            portal,
+            qr,
+            listen,
+            minutes,
+        } => {
+            let phone = qr.then_some((listen.as_str(), minutes));
+            let locks = LoginLocks::new();
+            cmd::device::authorize_device(&cfg, &locks, mac, portal.as_deref(), phone).await
+        }
+        Command::Adopt { portal, ssid } => {
+            let registry = PortalRegistry::from_config(&cfg, &IdentityManager::default())?;
+            cmd::portal::adopt(&registry, config_path, portal, ssid)
+        }
+        Command::Config {
+            action: ConfigAction::Migrate { dry_run },
+        } => cmd::config::migrate_config(config_path, dry_run),
+        Command::Portal {
+            action: PortalAction::Export { name },
+        } => cmd::portal::export(&cfg, &name),
+        Command::Portal {
+            action: PortalAction::Import { file },
+        } => cmd::portal::import_portals(config_path, &file),
+        Command::Secret {
+            action: SecretAction::Set { name },
+        } => cmd::secret::set(&name),
+        Command::Secret {
+            action: SecretAction::Delete { name },
+        } => cmd::secret::delete(&name),
+        Command::ResetBackoff { ssid } => cmd::control::reset_backoff(ssid.as_deref()),
+        Command::Control { action } => {
+            let request = match action {
+                ControlAction::Status => Request::Status,
+                ControlAction::LoginNow => Request::LoginNow,
+                ControlAction::Pause => Request::Pause,
+                ControlAction::Resume => Request::Resume,
+                ControlAction::Reload => Request::Reload,
+            };
+            cmd::control::send(&cfg, request, output).await
+        }
+        Command::Tray => cmd::service::tray(&cfg, config_path).await,
+        Command::ReadOnly { mode } => {
+            let read_only = match mode {
+                ReadOnlyMode::On => Some(true),
+                ReadOnlyMode::Off => Some(false),
+                ReadOnlyMode::Config => None,
+            };
+            cmd::control::read_only(&cfg, read_only)
+        }
+        Command::Capabilities => cmd::doctor::capabilities(output),
+        Command::Service { action } => cmd::service::run(cfg, config_path, action).await,
+        Command::InstallService { user } => cmd::service::install_service(&cfg, config_path, user),
+        Command::Completions { shell } => {
+            print!("{}", completions::generate(shell, Args::command()));
+            Ok(())
         }
     }
 }
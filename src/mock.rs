@@ -0,0 +1,523 @@
+//! In-process mock of an Awing venue
+//!
+//! Serves the gateway page, the Awing API and the router login from one
+//! local port, so the complete login flow can run without a real network:
+//! for `wimesh bench` and for tests. Point an Awing portal at it with
+//! `gateway_url`/`base_url` (see `MockPortal::portal_config`).
+//!
+//! Started with `Faults`, the venue misbehaves the way real ones do under
+//! load, at random but reproducibly from a seed: 500s, slow answers, bodies
+//! cut short, forgotten session cookies and sessions dropped from under a
+//! logged-in client. It keeps track of whether the client is logged in, so
+//! it also answers the daemon's connectivity check (`wimesh stress`).
+
+use crate::config::PortalConfig;
+use crate::daemon::Connectivity;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Largest request head the mock accepts
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Cookie the `/login` handshake sets and `VerifyUrl` requires, followed
+/// by the session's generation
+const SESSION_COOKIE: &str = "ASP.NET_SessionId=mock-session";
+
+/// How often each fault hits, from 0 (never) to 1 (every time)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Faults {
+    /// A request answered with a 500
+    pub server_errors: f64,
+    /// A request answered `slow_by` late
+    pub slow: f64,
+    pub slow_by: Duration,
+    /// An answer cut off halfway through its body
+    pub truncated: f64,
+    /// Every session cookie forgotten before a request
+    pub cookie_resets: f64,
+    /// A logged-in client logged out before a connectivity check
+    pub expiry: f64,
+}
+
+impl Faults {
+    /// Every fault at `rate`
+    pub fn uniform(rate: f64, slow_by: Duration) -> Self {
+        Self {
+            server_errors: rate,
+            slow: rate,
+            slow_by,
+            truncated: rate,
+            cookie_resets: rate,
+            expiry: rate,
+        }
+    }
+}
+
+/// xorshift dice deciding when a fault hits
+struct Dice(AtomicU64);
+
+impl Dice {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves 0
+        Self(AtomicU64::new(seed | 1))
+    }
+
+    /// Whether a fault of probability `p` hits this time
+    fn roll(&self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let prev = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+            .unwrap_or_default();
+        (step(prev) % 10_000) as f64 / 10_000.0 < p
+    }
+}
+
+/// What the connections of one mock share
+struct Venue {
+    faults: Faults,
+    /// For the requests, and apart for the connectivity checks, so how many
+    /// checks a backoff lasts does not change what the requests meet
+    dice: Dice,
+    expiry_dice: Dice,
+    /// Bumped by a cookie reset, making earlier cookies unknown
+    generation: AtomicU64,
+    requests: AtomicU64,
+    logged_in: AtomicBool,
+}
+
+impl Venue {
+    fn cookie(&self) -> String {
+        format!(
+            "{}-{}",
+            SESSION_COOKIE,
+            self.generation.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// A running mock venue, shut down when dropped
+pub struct MockPortal {
+    addr: SocketAddr,
+    venue: Arc<Venue>,
+    task: JoinHandle<()>,
+}
+
+impl MockPortal {
+    /// Start serving on a random local port, answering every request after
+    /// `latency` to mimic a venue's backend
+    pub async fn start(latency: Duration) -> Result<Self> {
+        Self::start_faulty(latency, Faults::default(), 0).await
+    }
+
+    /// `start`, injecting `faults` as dice seeded with `seed` decide
+    pub async fn start_faulty(latency: Duration, faults: Faults, seed: u64) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("Failed to bind mock portal")?;
+        let addr = listener.local_addr()?;
+        let venue = Arc::new(Venue {
+            faults,
+            dice: Dice::new(seed),
+            expiry_dice: Dice::new(seed.rotate_left(32)),
+            generation: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            logged_in: AtomicBool::new(false),
+        });
+
+        let shared = venue.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let venue = shared.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, addr, latency, &venue).await {
+                        tracing::debug!("Mock portal connection failed: {:#}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, venue, task })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Requests answered so far
+    pub fn requests(&self) -> u64 {
+        self.venue.requests.load(Ordering::Relaxed)
+    }
+
+    /// Whether the router let the client in, and has not dropped it since
+    pub fn logged_in(&self) -> bool {
+        self.venue.logged_in.load(Ordering::Relaxed)
+    }
+
+    /// `portal` with its gateway and API pointed at the mock
+    pub fn portal_config(&self, portal: &PortalConfig) -> PortalConfig {
+        let mut portal = portal.clone();
+        let settings = portal
+            .extra
+            .entry(portal.portal_type.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if let Some(settings) = settings.as_table_mut() {
+            for key in ["gateway_url", "base_url"] {
+                settings.insert(key.to_string(), toml::Value::String(self.url()));
+            }
+        }
+        portal
+    }
+}
+
+/// The venue lets traffic through once the router logged the client in
+#[async_trait]
+impl Connectivity for MockPortal {
+    async fn online(&self, _interface: Option<&str>) -> bool {
+        if self.venue.expiry_dice.roll(self.venue.faults.expiry) {
+            self.venue.logged_in.store(false, Ordering::Relaxed);
+        }
+        self.logged_in()
+    }
+}
+
+impl Drop for MockPortal {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answer one request on `stream`, then close it
+async fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    latency: Duration,
+    venue: &Venue,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("Connection closed mid-request");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD {
+            anyhow::bail!("Request head too large");
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default();
+
+    // Drain the body so the client does not see a reset
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body_read = buf.len() - head_end;
+    while body_read < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body_read += n;
+    }
+
+    venue.requests.fetch_add(1, Ordering::Relaxed);
+    let faults = venue.faults;
+    if venue.dice.roll(faults.cookie_resets) {
+        venue.generation.fetch_add(1, Ordering::Relaxed);
+    }
+    let slow = if venue.dice.roll(faults.slow) {
+        faults.slow_by
+    } else {
+        Duration::ZERO
+    };
+    tokio::time::sleep(latency + slow).await;
+
+    let cookie = venue.cookie();
+    let has_session = head.lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("cookie") && value.split(';').any(|c| c.trim() == cookie)
+        })
+    });
+    let (status, content_type, body) = if venue.dice.roll(faults.server_errors) {
+        (
+            "500 Internal Server Error",
+            "text/html",
+            "Server Error".to_string(),
+        )
+    } else {
+        route(method, path, addr, has_session)
+    };
+    if path == "/hotspot/login" && status.starts_with("200") {
+        venue.logged_in.store(true, Ordering::Relaxed);
+    }
+    // Like the real handshake: a 403, with the cookie VerifyUrl needs
+    let set_cookie = if path == "/login" {
+        format!("Set-Cookie: {}; Path=/; HttpOnly\r\n", cookie)
+    } else {
+        String::new()
+    };
+    // The captive redirect a plain HTTP request meets, to the gateway's page
+    let location = if status.starts_with("302") {
+        format!("Location: http://{}/\r\n", addr)
+    } else {
+        String::new()
+    };
+    let sent = if !body.is_empty() && venue.dice.roll(faults.truncated) {
+        body.len() / 2
+    } else {
+        body.len()
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        set_cookie,
+        location
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(&body.as_bytes()[..sent]).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// `path` served to a client that has (or has not) been through `/login`
+fn route(
+    method: &str,
+    path: &str,
+    addr: SocketAddr,
+    has_session: bool,
+) -> (&'static str, &'static str, String) {
+    const HTML: &str = "text/html; charset=utf-8";
+    const JSON: &str = "application/json; charset=utf-8";
+    let router_login = format!("http://{}/hotspot/login", addr);
+
+    match (method, path) {
+        ("GET", "/") => (
+            "200 OK",
+            HTML,
+            format!(
+                concat!(
+                    "<html><head><script>\n",
+                    "var mac = \"02:00:00:AA:BB:01\";\n",
+                    "var ip = \"10.20.30.41\";\n",
+                    "var chap_id = \"\\321\";\n",
+                    "var chap_challenge = \"\\064\\231\\210\\353\\027\\024\\150\\376\";\n",
+                    "'link-login-only': '{}';\n",
+                    "</script></head><body></body></html>\n"
+                ),
+                router_login
+            ),
+        ),
+        ("GET", "/login") => (
+            "403 Forbidden",
+            HTML,
+            "<html><body>Forbidden</body></html>".to_string(),
+        ),
+        ("GET", "/generate_204") => ("302 Found", HTML, String::new()),
+        ("GET", "/landing") => (
+            "200 OK",
+            HTML,
+            "<html><head><meta http-equiv=\"refresh\" content=\"0; url=/\"></head></html>"
+                .to_string(),
+        ),
+        ("GET", "/Success") => ("200 OK", HTML, "<html><body>OK</body></html>".to_string()),
+        ("POST", "/Home/VerifyUrl") if !has_session => (
+            "401 Unauthorized",
+            JSON,
+            "{\"isSuccess\":false}".to_string(),
+        ),
+        ("POST", "/Home/VerifyUrl") => (
+            "200 OK",
+            JSON,
+            serde_json::json!({
+                "serial": "AW-MOCK",
+                "clientMac": "02:00:00:AA:BB:01",
+                "clientIp": "10.20.30.41",
+                "venueName": "Mock venue",
+            })
+            .to_string(),
+        ),
+        ("POST", "/Content/GetCustomer") => (
+            "200 OK",
+            JSON,
+            serde_json::json!({
+                "captiveContext": {
+                    "contentAuthenForm": format!(
+                        concat!(
+                            "<form id=\"authenForm\" method=\"post\" action=\"{}\">",
+                            "<input type=\"hidden\" name=\"username\" value=\"mock-user\">",
+                            "<input type=\"hidden\" name=\"password\" value=\"mock-pass\">",
+                            "</form>"
+                        ),
+                        router_login
+                    ),
+                },
+                "isSuccess": true,
+            })
+            .to_string(),
+        ),
+        ("POST", "/Analytic/Send") => ("200 OK", JSON, "{\"isSuccess\":true}".to_string()),
+        ("POST", "/hotspot/login") | ("GET", "/hotspot/login") => (
+            "200 OK",
+            HTML,
+            "<html><body>You are logged in</body></html>".to_string(),
+        ),
+        ("GET", "/hotspot/status") => (
+            "200 OK",
+            HTML,
+            concat!(
+                "<html><body><table>",
+                "<tr><td>bytes up/down:</td><td>1 MiB / 3 MiB</td></tr>",
+                "<tr><td>connected / left:</td><td>10m / 50m</td></tr>",
+                "</table></body></html>"
+            )
+            .to_string(),
+        ),
+        ("HEAD", _) => ("200 OK", HTML, String::new()),
+        _ => ("404 Not Found", HTML, "Not Found".to_string()),
+    }
+}
+
+#[cfg(all(test, feature = "portal-awing"))]
+mod tests {
+    use super::*;
+    use crate::identity::IdentityManager;
+    use crate::portal::awing::AwingConfig;
+    use crate::portal::{AwingPortal, CaptivePortal, PortalSettings};
+
+    fn mock_config(mock: &MockPortal, extra: &str) -> AwingConfig {
+        let portal_cfg: PortalConfig = toml::from_str(&format!(
+            r#"
+            name = "mock"
+            type = "awing"
+            ssids = ["Mock"]
+            mac_address = "02:00:00:00:00:01"
+            {}
+            "#,
+            extra
+        ))
+        .unwrap();
+        let portal_cfg = mock.portal_config(&portal_cfg);
+        let settings = PortalSettings {
+            identity: IdentityManager::default().resolve(&portal_cfg),
+            ..PortalSettings::default()
+        };
+        AwingConfig::from_config(&portal_cfg, settings)
+    }
+
+    fn mock_portal(mock: &MockPortal, extra: &str) -> AwingPortal {
+        AwingPortal::new(mock_config(mock, extra)).unwrap()
+    }
+
+    /// An Awing portal that has to find the mock's gateway page from `path`,
+    /// with `gateway_url` left pointing at the mock only when `fallback`
+    fn discovering_portal(mock: &MockPortal, path: &str, fallback: bool) -> AwingPortal {
+        let mut config = mock_config(mock, "");
+        config.discover_gateway = true;
+        config.discovery_url = Some(format!("{}{}", mock.url(), path));
+        if !fallback {
+            config.gateway_url = "http://127.0.0.1:9".to_string();
+        }
+        AwingPortal::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_awing_flow_against_mock() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = mock_portal(&mock, "");
+
+        portal.connect().await.unwrap();
+
+        let steps: Vec<&str> = portal.last_steps().iter().map(|s| s.name).collect();
+        assert_eq!(
+            steps,
+            [
+                "scan_gateway",
+                "handshake",
+                "verify_device",
+                "get_credentials",
+                "send_analytics",
+                "login_router"
+            ]
+        );
+        assert!(portal.last_steps().iter().all(|s| s.ok));
+    }
+
+    #[tokio::test]
+    async fn test_awing_discovers_gateway_by_redirect() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = discovering_portal(&mock, "/generate_204", false);
+
+        portal.connect().await.unwrap();
+        assert_eq!(portal.endpoints()[0], mock.url());
+    }
+
+    #[tokio::test]
+    async fn test_awing_discovers_gateway_through_meta_refresh() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = discovering_portal(&mock, "/landing", false);
+
+        portal.connect().await.unwrap();
+        assert_eq!(portal.endpoints()[0], mock.url());
+    }
+
+    #[tokio::test]
+    async fn test_awing_falls_back_to_gateway_url_when_not_redirected() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = discovering_portal(&mock, "/Success", true);
+        portal.connect().await.unwrap();
+
+        let mut portal = discovering_portal(&mock, "/Success", false);
+        assert!(portal.connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_awing_compat_flow_against_mock() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = mock_portal(&mock, "compat = true");
+
+        portal.connect().await.unwrap();
+        assert!(portal.last_steps().iter().all(|s| s.ok));
+    }
+
+    #[tokio::test]
+    async fn test_awing_session_info_from_mock() {
+        let mock = MockPortal::start(Duration::ZERO).await.unwrap();
+        let mut portal = mock_portal(&mock, "");
+        assert_eq!(portal.session_info().await.unwrap(), None);
+
+        portal.connect().await.unwrap();
+        let info = portal.session_info().await.unwrap().unwrap();
+        assert_eq!(info.bytes_used, Some(4 * 1024 * 1024));
+        assert!(info.expires_at.is_some());
+        assert_eq!(info.venue.as_deref(), Some("Mock venue"));
+    }
+}
@@ -1,9 +1,9 @@
 //! Data models for Wi-MESH authentication
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Gateway configuration extracted from captive portal HTML
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatewayConfig {
     pub mac: String,
     pub ip: String,
@@ -12,6 +12,19 @@ pub struct GatewayConfig {
     pub link_login_only: String,
 }
 
+/// What the portal says about the current session; any field the portal
+/// does not report is `None`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Unix time the session ends
+    pub expires_at: Option<u64>,
+    /// Bytes transferred (up and down) so far
+    pub bytes_used: Option<u64>,
+    /// Total bytes the session may transfer
+    pub bytes_limit: Option<u64>,
+    pub venue: Option<String>,
+}
+
 /// Login credentials extracted from authentication form
 #[derive(Debug, Clone)]
 pub struct Credentials {
@@ -19,6 +32,61 @@ pub struct Credentials {
     pub password: String,
 }
 
+/// Named input fields of an HTML form, in document order
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FormFields(Vec<(String, String)>);
+
+impl FormFields {
+    /// Value of the field `name`, if the form has it
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set a field, replacing its value in place if it already exists
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.0.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.0.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Fields as `(name, value)` pairs, ready for form encoding
+    pub fn as_pairs(&self) -> &[(String, String)] {
+        &self.0
+    }
+}
+
+/// An HTML form as the page declares it: where, how, and what it submits
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ParsedForm {
+    /// `action` attribute, possibly relative or empty
+    pub action: String,
+    /// Lowercased `method` attribute
+    pub method: String,
+    pub fields: FormFields,
+}
+
+impl ParsedForm {
+    /// Credentials carried in the form's `username`/`password` fields
+    pub fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            username: self.fields.get("username")?.to_string(),
+            password: self.fields.get("password")?.to_string(),
+        })
+    }
+}
+
 /// Response from /Home/VerifyUrl endpoint
 #[derive(Debug, Clone, Deserialize)]
 pub struct VerifyResponse {
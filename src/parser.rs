@@ -1,10 +1,40 @@
 //! HTML and JSON parsing utilities
 
-use crate::models::{Credentials, GatewayConfig};
-use anyhow::{anyhow, Result};
+use crate::models::{Credentials, FormFields, GatewayConfig, ParsedForm, SessionInfo};
+use crate::phrases::{Phrase, PhraseTable};
+use anyhow::Result;
 use regex::Regex;
+use std::collections::HashMap;
+
+/// A value the parser expected is missing from the page
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    #[error("{0} not found in form")]
+    NotInForm(&'static str),
+    #[error("{0} not found in response")]
+    NotInResponse(&'static str),
+}
+
+/// Decode HTML entities (`&amp;`, `&#47;`, ...) in an attribute or script value
+pub fn decode_html(value: &str) -> String {
+    html_escape::decode_html_entities(value).into_owned()
+}
+
+/// Decode percent-encoding (`%5C064`), keeping the raw value if the result
+/// would not be valid UTF-8
+pub fn decode_percent(value: &str) -> String {
+    urlencoding::decode(value)
+        .map(|v| v.into_owned())
+        .unwrap_or_else(|_| value.to_string())
+}
 
 /// Parse gateway configuration from captive portal HTML
+///
+/// All values are HTML-entity decoded. Scalar values (MAC, IP, CHAP fields)
+/// are also percent-decoded; URLs are left encoded since their query strings
+/// legitimately contain escapes.
 pub fn parse_gateway_html(html: &str) -> Result<GatewayConfig> {
     fn extract_value(html: &str, key: &str) -> Option<String> {
         let pattern = format!(r#"["']?{}["']?\s*[:=]\s*["']([^"']+)["']"#, key);
@@ -12,53 +42,298 @@ pub fn parse_gateway_html(html: &str) -> Result<GatewayConfig> {
             .ok()?
             .captures(html)?
             .get(1)
-            .map(|m| m.as_str().to_string())
+            .map(|m| decode_html(m.as_str()))
+    }
+
+    fn extract_scalar(html: &str, key: &str) -> Option<String> {
+        extract_value(html, key).map(|v| decode_percent(&v))
     }
 
     let chap_challenge =
-        extract_value(html, "chap_challenge").ok_or_else(|| anyhow!("chap_challenge not found"))?;
+        extract_scalar(html, "chap_challenge").ok_or(ParseError::NotFound("chap_challenge"))?;
 
     Ok(GatewayConfig {
-        mac: extract_value(html, "mac").unwrap_or_default(),
-        ip: extract_value(html, "ip").unwrap_or_default(),
-        chap_id: extract_value(html, "chap_id").unwrap_or_default(),
+        mac: extract_scalar(html, "mac").unwrap_or_default(),
+        ip: extract_scalar(html, "ip").unwrap_or_default(),
+        chap_id: extract_scalar(html, "chap_id").unwrap_or_default(),
         chap_challenge,
         link_login_only: extract_value(html, "link-login-only").unwrap_or_default(),
     })
 }
 
-/// Parse credentials from authentication form HTML
-pub fn parse_credentials(html: &str) -> Result<Credentials> {
-    fn extract_input_value(html: &str, name: &str) -> Option<String> {
-        // Try: <input ... name="xxx" ... value="yyy" ...>
-        let pattern1 = format!(
-            r#"<input[^>]*name=["']{}["'][^>]*value=["']([^"']*)["']"#,
-            name
-        );
-        if let Some(caps) = Regex::new(&pattern1).ok()?.captures(html) {
-            return caps.get(1).map(|m| m.as_str().to_string());
+/// Attributes of one HTML tag, names lowercased, values entity-decoded
+pub type Attributes = HashMap<String, String>;
+
+/// Find every `<tag ...>` in `html` and return its attributes, in document
+/// order
+///
+/// Attribute values may be double-quoted, single-quoted or bare, span
+/// several lines, and contain `>` or any non-ASCII text inside quotes.
+pub fn find_tags(html: &str, tag: &str) -> Vec<Attributes> {
+    // ASCII lowercasing keeps byte offsets identical to `html`
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{}", tag.to_ascii_lowercase());
+    let bytes = html.as_bytes();
+    let mut tags = Vec::new();
+    let mut pos = 0;
+
+    while let Some(found) = lower[pos..].find(&open) {
+        let mut i = pos + found + open.len();
+        pos = i;
+
+        // `<inputs` is not an `<input`
+        if i < bytes.len()
+            && !(bytes[i].is_ascii_whitespace() || bytes[i] == b'/' || bytes[i] == b'>')
+        {
+            continue;
         }
 
-        // Try reverse: <input ... value="yyy" ... name="xxx" ...>
-        let pattern2 = format!(
-            r#"<input[^>]*value=["']([^"']*)["'][^>]*name=["']{}["']"#,
-            name
-        );
-        Regex::new(&pattern2)
-            .ok()?
-            .captures(html)?
-            .get(1)
-            .map(|m| m.as_str().to_string())
+        let mut attrs = Attributes::new();
+        loop {
+            while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+                i += 1;
+            }
+            if i >= bytes.len() || bytes[i] == b'>' {
+                break;
+            }
+
+            let name_start = i;
+            while i < bytes.len()
+                && !(bytes[i].is_ascii_whitespace() || matches!(bytes[i], b'=' | b'>' | b'/'))
+            {
+                i += 1;
+            }
+            let name = lower[name_start..i].to_string();
+
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i >= bytes.len() || bytes[i] != b'=' {
+                attrs.entry(name).or_default();
+                continue;
+            }
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+
+            let value = match bytes.get(i) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let value_start = i + 1;
+                    let value_end = html[value_start..]
+                        .find(quote as char)
+                        .map_or(bytes.len(), |n| value_start + n);
+                    i = (value_end + 1).min(bytes.len());
+                    &html[value_start..value_end]
+                }
+                _ => {
+                    let value_start = i;
+                    while i < bytes.len() && !(bytes[i].is_ascii_whitespace() || bytes[i] == b'>') {
+                        i += 1;
+                    }
+                    &html[value_start..i]
+                }
+            };
+            attrs.entry(name).or_insert_with(|| decode_html(value));
+        }
+
+        pos = i;
+        tags.push(attrs);
     }
 
-    let username =
-        extract_input_value(html, "username").ok_or_else(|| anyhow!("username not found in form"))?;
-    let password =
-        extract_input_value(html, "password").ok_or_else(|| anyhow!("password not found in form"))?;
+    tags
+}
+
+/// Extract every named `<input>` of a form into a field map, in document
+/// order. Buttons are skipped; an input without a value submits as empty.
+pub fn parse_form_fields(html: &str) -> FormFields {
+    let mut fields = FormFields::default();
+
+    for attrs in find_tags(html, "input") {
+        let Some(name) = attrs.get("name").filter(|n| !n.is_empty()) else {
+            continue;
+        };
+        let kind = attrs.get("type").map(|t| t.to_ascii_lowercase());
+        if matches!(
+            kind.as_deref(),
+            Some("submit" | "button" | "image" | "reset")
+        ) {
+            continue;
+        }
+
+        let value = attrs.get("value").cloned().unwrap_or_default();
+        fields.insert(name, &value);
+    }
+
+    fields
+}
+
+/// Parse the first form in `html`: its action, method and fields
+///
+/// Only inputs inside that form are collected. Without a `<form>` tag the
+/// whole document counts as the form. The method defaults to POST, which is
+/// what every hotspot login expects, rather than the HTML default of GET.
+pub fn parse_form(html: &str) -> ParsedForm {
+    let lower = html.to_ascii_lowercase();
+    let attrs = find_tags(html, "form")
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+
+    let body = match lower.find("<form") {
+        Some(start) => {
+            let end = lower[start..]
+                .find("</form")
+                .map_or(html.len(), |n| start + n);
+            &html[start..end]
+        }
+        None => html,
+    };
+
+    ParsedForm {
+        action: attrs.get("action").cloned().unwrap_or_default(),
+        method: attrs
+            .get("method")
+            .filter(|m| !m.is_empty())
+            .map_or_else(|| "post".to_string(), |m| m.to_ascii_lowercase()),
+        fields: parse_form_fields(body),
+    }
+}
+
+/// Target of a `<meta http-equiv="refresh" content="0; url=...">`, the
+/// "redirecting..." pages hotspots put in front of their splash
+pub fn parse_meta_refresh(html: &str) -> Option<String> {
+    find_tags(html, "meta").into_iter().find_map(|attrs| {
+        let refresh = attrs.get("http-equiv")?.eq_ignore_ascii_case("refresh");
+        let content = attrs.get("content").filter(|_| refresh)?;
+        let (_, target) = content.split_once(';')?;
+        let target = target.trim();
+        let (key, url) = target.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("url") {
+            return None;
+        }
+        let url = url.trim().trim_matches(|c| c == '\'' || c == '"');
+        Some(url.to_string()).filter(|url| !url.is_empty())
+    })
+}
+
+/// Parse credentials from authentication form HTML
+pub fn parse_credentials(html: &str) -> Result<Credentials> {
+    let fields = parse_form_fields(html);
+
+    let username = fields
+        .get("username")
+        .ok_or(ParseError::NotInForm("username"))?
+        .to_string();
+    let password = fields
+        .get("password")
+        .ok_or(ParseError::NotInForm("password"))?
+        .to_string();
 
     Ok(Credentials { username, password })
 }
 
+/// Parse a MikroTik hotspot status page (`/status`)
+///
+/// The stock template lays the session out as `label: | value` table rows:
+/// "bytes up/down", "connected / left" and, with a quota, "remaining
+/// bytes", or their translations in `phrases`. `now` is the Unix time the
+/// page was fetched, to turn the time left into an expiry.
+pub fn parse_mikrotik_status(html: &str, now: u64, phrases: &PhraseTable) -> Result<SessionInfo> {
+    let row = Regex::new(r"(?is)<td[^>]*>\s*([^<]+?):\s*</td>\s*<td[^>]*>\s*([^<]*?)\s*</td>")?;
+    let rows: Vec<(String, String)> = row
+        .captures_iter(html)
+        .map(|c| (decode_html(&c[1]), decode_html(&c[2])))
+        .collect();
+    let field = |phrase| {
+        rows.iter()
+            .find(|(label, _)| phrases.is(phrase, label))
+            .map(|(_, value)| value)
+    };
+    let traffic = field(Phrase::BytesUpDown);
+    let time = field(Phrase::ConnectedLeft);
+    if traffic.is_none() && time.is_none() {
+        return Err(ParseError::NotFound("hotspot status table").into());
+    }
+
+    let bytes_used = traffic.and_then(|v| {
+        let (up, down) = v.split_once('/')?;
+        Some(parse_nice_bytes(up)? + parse_nice_bytes(down)?)
+    });
+    let expires_at = time
+        .and_then(|v| v.split_once('/'))
+        .and_then(|(_, left)| parse_mikrotik_duration(left))
+        .map(|left| now + left);
+    let bytes_limit = match (bytes_used, field(Phrase::RemainingBytes)) {
+        (Some(used), Some(remaining)) => parse_nice_bytes(remaining).map(|r| used + r),
+        _ => None,
+    };
+
+    Ok(SessionInfo {
+        expires_at,
+        bytes_used,
+        bytes_limit,
+        venue: None,
+    })
+}
+
+/// RouterOS "nice" byte counts: `2048 B`, `1.4 MiB`, `3 GB`
+fn parse_nice_bytes(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let number: f64 = value[..split].trim().parse().ok()?;
+    let multiplier = match value[split..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        _ => return None,
+    };
+    Some((number * multiplier) as u64)
+}
+
+/// RouterOS durations in seconds: `47m26s`, `1d2h3m`, `1w`, `01:02:03`
+fn parse_mikrotik_duration(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            ':' => {
+                total = (total + number.parse::<u64>().ok()?) * 60;
+                number.clear();
+            }
+            'w' | 'd' | 'h' | 'm' | 's' => {
+                let seconds = match c {
+                    'w' => 7 * 86400,
+                    'd' => 86400,
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total += number.parse::<u64>().ok()? * seconds;
+                number.clear();
+            }
+            ' ' => {}
+            _ => return None,
+        }
+    }
+    if !number.is_empty() {
+        total += number.parse::<u64>().ok()?;
+    }
+    Some(total)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,4 +367,266 @@ mod tests {
         assert_eq!(creds.username, "user123");
         assert_eq!(creds.password, "pass456");
     }
+
+    #[test]
+    fn test_decode_html() {
+        assert_eq!(
+            decode_html("http://10.5.50.1/login?a=1&amp;b=2"),
+            "http://10.5.50.1/login?a=1&b=2"
+        );
+        assert_eq!(decode_html("http:&#47;&#x2F;x"), "http://x");
+        assert_eq!(decode_html("plain"), "plain");
+    }
+
+    #[test]
+    fn test_decode_percent() {
+        assert_eq!(decode_percent(r"%5C064%5C231"), r"\064\231");
+        assert_eq!(decode_percent("02%3A00%3A00"), "02:00:00");
+        // Invalid UTF-8 after decoding keeps the raw value
+        assert_eq!(decode_percent("%FF%FE"), "%FF%FE");
+    }
+
+    #[test]
+    fn test_parse_gateway_decodes_values() {
+        let html = r#"
+            var chap_challenge = "%5C064%5C231%5C210";
+            var chap_id = "%5C321";
+            'link-login-only': 'http://10.5.50.1/login?dst=http%3A%2F%2Fx&amp;popup=true'
+        "#;
+
+        let gw = parse_gateway_html(html).unwrap();
+        assert_eq!(gw.chap_challenge, r"\064\231\210");
+        assert_eq!(gw.chap_id, r"\321");
+        // URLs are entity-decoded but keep their own percent-encoding
+        assert_eq!(
+            gw.link_login_only,
+            "http://10.5.50.1/login?dst=http%3A%2F%2Fx&popup=true"
+        );
+    }
+
+    #[test]
+    fn test_parse_credentials_decodes_entities() {
+        let html =
+            r#"<input name="username" value="a&amp;b"><input name="password" value="p&lt;q">"#;
+
+        let creds = parse_credentials(html).unwrap();
+        assert_eq!(creds.username, "a&b");
+        assert_eq!(creds.password, "p<q");
+    }
+
+    #[test]
+    fn test_parse_credentials_any_attribute_layout() {
+        let html = "<form>
+            <INPUT value='tài khoản >1' TYPE=hidden
+                name='username'>
+            <input
+                name=password
+                type=\"hidden\"
+                value=\"mật-khẩu\"/>
+        </form>";
+
+        let creds = parse_credentials(html).unwrap();
+        assert_eq!(creds.username, "tài khoản >1");
+        assert_eq!(creds.password, "mật-khẩu");
+    }
+
+    #[test]
+    fn test_parse_form_fields() {
+        let html = r#"
+            <form method="post">
+                <input type="hidden" name="dst" value="http://x/?a=1&amp;b=2">
+                <input type="hidden" name="popup" value="true">
+                <input type="text" name="username">
+                <input type="submit" name="login" value="Đăng nhập">
+                <inputs name="bogus" value="x">
+            </form>
+        "#;
+
+        let fields = parse_form_fields(html);
+        let names: Vec<&str> = fields.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["dst", "popup", "username"]);
+        assert_eq!(fields.get("dst"), Some("http://x/?a=1&b=2"));
+        assert_eq!(fields.get("username"), Some(""));
+    }
+
+    #[test]
+    fn test_parse_form() {
+        let html = r#"
+            <form id="authenForm" METHOD="GET" action="/login?lang=vi">
+                <input type="hidden" name="username" value="u">
+                <input type="hidden" name="password" value="p">
+                <input type="hidden" name="popup" value="true">
+            </form>
+            <form action="/other"><input name="outside" value="x"></form>
+        "#;
+
+        let form = parse_form(html);
+        assert_eq!(form.action, "/login?lang=vi");
+        assert_eq!(form.method, "get");
+        assert_eq!(form.fields.get("popup"), Some("true"));
+        assert_eq!(form.fields.get("outside"), None);
+
+        let creds = form.credentials().unwrap();
+        assert_eq!(creds.username, "u");
+        assert_eq!(creds.password, "p");
+    }
+
+    #[test]
+    fn test_parse_meta_refresh() {
+        let html = r#"<html><head><META HTTP-EQUIV="Refresh" CONTENT="0; URL='/splash?ap=01'">"#;
+        assert_eq!(parse_meta_refresh(html).as_deref(), Some("/splash?ap=01"));
+        let html = r#"<meta http-equiv="refresh" content="5">"#;
+        assert_eq!(parse_meta_refresh(html), None);
+        let html = r#"<meta name="viewport" content="width=device-width; url=x">"#;
+        assert_eq!(parse_meta_refresh(html), None);
+    }
+
+    #[test]
+    fn test_parse_form_without_form_tag() {
+        let form = parse_form(r#"<input name="username" value="u">"#);
+        assert_eq!(form.action, "");
+        assert_eq!(form.method, "post");
+        assert_eq!(form.fields.get("username"), Some("u"));
+        assert!(form.credentials().is_none());
+    }
+
+    macro_rules! fixture {
+        ($path:literal) => {
+            ($path, include_str!(concat!("../tests/fixtures/", $path)))
+        };
+    }
+
+    #[test]
+    fn test_gateway_fixtures() {
+        // (fixture, mac, ip, chap_challenge)
+        let cases = [
+            (
+                fixture!("gateway/awing-ktx-khu-b.html"),
+                "02:00:00:AA:BB:01",
+                "10.20.30.41",
+                r"\064\231\210\353\027\024\150\376\041\323\150\044\170\045\330\142",
+            ),
+            (
+                fixture!("gateway/awing-ktx-khu-a.html"),
+                "02:00:00:AA:BB:02",
+                "10.21.4.17",
+                r"\317\066\205\275\165\264\342\066\356\044\173\324\311\053\326\201",
+            ),
+            (
+                fixture!("gateway/awing-cafe-json.html"),
+                "02:00:00:AA:BB:03",
+                "192.168.88.254",
+                r"\052\375\311\074\377\150\367\013\117\065\303\304\275\200\050\027",
+            ),
+            (
+                fixture!("gateway/awing-encoded.html"),
+                "02:00:00:AA:BB:06",
+                "10.22.8.3",
+                r"\311\022\377\003\206\051\134\132\272\023\323\144\017\221\006\342",
+            ),
+            (
+                fixture!("gateway/mikrotik-default.html"),
+                "02:00:00:AA:BB:04",
+                "10.5.50.200",
+                r"\311\022\377\003\206\051\134\132\272\023\323\144\017\221\006\342",
+            ),
+            (
+                fixture!("gateway/mikrotik-minimal.html"),
+                "02:00:00:AA:BB:05",
+                "172.16.0.99",
+                r"\150\067\233\325\107\012\342\373\161\325\315\021\365\240\247\301",
+            ),
+        ];
+
+        for ((path, html), mac, ip, chap_challenge) in cases {
+            let gw = parse_gateway_html(html).unwrap_or_else(|e| panic!("{}: {}", path, e));
+            assert_eq!(gw.mac, mac, "{}", path);
+            assert_eq!(gw.ip, ip, "{}", path);
+            assert_eq!(gw.chap_challenge, chap_challenge, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_gateway_fixtures_link_login_only() {
+        let cases = [
+            (
+                fixture!("gateway/awing-ktx-khu-a.html"),
+                "http://free.wi-mesh.vn/login",
+            ),
+            (
+                fixture!("gateway/awing-cafe-json.html"),
+                "http://10.5.50.1/login",
+            ),
+            (
+                fixture!("gateway/awing-encoded.html"),
+                "http://free.wi-mesh.vn/login?target=awing&lang=vi",
+            ),
+        ];
+
+        for ((path, html), link_login_only) in cases {
+            let gw = parse_gateway_html(html).unwrap();
+            assert_eq!(gw.link_login_only, link_login_only, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_status_fixtures() {
+        let now = 1_700_000_000;
+        let phrases = PhraseTable::default();
+
+        let (_, html) = fixture!("status/mikrotik-default.html");
+        let info = parse_mikrotik_status(html, now, &phrases).unwrap();
+        let used = (1.4 * 1048576.0) as u64 + (23.7 * 1048576.0) as u64;
+        assert_eq!(info.bytes_used, Some(used));
+        assert_eq!(info.expires_at, Some(now + 47 * 60 + 26));
+        assert_eq!(info.bytes_limit, Some(used + (999.3 * 1048576.0) as u64));
+
+        let (_, html) = fixture!("status/mikrotik-minimal.html");
+        let info = parse_mikrotik_status(html, now, &phrases).unwrap();
+        assert_eq!(info.bytes_used, Some(512 * 1024 + 2048));
+        assert_eq!(info.expires_at, None);
+        assert_eq!(info.bytes_limit, None);
+
+        let (_, html) = fixture!("gateway/mikrotik-default.html");
+        assert!(parse_mikrotik_status(html, now, &phrases).is_err());
+
+        // The same page from a venue whose templates are in Vietnamese
+        let (_, html) = fixture!("status/mikrotik-default.html");
+        let html = html
+            .replace("bytes up/down", "Dung lượng gửi/nhận")
+            .replace("connected / left", "Thời gian kết nối / còn lại");
+        let info = parse_mikrotik_status(&html, now, &phrases).unwrap();
+        assert_eq!(info.bytes_used, Some(used));
+        assert_eq!(info.expires_at, Some(now + 47 * 60 + 26));
+    }
+
+    #[test]
+    fn test_mikrotik_duration() {
+        assert_eq!(parse_mikrotik_duration("47m26s"), Some(2846));
+        assert_eq!(parse_mikrotik_duration("1d2h"), Some(93600));
+        assert_eq!(parse_mikrotik_duration("01:02:03"), Some(3723));
+        assert_eq!(parse_mikrotik_duration("soon"), None);
+    }
+
+    #[test]
+    fn test_authen_form_fixtures() {
+        let cases = [
+            (
+                fixture!("authen_form/awing-ktx-khu-b.html"),
+                "awing15-0000000001",
+                "Awing15-0000000001@2024",
+            ),
+            (
+                fixture!("authen_form/awing-value-first.html"),
+                "awing07-0000000002",
+                "Awing07-0000000002@2024",
+            ),
+        ];
+
+        for ((path, html), username, password) in cases {
+            let creds = parse_credentials(html).unwrap_or_else(|e| panic!("{}: {}", path, e));
+            assert_eq!(creds.username, username, "{}", path);
+            assert_eq!(creds.password, password, "{}", path);
+        }
+    }
 }
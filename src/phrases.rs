@@ -0,0 +1,175 @@
+//! Portal wording, in both languages venues use
+//!
+//! Depending on the venue's settings, Awing pages and the MikroTik hotspot
+//! templates behind them speak Vietnamese or English. Every string the
+//! flow matches on lives in the table below with both variants, so
+//! detection does not silently stop working at a venue set to the other
+//! language. A portal can add its own variants with `phrases` in its
+//! config entry:
+//!
+//! ```toml
+//! phrases = { login_rejected = ["Mã truy cập không hợp lệ"] }
+//! ```
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Something the flow recognizes on a portal page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phrase {
+    /// The router refused the login; MikroTik serves the login page again
+    /// with this in place of `$(error)`
+    LoginRejected,
+    /// Status page row: traffic sent / received
+    BytesUpDown,
+    /// Status page row: session time so far / left
+    ConnectedLeft,
+    /// Status page row: quota left
+    RemainingBytes,
+}
+
+impl Phrase {
+    pub const ALL: [Phrase; 4] = [
+        Self::LoginRejected,
+        Self::BytesUpDown,
+        Self::ConnectedLeft,
+        Self::RemainingBytes,
+    ];
+
+    /// Name of this phrase in a portal's `phrases` table
+    pub fn key(self) -> &'static str {
+        match self {
+            Self::LoginRejected => "login_rejected",
+            Self::BytesUpDown => "bytes_up_down",
+            Self::ConnectedLeft => "connected_left",
+            Self::RemainingBytes => "remaining_bytes",
+        }
+    }
+
+    /// Built-in variants, lowercase: English (RouterOS stock templates)
+    /// first, then Vietnamese
+    fn builtin(self) -> &'static [&'static str] {
+        match self {
+            Self::LoginRejected => &[
+                "invalid username or password",
+                "invalid password",
+                "no more sessions are allowed",
+                "has reached uptime limit",
+                "has reached traffic limit",
+                "sai tên đăng nhập hoặc mật khẩu",
+                "tên đăng nhập hoặc mật khẩu không đúng",
+                "mật khẩu không đúng",
+                "đã hết thời gian sử dụng",
+                "đã hết dung lượng",
+            ],
+            Self::BytesUpDown => &["bytes up/down", "dung lượng gửi/nhận", "tải lên/tải xuống"],
+            Self::ConnectedLeft => &[
+                "connected / left",
+                "thời gian kết nối / còn lại",
+                "đã kết nối / còn lại",
+            ],
+            Self::RemainingBytes => &["remaining bytes", "dung lượng còn lại"],
+        }
+    }
+}
+
+/// The variants of every `Phrase` one portal matches
+#[derive(Debug, Clone, Default)]
+pub struct PhraseTable {
+    /// Variants from the portal's config, lowercase, tried after the
+    /// built-in ones
+    extra: HashMap<Phrase, Vec<String>>,
+}
+
+impl PhraseTable {
+    /// The built-in table plus the `phrases` of a portal's config entry:
+    /// a table of phrase keys to strings or arrays of strings
+    pub fn from_config(phrases: Option<&toml::Value>) -> Result<Self> {
+        let mut table = Self::default();
+        let Some(phrases) = phrases else {
+            return Ok(table);
+        };
+        let phrases = phrases
+            .as_table()
+            .ok_or_else(|| anyhow::anyhow!("`phrases` must be a table"))?;
+        for (key, value) in phrases {
+            let phrase = Phrase::ALL
+                .into_iter()
+                .find(|p| p.key() == key)
+                .ok_or_else(|| {
+                    let known: Vec<&str> = Phrase::ALL.iter().map(|p| p.key()).collect();
+                    anyhow::anyhow!("Unknown phrase '{}' (known: {})", key, known.join(", "))
+                })?;
+            let variants = match value {
+                toml::Value::String(s) => vec![s.as_str()],
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| anyhow::anyhow!("Phrases for '{}' must be strings", key))?,
+                _ => anyhow::bail!("Phrases for '{}' must be a string or an array", key),
+            };
+            table
+                .extra
+                .entry(phrase)
+                .or_default()
+                .extend(variants.into_iter().map(|v| v.trim().to_lowercase()));
+        }
+        Ok(table)
+    }
+
+    /// Every variant of `phrase`, lowercase
+    pub fn variants(&self, phrase: Phrase) -> impl Iterator<Item = &str> {
+        phrase.builtin().iter().copied().chain(
+            self.extra
+                .get(&phrase)
+                .into_iter()
+                .flatten()
+                .map(String::as_str),
+        )
+    }
+
+    /// Whether `label` (a table row label, say) is `phrase`, ignoring case
+    /// and surrounding whitespace
+    pub fn is(&self, phrase: Phrase, label: &str) -> bool {
+        let label = label.trim().to_lowercase();
+        self.variants(phrase).any(|v| v == label)
+    }
+
+    /// The variant of `phrase` appearing in `text`, ignoring case
+    pub fn find_in(&self, phrase: Phrase, text: &str) -> Option<&str> {
+        let text = text.to_lowercase();
+        self.variants(phrase).find(|v| text.contains(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_languages_and_config() {
+        let table = PhraseTable::default();
+        let english = "<p class=\"error\">invalid username or password</p>";
+        let vietnamese = "<p class=\"error\">Sai tên đăng nhập hoặc mật khẩu</p>";
+        assert!(table.find_in(Phrase::LoginRejected, english).is_some());
+        assert!(table.find_in(Phrase::LoginRejected, vietnamese).is_some());
+        assert!(table.is(Phrase::ConnectedLeft, " Thời gian kết nối / còn lại "));
+        assert!(!table.is(Phrase::ConnectedLeft, "bytes up/down"));
+
+        let config: toml::Value = toml::from_str(
+            "phrases = { login_rejected = [\"Mã truy cập không hợp lệ\"], \
+             remaining_bytes = \"Quota\" }",
+        )
+        .unwrap();
+        let table = PhraseTable::from_config(config.get("phrases")).unwrap();
+        assert_eq!(
+            table.find_in(Phrase::LoginRejected, "MÃ TRUY CẬP KHÔNG HỢP LỆ"),
+            Some("mã truy cập không hợp lệ")
+        );
+        assert!(table.is(Phrase::RemainingBytes, "quota"));
+
+        let unknown: toml::Value = toml::from_str("phrases = { welcome = \"Hi\" }").unwrap();
+        assert!(PhraseTable::from_config(unknown.get("phrases")).is_err());
+    }
+}
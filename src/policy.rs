@@ -0,0 +1,342 @@
+//! Retry policies
+//!
+//! How many times something is tried, how long it waits between tries and
+//! which failures are worth another one, declared once: the HTTP client
+//! applies `[policy.http]` to every request, the portal flows
+//! `[policy.step]` to every step, and the daemon `[policy.daemon]` to its
+//! backoff after failed logins. A table only lists what it changes; each
+//! use has its own defaults, which keep the older `[http]` and `[global]`
+//! keys working.
+
+use crate::http::HttpError;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// How the wait grows from one retry to the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    /// `delay` every time
+    Constant,
+    /// `delay`, twice `delay`, three times...
+    Linear,
+    /// `delay`, doubling every time
+    Exponential,
+}
+
+/// A class of failures, named after the `report::error_kind` it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The connection could not be made
+    Connect,
+    /// No answer in time
+    Timeout,
+    /// Any other failed request
+    Request,
+    /// A 5xx answer
+    ServerError,
+    /// Another status the request does not accept
+    HttpStatus,
+    /// A page that did not parse
+    Parse,
+    /// Every failure
+    Any,
+}
+
+impl RetryOn {
+    pub fn matches(self, err: &anyhow::Error) -> bool {
+        let kind = crate::report::error_kind(err);
+        match self {
+            Self::Any => true,
+            Self::Connect => kind == "connect",
+            Self::Timeout => kind == "timeout",
+            Self::Request => kind == "request",
+            Self::Parse => kind == "parse",
+            Self::ServerError | Self::HttpStatus => {
+                let server = err.chain().any(|cause| {
+                    matches!(
+                        cause.downcast_ref::<HttpError>(),
+                        Some(HttpError::Status { status, .. }) if status.is_server_error()
+                    )
+                });
+                kind == "http_status" && server == (self == Self::ServerError)
+            }
+        }
+    }
+}
+
+/// One attempt of `RetryPolicy::retry`, borrowing `S` while it runs
+pub type Attempt<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// How an operation is retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Tries in all, the first one included; at least 1
+    pub max_attempts: u32,
+    pub backoff: Curve,
+    /// Wait before the first retry
+    pub delay: Duration,
+    /// Longest wait, whatever the curve
+    pub max_delay: Duration,
+    /// Fraction of each wait taken off at random (0 to 1), so clients that
+    /// failed together do not retry together
+    pub jitter: f64,
+    pub retry_on: Vec<RetryOn>,
+    /// Longest one attempt may take, where the use enforces one
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// The HTTP request defaults
+    fn default() -> Self {
+        Self::http(Duration::from_secs(10), 3)
+    }
+}
+
+impl RetryPolicy {
+    /// Every request: `attempts` tries of at most `timeout`, 1s apart and
+    /// doubling, on network errors and 5xx answers
+    pub fn http(timeout: Duration, attempts: u32) -> Self {
+        Self {
+            max_attempts: attempts.max(1),
+            backoff: Curve::Exponential,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+            retry_on: vec![
+                RetryOn::Connect,
+                RetryOn::Timeout,
+                RetryOn::Request,
+                RetryOn::ServerError,
+            ],
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Every flow step: a single try, the requests in it retry already
+    pub fn step() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Curve::Constant,
+            delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+            retry_on: vec![RetryOn::Connect, RetryOn::Timeout],
+            timeout: None,
+        }
+    }
+
+    /// The daemon's login backoff: no wait for `attempts` - 1 failures,
+    /// then `base` doubling up to `max`
+    pub fn daemon(attempts: u32, base: Duration, max: Duration) -> Self {
+        Self {
+            max_attempts: attempts.max(1),
+            backoff: Curve::Exponential,
+            delay: base,
+            max_delay: max,
+            jitter: 0.0,
+            retry_on: vec![RetryOn::Any],
+            timeout: None,
+        }
+    }
+
+    /// Whether `err` is worth another try
+    pub fn retries(&self, err: &anyhow::Error) -> bool {
+        self.retry_on.iter().any(|class| class.matches(err))
+    }
+
+    /// Whether a 5xx answer is worth another try
+    pub fn retries_server_errors(&self) -> bool {
+        self.retries_class(RetryOn::ServerError)
+    }
+
+    /// Whether a request that got no answer is worth another try
+    pub fn retries_request(&self, err: &reqwest::Error) -> bool {
+        self.retries_class(if err.is_timeout() {
+            RetryOn::Timeout
+        } else if err.is_connect() {
+            RetryOn::Connect
+        } else {
+            RetryOn::Request
+        })
+    }
+
+    fn retries_class(&self, class: RetryOn) -> bool {
+        self.retry_on
+            .iter()
+            .any(|retried| *retried == class || *retried == RetryOn::Any)
+    }
+
+    /// The wait before retry number `retry` (1 for the first), without
+    /// jitter
+    pub fn delay(&self, retry: u32) -> Duration {
+        let steps = retry.saturating_sub(1);
+        let delay = match self.backoff {
+            Curve::Constant => self.delay,
+            Curve::Linear => self.delay.saturating_mul(steps.saturating_add(1)),
+            Curve::Exponential => self.delay.saturating_mul(1 << steps.min(31)),
+        };
+        delay.min(self.max_delay)
+    }
+
+    /// The wait before retry number `retry`, with jitter
+    pub fn jittered_delay(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        let random = (crate::utils::random_u64() % 1000) as f64 / 1000.0;
+        delay.mul_f64(1.0 - jitter * random)
+    }
+
+    /// Run `attempt` on `state` until it succeeds, fails in a way this
+    /// policy does not retry, or runs out of attempts; `what` names it in
+    /// the log
+    pub async fn retry<S, T, F>(&self, what: &str, state: &mut S, attempt: F) -> Result<T>
+    where
+        S: ?Sized,
+        F: for<'a> Fn(&'a mut S) -> Attempt<'a, T>,
+    {
+        let attempts = self.max_attempts.max(1);
+        let mut tries = 1;
+        loop {
+            let result = match self.timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, attempt(state)).await {
+                    Ok(result) => result,
+                    Err(elapsed) => Err(anyhow::Error::new(elapsed)
+                        .context(format!("{} took longer than {:?}", what, timeout))),
+                },
+                None => attempt(state).await,
+            };
+            match result {
+                Err(e) if tries < attempts && self.retries(&e) => {
+                    let delay = self.jittered_delay(tries);
+                    tracing::warn!(
+                        attempt = tries,
+                        "{} failed, retrying in {:?}... (attempt {}/{}): {:#}",
+                        what,
+                        delay,
+                        tries,
+                        attempts,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A `[policy.*]` table: what it leaves out keeps the defaults of its use
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PolicyOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Curve>,
+    /// Seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<f64>,
+    /// Seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_on: Option<Vec<RetryOn>>,
+    /// Seconds, 0 for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<f64>,
+}
+
+fn seconds(value: f64) -> Duration {
+    Duration::try_from_secs_f64(value.max(0.0)).unwrap_or(Duration::MAX)
+}
+
+impl PolicyOverrides {
+    /// `defaults` with what this table sets
+    pub fn over(&self, defaults: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(defaults.max_attempts).max(1),
+            backoff: self.backoff.unwrap_or(defaults.backoff),
+            delay: self.delay.map(seconds).unwrap_or(defaults.delay),
+            max_delay: self.max_delay.map(seconds).unwrap_or(defaults.max_delay),
+            jitter: self.jitter.unwrap_or(defaults.jitter).clamp(0.0, 1.0),
+            retry_on: self.retry_on.clone().unwrap_or(defaults.retry_on),
+            timeout: match self.timeout {
+                Some(timeout) if timeout > 0.0 => Some(seconds(timeout)),
+                Some(_) => None,
+                None => defaults.timeout,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays() {
+        let policy = RetryPolicy::daemon(3, Duration::from_secs(60), Duration::from_secs(600));
+        let waits: Vec<u64> = (1..=6).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(waits, [60, 120, 240, 480, 600, 600]);
+
+        let table: PolicyOverrides =
+            toml::from_str("backoff = \"linear\"\ndelay = 0.5\njitter = 0.5\ntimeout = 0").unwrap();
+        let policy = table.over(RetryPolicy::default());
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.timeout, None);
+        assert_eq!(policy.delay(3), Duration::from_millis(1500));
+        for retry in 1..=3 {
+            let wait = policy.jittered_delay(retry);
+            assert!(wait <= policy.delay(retry) && wait >= policy.delay(retry) / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = PolicyOverrides {
+            max_attempts: Some(3),
+            delay: Some(0.0),
+            retry_on: Some(vec![RetryOn::Parse]),
+            ..Default::default()
+        }
+        .over(RetryPolicy::step());
+
+        // Fails twice on a parse error, then works
+        let mut tries = 0;
+        let result = policy
+            .retry("test", &mut tries, |tries| {
+                Box::pin(async move {
+                    *tries += 1;
+                    if *tries < 3 {
+                        return Err(crate::parser::ParseError::NotFound("form").into());
+                    }
+                    Ok(*tries)
+                })
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Other failures are not retried
+        let mut tries = 0;
+        let result: Result<()> = policy
+            .retry("test", &mut tries, |tries| {
+                Box::pin(async move {
+                    *tries += 1;
+                    anyhow::bail!("no")
+                })
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(tries, 1);
+    }
+}
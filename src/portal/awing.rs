@@ -3,15 +3,117 @@
 //! This module handles authentication for Wi-MESH networks using the
 //! Awing Connect portal (awingconnect.vn).
 
-use crate::http::HttpClient;
-use crate::models::{Credentials, CustomerResponse, GatewayConfig};
-use crate::parser;
-use crate::portal::CaptivePortal;
+use crate::compat::CompatClient;
+use crate::config::PortalConfig;
+use crate::har::HarLog;
+use crate::http::{HttpClient, StatusPolicy};
+use crate::models::{CustomerResponse, GatewayConfig, ParsedForm, SessionInfo};
+use crate::parser::{self, ParseError};
+use crate::phrases::{Phrase, PhraseTable};
+use crate::portal::middleware::{HarPages, RateLimit, RedactMacs, StepDelay, TimingLog};
+use crate::portal::{
+    CaptivePortal, Inspection, PortalCapabilities, PortalSettings, StepRecorder, StepReport,
+    VenueChanged,
+};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const GATEWAY_URL: &str = "http://login.net.vn";
-const BASE_URL: &str = "http://v1.awingconnect.vn";
+const DEFAULT_GATEWAY_URL: &str = "http://login.net.vn";
+/// Plain HTTP page fetched for the gateway to intercept, when none of the
+/// `[connectivity]` probes is plain HTTP
+const DISCOVERY_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+/// Meta refreshes followed from the intercepted page before giving up on
+/// reaching the gateway's page
+const MAX_REFRESHES: usize = 3;
+const DEFAULT_BASE_URL: &str = "http://v1.awingconnect.vn";
+/// Router login used when the gateway page does not name one
+const DEFAULT_ROUTER_LOGIN_URL: &str = "http://free.wi-mesh.vn/login";
+/// Keys of the `awing` table
+pub const SETTINGS: &[&str] = &[
+    "gateway_url",
+    "base_url",
+    "quirks",
+    "phrases",
+    "emulate_ad_view",
+    "compat",
+    "captive_dns",
+    "min_step_interval_ms",
+    "har_file",
+];
+/// What `awing` portals can do and need
+pub const CAPABILITIES: PortalCapabilities = PortalCapabilities {
+    supports_logout: true,
+    supports_session_info: true,
+    supports_resume: true,
+    supports_inspect: true,
+    ..PortalCapabilities::NONE
+};
+
+/// View duration when the campaign does not advertise one
+const DEFAULT_AD_VIEW: Duration = Duration::from_secs(5);
+/// Never wait longer than this, whatever the campaign claims
+const MAX_AD_VIEW: Duration = Duration::from_secs(60);
+
+/// Response keys carrying the advertised view duration, in seconds
+const AD_DURATION_KEYS: &[&str] = &[
+    "countdown",
+    "countDown",
+    "timeCountDown",
+    "duration",
+    "viewDuration",
+    "timeView",
+];
+
+/// File extensions of campaign assets a browser would load
+const AD_ASSET_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif", ".webp", ".mp4", ".webm"];
+
+/// Pause inserted by `Quirk::DelayBetweenSteps`
+const QUIRK_STEP_DELAY: Duration = Duration::from_secs(1);
+/// Pause inserted by `Quirk::DelayBeforeLogin`
+const QUIRK_LOGIN_DELAY: Duration = Duration::from_secs(3);
+
+/// Venue-specific deviations from the standard Awing flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// Pause between every step; slow backends drop sessions otherwise
+    DelayBetweenSteps,
+    /// Pause before submitting the form to the router
+    DelayBeforeLogin,
+    /// Submit `popup=true` instead of `popup=false`
+    PopupTrue,
+    /// Use the gateway's original URL as `dst` instead of the Awing
+    /// Success page
+    DstLinkOrig,
+    /// The venue refuses logins without the analytics call; send it even
+    /// when `privacy.skip_analytics` is set, and stop when it fails
+    MandatoryAnalytics,
+}
+
+impl FromStr for Quirk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "delay-between-steps" => Self::DelayBetweenSteps,
+            "delay-before-login" => Self::DelayBeforeLogin,
+            "popup-true" => Self::PopupTrue,
+            "dst-link-orig" => Self::DstLinkOrig,
+            "mandatory-analytics" => Self::MandatoryAnalytics,
+            other => anyhow::bail!("Unknown Awing quirk '{}'", other),
+        })
+    }
+}
+
+/// A URL override from the portal's extra settings, without trailing slash
+fn extra_url(portal: &PortalConfig, key: &str) -> Option<String> {
+    let url = portal.setting(key)?.as_str()?;
+    Some(url.trim_end_matches('/').to_string())
+}
 
 /// Configuration for the Awing portal
 #[derive(Debug, Clone)]
@@ -20,8 +122,35 @@ pub struct AwingConfig {
     pub name: String,
     /// SSIDs that this portal handles
     pub ssids: Vec<String>,
-    /// MAC address for authentication
-    pub mac_address: String,
+    /// Identity, privacy switches and retries
+    pub settings: PortalSettings,
+    /// Load the ad campaign and wait out its view time before logging in,
+    /// like a manual login would
+    pub emulate_ad_view: bool,
+    /// Venue-specific deviations from the standard flow
+    pub quirks: Vec<Quirk>,
+    /// Page the gateway intercepts to reveal its configuration
+    pub gateway_url: String,
+    /// Find the gateway's page by where it redirects a plain HTTP request,
+    /// `gateway_url` being the fallback; on unless `gateway_url` is set
+    pub discover_gateway: bool,
+    /// Plain HTTP page fetched for discovery; the first plain HTTP
+    /// `[connectivity]` probe when `None`
+    pub discovery_url: Option<String>,
+    /// Awing API root
+    pub base_url: String,
+    /// Talk to the gateway with the lenient HTTP/1.0 client, for gateways
+    /// whose responses reqwest rejects
+    pub compat: bool,
+    /// Resolve API hosts through the captive network's DNS when the system
+    /// resolver cannot
+    pub captive_dns: bool,
+    /// Minimum time between the starts of two steps
+    pub min_step_interval: Duration,
+    /// Write a HAR capture of every login flow here
+    pub har_file: Option<PathBuf>,
+    /// What the venue's pages say, in its language
+    pub phrases: PhraseTable,
 }
 
 impl Default for AwingConfig {
@@ -29,59 +158,450 @@ impl Default for AwingConfig {
         Self {
             name: "Wi-MESH Awing".to_string(),
             ssids: vec!["1.Free Wi-MESH".to_string()],
-            mac_address: String::new(),
+            settings: PortalSettings::default(),
+            emulate_ad_view: false,
+            quirks: Vec::new(),
+            gateway_url: DEFAULT_GATEWAY_URL.to_string(),
+            discover_gateway: true,
+            discovery_url: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            compat: false,
+            captive_dns: true,
+            min_step_interval: Duration::ZERO,
+            har_file: None,
+            phrases: PhraseTable::default(),
         }
     }
 }
 
+impl AwingConfig {
+    /// Build from a `[[portals]]` entry; Awing-specific keys live in its
+    /// `awing` table (or the entry itself, in version 1 configs)
+    pub fn from_config(portal: &PortalConfig, settings: PortalSettings) -> Self {
+        let mut quirks = Vec::new();
+        for value in portal
+            .setting("quirks")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+        {
+            match value.as_str().map(Quirk::from_str) {
+                Some(Ok(quirk)) => quirks.push(quirk),
+                Some(Err(e)) => tracing::warn!("[{}] {}, ignoring", portal.name, e),
+                None => tracing::warn!(
+                    "[{}] Quirks must be strings, ignoring {}",
+                    portal.name,
+                    value
+                ),
+            }
+        }
+
+        let phrases = PhraseTable::from_config(portal.setting("phrases")).unwrap_or_else(|e| {
+            tracing::warn!("[{}] {:#}, using the built-in phrases", portal.name, e);
+            PhraseTable::default()
+        });
+
+        Self {
+            name: portal.name.clone(),
+            ssids: portal.ssids.clone(),
+            settings,
+            emulate_ad_view: portal
+                .setting("emulate_ad_view")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            quirks,
+            gateway_url: extra_url(portal, "gateway_url")
+                .unwrap_or(DEFAULT_GATEWAY_URL.to_string()),
+            discover_gateway: extra_url(portal, "gateway_url").is_none(),
+            discovery_url: None,
+            base_url: extra_url(portal, "base_url").unwrap_or(DEFAULT_BASE_URL.to_string()),
+            compat: portal
+                .setting("compat")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            captive_dns: portal
+                .setting("captive_dns")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+            min_step_interval: portal
+                .setting("min_step_interval_ms")
+                .and_then(|v| v.as_integer())
+                .map(|ms| Duration::from_millis(ms.max(0) as u64))
+                .unwrap_or_default(),
+            har_file: portal
+                .setting("har_file")
+                .and_then(|v| v.as_str())
+                .map(PathBuf::from),
+            phrases,
+        }
+    }
+
+    pub fn has_quirk(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+}
+
 /// Awing portal implementation for Wi-MESH networks
 pub struct AwingPortal {
     config: AwingConfig,
     client: HttpClient,
     gateway: Option<GatewayConfig>,
     handshake_url: Option<String>,
+    last_steps: Vec<StepReport>,
+    /// Form submitted by the last successful login, for session caching
+    last_form: Option<ParsedForm>,
+    /// Venue name from the last VerifyUrl context
+    venue: Option<String>,
+    /// Where the last plain HTTP request was redirected, when that was the
+    /// gateway's page
+    discovered_gateway: Option<String>,
+    /// Requests of the current flow, when `har_file` is set
+    har: Option<Arc<HarLog>>,
+}
+
+/// What `AwingPortal` caches to resume a session
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AwingSession {
+    gateway: GatewayConfig,
+    form: ParsedForm,
 }
 
 impl AwingPortal {
     /// Create a new Awing portal instance
     pub fn new(config: AwingConfig) -> Result<Self> {
+        let har = config.har_file.as_ref().map(|_| Arc::new(HarLog::new()));
+        let client = Self::http_client(&config, None, None, &har)?;
+
         Ok(Self {
+            client,
             config,
-            client: HttpClient::new()?,
             gateway: None,
             handshake_url: None,
+            last_steps: Vec::new(),
+            last_form: None,
+            venue: None,
+            discovered_gateway: None,
+            har,
         })
     }
 
+    fn http_client(
+        config: &AwingConfig,
+        interface: Option<&str>,
+        proxy: Option<&str>,
+        har: &Option<Arc<HarLog>>,
+    ) -> Result<HttpClient> {
+        let options = config.settings.http_options(interface, proxy);
+        let client = HttpClient::with_options(options)?;
+        client.set_capture(har.clone());
+        Ok(client)
+    }
+
+    /// A step recorder with the middlewares this portal's config asks for
+    fn recorder(&self) -> StepRecorder {
+        let mut steps = StepRecorder::default()
+            .with_budget(self.config.settings.flow_budget)
+            .with(TimingLog::new(&self.config.name));
+        if let Some(ref har) = self.har {
+            steps = steps.with(HarPages::new(har.clone()));
+        }
+        if !self.config.min_step_interval.is_zero() {
+            steps = steps.with(RateLimit::new(self.config.min_step_interval));
+        }
+        if self.config.has_quirk(Quirk::DelayBetweenSteps) {
+            steps = steps.with(StepDelay::between_steps(QUIRK_STEP_DELAY));
+        }
+        if self.config.has_quirk(Quirk::DelayBeforeLogin) {
+            steps = steps.with(StepDelay::before("login_router", QUIRK_LOGIN_DELAY));
+        }
+        if self.config.settings.privacy.redact_mac {
+            steps = steps.with(RedactMacs::new());
+        }
+        steps
+    }
+
+    /// Finish a flow: keep its steps, write the HAR capture, go back to
+    /// system DNS
+    fn finish_flow(&mut self, steps: StepRecorder) {
+        self.last_steps = steps.finish();
+        self.client.clear_dns_overrides();
+        if let (Some(har), Some(path)) = (&self.har, &self.config.har_file) {
+            match har.save(path) {
+                Ok(()) => {
+                    tracing::info!("[{}] HAR written to {}", self.config.name, path.display())
+                }
+                Err(e) => tracing::warn!("[{}] {:#}", self.config.name, e),
+            }
+        }
+    }
+
+    /// Run the full six-step flow, recording each step in `steps`
+    async fn run_flow(&mut self, steps: &mut StepRecorder) -> Result<()> {
+        self.client.new_session();
+
+        let retry = self.config.settings.step_retry.clone();
+        steps
+            .run_retrying("scan_gateway", &retry, self, |p| Box::pin(p.scan_gateway()))
+            .await?;
+        if self.config.captive_dns && self.client.proxy().is_none() {
+            self.captive_dns().await;
+        }
+        steps
+            .run_retrying("handshake", &retry, self, |p| Box::pin(p.handshake()))
+            .await?;
+        let context = steps
+            .run_retrying("verify_device", &retry, self, |p| {
+                Box::pin(p.verify_device())
+            })
+            .await?;
+        self.venue = context
+            .get("venueName")
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+        let (form, customer) = steps
+            .run_retrying("get_credentials", &retry, self, |p| {
+                let context = context.clone();
+                Box::pin(async move { p.get_credentials(&context).await })
+            })
+            .await?;
+        if self.config.emulate_ad_view {
+            steps
+                .run_retrying("view_ad", &retry, self, |p| {
+                    let customer = customer.clone();
+                    Box::pin(async move { p.view_ad(&customer).await })
+                })
+                .await?;
+        }
+        if self.config.settings.privacy.skip_analytics
+            && !self.config.has_quirk(Quirk::MandatoryAnalytics)
+        {
+            tracing::info!(
+                "[{}] Step 4: Skipping Analytics (privacy)",
+                self.config.name
+            );
+        } else if self.config.has_quirk(Quirk::MandatoryAnalytics) {
+            steps
+                .run_retrying("send_analytics", &retry, self, |p| {
+                    let context = context.clone();
+                    Box::pin(async move { p.send_analytics(&context).await })
+                })
+                .await?;
+        } else {
+            // The router lets the client in without it
+            steps
+                .run_retrying_soft("send_analytics", &retry, self, |p| {
+                    let context = context.clone();
+                    Box::pin(async move { p.send_analytics(&context).await })
+                })
+                .await;
+        }
+        steps
+            .run_retrying("login_router", &retry, self, |p| {
+                let form = form.clone();
+                Box::pin(async move { p.login_router(&form).await })
+            })
+            .await?;
+        self.last_form = Some(form);
+
+        tracing::info!("[{}] Connected successfully!", self.config.name);
+        Ok(())
+    }
+
+    /// Replay the cached login form, then check that traffic flows
+    async fn resume_session(&mut self, session: AwingSession) -> Result<()> {
+        tracing::info!(
+            "[{}] Resuming cached session for {}...",
+            self.config.name,
+            session
+                .form
+                .credentials()
+                .map(|c| c.username)
+                .unwrap_or_default()
+        );
+        self.client.new_session();
+        // A replaced mesh node names another router login, which the cached
+        // form was not made for
+        let cached_login = &session.gateway.link_login_only;
+        let html = self.gateway_get(self.gateway_url()).await;
+        if let Ok(Ok(gw)) = html.map(|html| parser::parse_gateway_html(&html)) {
+            if !gw.link_login_only.is_empty()
+                && !cached_login.is_empty()
+                && gw.link_login_only != *cached_login
+            {
+                return Err(VenueChanged(format!(
+                    "login URL {} -> {}",
+                    cached_login, gw.link_login_only
+                ))
+                .into());
+            }
+        }
+        self.gateway = Some(session.gateway);
+        if self.config.captive_dns && self.client.proxy().is_none() {
+            self.captive_dns().await;
+        }
+        self.login_router(&session.form).await?;
+
+        if !crate::utils::nonblocking::has_internet_connectivity().await {
+            anyhow::bail!("Cached session was not accepted");
+        }
+        self.last_form = Some(session.form);
+        Ok(())
+    }
+
+    /// The MikroTik login URL the gateway named, or the usual one
+    fn router_login_url(&self) -> &str {
+        self.gateway
+            .as_ref()
+            .map(|gw| gw.link_login_only.as_str())
+            .filter(|url| !url.is_empty())
+            .unwrap_or(DEFAULT_ROUTER_LOGIN_URL)
+    }
+
+    /// Point the flow's DNS at the captive network's own resolvers for the
+    /// API and router hosts the system resolver cannot resolve
+    async fn captive_dns(&self) {
+        let gateway_login = self.router_login_url();
+        let hosts: Vec<String> = [self.config.base_url.as_str(), gateway_login]
+            .iter()
+            .filter_map(|url| {
+                reqwest::Url::parse(url)
+                    .ok()?
+                    .host_str()
+                    .map(str::to_string)
+            })
+            .collect();
+
+        // The router named by the captive redirect, then the default gateway
+        let mut servers: Vec<IpAddr> = Vec::new();
+        if let Some(router) = reqwest::Url::parse(gateway_login)
+            .ok()
+            .and_then(|url| url.host_str()?.parse().ok())
+        {
+            servers.push(router);
+        }
+        if let Some(gateway) = crate::utils::nonblocking::default_gateway(None).await {
+            if !servers.contains(&gateway) {
+                servers.push(gateway);
+            }
+        }
+
+        for (host, ips) in crate::dns::captive_overrides(&hosts, &servers).await {
+            self.client.override_dns(&host, ips);
+        }
+    }
+
     /// Step 0: Scan Gateway - Fetch captive portal page and extract config
     async fn scan_gateway(&mut self) -> Result<()> {
         tracing::info!("[{}] Step 0: Scanning Gateway...", self.config.name);
 
-        let resp = self.client.get(GATEWAY_URL).await?;
-        let html = resp.text().await?;
-
-        let gw = parser::parse_gateway_html(&html)?;
+        let discovered = match self.config.discover_gateway {
+            true => self.discover_gateway().await,
+            false => None,
+        };
+        let gw = match discovered {
+            Some(gw) => gw,
+            None => {
+                let html = self.gateway_get(&self.config.gateway_url).await?;
+                parser::parse_gateway_html(&html)?
+            }
+        };
         tracing::info!("   -> Found gateway: {}", gw.ip);
 
         self.gateway = Some(gw);
         Ok(())
     }
 
+    /// The gateway's page, found where it redirects a plain HTTP request
+    /// (through meta refreshes too), so a new landing domain needs no
+    /// `gateway_url`; `None` when that leads elsewhere
+    async fn discover_gateway(&mut self) -> Option<GatewayConfig> {
+        let mut url = match self.config.discovery_url {
+            Some(ref url) => url.clone(),
+            None => crate::probe::targets()
+                .iter()
+                .find(|target| target.url.starts_with("http://"))
+                .map_or(DISCOVERY_URL, |target| target.url.as_str())
+                .to_string(),
+        };
+        let start = url.clone();
+        for _ in 0..=MAX_REFRESHES {
+            let (page, html) = match self.client.get_page(&url).await {
+                Ok(page) => page,
+                Err(e) => {
+                    tracing::debug!("   -> No redirect to follow from {}: {:#}", start, e);
+                    return None;
+                }
+            };
+            if let Some(next) = parser::parse_meta_refresh(&html) {
+                url = page.join(&next).ok()?.to_string();
+                tracing::debug!("   -> Refreshes to {}", url);
+                continue;
+            }
+            if page.as_str() == start {
+                tracing::debug!("   -> {} was not redirected, no portal in the way?", start);
+                return None;
+            }
+            return match parser::parse_gateway_html(&html) {
+                Ok(gw) => {
+                    let found = page.as_str().trim_end_matches('/').to_string();
+                    if found != self.config.gateway_url {
+                        tracing::info!("   -> Discovered the gateway page at {}", found);
+                    }
+                    self.discovered_gateway = Some(found);
+                    Some(gw)
+                }
+                Err(e) => {
+                    tracing::debug!("   -> {} is not the gateway page: {}", page, e);
+                    None
+                }
+            };
+        }
+        None
+    }
+
+    /// Page showing the gateway's configuration: the one discovered last,
+    /// else `gateway_url`
+    fn gateway_url(&self) -> &str {
+        self.discovered_gateway
+            .as_deref()
+            .unwrap_or(&self.config.gateway_url)
+    }
+
+    /// The MAC to register: `mac_address`, else the one of the interface
+    /// the flow goes out of, else the one the gateway reported
+    async fn device_mac(&self) -> Result<String> {
+        if !self.config.settings.identity.mac_address.is_empty() {
+            return Ok(self.config.settings.identity.mac_address.clone());
+        }
+        let interface = self.client.interface();
+        if let Some(mac) = crate::utils::nonblocking::get_interface_mac(interface).await {
+            tracing::debug!("   -> Detected MAC of {}", interface.unwrap_or("the WiFi"));
+            return Ok(mac);
+        }
+        self.gateway
+            .as_ref()
+            .map(|gw| gw.mac.clone())
+            .filter(|mac| !mac.is_empty())
+            .context("No MAC address to register: set mac_address, none was detected")
+    }
+
     /// Step 1: Handshake - Register device with portal
     async fn handshake(&mut self) -> Result<()> {
         let gw = self.gateway.as_ref().context("Gateway not scanned")?;
         tracing::info!("[{}] Step 1: Handshaking...", self.config.name);
-        tracing::info!("   -> Using MAC: {}", self.config.mac_address);
+        let mac = self.device_mac().await?;
+        tracing::info!("   -> Using MAC: {}", self.log_mac(&mac));
 
         let url = format!(
-            "{}/login?serial={}&client_mac={}&client_ip={}&userurl=http://login.net.vn/&login_url={}&chap_id={}&chap_challenge={}",
-            BASE_URL,
-            self.config.mac_address,
+            "{}/login?serial={}&client_mac={}&client_ip={}&userurl={}/&login_url={}&chap_id={}&chap_challenge={}",
+            self.config.base_url,
+            mac,
             gw.mac,
             gw.ip,
+            self.gateway_url(),
             urlencoding::encode(&gw.link_login_only),
-            gw.chap_id,
-            gw.chap_challenge
+            urlencoding::encode(&gw.chap_id),
+            urlencoding::encode(&gw.chap_challenge)
         );
 
         let mut headers = reqwest::header::HeaderMap::new();
@@ -91,14 +611,27 @@ impl AwingPortal {
         );
         headers.insert(
             reqwest::header::ORIGIN,
-            reqwest::header::HeaderValue::from_static(BASE_URL),
+            reqwest::header::HeaderValue::from_str(&self.config.base_url)?,
         );
 
-        self.client.get_with_headers(&url, headers).await?;
+        // Only the session cookie matters, which comes with redirects and
+        // 403s too
+        self.client
+            .get_accepting(&url, headers, StatusPolicy::NotServerError)
+            .await?;
         self.handshake_url = Some(url);
         Ok(())
     }
 
+    /// A MAC address as it may appear in the logs
+    fn log_mac(&self, mac: &str) -> String {
+        if self.config.settings.privacy.redact_mac {
+            crate::utils::redact_mac(mac)
+        } else {
+            mac.to_string()
+        }
+    }
+
     /// Helper to create headers with Referer and Origin for API requests
     fn api_headers(&self) -> Result<reqwest::header::HeaderMap> {
         let mut headers = reqwest::header::HeaderMap::new();
@@ -112,7 +645,7 @@ impl AwingPortal {
 
         headers.insert(
             reqwest::header::ORIGIN,
-            reqwest::header::HeaderValue::from_static(BASE_URL),
+            reqwest::header::HeaderValue::from_str(&self.config.base_url)?,
         );
 
         Ok(headers)
@@ -126,7 +659,7 @@ impl AwingPortal {
         let resp = self
             .client
             .post_json_with_headers(
-                &format!("{}/Home/VerifyUrl", BASE_URL),
+                &format!("{}/Home/VerifyUrl", self.config.base_url),
                 &serde_json::json!({}),
                 headers,
             )
@@ -136,13 +669,19 @@ impl AwingPortal {
         Ok(context)
     }
 
-    /// Step 3: Get Credentials - Extract login credentials from form
-    async fn get_credentials(&self, context: &serde_json::Value) -> Result<Credentials> {
+    /// Step 3: Get Credentials - Extract the authentication form
+    ///
+    /// Also returns the raw GetCustomer response, which carries the ad
+    /// campaign.
+    async fn get_credentials(
+        &self,
+        context: &serde_json::Value,
+    ) -> Result<(ParsedForm, serde_json::Value)> {
         tracing::info!("[{}] Step 3: Getting Credentials...", self.config.name);
 
         let mut payload = serde_json::json!({
             "captiveContextDTO": context,
-            "customer": {"gender": 1, "name": ""},
+            "customer": {"gender": 1, "name": self.config.settings.identity.customer_name},
             "customerRequiredFields": []
         });
 
@@ -159,24 +698,51 @@ impl AwingPortal {
         let resp = self
             .client
             .post_json_with_headers(
-                &format!("{}/Content/GetCustomer", BASE_URL),
+                &format!("{}/Content/GetCustomer", self.config.base_url),
                 &payload,
                 headers,
             )
             .await?;
 
-        let data: CustomerResponse = resp.json().await?;
+        let raw: serde_json::Value = resp.json().await?;
+        let data: CustomerResponse = serde_json::from_value(raw.clone())?;
 
-        let form_html = data
-            .captive_context
-            .as_ref()
-            .and_then(|c| c.content_authen_form.as_ref())
-            .or(data.content_authen_form.as_ref())
-            .ok_or_else(|| anyhow::anyhow!("contentAuthenForm not found in response"))?;
+        let form_html = authen_form(&data).ok_or(ParseError::NotInResponse("contentAuthenForm"))?;
 
-        let creds = parser::parse_credentials(form_html)?;
+        let form = parser::parse_form(form_html);
+        let creds = form
+            .credentials()
+            .ok_or(ParseError::NotInForm("username/password"))?;
         tracing::info!("   -> Got credentials for: {}", creds.username);
-        Ok(creds)
+        Ok((form, raw))
+    }
+
+    /// Step 3b: View Ad - Load the campaign assets and wait out the
+    /// advertised view time, as the splash page does in a browser
+    async fn view_ad(&self, customer: &serde_json::Value) -> Result<()> {
+        tracing::info!("[{}] Step 3b: Viewing Ad...", self.config.name);
+        let started = Instant::now();
+
+        let mut assets = Vec::new();
+        let mut duration = None;
+        scan_campaign(customer, &mut assets, &mut duration);
+        let duration = duration.unwrap_or(DEFAULT_AD_VIEW).min(MAX_AD_VIEW);
+
+        for asset in &assets {
+            if let Err(e) = self.client.get(asset).await {
+                tracing::debug!("   -> Failed to load ad asset {}: {:#}", asset, e);
+            }
+        }
+
+        tracing::info!(
+            "   -> Loaded {} asset(s), waiting {:?}",
+            assets.len(),
+            duration
+        );
+        if let Some(remaining) = duration.checked_sub(started.elapsed()) {
+            tokio::time::sleep(remaining).await;
+        }
+        Ok(())
     }
 
     /// Step 4: Send Analytics
@@ -191,33 +757,187 @@ impl AwingPortal {
 
         let headers = self.api_headers()?;
         self.client
-            .post_json_with_headers(&format!("{}/Analytic/Send", BASE_URL), &payload, headers)
+            .post_json_with_headers(
+                &format!("{}/Analytic/Send", self.config.base_url),
+                &payload,
+                headers,
+            )
             .await?;
 
         Ok(())
     }
 
-    /// Step 5: Login to Router - Submit credentials to gateway
-    async fn login_router(&self, creds: &Credentials) -> Result<()> {
+    /// Step 5: Login to Router - Submit the authentication form to gateway
+    ///
+    /// The form is submitted as declared (action, method, every field); only
+    /// `dst` and `popup` are filled in when the venue's form omits them, or
+    /// forced by the venue's quirks.
+    async fn login_router(&self, form: &ParsedForm) -> Result<()> {
         let gw = self.gateway.as_ref().context("Gateway not scanned")?;
         tracing::info!("[{}] Step 5: Logging into Router...", self.config.name);
 
-        let login_url = if gw.link_login_only.is_empty() {
-            "http://free.wi-mesh.vn/login".to_string()
+        let gateway_login = if gw.link_login_only.is_empty() {
+            DEFAULT_ROUTER_LOGIN_URL
         } else {
-            gw.link_login_only.clone()
+            gw.link_login_only.as_str()
         };
+        let login_url = reqwest::Url::parse(gateway_login)
+            .and_then(|base| base.join(&form.action))
+            .with_context(|| format!("Invalid form action '{}'", form.action))?;
 
-        let form = [
-            ("username", creds.username.as_str()),
-            ("password", creds.password.as_str()),
-            ("dst", &format!("{}/Success", BASE_URL)),
-            ("popup", "false"),
-        ];
+        let mut fields = form.fields.clone();
+        if self.config.has_quirk(Quirk::DstLinkOrig) {
+            fields.insert("dst", self.gateway_url());
+        } else if fields.get("dst").is_none() {
+            fields.insert("dst", &format!("{}/Success", self.config.base_url));
+        }
+        if self.config.has_quirk(Quirk::PopupTrue) {
+            fields.insert("popup", "true");
+        } else if fields.get("popup").is_none() {
+            fields.insert("popup", "false");
+        }
 
-        self.client.post_form(&login_url, &form).await?;
+        let page = if form.method == "get" {
+            let url = reqwest::Url::parse_with_params(login_url.as_str(), fields.as_pairs())?;
+            self.gateway_get(url.as_str()).await?
+        } else {
+            self.gateway_post_form(login_url.as_str(), fields.as_pairs())
+                .await?
+        };
+        // A refused login comes back as the login page with the reason in it
+        if let Some(reason) = self.config.phrases.find_in(Phrase::LoginRejected, &page) {
+            anyhow::bail!("Router rejected the login: {}", reason);
+        }
         Ok(())
     }
+
+    /// GET a gateway page, through the compat client if configured
+    async fn gateway_get(&self, url: &str) -> Result<String> {
+        if self.config.compat {
+            let client = CompatClient::new(&self.client.user_agent())
+                .with_interface(self.client.interface())
+                .with_proxy(self.client.proxy())?;
+            return Ok(client.get(url).await?.body);
+        }
+        self.client.get_text(url).await
+    }
+
+    /// POST a form to the gateway, through the compat client if configured;
+    /// returns the page it answers with
+    async fn gateway_post_form(&self, url: &str, form: &[(String, String)]) -> Result<String> {
+        if self.config.compat {
+            let client = CompatClient::new(&self.client.user_agent())
+                .with_interface(self.client.interface())
+                .with_proxy(self.client.proxy())?;
+            return Ok(client.post_form(url, form).await?.body);
+        }
+        self.client.post_form_text(url, form).await
+    }
+}
+
+/// Collect campaign asset URLs and the advertised view duration from
+/// anywhere in a GetCustomer response; venues nest them differently
+fn scan_campaign(
+    value: &serde_json::Value,
+    assets: &mut Vec<String>,
+    duration: &mut Option<Duration>,
+) {
+    match value {
+        serde_json::Value::Object(obj) => {
+            for (key, value) in obj {
+                if duration.is_none() && AD_DURATION_KEYS.contains(&key.as_str()) {
+                    *duration = value
+                        .as_u64()
+                        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+                        .filter(|&secs| secs > 0)
+                        .map(Duration::from_secs);
+                }
+                scan_campaign(value, assets, duration);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                scan_campaign(item, assets, duration);
+            }
+        }
+        serde_json::Value::String(s) => {
+            let lower = s.to_ascii_lowercase();
+            let path = lower.split(['?', '#']).next().unwrap_or_default();
+            if lower.starts_with("http")
+                && AD_ASSET_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+                && !assets.contains(s)
+            {
+                assets.push(s.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The authentication form HTML, wherever this venue's response puts it
+fn authen_form(data: &CustomerResponse) -> Option<&String> {
+    data.captive_context
+        .as_ref()
+        .and_then(|c| c.content_authen_form.as_ref())
+        .or(data.content_authen_form.as_ref())
+}
+
+fn push_gateway(fields: &mut Inspection, gw: &GatewayConfig) {
+    fields.push(("gateway.mac".into(), gw.mac.clone()));
+    fields.push(("gateway.ip".into(), gw.ip.clone()));
+    fields.push(("gateway.chap_id".into(), gw.chap_id.clone()));
+    fields.push(("gateway.chap_challenge".into(), gw.chap_challenge.clone()));
+    fields.push(("gateway.link_login_only".into(), gw.link_login_only.clone()));
+}
+
+fn push_context(fields: &mut Inspection, context: &serde_json::Value) {
+    if let Some(obj) = context.as_object() {
+        for (key, value) in obj {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            fields.push((format!("context.{}", key), value));
+        }
+    }
+}
+
+fn push_form(fields: &mut Inspection, form: &ParsedForm) {
+    fields.push(("form.action".into(), form.action.clone()));
+    fields.push(("form.method".into(), form.method.clone()));
+    for (name, value) in form.fields.iter() {
+        fields.push((format!("form.{}", name), value.to_string()));
+    }
+}
+
+/// Run the offline parser stages against a saved gateway page, auth form,
+/// or VerifyUrl/GetCustomer JSON response
+fn inspect_fixture(content: &str) -> Result<Inspection> {
+    let mut fields = Inspection::new();
+
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(content) {
+        push_context(&mut fields, &json);
+        let data: CustomerResponse = serde_json::from_value(json)?;
+        if let Some(form_html) = authen_form(&data) {
+            push_form(&mut fields, &parser::parse_form(form_html));
+        }
+        return Ok(fields);
+    }
+
+    match parser::parse_gateway_html(content) {
+        Ok(gw) => push_gateway(&mut fields, &gw),
+        Err(gw_err) => {
+            let form = parser::parse_form(content);
+            if form.fields.is_empty() {
+                return Err(
+                    gw_err.context("Page is neither a gateway page nor an authentication form")
+                );
+            }
+            push_form(&mut fields, &form);
+        }
+    }
+
+    Ok(fields)
 }
 
 #[async_trait]
@@ -231,14 +951,145 @@ impl CaptivePortal for AwingPortal {
     }
 
     async fn connect(&mut self) -> Result<()> {
+        let mut steps = self.recorder();
+        let result = self.run_flow(&mut steps).await;
+        self.finish_flow(steps);
+        result
+    }
+
+    /// The client MAC the gateway reported, else the configured one
+    fn client_mac(&self) -> Option<String> {
+        self.gateway
+            .as_ref()
+            .map(|gw| gw.mac.clone())
+            .filter(|mac| !mac.is_empty())
+            .or_else(|| {
+                Some(self.config.settings.identity.mac_address.clone())
+                    .filter(|mac| !mac.is_empty())
+            })
+    }
+
+    fn login_url(&self) -> Option<String> {
+        self.gateway
+            .as_ref()
+            .map(|gw| gw.link_login_only.clone())
+            .filter(|url| !url.is_empty())
+    }
+
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        if self.client.interface() != interface {
+            let proxy = self.client.proxy().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface, proxy.as_deref(), &self.har)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let interface = self.client.interface().map(str::to_string);
+        let proxy = self.client.proxy().map(str::to_string);
+        self.client = Self::http_client(
+            &self.config,
+            interface.as_deref(),
+            proxy.as_deref(),
+            &self.har,
+        )?;
+        self.gateway = None;
+        self.handshake_url = None;
+        self.last_form = None;
+        self.venue = None;
+        Ok(())
+    }
+
+    /// Through a proxy the default gateway here is not the captive one, so
+    /// captive DNS is skipped; the proxy resolves names on its side
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        if self.client.proxy() != proxy {
+            let interface = self.client.interface().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface.as_deref(), proxy, &self.har)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> PortalCapabilities {
+        CAPABILITIES
+    }
+
+    /// Wi-MESH venues run MikroTik hotspots, whose status page next to the
+    /// router login reports traffic and time left
+    async fn session_info(&self) -> Result<Option<SessionInfo>> {
+        if self.gateway.is_none() {
+            return Ok(None);
+        }
+        let status_url = reqwest::Url::parse(self.router_login_url())?.join("status")?;
+
+        let html = self.gateway_get(status_url.as_str()).await?;
+        let mut info =
+            parser::parse_mikrotik_status(&html, crate::state::unix_now(), &self.config.phrases)?;
+        info.venue = self.venue.clone();
+        Ok(Some(info))
+    }
+
+    /// The hotspot logs out whichever client requests its logout page, so
+    /// this works without the session of the login
+    async fn logout(&mut self) -> Result<()> {
+        let logout_url = reqwest::Url::parse(self.router_login_url())?.join("logout")?;
+        self.gateway_get(logout_url.as_str()).await?;
+        tracing::info!("Logged out at {}", logout_url);
+        Ok(())
+    }
+
+    fn last_steps(&self) -> &[StepReport] {
+        &self.last_steps
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        let mut endpoints = vec![
+            self.gateway_url().to_string(),
+            self.config.base_url.clone(),
+        ];
+        match self.gateway {
+            Some(ref gw) if !gw.link_login_only.is_empty() => {
+                endpoints.push(gw.link_login_only.clone())
+            }
+            _ => endpoints.push(DEFAULT_ROUTER_LOGIN_URL.to_string()),
+        }
+        endpoints
+    }
+
+    fn session(&self) -> Option<serde_json::Value> {
+        let session = AwingSession {
+            gateway: self.gateway.clone()?,
+            form: self.last_form.clone()?,
+        };
+        serde_json::to_value(session).ok()
+    }
+
+    async fn resume(&mut self, session: &serde_json::Value) -> Result<()> {
+        let session: AwingSession = serde_json::from_value(session.clone())
+            .context("Cached session is not an Awing session")?;
+
+        let mut steps = self.recorder();
+        let result = steps.run("resume", self.resume_session(session)).await;
+        self.finish_flow(steps);
+        result
+    }
+
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        if let Some(content) = fixture {
+            return inspect_fixture(content);
+        }
+
+        let mut fields = Inspection::new();
         self.scan_gateway().await?;
+        if let Some(ref gw) = self.gateway {
+            push_gateway(&mut fields, gw);
+        }
         self.handshake().await?;
         let context = self.verify_device().await?;
-        let creds = self.get_credentials(&context).await?;
-        self.send_analytics(&context).await?;
-        self.login_router(&creds).await?;
+        push_context(&mut fields, &context);
+        let (form, _) = self.get_credentials(&context).await?;
+        push_form(&mut fields, &form);
 
-        tracing::info!("[{}] Connected successfully!", self.config.name);
-        Ok(())
+        Ok(fields)
     }
 }
@@ -0,0 +1,301 @@
+//! Step bookkeeping for portal login flows
+//!
+//! Portals run their flow through a `StepRecorder`, which times each step
+//! and keeps a report of how far the flow got, for `--output json` and the
+//! status commands. Concerns shared by every portal (delays, rate limits,
+//! capture, redaction) hook in as `StepMiddleware`s around each step; the
+//! built-in ones are in `middleware`. One more, set with `observe`, runs
+//! outside those of every flow, for the interactive progress display.
+//!
+//! Steps are critical unless run with `run_soft`: the login does without
+//! a non-critical one (analytics, say), so its failure is only warned
+//! about, and the flow carries on to a partial success (`soft_failures`).
+//!
+//! With a time budget (`with_budget`), each step gets what is left of it as
+//! its timeout, and the flow stops with `BudgetExceeded` once it is gone,
+//! instead of stacking every request's timeout and retries.
+//!
+//! Each step runs in a `step` span named after it, for the traces of
+//! `telemetry` and the `step` of JSON log lines.
+
+use crate::congestion::{self, Congestion};
+use crate::policy::{Attempt, RetryPolicy};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+/// Timing and outcome of one step of a login flow
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    pub name: &'static str,
+    pub duration_ms: u64,
+    pub ok: bool,
+    /// Whether the flow stops when the step fails
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A login flow ran out of its time budget (`policy.flow_budget`)
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "Login flow ran out of its {:.1}s budget in step {step} (slowest step: {slowest}, {:.1}s)",
+    .budget.as_secs_f64(),
+    .slowest_took.as_secs_f64()
+)]
+pub struct BudgetExceeded {
+    pub budget: Duration,
+    /// The step running, or about to, when the budget ran out
+    pub step: &'static str,
+    /// The step that used the most of it
+    pub slowest: &'static str,
+    pub slowest_took: Duration,
+}
+
+/// Middleware run around the steps of every flow, outside its own
+static OBSERVER: Mutex<Option<Arc<dyn StepMiddleware>>> = Mutex::new(None);
+
+/// Run `observer` around every step of every flow from now on, or stop
+pub fn observe(observer: Option<Arc<dyn StepMiddleware>>) {
+    *OBSERVER.lock().unwrap_or_else(|e| e.into_inner()) = observer;
+}
+
+/// Names of the non-critical steps of `steps` that failed
+pub fn soft_failures(steps: &[StepReport]) -> Vec<&'static str> {
+    steps
+        .iter()
+        .filter(|s| !s.ok && !s.critical)
+        .map(|s| s.name)
+        .collect()
+}
+
+/// Hook run around every step of a flow
+///
+/// `before` hooks run in the order the middlewares were added, `after`
+/// hooks in reverse order, so the first middleware wraps all the others.
+#[async_trait]
+pub trait StepMiddleware: Send + Sync {
+    /// Called before `step` starts; may wait
+    async fn before(&self, step: &'static str) {
+        let _ = step;
+    }
+
+    /// Called once the step finished, with its report, which it may amend
+    async fn after(&self, report: &mut StepReport) {
+        let _ = report;
+    }
+}
+
+/// Collects a `StepReport` for every step run through it
+#[derive(Default)]
+pub struct StepRecorder {
+    steps: Vec<StepReport>,
+    middlewares: Vec<Arc<dyn StepMiddleware>>,
+    /// The whole budget, and when it runs out
+    budget: Option<(Duration, Instant)>,
+}
+
+impl StepRecorder {
+    /// Give the following steps `budget` in all, from now; stretched like
+    /// the request timeouts while the portal is congested
+    pub fn with_budget(mut self, budget: Option<Duration>) -> Self {
+        self.budget = budget.map(|budget| {
+            let budget = if Congestion::global().is_active() {
+                budget * congestion::TIMEOUT_FACTOR
+            } else {
+                budget
+            };
+            (budget, Instant::now() + budget)
+        });
+        self
+    }
+
+    /// Run `middleware` around every following step
+    pub fn with(mut self, middleware: Arc<dyn StepMiddleware>) -> Self {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    /// Run one step, recording its duration and outcome
+    pub async fn run<T, F>(&mut self, name: &'static str, step: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        self.record(name, true, step).await
+    }
+
+    /// Run one non-critical step: a failure is recorded and warned about,
+    /// and the flow goes on without its result
+    pub async fn run_soft<T, F>(&mut self, name: &'static str, step: F) -> Option<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        match self.record(name, false, step).await {
+            Ok(value) => Some(value),
+            Err(e) => {
+                tracing::warn!("Step {} failed, carrying on without it: {:#}", name, e);
+                None
+            }
+        }
+    }
+
+    async fn record<T, F>(&mut self, name: &'static str, critical: bool, step: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let observer = OBSERVER.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        if let Some(observer) = &observer {
+            observer.before(name).await;
+        }
+        for middleware in &self.middlewares {
+            middleware.before(name).await;
+        }
+
+        let span = tracing::info_span!(
+            "step",
+            otel.name = name,
+            step = name,
+            critical,
+            error = tracing::field::Empty
+        );
+        let step = step.instrument(span.clone());
+        let started = Instant::now();
+        let result = match self.budget {
+            None => step.await,
+            Some((_, deadline)) => {
+                let left = deadline.saturating_duration_since(started);
+                let result = if left.is_zero() {
+                    None
+                } else {
+                    tokio::time::timeout(left, step).await.ok()
+                };
+                match result {
+                    Some(result) => result,
+                    None => Err(self.exceeded(name, started.elapsed()).into()),
+                }
+            }
+        };
+
+        if let Err(e) = &result {
+            span.record("error", format!("{:#}", e));
+        }
+        let mut report = StepReport {
+            name,
+            duration_ms: started.elapsed().as_millis() as u64,
+            ok: result.is_ok(),
+            critical,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        for middleware in self.middlewares.iter().rev() {
+            middleware.after(&mut report).await;
+        }
+        if let Some(observer) = &observer {
+            observer.after(&mut report).await;
+        }
+        self.steps.push(report);
+
+        result
+    }
+
+    /// Run one step like `run`, trying it again as `policy` says; `step`
+    /// starts a fresh attempt on `state` every time
+    pub async fn run_retrying<S, T, F>(
+        &mut self,
+        name: &'static str,
+        policy: &RetryPolicy,
+        state: &mut S,
+        step: F,
+    ) -> Result<T>
+    where
+        S: ?Sized,
+        F: for<'a> Fn(&'a mut S) -> Attempt<'a, T>,
+    {
+        let what = format!("Step {}", name);
+        self.run(name, policy.retry(&what, state, step)).await
+    }
+
+    /// Run one non-critical step like `run_soft`, retrying it like
+    /// `run_retrying`
+    pub async fn run_retrying_soft<S, T, F>(
+        &mut self,
+        name: &'static str,
+        policy: &RetryPolicy,
+        state: &mut S,
+        step: F,
+    ) -> Option<T>
+    where
+        S: ?Sized,
+        F: for<'a> Fn(&'a mut S) -> Attempt<'a, T>,
+    {
+        let what = format!("Step {}", name);
+        self.run_soft(name, policy.retry(&what, state, step)).await
+    }
+
+    /// The error for a budget that ran out in `step`, `took` into it
+    fn exceeded(&self, step: &'static str, took: Duration) -> BudgetExceeded {
+        let (slowest, slowest_took) = self
+            .steps
+            .iter()
+            .map(|s| (s.name, Duration::from_millis(s.duration_ms)))
+            .chain([(step, took)])
+            .max_by_key(|(_, took)| *took)
+            .unwrap_or((step, took));
+        BudgetExceeded {
+            budget: self.budget.map(|(budget, _)| budget).unwrap_or_default(),
+            step,
+            slowest,
+            slowest_took,
+        }
+    }
+
+    pub fn finish(self) -> Vec<StepReport> {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_soft_step_failure() {
+        let mut steps = StepRecorder::default();
+        let _ = steps.run("first", async { Ok(()) }).await;
+        let skipped: Option<()> = steps
+            .run_soft("analytics", async { anyhow::bail!("HTTP 500") })
+            .await;
+        assert!(skipped.is_none());
+        let _ = steps.run("login", async { Ok(()) }).await;
+
+        let reports = steps.finish();
+        assert_eq!(reports.len(), 3);
+        assert!(!reports[1].critical && !reports[1].ok);
+        assert_eq!(soft_failures(&reports), vec!["analytics"]);
+    }
+
+    #[tokio::test]
+    async fn test_flow_budget() {
+        let mut steps = StepRecorder::default().with_budget(Some(Duration::from_millis(100)));
+        let _ = steps.run("quick", async { Ok(()) }).await;
+        let slow = steps
+            .run("slow", async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await;
+        let e = slow.unwrap_err();
+        let exceeded = e.downcast_ref::<BudgetExceeded>().unwrap();
+        assert_eq!((exceeded.step, exceeded.slowest), ("slow", "slow"));
+        assert!(exceeded.slowest_took < Duration::from_secs(1));
+
+        // Nothing left for the next one
+        let next = steps.run("next", async { Ok(()) }).await.unwrap_err();
+        let exceeded = next.downcast_ref::<BudgetExceeded>().unwrap();
+        assert_eq!((exceeded.step, exceeded.slowest), ("next", "slow"));
+        assert_eq!(steps.finish().len(), 3);
+    }
+}
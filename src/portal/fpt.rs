@@ -0,0 +1,286 @@
+//! FPT Telecom public Wi-Fi implementation
+//!
+//! FPT hotspots hold traffic behind a splash page (reached through
+//! wifi.fpt.vn) that asks the visitor to accept the terms and press
+//! connect. This portal opens that page the way a phone's captive-portal
+//! browser would, submits its form with whatever the venue pre-filled plus
+//! the configured `fpt.fields`, and checks that traffic flows. The form is
+//! read from the page every time rather than hardcoded, so venues that
+//! customise the splash keep working as long as it is a single form.
+
+use crate::config::PortalConfig;
+use crate::http::HttpClient;
+use crate::models::ParsedForm;
+use crate::parser::{self, ParseError};
+use crate::portal::middleware::{RedactMacs, TimingLog};
+use crate::portal::{
+    CaptivePortal, Inspection, PortalCapabilities, PortalSettings, StepRecorder, StepReport,
+};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+const DEFAULT_SPLASH_URL: &str = "http://wifi.fpt.vn";
+/// Meta refreshes followed before the splash form, which some venues put
+/// behind a "redirecting..." page
+const MAX_REFRESHES: usize = 3;
+/// Keys of the `fpt` table
+pub const SETTINGS: &[&str] = &["splash_url", "fields"];
+/// What `fpt` portals can do and need
+pub const CAPABILITIES: PortalCapabilities = PortalCapabilities {
+    supports_inspect: true,
+    needs_consent: true,
+    ..PortalCapabilities::NONE
+};
+
+/// Configuration for the FPT portal
+#[derive(Debug, Clone)]
+pub struct FptConfig {
+    /// Human-readable name for this portal instance
+    pub name: String,
+    /// SSIDs that this portal handles
+    pub ssids: Vec<String>,
+    /// Identity, privacy switches and retries
+    pub settings: PortalSettings,
+    /// Page the hotspot redirects to its splash
+    pub splash_url: String,
+    /// Form fields to fill in or override, e.g. a phone number
+    pub fields: Vec<(String, String)>,
+}
+
+impl Default for FptConfig {
+    fn default() -> Self {
+        Self {
+            name: "FPT Wi-Fi".to_string(),
+            ssids: vec!["FPT Telecom".to_string()],
+            settings: PortalSettings::default(),
+            splash_url: DEFAULT_SPLASH_URL.to_string(),
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl FptConfig {
+    /// Build from a `[[portals]]` entry; FPT-specific keys live in its
+    /// `fpt` table
+    pub fn from_config(portal: &PortalConfig, settings: PortalSettings) -> Self {
+        let mut fields = Vec::new();
+        for (name, value) in portal
+            .setting("fields")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+        {
+            match value {
+                toml::Value::String(value) => fields.push((name.clone(), value.clone())),
+                other => fields.push((name.clone(), other.to_string())),
+            }
+        }
+
+        Self {
+            name: portal.name.clone(),
+            ssids: portal.ssids.clone(),
+            settings,
+            splash_url: portal
+                .setting("splash_url")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .unwrap_or(DEFAULT_SPLASH_URL.to_string()),
+            fields,
+        }
+    }
+}
+
+/// The splash page as the hotspot served it
+#[derive(Debug, Clone)]
+struct Splash {
+    /// Where the page ended up after redirects, for relative actions
+    url: reqwest::Url,
+    form: ParsedForm,
+}
+
+/// FPT Telecom portal implementation
+pub struct FptPortal {
+    config: FptConfig,
+    client: HttpClient,
+    last_steps: Vec<StepReport>,
+}
+
+impl FptPortal {
+    pub fn new(config: FptConfig) -> Result<Self> {
+        let client = Self::http_client(&config, None, None)?;
+        Ok(Self {
+            config,
+            client,
+            last_steps: Vec::new(),
+        })
+    }
+
+    fn http_client(
+        config: &FptConfig,
+        interface: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Result<HttpClient> {
+        HttpClient::with_options(config.settings.http_options(interface, proxy))
+    }
+
+    fn recorder(&self) -> StepRecorder {
+        let mut steps = StepRecorder::default()
+            .with_budget(self.config.settings.flow_budget)
+            .with(TimingLog::new(&self.config.name));
+        if self.config.settings.privacy.redact_mac {
+            steps = steps.with(RedactMacs::new());
+        }
+        steps
+    }
+
+    async fn run_flow(&mut self, steps: &mut StepRecorder) -> Result<()> {
+        self.client.new_session();
+
+        let retry = self.config.settings.step_retry.clone();
+        let splash = steps
+            .run_retrying("open_splash", &retry, self, |p| Box::pin(p.open_splash()))
+            .await?;
+        steps
+            .run_retrying("submit_splash", &retry, self, |p| {
+                let splash = splash.clone();
+                Box::pin(async move { p.submit_splash(&splash).await })
+            })
+            .await?;
+        steps
+            .run("check_online", async {
+                if !crate::utils::nonblocking::has_internet_connectivity().await {
+                    anyhow::bail!("The splash was submitted but traffic is still held");
+                }
+                Ok(())
+            })
+            .await?;
+
+        tracing::info!("[{}] Connected successfully!", self.config.name);
+        Ok(())
+    }
+
+    /// Step 1: Open the splash page, following "redirecting..." pages
+    async fn open_splash(&self) -> Result<Splash> {
+        tracing::info!("[{}] Step 1: Opening the splash page...", self.config.name);
+
+        let mut url = self.config.splash_url.clone();
+        for _ in 0..=MAX_REFRESHES {
+            let (page, html) = self.client.get_page(&url).await?;
+            if let Some(next) = parser::parse_meta_refresh(&html) {
+                url = page.join(&next)?.to_string();
+                tracing::debug!("   -> Refreshes to {}", url);
+                continue;
+            }
+            if !html.to_ascii_lowercase().contains("<form") {
+                return Err(ParseError::NotFound("splash form").into());
+            }
+            let form = parser::parse_form(&html);
+            tracing::info!("   -> Found splash form at {}", page);
+            return Ok(Splash { url: page, form });
+        }
+        anyhow::bail!(
+            "More than {} refreshes before the splash form",
+            MAX_REFRESHES
+        )
+    }
+
+    /// Step 2: Accept the terms, submitting the form as the page declares it
+    async fn submit_splash(&self, splash: &Splash) -> Result<()> {
+        tracing::info!(
+            "[{}] Step 2: Submitting the splash form...",
+            self.config.name
+        );
+
+        let action = splash
+            .url
+            .join(&splash.form.action)
+            .context("Splash form action is not a URL")?;
+        let mut fields = splash.form.fields.clone();
+        for (name, value) in &self.config.fields {
+            fields.insert(name, value);
+        }
+
+        if splash.form.method == "get" {
+            let mut url = action;
+            url.query_pairs_mut().extend_pairs(fields.as_pairs());
+            self.client.get(url.as_str()).await?;
+        } else {
+            self.client
+                .post_form(action.as_str(), fields.as_pairs())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CaptivePortal for FptPortal {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn ssids(&self) -> &[String] {
+        &self.config.ssids
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let mut steps = self.recorder();
+        let result = self.run_flow(&mut steps).await;
+        self.last_steps = steps.finish();
+        result
+    }
+
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        if self.client.interface() != interface {
+            let proxy = self.client.proxy().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface, proxy.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let interface = self.client.interface().map(str::to_string);
+        let proxy = self.client.proxy().map(str::to_string);
+        self.client = Self::http_client(&self.config, interface.as_deref(), proxy.as_deref())?;
+        Ok(())
+    }
+
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        if self.client.proxy() != proxy {
+            let interface = self.client.interface().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface.as_deref(), proxy)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> PortalCapabilities {
+        CAPABILITIES
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        vec![self.config.splash_url.clone()]
+    }
+
+    fn client_mac(&self) -> Option<String> {
+        Some(self.config.settings.identity.mac_address.clone()).filter(|mac| !mac.is_empty())
+    }
+
+    fn last_steps(&self) -> &[StepReport] {
+        &self.last_steps
+    }
+
+    /// The splash form, from a saved page or the live hotspot
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        let form = match fixture {
+            Some(content) => parser::parse_form(content),
+            None => self.open_splash().await?.form,
+        };
+        let mut fields = Inspection::new();
+        fields.push(("form.action".into(), form.action.clone()));
+        fields.push(("form.method".into(), form.method.clone()));
+        for (name, value) in form.fields.as_pairs() {
+            fields.push((format!("form.{}", name), value.clone()));
+        }
+        Ok(fields)
+    }
+}
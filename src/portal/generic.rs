@@ -0,0 +1,398 @@
+//! Config-driven form portal
+//!
+//! Most hotel and campus portals are a page with one form: fetch it, fill
+//! in a room number or an access code, post it. `type = "generic"` does
+//! exactly that from the `generic` table of the entry, so a new portal of
+//! that kind needs a few lines of config instead of a module: the page to
+//! open, the fields to fill in (`{mac}` in a value is the portal's MAC),
+//! optionally where and how to submit, and what the answer must look like.
+//! Fields the page already carries, hidden tokens included, are sent along.
+
+use crate::config::PortalConfig;
+use crate::http::HttpClient;
+use crate::models::ParsedForm;
+use crate::parser;
+use crate::portal::middleware::{RedactMacs, TimingLog};
+use crate::portal::{
+    CaptivePortal, Inspection, PortalCapabilities, PortalSettings, StepRecorder, StepReport,
+};
+use crate::secrets::Secret;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+/// Keys of the `generic` table
+pub const SETTINGS: &[&str] = &["form_url", "action", "method", "fields", "success"];
+/// What `generic` portals can do and need
+pub const CAPABILITIES: PortalCapabilities = PortalCapabilities {
+    supports_inspect: true,
+    ..PortalCapabilities::NONE
+};
+
+/// What the answer to the submitted form must look like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Success {
+    /// Text the answer contains
+    pub contains: Option<String>,
+    /// Text the answer does not contain, e.g. "Invalid room number"
+    pub not_contains: Option<String>,
+    /// Text in the URL the answer ended up at after redirects
+    pub url_contains: Option<String>,
+    /// Check that traffic flows afterwards
+    pub online: bool,
+}
+
+impl Default for Success {
+    fn default() -> Self {
+        Self {
+            contains: None,
+            not_contains: None,
+            url_contains: None,
+            online: true,
+        }
+    }
+}
+
+impl Success {
+    fn from_value(value: Option<&toml::Value>) -> Self {
+        let text = |key: &str| {
+            value
+                .and_then(|v| v.get(key))
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            contains: text("contains"),
+            not_contains: text("not_contains"),
+            url_contains: text("url_contains"),
+            online: value
+                .and_then(|v| v.get("online"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+        }
+    }
+
+    /// Why the answer at `url` with `body` is not a success, if it is not
+    pub fn failure(&self, url: &str, body: &str) -> Option<String> {
+        if let Some(text) = &self.not_contains {
+            if body.contains(text.as_str()) {
+                return Some(format!("The portal answered with '{}'", text));
+            }
+        }
+        if let Some(text) = &self.contains {
+            if !body.contains(text.as_str()) {
+                return Some(format!("The portal's answer lacks '{}'", text));
+            }
+        }
+        if let Some(text) = &self.url_contains {
+            if !url.contains(text.as_str()) {
+                return Some(format!("The portal answered at {}, not at '{}'", url, text));
+            }
+        }
+        None
+    }
+}
+
+/// Configuration for the generic portal
+#[derive(Debug, Clone)]
+pub struct GenericConfig {
+    /// Human-readable name for this portal instance
+    pub name: String,
+    /// SSIDs that this portal handles
+    pub ssids: Vec<String>,
+    /// Identity, privacy switches and retries
+    pub settings: PortalSettings,
+    /// Page carrying the login form
+    pub form_url: String,
+    /// Where the form is submitted, instead of the form's own action
+    pub action: Option<String>,
+    /// "get" or "post", instead of the form's own method
+    pub method: Option<String>,
+    /// Form fields to fill in or override
+    pub fields: Vec<(String, Secret)>,
+    pub success: Success,
+}
+
+impl GenericConfig {
+    /// Build from a `[[portals]]` entry; the form lives in its `generic`
+    /// table
+    pub fn from_config(portal: &PortalConfig, settings: PortalSettings) -> Self {
+        let text = |key: &str| {
+            portal
+                .setting(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+        let mut fields = Vec::new();
+        for (name, value) in portal
+            .setting("fields")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+        {
+            let value = Secret::from_value(value).unwrap_or(Secret::Plain(value.to_string()));
+            fields.push((name.clone(), value));
+        }
+
+        Self {
+            name: portal.name.clone(),
+            ssids: portal.ssids.clone(),
+            settings,
+            form_url: text("form_url").unwrap_or_default(),
+            action: text("action"),
+            method: text("method").map(|m| m.to_ascii_lowercase()),
+            fields,
+            success: Success::from_value(portal.setting("success")),
+        }
+    }
+}
+
+/// The login page as the portal served it
+#[derive(Debug, Clone)]
+struct Page {
+    /// Where the page ended up after redirects, for relative actions
+    url: reqwest::Url,
+    form: ParsedForm,
+}
+
+/// Config-driven form portal implementation
+pub struct GenericPortal {
+    config: GenericConfig,
+    client: HttpClient,
+    last_steps: Vec<StepReport>,
+}
+
+impl GenericPortal {
+    pub fn new(config: GenericConfig) -> Result<Self> {
+        if config.form_url.is_empty() {
+            anyhow::bail!("Portal '{}' has no generic.form_url", config.name);
+        }
+        if let Some(method) = config
+            .method
+            .as_deref()
+            .filter(|m| !["get", "post"].contains(m))
+        {
+            anyhow::bail!(
+                "Portal '{}': generic.method is '{}', not get or post",
+                config.name,
+                method
+            );
+        }
+        let client = Self::http_client(&config, None, None)?;
+        Ok(Self {
+            config,
+            client,
+            last_steps: Vec::new(),
+        })
+    }
+
+    fn http_client(
+        config: &GenericConfig,
+        interface: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Result<HttpClient> {
+        HttpClient::with_options(config.settings.http_options(interface, proxy))
+    }
+
+    fn recorder(&self) -> StepRecorder {
+        let mut steps = StepRecorder::default()
+            .with_budget(self.config.settings.flow_budget)
+            .with(TimingLog::new(&self.config.name));
+        if self.config.settings.privacy.redact_mac {
+            steps = steps.with(RedactMacs::new());
+        }
+        steps
+    }
+
+    async fn run_flow(&mut self, steps: &mut StepRecorder) -> Result<()> {
+        self.client.new_session();
+
+        let retry = self.config.settings.step_retry.clone();
+        let page = steps
+            .run_retrying("open_form", &retry, self, |p| Box::pin(p.open_form()))
+            .await?;
+        steps
+            .run_retrying("submit_form", &retry, self, |p| {
+                let page = page.clone();
+                Box::pin(async move { p.submit_form(&page).await })
+            })
+            .await?;
+        if self.config.success.online {
+            steps
+                .run("check_online", async {
+                    if !crate::utils::nonblocking::has_internet_connectivity().await {
+                        anyhow::bail!("The form was accepted but traffic is still held");
+                    }
+                    Ok(())
+                })
+                .await?;
+        }
+
+        tracing::info!("[{}] Connected successfully!", self.config.name);
+        Ok(())
+    }
+
+    /// Step 1: Open the page carrying the form
+    async fn open_form(&self) -> Result<Page> {
+        tracing::info!("[{}] Step 1: Opening the login form...", self.config.name);
+
+        let (url, html) = self.client.get_page(&self.config.form_url).await?;
+        let form = parser::parse_form(&html);
+        tracing::info!(
+            "   -> Found a form with {} field(s) at {}",
+            form.fields.as_pairs().len(),
+            url
+        );
+        Ok(Page { url, form })
+    }
+
+    /// Step 2: Submit the form with the configured fields, and check the
+    /// answer against `generic.success`
+    async fn submit_form(&self, page: &Page) -> Result<()> {
+        tracing::info!(
+            "[{}] Step 2: Submitting the login form...",
+            self.config.name
+        );
+
+        let action = self.config.action.as_deref().unwrap_or(&page.form.action);
+        let action = page
+            .url
+            .join(action)
+            .context("Login form action is not a URL")?;
+        let mut fields = page.form.fields.clone();
+        let mac = match self.config.settings.identity.mac_address.as_str() {
+            "" => crate::utils::nonblocking::get_interface_mac(self.client.interface())
+                .await
+                .unwrap_or_default(),
+            mac => mac.to_string(),
+        };
+        for (name, value) in &self.config.fields {
+            fields.insert(name, &value.reveal().await?.replace("{mac}", &mac));
+        }
+
+        let method = self.config.method.as_deref().unwrap_or(&page.form.method);
+        let resp = if method == "get" {
+            let mut url = action;
+            url.query_pairs_mut().extend_pairs(fields.as_pairs());
+            self.client.get(url.as_str()).await?
+        } else {
+            self.client
+                .post_form(action.as_str(), fields.as_pairs())
+                .await?
+        };
+        let url = resp.url().to_string();
+        let body = HttpClient::text(resp).await?;
+        if let Some(failure) = self.config.success.failure(&url, &body) {
+            anyhow::bail!(failure);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CaptivePortal for GenericPortal {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn ssids(&self) -> &[String] {
+        &self.config.ssids
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let mut steps = self.recorder();
+        let result = self.run_flow(&mut steps).await;
+        self.last_steps = steps.finish();
+        result
+    }
+
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        if self.client.interface() != interface {
+            let proxy = self.client.proxy().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface, proxy.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let interface = self.client.interface().map(str::to_string);
+        let proxy = self.client.proxy().map(str::to_string);
+        self.client = Self::http_client(&self.config, interface.as_deref(), proxy.as_deref())?;
+        Ok(())
+    }
+
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        if self.client.proxy() != proxy {
+            let interface = self.client.interface().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface.as_deref(), proxy)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> PortalCapabilities {
+        CAPABILITIES
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        let mut endpoints = vec![self.config.form_url.clone()];
+        endpoints.extend(self.config.action.clone());
+        endpoints
+    }
+
+    fn client_mac(&self) -> Option<String> {
+        Some(self.config.settings.identity.mac_address.clone()).filter(|mac| !mac.is_empty())
+    }
+
+    fn last_steps(&self) -> &[StepReport] {
+        &self.last_steps
+    }
+
+    /// The login form and what would be submitted, from a saved page or
+    /// the live portal
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        let form = match fixture {
+            Some(content) => parser::parse_form(content),
+            None => self.open_form().await?.form,
+        };
+        let mut fields = Inspection::new();
+        let action = self.config.action.as_ref().unwrap_or(&form.action);
+        let method = self.config.method.as_ref().unwrap_or(&form.method);
+        fields.push(("form.action".into(), action.clone()));
+        fields.push(("form.method".into(), method.clone()));
+        for (name, value) in form.fields.as_pairs() {
+            fields.push((format!("form.{}", name), value.clone()));
+        }
+        for (name, value) in &self.config.fields {
+            fields.push((format!("config.{}", name), value.to_string()));
+        }
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_success() {
+        let table: toml::Value =
+            toml::from_str("contains = \"Welcome\"\nnot_contains = \"Invalid\"\nonline = false")
+                .unwrap();
+        let success = Success::from_value(Some(&table));
+        assert!(!success.online);
+        assert_eq!(success.failure("http://hotel/", "Welcome, room 204"), None);
+        assert!(success
+            .failure("http://hotel/", "Welcome. Invalid room number")
+            .is_some());
+        assert!(success.failure("http://hotel/", "Try again").is_some());
+
+        let success = Success {
+            url_contains: Some("/connected".to_string()),
+            ..Success::default()
+        };
+        assert_eq!(success.failure("http://hotel/connected?x=1", ""), None);
+        assert!(success.failure("http://hotel/login", "").is_some());
+        assert!(Success::from_value(None).online);
+    }
+}
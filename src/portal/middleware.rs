@@ -0,0 +1,183 @@
+//! Built-in step middlewares
+//!
+//! Each wraps the steps of a `StepRecorder` with one cross-cutting
+//! behaviour, so portal implementations assemble them instead of sprinkling
+//! sleeps and log lines through their flows.
+
+use super::flow::{StepMiddleware, StepReport};
+use crate::har::HarLog;
+use async_trait::async_trait;
+use regex::Regex;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Logs how long each step took
+pub struct TimingLog {
+    portal: String,
+}
+
+impl TimingLog {
+    pub fn new(portal: &str) -> Arc<Self> {
+        Arc::new(Self {
+            portal: portal.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for TimingLog {
+    async fn after(&self, report: &mut StepReport) {
+        tracing::debug!(
+            "[{}] {} {} in {}ms",
+            self.portal,
+            report.name,
+            if report.ok { "done" } else { "failed" },
+            report.duration_ms
+        );
+    }
+}
+
+/// Pauses before steps: between all of them, or before one in particular
+pub struct StepDelay {
+    delay: Duration,
+    /// Only before this step; `None` means before every step but the first
+    step: Option<&'static str>,
+    started: Mutex<bool>,
+}
+
+impl StepDelay {
+    /// Pause before every step except the first
+    pub fn between_steps(delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            delay,
+            step: None,
+            started: Mutex::new(false),
+        })
+    }
+
+    /// Pause before `step` only
+    pub fn before(step: &'static str, delay: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            delay,
+            step: Some(step),
+            started: Mutex::new(false),
+        })
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for StepDelay {
+    async fn before(&self, step: &'static str) {
+        let wait = match self.step {
+            Some(only) => only == step,
+            None => std::mem::replace(
+                &mut *self.started.lock().unwrap_or_else(|e| e.into_inner()),
+                true,
+            ),
+        };
+        if wait {
+            tokio::time::sleep(self.delay).await;
+        }
+    }
+}
+
+/// Keeps step starts at least `interval` apart, however long the steps take
+pub struct RateLimit {
+    interval: Duration,
+    last_start: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            interval,
+            last_start: Mutex::new(None),
+        })
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for RateLimit {
+    async fn before(&self, _step: &'static str) {
+        let last = *self.last_start.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(wait) = last.and_then(|at| self.interval.checked_sub(at.elapsed())) {
+            tokio::time::sleep(wait).await;
+        }
+        *self.last_start.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+}
+
+/// Groups the requests a `HarLog` records by step
+pub struct HarPages {
+    log: Arc<HarLog>,
+}
+
+impl HarPages {
+    pub fn new(log: Arc<HarLog>) -> Arc<Self> {
+        Arc::new(Self { log })
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for HarPages {
+    async fn before(&self, step: &'static str) {
+        self.log.start_page(step);
+    }
+}
+
+/// Masks MAC addresses in step errors, which quote portal URLs and
+/// responses carrying the device's MAC
+pub struct RedactMacs;
+
+impl RedactMacs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for RedactMacs {
+    async fn after(&self, report: &mut StepReport) {
+        static MAC: OnceLock<Regex> = OnceLock::new();
+        let mac = MAC.get_or_init(|| {
+            Regex::new(r"(?i)\b[0-9a-f]{2}(?:(?::|%3A)[0-9a-f]{2}){5}\b").expect("valid regex")
+        });
+        if let Some(ref mut error) = report.error {
+            *error = mac
+                .replace_all(error, |caps: &regex::Captures| {
+                    crate::utils::redact_mac(&caps[0].replace("%3A", ":").replace("%3a", ":"))
+                })
+                .into_owned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portal::StepRecorder;
+
+    #[tokio::test]
+    async fn test_step_delay_and_mac_redaction() {
+        let delay = Duration::from_millis(30);
+        let mut steps = StepRecorder::default()
+            .with(StepDelay::between_steps(delay))
+            .with(RedactMacs::new());
+
+        let started = Instant::now();
+        let _ = steps.run("first", async { Ok(()) }).await;
+        assert!(started.elapsed() < delay);
+        let _: anyhow::Result<()> = steps
+            .run("second", async {
+                anyhow::bail!("GET /login?mac=AA%3ABB%3ACC%3ADD%3AEE%3AFF failed")
+            })
+            .await;
+        assert!(started.elapsed() >= delay);
+
+        let reports = steps.finish();
+        assert_eq!(
+            reports[1].error.as_deref(),
+            Some("GET /login?mac=**:**:**:**:**:FF failed")
+        );
+    }
+}
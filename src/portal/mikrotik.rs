@@ -0,0 +1,522 @@
+//! MikroTik hotspot implementation
+//!
+//! Behind the Awing pages, Wi-MESH ends at a MikroTik `link-login-only`
+//! endpoint; plenty of cafés and dorms expose that hotspot directly. This
+//! portal opens the hotspot's login page and submits the configured
+//! account to it the way the stock template's JavaScript does: when the
+//! page offers a CHAP challenge, the password field carries
+//! `hex(md5(chap_id + password + chap_challenge))`, computed here, never the
+//! password itself. Hotspots set up for plain (PAP) logins only get the
+//! password in the clear with `mikrotik.plaintext = true`.
+
+use crate::config::PortalConfig;
+use crate::http::HttpClient;
+use crate::models::{ParsedForm, SessionInfo};
+use crate::parser;
+use crate::phrases::{Phrase, PhraseTable};
+use crate::portal::middleware::{RedactMacs, TimingLog};
+use crate::portal::{
+    CaptivePortal, Inspection, PortalCapabilities, PortalSettings, StepRecorder, StepReport,
+};
+use crate::secrets::Secret;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+
+/// Keys of the `mikrotik` table
+pub const SETTINGS: &[&str] = &[
+    "login_url",
+    "username",
+    "password",
+    "dst",
+    "plaintext",
+    "phrases",
+];
+/// What `mikrotik` portals can do and need
+pub const CAPABILITIES: PortalCapabilities = PortalCapabilities {
+    supports_logout: true,
+    supports_session_info: true,
+    supports_is_authenticated: true,
+    supports_inspect: true,
+    needs_credentials: true,
+    ..PortalCapabilities::NONE
+};
+
+/// Configuration for the MikroTik portal
+#[derive(Debug, Clone)]
+pub struct MikrotikConfig {
+    /// Human-readable name for this portal instance
+    pub name: String,
+    /// SSIDs that this portal handles
+    pub ssids: Vec<String>,
+    /// Identity, privacy switches and retries
+    pub settings: PortalSettings,
+    /// The hotspot's login page, e.g. `http://10.5.50.1/login`
+    pub login_url: String,
+    pub username: String,
+    pub password: Secret,
+    /// Where the hotspot sends the browser after the login, instead of the
+    /// page's own `dst`
+    pub dst: Option<String>,
+    /// Send the password in the clear when the page offers no CHAP
+    pub plaintext: bool,
+    /// Wording of a refused login and of the status page
+    pub phrases: PhraseTable,
+}
+
+impl MikrotikConfig {
+    /// Build from a `[[portals]]` entry; MikroTik-specific keys live in
+    /// its `mikrotik` table
+    pub fn from_config(portal: &PortalConfig, settings: PortalSettings) -> Self {
+        let text = |key: &str| {
+            portal
+                .setting(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+        let phrases = PhraseTable::from_config(portal.setting("phrases")).unwrap_or_else(|e| {
+            tracing::warn!("[{}] {:#}, using the built-in phrases", portal.name, e);
+            PhraseTable::default()
+        });
+
+        Self {
+            name: portal.name.clone(),
+            ssids: portal.ssids.clone(),
+            settings,
+            login_url: text("login_url").unwrap_or_default(),
+            username: text("username").unwrap_or_default(),
+            password: portal.secret("password"),
+            dst: text("dst"),
+            plaintext: portal
+                .setting("plaintext")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+            phrases,
+        }
+    }
+}
+
+/// The CHAP parameters of a login page, as the bytes they stand for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chap {
+    pub id: Vec<u8>,
+    pub challenge: Vec<u8>,
+}
+
+impl Chap {
+    /// The challenge the stock template hands to `hexMD5(...)`, or the
+    /// `chap_id` / `chap_challenge` script variables of pages built on it
+    pub fn parse(html: &str) -> Option<Self> {
+        let call = Regex::new(r#"hexMD5\(\s*'([^']*)'\s*\+[^+]*\+\s*'([^']*)'\s*\)"#).ok()?;
+        if let Some(captures) = call.captures(html) {
+            return Some(Self {
+                id: unescape(&captures[1]),
+                challenge: unescape(&captures[2]),
+            });
+        }
+        let var = |name: &str| {
+            Regex::new(&format!(r#"{}\s*=\s*["']([^"']*)["']"#, name))
+                .ok()?
+                .captures(html)
+                .map(|c| parser::decode_percent(&c[1]))
+        };
+        let challenge = var("chap_challenge").filter(|c| !c.is_empty())?;
+        Some(Self {
+            id: unescape(&var("chap_id").unwrap_or_default()),
+            challenge: unescape(&challenge),
+        })
+    }
+
+    /// What goes in the password field for `password`
+    pub fn response(&self, password: &str) -> String {
+        let mut data = self.id.clone();
+        data.extend_from_slice(password.as_bytes());
+        data.extend_from_slice(&self.challenge);
+        md5(&data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// The bytes of a JavaScript string literal's content: `\321` is one byte
+/// (octal), `\x41` another (hex), `\'` a quote
+fn unescape(literal: &str) -> Vec<u8> {
+    let bytes = literal.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let octal = bytes[i..]
+            .iter()
+            .take(3)
+            .take_while(|b| (b'0'..=b'7').contains(*b))
+            .count();
+        if octal > 0 {
+            let digits = std::str::from_utf8(&bytes[i..i + octal]).unwrap_or("0");
+            out.push(u16::from_str_radix(digits, 8).unwrap_or(0) as u8);
+            i += octal;
+        } else if bytes[i] == b'x' && i + 3 <= bytes.len() {
+            match std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    out.push(byte);
+                    i += 3;
+                }
+                None => out.push(b'\\'),
+            }
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// MD5 (RFC 1321), which MikroTik's CHAP is built on
+fn md5(data: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks_exact(64) {
+        let m: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k[i])
+                .wrapping_add(m[g])
+                .rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// The login page as the hotspot served it
+#[derive(Debug, Clone)]
+struct LoginPage {
+    /// Where the page ended up after redirects, for relative actions
+    url: reqwest::Url,
+    /// The first form: `sendin`, the one the script submits, on CHAP pages
+    form: ParsedForm,
+    chap: Option<Chap>,
+}
+
+/// MikroTik hotspot portal implementation
+pub struct MikrotikPortal {
+    config: MikrotikConfig,
+    client: HttpClient,
+    last_steps: Vec<StepReport>,
+}
+
+impl MikrotikPortal {
+    pub fn new(config: MikrotikConfig) -> Result<Self> {
+        if config.login_url.is_empty() {
+            anyhow::bail!("Portal '{}' has no mikrotik.login_url", config.name);
+        }
+        if config.username.is_empty() {
+            anyhow::bail!("Portal '{}' has no mikrotik.username", config.name);
+        }
+        let client = Self::http_client(&config, None, None)?;
+        Ok(Self {
+            config,
+            client,
+            last_steps: Vec::new(),
+        })
+    }
+
+    fn http_client(
+        config: &MikrotikConfig,
+        interface: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Result<HttpClient> {
+        HttpClient::with_options(config.settings.http_options(interface, proxy))
+    }
+
+    fn recorder(&self) -> StepRecorder {
+        let mut steps = StepRecorder::default()
+            .with_budget(self.config.settings.flow_budget)
+            .with(TimingLog::new(&self.config.name));
+        if self.config.settings.privacy.redact_mac {
+            steps = steps.with(RedactMacs::new());
+        }
+        steps
+    }
+
+    /// A page of the hotspot next to its login page, e.g. `status`
+    fn hotspot_url(&self, page: &str) -> Result<reqwest::Url> {
+        Ok(reqwest::Url::parse(&self.config.login_url)?.join(page)?)
+    }
+
+    async fn run_flow(&mut self, steps: &mut StepRecorder) -> Result<()> {
+        self.client.new_session();
+
+        let retry = self.config.settings.step_retry.clone();
+        let page = steps
+            .run_retrying("open_login", &retry, self, |p| Box::pin(p.open_login()))
+            .await?;
+        steps
+            .run_retrying("login", &retry, self, |p| {
+                let page = page.clone();
+                Box::pin(async move { p.login(&page).await })
+            })
+            .await?;
+
+        tracing::info!("[{}] Connected successfully!", self.config.name);
+        Ok(())
+    }
+
+    /// Step 1: Open the hotspot's login page and read its challenge
+    async fn open_login(&self) -> Result<LoginPage> {
+        tracing::info!(
+            "[{}] Step 1: Opening the hotspot login page...",
+            self.config.name
+        );
+
+        let (url, html) = self.client.get_page(&self.config.login_url).await?;
+        let chap = Chap::parse(&html);
+        match chap {
+            Some(_) => tracing::info!("   -> CHAP login page at {}", url),
+            None => tracing::info!("   -> Login page without CHAP at {}", url),
+        }
+        Ok(LoginPage {
+            url,
+            form: parser::parse_form(&html),
+            chap,
+        })
+    }
+
+    /// Step 2: Submit the account, hashed with the page's challenge
+    async fn login(&self, page: &LoginPage) -> Result<()> {
+        tracing::info!("[{}] Step 2: Logging in...", self.config.name);
+
+        let password = self.config.password.reveal().await?;
+        let password = match &page.chap {
+            Some(chap) => chap.response(&password),
+            None if self.config.plaintext => password,
+            None => anyhow::bail!(
+                "The hotspot offers no CHAP challenge; set mikrotik.plaintext = true to send \
+                 the password in the clear"
+            ),
+        };
+        let action = page
+            .url
+            .join(&page.form.action)
+            .context("Login form action is not a URL")?;
+        let mut fields = page.form.fields.clone();
+        fields.insert("username", &self.config.username);
+        fields.insert("password", &password);
+        if let Some(dst) = &self.config.dst {
+            fields.insert("dst", dst);
+        }
+        if fields.get("popup").is_none() {
+            fields.insert("popup", "true");
+        }
+
+        let answer = self
+            .client
+            .post_form_text(action.as_str(), fields.as_pairs())
+            .await?;
+        // A refused login comes back as the login page with the reason in it
+        if let Some(reason) = self.config.phrases.find_in(Phrase::LoginRejected, &answer) {
+            anyhow::bail!("Hotspot rejected the login: {}", reason);
+        }
+        if Chap::parse(&answer).is_some() {
+            anyhow::bail!("The hotspot answered with its login page again");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CaptivePortal for MikrotikPortal {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn ssids(&self) -> &[String] {
+        &self.config.ssids
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let mut steps = self.recorder();
+        let result = self.run_flow(&mut steps).await;
+        self.last_steps = steps.finish();
+        result
+    }
+
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        if self.client.interface() != interface {
+            let proxy = self.client.proxy().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface, proxy.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let interface = self.client.interface().map(str::to_string);
+        let proxy = self.client.proxy().map(str::to_string);
+        self.client = Self::http_client(&self.config, interface.as_deref(), proxy.as_deref())?;
+        Ok(())
+    }
+
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        if self.client.proxy() != proxy {
+            let interface = self.client.interface().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface.as_deref(), proxy)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> PortalCapabilities {
+        CAPABILITIES
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        vec![self.config.login_url.clone()]
+    }
+
+    /// The stock status page reports traffic and time left
+    async fn session_info(&self) -> Result<Option<SessionInfo>> {
+        let html = self
+            .client
+            .get_text(self.hotspot_url("status")?.as_str())
+            .await?;
+        let info =
+            parser::parse_mikrotik_status(&html, crate::state::unix_now(), &self.config.phrases)?;
+        Ok(Some(info))
+    }
+
+    /// The status page only shows its table to a logged-in client; the
+    /// hotspot answers anyone else with its login page
+    async fn is_authenticated(&self) -> Result<bool> {
+        let html = self
+            .client
+            .get_text(self.hotspot_url("status")?.as_str())
+            .await?;
+        Ok(parser::parse_mikrotik_status(&html, 0, &self.config.phrases).is_ok())
+    }
+
+    /// The hotspot logs out whichever client requests its logout page
+    async fn logout(&mut self) -> Result<()> {
+        let logout_url = self.hotspot_url("logout")?;
+        self.client.get(logout_url.as_str()).await?;
+        tracing::info!("Logged out at {}", logout_url);
+        Ok(())
+    }
+
+    fn client_mac(&self) -> Option<String> {
+        Some(self.config.settings.identity.mac_address.clone()).filter(|mac| !mac.is_empty())
+    }
+
+    fn login_url(&self) -> Option<String> {
+        Some(self.config.login_url.clone())
+    }
+
+    fn last_steps(&self) -> &[StepReport] {
+        &self.last_steps
+    }
+
+    /// The login form and its challenge, from a saved page or the live
+    /// hotspot
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        let (form, chap) = match fixture {
+            Some(content) => (parser::parse_form(content), Chap::parse(content)),
+            None => {
+                let page = self.open_login().await?;
+                (page.form, page.chap)
+            }
+        };
+        let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        let mut fields = Inspection::new();
+        fields.push(("form.action".into(), form.action.clone()));
+        fields.push(("form.method".into(), form.method.clone()));
+        for (name, value) in form.fields.as_pairs() {
+            fields.push((format!("form.{}", name), value.clone()));
+        }
+        if let Some(chap) = chap {
+            fields.push(("chap.id".into(), hex(&chap.id)));
+            fields.push(("chap.challenge".into(), hex(&chap.challenge)));
+        }
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chap_response() {
+        let hex = |d: [u8; 16]| -> String { d.iter().map(|b| format!("{:02x}", b)).collect() };
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(md5("1234567890".repeat(8).as_bytes())),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+
+        let page = concat!(
+            "<form name=\"sendin\" action=\"http://10.5.50.1/login\" method=\"post\">\n",
+            "<input type=\"hidden\" name=\"username\" />\n",
+            "<input type=\"hidden\" name=\"password\" />\n",
+            "<input type=\"hidden\" name=\"dst\" value=\"http://example.com/\" />\n",
+            "</form>\n",
+            "document.sendin.password.value = hexMD5('\\321' + ",
+            "document.login.password.value + '\\064\\231\\210\\353\\027\\024\\150\\376');\n",
+        );
+        let chap = Chap::parse(page).unwrap();
+        assert_eq!(chap.id, vec![0o321]);
+        assert_eq!(
+            chap.challenge,
+            vec![0o064, 0o231, 0o210, 0o353, 0o027, 0o024, 0o150, 0o376]
+        );
+        assert_eq!(chap.response("secret"), "b6d7fddf430e722761a8ec19f382efd8");
+
+        let vars =
+            Chap::parse("var chap_id = \"%5C321\";\nvar chap_challenge = \"\\x41b\";").unwrap();
+        assert_eq!(vars.id, vec![0o321]);
+        assert_eq!(vars.challenge, b"Ab".to_vec());
+        assert_eq!(Chap::parse("<form name=\"login\"></form>"), None);
+    }
+}
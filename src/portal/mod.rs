@@ -4,12 +4,144 @@
 //! captive portals. Each portal type implements the `CaptivePortal` trait,
 //! allowing the main daemon to work with any supported portal transparently.
 
+#[cfg(feature = "portal-awing")]
 pub mod awing;
+pub mod flow;
+#[cfg(feature = "portal-fpt")]
+pub mod fpt;
+#[cfg(feature = "portal-generic")]
+pub mod generic;
+pub mod middleware;
+#[cfg(feature = "portal-mikrotik")]
+pub mod mikrotik;
+#[cfg(feature = "portal-wispr")]
+pub mod wispr;
 
+#[cfg(feature = "portal-awing")]
 pub use awing::AwingPortal;
+#[cfg(feature = "portal-fpt")]
+pub use fpt::FptPortal;
+#[cfg(feature = "portal-generic")]
+pub use generic::GenericPortal;
+#[cfg(feature = "portal-mikrotik")]
+pub use mikrotik::MikrotikPortal;
+#[cfg(feature = "portal-wispr")]
+pub use wispr::WisprPortal;
+pub use flow::{soft_failures, BudgetExceeded, StepMiddleware, StepRecorder, StepReport};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use crate::config::{Config, PortalConfig, PrivacyConfig};
+use crate::http::HttpOptions;
+use crate::identity::{Identity, IdentityManager};
+use crate::models::SessionInfo;
+use crate::policy::RetryPolicy;
+use crate::schedule::Schedule;
+use crate::suggest::SsidSuggestion;
+use serde::Serialize;
+use std::time::Duration;
+
+/// No configured portal handles the SSID the machine is connected to
+#[derive(Debug, thiserror::Error)]
+#[error("No portal configured for SSID: {0}")]
+pub struct NoPortalForSsid(pub String);
+
+/// The venue's router is not the one a cached session was made with
+#[derive(Debug, thiserror::Error)]
+#[error("Venue infrastructure changed: {0}")]
+pub struct VenueChanged(pub String);
+
+/// Fields extracted by a portal's parser stages, in stage order
+pub type Inspection = Vec<(String, String)>;
+
+/// What a portal implementation can do and what it needs, so callers can
+/// check before calling an optional trait method
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PortalCapabilities {
+    /// `logout` ends the session at the portal
+    pub supports_logout: bool,
+    /// The portal reports session details (expiry, quota, venue)
+    pub supports_session_info: bool,
+    /// `is_authenticated` asks the portal, and the daemon believes it
+    /// instead of the probe URLs
+    pub supports_is_authenticated: bool,
+    /// `session` and `resume` work, so sessions can be cached
+    pub supports_resume: bool,
+    /// `inspect` runs the parser stages without logging in
+    pub supports_inspect: bool,
+    /// The user must configure an account (`username`) for login;
+    /// `wimesh validate` says when it is missing
+    pub needs_credentials: bool,
+    /// Logging in accepts terms on the user's behalf, which
+    /// `global.accept_terms = false` forbids
+    pub needs_consent: bool,
+}
+
+impl PortalCapabilities {
+    /// Nothing optional; what the trait's default methods amount to
+    pub const NONE: Self = Self {
+        supports_logout: false,
+        supports_session_info: false,
+        supports_is_authenticated: false,
+        supports_resume: false,
+        supports_inspect: false,
+        needs_credentials: false,
+        needs_consent: false,
+    };
+}
+
+/// What every portal type takes from the config besides its own table
+#[derive(Debug, Clone)]
+pub struct PortalSettings {
+    /// MAC address, User-Agent and customer name presented to the venue
+    pub identity: Identity,
+    /// Privacy switches shared by all portals
+    pub privacy: PrivacyConfig,
+    /// How each request is retried
+    pub http_retry: RetryPolicy,
+    /// How each step of the flow is retried
+    pub step_retry: RetryPolicy,
+    /// Time the whole flow may take (`policy.flow_budget`)
+    pub flow_budget: Option<Duration>,
+}
+
+impl Default for PortalSettings {
+    fn default() -> Self {
+        Self {
+            identity: Identity::default(),
+            privacy: PrivacyConfig::default(),
+            http_retry: RetryPolicy::default(),
+            step_retry: RetryPolicy::step(),
+            flow_budget: None,
+        }
+    }
+}
+
+impl PortalSettings {
+    /// The settings `cfg` gives a portal presenting `identity`
+    pub fn new(cfg: &Config, identity: Identity) -> Self {
+        Self {
+            identity,
+            privacy: cfg.privacy.clone(),
+            http_retry: cfg.http_policy(),
+            step_retry: cfg.step_policy(),
+            flow_budget: cfg.flow_budget(),
+        }
+    }
+
+    /// Options of the portal's HTTP client, sending out of `interface` and
+    /// through `proxy`
+    pub fn http_options(&self, interface: Option<&str>, proxy: Option<&str>) -> HttpOptions {
+        HttpOptions {
+            strip_device_hints: self.privacy.strip_device_hints,
+            randomize_user_agent: self.privacy.randomize_user_agent,
+            user_agent: self.identity.user_agent.clone(),
+            interface: interface.map(str::to_string),
+            proxy: proxy.map(str::to_string),
+            retry: self.http_retry.clone(),
+        }
+    }
+}
 
 /// Trait defining the interface for captive portal handlers
 ///
@@ -31,16 +163,100 @@ pub trait CaptivePortal: Send + Sync {
     /// Execute the full authentication flow for this portal
     async fn connect(&mut self) -> Result<()>;
 
-    /// Optional: Check if already authenticated (for portals that support this)
+    /// Send the following flows out of `interface`, or wherever the routing
+    /// table says with `None`, for machines with several WiFi adapters
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        let _ = interface;
+        Ok(())
+    }
+
+    /// Forget what the current flow learned (gateway, cookies, DNS
+    /// overrides), after it was cancelled halfway
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Send the following flows through `proxy`, e.g. a SOCKS tunnel to
+    /// the machine that should get logged in
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        match proxy {
+            Some(_) => anyhow::bail!("Portal '{}' cannot run through a proxy", self.name()),
+            None => Ok(()),
+        }
+    }
+
+    /// Which optional methods this portal actually implements
+    fn capabilities(&self) -> PortalCapabilities {
+        PortalCapabilities::NONE
+    }
+
+    /// URLs of the servers the login flow talks to
+    fn endpoints(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Session data to cache after a successful `connect`, so a later run
+    /// can `resume` instead of running the full flow
+    fn session(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Cheaply re-validate a cached session; an error means the full flow
+    /// is needed
+    async fn resume(&mut self, session: &serde_json::Value) -> Result<()> {
+        let _ = session;
+        bail!("Portal '{}' cannot resume sessions", self.name())
+    }
+
+    /// What the portal reports about the current session, if it reports
+    /// anything (see `PortalCapabilities::supports_session_info`)
+    async fn session_info(&self) -> Result<Option<SessionInfo>> {
+        Ok(None)
+    }
+
+    /// End the session at the portal (see
+    /// `PortalCapabilities::supports_logout`)
+    async fn logout(&mut self) -> Result<()> {
+        bail!("Portal '{}' does not support logout", self.name())
+    }
+
+    /// MAC address the most recent flow authenticated, as the venue saw it,
+    /// for the audit log
+    fn client_mac(&self) -> Option<String> {
+        None
+    }
+
+    /// Router login URL the captive page named in the most recent flow, to
+    /// notice the venue replacing its router
+    fn login_url(&self) -> Option<String> {
+        None
+    }
+
+    /// Steps run by the most recent `connect`, for portals that record them
+    fn last_steps(&self) -> &[StepReport] {
+        &[]
+    }
+
+    /// Run the parser stages without logging in, against a saved page
+    /// (`fixture`) or against the live portal when `fixture` is `None`
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        let _ = fixture;
+        bail!("Portal '{}' does not support inspection", self.name())
+    }
+
+    /// Optional: Check if already authenticated (see
+    /// `PortalCapabilities::supports_is_authenticated`)
     async fn is_authenticated(&self) -> Result<bool> {
         // Default implementation: try to reach the internet
-        Ok(crate::utils::has_internet_connectivity())
+        Ok(crate::utils::nonblocking::has_internet_connectivity().await)
     }
 }
 
 /// Registry of all available portal implementations
 pub struct PortalRegistry {
     portals: Vec<Box<dyn CaptivePortal>>,
+    /// When each of `portals` applies; `None` always
+    schedules: Vec<Option<Schedule>>,
 }
 
 impl PortalRegistry {
@@ -48,20 +264,90 @@ impl PortalRegistry {
     pub fn new() -> Self {
         Self {
             portals: Vec::new(),
+            schedules: Vec::new(),
+        }
+    }
+
+    /// A registry of every `[[portals]]` entry of `cfg` with a known type
+    pub fn from_config(cfg: &Config, identities: &IdentityManager) -> Result<Self> {
+        let mut registry = Self::new();
+        tracing::debug!("Portal types in this build: {}", compiled_types().join(", "));
+
+        for portal_cfg in &cfg.portals {
+            let schedule = Schedule::parse(&portal_cfg.schedule)
+                .with_context(|| format!("Portal '{}'", portal_cfg.name))?;
+            match build(cfg, portal_cfg, identities)? {
+                Some(portal) => registry.register_scheduled(portal, schedule),
+                None => {
+                    tracing::warn!(
+                        "{}, skipping: {}",
+                        missing_type(&portal_cfg.portal_type),
+                        portal_cfg.name
+                    );
+                }
+            }
+        }
+
+        if registry.all_ssids().is_empty() {
+            tracing::warn!("No portals configured! Add portal configurations to config.toml");
         }
+
+        Ok(registry)
     }
 
     /// Register a portal implementation
     pub fn register(&mut self, portal: Box<dyn CaptivePortal>) {
+        self.register_scheduled(portal, None);
+    }
+
+    /// Register a portal that only handles its SSIDs inside `schedule`
+    pub fn register_scheduled(
+        &mut self,
+        portal: Box<dyn CaptivePortal>,
+        schedule: Option<Schedule>,
+    ) {
         tracing::debug!("Registered portal: {} (SSIDs: {})", 
             portal.name(), 
             portal.ssids().join(", "));
+        tracing::debug!("   -> {:?}", portal.capabilities());
         self.portals.push(portal);
+        self.schedules.push(schedule);
     }
 
-    /// Find a portal that handles the given SSID
+    /// Find a portal that handles the given SSID now: the first one whose
+    /// schedule is active, else the first one without a schedule
     pub fn find_for_ssid(&mut self, ssid: &str) -> Option<&mut Box<dyn CaptivePortal>> {
-        self.portals.iter_mut().find(|p| p.matches_ssid(ssid))
+        let now = self.schedules.iter().any(Option::is_some).then(crate::schedule::local_now);
+        let index = self.index_for_ssid(ssid, now.unwrap_or_default())?;
+        Some(&mut self.portals[index])
+    }
+
+    /// Index of the portal `find_for_ssid` picks at local time `now`
+    fn index_for_ssid(&self, ssid: &str, now: i64) -> Option<usize> {
+        let mut unscheduled = None;
+        for (index, portal) in self.portals.iter().enumerate() {
+            if !portal.matches_ssid(ssid) {
+                continue;
+            }
+            match &self.schedules[index] {
+                Some(schedule) if schedule.is_active(now) => return Some(index),
+                Some(_) => tracing::debug!("Portal '{}' is off schedule", portal.name()),
+                None => {
+                    unscheduled.get_or_insert(index);
+                }
+            }
+        }
+        unscheduled
+    }
+
+    /// Find a portal by its configured name
+    pub fn find_by_name(&mut self, name: &str) -> Option<&mut Box<dyn CaptivePortal>> {
+        self.portals.iter_mut().find(|p| p.name() == name)
+    }
+
+    /// Names of all registered portals
+    pub fn names(&self) -> Vec<&str> {
+        self.portals.iter().map(|p| p.name()).collect()
     }
 
     /// Get all registered SSIDs across all portals
@@ -76,6 +362,18 @@ impl PortalRegistry {
     pub fn has_ssid(&self, ssid: &str) -> bool {
         self.portals.iter().any(|p| p.matches_ssid(ssid))
     }
+
+    /// The configured SSID that `ssid`, handled by no portal, was probably
+    /// meant to be
+    pub fn suggest_ssid(&self, ssid: &str) -> Option<SsidSuggestion> {
+        let mut suggestion = crate::suggest::closest_ssid(ssid, &self.all_ssids())?;
+        suggestion.portal = self
+            .portals
+            .iter()
+            .find(|p| p.matches_ssid(&suggestion.ssid))
+            .map(|p| p.name().to_string());
+        Some(suggestion)
+    }
 }
 
 impl Default for PortalRegistry {
@@ -83,3 +381,117 @@ impl Default for PortalRegistry {
         Self::new()
     }
 }
+
+/// Every value of `type` there is, and whether this build has it (the
+/// cargo feature `portal-<type>`)
+pub const PORTAL_TYPES: &[(&str, bool)] = &[
+    ("awing", cfg!(feature = "portal-awing")),
+    ("fpt", cfg!(feature = "portal-fpt")),
+    ("generic", cfg!(feature = "portal-generic")),
+    ("mikrotik", cfg!(feature = "portal-mikrotik")),
+    ("wispr", cfg!(feature = "portal-wispr")),
+];
+
+/// Values of `type` that `build` knows in this build
+pub fn compiled_types() -> Vec<&'static str> {
+    PORTAL_TYPES
+        .iter()
+        .filter(|(_, compiled)| *compiled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// The settings a `portal_type` of this build reads from its table;
+/// `None` for types it does not have
+pub fn settings(portal_type: &str) -> Option<&'static [&'static str]> {
+    match portal_type {
+        #[cfg(feature = "portal-awing")]
+        "awing" => Some(awing::SETTINGS),
+        #[cfg(feature = "portal-fpt")]
+        "fpt" => Some(fpt::SETTINGS),
+        #[cfg(feature = "portal-generic")]
+        "generic" => Some(generic::SETTINGS),
+        #[cfg(feature = "portal-mikrotik")]
+        "mikrotik" => Some(mikrotik::SETTINGS),
+        #[cfg(feature = "portal-wispr")]
+        "wispr" => Some(wispr::SETTINGS),
+        _ => None,
+    }
+}
+
+/// What a `portal_type` of this build can do and needs, before any portal
+/// of it is built; `None` for types it does not have
+pub fn capabilities(portal_type: &str) -> Option<PortalCapabilities> {
+    match portal_type {
+        #[cfg(feature = "portal-awing")]
+        "awing" => Some(awing::CAPABILITIES),
+        #[cfg(feature = "portal-fpt")]
+        "fpt" => Some(fpt::CAPABILITIES),
+        #[cfg(feature = "portal-generic")]
+        "generic" => Some(generic::CAPABILITIES),
+        #[cfg(feature = "portal-mikrotik")]
+        "mikrotik" => Some(mikrotik::CAPABILITIES),
+        #[cfg(feature = "portal-wispr")]
+        "wispr" => Some(wispr::CAPABILITIES),
+        _ => None,
+    }
+}
+
+/// Why `build` has nothing for `portal_type`
+pub fn missing_type(portal_type: &str) -> String {
+    match PORTAL_TYPES.iter().find(|(name, _)| *name == portal_type) {
+        Some(_) => format!(
+            "Portal type '{}' is not in this build (cargo feature portal-{})",
+            portal_type, portal_type
+        ),
+        None => format!("Unknown portal type '{}'", portal_type),
+    }
+}
+
+/// Instantiate one `[[portals]]` entry, or `None` for a type this build
+/// does not have
+#[cfg_attr(
+    not(any(
+        feature = "portal-awing",
+        feature = "portal-fpt",
+        feature = "portal-generic",
+        feature = "portal-mikrotik",
+        feature = "portal-wispr"
+    )),
+    allow(unused_variables)
+)]
+pub fn build(
+    cfg: &Config,
+    portal_cfg: &PortalConfig,
+    identities: &IdentityManager,
+) -> Result<Option<Box<dyn CaptivePortal>>> {
+    let settings = PortalSettings::new(cfg, identities.resolve(portal_cfg));
+    match portal_cfg.portal_type.as_str() {
+        #[cfg(feature = "portal-awing")]
+        "awing" => {
+            let awing_config = awing::AwingConfig::from_config(portal_cfg, settings);
+            Ok(Some(Box::new(AwingPortal::new(awing_config)?)))
+        }
+        #[cfg(feature = "portal-fpt")]
+        "fpt" => {
+            let fpt_config = fpt::FptConfig::from_config(portal_cfg, settings);
+            Ok(Some(Box::new(FptPortal::new(fpt_config)?)))
+        }
+        #[cfg(feature = "portal-generic")]
+        "generic" => {
+            let generic_config = generic::GenericConfig::from_config(portal_cfg, settings);
+            Ok(Some(Box::new(GenericPortal::new(generic_config)?)))
+        }
+        #[cfg(feature = "portal-mikrotik")]
+        "mikrotik" => {
+            let mikrotik_config = mikrotik::MikrotikConfig::from_config(portal_cfg, settings);
+            Ok(Some(Box::new(MikrotikPortal::new(mikrotik_config)?)))
+        }
+        #[cfg(feature = "portal-wispr")]
+        "wispr" => {
+            let wispr_config = wispr::WisprConfig::from_config(portal_cfg, settings);
+            Ok(Some(Box::new(WisprPortal::new(wispr_config)?)))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,463 @@
+//! WISPr hotspot implementation
+//!
+//! Commercial hotspots (airports, hotel chains, roaming partners) often
+//! embed a WISPr 1.0 block in the page they redirect clients to: an XML
+//! `<WISPAccessGatewayParam>`, usually inside an HTML comment, naming the
+//! `<LoginURL>` smart clients post their credentials to. This portal
+//! requests a probe page the way an iPhone does, reads that block, posts
+//! the configured `wispr.username` and `wispr.password` and reads the
+//! gateway's verdict from the `<AuthenticationReply>`, polling while it
+//! says pending. The reply names a `<LogoffURL>`, which `logout` requests.
+
+use crate::config::PortalConfig;
+use crate::http::HttpClient;
+use crate::parser::{self, ParseError};
+use crate::portal::middleware::{RedactMacs, TimingLog};
+use crate::portal::{
+    CaptivePortal, Inspection, PortalCapabilities, PortalSettings, StepRecorder, StepReport,
+};
+use crate::secrets::Secret;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use std::time::Duration;
+
+/// Page the gateway intercepts; what Apple devices probe with
+const DEFAULT_PROBE_URL: &str = "http://captive.apple.com/hotspot-detect.html";
+/// Keys of the `wispr` table
+pub const SETTINGS: &[&str] = &["probe_url", "username", "password"];
+/// What `wispr` portals can do and need
+pub const CAPABILITIES: PortalCapabilities = PortalCapabilities {
+    supports_logout: true,
+    supports_inspect: true,
+    needs_credentials: true,
+    ..PortalCapabilities::NONE
+};
+/// Proxy notifications (message type 110) followed before the redirect
+const MAX_PROXY_HOPS: usize = 3;
+/// Polls of `LoginResultsURL` while the gateway says pending
+const MAX_POLLS: usize = 5;
+/// Wait between polls when the gateway names no `<Delay>`, and the most
+/// any `<Delay>` is honored
+const DEFAULT_POLL_DELAY: Duration = Duration::from_secs(2);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(30);
+
+/// WISPr message types
+const INITIAL_REDIRECT: u32 = 100;
+const PROXY_NOTIFICATION: u32 = 110;
+
+/// WISPr response codes
+const LOGIN_SUCCEEDED: u32 = 50;
+const LOGIN_FAILED: u32 = 100;
+const LOGOFF_SUCCEEDED: u32 = 150;
+const AUTHENTICATION_PENDING: u32 = 201;
+
+/// One `<WISPAccessGatewayParam>` block, whichever message it carries
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WisprMessage {
+    pub message_type: Option<u32>,
+    pub response_code: Option<u32>,
+    pub login_url: Option<String>,
+    pub abort_login_url: Option<String>,
+    /// With a proxy notification, the page to request next
+    pub next_url: Option<String>,
+    pub login_results_url: Option<String>,
+    pub logoff_url: Option<String>,
+    /// Seconds to wait before polling `login_results_url`
+    pub delay: Option<u64>,
+    /// The gateway's words, e.g. why the login failed
+    pub reply_message: Option<String>,
+}
+
+impl WisprMessage {
+    /// The WISPr block in `page`, if it carries one
+    pub fn parse(page: &str) -> Option<Self> {
+        let block = Regex::new(r"(?is)<WISPAccessGatewayParam\b.*?</WISPAccessGatewayParam>")
+            .ok()?
+            .find(page)?
+            .as_str();
+        let tag = |name: &str| {
+            Regex::new(&format!(r"(?is)<{0}>\s*(.*?)\s*</{0}>", name))
+                .ok()?
+                .captures(block)
+                .map(|c| parser::decode_html(&c[1]))
+                .filter(|value| !value.is_empty())
+        };
+        let number = |name: &str| tag(name).and_then(|value| value.parse().ok());
+        Some(Self {
+            message_type: number("MessageType"),
+            response_code: number("ResponseCode"),
+            login_url: tag("LoginURL"),
+            abort_login_url: tag("AbortLoginURL"),
+            next_url: tag("NextURL"),
+            login_results_url: tag("LoginResultsURL"),
+            logoff_url: tag("LogoffURL"),
+            delay: tag("Delay").and_then(|value| value.parse().ok()),
+            reply_message: tag("ReplyMessage"),
+        })
+    }
+
+    fn reply(&self) -> String {
+        match &self.reply_message {
+            Some(message) => format!(": {}", message),
+            None => String::new(),
+        }
+    }
+}
+
+/// Configuration for the WISPr portal
+#[derive(Debug, Clone)]
+pub struct WisprConfig {
+    /// Human-readable name for this portal instance
+    pub name: String,
+    /// SSIDs that this portal handles
+    pub ssids: Vec<String>,
+    /// Identity, privacy switches and retries
+    pub settings: PortalSettings,
+    /// Page requested to be redirected to the gateway's WISPr block
+    pub probe_url: String,
+    pub username: String,
+    pub password: Secret,
+}
+
+impl WisprConfig {
+    /// Build from a `[[portals]]` entry; the credentials live in its
+    /// `wispr` table
+    pub fn from_config(portal: &PortalConfig, settings: PortalSettings) -> Self {
+        let text = |key: &str| {
+            portal
+                .setting(key)
+                .and_then(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+        };
+
+        Self {
+            name: portal.name.clone(),
+            ssids: portal.ssids.clone(),
+            settings,
+            probe_url: text("probe_url").unwrap_or(DEFAULT_PROBE_URL.to_string()),
+            username: text("username").unwrap_or_default(),
+            password: portal.secret("password"),
+        }
+    }
+}
+
+/// WISPr portal implementation
+pub struct WisprPortal {
+    config: WisprConfig,
+    client: HttpClient,
+    /// `LoginURL` of the most recent flow
+    login_url: Option<String>,
+    /// `LogoffURL` of the most recent successful login
+    logoff_url: Option<String>,
+    last_steps: Vec<StepReport>,
+}
+
+impl WisprPortal {
+    pub fn new(config: WisprConfig) -> Result<Self> {
+        if config.username.is_empty() {
+            anyhow::bail!("Portal '{}' has no wispr.username", config.name);
+        }
+        let client = Self::http_client(&config, None, None)?;
+        Ok(Self {
+            config,
+            client,
+            login_url: None,
+            logoff_url: None,
+            last_steps: Vec::new(),
+        })
+    }
+
+    fn http_client(
+        config: &WisprConfig,
+        interface: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Result<HttpClient> {
+        HttpClient::with_options(config.settings.http_options(interface, proxy))
+    }
+
+    fn recorder(&self) -> StepRecorder {
+        let mut steps = StepRecorder::default()
+            .with_budget(self.config.settings.flow_budget)
+            .with(TimingLog::new(&self.config.name));
+        if self.config.settings.privacy.redact_mac {
+            steps = steps.with(RedactMacs::new());
+        }
+        steps
+    }
+
+    async fn run_flow(&mut self, steps: &mut StepRecorder) -> Result<()> {
+        self.client.new_session();
+        self.login_url = None;
+
+        let retry = self.config.settings.step_retry.clone();
+        let login_url = steps
+            .run_retrying("find_login_url", &retry, self, |p| {
+                Box::pin(p.find_login_url())
+            })
+            .await?;
+        self.login_url = Some(login_url.clone());
+        let reply = steps
+            .run_retrying("login", &retry, self, |p| {
+                let login_url = login_url.clone();
+                Box::pin(async move { p.login(&login_url).await })
+            })
+            .await?;
+        self.logoff_url = reply.logoff_url;
+
+        tracing::info!("[{}] Connected successfully!", self.config.name);
+        Ok(())
+    }
+
+    /// The WISPr block the gateway answers the probe with, following proxy
+    /// notifications
+    async fn redirect(&self) -> Result<WisprMessage> {
+        let mut url = self.config.probe_url.clone();
+        for _ in 0..=MAX_PROXY_HOPS {
+            let (page, body) = self.client.get_page(&url).await?;
+            let message = WisprMessage::parse(&body).ok_or(ParseError::NotFound("WISPr block"))?;
+            match (message.message_type, &message.next_url) {
+                (Some(PROXY_NOTIFICATION), Some(next)) => {
+                    url = page.join(next)?.to_string();
+                    tracing::debug!("   -> Proxy notification, on to {}", url);
+                }
+                _ => return Ok(message),
+            }
+        }
+        anyhow::bail!(
+            "More than {} proxy notifications before the WISPr redirect",
+            MAX_PROXY_HOPS
+        )
+    }
+
+    /// Step 1: Request the probe page, and read the login URL from the
+    /// gateway's WISPr redirect
+    async fn find_login_url(&self) -> Result<String> {
+        tracing::info!(
+            "[{}] Step 1: Looking for the WISPr gateway...",
+            self.config.name
+        );
+
+        let message = self.redirect().await?;
+        if message.message_type != Some(INITIAL_REDIRECT) {
+            tracing::debug!(
+                "   -> WISPr message type {:?}, expected {}",
+                message.message_type,
+                INITIAL_REDIRECT
+            );
+        }
+        let login_url = message
+            .login_url
+            .ok_or(ParseError::NotInResponse("WISPr LoginURL"))?;
+        tracing::info!("   -> Gateway login at {}", login_url);
+        Ok(login_url)
+    }
+
+    /// Step 2: Post the credentials, waiting out "authentication pending"
+    async fn login(&self, login_url: &str) -> Result<WisprMessage> {
+        tracing::info!("[{}] Step 2: Logging in...", self.config.name);
+
+        let password = self.config.password.reveal().await?;
+        let form = [
+            ("UserName", self.config.username.as_str()),
+            ("Password", password.as_str()),
+            ("button", "Login"),
+            ("OriginatingServer", self.config.probe_url.as_str()),
+            ("FNAME", "0"),
+        ];
+        let body = self.client.post_form_text(login_url, &form).await?;
+        let mut reply = WisprMessage::parse(&body)
+            .ok_or(ParseError::NotInResponse("WISPr authentication reply"))?;
+
+        for _ in 0..MAX_POLLS {
+            if reply.response_code != Some(AUTHENTICATION_PENDING) {
+                break;
+            }
+            let results_url = reply
+                .login_results_url
+                .clone()
+                .ok_or(ParseError::NotInResponse("WISPr LoginResultsURL"))?;
+            let delay = reply
+                .delay
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_POLL_DELAY)
+                .min(MAX_POLL_DELAY);
+            tracing::debug!("   -> Authentication pending, polling in {:?}", delay);
+            tokio::time::sleep(delay).await;
+            let body = self.client.get_text(&results_url).await?;
+            reply = WisprMessage::parse(&body)
+                .ok_or(ParseError::NotInResponse("WISPr authentication poll reply"))?;
+        }
+
+        match reply.response_code {
+            Some(LOGIN_SUCCEEDED) => Ok(reply),
+            Some(LOGIN_FAILED) => {
+                anyhow::bail!("The gateway rejected the credentials{}", reply.reply())
+            }
+            Some(AUTHENTICATION_PENDING) => anyhow::bail!(
+                "Authentication still pending after {} polls{}",
+                MAX_POLLS,
+                reply.reply()
+            ),
+            Some(code) => anyhow::bail!("WISPr login failed with code {}{}", code, reply.reply()),
+            None => Err(ParseError::NotInResponse("WISPr ResponseCode").into()),
+        }
+    }
+}
+
+#[async_trait]
+impl CaptivePortal for WisprPortal {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn ssids(&self) -> &[String] {
+        &self.config.ssids
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let mut steps = self.recorder();
+        let result = self.run_flow(&mut steps).await;
+        self.last_steps = steps.finish();
+        result
+    }
+
+    fn bind_interface(&mut self, interface: Option<&str>) -> Result<()> {
+        if self.client.interface() != interface {
+            let proxy = self.client.proxy().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface, proxy.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let interface = self.client.interface().map(str::to_string);
+        let proxy = self.client.proxy().map(str::to_string);
+        self.client = Self::http_client(&self.config, interface.as_deref(), proxy.as_deref())?;
+        Ok(())
+    }
+
+    fn use_proxy(&mut self, proxy: Option<&str>) -> Result<()> {
+        if self.client.proxy() != proxy {
+            let interface = self.client.interface().map(str::to_string);
+            self.client = Self::http_client(&self.config, interface.as_deref(), proxy)?;
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self) -> PortalCapabilities {
+        CAPABILITIES
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        let mut endpoints = vec![self.config.probe_url.clone()];
+        endpoints.extend(self.login_url.clone());
+        endpoints
+    }
+
+    /// The logoff URL comes with the login reply, so this needs a login
+    /// by this process
+    async fn logout(&mut self) -> Result<()> {
+        let logoff_url = self
+            .logoff_url
+            .clone()
+            .context("No WISPr LogoffURL: not logged in by this process")?;
+        let body = self.client.get_text(&logoff_url).await?;
+        match WisprMessage::parse(&body).and_then(|reply| reply.response_code) {
+            Some(LOGOFF_SUCCEEDED) | None => {
+                self.logoff_url = None;
+                tracing::info!("Logged out at {}", logoff_url);
+                Ok(())
+            }
+            Some(code) => anyhow::bail!("WISPr logoff failed with code {}", code),
+        }
+    }
+
+    fn client_mac(&self) -> Option<String> {
+        Some(self.config.settings.identity.mac_address.clone()).filter(|mac| !mac.is_empty())
+    }
+
+    fn login_url(&self) -> Option<String> {
+        self.login_url.clone()
+    }
+
+    fn last_steps(&self) -> &[StepReport] {
+        &self.last_steps
+    }
+
+    /// The WISPr block, from a saved page or the live gateway
+    async fn inspect(&mut self, fixture: Option<&str>) -> Result<Inspection> {
+        let message = match fixture {
+            Some(content) => {
+                WisprMessage::parse(content).ok_or(ParseError::NotFound("WISPr block"))?
+            }
+            None => self.redirect().await?,
+        };
+        let mut fields = Inspection::new();
+        let numbers = [
+            ("wispr.message_type", message.message_type),
+            ("wispr.response_code", message.response_code),
+        ];
+        for (name, value) in numbers {
+            fields.extend(value.map(|v| (name.to_string(), v.to_string())));
+        }
+        let texts = [
+            ("wispr.login_url", message.login_url),
+            ("wispr.abort_login_url", message.abort_login_url),
+            ("wispr.next_url", message.next_url),
+            ("wispr.login_results_url", message.login_results_url),
+            ("wispr.logoff_url", message.logoff_url),
+            ("wispr.reply_message", message.reply_message),
+        ];
+        for (name, value) in texts {
+            fields.extend(value.map(|v| (name.to_string(), v)));
+        }
+        Ok(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_wispr() {
+        let page = r#"<html><head><title>Hotspot</title></head><body>
+<!--<?xml version="1.0" encoding="UTF-8"?>
+<WISPAccessGatewayParam xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"
+ xsi:noNamespaceSchemaLocation="http://www.acmewisp.com/WISPAccessGatewayParam.xsd">
+<Redirect>
+<AccessProcedure>1.0</AccessProcedure>
+<AccessLocation>CDATA[[isocc=,cc=,ac=,network=ACMEWISP,]]</AccessLocation>
+<LocationName>CDATA[[Airport Lounge]]</LocationName>
+<LoginURL>https://gw.example/login?res=wispr&amp;uamip=10.0.0.1</LoginURL>
+<AbortLoginURL>https://gw.example/abort</AbortLoginURL>
+<MessageType>100</MessageType>
+<ResponseCode>0</ResponseCode>
+</Redirect>
+</WISPAccessGatewayParam>-->
+<a href="/login">Log in</a></body></html>"#;
+        let message = WisprMessage::parse(page).unwrap();
+        assert_eq!(message.message_type, Some(INITIAL_REDIRECT));
+        assert_eq!(message.response_code, Some(0));
+        assert_eq!(
+            message.login_url.as_deref(),
+            Some("https://gw.example/login?res=wispr&uamip=10.0.0.1")
+        );
+        assert_eq!(message.logoff_url, None);
+
+        let reply = WisprMessage::parse(
+            "<WISPAccessGatewayParam><AuthenticationReply><MessageType>120</MessageType>\
+             <ResponseCode>50</ResponseCode><LogoffURL>https://gw.example/logoff</LogoffURL>\
+             </AuthenticationReply></WISPAccessGatewayParam>",
+        )
+        .unwrap();
+        assert_eq!(reply.response_code, Some(LOGIN_SUCCEEDED));
+        assert_eq!(
+            reply.logoff_url.as_deref(),
+            Some("https://gw.example/logoff")
+        );
+        assert_eq!(WisprMessage::parse("<html>Welcome</html>"), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! Verbose connectivity detection
+//!
+//! `wimesh probe` answers "is the portal holding my traffic?" on its own,
+//! without any portal involved: it fetches a few well-known check URLs and
+//! reports, for each, what DNS returned, the status, where it redirected,
+//! how long it took and what that means. Distinguishes a captive network
+//! from broken DNS or a dead uplink when a login keeps failing. The URLs
+//! are `[connectivity] probes`; `online` asks the same ones for everything
+//! else wanting to know whether the internet is there, in process rather
+//! than through curl.
+
+use crate::config::{ConnectivityConfig, ProbeConfig};
+use reqwest::redirect::Policy;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// What a check URL answers when nothing is in the way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expect {
+    /// This exact status
+    Status(u16),
+    /// This status, and a body starting with this
+    Body(u16, String),
+    /// Any status below 400, like `curl -f`
+    Success,
+}
+
+/// A check URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeTarget {
+    pub url: String,
+    pub expect: Expect,
+}
+
+impl From<&ProbeConfig> for ProbeTarget {
+    fn from(probe: &ProbeConfig) -> Self {
+        let expect = match (probe.status, &probe.body) {
+            (status, Some(body)) => Expect::Body(status.unwrap_or(200), body.clone()),
+            (Some(status), None) => Expect::Status(status),
+            (None, None) => Expect::Success,
+        };
+        Self {
+            url: probe.url.clone(),
+            expect,
+        }
+    }
+}
+
+/// The check URLs of `[connectivity]`
+struct Probes {
+    targets: Vec<ProbeTarget>,
+    timeout: Duration,
+}
+
+static PROBES: OnceLock<Probes> = OnceLock::new();
+
+fn probes() -> &'static Probes {
+    PROBES.get_or_init(|| Probes::from(&ConnectivityConfig::default()))
+}
+
+impl From<&ConnectivityConfig> for Probes {
+    fn from(cfg: &ConnectivityConfig) -> Self {
+        Self {
+            targets: cfg.probes.iter().map(ProbeTarget::from).collect(),
+            timeout: Duration::from_secs(cfg.timeout.max(1)),
+        }
+    }
+}
+
+/// Check with the URLs of `[connectivity]` from now on, instead of the
+/// defaults
+pub fn configure(cfg: &ConnectivityConfig) {
+    let _ = PROBES.set(Probes::from(cfg));
+}
+
+/// The URLs `wimesh probe` and the connectivity check ask, in order
+pub fn targets() -> &'static [ProbeTarget] {
+    &probes().targets
+}
+
+/// What one probe result means
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Classification {
+    /// The expected answer came back
+    Online,
+    /// Something else answered: redirected or rewritten by a portal
+    Captive,
+    /// The host name did not resolve
+    DnsFailure,
+    /// Resolved, but no answer: the uplink or a firewall drops traffic
+    Unreachable,
+}
+
+impl Classification {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Captive => "captive",
+            Self::DnsFailure => "dns_failure",
+            Self::Unreachable => "unreachable",
+        }
+    }
+}
+
+/// Everything one probe saw
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub url: String,
+    /// Addresses the system resolver returned
+    pub dns: Vec<IpAddr>,
+    pub status: Option<u16>,
+    /// `Location` of a redirect response
+    pub redirect: Option<String>,
+    pub latency_ms: u64,
+    pub classification: Classification,
+    pub error: Option<String>,
+}
+
+/// Whether the internet is reachable out of `interface` (any when
+/// `None`): the first check URL to answer decides, an expected answer
+/// meaning online and any other a portal in the way
+pub async fn online(interface: Option<&str>) -> bool {
+    for target in targets() {
+        let result = run(target, interface).await;
+        match result.classification {
+            Classification::Online => return true,
+            Classification::Captive => return false,
+            Classification::DnsFailure | Classification::Unreachable => tracing::debug!(
+                "Connectivity probe {} failed: {}",
+                target.url,
+                result.error.as_deref().unwrap_or(result.classification.as_str())
+            ),
+        }
+    }
+    false
+}
+
+/// Fetch `target` without following redirects, out of `interface` if given
+/// (Linux only)
+pub async fn run(target: &ProbeTarget, interface: Option<&str>) -> ProbeResult {
+    let timeout = probes().timeout;
+    let mut result = ProbeResult {
+        url: target.url.clone(),
+        dns: Vec::new(),
+        status: None,
+        redirect: None,
+        latency_ms: 0,
+        classification: Classification::DnsFailure,
+        error: None,
+    };
+
+    let url = match reqwest::Url::parse(&target.url) {
+        Ok(url) => url,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+    let host = url.host_str().unwrap_or_default();
+    match tokio::time::timeout(timeout, crate::dns::lookup(host)).await {
+        Ok(Ok(addrs)) => result.dns = addrs,
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => result.error = Some("lookup timed out".to_string()),
+    }
+    if result.dns.is_empty() {
+        return result;
+    }
+
+    let builder = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .timeout(timeout);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let builder = match interface {
+        Some(interface) => builder.interface(interface),
+        None => builder,
+    };
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = interface;
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+
+    let started = Instant::now();
+    let response = client.get(url).send().await;
+    result.latency_ms = started.elapsed().as_millis() as u64;
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            result.classification = Classification::Unreachable;
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+
+    let status = response.status().as_u16();
+    result.status = Some(status);
+    result.redirect = response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = match target.expect {
+        Expect::Body(..) => response.text().await.unwrap_or_default(),
+        _ => String::new(),
+    };
+    result.classification = classify(&target.expect, status, &body);
+    result
+}
+
+/// Classify the answer `status` / `body` to a probe expecting `expect`
+pub fn classify(expect: &Expect, status: u16, body: &str) -> Classification {
+    let online = match expect {
+        Expect::Status(expected) => status == *expected,
+        Expect::Body(expected, prefix) => {
+            status == *expected && body.trim_start().starts_with(prefix.as_str())
+        }
+        Expect::Success => status < 400,
+    };
+    if online {
+        Classification::Online
+    } else {
+        Classification::Captive
+    }
+}
+
+/// The overall answer of several probes: any portal interference means
+/// captive, else any expected answer means online
+pub fn verdict(results: &[ProbeResult]) -> Classification {
+    let any = |c| results.iter().any(|r| r.classification == c);
+    if any(Classification::Captive) {
+        Classification::Captive
+    } else if any(Classification::Online) {
+        Classification::Online
+    } else if !results.is_empty()
+        && results
+            .iter()
+            .all(|r| r.classification == Classification::DnsFailure)
+    {
+        Classification::DnsFailure
+    } else {
+        Classification::Unreachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let success = Expect::Body(200, "success".to_string());
+        assert_eq!(
+            classify(&Expect::Status(204), 204, ""),
+            Classification::Online
+        );
+        assert_eq!(
+            classify(&Expect::Status(204), 302, ""),
+            Classification::Captive
+        );
+        assert_eq!(
+            classify(&Expect::Status(204), 200, "<html>"),
+            Classification::Captive
+        );
+        assert_eq!(
+            classify(&success, 200, "success\n"),
+            Classification::Online
+        );
+        assert_eq!(
+            classify(&success, 200, "<form action=login>"),
+            Classification::Captive
+        );
+        assert_eq!(classify(&Expect::Success, 301, ""), Classification::Online);
+
+        let probe = |status, body: Option<&str>| ProbeConfig {
+            url: "http://example.com/".to_string(),
+            status,
+            body: body.map(str::to_string),
+        };
+        let expect = |status, body| ProbeTarget::from(&probe(status, body)).expect;
+        assert_eq!(expect(None, None), Expect::Success);
+        assert_eq!(expect(Some(204), None), Expect::Status(204));
+        assert_eq!(expect(None, Some("ok")), Expect::Body(200, "ok".to_string()));
+        assert_eq!(targets().len(), 3);
+    }
+}
@@ -0,0 +1,236 @@
+//! Step-by-step progress for an interactive `wimesh login`
+//!
+//! On a terminal, a login shows a spinner for the step running, a line per
+//! finished step with its duration and a colored summary, instead of raw
+//! log lines. `Progress` sees the steps of every flow through
+//! `flow::observe`; the caller hides logs below warnings meanwhile and
+//! writes the ones left through `stderr`, which clears the spinner line
+//! first. `NO_COLOR` turns the colors off.
+
+use crate::portal::{flow, soft_failures, StepMiddleware, StepReport};
+use crate::report::{Outcome, RunReport};
+use async_trait::async_trait;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const TICK: Duration = Duration::from_millis(80);
+/// Erase the current terminal line
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+/// Whether a spinner line is on screen
+static SPINNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+enum Color {
+    Green,
+    Yellow,
+    Red,
+    Dim,
+}
+
+/// The spinner and step lines of one login
+pub struct Progress {
+    color: bool,
+    started: Instant,
+    spinner: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Progress {
+    /// Show the steps of every flow from now on
+    pub fn start() -> Arc<Self> {
+        let progress = Arc::new(Self {
+            color: std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            started: Instant::now(),
+            spinner: Mutex::new(None),
+        });
+        flow::observe(Some(progress.clone()));
+        progress
+    }
+
+    /// Stop showing steps, and print the summary of the run
+    pub fn finish(&self, result: &anyhow::Result<Outcome>, report: &RunReport) {
+        flow::observe(None);
+        self.stop_spinner();
+        write_stderr(&format!("{}\n", self.summary(result, report)));
+    }
+
+    fn paint(&self, color: Color, text: &str) -> String {
+        paint(self.color, color, text)
+    }
+
+    /// One line saying how the run ended
+    fn summary(&self, result: &anyhow::Result<Outcome>, report: &RunReport) -> String {
+        let took = format!("{:.1}s", self.started.elapsed().as_secs_f64());
+        let ssid = report.ssid.as_deref().unwrap_or("-");
+        let via = match &report.portal {
+            Some(portal) => format!(" via '{}'", portal),
+            None => String::new(),
+        };
+        let (color, line) = match result {
+            Ok(Outcome::Connected) => (
+                Color::Green,
+                format!("✓ Logged in on '{}'{} in {}", ssid, via, took),
+            ),
+            Ok(Outcome::Partial) => (
+                Color::Yellow,
+                format!(
+                    "✓ Logged in on '{}'{} in {}, without {}",
+                    ssid,
+                    via,
+                    took,
+                    soft_failures(&report.steps).join(", ")
+                ),
+            ),
+            Ok(Outcome::Online) => (Color::Green, format!("✓ Already online on '{}'", ssid)),
+            Ok(Outcome::NotConnected) => (
+                Color::Yellow,
+                "! Not connected to a configured WiFi network".to_string(),
+            ),
+            Ok(Outcome::NoAddress) => (
+                Color::Yellow,
+                format!("! No usable IP address on '{}', login not attempted", ssid),
+            ),
+            Ok(Outcome::GatewayUnreachable) => (
+                Color::Yellow,
+                format!("! The gateway of '{}' does not answer, login not attempted", ssid),
+            ),
+            Ok(Outcome::Failed) | Err(_) => (
+                Color::Red,
+                match result {
+                    Err(e) => format!("✗ Login failed after {}: {:#}", took, e),
+                    Ok(_) => format!("✗ Login failed after {}", took),
+                },
+            ),
+        };
+        if let Some(warning) = &report.route_warning {
+            return format!(
+                "{}\n{}",
+                self.paint(color, &line),
+                self.paint(Color::Yellow, &format!("! {}", warning))
+            );
+        }
+        self.paint(color, &line)
+    }
+
+    /// The line of a finished step
+    fn step_line(&self, report: &StepReport) -> String {
+        let took = self.paint(Color::Dim, &format!("{}ms", report.duration_ms));
+        match (report.ok, report.critical) {
+            (true, _) => format!("  {} {} {}", self.paint(Color::Green, "✓"), report.name, took),
+            (false, critical) => {
+                let (color, mark) = if critical {
+                    (Color::Red, "✗")
+                } else {
+                    (Color::Yellow, "!")
+                };
+                let error = report.error.as_deref().unwrap_or("failed");
+                format!(
+                    "  {} {} {} {}",
+                    self.paint(color, mark),
+                    report.name,
+                    took,
+                    self.paint(color, error)
+                )
+            }
+        }
+    }
+
+    fn stop_spinner(&self) {
+        let spinner = self.spinner.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(spinner) = spinner {
+            spinner.abort();
+        }
+        if SPINNING.swap(false, Ordering::Relaxed) {
+            write_stderr(CLEAR_LINE);
+        }
+    }
+}
+
+#[async_trait]
+impl StepMiddleware for Progress {
+    async fn before(&self, step: &'static str) {
+        self.stop_spinner();
+        let color = self.color;
+        let spinner = tokio::spawn(async move {
+            for frame in FRAMES.iter().cycle() {
+                let frame = paint(color, Color::Yellow, &frame.to_string());
+                write_stderr(&format!("{}  {} {}", CLEAR_LINE, frame, step));
+                SPINNING.store(true, Ordering::Relaxed);
+                tokio::time::sleep(TICK).await;
+            }
+        });
+        *self.spinner.lock().unwrap_or_else(|e| e.into_inner()) = Some(spinner);
+    }
+
+    async fn after(&self, report: &mut StepReport) {
+        self.stop_spinner();
+        write_stderr(&format!("{}\n", self.step_line(report)));
+    }
+}
+
+fn paint(enabled: bool, color: Color, text: &str) -> String {
+    if !enabled {
+        return text.to_string();
+    }
+    let code = match color {
+        Color::Green => "32",
+        Color::Yellow => "33",
+        Color::Red => "31",
+        Color::Dim => "2",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+fn write_stderr(text: &str) {
+    let mut stderr = std::io::stderr().lock();
+    let _ = stderr.write_all(text.as_bytes());
+    let _ = stderr.flush();
+}
+
+/// Stderr for log lines, with the spinner line cleared first; the spinner
+/// redraws on its next tick
+pub fn stderr() -> std::io::Stderr {
+    if SPINNING.load(Ordering::Relaxed) {
+        write_stderr(CLEAR_LINE);
+    }
+    std::io::stderr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_and_step_lines() {
+        let progress = Progress {
+            color: false,
+            started: Instant::now(),
+            spinner: Mutex::new(None),
+        };
+        let mut report = RunReport::new();
+        report.ssid = Some("1.Free Wi-MESH".to_string());
+        report.portal = Some("KTX".to_string());
+        report.steps.push(StepReport {
+            name: "send_analytics",
+            duration_ms: 12,
+            ok: false,
+            critical: false,
+            error: Some("HTTP 500".to_string()),
+        });
+
+        let summary = progress.summary(&Ok(Outcome::Partial), &report);
+        assert!(summary.starts_with("✓ Logged in on '1.Free Wi-MESH' via 'KTX' in "));
+        assert!(summary.ends_with(", without send_analytics"), "{}", summary);
+        assert_eq!(
+            progress.step_line(&report.steps[0]),
+            "  ! send_analytics 12ms HTTP 500"
+        );
+        let failed = progress.summary(&Err(anyhow::anyhow!("timed out")), &report);
+        assert!(failed.starts_with("✗ Login failed after "), "{}", failed);
+        assert!(failed.ends_with(": timed out"));
+    }
+}
@@ -0,0 +1,406 @@
+//! QR codes for the terminal
+//!
+//! Just enough of ISO/IEC 18004 for a URL: byte mode, error correction
+//! level M, versions 1 to 6 (up to 106 bytes), the mask with the lowest
+//! penalty. `authorize-device --qr` prints one so a phone can open the
+//! companion page without anyone typing an address.
+
+use anyhow::Result;
+
+/// (data codewords per block, blocks, EC codewords per block) at level M,
+/// by version
+const BLOCKS_M: [(usize, usize, usize); 6] = [
+    (16, 1, 10),
+    (28, 1, 16),
+    (44, 1, 26),
+    (32, 2, 18),
+    (43, 2, 24),
+    (27, 4, 16),
+];
+
+/// Light margin around the symbol, in modules
+const QUIET_ZONE: usize = 4;
+
+/// A QR code symbol: `size` x `size` modules, `true` for dark
+#[derive(Debug, Clone)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    /// Finder, timing, alignment and format modules, which masks skip
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// The smallest symbol holding `data`
+    pub fn encode(data: &[u8]) -> Result<Self> {
+        let version = (1..=BLOCKS_M.len())
+            .find(|&v| 12 + 8 * data.len() <= data_codewords(v) * 8)
+            .ok_or_else(|| anyhow::anyhow!("{} bytes do not fit in a QR code", data.len()))?;
+        let codewords = interleave(version, &data_bits(version, data));
+
+        let size = 17 + 4 * version;
+        let mut qr = Self {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        qr.draw_function_patterns(version);
+        qr.draw_codewords(&codewords);
+
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                qr.apply_mask(mask);
+                qr.draw_format(mask);
+                let penalty = qr.penalty();
+                qr.apply_mask(mask);
+                penalty
+            })
+            .unwrap_or_default();
+        qr.apply_mask(mask);
+        qr.draw_format(mask);
+        Ok(qr)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at column `x`, row `y` is dark
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// The symbol in half-block characters, two module rows per line, for
+    /// light text on a dark terminal: light modules are drawn, dark ones
+    /// left blank
+    pub fn to_terminal(&self) -> String {
+        let span = self.size + 2 * QUIET_ZONE;
+        let dark = |x: usize, y: usize| {
+            let (x, y) = (x.wrapping_sub(QUIET_ZONE), y.wrapping_sub(QUIET_ZONE));
+            x < self.size && y < self.size && self.module(x, y)
+        };
+        let mut out = String::new();
+        for y in (0..span).step_by(2) {
+            for x in 0..span {
+                out.push(match (dark(x, y), dark(x, y + 1)) {
+                    (false, false) => '█',
+                    (false, true) => '▀',
+                    (true, false) => '▄',
+                    (true, true) => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            // The finder and its light separator
+            for dy in -4i32..=4 {
+                for dx in -4i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let ring = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, ring != 2 && ring != 4);
+                    }
+                }
+            }
+        }
+        if version >= 2 {
+            // Versions up to 6 have one alignment pattern; the others of
+            // the row and column would overlap the finders
+            let c = size - 7;
+            for dy in -2i32..=2 {
+                for dx in -2i32..=2 {
+                    let ring = dx.abs().max(dy.abs());
+                    let (x, y) = ((c as i32 + dx) as usize, (c as i32 + dy) as usize);
+                    self.set_function(x, y, ring != 1);
+                }
+            }
+        }
+        // Reserve the format areas; `draw_format` fills them in
+        self.draw_format(0);
+    }
+
+    /// The format information (level M, `mask`) in its two copies, and
+    /// the dark module
+    fn draw_format(&mut self, mask: usize) {
+        let data = mask as u32; // level M is 0b00
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size;
+        for i in 0..=5 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Fill the other modules with `codewords`, in the zigzag column pairs
+    /// from the bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// XOR mask pattern `mask` over the non-function modules; applying it
+    /// twice undoes it
+    fn apply_mask(&mut self, mask: usize) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let at = y * self.size + x;
+                if flip && !self.function[at] {
+                    self.modules[at] = !self.modules[at];
+                }
+            }
+        }
+    }
+
+    /// How hard the symbol is to scan: long runs, 2x2 blocks, finder
+    /// lookalikes and an uneven dark/light balance
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let lines = |transpose: bool| {
+            (0..size).map(move |a| {
+                (0..size)
+                    .map(|b| {
+                        if transpose {
+                            self.module(a, b)
+                        } else {
+                            self.module(b, a)
+                        }
+                    })
+                    .collect::<Vec<bool>>()
+            })
+        };
+        const FINDER: [bool; 11] = [
+            true, false, true, true, true, false, true, false, false, false, false,
+        ];
+        for line in lines(false).chain(lines(true)) {
+            let mut run = 1;
+            for i in 1..=size {
+                if i < size && line[i] == line[i - 1] {
+                    run += 1;
+                    continue;
+                }
+                if run >= 5 {
+                    penalty += run - 2;
+                }
+                run = 1;
+            }
+            for window in line.windows(11) {
+                if window == FINDER || window.iter().rev().eq(FINDER.iter()) {
+                    penalty += 40;
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.module(x, y);
+                if self.module(x + 1, y) == color
+                    && self.module(x, y + 1) == color
+                    && self.module(x + 1, y + 1) == color
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|&&m| m).count();
+        let percent = dark * 100 / self.modules.len();
+        penalty + percent.abs_diff(50) / 5 * 10
+    }
+}
+
+fn data_codewords(version: usize) -> usize {
+    let (per_block, blocks, _) = BLOCKS_M[version - 1];
+    per_block * blocks
+}
+
+/// Byte mode header, `data`, terminator and padding, as codewords
+fn data_bits(version: usize, data: &[u8]) -> Vec<u8> {
+    let capacity = data_codewords(version);
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity * 8);
+    let mut push = |value: u32, len: usize| {
+        bits.extend((0..len).rev().map(|i| (value >> i) & 1 != 0));
+    };
+    push(0b0100, 4);
+    push(data.len() as u32, 8);
+    for &byte in data {
+        push(byte as u32, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    while !bits.len().is_multiple_of(8) {
+        bits.push(false);
+    }
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+/// The data codewords split into blocks, each followed by its error
+/// correction, interleaved as the symbol stores them
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let (per_block, _, ec_len) = BLOCKS_M[version - 1];
+    let blocks: Vec<&[u8]> = data.chunks(per_block).collect();
+    let ec: Vec<Vec<u8>> = blocks.iter().map(|b| reed_solomon(b, ec_len)).collect();
+    let mut out = Vec::with_capacity(data.len() + ec.len() * ec_len);
+    for i in 0..per_block {
+        out.extend(blocks.iter().map(|b| b[i]));
+    }
+    for i in 0..ec_len {
+        out.extend(ec.iter().map(|b| b[i]));
+    }
+    out
+}
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(a: u8, b: u8) -> u8 {
+    let mut product = 0u16;
+    for i in (0..8).rev() {
+        product = (product << 1) ^ ((product >> 7) * 0x11D);
+        product ^= ((b >> i) & 1) as u16 * a as u16;
+    }
+    product as u8
+}
+
+/// The `len` error correction codewords of `data`
+fn reed_solomon(data: &[u8], len: usize) -> Vec<u8> {
+    // Generator polynomial (x - a^0)(x - a^1)...(x - a^(len-1)), highest
+    // coefficient (always 1) dropped
+    let mut generator = vec![0u8; len];
+    generator[len - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..len {
+        for j in 0..len {
+            generator[j] = gf_mul(generator[j], root);
+            if j + 1 < len {
+                generator[j] ^= generator[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+
+    let mut remainder = vec![0u8; len];
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.remove(0);
+        remainder.push(0);
+        for (r, g) in remainder.iter_mut().zip(&generator) {
+            *r ^= gf_mul(*g, factor);
+        }
+    }
+    remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_correction() {
+        // "HELLO WORLD" at 1-M, from the usual worked example
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        assert_eq!(
+            reed_solomon(&data, 10),
+            [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn test_symbol_layout() {
+        let qr = QrCode::encode(b"http://10.20.30.57:8787/0123456789abcdef").unwrap();
+        let size = qr.size();
+        assert_eq!(size, 29); // version 3
+                              // Finder corners, timing, the alignment centre and the dark module
+        for (x, y) in [
+            (0, 0),
+            (6, 6),
+            (size - 1, 0),
+            (0, size - 1),
+            (22, 22),
+            (8, size - 8),
+        ] {
+            assert!(qr.module(x, y), "({}, {})", x, y);
+        }
+        assert!(!qr.module(7, 7));
+        assert!(qr.module(8, 6) && !qr.module(9, 6));
+        // Both copies of the format information agree
+        let first: Vec<bool> = (0..=5).map(|y| qr.module(8, y)).collect();
+        let second: Vec<bool> = (0..6).map(|i| qr.module(size - 1 - i, 8)).collect();
+        assert_eq!(first, second);
+
+        assert_eq!(qr.to_terminal().lines().count(), (size + 8).div_ceil(2));
+        assert!(QrCode::encode(&[b'x'; 107]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Escalation after a long outage
+//!
+//! Normally the daemon just retries the same login, backing off between
+//! failures. When an SSID has been captive for longer than
+//! `[recovery] window` despite that, retrying identically is unlikely to
+//! help, so recovery mode climbs a ladder, one rung every `step_interval`:
+//! scan the gateway afresh, drop every cookie, rotate the MAC (if
+//! enabled), bounce the interface, and finally say that a human needs to
+//! look. Working internet ends it.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One rung of the ladder, in climbing order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Forget the cached session, so the next login scans the gateway
+    FreshGateway,
+    /// Rebuild the portals, with empty cookie jars
+    ClearCookies,
+    /// Present a new random MAC to the venue
+    RotateMac,
+    /// Take the WiFi interface down and up again
+    BounceInterface,
+    /// Nothing left to try on our own
+    NeedsAttention,
+}
+
+impl Step {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::FreshGateway => "fresh_gateway",
+            Self::ClearCookies => "clear_cookies",
+            Self::RotateMac => "rotate_mac",
+            Self::BounceInterface => "bounce_interface",
+            Self::NeedsAttention => "needs_attention",
+        }
+    }
+}
+
+/// Outage of one SSID
+#[derive(Debug, Clone, Copy)]
+struct Outage {
+    since: u64,
+    /// Rungs climbed so far
+    climbed: usize,
+    last_step: u64,
+}
+
+/// Tracks outages per SSID and hands out the next rung when one is due
+#[derive(Debug)]
+pub struct Recovery {
+    window: u64,
+    step_interval: u64,
+    ladder: Vec<Step>,
+    outages: HashMap<String, Outage>,
+}
+
+impl Recovery {
+    /// `window` 0 never escalates; `rotate_mac` puts that rung on the
+    /// ladder
+    pub fn new(window: u64, step_interval: u64, rotate_mac: bool) -> Self {
+        let ladder = [
+            Step::FreshGateway,
+            Step::ClearCookies,
+            Step::RotateMac,
+            Step::BounceInterface,
+            Step::NeedsAttention,
+        ]
+        .into_iter()
+        .filter(|step| rotate_mac || *step != Step::RotateMac)
+        .collect();
+        Self {
+            window,
+            step_interval,
+            ladder,
+            outages: HashMap::new(),
+        }
+    }
+
+    /// `ssid` is still without internet at `now`: the rung to take now, if
+    /// the outage is long enough and the last rung long enough ago
+    pub fn failing(&mut self, ssid: &str, now: u64) -> Option<Step> {
+        let outage = self.outages.entry(ssid.to_string()).or_insert(Outage {
+            since: now,
+            climbed: 0,
+            last_step: 0,
+        });
+        if self.window == 0 || now.saturating_sub(outage.since) < self.window {
+            return None;
+        }
+        if outage.climbed > 0 && now.saturating_sub(outage.last_step) < self.step_interval {
+            return None;
+        }
+        let step = *self.ladder.get(outage.climbed)?;
+        outage.climbed += 1;
+        outage.last_step = now;
+        Some(step)
+    }
+
+    /// `ssid` has internet again; returns how long the outage lasted if it
+    /// had gone into recovery mode
+    pub fn recovered(&mut self, ssid: &str, now: u64) -> Option<u64> {
+        let outage = self.outages.remove(ssid)?;
+        (outage.climbed > 0).then(|| now.saturating_sub(outage.since))
+    }
+
+    /// Seconds `ssid` has been without internet
+    pub fn outage(&self, ssid: &str, now: u64) -> Option<u64> {
+        let outage = self.outages.get(ssid)?;
+        Some(now.saturating_sub(outage.since))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ladder() {
+        let mut recovery = Recovery::new(600, 60, false);
+        assert_eq!(recovery.failing("Cafe", 1000), None);
+        assert_eq!(recovery.failing("Cafe", 1599), None);
+        assert_eq!(recovery.failing("Cafe", 1600), Some(Step::FreshGateway));
+        // One rung per step interval
+        assert_eq!(recovery.failing("Cafe", 1630), None);
+        assert_eq!(recovery.failing("Cafe", 1660), Some(Step::ClearCookies));
+        // No MAC rotation unless enabled
+        assert_eq!(recovery.failing("Cafe", 1720), Some(Step::BounceInterface));
+        assert_eq!(recovery.failing("Cafe", 1780), Some(Step::NeedsAttention));
+        assert_eq!(recovery.failing("Cafe", 1840), None);
+        assert_eq!(recovery.recovered("Cafe", 1900), Some(900));
+        assert_eq!(recovery.failing("Cafe", 2000), None);
+        assert_eq!(recovery.recovered("Cafe", 2001), None);
+
+        let mut recovery = Recovery::new(600, 60, true);
+        recovery.failing("Cafe", 0);
+        recovery.failing("Cafe", 600);
+        recovery.failing("Cafe", 660);
+        assert_eq!(recovery.failing("Cafe", 720), Some(Step::RotateMac));
+
+        let mut off = Recovery::new(0, 60, true);
+        off.failing("Cafe", 0);
+        assert_eq!(off.failing("Cafe", 100_000), None);
+    }
+}
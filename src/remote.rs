@@ -0,0 +1,155 @@
+//! Logging in on behalf of another machine over SSH
+//!
+//! `wimesh remote --host pi@10.0.0.2` runs the WiFi checks on the remote
+//! machine with `ssh`, and the portal flow here through an `ssh -D` SOCKS
+//! tunnel, so the portal sees the remote machine's connection and names
+//! resolve on the remote side. Handy to unstick a headless box whose own
+//! daemon is wedged, from any machine that can still reach it.
+
+use anyhow::{Context, Result};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+
+/// How long `ssh` may take to bring the tunnel up
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Options every `ssh` call gets: fail instead of prompting, and give up on
+/// a host that vanished
+const SSH_OPTIONS: &[&str] = &["-o", "BatchMode=yes", "-o", "ConnectTimeout=10"];
+
+/// The machine on the other end of `ssh`
+pub struct Remote {
+    host: String,
+}
+
+impl Remote {
+    /// `host` as `ssh` takes it: `user@addr` or a `~/.ssh/config` alias
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Run `program args` on the remote machine, returning whether it
+    /// succeeded and its stdout
+    pub async fn run(&self, program: &str, args: &[&str]) -> Result<(bool, String)> {
+        let output = Command::new("ssh")
+            .args(SSH_OPTIONS)
+            .arg(&self.host)
+            .arg("--")
+            .arg(program)
+            .args(args.iter().map(|arg| shell_quote(arg)))
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .context("Failed to run ssh")?;
+        // ssh itself failing is 255; anything else is the remote program's
+        if output.status.code() == Some(255) {
+            anyhow::bail!(
+                "ssh {} failed: {}",
+                self.host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok((
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        ))
+    }
+
+    /// `(interface, ssid)` of every associated WiFi interface of the remote
+    /// machine, which must run NetworkManager
+    pub async fn active_wifi(&self) -> Result<Vec<(String, String)>> {
+        let (ok, stdout) = self.run("nmcli", crate::utils::NMCLI_ACTIVE_ARGS).await?;
+        if !ok {
+            anyhow::bail!("nmcli failed on {}", self.host);
+        }
+        Ok(crate::utils::parse_nmcli_active(&stdout))
+    }
+
+    /// Whether the remote machine reaches the internet
+    pub async fn has_internet_connectivity(&self) -> Result<bool> {
+        let args = crate::utils::connectivity_check_args(None);
+        Ok(self.run("curl", &args).await?.0)
+    }
+
+    /// Open a SOCKS tunnel out of the remote machine
+    pub async fn tunnel(&self) -> Result<Tunnel> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let child = Command::new("ssh")
+            .args(SSH_OPTIONS)
+            .args(["-o", "ExitOnForwardFailure=yes", "-N", "-D"])
+            .arg(format!("127.0.0.1:{}", port))
+            .arg(&self.host)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to run ssh")?;
+        let mut tunnel = Tunnel { child, port };
+
+        let started = std::time::Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            if let Some(status) = tunnel.child.try_wait()? {
+                anyhow::bail!(
+                    "ssh {} exited ({}) before the tunnel was up",
+                    self.host,
+                    status
+                );
+            }
+            if started.elapsed() > TUNNEL_TIMEOUT {
+                anyhow::bail!("Tunnel to {} not up after {:?}", self.host, TUNNEL_TIMEOUT);
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        tracing::info!("SOCKS tunnel through {} on port {}", self.host, port);
+        Ok(tunnel)
+    }
+}
+
+/// A running `ssh -D`, closed when dropped
+pub struct Tunnel {
+    child: Child,
+    port: u16,
+}
+
+impl Tunnel {
+    /// Proxy URL for `HttpOptions::proxy`; `socks5h` so that names resolve
+    /// on the remote side
+    pub fn proxy_url(&self) -> String {
+        format!("socks5h://127.0.0.1:{}", self.port)
+    }
+}
+
+/// `arg` quoted for the remote shell, which `ssh` hands the command to
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.,:/=@".contains(&b))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--max-time"), "--max-time");
+        assert_eq!(shell_quote("active,device,ssid"), "active,device,ssid");
+        assert_eq!(shell_quote("Free Wi-MESH"), "'Free Wi-MESH'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
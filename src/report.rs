@@ -0,0 +1,156 @@
+//! Machine-readable run reports
+//!
+//! A `RunReport` summarizes one login attempt (which network, which portal,
+//! how each step went, how it ended) as a stable JSON object for scripts and
+//! status bar widgets.
+
+use crate::breaker::CircuitOpen;
+use crate::http::HttpError;
+use crate::lock::LockTimeout;
+use crate::parser::ParseError;
+use crate::portal::{soft_failures, BudgetExceeded, NoPortalForSsid, StepReport};
+use crate::suggest::SsidSuggestion;
+use serde::Serialize;
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The portal flow completed
+    Connected,
+    /// The portal flow completed, but non-critical steps failed; see
+    /// `steps`
+    Partial,
+    /// Not associated to any configured SSID, nothing to do
+    NotConnected,
+    /// Associated, but the WiFi gateway does not answer (dead AP uplink);
+    /// the login was not attempted
+    GatewayUnreachable,
+    /// Associated, but without a usable IP address; the login was not
+    /// attempted
+    NoAddress,
+    /// Traffic already flows, no login was needed
+    Online,
+    /// The run failed; see `error`
+    Failed,
+}
+
+impl Outcome {
+    /// The outcome of a completed portal flow that ran `steps`
+    pub fn connected(steps: &[StepReport]) -> Self {
+        if soft_failures(steps).is_empty() {
+            Self::Connected
+        } else {
+            Self::Partial
+        }
+    }
+}
+
+/// Stable classification of a failure
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        Self {
+            kind: error_kind(err),
+            message: format!("{:#}", err),
+        }
+    }
+}
+
+/// Summary of one login attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub ssid: Option<String>,
+    pub portal: Option<String>,
+    pub steps: Vec<StepReport>,
+    pub outcome: Outcome,
+    pub error: Option<ErrorReport>,
+    /// With `not_connected`, the configured SSID the current one probably
+    /// is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<SsidSuggestion>,
+    /// With `connected` or `partial`, why traffic may still not flow: the default route
+    /// bypasses the interface logged in on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_warning: Option<String>,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        Self {
+            ssid: None,
+            portal: None,
+            steps: Vec::new(),
+            outcome: Outcome::Failed,
+            error: None,
+            suggestion: None,
+            route_warning: None,
+        }
+    }
+
+    /// Record the final result of the run
+    pub fn finish(&mut self, result: &anyhow::Result<Outcome>) {
+        match result {
+            Ok(outcome) => {
+                self.outcome = *outcome;
+                self.error = None;
+            }
+            Err(e) => {
+                self.outcome = Outcome::Failed;
+                self.error = Some(ErrorReport::from_error(e));
+            }
+        }
+    }
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Classify an error by the first recognizable cause in its chain
+///
+/// Kinds are part of the JSON output format: add new ones, never rename.
+pub fn error_kind(err: &anyhow::Error) -> &'static str {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return if e.is_timeout() {
+                "timeout"
+            } else if e.is_connect() {
+                "connect"
+            } else if e.is_decode() {
+                "decode"
+            } else {
+                "request"
+            };
+        }
+        if cause.is::<tokio::time::error::Elapsed>() || cause.is::<BudgetExceeded>() {
+            return "timeout";
+        }
+        if cause.is::<CircuitOpen>() {
+            return "circuit_open";
+        }
+        if cause.is::<HttpError>() {
+            return "http_status";
+        }
+        if cause.is::<ParseError>() {
+            return "parse";
+        }
+        if cause.is::<LockTimeout>() {
+            return "busy";
+        }
+        if cause.is::<NoPortalForSsid>() {
+            return "no_portal";
+        }
+        if cause.is::<std::io::Error>() {
+            return "io";
+        }
+    }
+    "other"
+}
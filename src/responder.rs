@@ -0,0 +1,312 @@
+//! Answering other devices' captive-portal probes (`[responder]`)
+//!
+//! With wimesh on the router, the router's session is what the venue
+//! authenticates, yet phones and laptops behind it still run their own
+//! connectivity checks and may pop a login sheet on a slow or filtered
+//! probe. The responder answers those probes from the LAN instead: an HTTP
+//! server with the replies each OS looks for (`/generate_204`,
+//! `hotspot-detect.html`, `connecttest.txt`, ...) while wimesh knows the
+//! network is online, and optionally a DNS server resolving the probe
+//! hostnames to it, for the router's resolver to forward those names to.
+//! While the network is captive the probes fail, as they would upstream.
+
+use crate::config::ResponderConfig;
+use crate::tasks::Tasks;
+use anyhow::{Context, Result};
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::{RData, Record, RecordType};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinSet;
+
+/// Hostnames the common OSes probe, answered by the DNS side: only names
+/// that exist for the probes, as resolving a real site (`www.google.com`,
+/// `www.apple.com`) here would take it from every device on the LAN
+pub const PROBE_HOSTS: &[&str] = &[
+    "connectivitycheck.gstatic.com",
+    "connectivitycheck.android.com",
+    "clients3.google.com",
+    "captive.apple.com",
+    "www.msftconnecttest.com",
+    "www.msftncsi.com",
+    "detectportal.firefox.com",
+    "nmcheck.gnome.org",
+    "network-test.debian.org",
+];
+
+/// Largest request head read
+const MAX_HEAD: usize = 8 * 1024;
+/// How long one client may take to send its probe
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Probe connections answered at once; more wait to be accepted
+const MAX_CONNECTIONS: usize = 64;
+/// TTL of the DNS answers, short so a reconfigured router takes effect
+const DNS_TTL: u32 = 60;
+
+/// A probe reply: status, content type and body
+pub type Reply = (u16, &'static str, &'static str);
+
+/// The reply to an HTTP probe for `path`, while the network is `online`
+pub fn http_reply(path: &str, online: bool) -> Reply {
+    if !online {
+        return (503, "text/plain", "Not online yet, wimesh is logging in\n");
+    }
+    let path = path.split('?').next().unwrap_or(path);
+    match path {
+        "/hotspot-detect.html" | "/library/test/success.html" => (
+            200,
+            "text/html",
+            "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>",
+        ),
+        "/connecttest.txt" => (200, "text/plain", "Microsoft Connect Test"),
+        "/ncsi.txt" => (200, "text/plain", "Microsoft NCSI"),
+        "/success.txt" => (200, "text/plain", "success\n"),
+        "/check_network_status.txt" => (200, "text/plain", "NetworkManager is online\n"),
+        "/canonical.html" => (
+            200,
+            "text/html",
+            "<meta http-equiv=\"refresh\" content=\"0;url=https://support.mozilla.org/kb/captive-portal\"/>",
+        ),
+        // generate_204, gen_204 and anything else
+        _ => (204, "text/plain", ""),
+    }
+}
+
+/// The answer to the DNS query `packet`: `address` for the probe
+/// hostnames, REFUSED for anything else; None if it is not a query
+pub fn dns_reply(packet: &[u8], address: Ipv4Addr) -> Option<Vec<u8>> {
+    let query = Message::from_vec(packet).ok()?;
+    if query.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut reply = Message::new();
+    reply
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_recursion_available(false)
+        .add_queries(query.queries().to_vec());
+
+    let mut known = true;
+    for q in query.queries() {
+        let name = q.name().to_ascii().trim_end_matches('.').to_lowercase();
+        if !PROBE_HOSTS.contains(&name.as_str()) {
+            known = false;
+            continue;
+        }
+        if q.query_type() == RecordType::A {
+            reply.add_answer(Record::from_rdata(
+                q.name().clone(),
+                DNS_TTL,
+                RData::A(address.into()),
+            ));
+        }
+    }
+    if known {
+        reply.set_authoritative(true);
+    } else {
+        reply.set_response_code(ResponseCode::Refused);
+    }
+    reply.to_vec().ok()
+}
+
+/// The running responder; the daemon tells it whether the network is up
+#[derive(Clone)]
+pub struct Responder {
+    online: Arc<AtomicBool>,
+}
+
+impl Responder {
+    /// Bind the configured listeners and start answering, in `tasks`
+    pub async fn start(cfg: &ResponderConfig, tasks: &mut Tasks) -> Result<Self> {
+        let responder = Self {
+            online: Arc::default(),
+        };
+
+        let http = TcpListener::bind(&cfg.http_listen)
+            .await
+            .with_context(|| format!("Failed to listen on {} for [responder]", cfg.http_listen))?;
+        let online = responder.online.clone();
+        tasks.spawn("http", |cancel| async move {
+            // Dropped with this task, which aborts the connections still open
+            let mut connections = JoinSet::new();
+            loop {
+                while connections.try_join_next().is_some() {}
+                if connections.len() >= MAX_CONNECTIONS {
+                    tokio::select! {
+                        _ = connections.join_next() => continue,
+                        _ = cancel.cancelled() => return,
+                    }
+                }
+                let accepted = tokio::select! {
+                    accepted = http.accept() => accepted,
+                    _ = cancel.cancelled() => return,
+                };
+                let Ok((stream, _)) = accepted else {
+                    return;
+                };
+                let online = online.load(Ordering::Relaxed);
+                connections.spawn(async move {
+                    match tokio::time::timeout(REQUEST_TIMEOUT, serve(stream, online)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => tracing::debug!("Probe connection failed: {:#}", e),
+                        Err(_) => tracing::debug!("Probe connection timed out"),
+                    }
+                });
+            }
+        });
+        tracing::info!(
+            "Answering connectivity probes on http://{}",
+            cfg.http_listen
+        );
+
+        if !cfg.dns_listen.is_empty() {
+            let address: Ipv4Addr = cfg
+                .address
+                .parse()
+                .with_context(|| format!("responder.address '{}' is not IPv4", cfg.address))?;
+            let dns = UdpSocket::bind(&cfg.dns_listen).await.with_context(|| {
+                format!("Failed to listen on {} for [responder]", cfg.dns_listen)
+            })?;
+            tasks.spawn("dns", |cancel| async move {
+                let mut buf = [0u8; 1500];
+                loop {
+                    let received = tokio::select! {
+                        received = dns.recv_from(&mut buf) => received,
+                        _ = cancel.cancelled() => return,
+                    };
+                    let Ok((len, from)) = received else {
+                        continue;
+                    };
+                    if let Some(reply) = dns_reply(&buf[..len], address) {
+                        let _ = dns.send_to(&reply, from).await;
+                    }
+                }
+            });
+            tracing::info!(
+                "Resolving probe hostnames to {} on {}/udp",
+                address,
+                cfg.dns_listen
+            );
+        }
+        Ok(responder)
+    }
+
+    /// Whether wimesh currently has internet on some configured network
+    pub fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::Relaxed);
+    }
+}
+
+/// Answer one probe on `stream`, then close it
+async fn serve(mut stream: TcpStream, online: bool) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 2048];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_HEAD {
+            anyhow::bail!("Request head too large");
+        }
+    }
+    let head = String::from_utf8_lossy(&buf);
+    let path = head.split_whitespace().nth(1).unwrap_or("/");
+    let (status, content_type, body) = http_reply(path, online);
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        _ => "Service Unavailable",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::Name;
+
+    #[test]
+    fn test_http_replies() {
+        assert_eq!(http_reply("/generate_204", true).0, 204);
+        assert_eq!(http_reply("/gen_204?x=1", true).0, 204);
+        assert!(http_reply("/hotspot-detect.html", true)
+            .2
+            .contains("Success"));
+        assert_eq!(http_reply("/ncsi.txt", true).2, "Microsoft NCSI");
+        // Captive: the probe fails, as it would upstream
+        assert_eq!(http_reply("/generate_204", false).0, 503);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drops_idle_connections() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = ResponderConfig {
+            http_listen: format!("127.0.0.1:{}", port),
+            ..ResponderConfig::default()
+        };
+        let mut tasks = Tasks::new("responder");
+        Responder::start(&cfg, &mut tasks).await.unwrap();
+
+        // A client that never finishes its request
+        let mut idle = TcpStream::connect(&cfg.http_listen).await.unwrap();
+        idle.write_all(b"GET /generate_204 HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(tasks.shutdown(Duration::from_secs(1)).await.is_empty());
+
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(Duration::from_secs(1), idle.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+    }
+
+    #[test]
+    fn test_dns_replies() {
+        let address = Ipv4Addr::new(192, 168, 1, 1);
+        let query = |host: &str| {
+            let mut message = Message::new();
+            message
+                .set_id(7)
+                .add_query(Query::query(Name::from_ascii(host).unwrap(), RecordType::A));
+            message.to_vec().unwrap()
+        };
+
+        let reply =
+            Message::from_vec(&dns_reply(&query("Captive.Apple.com."), address).unwrap()).unwrap();
+        assert_eq!(reply.id(), 7);
+        assert_eq!(reply.response_code(), ResponseCode::NoError);
+        assert_eq!(reply.answers()[0].data(), Some(&RData::A(address.into())));
+
+        let reply =
+            Message::from_vec(&dns_reply(&query("example.com."), address).unwrap()).unwrap();
+        assert_eq!(reply.response_code(), ResponseCode::Refused);
+        assert!(reply.answers().is_empty());
+        // Real sites some OSes also probe stay theirs
+        let reply =
+            Message::from_vec(&dns_reply(&query("www.apple.com."), address).unwrap()).unwrap();
+        assert_eq!(reply.response_code(), ResponseCode::Refused);
+        assert_eq!(dns_reply(b"junk", address), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Fresh MACs for sessions limited per MAC (`[mac_rotation]`)
+//!
+//! Wi-MESH venues give each MAC a session of so many minutes, after which
+//! the portal holds it again; a device presenting a new MAC starts a new
+//! one. With `[mac_rotation] enabled`, once the login on an SSID is
+//! `after_minutes` old (the portal's `session_minutes` when 0) the daemon
+//! rotates the MAC before its next check, once per login; `wimesh
+//! rotate-mac` does it on demand. Rotating makes a locally administered
+//! random MAC (the portal's new auto identity with `identity.auto`) the
+//! cloned MAC of the NetworkManager connection, reconnects the interface
+//! and waits for it to associate again, after which the portal flow runs
+//! with the new MAC. A portal with `mac_address` set keeps that one.
+
+use crate::config::{MacRotationConfig, PortalConfig};
+use crate::identity::{random_mac, IdentityManager};
+use crate::state::{self, unix_now, State};
+use crate::utils::nonblocking;
+use anyhow::Result;
+use std::time::Duration;
+
+/// How long the interface gets to associate again with the new MAC
+pub const REASSOCIATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Seconds a session lasts on `portal`: `after_minutes`, else the portal's
+/// `session_minutes`; `None` when neither says
+pub fn session_limit(cfg: &MacRotationConfig, portal: &PortalConfig) -> Option<u64> {
+    let minutes = match cfg.after_minutes {
+        0 => portal.session_minutes?,
+        minutes => minutes,
+    };
+    Some(minutes * 60).filter(|&secs| secs > 0)
+}
+
+/// Whether the login on `ssid`, through `portal`, is due a fresh MAC at
+/// `now`: as old as the session limit, and not rotated since
+pub fn is_due(
+    cfg: &MacRotationConfig,
+    portal: &PortalConfig,
+    state: &State,
+    ssid: &str,
+    now: u64,
+) -> bool {
+    if !cfg.enabled || !portal.mac_address.is_empty() {
+        return false;
+    }
+    let (Some(limit), Some(&login)) = (session_limit(cfg, portal), state.last_login.get(ssid))
+    else {
+        return false;
+    };
+    let rotated = state.mac_rotations.get(ssid).copied().unwrap_or_default();
+    rotated < login && now.saturating_sub(login) >= limit
+}
+
+/// Present a new MAC on `ssid`, reconnecting `interface`; the new MAC. The
+/// portals of a registry built before this still hold the old identity.
+pub async fn rotate(
+    portal: &PortalConfig,
+    identities: &IdentityManager,
+    ssid: &str,
+    interface: &str,
+) -> Result<String> {
+    if !portal.mac_address.is_empty() {
+        anyhow::bail!("'{}' has mac_address set, which pins its MAC", portal.name);
+    }
+    let mac = match identities.rotate(portal) {
+        Some(identity) => identity.mac_address,
+        None => random_mac(crate::utils::random_u64()),
+    };
+    nonblocking::set_cloned_mac(ssid, &mac).await?;
+    state::nonblocking::record_mac_rotation(ssid, unix_now()).await;
+    nonblocking::bounce_interface(interface).await?;
+
+    let deadline = tokio::time::Instant::now() + REASSOCIATE_TIMEOUT;
+    while nonblocking::wifi_interface_for_ssid(ssid).await.is_none() {
+        if tokio::time::Instant::now() >= deadline {
+            anyhow::bail!(
+                "{} did not associate to '{}' again within {}s",
+                interface,
+                ssid,
+                REASSOCIATE_TIMEOUT.as_secs()
+            );
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_rotation_due_once_per_login() {
+        let mut portal = Config::default().portals.remove(0);
+        let mut cfg = MacRotationConfig {
+            enabled: true,
+            after_minutes: 0,
+        };
+        let mut state = State::default();
+        let ssid = "1.Free Wi-MESH";
+        state.last_login.insert(ssid.to_string(), 1000);
+
+        // No limit to go by
+        assert!(!is_due(&cfg, &portal, &state, ssid, 100_000));
+        portal.session_minutes = Some(60);
+        assert_eq!(session_limit(&cfg, &portal), Some(3600));
+        assert!(!is_due(&cfg, &portal, &state, ssid, 4599));
+        assert!(is_due(&cfg, &portal, &state, ssid, 4600));
+
+        // Rotated, and the login with the new MAC has not happened yet
+        state.mac_rotations.insert(ssid.to_string(), 4600);
+        assert!(!is_due(&cfg, &portal, &state, ssid, 9000));
+        state.last_login.insert(ssid.to_string(), 4700);
+        cfg.after_minutes = 30;
+        assert!(is_due(&cfg, &portal, &state, ssid, 6500));
+
+        portal.mac_address = "02:00:00:00:00:01".into();
+        assert!(!is_due(&cfg, &portal, &state, ssid, 6500));
+        portal.mac_address.clear();
+        cfg.enabled = false;
+        assert!(!is_due(&cfg, &portal, &state, ssid, 6500));
+    }
+}
@@ -0,0 +1,177 @@
+//! When a portal entry applies
+//!
+//! Some venues run one SSID two ways, a guest portal by day and a resident
+//! one at night, say. A `[[portals]]` entry with a `schedule` only handles
+//! its SSIDs inside one of its windows, and takes precedence there over
+//! entries without one. A window is weekdays and a time range in local
+//! time, `"mon-fri 08:00-22:00"`; the days are optional (every day), and a
+//! range ending before it starts runs past midnight into the next day
+//! (`"fri 22:00-07:00"` ends Saturday morning).
+
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// One weekday and time range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Bit `n` set: starts on `DAYS[n]`
+    days: u8,
+    /// Minutes after midnight, `end` up to 24:00
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    /// Whether minute `minute` of weekday `day` (0 is Monday) falls in
+    /// this window
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        let starts_on = |day: usize| self.days & (1 << day) != 0;
+        if self.start < self.end {
+            return starts_on(day) && (self.start..self.end).contains(&minute);
+        }
+        // Past midnight: the evening of its day, the morning of the next
+        let yesterday = (day + 6) % 7;
+        (starts_on(day) && minute >= self.start) || (starts_on(yesterday) && minute < self.end)
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (days, range) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, range)) => (parse_days(days.trim())?, range),
+            None => (0x7f, s.trim()),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("'{}' is not a time range like 08:00-22:00", range))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            anyhow::bail!("'{}' is an empty time range", range);
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+/// `mon-fri`, `sat,sun`, `mon,wed-fri`
+fn parse_days(s: &str) -> Result<u8> {
+    let day = |name: &str| {
+        let name = name.trim().to_ascii_lowercase();
+        DAYS.iter()
+            .position(|d| name.starts_with(d))
+            .with_context(|| format!("'{}' is not a weekday (mon, tue, ... sun)", name))
+    };
+    let mut days = 0;
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                let mut d = from;
+                loop {
+                    days |= 1 << d;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Ok(days)
+}
+
+/// `HH:MM` as minutes after midnight, `24:00` included
+fn parse_time(s: &str) -> Result<u32> {
+    let s = s.trim();
+    let (hours, minutes) = s
+        .split_once(':')
+        .with_context(|| format!("'{}' is not a time like 08:30", s))?;
+    let hours: u32 = hours.parse().with_context(|| format!("Bad hour in '{}'", s))?;
+    let minutes: u32 = minutes.parse().with_context(|| format!("Bad minutes in '{}'", s))?;
+    let total = hours * 60 + minutes;
+    if minutes >= 60 || total > MINUTES_PER_DAY {
+        anyhow::bail!("'{}' is not a time of day", s);
+    }
+    Ok(total)
+}
+
+/// The windows of one portal entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// The `schedule` of a portal entry; `None` when it has none
+    pub fn parse(windows: &[String]) -> Result<Option<Self>> {
+        if windows.is_empty() {
+            return Ok(None);
+        }
+        let windows = windows
+            .iter()
+            .map(|w| w.parse().with_context(|| format!("Bad schedule window '{}'", w)))
+            .collect::<Result<_>>()?;
+        Ok(Some(Self { windows }))
+    }
+
+    /// Whether the local time `local` (seconds since the epoch, shifted by
+    /// the UTC offset) is inside a window
+    pub fn is_active(&self, local: i64) -> bool {
+        let days = local.div_euclid(86400);
+        // 1970-01-01 was a Thursday
+        let day = (days + 3).rem_euclid(7) as usize;
+        let minute = (local.rem_euclid(86400) / 60) as u32;
+        self.windows.iter().any(|w| w.contains(day, minute))
+    }
+}
+
+/// The local time now, as `Schedule::is_active` takes it
+pub fn local_now() -> i64 {
+    crate::state::unix_now() as i64 + crate::utils::utc_offset()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local time of weekday `day` (0 is Monday), `hh:mm`
+    fn at(day: i64, hh: i64, mm: i64) -> i64 {
+        // 1970-01-05 was a Monday
+        (4 + day) * 86400 + hh * 3600 + mm * 60
+    }
+
+    #[test]
+    fn test_schedule_windows() {
+        let night = Schedule::parse(&["fri 22:00-07:00".to_string()])
+            .unwrap()
+            .unwrap();
+        assert!(night.is_active(at(4, 23, 0)));
+        assert!(night.is_active(at(5, 6, 59)));
+        assert!(!night.is_active(at(5, 7, 0)));
+        assert!(!night.is_active(at(4, 6, 0)));
+        assert!(!night.is_active(at(3, 23, 0)));
+
+        let day = Schedule::parse(&["mon-fri 08:00-18:00".into(), "sat,sun 10:00-24:00".into()])
+            .unwrap()
+            .unwrap();
+        assert!(day.is_active(at(0, 8, 0)));
+        assert!(!day.is_active(at(2, 18, 0)));
+        assert!(day.is_active(at(6, 23, 59)));
+        assert!(!day.is_active(at(6, 9, 0)));
+
+        let daily: Window = "22:00-06:00".parse().unwrap();
+        assert!(daily.contains(0, 23 * 60) && daily.contains(0, 60));
+        let wrapping: Window = "sat-mon 09:00-10:00".parse().unwrap();
+        assert!(wrapping.contains(6, 9 * 60) && !wrapping.contains(1, 9 * 60));
+
+        assert_eq!(Schedule::parse(&[]).unwrap(), None);
+        for bad in ["mon", "funday 08:00-09:00", "08:00-08:00", "25:00-26:00", "8-9"] {
+            assert!(bad.parse::<Window>().is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Portal credentials kept in the OS keyring
+//!
+//! A password, voucher or form field can be `{ keyring = "wimesh/fpt" }`
+//! instead of the secret itself, so config.toml holds no plaintext
+//! credentials; `wimesh secret set wimesh/fpt` stores it. The entry is
+//! looked up when the login needs it, not at startup, so a daemon started
+//! before the keyring is unlocked picks it up once it is. The keyring is
+//! the platform's, through its own tool: the Secret Service (GNOME
+//! Keyring, KWallet) with `secret-tool` on Linux and FreeBSD, the login
+//! keychain with `security` on macOS, the Credential Locker through
+//! PowerShell on Windows. Entries live under the service `wimesh`, named
+//! as in the config.
+
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Service the entries are stored under
+pub const SERVICE: &str = "wimesh";
+
+/// A credential from the config: the value itself, or the keyring entry
+/// holding it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    Plain(String),
+    Keyring(String),
+}
+
+impl Default for Secret {
+    fn default() -> Self {
+        Self::Plain(String::new())
+    }
+}
+
+impl Secret {
+    /// The secret a setting gives: a string, or `{ keyring = "name" }`;
+    /// `None` for anything else
+    pub fn from_value(value: &toml::Value) -> Option<Self> {
+        match value {
+            toml::Value::String(plain) => Some(Self::Plain(plain.clone())),
+            other => reference(other).map(|name| Self::Keyring(name.to_string())),
+        }
+    }
+
+    /// Whether nothing was configured
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Plain(plain) if plain.is_empty())
+    }
+
+    /// The secret itself, from the keyring if it is there; the keyring's
+    /// tool runs on the blocking pool, as it may wait for an unlock prompt
+    pub async fn reveal(&self) -> Result<String> {
+        match self {
+            Self::Plain(plain) => Ok(plain.clone()),
+            Self::Keyring(name) => {
+                let name = name.clone();
+                crate::utils::nonblocking::offload(move || get(&name)).await
+            }
+        }
+    }
+}
+
+/// As written in the config; the keyring entry's name, not its secret
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(plain) => f.write_str(plain),
+            Self::Keyring(name) => write!(f, "{{ keyring = \"{}\" }}", name),
+        }
+    }
+}
+
+/// The entry `{ keyring = "name" }` names
+pub fn reference(value: &toml::Value) -> Option<&str> {
+    let table = value.as_table().filter(|table| table.len() == 1)?;
+    table
+        .get("keyring")?
+        .as_str()
+        .filter(|name| !name.is_empty())
+}
+
+/// The secret stored as `name`
+pub fn get(name: &str) -> Result<String> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["find-generic-password", "-s", SERVICE, "-a", name, "-w"])
+            .output()
+    } else if cfg!(windows) {
+        powershell(
+            "$v = New-Object Windows.Security.Credentials.PasswordVault;\
+             $c = $v.Retrieve($env:WIMESH_SERVICE, $env:WIMESH_NAME);\
+             $c.RetrievePassword(); [Console]::Out.Write($c.Password)",
+            name,
+        )
+        .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", SERVICE, "account", name])
+            .output()
+    };
+    let output = output.with_context(|| format!("Failed to run {}", tool()))?;
+    let secret = String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    if !output.status.success() || secret.is_empty() {
+        anyhow::bail!(
+            "No keyring entry '{}' ({}), store it with `wimesh secret set {}`",
+            name,
+            tool(),
+            name
+        );
+    }
+    Ok(secret)
+}
+
+/// Store `secret` as `name`, replacing what was there
+pub fn set(name: &str, secret: &str) -> Result<()> {
+    let (mut command, stdin) = if cfg!(target_os = "macos") {
+        // `security` takes the secret as an argument or from a prompt only;
+        // its interactive mode reads the whole command from stdin instead,
+        // so the secret never shows on a command line
+        let mut command = Command::new("security");
+        command.arg("-i");
+        (command, Some(security_add_command(name, secret)?))
+    } else if cfg!(windows) {
+        let command = powershell(
+            "$v = New-Object Windows.Security.Credentials.PasswordVault;\
+             $s = [Console]::In.ReadToEnd();\
+             $v.Add((New-Object Windows.Security.Credentials.PasswordCredential(\
+             $env:WIMESH_SERVICE, $env:WIMESH_NAME, $s)))",
+            name,
+        );
+        (command, Some(secret.to_string()))
+    } else {
+        let mut command = Command::new("secret-tool");
+        let label = format!("{} {}", SERVICE, name);
+        command.args([
+            "store", "--label", &label, "service", SERVICE, "account", name,
+        ]);
+        (command, Some(secret.to_string()))
+    };
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to run {}", tool()))?;
+    if let (Some(secret), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(secret.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    // `security -i` exits 0 whatever its commands did, and says so on stderr
+    let complained = cfg!(target_os = "macos") && !output.stderr.trim_ascii().is_empty();
+    if !output.status.success() || complained {
+        anyhow::bail!(
+            "{} could not store '{}': {}",
+            tool(),
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The line `security -i` runs to store `secret` as `name`
+fn security_add_command(name: &str, secret: &str) -> Result<String> {
+    if secret.contains(['\n', '\r']) || name.contains(['\n', '\r']) {
+        anyhow::bail!("The keychain cannot store a secret or name spanning lines");
+    }
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    Ok(format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        quote(SERVICE),
+        quote(name),
+        quote(secret)
+    ))
+}
+
+/// Remove the entry `name`
+pub fn delete(name: &str) -> Result<()> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["delete-generic-password", "-s", SERVICE, "-a", name])
+            .output()
+    } else if cfg!(windows) {
+        powershell(
+            "$v = New-Object Windows.Security.Credentials.PasswordVault;\
+             $v.Remove($v.Retrieve($env:WIMESH_SERVICE, $env:WIMESH_NAME))",
+            name,
+        )
+        .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["clear", "service", SERVICE, "account", name])
+            .output()
+    };
+    let output = output.with_context(|| format!("Failed to run {}", tool()))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} could not remove '{}': {}",
+            tool(),
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// The program talking to the keyring here
+pub fn tool() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "security"
+    } else if cfg!(windows) {
+        "powershell"
+    } else {
+        "secret-tool"
+    }
+}
+
+/// Windows PowerShell running `script`, with the entry's service and name
+/// in the environment rather than on the command line
+fn powershell(script: &str, name: &str) -> Command {
+    let mut command = Command::new("powershell");
+    let script = format!(
+        "[void][Windows.Security.Credentials.PasswordVault,Windows.Security.Credentials,\
+         ContentType=WindowsRuntime]; {}",
+        script
+    );
+    command
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .env("WIMESH_SERVICE", SERVICE)
+        .env("WIMESH_NAME", name);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secret_from_value() {
+        let value: toml::Value = toml::from_str(
+            r#"
+            plain = "hunter2"
+            stored = { keyring = "wimesh/fpt" }
+            other = { keyring = "wimesh/fpt", extra = 1 }
+            empty = { keyring = "" }
+            "#,
+        )
+        .unwrap();
+        let secret = |key: &str| Secret::from_value(&value[key]);
+
+        assert_eq!(secret("plain"), Some(Secret::Plain("hunter2".into())));
+        assert_eq!(secret("plain").unwrap().reveal().await.unwrap(), "hunter2");
+        assert_eq!(secret("stored"), Some(Secret::Keyring("wimesh/fpt".into())));
+        assert!(!secret("stored").unwrap().is_empty());
+        assert_eq!(secret("other"), None);
+        assert_eq!(secret("empty"), None);
+        assert!(Secret::default().is_empty());
+        assert_eq!(reference(&value["stored"]), Some("wimesh/fpt"));
+    }
+
+    #[test]
+    fn test_security_add_command_quotes_the_secret() {
+        assert_eq!(
+            security_add_command("wimesh/fpt", r#"p"a\ss w"#).unwrap(),
+            "add-generic-password -U -s \"wimesh\" -a \"wimesh/fpt\" -w \"p\\\"a\\\\ss w\"\n"
+        );
+        assert!(security_add_command("wimesh/fpt", "two\nlines").is_err());
+    }
+}
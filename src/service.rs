@@ -0,0 +1,433 @@
+//! Service definitions for running the daemon
+//!
+//! `wimesh service` prints a systemd unit (or a NixOS / Home Manager module)
+//! hardened for what this build and configuration actually need, instead of
+//! the generic template: no fixed user, a read-only system, and no
+//! capabilities beyond the ones the enabled probes use. On FreeBSD it prints
+//! an rc.d script instead.
+
+use crate::config::{Config, ResponderConfig};
+use std::path::Path;
+
+/// Helper programs the daemon runs
+const RUNTIME_TOOLS: &[(&str, &str)] = &[
+    ("nmcli", "networkmanager"),
+    ("ip", "iproute2"),
+    ("ping", "iputils"),
+];
+
+/// `Key=value` lines of the `[Service]` section shared by every format
+///
+/// The gateway probe falls back to `ping`, which needs `CAP_NET_RAW` where
+/// unprivileged ICMP sockets are not enabled, and the `[responder]` on
+/// port 80 needs `CAP_NET_BIND_SERVICE`; nothing else needs a capability.
+/// Identity MACs are cloned through NetworkManager, which authorizes that
+/// itself (polkit), so `CAP_NET_ADMIN` is never granted.
+pub fn hardening(cfg: &Config) -> Vec<(&'static str, String)> {
+    let mut capabilities = Vec::new();
+    if cfg.global.probe_gateway {
+        capabilities.push("CAP_NET_RAW");
+    }
+    if cfg.responder.enabled && binds_privileged_port(&cfg.responder) {
+        capabilities.push("CAP_NET_BIND_SERVICE");
+    }
+    let capabilities = capabilities.join(" ");
+
+    let mut lines: Vec<(&'static str, String)> = vec![
+        ("DynamicUser", "yes".into()),
+        ("StateDirectory", "wimesh".into()),
+        ("ConfigurationDirectory", "wimesh".into()),
+        ("WorkingDirectory", "/etc/wimesh".into()),
+        ("CapabilityBoundingSet", capabilities.clone()),
+        ("AmbientCapabilities", capabilities),
+        ("NoNewPrivileges", "yes".into()),
+        ("ProtectSystem", "strict".into()),
+        ("ProtectHome", "yes".into()),
+        ("PrivateTmp", "yes".into()),
+        ("PrivateDevices", "yes".into()),
+        ("ProtectClock", "yes".into()),
+        ("ProtectHostname", "yes".into()),
+        ("ProtectKernelTunables", "yes".into()),
+        ("ProtectKernelModules", "yes".into()),
+        ("ProtectKernelLogs", "yes".into()),
+        ("ProtectControlGroups", "yes".into()),
+        ("ProtectProc", "invisible".into()),
+        ("RestrictNamespaces", "yes".into()),
+        ("RestrictRealtime", "yes".into()),
+        ("RestrictSUIDSGID", "yes".into()),
+        ("LockPersonality", "yes".into()),
+        ("MemoryDenyWriteExecute", "yes".into()),
+        ("SystemCallArchitectures", "native".into()),
+        ("SystemCallFilter", "@system-service".into()),
+        ("UMask", "0077".into()),
+    ];
+    // nmcli talks D-Bus over AF_UNIX, `ip route` uses netlink
+    lines.push((
+        "RestrictAddressFamilies",
+        "AF_UNIX AF_INET AF_INET6 AF_NETLINK".into(),
+    ));
+    lines
+}
+
+/// How the daemon reports to systemd (see `systemd`): ready once its first
+/// check is done, which may be a whole login, then pinging the watchdog
+pub const NOTIFY: &[(&str, &str)] = &[
+    ("Type", "notify"),
+    ("TimeoutStartSec", "300"),
+    ("WatchdogSec", "120"),
+];
+
+/// Settings of `hardening` a user service can have: it cannot switch users
+/// or hold capabilities
+const USER_SAFE: &[&str] = &[
+    "NoNewPrivileges",
+    "PrivateTmp",
+    "ProtectKernelTunables",
+    "ProtectKernelModules",
+    "ProtectControlGroups",
+    "RestrictNamespaces",
+    "RestrictRealtime",
+    "LockPersonality",
+    "MemoryDenyWriteExecute",
+    "SystemCallArchitectures",
+    "RestrictAddressFamilies",
+];
+
+/// Hardened system unit running `binary daemon`
+pub fn systemd_unit(cfg: &Config, binary: &str) -> String {
+    let exec = format!("{} daemon", binary);
+    system_unit("wimesh service systemd", &exec, hardening(cfg))
+}
+
+/// Hardened system unit running `binary` with the config at `config`, as
+/// `wimesh install-service` writes it
+///
+/// The sandbox hides home directories, so a config in one is left
+/// readable (`ProtectHome=read-only`) for a dynamic user allowed to read it.
+pub fn installed_unit(cfg: &Config, binary: &str, config: &Path) -> String {
+    let exec = format!(
+        "{} --config {} daemon",
+        exec_arg(binary),
+        exec_arg(&config.display().to_string())
+    );
+    let dir = config.parent().unwrap_or(Path::new("/"));
+    let in_home = ["/home", "/root"]
+        .iter()
+        .any(|home| config.starts_with(home));
+    let mut lines = hardening(cfg);
+    for (key, value) in &mut lines {
+        match *key {
+            "WorkingDirectory" => *value = dir.display().to_string(),
+            "ProtectHome" if in_home => *value = "read-only".into(),
+            _ => {}
+        }
+    }
+    system_unit("wimesh install-service", &exec, lines)
+}
+
+/// User unit running `binary` with the config at `config`, as `wimesh
+/// install-service --user` writes it
+pub fn installed_user_unit(cfg: &Config, binary: &str, config: &Path) -> String {
+    let mut unit = String::from(concat!(
+        "# Generated by `wimesh install-service --user`\n",
+        "[Unit]\n",
+        "Description=Wimesh Auto-Login Service\n",
+        "Documentation=https://github.com/sotsuba/wimesh\n",
+        "\n",
+        "[Service]\n",
+    ));
+    for (key, value) in NOTIFY {
+        unit.push_str(&format!("{}={}\n", key, value));
+    }
+    unit.push_str(&format!(
+        "ExecStart={} --config {} daemon\n",
+        exec_arg(binary),
+        exec_arg(&config.display().to_string())
+    ));
+    unit.push_str("Restart=on-failure\nRestartSec=10\n\n");
+    for (key, value) in hardening(cfg) {
+        if USER_SAFE.contains(&key) {
+            unit.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    unit.push_str("\n[Install]\nWantedBy=default.target\n");
+    unit
+}
+
+fn system_unit(generator: &str, exec: &str, hardening: Vec<(&'static str, String)>) -> String {
+    let mut unit = format!("# Generated by `{}`\n", generator);
+    unit.push_str(concat!(
+        "[Unit]\n",
+        "Description=Wimesh Auto-Login Service\n",
+        "Documentation=https://github.com/sotsuba/wimesh\n",
+        "After=network-online.target NetworkManager.service\n",
+        "Wants=network-online.target\n",
+        "\n",
+        "[Service]\n",
+    ));
+    for (key, value) in NOTIFY {
+        unit.push_str(&format!("{}={}\n", key, value));
+    }
+    unit.push_str(&format!("ExecStart={}\n", exec));
+    unit.push_str(concat!(
+        "Restart=on-failure\n",
+        "RestartSec=10\n",
+        "StandardOutput=journal\n",
+        "StandardError=journal\n",
+        "SyslogIdentifier=wimesh\n",
+        "\n",
+    ));
+    for (key, value) in hardening {
+        unit.push_str(&format!("{}={}\n", key, value));
+    }
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+/// `arg` as one word of an `ExecStart=` line
+fn exec_arg(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'\\;$%".contains(c)) {
+        let escaped = arg
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "$$")
+            .replace('%', "%%");
+        format!("\"{}\"", escaped)
+    } else {
+        arg.to_string()
+    }
+}
+
+/// Label of the macOS LaunchAgent
+pub const LAUNCHD_LABEL: &str = "io.github.sotsuba.wimesh";
+
+/// macOS LaunchAgent running `binary` with the config at `config`, started
+/// at login and kept alive, its output appended to `log`
+pub fn launchd_agent(binary: &str, config: &Path, log: &Path) -> String {
+    let config = config.display().to_string();
+    let log = xml_escape(&log.display().to_string());
+    let mut plist = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<!-- Generated by `wimesh install-service` -->\n",
+        "<plist version=\"1.0\">\n",
+        "<dict>\n",
+    ));
+    plist.push_str(&format!(
+        "  <key>Label</key>\n  <string>{}</string>\n",
+        LAUNCHD_LABEL
+    ));
+    plist.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    for arg in [binary, "--config", &config, "daemon"] {
+        plist.push_str(&format!("    <string>{}</string>\n", xml_escape(arg)));
+    }
+    plist.push_str(concat!(
+        "  </array>\n",
+        "  <key>RunAtLoad</key>\n",
+        "  <true/>\n",
+        "  <key>KeepAlive</key>\n",
+        "  <true/>\n",
+        "  <key>ThrottleInterval</key>\n",
+        "  <integer>10</integer>\n",
+        "  <key>ProcessType</key>\n",
+        "  <string>Background</string>\n",
+    ));
+    plist.push_str(&format!(
+        "  <key>StandardOutPath</key>\n  <string>{}</string>\n\
+         \x20 <key>StandardErrorPath</key>\n  <string>{}</string>\n",
+        log, log
+    ));
+    plist.push_str("</dict>\n</plist>\n");
+    plist
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// NixOS module providing `services.wimesh`
+pub fn nixos_module(cfg: &Config) -> String {
+    let mut module = String::from(concat!(
+        "# Generated by `wimesh service nixos`\n",
+        "{ config, lib, pkgs, ... }:\n",
+        "\n",
+        "let\n",
+        "  cfg = config.services.wimesh;\n",
+        "  toml = pkgs.formats.toml { };\n",
+        "in\n",
+        "{\n",
+        "  options.services.wimesh = {\n",
+        "    enable = lib.mkEnableOption \"Wimesh captive portal auto-login\";\n",
+        "    package = lib.mkOption {\n",
+        "      type = lib.types.package;\n",
+        "      description = \"Package providing bin/wimesh.\";\n",
+        "    };\n",
+        "    settings = lib.mkOption {\n",
+        "      type = toml.type;\n",
+        "      default = { };\n",
+        "      description = \"Contents of /etc/wimesh/config.toml.\";\n",
+        "    };\n",
+        "  };\n",
+        "\n",
+        "  config = lib.mkIf cfg.enable {\n",
+        "    environment.etc.\"wimesh/config.toml\".source =\n",
+        "      toml.generate \"wimesh-config.toml\" cfg.settings;\n",
+        "\n",
+        "    systemd.services.wimesh = {\n",
+        "      description = \"Wimesh Auto-Login Service\";\n",
+        "      wantedBy = [ \"multi-user.target\" ];\n",
+        "      after = [ \"network-online.target\" \"NetworkManager.service\" ];\n",
+        "      wants = [ \"network-online.target\" ];\n",
+    ));
+    module.push_str(&format!("      path = {};\n", nix_tool_list()));
+    module.push_str(concat!(
+        "      serviceConfig = {\n",
+        "        ExecStart = \"${cfg.package}/bin/wimesh daemon\";\n",
+        "        Restart = \"on-failure\";\n",
+        "        RestartSec = 10;\n",
+    ));
+    for (key, value) in NOTIFY {
+        module.push_str(&format!("        {} = {};\n", key, nix_value(value)));
+    }
+    for (key, value) in hardening(cfg) {
+        module.push_str(&format!("        {} = {};\n", key, nix_value(&value)));
+    }
+    module.push_str("      };\n    };\n  };\n}\n");
+    module
+}
+
+/// Home Manager module running the daemon as a user service
+///
+/// User services cannot switch users or hold capabilities, so only the
+/// filesystem and kernel protections carry over, and the gateway probe
+/// relies on unprivileged ping.
+pub fn home_manager_module(cfg: &Config) -> String {
+    let mut module = String::from(concat!(
+        "# Generated by `wimesh service home-manager`\n",
+        "{ config, lib, pkgs, ... }:\n",
+        "\n",
+        "let\n",
+        "  cfg = config.services.wimesh;\n",
+        "  toml = pkgs.formats.toml { };\n",
+        "in\n",
+        "{\n",
+        "  options.services.wimesh = {\n",
+        "    enable = lib.mkEnableOption \"Wimesh captive portal auto-login\";\n",
+        "    package = lib.mkOption {\n",
+        "      type = lib.types.package;\n",
+        "      description = \"Package providing bin/wimesh.\";\n",
+        "    };\n",
+        "    settings = lib.mkOption {\n",
+        "      type = toml.type;\n",
+        "      default = { };\n",
+        "      description = \"Contents of ~/.config/wimesh/config.toml.\";\n",
+        "    };\n",
+        "  };\n",
+        "\n",
+        "  config = lib.mkIf cfg.enable {\n",
+        "    xdg.configFile.\"wimesh/config.toml\".source =\n",
+        "      toml.generate \"wimesh-config.toml\" cfg.settings;\n",
+        "\n",
+        "    systemd.user.services.wimesh = {\n",
+        "      Unit.Description = \"Wimesh Auto-Login Service\";\n",
+        "      Install.WantedBy = [ \"default.target\" ];\n",
+        "      Service = {\n",
+        "        ExecStart = \"${cfg.package}/bin/wimesh daemon\";\n",
+        "        Restart = \"on-failure\";\n",
+        "        RestartSec = 10;\n",
+    ));
+    for (key, value) in NOTIFY {
+        module.push_str(&format!("        {} = {};\n", key, nix_value(value)));
+    }
+    module.push_str(&format!(
+        "        Environment = \"PATH=${{lib.makeBinPath {}}}\";\n",
+        nix_tool_list()
+    ));
+    for (key, value) in hardening(cfg) {
+        if USER_SAFE.contains(&key) {
+            module.push_str(&format!("        {} = {};\n", key, nix_value(&value)));
+        }
+    }
+    module.push_str("      };\n    };\n  };\n}\n");
+    module
+}
+
+/// rc.d script for FreeBSD, supervising `binary daemon` with daemon(8)
+///
+/// Reads its config from /usr/local/etc/wimesh and keeps state in
+/// /var/db/wimesh, the FreeBSD locations.
+pub fn rcd_script(binary: &str) -> String {
+    let mut script = String::from(concat!(
+        "#!/bin/sh\n",
+        "# Generated by `wimesh service rcd`\n",
+        "#\n",
+        "# PROVIDE: wimesh\n",
+        "# REQUIRE: NETWORKING\n",
+        "# KEYWORD: shutdown\n",
+        "#\n",
+        "# Add to /etc/rc.conf:  wimesh_enable=\"YES\"\n",
+        "\n",
+        ". /etc/rc.subr\n",
+        "\n",
+        "name=\"wimesh\"\n",
+        "rcvar=\"wimesh_enable\"\n",
+        "\n",
+        "load_rc_config $name\n",
+        "\n",
+        ": ${wimesh_enable:=\"NO\"}\n",
+        ": ${wimesh_config_dir:=\"/usr/local/etc/wimesh\"}\n",
+        ": ${wimesh_state_dir:=\"/var/db/wimesh\"}\n",
+        "\n",
+        "pidfile=\"/var/run/${name}.pid\"\n",
+        "wimesh_chdir=\"${wimesh_config_dir}\"\n",
+        "wimesh_env=\"STATE_DIRECTORY=${wimesh_state_dir}\"\n",
+        "command=\"/usr/sbin/daemon\"\n",
+    ));
+    script.push_str(&format!(
+        "command_args=\"-r -S -T ${{name}} -P ${{pidfile}} {} daemon\"\n",
+        binary
+    ));
+    script.push_str(concat!(
+        "start_precmd=\"wimesh_precmd\"\n",
+        "\n",
+        "wimesh_precmd()\n",
+        "{\n",
+        "    install -d -m 700 \"${wimesh_state_dir}\"\n",
+        "}\n",
+        "\n",
+        "run_rc_command \"$1\"\n",
+    ));
+    script
+}
+
+/// Whether one of the responder's listeners is on a port below 1024
+fn binds_privileged_port(responder: &ResponderConfig) -> bool {
+    [&responder.http_listen, &responder.dns_listen]
+        .into_iter()
+        .filter_map(|addr| addr.parse::<std::net::SocketAddr>().ok())
+        .any(|addr| addr.port() < 1024)
+}
+
+fn nix_tool_list() -> String {
+    let packages: Vec<String> = RUNTIME_TOOLS
+        .iter()
+        .map(|(_, package)| format!("pkgs.{}", package))
+        .collect();
+    format!("[ {} ]", packages.join(" "))
+}
+
+/// A unit setting as a Nix value: booleans and numbers bare, the rest
+/// quoted
+fn nix_value(value: &str) -> String {
+    match value {
+        "yes" => "true".to_string(),
+        "no" => "false".to_string(),
+        v if !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()) && !v.starts_with('0') => {
+            v.to_string()
+        }
+        v => format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
@@ -0,0 +1,705 @@
+//! Persistent runtime state
+//!
+//! A small file remembering what has to survive between runs, such as when
+//! each SSID was last logged into. The daemon writes it; one-shot runs and
+//! the status widget read it. How it is stored is up to the selected
+//! `StateStore` (a JSON file by default).
+
+use crate::identity::Identity;
+use crate::models::SessionInfo;
+use crate::policy::RetryPolicy;
+use crate::store;
+use crate::supervisor::Health;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct State {
+    /// Unix time of the last successful login, per SSID
+    #[serde(default)]
+    pub last_login: HashMap<String, u64>,
+
+    /// Last successful portal session, per SSID
+    #[serde(default)]
+    pub sessions: HashMap<String, CachedSession>,
+
+    /// What the portal last reported about the session, per SSID
+    #[serde(default)]
+    pub session_info: HashMap<String, SessionInfo>,
+
+    /// Login failure backoff, per SSID
+    #[serde(default)]
+    pub backoff: HashMap<String, Backoff>,
+
+    /// The routers last seen behind each access point, per BSSID
+    #[serde(default)]
+    pub venues: HashMap<String, Venue>,
+
+    /// Generated auto identities, per portal name
+    #[serde(default)]
+    pub identities: HashMap<String, Identity>,
+
+    /// What the daemon plans to do next, rewritten on every check
+    #[serde(default)]
+    pub next_action: Option<NextAction>,
+
+    /// `wimesh read-only` override of `global.read_only`, for the running
+    /// daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+
+    /// Unix time `wimesh control pause` paused the running daemon's checks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused_at: Option<u64>,
+
+    /// Unix time the MAC was last rotated, per SSID (see `rotation`)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub mac_rotations: HashMap<String, u64>,
+
+    /// How the daemon's optional parts fare, by name (see `supervisor`)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub subsystems: HashMap<String, Health>,
+}
+
+/// The daemon's next scheduled action, for "is it stuck or waiting?"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NextAction {
+    pub action: Action,
+    /// Unix time it is due
+    pub at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// Unix time the daemon planned it; a daemon that stopped updating this
+    /// is not running
+    pub planned_at: u64,
+}
+
+/// Kinds of `NextAction`
+///
+/// Names are part of the status JSON: add new ones, never rename.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Check WiFi and connectivity again
+    Probe,
+    /// Run the login flow at the next check
+    Login,
+    /// Retry the login once the failure backoff is over
+    RetryAfterBackoff,
+    /// Log in again once the portal session runs out
+    RenewSession,
+}
+
+impl NextAction {
+    /// `retry login on '1.Free Wi-MESH' in 42s`
+    pub fn describe(&self, now: u64) -> String {
+        let what = match self.action {
+            Action::Probe => "check connectivity",
+            Action::Login => "log in",
+            Action::RetryAfterBackoff => "retry login after backoff",
+            Action::RenewSession => "log in again when the session expires",
+        };
+        let mut line = what.to_string();
+        if let Some(ref ssid) = self.ssid {
+            line.push_str(&format!(" on '{}'", ssid));
+        }
+        match self.at.checked_sub(now) {
+            Some(wait) => line.push_str(&format!(" in {}s", wait)),
+            None => line.push_str(&format!(", due {}s ago", now - self.at)),
+        }
+        line
+    }
+
+    /// Whether this, planned by an earlier run, is something other than a
+    /// check and already due
+    pub fn is_overdue(&self, now: u64) -> bool {
+        self.action != Action::Probe && self.at <= now
+    }
+
+    /// Seconds until this login, if it is one and due within `within`
+    pub fn login_within(&self, now: u64, within: u64) -> Option<u64> {
+        if !matches!(self.action, Action::Login | Action::RetryAfterBackoff) {
+            return None;
+        }
+        let wait = self.at.saturating_sub(now);
+        (wait <= within).then_some(wait)
+    }
+}
+
+/// Where an SSID is in the login failure backoff schedule
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Backoff {
+    /// Consecutive failed logins
+    pub failures: u32,
+    /// Unix time before which no login is attempted
+    pub until: u64,
+}
+
+impl Backoff {
+    /// The schedule after one more failure at `now`: no wait for the
+    /// first `policy.max_attempts` - 1 failures, then the policy's delays.
+    /// A failure the policy does not retry starts the waits right away.
+    pub fn after_failure(self, now: u64, policy: &RetryPolicy, retried: bool) -> Self {
+        let threshold = policy.max_attempts.max(1);
+        let mut failures = self.failures.saturating_add(1);
+        if !retried {
+            failures = failures.max(threshold);
+        }
+        if failures < threshold {
+            return Self { failures, until: 0 };
+        }
+        let delay = policy.jittered_delay(failures - threshold + 1).as_secs();
+        Self {
+            failures,
+            until: now + delay,
+        }
+    }
+}
+
+/// Whatever a portal needs to re-validate a session without the full flow
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedSession {
+    /// Name of the portal that created the session
+    pub portal: String,
+    /// Unix time the session was saved
+    pub saved_at: u64,
+    /// Portal-specific session data
+    pub data: serde_json::Value,
+}
+
+/// The routers behind an access point, as far as they were seen
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Venue {
+    /// MAC of the default gateway, from the neighbor table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_mac: Option<String>,
+    /// Router login URL the captive page named
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_url: Option<String>,
+}
+
+impl Venue {
+    /// What differs in `now`, of what both know; None if nothing does
+    pub fn changes(&self, now: &Venue) -> Option<String> {
+        let mut changes = Vec::new();
+        let fields = [
+            ("gateway MAC", &self.gateway_mac, &now.gateway_mac),
+            ("login URL", &self.login_url, &now.login_url),
+        ];
+        for (name, before, after) in fields {
+            if let (Some(before), Some(after)) = (before, after) {
+                if before != after {
+                    changes.push(format!("{} {} -> {}", name, before, after));
+                }
+            }
+        }
+        (!changes.is_empty()).then(|| changes.join(", "))
+    }
+
+    /// `self` with what `now` saw instead
+    fn update(&mut self, now: Venue) {
+        if now.gateway_mac.is_some() {
+            self.gateway_mac = now.gateway_mac;
+        }
+        if now.login_url.is_some() {
+            self.login_url = now.login_url;
+        }
+    }
+}
+
+/// Current Unix time in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The only state directory of this process, when `isolate_in` set one
+static ISOLATED: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keep every runtime file of this process in `dir`, never reading or
+/// writing the real ones, as the stress harness does; for the rest of the
+/// process, so call it before anything touches state
+pub fn isolate_in(dir: &Path) -> Result<()> {
+    let isolated = ISOLATED.get_or_init(|| dir.to_path_buf());
+    if isolated != dir {
+        anyhow::bail!("State already isolated in {}", isolated.display());
+    }
+    Ok(())
+}
+
+/// Directories runtime files may live in, primary (written) one first
+pub fn state_dirs() -> Vec<PathBuf> {
+    if let Some(dir) = ISOLATED.get() {
+        return vec![dir.clone()];
+    }
+    let mut found = Vec::new();
+    if let Some(dir) = std::env::var_os("STATE_DIRECTORY") {
+        found.push(PathBuf::from(dir));
+    }
+    if let Some(dir) = dirs::state_dir().or_else(dirs::cache_dir) {
+        found.push(dir.join("wimesh"));
+    }
+    found.push(PathBuf::from("/var/lib/wimesh"));
+    found
+}
+
+/// Held while this process reads, changes and writes back the state, so
+/// the daemon loop, the supervised subsystems and the control socket do
+/// not undo each other's changes
+static WRITER: Mutex<()> = Mutex::new(());
+
+fn writer() -> std::sync::MutexGuard<'static, ()> {
+    WRITER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl State {
+    /// Where state files may live, primary (written) location first
+    ///
+    /// Under systemd `StateDirectory=` the daemon writes to
+    /// `$STATE_DIRECTORY`, while a widget in the user session looks in its
+    /// own state dir, so readers check every location.
+    pub fn candidate_paths() -> Vec<PathBuf> {
+        state_dirs()
+            .into_iter()
+            .map(|dir| dir.join(store::current().file_name()))
+            .collect()
+    }
+
+    /// Load the most recently written state file, or empty state if none
+    pub fn load() -> Self {
+        let newest = Self::candidate_paths()
+            .into_iter()
+            .filter_map(|path| {
+                let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+                Some((modified, path))
+            })
+            .max_by_key(|(modified, _)| *modified);
+
+        let Some((_, path)) = newest else {
+            return Self::default();
+        };
+
+        match store::current().read(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable state file {}: {:#}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Write the state to the primary location
+    pub fn save(&self) -> Result<()> {
+        let path = Self::candidate_paths()
+            .into_iter()
+            .next()
+            .context("No state directory available")?;
+        self.save_to(&path)
+    }
+
+    fn save_to(&self, path: &Path) -> Result<()> {
+        store::current().write(path, self)
+    }
+
+    /// Load the state, apply `change` and save it if `change` says it
+    /// changed anything, with no other change of this process in between
+    fn modify(change: impl FnOnce(&mut Self) -> bool) {
+        let _writer = writer();
+        let mut state = Self::load();
+        if !change(&mut state) {
+            return;
+        }
+        if let Err(e) = state.save() {
+            tracing::warn!("Failed to save state: {:#}", e);
+        }
+    }
+
+    /// Read the state at the primary location and write it back, as the
+    /// daemon is about to: fails while its directory is read-only or the
+    /// database is locked
+    pub fn check_store() -> Result<()> {
+        let _writer = writer();
+        let path = Self::candidate_paths()
+            .into_iter()
+            .next()
+            .context("No state directory available")?;
+        let state = match path.exists() {
+            true => store::current().read(&path)?,
+            false => Self::default(),
+        };
+        state.save_to(&path)
+    }
+
+    /// Remember a successful login on `ssid`, with the portal's session if
+    /// it exported one, and persist it
+    pub fn record_login(ssid: &str, portal: &str, session: Option<serde_json::Value>) {
+        Self::modify(|state| {
+            let now = unix_now();
+            state.last_login.insert(ssid.to_string(), now);
+            // A new login starts a new session; the old report no longer holds
+            state.session_info.remove(ssid);
+            state.backoff.remove(ssid);
+            match session {
+                Some(data) => {
+                    state.sessions.insert(
+                        ssid.to_string(),
+                        CachedSession {
+                            portal: portal.to_string(),
+                            saved_at: now,
+                            data,
+                        },
+                    );
+                }
+                None => {
+                    state.sessions.remove(ssid);
+                }
+            }
+            true
+        });
+    }
+
+    /// Replace the data of `portal`'s cached session on `ssid` with what it
+    /// holds now (cookies renewed since the login), keeping its age
+    pub fn refresh_session(ssid: &str, portal: &str, data: serde_json::Value) {
+        Self::modify(|state| {
+            let Some(cached) = state.sessions.get_mut(ssid).filter(|s| s.portal == portal) else {
+                return false;
+            };
+            if cached.data == data {
+                return false;
+            }
+            cached.data = data;
+            true
+        });
+    }
+
+    /// The cached session of `portal` on `ssid`, if younger than `ttl` seconds
+    pub fn session(&self, ssid: &str, portal: &str, ttl: u64) -> Option<&CachedSession> {
+        self.sessions
+            .get(ssid)
+            .filter(|s| s.portal == portal && unix_now().saturating_sub(s.saved_at) < ttl)
+    }
+
+    /// Remember what the portal reported about the session on `ssid`
+    pub fn record_session_info(ssid: &str, info: SessionInfo) {
+        Self::modify(|state| {
+            state.session_info.insert(ssid.to_string(), info);
+            true
+        });
+    }
+
+    /// Remember the auto identity generated for `portal`
+    pub fn record_identity(portal: &str, identity: Identity) {
+        Self::modify(|state| {
+            state.identities.insert(portal.to_string(), identity);
+            true
+        });
+    }
+
+    /// Forget the cached session on `ssid`, e.g. once it failed to resume
+    pub fn forget_session(ssid: &str) {
+        Self::modify(|state| state.sessions.remove(ssid).is_some());
+    }
+
+    /// Remember the routers seen behind `bssid`, returning what changed
+    /// since they were last seen
+    pub fn observe_venue(bssid: &str, now: Venue) -> Option<String> {
+        let mut changes = None;
+        Self::modify(|state| {
+            let venue = state.venues.entry(bssid.to_string()).or_default();
+            let before = venue.clone();
+            changes = venue.changes(&now);
+            venue.update(now);
+            *venue != before
+        });
+        changes
+    }
+
+    /// Seconds left before a login may be attempted on `ssid` again
+    pub fn backoff_remaining(&self, ssid: &str) -> Option<u64> {
+        let until = self.backoff.get(ssid)?.until;
+        let now = unix_now();
+        (until > now).then(|| until - now)
+    }
+
+    /// Count a failed login on `ssid`, failing with `err`, and persist the
+    /// new backoff schedule
+    pub fn record_failure(ssid: &str, policy: &RetryPolicy, err: &anyhow::Error) -> Backoff {
+        Self::record_retried_failure(ssid, policy, policy.retries(err))
+    }
+
+    /// `record_failure`, with whether `policy` retries the error
+    fn record_retried_failure(ssid: &str, policy: &RetryPolicy, retried: bool) -> Backoff {
+        let mut backoff = Backoff::default();
+        Self::modify(|state| {
+            let entry = state.backoff.entry(ssid.to_string()).or_default();
+            *entry = entry.after_failure(unix_now(), policy, retried);
+            backoff = *entry;
+            true
+        });
+        backoff
+    }
+
+    /// Publish what the daemon does next
+    pub fn record_next_action(next: NextAction) {
+        Self::modify(|state| {
+            state.next_action = Some(next);
+            true
+        });
+    }
+
+    /// Replace the health of the daemon's optional parts
+    pub fn record_subsystems(subsystems: HashMap<String, Health>) {
+        Self::modify(|state| {
+            state.subsystems = subsystems;
+            true
+        });
+    }
+
+    /// Remember when the daemon paused its checks (None: it checks)
+    pub fn record_paused(at: Option<u64>) {
+        Self::modify(|state| {
+            if state.paused_at == at {
+                return false;
+            }
+            state.paused_at = at;
+            true
+        });
+    }
+
+    /// Remember that the MAC on `ssid` was rotated at `at`
+    pub fn record_mac_rotation(ssid: &str, at: u64) {
+        Self::modify(|state| {
+            state.mac_rotations.insert(ssid.to_string(), at);
+            true
+        });
+    }
+
+    /// Forget the failures on `ssid`, e.g. once the internet works again
+    pub fn clear_backoff(ssid: &str) {
+        Self::modify(|state| state.backoff.remove(ssid).is_some());
+    }
+
+    /// Whether the daemon only watches, with `configured` the config's
+    /// `global.read_only`
+    pub fn is_read_only(&self, configured: bool) -> bool {
+        self.read_only.unwrap_or(configured)
+    }
+
+    /// Override read-only mode (None: back to the config) in every state
+    /// file there is, like `reset_backoff`
+    pub fn set_read_only(read_only: Option<bool>) -> Result<()> {
+        let _writer = writer();
+        let mut written = false;
+        for path in Self::candidate_paths() {
+            if !path.exists() {
+                continue;
+            }
+            let mut state = store::current().read(&path)?;
+            state.read_only = read_only;
+            state.save_to(&path)?;
+            written = true;
+        }
+        if !written {
+            Self {
+                read_only,
+                ..Self::default()
+            }
+            .save()?;
+        }
+        Ok(())
+    }
+
+    /// Clear the backoff of `ssid`, or of every SSID, in every state file
+    /// there is, so a daemon writing to another location sees it too;
+    /// returns how many schedules were cleared
+    pub fn reset_backoff(ssid: Option<&str>) -> Result<usize> {
+        let _writer = writer();
+        let mut cleared = 0;
+        for path in Self::candidate_paths() {
+            if !path.exists() {
+                continue;
+            }
+            let mut state = store::current().read(&path)?;
+            let before = state.backoff.len();
+            match ssid {
+                Some(ssid) => {
+                    state.backoff.remove(ssid);
+                }
+                None => state.backoff.clear(),
+            }
+            if state.backoff.len() < before {
+                cleared += before - state.backoff.len();
+                state.save_to(&path)?;
+            }
+        }
+        Ok(cleared)
+    }
+}
+
+/// The `State` functions async code calls, on tokio's blocking pool: they
+/// read and write files (or SQLite, waiting out its lock) under a
+/// process-wide mutex
+pub mod nonblocking {
+    use super::*;
+    use crate::utils::nonblocking::offload;
+
+    /// `State::load`
+    pub async fn load() -> State {
+        offload(State::load).await
+    }
+
+    /// `State::check_store`
+    pub async fn check_store() -> Result<()> {
+        offload(State::check_store).await
+    }
+
+    /// `State::record_login`
+    pub async fn record_login(ssid: &str, portal: &str, session: Option<serde_json::Value>) {
+        let (ssid, portal) = (ssid.to_string(), portal.to_string());
+        offload(move || State::record_login(&ssid, &portal, session)).await
+    }
+
+    /// `State::refresh_session`
+    pub async fn refresh_session(ssid: &str, portal: &str, data: serde_json::Value) {
+        let (ssid, portal) = (ssid.to_string(), portal.to_string());
+        offload(move || State::refresh_session(&ssid, &portal, data)).await
+    }
+
+    /// `State::record_session_info`
+    pub async fn record_session_info(ssid: &str, info: SessionInfo) {
+        let ssid = ssid.to_string();
+        offload(move || State::record_session_info(&ssid, info)).await
+    }
+
+    /// `State::forget_session`
+    pub async fn forget_session(ssid: &str) {
+        let ssid = ssid.to_string();
+        offload(move || State::forget_session(&ssid)).await
+    }
+
+    /// `State::observe_venue`
+    pub async fn observe_venue(bssid: &str, now: Venue) -> Option<String> {
+        let bssid = bssid.to_string();
+        offload(move || State::observe_venue(&bssid, now)).await
+    }
+
+    /// `State::record_failure`
+    pub async fn record_failure(ssid: &str, policy: &RetryPolicy, err: &anyhow::Error) -> Backoff {
+        // Whether it is retried is all that is needed of the error
+        let retried = policy.retries(err);
+        let (ssid, policy) = (ssid.to_string(), policy.clone());
+        offload(move || State::record_retried_failure(&ssid, &policy, retried)).await
+    }
+
+    /// `State::record_next_action`
+    pub async fn record_next_action(next: NextAction) {
+        offload(move || State::record_next_action(next)).await
+    }
+
+    /// `State::record_subsystems`
+    pub async fn record_subsystems(subsystems: HashMap<String, Health>) {
+        offload(move || State::record_subsystems(subsystems)).await
+    }
+
+    /// `State::record_paused`
+    pub async fn record_paused(at: Option<u64>) {
+        offload(move || State::record_paused(at)).await
+    }
+
+    /// `State::record_mac_rotation`
+    pub async fn record_mac_rotation(ssid: &str, at: u64) {
+        let ssid = ssid.to_string();
+        offload(move || State::record_mac_rotation(&ssid, at)).await
+    }
+
+    /// `State::clear_backoff`
+    pub async fn clear_backoff(ssid: &str) {
+        let ssid = ssid.to_string();
+        offload(move || State::clear_backoff(&ssid)).await
+    }
+
+    /// `State::reset_backoff`
+    pub async fn reset_backoff(ssid: Option<&str>) -> Result<usize> {
+        let ssid = ssid.map(str::to_string);
+        offload(move || State::reset_backoff(ssid.as_deref())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_schedule() {
+        let policy = RetryPolicy::daemon(3, Duration::from_secs(60), Duration::from_secs(600));
+        let mut backoff = Backoff::default();
+        let waits: Vec<u64> = (0..8)
+            .map(|_| {
+                backoff = backoff.after_failure(1000, &policy, true);
+                backoff.until.saturating_sub(1000)
+            })
+            .collect();
+        assert_eq!(waits, [0, 0, 60, 120, 240, 480, 600, 600]);
+        assert_eq!(backoff.failures, 8);
+
+        // Not worth retrying: the waits start with the first failure
+        let backoff = Backoff::default().after_failure(1000, &policy, false);
+        assert_eq!((backoff.failures, backoff.until), (3, 1060));
+    }
+
+    #[test]
+    fn test_batch_plans() {
+        let next = |action, at| NextAction {
+            action,
+            at,
+            ssid: Some("1.Free Wi-MESH".to_string()),
+            planned_at: 900,
+        };
+        assert!(next(Action::RenewSession, 950).is_overdue(1000));
+        assert!(!next(Action::Probe, 950).is_overdue(1000));
+        assert!(!next(Action::Login, 1060).is_overdue(1000));
+
+        assert_eq!(
+            next(Action::RetryAfterBackoff, 1040).login_within(1000, 60),
+            Some(40)
+        );
+        assert_eq!(next(Action::Login, 950).login_within(1000, 60), Some(0));
+        assert_eq!(
+            next(Action::RetryAfterBackoff, 1400).login_within(1000, 60),
+            None
+        );
+        assert_eq!(
+            next(Action::RenewSession, 1010).login_within(1000, 60),
+            None
+        );
+    }
+
+    #[test]
+    fn test_venue_changes() {
+        let mut venue = Venue {
+            gateway_mac: Some("02:00:00:AA:BB:01".to_string()),
+            login_url: None,
+        };
+        let replaced = Venue {
+            gateway_mac: Some("02:00:00:AA:BB:99".to_string()),
+            login_url: Some("http://10.20.30.1/login".to_string()),
+        };
+        assert_eq!(
+            venue.changes(&replaced).as_deref(),
+            Some("gateway MAC 02:00:00:AA:BB:01 -> 02:00:00:AA:BB:99")
+        );
+        venue.update(replaced.clone());
+        assert_eq!(venue, replaced);
+
+        // What was not seen this time is no change
+        assert_eq!(venue.changes(&Venue::default()), None);
+    }
+}
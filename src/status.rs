@@ -0,0 +1,277 @@
+//! Network status summary
+//!
+//! Answers "am I online?" in one of four states, for the status widget and
+//! other one-glance displays.
+
+use crate::config::Config;
+use crate::state::{self, unix_now, Action, NextAction, State};
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkState {
+    /// On a configured SSID with working internet
+    Online,
+    /// On a configured SSID, but the portal is holding traffic
+    Captive,
+    /// On a configured SSID without a usable IP address (no DHCP lease, a
+    /// link-local address or an expired lease); no portal can help
+    NoAddress,
+    /// Not on any configured SSID
+    Offline,
+}
+
+impl NetworkState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Captive => "captive",
+            Self::NoAddress => "no_address",
+            Self::Offline => "offline",
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            Self::Online => "✓",
+            Self::Captive => "!",
+            Self::NoAddress => "?",
+            Self::Offline => "✗",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkStatus {
+    pub state: NetworkState,
+    pub ssid: Option<String>,
+    /// Time left in the portal session, when the venue's session length is
+    /// configured and a login has been recorded
+    pub session_remaining: Option<Duration>,
+}
+
+impl NetworkStatus {
+    /// Probe WiFi association and internet connectivity
+    pub async fn probe(cfg: &Config) -> Self {
+        let ssids: Vec<String> = cfg.all_ssids().iter().map(|s| s.to_string()).collect();
+
+        let ssid = match utils::nonblocking::is_connected_to_wifi(&ssids).await {
+            Ok(Some(ssid)) => ssid,
+            _ => {
+                return Self {
+                    state: NetworkState::Offline,
+                    ssid: None,
+                    session_remaining: None,
+                }
+            }
+        };
+
+        let no_address = match utils::nonblocking::wifi_interface_for_ssid(&ssid).await {
+            Some(interface) => utils::nonblocking::interface_address_problem(&interface).await,
+            None => None,
+        };
+        if no_address.is_some() {
+            return Self {
+                state: NetworkState::NoAddress,
+                ssid: Some(ssid),
+                session_remaining: None,
+            };
+        }
+
+        if !utils::nonblocking::has_internet_connectivity().await {
+            return Self {
+                state: NetworkState::Captive,
+                ssid: Some(ssid),
+                session_remaining: None,
+            };
+        }
+
+        let state = state::nonblocking::load().await;
+        let session_remaining = session_remaining(cfg, &state, &ssid);
+        Self {
+            state: NetworkState::Online,
+            ssid: Some(ssid),
+            session_remaining,
+        }
+    }
+
+    /// Compact one-line form: icon, SSID, time left
+    pub fn line(&self) -> String {
+        let mut line = self.state.icon().to_string();
+        if let Some(ref ssid) = self.ssid {
+            line.push(' ');
+            line.push_str(ssid);
+        }
+        if let Some(remaining) = self.session_remaining {
+            line.push(' ');
+            line.push_str(&format_remaining(remaining));
+        }
+        line
+    }
+
+    /// Machine-readable form for `wimesh status -o json`
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "state": self.state,
+            "ssid": self.ssid,
+            "session_remaining_secs": self.session_remaining.map(|d| d.as_secs()),
+        })
+    }
+
+    /// Object for a Waybar `custom` module with `return-type: json`
+    pub fn waybar_json(&self) -> serde_json::Value {
+        let mut tooltip = match self.ssid {
+            Some(ref ssid) => format!("{} ({})", ssid, self.state.as_str()),
+            None => "Not on a configured network".to_string(),
+        };
+        if let Some(remaining) = self.session_remaining {
+            tooltip.push_str(&format!("\nSession: {} left", format_remaining(remaining)));
+        }
+
+        serde_json::json!({
+            "text": self.line(),
+            "alt": self.state.as_str(),
+            "class": self.state.as_str(),
+            "tooltip": tooltip,
+        })
+    }
+}
+
+/// How long a login flow may hold up the daemon's checks
+const LOGIN_GRACE: u64 = 120;
+
+/// What the daemon does next, given the state last seen on each network it
+/// is associated to and when its next check is due
+///
+/// Logins come first: the soonest one due, be it at the next check or after
+/// a backoff. Then the soonest session expiry, and otherwise just the next
+/// check.
+pub fn plan_next_action(
+    cfg: &Config,
+    state: &State,
+    networks: &[(String, NetworkState)],
+    next_check: u64,
+) -> NextAction {
+    let now = unix_now();
+    let action = |action, at, ssid: &str| NextAction {
+        action,
+        at,
+        ssid: Some(ssid.to_string()),
+        planned_at: now,
+    };
+
+    let login = networks
+        .iter()
+        .filter(|(_, network)| *network == NetworkState::Captive)
+        .map(|(ssid, _)| match state.backoff_remaining(ssid) {
+            Some(wait) => action(
+                Action::RetryAfterBackoff,
+                (now + wait).max(next_check),
+                ssid,
+            ),
+            None => action(Action::Login, next_check, ssid),
+        })
+        .min_by_key(|next| next.at);
+    let renewal = || {
+        networks
+            .iter()
+            .filter(|(_, network)| *network == NetworkState::Online)
+            .filter_map(|(ssid, _)| {
+                let remaining = session_remaining(cfg, state, ssid)?;
+                Some(action(
+                    Action::RenewSession,
+                    now + remaining.as_secs(),
+                    ssid,
+                ))
+            })
+            .min_by_key(|next| next.at)
+    };
+
+    login.or_else(renewal).unwrap_or(NextAction {
+        action: Action::Probe,
+        at: next_check,
+        ssid: None,
+        planned_at: now,
+    })
+}
+
+/// Whether the daemon that planned `next` stopped planning: it replans on
+/// every check, so a plan older than two checks and a slow login is stale
+pub fn is_stale(next: &NextAction, cfg: &Config, now: u64) -> bool {
+    now > next.planned_at + 2 * cfg.global.check_interval + LOGIN_GRACE
+}
+
+/// Session time left on `ssid`: the expiry the portal reported, else the
+/// portal's configured session length from the last recorded login
+fn session_remaining(cfg: &Config, state: &State, ssid: &str) -> Option<Duration> {
+    if let Some(expires) = state.session_info.get(ssid).and_then(|i| i.expires_at) {
+        return Some(Duration::from_secs(expires.saturating_sub(unix_now())));
+    }
+
+    let minutes = cfg
+        .portals
+        .iter()
+        .find(|p| p.ssids.iter().any(|s| s == ssid))?
+        .session_minutes?;
+    let last_login = *state.last_login.get(ssid)?;
+
+    let expires = last_login + minutes * 60;
+    Some(Duration::from_secs(expires.saturating_sub(unix_now())))
+}
+
+fn format_remaining(remaining: Duration) -> String {
+    let minutes = remaining.as_secs() / 60;
+    if minutes >= 60 {
+        format!("{}h{:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Backoff;
+
+    #[test]
+    fn test_plan_next_action() {
+        let cfg = Config::default();
+        let mut state = State::default();
+        let now = unix_now();
+        let next_check = now + 30;
+
+        let idle = plan_next_action(&cfg, &state, &[], next_check);
+        assert_eq!((idle.action, idle.at), (Action::Probe, next_check));
+
+        let wifi = "1.Free Wi-MESH".to_string();
+        let online = [(wifi.clone(), NetworkState::Online)];
+        state.session_info.insert(
+            wifi.clone(),
+            crate::models::SessionInfo {
+                expires_at: Some(now + 3600),
+                ..Default::default()
+            },
+        );
+        let renew = plan_next_action(&cfg, &state, &online, next_check);
+        assert_eq!((renew.action, renew.at), (Action::RenewSession, now + 3600));
+
+        let captive = [(wifi.clone(), NetworkState::Captive)];
+        let login = plan_next_action(&cfg, &state, &captive, next_check);
+        assert_eq!((login.action, login.at), (Action::Login, next_check));
+
+        state.backoff.insert(
+            wifi.clone(),
+            Backoff {
+                failures: 4,
+                until: now + 600,
+            },
+        );
+        let retry = plan_next_action(&cfg, &state, &captive, next_check);
+        assert_eq!(retry.action, Action::RetryAfterBackoff);
+        assert!(retry.at >= now + 599);
+        assert!(!is_stale(&retry, &cfg, now));
+        assert!(is_stale(&retry, &cfg, now + 3600));
+    }
+}
@@ -0,0 +1,317 @@
+//! Where the runtime state is kept
+//!
+//! `State` is read and written through a `StateStore`. The default is the
+//! plain JSON file, which needs nothing beyond the binary and suits
+//! OpenWrt routers with a few MB of flash. Builds with the `sqlite` feature
+//! can keep it in an SQLite database instead (`[storage] backend =
+//! "sqlite"`), one row per entry, for people who query it with `sqlite3`.
+//! The event history stays the rotated `events.jsonl` with either backend.
+
+use crate::config::StorageBackend;
+use crate::state::State;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Reads and writes the whole state at a path in a state directory
+pub trait StateStore: Send + Sync {
+    /// Name of the state file inside a state directory
+    fn file_name(&self) -> &'static str;
+
+    fn read(&self, path: &Path) -> Result<State>;
+
+    /// Replace the state at `path`; readers never see a partial write
+    fn write(&self, path: &Path, state: &State) -> Result<()>;
+}
+
+static STORE: OnceLock<Box<dyn StateStore>> = OnceLock::new();
+
+/// Use `backend` for the rest of the process; call before touching state
+pub fn select(backend: StorageBackend) -> Result<()> {
+    let store: Box<dyn StateStore> = match backend {
+        StorageBackend::Json => Box::new(JsonStore),
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => Box::new(SqliteStore),
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => {
+            anyhow::bail!("storage.backend = \"sqlite\" needs a build with the `sqlite` feature")
+        }
+    };
+    if STORE.set(store).is_err() {
+        tracing::debug!("State store already selected, keeping it");
+    }
+    Ok(())
+}
+
+/// The selected store, the JSON file unless `select` chose another
+pub fn current() -> &'static dyn StateStore {
+    STORE.get_or_init(|| Box::new(JsonStore)).as_ref()
+}
+
+/// `state.json`, pretty-printed
+pub struct JsonStore;
+
+/// Numbers this process's temporary state files
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+impl StateStore for JsonStore {
+    fn file_name(&self) -> &'static str {
+        "state.json"
+    }
+
+    fn read(&self, path: &Path) -> Result<State> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn write(&self, path: &Path, state: &State) -> Result<()> {
+        create_parent(path)?;
+        // Write then rename, so readers never see a half-written file; the
+        // temporary file is this write's own, as other processes (a CLI
+        // command next to the daemon) may be writing at the same time
+        let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("json.{}.{}.tmp", std::process::id(), n));
+        std::fs::write(&tmp, serde_json::to_string_pretty(state)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        restrict_permissions(&tmp)?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// `state.sqlite`: a `state (section, key, value)` table, where `section`
+/// is a `State` field and `value` JSON. Maps by SSID or portal name take a
+/// row per entry, keyed by it; the fields in `SINGLE_VALUES` take one row
+/// holding the whole value, with an empty key
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore;
+
+/// `State` fields that are one value rather than a map
+#[cfg(feature = "sqlite")]
+const SINGLE_VALUES: &[&str] = &["next_action", "read_only", "paused_at"];
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    fn open(path: &Path) -> Result<rusqlite::Connection> {
+        let db = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        // Readers (the widget) and the daemon may overlap
+        db.busy_timeout(std::time::Duration::from_secs(5))?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS state (
+                section TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (section, key)
+            )",
+        )?;
+        Ok(db)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl StateStore for SqliteStore {
+    fn file_name(&self) -> &'static str {
+        "state.sqlite"
+    }
+
+    fn read(&self, path: &Path) -> Result<State> {
+        let db = Self::open(path)?;
+        let mut rows = db.prepare("SELECT section, key, value FROM state")?;
+        let rows = rows.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut sections = serde_json::Map::new();
+        for row in rows {
+            let (section, key, value) = row?;
+            let value: serde_json::Value = serde_json::from_str(&value).with_context(|| {
+                format!("Bad value for {}.{} in {}", section, key, path.display())
+            })?;
+            // Maps may have an empty key too (a hidden SSID); older files
+            // also split `next_action` into a row per field
+            if key.is_empty() && SINGLE_VALUES.contains(&section.as_str()) {
+                sections.insert(section, value);
+            } else if let serde_json::Value::Object(entries) = sections
+                .entry(section)
+                .or_insert_with(|| serde_json::Value::Object(Default::default()))
+            {
+                entries.insert(key, value);
+            }
+        }
+        serde_json::from_value(serde_json::Value::Object(sections))
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn write(&self, path: &Path, state: &State) -> Result<()> {
+        create_parent(path)?;
+        let mut db = Self::open(path)?;
+        restrict_permissions(path)?;
+
+        let serde_json::Value::Object(sections) = serde_json::to_value(state)? else {
+            anyhow::bail!("State did not serialize to an object");
+        };
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM state", [])?;
+        {
+            let mut insert =
+                tx.prepare("INSERT INTO state (section, key, value) VALUES (?1, ?2, ?3)")?;
+            for (section, value) in sections {
+                match value {
+                    serde_json::Value::Null => {}
+                    serde_json::Value::Object(entries)
+                        if !SINGLE_VALUES.contains(&section.as_str()) =>
+                    {
+                        for (key, value) in entries {
+                            insert.execute((&section, &key, value.to_string()))?;
+                        }
+                    }
+                    value => {
+                        insert.execute((&section, "", value.to_string()))?;
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Sessions hold portal credentials, hence owner-only permissions
+fn restrict_permissions(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Action, Backoff, NextAction};
+
+    fn sample() -> State {
+        let mut state = State::default();
+        state
+            .last_login
+            .insert("1.Free Wi-MESH".to_string(), 1_700_000_000);
+        state.backoff.insert(
+            "Cafe".to_string(),
+            Backoff {
+                failures: 3,
+                until: 1_700_000_300,
+            },
+        );
+        state.next_action = Some(NextAction {
+            action: Action::Probe,
+            at: 1_700_000_030,
+            ssid: None,
+            planned_at: 1_700_000_000,
+        });
+        state
+    }
+
+    fn round_trip(store: &dyn StateStore) {
+        let dir = std::env::temp_dir().join(format!(
+            "wimesh-store-{}-{}",
+            store.file_name(),
+            std::process::id()
+        ));
+        let path = dir.join(store.file_name());
+        store.write(&path, &sample()).unwrap();
+        // Overwriting replaces, it does not merge
+        store.write(&path, &sample()).unwrap();
+        let read = store.read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.last_login, sample().last_login);
+        assert_eq!(read.backoff["Cafe"].failures, 3);
+        assert_eq!(read.next_action.unwrap().at, 1_700_000_030);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        round_trip(&JsonStore);
+    }
+
+    #[test]
+    fn test_json_concurrent_writes() {
+        let dir =
+            std::env::temp_dir().join(format!("wimesh-store-concurrent-{}", std::process::id()));
+        let path = dir.join(JsonStore.file_name());
+        std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| JsonStore.write(&path, &sample())))
+                .collect();
+            for writer in writers {
+                writer.join().unwrap().unwrap();
+            }
+        });
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        let read = JsonStore.read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // No temporary file was left behind or taken by another write
+        assert_eq!(files, 1);
+        assert_eq!(read.backoff["Cafe"].failures, 3);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_round_trip() {
+        round_trip(&SqliteStore);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_rows() {
+        let dir = std::env::temp_dir().join(format!("wimesh-store-rows-{}", std::process::id()));
+        let path = dir.join(SqliteStore.file_name());
+        let mut state = sample();
+        state.last_login.insert(String::new(), 1_700_000_060);
+        SqliteStore.write(&path, &state).unwrap();
+        let rows: Vec<(String, String)> = SqliteStore::open(&path)
+            .unwrap()
+            .prepare("SELECT section, key FROM state ORDER BY section, key")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let read = SqliteStore.read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let rows: Vec<(&str, &str)> = rows.iter().map(|(s, k)| (s.as_str(), k.as_str())).collect();
+        assert_eq!(
+            rows,
+            [
+                ("backoff", "Cafe"),
+                ("last_login", ""),
+                ("last_login", "1.Free Wi-MESH"),
+                ("next_action", ""),
+            ]
+        );
+        assert_eq!(read.last_login, state.last_login);
+        assert_eq!(read.next_action.unwrap().at, 1_700_000_030);
+    }
+}
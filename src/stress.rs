@@ -0,0 +1,313 @@
+//! Fault injection against the daemon's pass (`wimesh stress`)
+//!
+//! Runs the daemon's pass (`daemon::Pass`) back to back against the mock
+//! venue while it injects faults (`mock::Faults`), for minutes if asked,
+//! and checks after every pass what must hold however the venue behaves:
+//! a client found online is left alone and its backoff cleared, a login
+//! is only attempted outside a backoff, a failed one is counted once, a
+//! successful one really logged the client in, backoffs stay within
+//! `backoff_max`, and no pass sends more requests than the retry policies
+//! allow. A panic anywhere ends the run.
+//!
+//! The pass books its outcome in the state file, so the caller points
+//! `$STATE_DIRECTORY` at a scratch directory first.
+
+use crate::config::{Config, PortalConfig};
+use crate::daemon::{Connectivity, Pass};
+use crate::events::EventLog;
+use crate::identity::IdentityManager;
+use crate::lock::LoginLocks;
+use crate::mock::{Faults, MockPortal};
+use crate::portal::{self, PortalRegistry};
+use crate::state::{self, unix_now, State};
+use crate::status::NetworkState;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Pause between passes, so backoffs pass without spinning
+const PASS_GAP: Duration = Duration::from_millis(50);
+/// Violations kept in the report; the count goes on
+const MAX_VIOLATIONS: usize = 50;
+
+/// What to run
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    pub duration: Duration,
+    pub faults: Faults,
+    /// Seed of the mock's fault dice, to replay a run
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StressReport {
+    pub portal: String,
+    pub seed: u64,
+    pub passes: u64,
+    /// Passes that found the client online
+    pub online: u64,
+    pub logins: u64,
+    pub failed_logins: u64,
+    /// Passes that skipped the login for a backoff
+    pub backed_off: u64,
+    pub requests: u64,
+    /// Most requests one pass sent, and what the retry policies allow
+    pub max_requests: u64,
+    pub request_limit: u64,
+    pub violations: Vec<String>,
+}
+
+impl StressReport {
+    pub fn ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Plain text summary
+    pub fn table(&self) -> String {
+        let mut out = format!(
+            "{} pass(es) against a faulty '{}' (seed {})\n\n",
+            self.passes, self.portal, self.seed
+        );
+        for (name, value) in [
+            ("online", self.online.to_string()),
+            ("logins", self.logins.to_string()),
+            ("failed logins", self.failed_logins.to_string()),
+            ("backed off", self.backed_off.to_string()),
+            ("requests", self.requests.to_string()),
+            (
+                "requests/pass",
+                format!("{} max, {} allowed", self.max_requests, self.request_limit),
+            ),
+        ] {
+            out.push_str(&format!("{:15}{}\n", name, value));
+        }
+        out.push('\n');
+        if self.ok() {
+            out.push_str("All invariants held\n");
+        }
+        for violation in &self.violations {
+            out.push_str(&format!("VIOLATED: {}\n", violation));
+        }
+        out
+    }
+
+    fn violated(&mut self, pass: u64, what: String) {
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(format!("pass {}: {}", pass, what));
+        }
+    }
+}
+
+/// The mock's connectivity answer, remembered for the checks
+struct Watched<'a> {
+    mock: &'a MockPortal,
+    seen: AtomicBool,
+}
+
+#[async_trait]
+impl Connectivity for Watched<'_> {
+    async fn online(&self, interface: Option<&str>) -> bool {
+        let online = self.mock.online(interface).await;
+        self.seen.store(online, Ordering::Relaxed);
+        online
+    }
+}
+
+/// `cfg` as the run needs it: short timeouts and backoffs, so minutes see
+/// many of them, and none of the checks that need a real network
+fn stress_config(cfg: &Config, faults: &Faults) -> Config {
+    let mut cfg = cfg.clone();
+    // A resumed session is checked with curl
+    cfg.global.session_cache_ttl = 0;
+    cfg.global.probe_gateway = false;
+    cfg.global.read_only = false;
+    cfg.global.backoff_base = 1;
+    cfg.global.backoff_max = 4;
+    cfg.http.timeout = faults.slow_by.as_secs().saturating_sub(1).max(1);
+    cfg.policy.http.delay = Some(0.1);
+    cfg.policy.step.delay = Some(0.1);
+    cfg.audit.enabled = false;
+    cfg
+}
+
+/// Run the daemon's pass on `portal_cfg`, an Awing portal, against the
+/// faulty mock venue for `options.duration`
+pub async fn run(
+    cfg: &Config,
+    portal_cfg: &PortalConfig,
+    options: &StressOptions,
+) -> Result<StressReport> {
+    if portal_cfg.portal_type != "awing" {
+        anyhow::bail!(
+            "The mock venue is an Awing one, '{}' is of type {}",
+            portal_cfg.name,
+            portal_cfg.portal_type
+        );
+    }
+    let ssid = portal_cfg
+        .ssids
+        .first()
+        .with_context(|| format!("Portal '{}' has no SSIDs", portal_cfg.name))?
+        .clone();
+    let cfg = stress_config(cfg, &options.faults);
+    let identities = IdentityManager::load();
+    let locks = LoginLocks::new();
+    let events = EventLog::disabled();
+    let policy = cfg.daemon_policy();
+
+    // What one clean login takes, times the retries
+    let clean = MockPortal::start(Duration::ZERO).await?;
+    let mut portal = portal::build(&cfg, &clean.portal_config(portal_cfg), &identities)?
+        .with_context(|| portal::missing_type(&portal_cfg.portal_type))?;
+    crate::login::locked_connect(&cfg, &locks, &events, &ssid, None, &mut portal)
+        .await
+        .context("The login fails against the mock venue even without faults")?;
+    let request_limit = clean.requests()
+        * u64::from(cfg.http_policy().max_attempts)
+        * u64::from(cfg.step_policy().max_attempts);
+    State::default().save()?;
+
+    let mock = MockPortal::start_faulty(Duration::ZERO, options.faults, options.seed).await?;
+    let mut registry = PortalRegistry::new();
+    let portal = portal::build(&cfg, &mock.portal_config(portal_cfg), &identities)?
+        .with_context(|| portal::missing_type(&portal_cfg.portal_type))?;
+    registry.register(portal);
+    let watched = Watched {
+        mock: &mock,
+        seen: AtomicBool::new(false),
+    };
+    let pass = Pass {
+        cfg: &cfg,
+        locks: &locks,
+        events: &events,
+        coop: None,
+        connectivity: &watched,
+        settle: Duration::ZERO,
+    };
+
+    let mut report = StressReport {
+        portal: portal_cfg.name.clone(),
+        seed: options.seed,
+        request_limit,
+        ..StressReport::default()
+    };
+    let started = Instant::now();
+    while started.elapsed() < options.duration {
+        if report.passes > 0 {
+            tokio::time::sleep(PASS_GAP).await;
+        }
+        report.passes += 1;
+        let n = report.passes;
+        let state = state::nonblocking::load().await;
+        let before = state.backoff.get(&ssid).copied();
+        let backing_off = state.backoff_remaining(&ssid).is_some();
+        let requests_before = mock.requests();
+
+        let found = pass.check(&mut registry, "", &ssid).await;
+
+        let requests = mock.requests() - requests_before;
+        let after = state::nonblocking::load().await.backoff.get(&ssid).copied();
+        let online = watched.seen.load(Ordering::Relaxed);
+        report.requests += requests;
+        report.max_requests = report.max_requests.max(requests);
+        if requests > request_limit {
+            report.violated(
+                n,
+                format!("{} requests, {} allowed", requests, request_limit),
+            );
+        }
+        if let Some(after) = after {
+            let longest = policy.max_delay.as_secs() + 1;
+            if after.until > unix_now() + longest {
+                report.violated(n, format!("backing off past backoff_max ({}s)", longest));
+            }
+        }
+
+        if online {
+            report.online += 1;
+            if found != NetworkState::Online {
+                report.violated(n, format!("online, but found {:?}", found));
+            }
+            if requests > 0 {
+                report.violated(n, format!("online, but sent {} requests", requests));
+            }
+            if after.is_some() {
+                report.violated(n, "online, but the backoff was kept".to_string());
+            }
+            continue;
+        }
+        if found == NetworkState::Online {
+            report.violated(n, "found online without internet".to_string());
+        }
+        if backing_off {
+            report.backed_off += 1;
+            if requests > 0 || after != before {
+                report.violated(
+                    n,
+                    format!("logged in during a backoff ({} requests)", requests),
+                );
+            }
+        } else {
+            match after {
+                None if mock.logged_in() => report.logins += 1,
+                None => report.violated(n, "login reported, the venue never saw it".to_string()),
+                Some(after) => {
+                    report.failed_logins += 1;
+                    let failures = before.map(|b| b.failures).unwrap_or_default();
+                    let most = (failures + 1).max(policy.max_attempts);
+                    if after.failures <= failures || after.failures > most {
+                        report.violated(
+                            n,
+                            format!("failures went from {} to {}", failures, after.failures),
+                        );
+                    }
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(all(test, feature = "portal-awing"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stress_against_faulty_mock() {
+        let dir = std::env::temp_dir().join(format!("wimesh-stress-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::state::isolate_in(&dir).unwrap();
+
+        let portal_cfg: PortalConfig = toml::from_str(
+            r#"
+            name = "mock"
+            type = "awing"
+            ssids = ["Mock"]
+            mac_address = "02:00:00:00:00:01"
+            "#,
+        )
+        .unwrap();
+        let options = StressOptions {
+            duration: Duration::from_secs(5),
+            faults: Faults {
+                slow: 0.02,
+                expiry: 0.3,
+                ..Faults::uniform(0.15, Duration::from_secs(2))
+            },
+            seed: 7,
+        };
+        let report = run(&Config::default(), &portal_cfg, &options)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert!(report.ok(), "{}", report.table());
+        assert!(
+            report.logins > 0 && report.failed_logins > 0,
+            "{}",
+            report.table()
+        );
+    }
+}
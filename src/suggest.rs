@@ -0,0 +1,132 @@
+//! "Did you mean" for SSIDs
+//!
+//! Associated to a network no portal is configured for, the likeliest
+//! reason is a typo in the config: a trailing space, different letter case,
+//! a digit off. `closest_ssid` finds the configured SSID the current one is
+//! probably meant to be, and says what differs when it can tell.
+
+use serde::Serialize;
+use std::fmt;
+
+/// A configured SSID close to the one associated to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SsidSuggestion {
+    /// The SSID associated to
+    pub connected: String,
+    /// The configured SSID it probably is
+    pub ssid: String,
+    /// The portal configured for `ssid`, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub portal: Option<String>,
+    /// What tells the two apart, when it is something easy to miss
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+impl fmt::Display for SsidSuggestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connected to '{}' — did you mean '{}'?",
+            self.connected, self.ssid
+        )?;
+        if let Some(hint) = self.hint {
+            write!(f, " {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entry of `configured` that `connected` is most likely meant to
+/// match, if any is close enough
+pub fn closest_ssid(connected: &str, configured: &[&str]) -> Option<SsidSuggestion> {
+    let (ssid, distance) = configured
+        .iter()
+        .filter(|ssid| **ssid != connected)
+        .map(|ssid| (*ssid, distance(connected, ssid)))
+        .min_by_key(|(_, distance)| *distance)?;
+
+    let hint = difference_hint(connected, ssid);
+    // A third of the characters may differ, and always two: "Wi-MESH" and
+    // "WiMESH 2" are the same venue, "Cafe" and "Home" are not
+    let max_distance = (ssid.chars().count() / 3).max(2);
+    if hint.is_none() && distance > max_distance {
+        return None;
+    }
+    Some(SsidSuggestion {
+        connected: connected.to_string(),
+        ssid: ssid.to_string(),
+        portal: None,
+        hint,
+    })
+}
+
+/// A human description of how `connected` differs from `configured`, for
+/// the differences that are hard to see in a log line
+fn difference_hint(connected: &str, configured: &str) -> Option<&'static str> {
+    if connected.trim() == configured.trim() {
+        let hint = if connected.trim_end() != connected && configured.trim_end() == configured {
+            "trailing space detected"
+        } else if connected.trim_start() != connected && configured.trim_start() == configured {
+            "leading space detected"
+        } else {
+            "the configured SSID has extra spaces"
+        };
+        return Some(hint);
+    }
+    if connected.to_lowercase() == configured.to_lowercase() {
+        return Some("only the letter case differs");
+    }
+    None
+}
+
+/// Levenshtein distance in characters
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("Wi-MESH", "Wi-MESH "), 1);
+        assert_eq!(distance("Phở", "Pho"), 1);
+    }
+
+    #[test]
+    fn test_closest_ssid() {
+        let configured = ["1.Free Wi-MESH", "Cafe Sua Da"];
+
+        let trailing = closest_ssid("1.Free Wi-MESH ", &configured).unwrap();
+        assert_eq!(trailing.ssid, "1.Free Wi-MESH");
+        assert_eq!(
+            trailing.to_string(),
+            "connected to '1.Free Wi-MESH ' — did you mean '1.Free Wi-MESH'? \
+             trailing space detected"
+        );
+
+        let case = closest_ssid("1.free wi-mesh", &configured).unwrap();
+        assert_eq!(case.hint, Some("only the letter case differs"));
+
+        let typo = closest_ssid("2.Free Wi-MESH", &configured).unwrap();
+        assert_eq!((typo.ssid.as_str(), typo.hint), ("1.Free Wi-MESH", None));
+
+        assert_eq!(closest_ssid("HomeNet", &configured), None);
+        assert_eq!(closest_ssid("Cafe Sua Da", &configured[1..]), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! The daemon's optional parts, and how they fare
+//!
+//! The responder needs its port, coop its multicast group, the state store
+//! a writable directory or an unlocked database; none of them is needed to
+//! keep the connection alive. The daemon starts each as a `Supervised`,
+//! which holds the running part or the error that stopped it and tries
+//! again after a delay growing from a minute to an hour, so a port freed or
+//! a lock released later brings it back without a restart. Its `Health`
+//! goes to the state file for `wimesh status`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const RETRY_MIN: u64 = 60;
+const RETRY_MAX: u64 = 3600;
+
+/// How an optional part of the daemon fares
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    pub up: bool,
+    /// What stopped it from starting last time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Unix time of the next start attempt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<u64>,
+}
+
+impl Health {
+    pub fn describe(&self, now: u64) -> String {
+        let error = self.error.as_deref().unwrap_or("not started");
+        match self.retry_at {
+            _ if self.up => "up".to_string(),
+            Some(at) => format!("{}; retrying in {}s", error, at.saturating_sub(now)),
+            None => error.to_string(),
+        }
+    }
+}
+
+/// One optional part of the daemon, started again until it runs
+pub struct Supervised<T> {
+    name: &'static str,
+    running: Option<T>,
+    error: Option<String>,
+    failures: u32,
+    retry_at: u64,
+}
+
+impl<T> Supervised<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            running: None,
+            error: None,
+            failures: 0,
+            retry_at: 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Whether to start it now: not running, and never tried or the delay
+    /// after the last failure is over
+    pub fn is_due(&self, now: u64) -> bool {
+        self.running.is_none() && now >= self.retry_at
+    }
+
+    /// Book a start attempt made at `now`; only the first failure of a run
+    /// of them is a warning
+    pub fn started(&mut self, result: Result<T>, now: u64) {
+        match result {
+            Ok(running) => {
+                if self.failures > 0 {
+                    tracing::info!(
+                        "{} is up after {} failed start(s)",
+                        self.name,
+                        self.failures
+                    );
+                }
+                self.running = Some(running);
+                self.error = None;
+                self.failures = 0;
+            }
+            Err(e) => {
+                self.failures += 1;
+                let delay = retry_delay(self.failures);
+                self.retry_at = now + delay;
+                let error = format!("{:#}", e);
+                if self.failures == 1 {
+                    tracing::warn!(
+                        "{} unavailable, trying again in {}s: {}",
+                        self.name,
+                        delay,
+                        error
+                    );
+                } else {
+                    tracing::debug!("{} still unavailable: {}", self.name, error);
+                }
+                self.error = Some(error);
+            }
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.running.as_ref()
+    }
+
+    /// `None` until a start was attempted
+    pub fn health(&self) -> Option<Health> {
+        if self.running.is_none() && self.failures == 0 {
+            return None;
+        }
+        Some(Health {
+            up: self.running.is_some(),
+            error: self.error.clone(),
+            retry_at: self.running.is_none().then_some(self.retry_at),
+        })
+    }
+}
+
+/// Wait before the next start after `failures` failed ones in a row
+fn retry_delay(failures: u32) -> u64 {
+    RETRY_MIN
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(RETRY_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supervised_retries() {
+        let mut part: Supervised<u16> = Supervised::new("responder");
+        assert!(part.is_due(0));
+        assert_eq!(part.health(), None);
+
+        part.started(Err(anyhow::anyhow!("Address in use")), 1000);
+        assert!(!part.is_due(1059) && part.is_due(1060));
+        let health = part.health().unwrap();
+        assert!(!health.up);
+        assert_eq!(health.describe(1030), "Address in use; retrying in 30s");
+
+        part.started(Err(anyhow::anyhow!("Address in use")), 1060);
+        assert!(!part.is_due(1179) && part.is_due(1180));
+
+        part.started(Ok(80), 1180);
+        assert_eq!(part.get(), Some(&80));
+        assert!(!part.is_due(u64::MAX));
+        assert_eq!(part.health().unwrap().describe(1180), "up");
+        assert_eq!(retry_delay(100), RETRY_MAX);
+    }
+}
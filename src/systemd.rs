@@ -0,0 +1,171 @@
+//! Telling systemd how the daemon is doing (`Type=notify` units)
+//!
+//! Under a unit with `Type=notify`, systemd passes `NOTIFY_SOCKET` and the
+//! daemon sends `READY=1` once its first check is done, a `STATUS=` line
+//! whenever what it sees changes, and `STOPPING=1` on its way out. With
+//! `WatchdogSec=`, systemd also passes `WATCHDOG_USEC` and restarts the
+//! daemon when the `WATCHDOG=1` pings, sent at half that interval, stop:
+//! they stop when the runtime hangs, or when one pass has been running
+//! for longer than `STUCK_PASS`. Outside systemd all of this does nothing.
+
+use crate::state::unix_now;
+use crate::tasks::Tasks;
+use std::ffi::OsStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a pass may run before the watchdog pings stop
+pub const STUCK_PASS: Duration = Duration::from_secs(600);
+
+/// Send `state` (`READY=1`, `STATUS=...`, newline-separated) to systemd;
+/// whether it was sent
+pub fn notify(state: &str) -> bool {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_to(&socket, state),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn notify_to(socket: &OsStr, state: &str) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Ok(datagram) = UnixDatagram::unbound() else {
+        return false;
+    };
+    let sent = match socket.as_bytes().strip_prefix(b"@") {
+        // An abstract socket, Linux only
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| datagram.send_to_addr(state.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return false,
+        None => datagram.send_to(state.as_bytes(), std::path::Path::new(socket)),
+    };
+    if let Err(e) = &sent {
+        tracing::debug!("Failed to notify systemd: {}", e);
+    }
+    sent.is_ok()
+}
+
+#[cfg(not(unix))]
+fn notify_to(_: &OsStr, _: &str) -> bool {
+    false
+}
+
+/// How often systemd wants a ping: `WATCHDOG_USEC`, unless `WATCHDOG_PID`
+/// names another process
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?;
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    parse_watchdog(&usec, pid.as_deref(), std::process::id())
+}
+
+fn parse_watchdog(usec: &str, pid: Option<&str>, own: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.trim().parse() != Ok(own)) {
+        return None;
+    }
+    let usec: u64 = usec.trim().parse().ok().filter(|&usec| usec > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Pings systemd's watchdog while passes finish; does nothing when systemd
+/// is not watching
+pub struct Watchdog {
+    /// When the running pass started, 0 between passes
+    busy_since: Option<Arc<AtomicU64>>,
+}
+
+impl Watchdog {
+    /// Ping from a task in `tasks` at half the interval systemd asks for
+    pub fn start(tasks: &mut Tasks) -> Self {
+        let Some(interval) = watchdog_interval() else {
+            return Self { busy_since: None };
+        };
+        let busy_since = Arc::new(AtomicU64::new(0));
+        let busy = busy_since.clone();
+        tasks.spawn("watchdog", move |cancel| async move {
+            let mut tick = tokio::time::interval(interval / 2);
+            let mut stuck = false;
+            loop {
+                tokio::select! {
+                    _ = tick.tick() => {}
+                    _ = cancel.cancelled() => return,
+                }
+                let since = busy.load(Ordering::Relaxed);
+                let running = unix_now().saturating_sub(since);
+                if since != 0 && running > STUCK_PASS.as_secs() {
+                    if !stuck {
+                        tracing::warn!(
+                            "A pass has run for {}s, no longer pinging the watchdog",
+                            running
+                        );
+                        stuck = true;
+                    }
+                    continue;
+                }
+                stuck = false;
+                notify("WATCHDOG=1");
+            }
+        });
+        tracing::debug!("Pinging the systemd watchdog every {:?}", interval / 2);
+        Self {
+            busy_since: Some(busy_since),
+        }
+    }
+
+    /// A pass starts
+    pub fn busy(&self) {
+        if let Some(busy) = &self.busy_since {
+            busy.store(unix_now().max(1), Ordering::Relaxed);
+        }
+    }
+
+    /// The pass is over
+    pub fn idle(&self) {
+        if let Some(busy) = &self.busy_since {
+            busy.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_and_watchdog() {
+        assert_eq!(
+            parse_watchdog("30000000", None, 7),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog("30000000", Some("7"), 7),
+            Some(Duration::from_secs(30))
+        );
+        // Meant for a parent, or nonsense
+        assert_eq!(parse_watchdog("30000000", Some("8"), 7), None);
+        assert_eq!(parse_watchdog("0", None, 7), None);
+        assert_eq!(parse_watchdog("soon", None, 7), None);
+
+        #[cfg(unix)]
+        {
+            let dir = std::env::temp_dir().join(format!("wimesh-notify-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("notify.sock");
+            let _ = std::fs::remove_file(&path);
+            let systemd = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+            assert!(notify_to(path.as_os_str(), "READY=1\nSTATUS=Online"));
+            let mut buf = [0; 64];
+            let n = systemd.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"READY=1\nSTATUS=Online");
+            assert!(!notify_to(dir.join("gone.sock").as_os_str(), "READY=1"));
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! The daemon's background tasks, and stopping them
+//!
+//! The responder's listeners and coop's run as tasks next to the checks.
+//! Each is spawned into a `Tasks` with a `Cancel` it watches, and `Tasks`
+//! nest: the daemon's own holds one per part, so cancelling a part (a
+//! responder that failed halfway through starting) stops just its tasks,
+//! and cancelling the daemon stops them all. On Ctrl-C or SIGTERM
+//! (`systemctl stop`) the daemon stops checking, caches the sessions it
+//! holds and calls `shutdown`: the parts go last started first, each
+//! given a grace period, and a task still running after it is aborted
+//! and named in the log.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::{Id, JoinSet};
+
+/// How long a task gets to finish once cancelled
+pub const GRACE: Duration = Duration::from_secs(5);
+
+struct Node {
+    fired: watch::Sender<bool>,
+    children: Mutex<Vec<Weak<Node>>>,
+}
+
+impl Node {
+    fn fire(&self) {
+        self.fired.send_replace(true);
+        let children = self.children.lock().unwrap_or_else(|e| e.into_inner());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.fire();
+        }
+    }
+}
+
+/// Tells tasks to stop; cancelling one cancels the ones made from it
+#[derive(Clone)]
+pub struct Cancel(Arc<Node>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self(Arc::new(Node {
+            fired: watch::Sender::new(false),
+            children: Mutex::default(),
+        }))
+    }
+
+    /// One cancelled with this, or on its own
+    pub fn child(&self) -> Self {
+        let child = Self::new();
+        let mut children = self.0.children.lock().unwrap_or_else(|e| e.into_inner());
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(&child.0));
+        if self.is_cancelled() {
+            child.cancel();
+        }
+        child
+    }
+
+    pub fn cancel(&self) {
+        self.0.fire();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.fired.borrow()
+    }
+
+    /// Wait until cancelled
+    pub async fn cancelled(&self) {
+        let mut fired = self.0.fired.subscribe();
+        let _ = fired.wait_for(|fired| *fired).await;
+    }
+}
+
+impl Default for Cancel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tasks of one part of the daemon, and of the parts under it
+pub struct Tasks {
+    name: String,
+    cancel: Cancel,
+    set: JoinSet<()>,
+    names: HashMap<Id, &'static str>,
+    children: Vec<Tasks>,
+}
+
+impl Tasks {
+    pub fn new(name: &str) -> Self {
+        Self::under(name, Cancel::new())
+    }
+
+    fn under(name: &str, cancel: Cancel) -> Self {
+        Self {
+            name: name.to_string(),
+            cancel,
+            set: JoinSet::new(),
+            names: HashMap::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// What the tasks here watch
+    pub fn cancel(&self) -> &Cancel {
+        &self.cancel
+    }
+
+    /// A part under this one, stopped before the tasks here
+    pub fn child(&mut self, name: &str) -> &mut Tasks {
+        // Parts cancelled on their own (failed starts) that are done
+        for child in &mut self.children {
+            child.reap();
+        }
+        self.children
+            .retain(|child| !child.cancel.is_cancelled() || child.running() > 0);
+        let name = format!("{}/{}", self.name, name);
+        let child = Self::under(&name, self.cancel.child());
+        self.children.push(child);
+        self.children.last_mut().expect("just pushed")
+    }
+
+    /// Run `task` until it returns; it gets the `Cancel` to watch
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, task: F)
+    where
+        F: FnOnce(Cancel) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handle = self.set.spawn(task(self.cancel.clone()));
+        self.names.insert(handle.id(), name);
+    }
+
+    /// Forget the tasks that finished
+    fn reap(&mut self) {
+        while let Some(done) = self.set.try_join_next_with_id() {
+            let id = match done {
+                Ok((id, ())) => id,
+                Err(e) => e.id(),
+            };
+            self.names.remove(&id);
+        }
+        for child in &mut self.children {
+            child.reap();
+        }
+    }
+
+    /// How many tasks are still running, here and in the parts under it
+    pub fn running(&self) -> usize {
+        self.set.len() + self.children.iter().map(Tasks::running).sum::<usize>()
+    }
+
+    /// Cancel everything and wait for it, the parts under this one last
+    /// started first, each for at most `grace`; the names of the tasks
+    /// that had to be aborted
+    pub async fn shutdown(mut self, grace: Duration) -> Vec<String> {
+        self.cancel.cancel();
+        let mut refused = Vec::new();
+        while let Some(child) = self.children.pop() {
+            refused.extend(Box::pin(child.shutdown(grace)).await);
+        }
+        let deadline = tokio::time::Instant::now() + grace;
+        loop {
+            match tokio::time::timeout_at(deadline, self.set.join_next_with_id()).await {
+                Ok(Some(Ok((id, ())))) => {
+                    self.names.remove(&id);
+                }
+                Ok(Some(Err(e))) => {
+                    let name = self.names.remove(&e.id()).unwrap_or("?");
+                    if e.is_panic() {
+                        tracing::warn!("Task {}/{} had panicked", self.name, name);
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    self.set.abort_all();
+                    let mut names: Vec<String> = self
+                        .names
+                        .values()
+                        .map(|name| format!("{}/{}", self.name, name))
+                        .collect();
+                    names.sort();
+                    refused.extend(names);
+                    break;
+                }
+            }
+        }
+        refused
+    }
+}
+
+/// Wait for Ctrl-C, SIGTERM on Unix or a service stop on Windows; what came
+pub async fn stop_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "Ctrl-C",
+                _ = term.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                tracing::debug!("Not listening for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "Ctrl-C"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "Ctrl-C",
+            _ = crate::winservice::stop_requested() => "Service stop",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_in_order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Tasks::new("daemon");
+        for part in ["responder", "coop"] {
+            let order = order.clone();
+            tasks.child(part).spawn("listen", move |cancel| async move {
+                cancel.cancelled().await;
+                order.lock().unwrap().push(part);
+            });
+        }
+        tasks
+            .child("stuck")
+            .spawn("loop", |_| std::future::pending());
+        let cancelled = tasks.children[0].cancel().clone();
+        assert_eq!(tasks.running(), 3);
+
+        // A part stops on its own, the others keep running
+        tasks.children[1].cancel().cancel();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!cancelled.is_cancelled());
+        assert_eq!(*order.lock().unwrap(), ["coop"]);
+
+        let refused = tasks.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(refused, ["daemon/stuck/loop"]);
+        assert!(cancelled.is_cancelled());
+        assert_eq!(*order.lock().unwrap(), ["coop", "responder"]);
+        assert!(cancelled.child().is_cancelled());
+    }
+}
@@ -0,0 +1,445 @@
+//! OpenTelemetry traces of the login flows (`[telemetry]`)
+//!
+//! Every login is a `login` span, and each step of its flow
+//! (`scan_gateway`, `handshake`, `verify_device`, ...) a span under it,
+//! with its error when it failed and the log lines written while it ran
+//! as span events. With `[telemetry] enabled`, the layer made here exports
+//! the spans of wimesh's own code to an OpenTelemetry collector as
+//! OTLP/HTTP JSON (`<endpoint>/v1/traces`), so a login that failed on a
+//! machine far away can be looked at in Jaeger, Tempo or any OTLP backend.
+//! Behind a portal the collector is out of reach until the login is done:
+//! finished spans are kept, up to `MAX_QUEUED`, until it answers.
+
+use crate::config::TelemetryConfig;
+use crate::utils::random_u64;
+use anyhow::{Context as _, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// How often finished spans are sent
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Spans per request
+const BATCH: usize = 256;
+/// Spans kept while the collector cannot be reached; the oldest go first
+const MAX_QUEUED: usize = 4096;
+/// Log lines kept per span
+const MAX_EVENTS: usize = 128;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest `flush` waits for the collector on the way out
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Message {
+    Span(Value),
+    Flush(oneshot::Sender<()>),
+}
+
+/// The export task, once started
+static EXPORTER: OnceLock<mpsc::UnboundedSender<Message>> = OnceLock::new();
+
+/// The layer exporting spans as `cfg` says, with its export task started;
+/// `None` while telemetry is off
+pub fn layer<S>(cfg: &TelemetryConfig) -> Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if !cfg.enabled {
+        return Ok(None);
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in &cfg.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid [telemetry] header name '{}'", name))?;
+        let value = HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value of [telemetry] header '{}'", name))?;
+        headers.insert(name, value);
+    }
+    // A portal answers with a redirect to its login page
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .context("Failed to build the telemetry client")?;
+    let (spans, messages) = mpsc::unbounded_channel();
+    let exporter = Exporter {
+        client,
+        url: traces_url(&cfg.endpoint),
+        service_name: cfg.service_name.clone(),
+        queue: VecDeque::new(),
+        failing: false,
+    };
+    tokio::spawn(exporter.run(messages));
+    let _ = EXPORTER.set(spans.clone());
+    let own_code = Targets::new().with_target("wimesh", Level::DEBUG);
+    Ok(Some(SpanExport { spans }.with_filter(own_code)))
+}
+
+/// Send the spans not sent yet, waiting `FLUSH_TIMEOUT` at most
+pub async fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let (done, sent) = oneshot::channel();
+    if exporter.send(Message::Flush(done)).is_ok() {
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, sent).await;
+    }
+}
+
+/// `endpoint/v1/traces`, unless `endpoint` is that already
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    match endpoint.ends_with("/v1/traces") {
+        true => endpoint.to_string(),
+        false => format!("{}/v1/traces", endpoint),
+    }
+}
+
+/// Turns spans, once closed, into OTLP spans for the export task
+pub struct SpanExport {
+    spans: mpsc::UnboundedSender<Message>,
+}
+
+/// What is known of a span until it closes, kept in its extensions
+struct Pending {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    /// `otel.name` if the span has one, else its name
+    name: String,
+    start: u128,
+    attributes: Vec<Value>,
+    events: Vec<Value>,
+    dropped_events: u32,
+    error: Option<String>,
+}
+
+impl Visit for Pending {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "otel.name" => self.name = value.to_string(),
+            "error" => {
+                self.error = Some(value.to_string());
+                self.attributes.push(attribute("error", string(value)));
+            }
+            name => self.attributes.push(attribute(name, string(value))),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attributes
+            .push(attribute(field.name(), json!({ "boolValue": value })));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes.push(attribute(field.name(), int(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.attributes.push(attribute(field.name(), int(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.attributes
+            .push(attribute(field.name(), json!({ "doubleValue": value })));
+    }
+}
+
+/// A log line, as a span event
+#[derive(Default)]
+struct LogLine {
+    message: String,
+    attributes: Vec<Value>,
+}
+
+impl Visit for LogLine {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.attributes.push(attribute(name, string(value))),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for SpanExport
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let pending = extensions.get::<Pending>()?;
+            Some((pending.trace_id, pending.span_id))
+        });
+        let mut pending = Pending {
+            trace_id: match parent {
+                Some((trace_id, _)) => trace_id,
+                None => (u128::from(random_u64()) << 64) | u128::from(random_u64()),
+            },
+            span_id: random_u64(),
+            parent_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name().to_string(),
+            start: now(),
+            attributes: Vec::new(),
+            events: Vec::new(),
+            dropped_events: 0,
+            error: None,
+        };
+        attrs.record(&mut pending);
+        span.extensions_mut().insert(pending);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(pending) = span.extensions_mut().get_mut::<Pending>() {
+                values.record(pending);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(pending) = extensions.get_mut::<Pending>() else {
+            return;
+        };
+        if pending.events.len() >= MAX_EVENTS {
+            pending.dropped_events += 1;
+            return;
+        }
+        let mut line = LogLine::default();
+        event.record(&mut line);
+        let level = event.metadata().level().as_str();
+        line.attributes.insert(0, attribute("level", string(level)));
+        pending.events.push(json!({
+            "timeUnixNano": now().to_string(),
+            "name": line.message,
+            "attributes": line.attributes,
+        }));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(pending) = span.extensions_mut().remove::<Pending>() else {
+            return;
+        };
+        let _ = self.spans.send(Message::Span(otlp_span(pending, now())));
+    }
+}
+
+/// `pending` as an OTLP span, ended at `end`
+fn otlp_span(pending: Pending, end: u128) -> Value {
+    let mut span = json!({
+        "traceId": format!("{:032x}", pending.trace_id),
+        "spanId": format!("{:016x}", pending.span_id),
+        "parentSpanId": pending
+            .parent_id
+            .map(|id| format!("{:016x}", id))
+            .unwrap_or_default(),
+        "name": pending.name,
+        // SPAN_KIND_INTERNAL
+        "kind": 1,
+        "startTimeUnixNano": pending.start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": pending.attributes,
+        "events": pending.events,
+        "droppedEventsCount": pending.dropped_events,
+    });
+    if let Some(error) = pending.error {
+        // STATUS_CODE_ERROR
+        span["status"] = json!({ "code": 2, "message": error });
+    }
+    span
+}
+
+/// An export request for `spans`
+fn request(service_name: &str, spans: &[Value]) -> Value {
+    let version = env!("CARGO_PKG_VERSION");
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", string(service_name)),
+                    attribute("service.version", string(version)),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "wimesh", "version": version },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+fn string(value: &str) -> Value {
+    json!({ "stringValue": value })
+}
+
+/// 64-bit integers are strings in OTLP JSON
+fn int(value: impl ToString) -> Value {
+    json!({ "intValue": value.to_string() })
+}
+
+/// Nanoseconds since the Unix epoch
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default()
+}
+
+/// The export task's side: finished spans, waiting to be sent
+struct Exporter {
+    client: reqwest::Client,
+    url: String,
+    service_name: String,
+    queue: VecDeque<Value>,
+    /// Whether the last export failed, so the next failure is not logged
+    failing: bool,
+}
+
+impl Exporter {
+    async fn run(mut self, mut messages: mpsc::UnboundedReceiver<Message>) {
+        let mut tick = tokio::time::interval(EXPORT_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(Message::Span(span)) => {
+                        if self.queue.len() >= MAX_QUEUED {
+                            self.queue.pop_front();
+                        }
+                        self.queue.push_back(span);
+                    }
+                    Some(Message::Flush(done)) => {
+                        self.send_queued().await;
+                        let _ = done.send(());
+                    }
+                    None => return,
+                },
+                _ = tick.tick() => self.send_queued().await,
+            }
+        }
+    }
+
+    /// Send the queue in batches, keeping what the collector did not take
+    async fn send_queued(&mut self) {
+        while !self.queue.is_empty() {
+            let batch: Vec<Value> = self.queue.iter().take(BATCH).cloned().collect();
+            match self.post(&batch).await {
+                Ok(()) => {
+                    self.queue.drain(..batch.len());
+                    self.failing = false;
+                }
+                Err(e) => {
+                    if !self.failing {
+                        tracing::info!("Not exporting traces for now: {:#}", e);
+                    }
+                    self.failing = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn post(&self, spans: &[Value]) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request(&self.service_name, spans))
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach {}", self.url))?;
+        if !response.status().is_success() {
+            anyhow::bail!("{} answered {}", self.url, response.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_spans_as_otlp() {
+        let (spans, mut messages) = mpsc::unbounded_channel();
+        let subscriber = tracing_subscriber::registry().with(SpanExport { spans });
+        tracing::subscriber::with_default(subscriber, || {
+            let login = tracing::info_span!("login", ssid = "1.Free Wi-MESH");
+            let _login = login.enter();
+            let step = tracing::info_span!(
+                "step",
+                otel.name = "handshake",
+                error = tracing::field::Empty
+            );
+            step.in_scope(|| tracing::warn!("No answer from the gateway"));
+            step.record("error", "Gateway timed out");
+        });
+        let mut finished = Vec::new();
+        while let Ok(Message::Span(span)) = messages.try_recv() {
+            finished.push(span);
+        }
+        let [step, login] = &finished[..] else {
+            panic!("Expected two spans, got {:?}", finished);
+        };
+
+        assert_eq!(step["name"], "handshake");
+        assert_eq!(login["name"], "login");
+        assert_eq!(step["traceId"], login["traceId"]);
+        assert_eq!(step["parentSpanId"], login["spanId"]);
+        assert_eq!(login["parentSpanId"], "");
+        assert_eq!(step["status"]["code"], 2);
+        assert_eq!(step["status"]["message"], "Gateway timed out");
+        assert_eq!(step["events"][0]["name"], "No answer from the gateway");
+        assert_eq!(
+            login["attributes"][0],
+            json!({ "key": "ssid", "value": { "stringValue": "1.Free Wi-MESH" } })
+        );
+        assert!(login.get("status").is_none());
+
+        assert_eq!(
+            traces_url("http://otel:4318/"),
+            "http://otel:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("http://otel:4318/v1/traces"),
+            "http://otel:4318/v1/traces"
+        );
+        let body = request("wimesh", &finished);
+        assert_eq!(
+            body["resourceSpans"][0]["scopeSpans"][0]["spans"][1],
+            *login
+        );
+    }
+}
@@ -0,0 +1,415 @@
+//! Tray icon (`wimesh tray`, feature `tray`)
+//!
+//! For laptops: an icon showing what the running daemon sees, green when
+//! the configured WiFi it is on is online, red when a portal is holding
+//! traffic or there is no address, grey while it is paused, off the
+//! configured WiFi or not answering. Its tooltip lists the networks and
+//! when each was last logged in; its menu has "Login now", "Pause
+//! monitoring" (or "Resume monitoring"), "Open logs" and "Quit". It all
+//! goes through the control socket (`[control]`), asked for `status` every
+//! `POLL`, so the tray runs as the user, wherever the daemon runs, as long
+//! as the user may open the socket. Linux and the BSDs show it through
+//! StatusNotifierItem on the session bus (KDE, Xfce, Cinnamon, GNOME with
+//! the AppIndicator extension), Windows in the notification area; there is
+//! no macOS tray.
+
+use crate::config::Config;
+use crate::control::{self, Reply, Request};
+use crate::logs::LogSource;
+use crate::status::NetworkState;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod sni;
+#[cfg(all(unix, not(target_os = "macos")))]
+use sni as platform;
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as platform;
+
+/// How often the daemon is asked what it sees
+pub const POLL: Duration = Duration::from_secs(5);
+
+/// Colour of the icon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Green,
+    Red,
+    Grey,
+}
+
+impl Color {
+    fn rgb(self) -> (u32, u32, u32) {
+        match self {
+            Color::Green => (0x43, 0xa0, 0x47),
+            Color::Red => (0xe5, 0x39, 0x35),
+            Color::Grey => (0x9e, 0x9e, 0x9e),
+        }
+    }
+}
+
+/// What the icon shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrayStatus {
+    pub color: Color,
+    /// In a word or three: `Online`, `Portal holding traffic`, ...
+    pub title: String,
+    /// A line per network the daemon is on, with its last login
+    pub details: Vec<String>,
+    pub paused: bool,
+    /// Whether the daemon answered
+    pub reachable: bool,
+}
+
+/// `status` as the daemon reports it
+#[derive(Debug, Deserialize)]
+struct DaemonStatus {
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    networks: Vec<Network>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Network {
+    interface: String,
+    ssid: String,
+    state: NetworkState,
+    #[serde(default)]
+    last_login: Option<u64>,
+}
+
+impl TrayStatus {
+    /// What the daemon's answer to `status` comes to; `now` and
+    /// `utc_offset` place its logins in local time
+    pub fn from_reply(reply: &Reply, now: u64, utc_offset: i64) -> Self {
+        let status = reply
+            .status
+            .clone()
+            .and_then(|status| serde_json::from_value::<DaemonStatus>(status).ok());
+        let Some(status) = status.filter(|_| reply.ok) else {
+            return Self::unreachable(&reply.message);
+        };
+        let held = status
+            .networks
+            .iter()
+            .find(|n| matches!(n.state, NetworkState::Captive | NetworkState::NoAddress));
+        let online = status
+            .networks
+            .iter()
+            .any(|n| n.state == NetworkState::Online);
+        let (color, title) = match held {
+            _ if status.paused => (Color::Grey, "Paused"),
+            Some(n) if n.state == NetworkState::Captive => (Color::Red, "Portal holding traffic"),
+            Some(_) => (Color::Red, "No address"),
+            None if online => (Color::Green, "Online"),
+            None => (Color::Grey, "Not on a configured WiFi"),
+        };
+        let details = status
+            .networks
+            .iter()
+            .map(|n| {
+                let login = match n.last_login {
+                    Some(at) => format!(", logged in {}", when(at, now, utc_offset)),
+                    None => String::new(),
+                };
+                format!(
+                    "{}: '{}' {}{}",
+                    n.interface,
+                    n.ssid,
+                    n.state.as_str(),
+                    login
+                )
+            })
+            .collect();
+        Self {
+            color,
+            title: title.to_string(),
+            details,
+            paused: status.paused,
+            reachable: true,
+        }
+    }
+
+    /// The daemon did not answer, for `why`
+    pub fn unreachable(why: &str) -> Self {
+        Self {
+            color: Color::Grey,
+            title: "Daemon not answering".to_string(),
+            details: why.lines().take(1).map(str::to_string).collect(),
+            paused: false,
+            reachable: false,
+        }
+    }
+
+    /// Title and details, a line each
+    pub fn tooltip(&self) -> String {
+        std::iter::once(self.title.as_str())
+            .chain(self.details.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A local time today, else how many days ago
+fn when(at: u64, now: u64, utc_offset: i64) -> String {
+    let days = now.saturating_sub(at) / 86_400;
+    if days > 0 {
+        return format!("{} day{} ago", days, if days == 1 { "" } else { "s" });
+    }
+    let clock = (at as i64 + utc_offset).rem_euclid(86_400);
+    format!("at {:02}:{:02}", clock / 3600, clock % 3600 / 60)
+}
+
+/// What a menu item does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    LoginNow,
+    Pause,
+    Resume,
+    OpenLogs,
+    Quit,
+}
+
+/// A line of the menu
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Greyed-out text
+    Text(String),
+    Separator,
+    Item {
+        action: Action,
+        label: &'static str,
+        enabled: bool,
+    },
+}
+
+/// The menu for `status`
+pub fn menu(status: &TrayStatus) -> Vec<Entry> {
+    let item = |action, label, enabled| Entry::Item {
+        action,
+        label,
+        enabled,
+    };
+    vec![
+        Entry::Text(status.title.clone()),
+        Entry::Separator,
+        item(Action::LoginNow, "Login now", status.reachable),
+        match status.paused {
+            true => item(Action::Resume, "Resume monitoring", status.reachable),
+            false => item(Action::Pause, "Pause monitoring", status.reachable),
+        },
+        item(Action::OpenLogs, "Open logs", true),
+        Entry::Separator,
+        item(Action::Quit, "Quit", true),
+    ]
+}
+
+/// The icon, `size` pixels square: a disc of `color` on transparency,
+/// ARGB
+pub fn icon(color: Color, size: u32) -> Vec<u32> {
+    let (r, g, b) = color.rgb();
+    let centre = size as f32 / 2.0;
+    let radius = size as f32 * 0.4;
+    (0..size * size)
+        .map(|i| {
+            let x = (i % size) as f32 + 0.5 - centre;
+            let y = (i / size) as f32 + 0.5 - centre;
+            // Pixels on the rim are partly covered
+            let coverage = (radius + 0.5 - (x * x + y * y).sqrt()).clamp(0.0, 1.0);
+            let alpha = (coverage * 255.0).round() as u32;
+            alpha << 24 | r << 16 | g << 8 | b
+        })
+        .collect()
+}
+
+/// Show the tray icon until Quit, or until the tray goes away
+pub async fn run(cfg: &Config, config_path: Option<&Path>) -> Result<()> {
+    let paths = control::socket_paths(&cfg.control);
+    let (actions, mut chosen) = mpsc::unbounded_channel();
+    let mut shown = query(&paths).await;
+    let mut tray = platform::Tray::start(&shown, actions)?;
+    tracing::info!("Showing the tray icon: {}", shown.title);
+    let mut tick = tokio::time::interval(POLL);
+    let stop = crate::tasks::stop_signal();
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = tick.tick() => {}
+            action = chosen.recv() => match action {
+                None => anyhow::bail!("The tray went away"),
+                Some(Action::Quit) => return Ok(()),
+                Some(Action::OpenLogs) => {
+                    if let Err(e) = open_logs(cfg, config_path) {
+                        tracing::warn!("Failed to open the logs: {:#}", e);
+                    }
+                    continue;
+                }
+                // A pass takes its time: the icon keeps up meanwhile
+                Some(Action::LoginNow) => {
+                    tokio::spawn(nudge(paths.clone(), Request::LoginNow));
+                }
+                Some(Action::Pause) => nudge(paths.clone(), Request::Pause).await,
+                Some(Action::Resume) => nudge(paths.clone(), Request::Resume).await,
+            },
+            stop = &mut stop => {
+                tracing::info!("Received {}, removing the tray icon", stop);
+                return Ok(());
+            }
+        }
+        let status = query(&paths).await;
+        if status != shown {
+            tray.show(&status)?;
+            shown = status;
+        }
+    }
+}
+
+async fn query(paths: &[PathBuf]) -> TrayStatus {
+    match control::send(paths, Request::Status).await {
+        Ok(reply) => {
+            TrayStatus::from_reply(&reply, crate::state::unix_now(), crate::utils::utc_offset())
+        }
+        Err(e) => TrayStatus::unreachable(&format!("{:#}", e)),
+    }
+}
+
+async fn nudge(paths: Vec<PathBuf>, request: Request) {
+    match control::send(&paths, request).await {
+        Ok(reply) if reply.ok => tracing::info!("{}", reply.message),
+        Ok(reply) => tracing::warn!("The daemon refused: {}", reply.message),
+        Err(e) => tracing::warn!("Failed to reach the daemon: {:#}", e),
+    }
+}
+
+/// The log file in the desktop's viewer, or `wimesh logs --follow` in a
+/// terminal for the journal
+fn open_logs(cfg: &Config, config_path: Option<&Path>) -> Result<()> {
+    let mut command = match LogSource::find(None, &cfg.logging.log_file)? {
+        LogSource::File(path) => opener(&path),
+        LogSource::Journal => {
+            let mut command = terminal()?;
+            command.arg(std::env::current_exe()?);
+            if let Some(path) = config_path {
+                command.arg("--config").arg(path);
+            }
+            command.args(["logs", "--follow"]);
+            command
+        }
+    };
+    command
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command.get_program()))?;
+    Ok(())
+}
+
+fn opener(path: &Path) -> Command {
+    let program = match () {
+        _ if cfg!(windows) => "explorer",
+        _ if cfg!(target_os = "macos") => "open",
+        _ => "xdg-open",
+    };
+    let mut command = Command::new(program);
+    command.arg(path);
+    command
+}
+
+/// A terminal emulator, ready for the command to run in it after `-e`
+fn terminal() -> Result<Command> {
+    let found = std::env::var("TERMINAL")
+        .ok()
+        .filter(|t| !t.is_empty())
+        .into_iter()
+        .chain(["x-terminal-emulator".to_string(), "xterm".to_string()])
+        .find(|t| crate::utils::find_in_path(t).is_some())
+        .context("No terminal to show the journal in; `wimesh logs --follow` shows it")?;
+    let mut command = Command::new(found);
+    command.arg("-e");
+    Ok(command)
+}
+
+#[cfg(not(any(windows, all(unix, not(target_os = "macos")))))]
+mod platform {
+    use super::{Action, TrayStatus};
+    use anyhow::Result;
+    use tokio::sync::mpsc;
+
+    pub struct Tray;
+
+    impl Tray {
+        pub fn start(_: &TrayStatus, _: mpsc::UnboundedSender<Action>) -> Result<Self> {
+            anyhow::bail!("No tray icon on this platform")
+        }
+
+        pub fn show(&mut self, _: &TrayStatus) -> Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tray_status() {
+        let reply = Reply {
+            status: Some(serde_json::json!({
+                "paused": false,
+                "next_check": 1_700_000_030,
+                "networks": [
+                    { "interface": "wlan0", "ssid": "Free Wi-MESH", "state": "online",
+                      "last_login": 1_699_996_500 },
+                ],
+            })),
+            ..Reply::ok("Next check in 30s")
+        };
+        // 2023-11-14 22:13:20 UTC, 7 hours ahead
+        let status = TrayStatus::from_reply(&reply, 1_700_000_000, 7 * 3600);
+        assert_eq!(status.color, Color::Green);
+        assert_eq!(
+            status.tooltip(),
+            "Online\nwlan0: 'Free Wi-MESH' online, logged in at 04:15"
+        );
+        assert!(menu(&status).contains(&Entry::Item {
+            action: Action::Pause,
+            label: "Pause monitoring",
+            enabled: true,
+        }));
+
+        let captive = Reply {
+            status: Some(serde_json::json!({
+                "paused": false,
+                "networks": [{ "interface": "wlan0", "ssid": "KTX", "state": "captive" }],
+            })),
+            ..Reply::ok("Checking now")
+        };
+        let status = TrayStatus::from_reply(&captive, 1_700_000_000, 0);
+        assert_eq!(
+            (status.color, status.title.as_str()),
+            (Color::Red, "Portal holding traffic")
+        );
+        assert_eq!(status.details, ["wlan0: 'KTX' captive"]);
+
+        let down = TrayStatus::unreachable("Failed to connect to /run/wimesh/control.sock\nagain");
+        assert_eq!(down.color, Color::Grey);
+        assert!(!down.reachable);
+        assert!(menu(&down).contains(&Entry::Item {
+            action: Action::LoginNow,
+            label: "Login now",
+            enabled: false,
+        }));
+        assert_eq!(when(1_699_800_000, 1_700_000_000, 0), "2 days ago");
+
+        let pixels = icon(Color::Red, 16);
+        assert_eq!(pixels.len(), 256);
+        assert_eq!(pixels[8 * 16 + 8], 0xffe53935);
+        assert_eq!(pixels[0] >> 24, 0);
+    }
+}
@@ -0,0 +1,651 @@
+//! The tray on freedesktop desktops: StatusNotifierItem
+//!
+//! The icon is the object `/StatusNotifierItem` on the session bus, under a
+//! name of its own registered with the desktop's `StatusNotifierWatcher`,
+//! which passes it to the tray; the tray reads its properties and calls
+//! back into its menu, the `com.canonical.dbusmenu` object `/MenuBar`. A
+//! thread answers those calls from what was last shown; changes go out as
+//! signals, after which the tray asks again. The D-Bus side is `dbus`'s.
+
+use super::{icon, menu, Action, Entry, TrayStatus};
+use crate::dbus::{self, Field, Message, Value, Writer, ERROR, METHOD_CALL, METHOD_RETURN, SIGNAL};
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+const DBUS: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const ITEM: &str = "org.kde.StatusNotifierItem";
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU: &str = "com.canonical.dbusmenu";
+const MENU_PATH: &str = "/MenuBar";
+/// `RequestName` flag: fail rather than wait in line for the name
+const DO_NOT_QUEUE: u32 = 4;
+const PRIMARY_OWNER: u64 = 1;
+/// Icon sizes offered; the tray scales the nearest
+const ICON_SIZES: [u32; 4] = [16, 22, 32, 48];
+
+const ITEM_PROPERTIES: &[&str] = &[
+    "Category",
+    "Id",
+    "Title",
+    "Status",
+    "IconName",
+    "IconPixmap",
+    "ToolTip",
+    "ItemIsMenu",
+    "Menu",
+];
+const MENU_PROPERTIES: &[&str] = &["Version", "TextDirection", "Status", "IconThemePath"];
+
+const ITEM_XML: &str = r#"<node>
+  <interface name="org.kde.StatusNotifierItem">
+    <property name="Category" type="s" access="read"/>
+    <property name="Id" type="s" access="read"/>
+    <property name="Title" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconName" type="s" access="read"/>
+    <property name="IconPixmap" type="a(iiay)" access="read"/>
+    <property name="ToolTip" type="(sa(iiay)ss)" access="read"/>
+    <property name="ItemIsMenu" type="b" access="read"/>
+    <property name="Menu" type="o" access="read"/>
+    <method name="Activate"><arg type="i" direction="in"/><arg type="i" direction="in"/></method>
+    <method name="SecondaryActivate"><arg type="i" direction="in"/><arg type="i" direction="in"/></method>
+    <method name="ContextMenu"><arg type="i" direction="in"/><arg type="i" direction="in"/></method>
+    <method name="Scroll"><arg type="i" direction="in"/><arg type="s" direction="in"/></method>
+    <signal name="NewIcon"/>
+    <signal name="NewToolTip"/>
+  </interface>
+</node>
+"#;
+
+const MENU_XML: &str = r#"<node>
+  <interface name="com.canonical.dbusmenu">
+    <property name="Version" type="u" access="read"/>
+    <property name="TextDirection" type="s" access="read"/>
+    <property name="Status" type="s" access="read"/>
+    <property name="IconThemePath" type="as" access="read"/>
+    <method name="GetLayout">
+      <arg type="i" direction="in"/><arg type="i" direction="in"/><arg type="as" direction="in"/>
+      <arg type="u" direction="out"/><arg type="(ia{sv}av)" direction="out"/>
+    </method>
+    <method name="GetGroupProperties">
+      <arg type="ai" direction="in"/><arg type="as" direction="in"/>
+      <arg type="a(ia{sv})" direction="out"/>
+    </method>
+    <method name="GetProperty">
+      <arg type="i" direction="in"/><arg type="s" direction="in"/><arg type="v" direction="out"/>
+    </method>
+    <method name="Event">
+      <arg type="i" direction="in"/><arg type="s" direction="in"/>
+      <arg type="v" direction="in"/><arg type="u" direction="in"/>
+    </method>
+    <method name="EventGroup">
+      <arg type="a(isvu)" direction="in"/><arg type="ai" direction="out"/>
+    </method>
+    <method name="AboutToShow">
+      <arg type="i" direction="in"/><arg type="b" direction="out"/>
+    </method>
+    <method name="AboutToShowGroup">
+      <arg type="ai" direction="in"/><arg type="ai" direction="out"/><arg type="ai" direction="out"/>
+    </method>
+    <signal name="LayoutUpdated"><arg type="u"/><arg type="i"/></signal>
+  </interface>
+</node>
+"#;
+
+/// What the tray is shown, read by the thread answering it
+struct Shown {
+    status: TrayStatus,
+    entries: Vec<Entry>,
+    /// Of the menu layout, bumped on every change
+    revision: u32,
+}
+
+/// The writing side of the connection, for both threads
+struct Connection {
+    stream: Mutex<UnixStream>,
+    serial: AtomicU32,
+}
+
+impl Connection {
+    fn send(&self, kind: u8, fields: &[Field], signature: &str, body: Writer) -> Result<()> {
+        let serial = self.serial.fetch_add(1, Ordering::Relaxed) + 1;
+        let message = dbus::message(kind, serial, fields, signature, body);
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.write_all(&message).context("Lost the session bus")
+    }
+
+    fn signal(
+        &self,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: Writer,
+    ) -> Result<()> {
+        let fields = [
+            Field::Path(path),
+            Field::Interface(interface),
+            Field::Member(member),
+        ];
+        self.send(SIGNAL, &fields, signature, body)
+    }
+
+    fn reply(&self, call: &Message, signature: &str, body: Writer) -> Result<()> {
+        let mut fields = vec![Field::ReplySerial(call.serial)];
+        fields.extend(call.sender.as_deref().map(Field::Destination));
+        self.send(METHOD_RETURN, &fields, signature, body)
+    }
+
+    fn error(&self, call: &Message, name: &str, text: &str) -> Result<()> {
+        let mut fields = vec![Field::ErrorName(name), Field::ReplySerial(call.serial)];
+        fields.extend(call.sender.as_deref().map(Field::Destination));
+        let mut body = Writer::default();
+        body.string(text);
+        self.send(ERROR, &fields, "s", body)
+    }
+
+    /// Hand the item to the watcher; the tray then asks for the rest
+    fn register(&self, name: &str) -> Result<()> {
+        let fields = [
+            Field::Path(WATCHER_PATH),
+            Field::Interface(WATCHER),
+            Field::Member("RegisterStatusNotifierItem"),
+            Field::Destination(WATCHER),
+        ];
+        let mut body = Writer::default();
+        body.string(name);
+        self.send(METHOD_CALL, &fields, "s", body)
+    }
+}
+
+/// The icon on the desktop's tray, there until dropped
+pub struct Tray {
+    connection: Arc<Connection>,
+    shown: Arc<Mutex<Shown>>,
+}
+
+impl Tray {
+    /// Show `status`, sending the menu items picked to `actions`
+    pub fn start(status: &TrayStatus, actions: mpsc::UnboundedSender<Action>) -> Result<Self> {
+        let mut bus = dbus::session_bus()?;
+        let watched = bus.call(DBUS, DBUS_PATH, DBUS, "NameHasOwner", &[WATCHER])?;
+        if watched.first() != Some(&Value::Bool(true)) {
+            anyhow::bail!(
+                "No tray to show the icon in: nothing on the session bus owns {} (on GNOME, \
+                 the AppIndicator extension provides it)",
+                WATCHER
+            );
+        }
+        let name = format!("{}-{}-1", ITEM, std::process::id());
+        let mut body = Writer::default();
+        body.string(&name);
+        body.u32(DO_NOT_QUEUE);
+        let owner = bus.call_with(DBUS, DBUS_PATH, DBUS, "RequestName", "su", body)?;
+        if owner.first().and_then(Value::as_u64) != Some(PRIMARY_OWNER) {
+            anyhow::bail!("{} is taken on the session bus", name);
+        }
+        // The desktop's panel restarting takes registering again
+        let rule = format!(
+            "type='signal',sender='{}',member='NameOwnerChanged',arg0='{}'",
+            DBUS, WATCHER
+        );
+        bus.call(DBUS, DBUS_PATH, DBUS, "AddMatch", &[&rule])?;
+
+        let connection = Arc::new(Connection {
+            stream: Mutex::new(bus.try_clone_stream()?),
+            serial: AtomicU32::new(bus.serial()),
+        });
+        let shown = Arc::new(Mutex::new(Shown {
+            status: status.clone(),
+            entries: menu(status),
+            revision: 1,
+        }));
+        let (answering, on) = (connection.clone(), shown.clone());
+        let registered = name.clone();
+        std::thread::Builder::new()
+            .name("tray".to_string())
+            .spawn(move || serve(bus, &answering, &on, &registered, &actions))?;
+        connection.register(&name)?;
+        Ok(Self { connection, shown })
+    }
+
+    /// Show `status` instead
+    pub fn show(&mut self, status: &TrayStatus) -> Result<()> {
+        let (icon, tooltip, layout) = {
+            let mut shown = self.shown.lock().unwrap_or_else(|e| e.into_inner());
+            let icon = shown.status.color != status.color;
+            let tooltip = shown.status.tooltip() != status.tooltip();
+            shown.status = status.clone();
+            let entries = menu(status);
+            let layout = (entries != shown.entries).then(|| {
+                shown.entries = entries;
+                shown.revision += 1;
+                shown.revision
+            });
+            (icon, tooltip, layout)
+        };
+        if icon {
+            self.connection
+                .signal(ITEM_PATH, ITEM, "NewIcon", "", Writer::default())?;
+        }
+        if tooltip {
+            self.connection
+                .signal(ITEM_PATH, ITEM, "NewToolTip", "", Writer::default())?;
+        }
+        if let Some(revision) = layout {
+            let mut body = Writer::default();
+            body.u32(revision);
+            body.i32(0);
+            self.connection
+                .signal(MENU_PATH, MENU, "LayoutUpdated", "ui", body)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Tray {
+    fn drop(&mut self) {
+        // Ends the thread's read, and with the connection the item
+        let stream = self
+            .connection
+            .stream
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Answer the tray until the bus goes away
+fn serve(
+    mut bus: dbus::Bus<UnixStream>,
+    connection: &Connection,
+    shown: &Mutex<Shown>,
+    name: &str,
+    actions: &mpsc::UnboundedSender<Action>,
+) {
+    loop {
+        let message = match bus.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::debug!("The tray's session bus went away: {:#}", e);
+                return;
+            }
+        };
+        let answered = match message.kind {
+            METHOD_CALL => answer(&message, connection, shown, actions),
+            SIGNAL if message.member.as_deref() == Some("NameOwnerChanged") => {
+                let owner = message.body.get(2).and_then(Value::as_str);
+                match owner.filter(|owner| !owner.is_empty()) {
+                    Some(_) => {
+                        tracing::info!("The tray is back, showing the icon again");
+                        connection.register(name)
+                    }
+                    None => Ok(()),
+                }
+            }
+            // The watcher refusing the item, say
+            ERROR => {
+                let why = message.body.first().and_then(Value::as_str);
+                tracing::warn!(
+                    "The tray refused: {} {}",
+                    message.error_name.as_deref().unwrap_or_default(),
+                    why.unwrap_or_default()
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = answered {
+            tracing::debug!("Failed to answer the tray: {:#}", e);
+            return;
+        }
+    }
+}
+
+fn answer(
+    call: &Message,
+    connection: &Connection,
+    shown: &Mutex<Shown>,
+    actions: &mpsc::UnboundedSender<Action>,
+) -> Result<()> {
+    let shown = shown.lock().unwrap_or_else(|e| e.into_inner());
+    let path = call.path.as_deref().unwrap_or_default();
+    let interface = call.interface.as_deref().unwrap_or_default();
+    let member = call.member.as_deref().unwrap_or_default();
+    let arg = |at: usize| call.body.get(at);
+    let id = |at: usize| arg(at).and_then(Value::as_i64).unwrap_or(-1);
+    let clicked = |id: i64| match usize::try_from(id - 1)
+        .ok()
+        .and_then(|at| shown.entries.get(at))
+    {
+        Some(Entry::Item {
+            action,
+            enabled: true,
+            ..
+        }) => Some(*action),
+        _ => None,
+    };
+    // Acted on once answered: Quit ends the process
+    let mut picked = None;
+
+    let mut body = Writer::default();
+    let signature = match (path, interface, member) {
+        (_, PROPERTIES, "Get") => {
+            let name = arg(1).and_then(Value::as_str).unwrap_or_default();
+            if !property(&shown, path, name, &mut body) {
+                return connection.error(
+                    call,
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    &format!("No property {} on {}", name, path),
+                );
+            }
+            "v"
+        }
+        (_, PROPERTIES, "GetAll") => {
+            let names = match path {
+                ITEM_PATH => ITEM_PROPERTIES,
+                MENU_PATH => MENU_PROPERTIES,
+                _ => &[],
+            };
+            body.array("{sv}", |w| {
+                for name in names {
+                    w.structure(|w| {
+                        w.string(name);
+                        property(&shown, path, name, w);
+                    });
+                }
+            });
+            "a{sv}"
+        }
+        (_, "org.freedesktop.DBus.Introspectable", "Introspect") => {
+            body.string(match path {
+                ITEM_PATH => ITEM_XML,
+                MENU_PATH => MENU_XML,
+                _ => "<node/>",
+            });
+            "s"
+        }
+        (_, "org.freedesktop.DBus.Peer", "Ping") => "",
+        // The menu is all there is to click
+        (ITEM_PATH, ITEM, "Activate" | "SecondaryActivate" | "ContextMenu" | "Scroll") => "",
+        (MENU_PATH, MENU, "GetLayout") => {
+            body.u32(shown.revision);
+            layout(&shown.entries, &mut body);
+            "u(ia{sv}av)"
+        }
+        (MENU_PATH, MENU, "GetGroupProperties") => {
+            let ids = ids(arg(0));
+            body.array("(ia{sv})", |w| {
+                for (at, entry) in shown.entries.iter().enumerate() {
+                    let id = at as i64 + 1;
+                    if ids.is_empty() || ids.contains(&id) {
+                        w.structure(|w| {
+                            w.i32(id as i32);
+                            entry_properties(entry, w);
+                        });
+                    }
+                }
+            });
+            "a(ia{sv})"
+        }
+        (MENU_PATH, MENU, "GetProperty") => {
+            let (id, name) = (id(0), arg(1).and_then(Value::as_str).unwrap_or_default());
+            let entry = usize::try_from(id - 1)
+                .ok()
+                .and_then(|at| shown.entries.get(at));
+            match entry.and_then(|entry| entry_property(entry, name)) {
+                Some(value) => value.write(&mut body),
+                None => {
+                    return connection.error(
+                        call,
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        &format!("No property {} on item {}", name, id),
+                    )
+                }
+            }
+            "v"
+        }
+        (MENU_PATH, MENU, "Event") => {
+            if arg(1).and_then(Value::as_str) == Some("clicked") {
+                picked = clicked(id(0));
+            }
+            ""
+        }
+        (MENU_PATH, MENU, "EventGroup") => {
+            for event in arg(0).and_then(Value::as_array).unwrap_or_default() {
+                if let Value::Struct(fields) = event {
+                    if fields.get(1).and_then(Value::as_str) == Some("clicked") {
+                        picked = picked.or(clicked(fields[0].as_i64().unwrap_or(-1)));
+                    }
+                }
+            }
+            body.array("i", |_| {});
+            "ai"
+        }
+        (MENU_PATH, MENU, "AboutToShow") => {
+            body.bool(false);
+            "b"
+        }
+        (MENU_PATH, MENU, "AboutToShowGroup") => {
+            body.array("i", |_| {});
+            body.array("i", |_| {});
+            "aiai"
+        }
+        _ => {
+            return connection.error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("No method {}.{} on {}", interface, member, path),
+            )
+        }
+    };
+    connection.reply(call, signature, body)?;
+    if let Some(action) = picked {
+        let _ = actions.send(action);
+    }
+    Ok(())
+}
+
+/// The ids of an `ai`
+fn ids(value: Option<&Value>) -> Vec<i64> {
+    value
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(Value::as_i64)
+        .collect()
+}
+
+/// Write the property `name` of the object at `path` as a variant; false
+/// when there is no such property
+fn property(shown: &Shown, path: &str, name: &str, w: &mut Writer) -> bool {
+    let text = |w: &mut Writer, s: &str| w.variant("s", |w| w.string(s));
+    match (path, name) {
+        (ITEM_PATH, "Category") => text(w, "SystemServices"),
+        (ITEM_PATH, "Id") => text(w, "wimesh"),
+        (ITEM_PATH, "Title") => text(w, "Wimesh"),
+        (ITEM_PATH, "Status") => text(w, "Active"),
+        // The pixmap instead
+        (ITEM_PATH, "IconName") => text(w, ""),
+        (ITEM_PATH, "IconPixmap") => w.variant("a(iiay)", |w| pixmaps(&shown.status, w)),
+        (ITEM_PATH, "ToolTip") => w.variant("(sa(iiay)ss)", |w| {
+            w.structure(|w| {
+                w.string("");
+                w.array("(iiay)", |_| {});
+                w.string("Wimesh");
+                w.string(&shown.status.tooltip());
+            })
+        }),
+        (ITEM_PATH, "ItemIsMenu") => w.variant("b", |w| w.bool(true)),
+        (ITEM_PATH, "Menu") => w.variant("o", |w| w.string(MENU_PATH)),
+        (MENU_PATH, "Version") => w.variant("u", |w| w.u32(3)),
+        (MENU_PATH, "TextDirection") => text(w, "ltr"),
+        (MENU_PATH, "Status") => text(w, "normal"),
+        (MENU_PATH, "IconThemePath") => w.variant("as", |w| w.array("s", |_| {})),
+        _ => return false,
+    }
+    true
+}
+
+/// The icon in each size, ARGB in network byte order
+fn pixmaps(status: &TrayStatus, w: &mut Writer) {
+    w.array("(iiay)", |w| {
+        for size in ICON_SIZES {
+            w.structure(|w| {
+                w.i32(size as i32);
+                w.i32(size as i32);
+                let pixels: Vec<u8> = icon(status.color, size)
+                    .iter()
+                    .flat_map(|pixel| pixel.to_be_bytes())
+                    .collect();
+                w.bytes(&pixels);
+            });
+        }
+    });
+}
+
+/// The root item, id 0, holding the entries, ids from 1
+fn layout(entries: &[Entry], w: &mut Writer) {
+    w.structure(|w| {
+        w.i32(0);
+        w.array("{sv}", |w| {
+            w.structure(|w| {
+                w.string("children-display");
+                w.variant("s", |w| w.string("submenu"));
+            })
+        });
+        w.array("v", |w| {
+            for (at, entry) in entries.iter().enumerate() {
+                w.variant("(ia{sv}av)", |w| {
+                    w.structure(|w| {
+                        w.i32(at as i32 + 1);
+                        entry_properties(entry, w);
+                        w.array("v", |_| {});
+                    })
+                });
+            }
+        });
+    });
+}
+
+/// A menu item property
+enum ItemProperty {
+    Str(String),
+    Bool(bool),
+}
+
+impl ItemProperty {
+    fn write(&self, w: &mut Writer) {
+        match self {
+            ItemProperty::Str(s) => w.variant("s", |w| w.string(s)),
+            ItemProperty::Bool(b) => w.variant("b", |w| w.bool(*b)),
+        }
+    }
+}
+
+const ENTRY_PROPERTIES: [&str; 3] = ["type", "label", "enabled"];
+
+fn entry_property(entry: &Entry, name: &str) -> Option<ItemProperty> {
+    // An underscore marks the access key
+    let label = |text: &str| ItemProperty::Str(text.replace('_', "__"));
+    match (entry, name) {
+        (Entry::Separator, "type") => Some(ItemProperty::Str("separator".to_string())),
+        (Entry::Text(text), "label") => Some(label(text)),
+        (Entry::Text(_), "enabled") => Some(ItemProperty::Bool(false)),
+        (Entry::Item { label: text, .. }, "label") => Some(label(text)),
+        (Entry::Item { enabled, .. }, "enabled") => Some(ItemProperty::Bool(*enabled)),
+        _ => None,
+    }
+}
+
+fn entry_properties(entry: &Entry, w: &mut Writer) {
+    w.array("{sv}", |w| {
+        for name in ENTRY_PROPERTIES {
+            if let Some(value) = entry_property(entry, name) {
+                w.structure(|w| {
+                    w.string(name);
+                    value.write(w);
+                });
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tray::Color;
+
+    #[test]
+    fn test_menu_layout() {
+        let status = TrayStatus {
+            color: Color::Green,
+            title: "Online".to_string(),
+            details: Vec::new(),
+            paused: true,
+            reachable: true,
+        };
+        let mut body = Writer::default();
+        body.u32(4);
+        layout(&menu(&status), &mut body);
+        let reply = dbus::message(
+            METHOD_RETURN,
+            9,
+            &[Field::ReplySerial(3)],
+            "u(ia{sv}av)",
+            body,
+        );
+        let reply = Message::parse(&reply).unwrap();
+        assert_eq!(reply.reply_serial, Some(3));
+        assert_eq!(reply.body[0], Value::UInt(4));
+        let Value::Struct(root) = &reply.body[1] else {
+            panic!("Not a layout: {:?}", reply.body[1]);
+        };
+        let children = root[2].as_array().unwrap();
+        assert_eq!(children.len(), 7);
+        let Value::Struct(resume) = children[3].inner() else {
+            panic!("Not an item: {:?}", children[3]);
+        };
+        assert_eq!(resume[0], Value::Int(4));
+        let labels: Vec<_> = resume[1]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| match entry {
+                Value::Struct(kv) => (kv[0].as_str().unwrap().to_string(), kv[1].clone()),
+                other => panic!("Not a dict entry: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                (
+                    "label".to_string(),
+                    Value::Variant(Box::new(Value::Str("Resume monitoring".into())))
+                ),
+                (
+                    "enabled".to_string(),
+                    Value::Variant(Box::new(Value::Bool(true)))
+                ),
+            ]
+        );
+        let Value::Struct(separator) = children[1].inner() else {
+            panic!("Not an item: {:?}", children[1]);
+        };
+        assert_eq!(
+            separator[1].as_array().unwrap()[0],
+            Value::Struct(vec![
+                Value::Str("type".into()),
+                Value::Variant(Box::new(Value::Str("separator".into())))
+            ])
+        );
+    }
+}
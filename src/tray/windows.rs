@@ -0,0 +1,306 @@
+//! The tray on Windows: the notification area
+//!
+//! The icon belongs to a hidden window of its own, whose thread runs the
+//! message loop: a click on the icon comes to it as `CALLBACK` and pops up
+//! the menu, built then from what was last shown. Changes are posted to
+//! it as `UPDATE`. A restarted Explorer broadcasts `TaskbarCreated`, and
+//! the icon is added again.
+
+use super::{icon, menu, Action, Entry, TrayStatus};
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use windows_sys::Win32::Foundation::{HWND, LPARAM, LRESULT, POINT, WPARAM};
+use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows_sys::Win32::UI::Shell::{
+    Shell_NotifyIconW, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE, NIM_MODIFY,
+    NOTIFYICONDATAW,
+};
+use windows_sys::Win32::UI::WindowsAndMessaging::{
+    AppendMenuW, CreateIcon, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DestroyIcon,
+    DestroyMenu, DestroyWindow, DispatchMessageW, GetCursorPos, GetMessageW, GetSystemMetrics,
+    PostMessageW, PostQuitMessage, RegisterClassW, RegisterWindowMessageW, SetForegroundWindow,
+    TrackPopupMenu, TranslateMessage, HICON, MF_GRAYED, MF_SEPARATOR, MF_STRING, MSG, SM_CXSMICON,
+    TPM_NONOTIFY, TPM_RETURNCMD, TPM_RIGHTBUTTON, WM_APP, WM_CLOSE, WM_DESTROY, WM_LBUTTONUP,
+    WM_NULL, WM_RBUTTONUP, WNDCLASSW,
+};
+
+/// A click on the icon, the mouse message in `lparam`
+const CALLBACK: u32 = WM_APP + 1;
+/// Something new to show
+const UPDATE: u32 = WM_APP + 2;
+const CLASS: &str = "wimesh-tray";
+
+/// Last shown, and where picks go: the window procedure has no context
+/// of its own
+static SHOWN: Mutex<Option<TrayStatus>> = Mutex::new(None);
+static ACTIONS: Mutex<Option<mpsc::UnboundedSender<Action>>> = Mutex::new(None);
+/// What Explorer broadcasts once it has restarted
+static TASKBAR_CREATED: AtomicU32 = AtomicU32::new(0);
+/// The icon handed to the shell, destroyed when replaced
+static ICON: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The icon in the notification area, there until dropped
+pub struct Tray {
+    window: HWND,
+}
+
+// SAFETY: the handle is only posted to, which any thread may do
+unsafe impl Send for Tray {}
+
+impl Tray {
+    /// Show `status`, sending the menu items picked to `actions`
+    pub fn start(status: &TrayStatus, actions: mpsc::UnboundedSender<Action>) -> Result<Self> {
+        *SHOWN.lock().unwrap_or_else(|e| e.into_inner()) = Some(status.clone());
+        *ACTIONS.lock().unwrap_or_else(|e| e.into_inner()) = Some(actions);
+        let (created, window) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("tray".to_string())
+            .spawn(move || run_window(created))?;
+        let window = window.recv().context("The tray window did not start")??;
+        Ok(Self {
+            window: window as HWND,
+        })
+    }
+
+    /// Show `status` instead
+    pub fn show(&mut self, status: &TrayStatus) -> Result<()> {
+        *SHOWN.lock().unwrap_or_else(|e| e.into_inner()) = Some(status.clone());
+        // SAFETY: posting to a window of this process
+        if unsafe { PostMessageW(self.window, UPDATE, 0, 0) } == 0 {
+            anyhow::bail!("The tray window is gone");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Tray {
+    fn drop(&mut self) {
+        // SAFETY: as in `show`
+        unsafe { PostMessageW(self.window, WM_CLOSE, 0, 0) };
+    }
+}
+
+/// The window's thread: create it, add the icon, and run its messages
+/// until it is closed; the window, as an address, is sent to `created`
+fn run_window(created: std::sync::mpsc::Sender<Result<usize>>) {
+    let class = wide(CLASS);
+    // SAFETY: the class name and window title outlive the calls; the
+    // window procedure is `window_proc`
+    let window = unsafe {
+        let instance = GetModuleHandleW(std::ptr::null());
+        let wc = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: class.as_ptr(),
+            ..Default::default()
+        };
+        RegisterClassW(&wc);
+        let title = wide("Wimesh");
+        CreateWindowExW(
+            0,
+            class.as_ptr(),
+            title.as_ptr(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            instance,
+            std::ptr::null(),
+        )
+    };
+    if window.is_null() {
+        let _ = created
+            .send(Err(std::io::Error::last_os_error()).context("Failed to create the tray window"));
+        return;
+    }
+    let name = wide("TaskbarCreated");
+    // SAFETY: `name` is NUL-terminated
+    TASKBAR_CREATED.store(
+        unsafe { RegisterWindowMessageW(name.as_ptr()) },
+        Ordering::Relaxed,
+    );
+    if !notify(window, NIM_ADD) {
+        let _ = created.send(Err(anyhow::anyhow!("The shell refused the tray icon")));
+        // SAFETY: the window was created on this thread
+        unsafe { DestroyWindow(window) };
+        return;
+    }
+    let _ = created.send(Ok(window as usize));
+
+    // SAFETY: a message loop over a zeroed MSG, filled in by GetMessageW
+    unsafe {
+        let mut message: MSG = std::mem::zeroed();
+        while GetMessageW(&mut message, std::ptr::null_mut(), 0, 0) > 0 {
+            TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+    }
+    // Nothing answers the menu any more
+    ACTIONS.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+unsafe extern "system" fn window_proc(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match message {
+        CALLBACK => {
+            if matches!(lparam as u32, WM_LBUTTONUP | WM_RBUTTONUP) {
+                popup(window);
+            }
+            0
+        }
+        UPDATE => {
+            notify(window, NIM_MODIFY);
+            0
+        }
+        _ if message == TASKBAR_CREATED.load(Ordering::Relaxed) => {
+            notify(window, NIM_ADD);
+            0
+        }
+        WM_CLOSE => {
+            notify(window, NIM_DELETE);
+            // SAFETY: the window's own thread
+            unsafe { DestroyWindow(window) };
+            0
+        }
+        WM_DESTROY => {
+            // SAFETY: ends this thread's message loop
+            unsafe { PostQuitMessage(0) };
+            0
+        }
+        // SAFETY: the arguments as received
+        _ => unsafe { DefWindowProcW(window, message, wparam, lparam) },
+    }
+}
+
+/// Add, change or remove the icon, with what was last shown; whether the
+/// shell took it
+fn notify(window: HWND, action: u32) -> bool {
+    let Some(status) = SHOWN.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+        return false;
+    };
+    let hicon = create_icon(&status);
+    let mut data = NOTIFYICONDATAW {
+        cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as u32,
+        hWnd: window,
+        uID: 1,
+        uFlags: NIF_ICON | NIF_TIP | NIF_MESSAGE,
+        uCallbackMessage: CALLBACK,
+        hIcon: hicon,
+        ..Default::default()
+    };
+    // The tooltip is cut at 127 characters
+    let tip: Vec<u16> = status.tooltip().encode_utf16().take(127).collect();
+    data.szTip[..tip.len()].copy_from_slice(&tip);
+    // SAFETY: `data` is filled in for the window's icon
+    let done = unsafe { Shell_NotifyIconW(action, &data) } != 0;
+    let old = ICON.swap(hicon, Ordering::AcqRel);
+    if !old.is_null() {
+        // SAFETY: made by `create_icon`, and no longer the shell's
+        unsafe { DestroyIcon(old) };
+    }
+    done
+}
+
+/// The icon for `status`, the small icon size of the display
+fn create_icon(status: &TrayStatus) -> HICON {
+    // SAFETY: no arguments
+    let size = unsafe { GetSystemMetrics(SM_CXSMICON) }.clamp(16, 64) as u32;
+    // 32-bit BGRA, its alpha used for transparency; the mask, a bit per
+    // pixel in rows of whole 16-bit words, all clear
+    let colour: Vec<u8> = icon(status.color, size)
+        .iter()
+        .flat_map(|pixel| pixel.to_le_bytes())
+        .collect();
+    let mask = vec![0u8; size.div_ceil(16) as usize * 2 * size as usize];
+    // SAFETY: both bitmaps are `size` pixels square in the formats given
+    unsafe {
+        CreateIcon(
+            GetModuleHandleW(std::ptr::null()),
+            size as i32,
+            size as i32,
+            1,
+            32,
+            mask.as_ptr(),
+            colour.as_ptr(),
+        )
+    }
+}
+
+/// The menu under the cursor; the entry picked goes to `ACTIONS`
+fn popup(window: HWND) {
+    let Some(status) = SHOWN.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+        return;
+    };
+    let entries = menu(&status);
+    // SAFETY: the menu is built, shown and destroyed here, its strings
+    // outliving the calls; SetForegroundWindow lets a click elsewhere
+    // close it
+    let picked = unsafe {
+        let popup = CreatePopupMenu();
+        if popup.is_null() {
+            return;
+        }
+        for (at, entry) in entries.iter().enumerate() {
+            let id = at + 1;
+            match entry {
+                Entry::Text(text) => {
+                    let text = wide(text);
+                    AppendMenuW(popup, MF_STRING | MF_GRAYED, id, text.as_ptr());
+                }
+                Entry::Separator => {
+                    AppendMenuW(popup, MF_SEPARATOR, 0, std::ptr::null());
+                }
+                Entry::Item { label, enabled, .. } => {
+                    let label = wide(label);
+                    let flags = if *enabled {
+                        MF_STRING
+                    } else {
+                        MF_STRING | MF_GRAYED
+                    };
+                    AppendMenuW(popup, flags, id, label.as_ptr());
+                }
+            }
+        }
+        let mut cursor = POINT { x: 0, y: 0 };
+        GetCursorPos(&mut cursor);
+        SetForegroundWindow(window);
+        let picked = TrackPopupMenu(
+            popup,
+            TPM_RETURNCMD | TPM_NONOTIFY | TPM_RIGHTBUTTON,
+            cursor.x,
+            cursor.y,
+            0,
+            window,
+            std::ptr::null(),
+        );
+        PostMessageW(window, WM_NULL, 0, 0);
+        DestroyMenu(popup);
+        picked
+    };
+    let entry = usize::try_from(picked - 1)
+        .ok()
+        .and_then(|at| entries.get(at));
+    if let Some(Entry::Item {
+        action,
+        enabled: true,
+        ..
+    }) = entry
+    {
+        if let Some(actions) = ACTIONS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            let _ = actions.send(*action);
+        }
+    }
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
@@ -1,41 +1,1589 @@
 //! Utility functions for network checks
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// Check if connected to any of the target WiFi SSIDs
 /// Returns Some(ssid) if connected to one of the target SSIDs, None otherwise
+///
+/// With several adapters associated this is only the first match; use
+/// `active_wifi` to see them all.
 pub fn is_connected_to_wifi(target_ssids: &[String]) -> Result<Option<String>> {
+    Ok(active_wifi()?
+        .into_iter()
+        .map(|(_, ssid)| ssid)
+        .find(|ssid| target_ssids.contains(ssid)))
+}
+
+/// Arguments of the `nmcli` call listing associated WiFi interfaces
+pub(crate) const NMCLI_ACTIVE_ARGS: &[&str] = &["-t", "-f", "active,device,ssid", "dev", "wifi"];
+
+/// `(interface, ssid)` of every associated WiFi interface
+pub fn active_wifi() -> Result<Vec<(String, String)>> {
+    if cfg!(target_os = "freebsd") {
+        return freebsd::associations();
+    }
+    if cfg!(windows) {
+        return windows::associations();
+    }
+    if cfg!(target_os = "macos") {
+        return macos::associations();
+    }
+    match crate::dbus::active_wifi() {
+        Ok(active) => return Ok(active),
+        Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+    }
+
+    let output = Command::new("nmcli").args(NMCLI_ACTIVE_ARGS).output()?;
+
+    Ok(parse_nmcli_active(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Active lines of `nmcli -t -f active,device,ssid dev wifi`, e.g.
+/// `yes:wlan0:Free Wi-MESH`; terse mode escapes colons in SSIDs as `\:`
+pub(crate) fn parse_nmcli_active(stdout: &str) -> Vec<(String, String)> {
+    let mut active: Vec<(String, String)> = Vec::new();
+    for line in stdout.lines() {
+        let Some(rest) = line.strip_prefix("yes:") else {
+            continue;
+        };
+        let Some((device, ssid)) = rest.split_once(':') else {
+            continue;
+        };
+        let pair = (device.to_string(), ssid.replace("\\:", ":"));
+        if !active.contains(&pair) {
+            active.push(pair);
+        }
+    }
+    active
+}
+
+/// Find the WiFi interface currently associated to `ssid`
+pub fn wifi_interface_for_ssid(ssid: &str) -> Option<String> {
+    active_wifi()
+        .ok()?
+        .into_iter()
+        .find(|(_, current)| current == ssid)
+        .map(|(interface, _)| interface)
+}
+
+/// BSSID of the access point `interface` is associated to
+pub fn active_bssid(interface: &str) -> Option<String> {
+    if cfg!(target_os = "freebsd") {
+        return freebsd::bssid(interface);
+    }
+    if cfg!(windows) {
+        return windows::bssid(interface);
+    }
+    if cfg!(target_os = "macos") {
+        return macos::bssid(interface);
+    }
+    match crate::dbus::active_bssid(interface) {
+        Ok(bssid) => return bssid,
+        Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+    }
+
+    let output = Command::new("nmcli").args(bssid_args(interface)).output().ok()?;
+    parse_nmcli_bssid(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Arguments of the `nmcli` call listing the access points `interface`
+/// sees, without a fresh scan
+fn bssid_args(interface: &str) -> [&str; 10] {
+    [
+        "-t", "-f", "active,bssid", "dev", "wifi", "list", "ifname", interface, "--rescan", "no",
+    ]
+}
+
+/// BSSID of the active line of `nmcli -t -f active,bssid dev wifi list`,
+/// e.g. `yes:02\:00\:00\:AA\:BB\:01`
+fn parse_nmcli_bssid(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("yes:"))
+        .and_then(|bssid| normalize_mac(&bssid.replace("\\:", ":")))
+}
+
+/// Frequency band of an access point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Band {
+    #[serde(rename = "2.4GHz")]
+    Ghz24,
+    #[serde(rename = "5GHz")]
+    Ghz5,
+    #[serde(rename = "6GHz")]
+    Ghz6,
+}
+
+impl Band {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ghz24 => "2.4GHz",
+            Self::Ghz5 => "5GHz",
+            Self::Ghz6 => "6GHz",
+        }
+    }
+
+    /// The band of `channel` when nothing else says; 6GHz channels reuse
+    /// the numbers of 5GHz ones
+    fn of_channel(channel: u32) -> Self {
+        if channel <= 14 {
+            Self::Ghz24
+        } else {
+            Self::Ghz5
+        }
+    }
+}
+
+/// The radio side of an association, as the WiFi backend reports it:
+/// mesh nodes on 2.4GHz and 5GHz are different machines to the portal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Radio {
+    pub band: Band,
+    pub channel: u32,
+    /// Link rate in Mbit/s, where the backend knows it (nmcli only has the
+    /// access point's best rate)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_mbps: Option<u32>,
+}
+
+impl Radio {
+    /// From the center frequency in MHz, if it is a WiFi channel
+    pub fn from_frequency(mhz: u32, rate_mbps: Option<u32>) -> Option<Self> {
+        let (band, channel) = match mhz {
+            2484 => (Band::Ghz24, 14),
+            2412..=2472 => (Band::Ghz24, (mhz - 2407) / 5),
+            5160..=5885 => (Band::Ghz5, (mhz - 5000) / 5),
+            5955..=7115 => (Band::Ghz6, (mhz - 5950) / 5),
+            _ => return None,
+        };
+        Some(Self {
+            band,
+            channel,
+            rate_mbps,
+        })
+    }
+
+    /// From the channel, and the band where the backend says it
+    fn from_channel(channel: u32, band: Option<Band>, rate_mbps: Option<u32>) -> Option<Self> {
+        (channel > 0).then(|| Self {
+            band: band.unwrap_or(Band::of_channel(channel)),
+            channel,
+            rate_mbps,
+        })
+    }
+
+    /// `5GHz ch 36, 866 Mbit/s`
+    pub fn describe(&self) -> String {
+        match self.rate_mbps {
+            Some(rate) => format!("{} ch {}, {} Mbit/s", self.band.as_str(), self.channel, rate),
+            None => format!("{} ch {}", self.band.as_str(), self.channel),
+        }
+    }
+}
+
+/// Band, channel and link rate of the access point `interface` is
+/// associated to
+pub fn active_radio(interface: &str) -> Option<Radio> {
+    if cfg!(target_os = "freebsd") {
+        return freebsd::radio(interface);
+    }
+    if cfg!(windows) {
+        return windows::radio(interface);
+    }
+    if cfg!(target_os = "macos") {
+        return macos::radio(interface);
+    }
+    match crate::dbus::radio(interface) {
+        Ok(radio) => return radio,
+        Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+    }
+
+    let output = Command::new("nmcli").args(radio_args(interface)).output().ok()?;
+    parse_nmcli_radio(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Like `bssid_args`, for the channel, frequency and rate
+fn radio_args(interface: &str) -> [&str; 10] {
+    [
+        "-t", "-f", "active,chan,freq,rate", "dev", "wifi", "list", "ifname", interface,
+        "--rescan", "no",
+    ]
+}
+
+/// The active line of `nmcli -t -f active,chan,freq,rate dev wifi list`,
+/// e.g. `yes:36:5180 MHz:270 Mbit/s`
+fn parse_nmcli_radio(stdout: &str) -> Option<Radio> {
+    let line = stdout.lines().find_map(|line| line.strip_prefix("yes:"))?;
+    let mut fields = line.split(':');
+    let channel: u32 = fields.next()?.trim().parse().ok()?;
+    let number = |field: Option<&str>| field?.split_whitespace().next()?.parse::<u32>().ok();
+    let frequency = number(fields.next());
+    let rate = number(fields.next());
+    frequency
+        .and_then(|mhz| Radio::from_frequency(mhz, rate))
+        .or_else(|| Radio::from_channel(channel, None, rate))
+}
+
+/// Arguments of the `nmcli` call printing whether the connection of
+/// `interface` is metered
+fn metered_args(interface: &str) -> [&str; 6] {
+    ["-t", "-f", "GENERAL.METERED", "device", "show", interface]
+}
+
+/// Whether NetworkManager marks the connection of `interface` metered,
+/// guessed or set (it guesses so for Android tethering); never elsewhere
+pub fn is_metered(interface: &str) -> bool {
+    if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+        return false;
+    }
+    match crate::dbus::is_metered(interface) {
+        Ok(metered) => return metered,
+        Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+    }
+    Command::new("nmcli")
+        .args(metered_args(interface))
+        .output()
+        .is_ok_and(|output| parse_nmcli_metered(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// `GENERAL.METERED:yes (guessed)`; `no`, `unknown` and friends are not
+fn parse_nmcli_metered(stdout: &str) -> bool {
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("GENERAL.METERED:"))
+        .is_some_and(|value| value.starts_with("yes"))
+}
+
+/// Arguments of the `nmcli` call printing the DHCP options of the lease
+/// on `interface`
+fn dhcp_options_args(interface: &str) -> [&str; 6] {
+    ["-t", "-f", "DHCP4", "device", "show", interface]
+}
+
+/// The Captive Portal API URI (RFC 8910, DHCP option 114) of the lease on
+/// `interface`, as NetworkManager keeps it; never elsewhere
+pub fn capport_uri(interface: &str) -> Option<String> {
+    if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+        return None;
+    }
+    let output = Command::new("nmcli").args(dhcp_options_args(interface)).output().ok()?;
+    parse_nmcli_capport(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// `DHCP4.OPTION[7]:captive_portal = https\://portal.example/api`
+fn parse_nmcli_capport(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .filter(|line| line.starts_with("DHCP4.OPTION"))
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?.1.split_once('=')?;
+            (name.trim() == "captive_portal").then(|| value.trim().replace("\\:", ":"))
+        })
+        .filter(|uri| !uri.is_empty())
+}
+
+/// Make `mac` the cloned MAC of the saved WiFi connection for `ssid`; it
+/// takes effect the next time the connection comes up
+pub fn set_cloned_mac(ssid: &str, mac: &str) -> Result<()> {
+    if cfg!(target_os = "freebsd") {
+        anyhow::bail!("Setting the WiFi MAC is not supported on FreeBSD");
+    }
+
+    // NetworkManager names WiFi connections after their SSID by default
     let output = Command::new("nmcli")
-        .args(["-t", "-f", "active,ssid", "dev", "wifi"])
+        .args(["connection", "modify", ssid, "802-11-wireless.cloned-mac-address", mac])
         .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nmcli could not set the MAC of '{}': {}",
+            ssid,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    
-    for line in stdout.lines() {
-        if line.starts_with("yes:") {
-            let current_ssid = line.strip_prefix("yes:").unwrap_or("");
-            // Check if current SSID matches any of the target SSIDs
-            if target_ssids.iter().any(|ssid| ssid == current_ssid) {
-                return Ok(Some(current_ssid.to_string()));
-            }
+/// Next hop of the default route, optionally of one interface only
+pub fn default_gateway(interface: Option<&str>) -> Option<IpAddr> {
+    if cfg!(target_os = "freebsd") {
+        return freebsd::default_gateway(interface);
+    }
+
+    let output = Command::new("ip").args(gateway_args(interface)).output().ok()?;
+    parse_default_gateway(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Arguments of the `ip` call listing default routes, of `interface` only
+/// if given
+fn gateway_args(interface: Option<&str>) -> Vec<&str> {
+    let mut args = DEFAULT_ROUTE_ARGS.to_vec();
+    if let Some(interface) = interface {
+        args.extend(["dev", interface]);
+    }
+    args
+}
+
+/// Next hop of the first route in `ip route show default` output, e.g.
+/// "default via 192.168.1.1 dev wlan0 proto dhcp metric 600"
+fn parse_default_gateway(output: &str) -> Option<IpAddr> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        words.find(|w| *w == "via")?;
+        words.next()?.parse().ok()
+    })
+}
+
+/// Arguments of the `ip` call listing default routes
+const DEFAULT_ROUTE_ARGS: &[&str] = &["-4", "route", "show", "default"];
+
+/// Interface of the default route traffic actually takes (lowest metric)
+pub fn default_route_interface() -> Option<String> {
+    if cfg!(target_os = "freebsd") {
+        return freebsd::default_route_interface();
+    }
+    let output = Command::new("ip").args(DEFAULT_ROUTE_ARGS).output().ok()?;
+    parse_default_routes(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Device of the lowest-metric route in `ip route show default` output
+pub(crate) fn parse_default_routes(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let after = |key: &str| {
+                let at = words.iter().position(|w| *w == key)?;
+                words.get(at + 1).copied()
+            };
+            let metric = after("metric").and_then(|m| m.parse::<u32>().ok()).unwrap_or(0);
+            Some((metric, after("dev")?.to_string()))
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, dev)| dev)
+}
+
+/// What is wrong with the routing after a login on `logged_in`, if traffic
+/// would not use that session: the default route `route` (None: there is
+/// none) leaves through something that is not one of `wifi` interfaces
+pub fn route_problem(logged_in: &str, route: Option<&str>, wifi: &[&str]) -> Option<String> {
+    match route {
+        None => Some(format!(
+            "Logged in on {}, but there is no default route: reconnect the WiFi so DHCP installs \
+             one (nmcli device reapply {})",
+            logged_in, logged_in
+        )),
+        Some(route) if route == logged_in || wifi.contains(&route) => None,
+        Some(route) => Some(format!(
+            "Logged in on {}, but the default route goes through {}, so traffic bypasses the \
+             portal session. Disconnect {} (a stale USB tether or Ethernet?), or give the WiFi a \
+             lower route metric: nmcli connection modify <connection> ipv4.route-metric 50",
+            logged_in, route, route
+        )),
+    }
+}
+
+/// IPv4 addresses of `interface`; None when they could not be listed
+pub fn interface_addresses(interface: &str) -> Option<Vec<Ipv4Addr>> {
+    let (program, args) = addresses_command(interface);
+    let output = Command::new(program).args(args).output();
+    let output = output.ok().filter(|o| o.status.success())?;
+    Some(parse_inet_addrs(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Program and arguments listing the IPv4 addresses of `interface`
+fn addresses_command(interface: &str) -> (&'static str, Vec<&str>) {
+    if cfg!(target_os = "freebsd") {
+        ("ifconfig", vec![interface, "inet"])
+    } else {
+        ("ip", vec!["-4", "-o", "addr", "show", "dev", interface])
+    }
+}
+
+/// Addresses after `inet` in `ip -o addr` (`inet 10.20.30.41/22 brd ...`)
+/// or `ifconfig` (`inet 10.20.30.41 netmask ...`) output
+pub(crate) fn parse_inet_addrs(output: &str) -> Vec<Ipv4Addr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            words.find(|w| *w == "inet")?;
+            words.next()?.split('/').next()?.parse().ok()
+        })
+        .collect()
+}
+
+/// Unix time the DHCP lease of `interface` runs out, as NetworkManager
+/// reports it
+pub fn lease_expiry(interface: &str) -> Option<u64> {
+    if cfg!(target_os = "freebsd") {
+        return None;
+    }
+    let output = Command::new("nmcli")
+        .args(lease_args(interface))
+        .output()
+        .ok()?;
+    parse_lease_expiry(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Arguments of the `nmcli` call showing the DHCP lease of `interface`
+fn lease_args(interface: &str) -> [&str; 6] {
+    ["-t", "-f", "DHCP4", "device", "show", interface]
+}
+
+/// `expiry` option of `nmcli -t -f DHCP4 device show` output, e.g.
+/// `DHCP4.OPTION[4]:expiry = 1760003600`
+pub(crate) fn parse_lease_expiry(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        let (_, value) = line.split_once("expiry = ")?;
+        value.trim().parse().ok()
+    })
+}
+
+/// Why `addrs` (with the DHCP lease ending at `lease_expiry`) give no
+/// usable IP at `now`: no address, only a link-local or unspecified one, or
+/// an address whose lease ran out
+pub fn address_problem(addrs: &[Ipv4Addr], lease_expiry: Option<u64>, now: u64) -> Option<String> {
+    if !addrs.iter().any(|a| !a.is_link_local() && !a.is_unspecified()) {
+        return Some(match addrs.iter().find(|a| a.is_link_local()) {
+            Some(addr) => format!("only the link-local address {} (DHCP got no answer)", addr),
+            None => "no IPv4 address (no DHCP lease)".to_string(),
+        });
+    }
+    match lease_expiry {
+        Some(expiry) if expiry > 0 && expiry <= now => {
+            Some(format!("the DHCP lease expired {}s ago", now - expiry))
         }
+        _ => None,
     }
-    
-    Ok(None)
 }
 
-/// Check internet connectivity by pinging Google
-pub fn has_internet_connectivity() -> bool {
-    Command::new("curl")
-        .args([
-            "-sf",
-            "--head",
-            "--max-time",
-            "5",
-            "https://www.google.com",
-        ])
+/// `address_problem` of `interface` right now; None also when its
+/// addresses could not be listed
+pub fn interface_address_problem(interface: &str) -> Option<String> {
+    let addrs = interface_addresses(interface)?;
+    address_problem(&addrs, lease_expiry(interface), crate::state::unix_now())
+}
+
+/// Ask for a fresh DHCP lease on `interface`: NetworkManager reactivates
+/// the device, on FreeBSD `dhclient` runs again
+pub fn renew_dhcp(interface: &str) -> Result<()> {
+    let (program, args) = if cfg!(target_os = "freebsd") {
+        ("dhclient", vec![interface])
+    } else {
+        ("nmcli", vec!["device", "connect", interface])
+    };
+    let output = Command::new(program).args(&args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} could not renew the lease on {}: {}",
+            program,
+            interface,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Take `interface` down and up again, which also re-associates and asks
+/// for a new lease
+pub fn bounce_interface(interface: &str) -> Result<()> {
+    let steps: [(&str, Vec<&str>); 2] = if cfg!(target_os = "freebsd") {
+        [
+            ("ifconfig", vec![interface, "down"]),
+            ("ifconfig", vec![interface, "up"]),
+        ]
+    } else {
+        [
+            ("nmcli", vec!["device", "disconnect", interface]),
+            ("nmcli", vec!["device", "connect", interface]),
+        ]
+    };
+    for (program, args) in steps {
+        let output = Command::new(program).args(&args).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "{} {} failed: {}",
+                program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that `gateway` is alive: a TCP connection to port 80 (refused
+/// counts, something answered it), else one ICMP echo through `ping`
+pub async fn gateway_reachable(gateway: IpAddr, timeout: Duration) -> bool {
+    let addr = SocketAddr::new(gateway, 80);
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => return true,
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => return true,
+        _ => {}
+    }
+
+    // Linux ping waits `-W` seconds; FreeBSD's `-W` is milliseconds and `-t`
+    // bounds the whole run in seconds
+    let wait = timeout.as_secs().max(1).to_string();
+    let wait_flag = if cfg!(target_os = "freebsd") { "-t" } else { "-W" };
+    tokio::process::Command::new("ping")
+        .args(["-c", "1", wait_flag, &wait, &gateway.to_string()])
         .output()
+        .await
         .map(|output| output.status.success())
         .unwrap_or(false)
 }
+
+/// Arguments of the `curl` call checking connectivity where wimesh does
+/// not run itself (`remote`)
+pub(crate) fn connectivity_check_args(interface: Option<&str>) -> Vec<&str> {
+    let mut args = vec!["-sf", "--head", "--max-time", "5"];
+    if let Some(interface) = interface {
+        args.extend(["--interface", interface]);
+    }
+    args.push("https://www.google.com");
+    args
+}
+
+/// Full path of the program `name` in `$PATH` (`name.exe` on Windows)
+pub fn find_in_path(name: &str) -> Option<std::path::PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    let file = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(&file))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Programs WiFi detection shells out to on this platform
+pub fn wifi_tools() -> &'static [&'static str] {
+    if cfg!(target_os = "freebsd") {
+        &["wpa_cli", "ifconfig"]
+    } else if cfg!(windows) {
+        &["netsh"]
+    } else if cfg!(target_os = "macos") {
+        &["networksetup"]
+    } else {
+        &["nmcli"]
+    }
+}
+
+/// Seconds the local time is ahead of UTC; 0 (UTC), with a warning, when
+/// the OS cannot tell, as on routers without a timezone. Looked up at most
+/// once a minute, so a pass evaluating several schedules asks only once
+pub fn utc_offset() -> i64 {
+    static CACHE: Mutex<Option<(u64, Option<i64>)>> = Mutex::new(None);
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    let minute = crate::state::unix_now() / 60;
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let offset = match *cache {
+        Some((at, offset)) if at == minute => offset,
+        _ => {
+            let offset = local_utc_offset();
+            *cache = Some((minute, offset));
+            offset
+        }
+    };
+    offset.unwrap_or_else(|| {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            tracing::warn!("Could not determine the local timezone, using UTC");
+        }
+        0
+    })
+}
+
+/// The local UTC offset now, from `localtime_r`
+#[cfg(unix)]
+fn local_utc_offset() -> Option<i64> {
+    // SAFETY: `time` accepts a null pointer, and `localtime_r` only writes
+    // the `tm` it is given, which is plain data
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        Some(tm.tm_gmtoff as i64)
+    }
+}
+
+/// The local UTC offset now, from `GetTimeZoneInformation`
+#[cfg(windows)]
+fn local_utc_offset() -> Option<i64> {
+    use windows_sys::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+    // What `GetTimeZoneInformation` says is in effect
+    const TIME_ZONE_ID_STANDARD: u32 = 1;
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+    const TIME_ZONE_ID_INVALID: u32 = u32::MAX;
+
+    // SAFETY: the call only fills in the plain-data struct it is given
+    let (id, info) = unsafe {
+        let mut info: TIME_ZONE_INFORMATION = std::mem::zeroed();
+        (GetTimeZoneInformation(&mut info), info)
+    };
+    // Biases are minutes UTC is ahead of the local time
+    let bias = match id {
+        TIME_ZONE_ID_INVALID => return None,
+        TIME_ZONE_ID_DAYLIGHT => info.Bias + info.DaylightBias,
+        TIME_ZONE_ID_STANDARD => info.Bias + info.StandardBias,
+        _ => info.Bias,
+    };
+    Some(-(bias as i64) * 60)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn local_utc_offset() -> Option<i64> {
+    None
+}
+
+/// A random number from the OS-seeded hasher keys; good enough for picking
+/// among options, not for cryptography
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default(),
+    );
+    hasher.finish()
+}
+
+/// Mask a MAC address for logs, keeping only the last octet
+pub fn redact_mac(mac: &str) -> String {
+    match mac.rsplit_once(':') {
+        Some((_, last)) => format!("**:**:**:**:**:{}", last),
+        None if mac.is_empty() => String::new(),
+        None => "**".to_string(),
+    }
+}
+
+/// `mac` in the `AA:BB:CC:DD:EE:FF` form the config uses, from colon,
+/// dash or bare notation; None if it is not a MAC address
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let hex: String = mac
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .collect();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let octets: Vec<String> = hex
+        .to_ascii_uppercase()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| String::from_utf8_lossy(pair).into_owned())
+        .collect();
+    Some(octets.join(":"))
+}
+
+/// MAC address of `interface`, or of the first associated WiFi interface
+/// without one, in the `AA:BB:CC:DD:EE:FF` form: what the venue sees, for
+/// portals configured without `mac_address`
+pub fn get_interface_mac(interface: Option<&str>) -> Option<String> {
+    let interface = match interface {
+        Some(interface) => interface.to_string(),
+        None => active_wifi().ok()?.into_iter().next()?.0,
+    };
+    if cfg!(windows) {
+        return windows::mac(&interface);
+    }
+    if cfg!(any(target_os = "freebsd", target_os = "macos")) {
+        let output = Command::new("ifconfig").arg(&interface).output().ok()?;
+        return parse_ifconfig_ether(&String::from_utf8_lossy(&output.stdout));
+    }
+    let address = std::fs::read_to_string(format!("/sys/class/net/{}/address", interface));
+    normalize_mac(&address.ok()?).filter(|mac| mac != "00:00:00:00:00:00")
+}
+
+/// The `ether 02:00:00:aa:bb:01` line of `ifconfig <interface>`
+pub(crate) fn parse_ifconfig_ether(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        words.find(|w| *w == "ether")?;
+        normalize_mac(words.next()?)
+    })
+}
+
+/// `(address, MAC)` of the devices `interface` has seen on the LAN, from
+/// the ARP / neighbor table
+pub fn neighbors(interface: &str) -> Vec<(Ipv4Addr, String)> {
+    let output = if cfg!(target_os = "freebsd") {
+        Command::new("arp").args(["-an", "-i", interface]).output()
+    } else {
+        Command::new("ip")
+            .args(["-4", "neigh", "show", "dev", interface])
+            .output()
+    };
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_neighbors(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Neighbors in `ip neigh` (`192.168.1.23 lladdr aa:bb:... REACHABLE`) or
+/// `arp -an` (`? (192.168.1.23) at aa:bb:... on wlan0 ...`) output;
+/// incomplete and failed entries have no MAC and are left out
+pub(crate) fn parse_neighbors(output: &str) -> Vec<(Ipv4Addr, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let addr = words
+                .iter()
+                .find_map(|w| w.trim_matches(|c| c == '(' || c == ')').parse().ok())?;
+            let at = words.iter().position(|w| *w == "lladdr" || *w == "at")?;
+            Some((addr, normalize_mac(words.get(at + 1)?)?))
+        })
+        .collect()
+}
+
+/// The system calls above, for async code
+///
+/// The plain versions block the thread they run on; on the runtime that
+/// stalls every timer, socket and notification behind a slow nmcli or
+/// curl. The checks every daemon pass makes run their programs through
+/// tokio's process API here (killed if the call is cancelled), the rare
+/// and FreeBSD ones on the blocking pool.
+pub mod nonblocking {
+    use super::*;
+    use std::process::Output;
+
+    async fn output(program: &str, args: &[&str]) -> std::io::Result<Output> {
+        tokio::process::Command::new(program)
+            .args(args)
+            .kill_on_drop(true)
+            .output()
+            .await
+    }
+
+    /// Run the blocking `f` on tokio's blocking pool
+    pub async fn offload<T, F>(f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        match tokio::task::spawn_blocking(f).await {
+            Ok(value) => value,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// `utils::active_wifi`
+    pub async fn active_wifi() -> Result<Vec<(String, String)>> {
+        if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+            return offload(super::active_wifi).await;
+        }
+        match offload(crate::dbus::active_wifi).await {
+            Ok(active) => return Ok(active),
+            Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+        }
+        let output = output("nmcli", NMCLI_ACTIVE_ARGS).await?;
+        Ok(parse_nmcli_active(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// `utils::is_connected_to_wifi`
+    pub async fn is_connected_to_wifi(target_ssids: &[String]) -> Result<Option<String>> {
+        Ok(active_wifi()
+            .await?
+            .into_iter()
+            .map(|(_, ssid)| ssid)
+            .find(|ssid| target_ssids.contains(ssid)))
+    }
+
+    /// `utils::wifi_interface_for_ssid`
+    pub async fn wifi_interface_for_ssid(ssid: &str) -> Option<String> {
+        active_wifi()
+            .await
+            .ok()?
+            .into_iter()
+            .find(|(_, current)| current == ssid)
+            .map(|(interface, _)| interface)
+    }
+
+    /// `utils::get_interface_mac`
+    pub async fn get_interface_mac(interface: Option<&str>) -> Option<String> {
+        let interface = interface.map(str::to_string);
+        offload(move || super::get_interface_mac(interface.as_deref())).await
+    }
+
+    /// `utils::is_metered`
+    pub async fn is_metered(interface: &str) -> bool {
+        if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+            return false;
+        }
+        let device = interface.to_string();
+        match offload(move || crate::dbus::is_metered(&device)).await {
+            Ok(metered) => return metered,
+            Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+        }
+        output("nmcli", &metered_args(interface))
+            .await
+            .is_ok_and(|output| parse_nmcli_metered(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// `utils::capport_uri`
+    pub async fn capport_uri(interface: &str) -> Option<String> {
+        if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+            return None;
+        }
+        let output = output("nmcli", &dhcp_options_args(interface)).await.ok()?;
+        parse_nmcli_capport(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Whether the internet is reachable through `interface` only, asking
+    /// the check URLs of `[connectivity]` in process (`probe::online`)
+    pub async fn has_internet_connectivity_on(interface: Option<&str>) -> bool {
+        crate::probe::online(interface).await
+    }
+
+    /// Whether the internet is reachable at all
+    pub async fn has_internet_connectivity() -> bool {
+        has_internet_connectivity_on(None).await
+    }
+
+    /// `utils::default_gateway`
+    pub async fn default_gateway(interface: Option<&str>) -> Option<IpAddr> {
+        if cfg!(target_os = "freebsd") {
+            let interface = interface.map(str::to_string);
+            return offload(move || super::default_gateway(interface.as_deref())).await;
+        }
+        let output = output("ip", &gateway_args(interface)).await.ok()?;
+        parse_default_gateway(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// `utils::default_route_interface`
+    pub async fn default_route_interface() -> Option<String> {
+        if cfg!(target_os = "freebsd") {
+            return offload(super::default_route_interface).await;
+        }
+        let output = output("ip", DEFAULT_ROUTE_ARGS).await.ok()?;
+        parse_default_routes(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// `utils::interface_addresses`
+    pub async fn interface_addresses(interface: &str) -> Option<Vec<Ipv4Addr>> {
+        let (program, args) = addresses_command(interface);
+        let output = output(program, &args).await.ok().filter(|o| o.status.success())?;
+        Some(parse_inet_addrs(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// `utils::interface_address_problem`
+    pub async fn interface_address_problem(interface: &str) -> Option<String> {
+        let addrs = interface_addresses(interface).await?;
+        let lease_expiry = if cfg!(target_os = "freebsd") {
+            None
+        } else {
+            let output = output("nmcli", &lease_args(interface)).await.ok();
+            output.and_then(|o| parse_lease_expiry(&String::from_utf8_lossy(&o.stdout)))
+        };
+        address_problem(&addrs, lease_expiry, crate::state::unix_now())
+    }
+
+    /// `utils::neighbors`
+    pub async fn neighbors(interface: &str) -> Vec<(Ipv4Addr, String)> {
+        let interface = interface.to_string();
+        offload(move || super::neighbors(&interface)).await
+    }
+
+    /// `utils::active_bssid`
+    pub async fn active_bssid(interface: &str) -> Option<String> {
+        if cfg!(any(target_os = "freebsd", target_os = "macos", windows)) {
+            let interface = interface.to_string();
+            return offload(move || super::active_bssid(&interface)).await;
+        }
+        let device = interface.to_string();
+        match offload(move || crate::dbus::active_bssid(&device)).await {
+            Ok(bssid) => return bssid,
+            Err(e) => tracing::debug!("Asking nmcli, NetworkManager over D-Bus failed: {:#}", e),
+        }
+        let output = output("nmcli", &bssid_args(interface)).await.ok()?;
+        parse_nmcli_bssid(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// `utils::active_radio`
+    pub async fn active_radio(interface: &str) -> Option<Radio> {
+        let interface = interface.to_string();
+        offload(move || super::active_radio(&interface)).await
+    }
+
+    /// `utils::renew_dhcp`
+    pub async fn renew_dhcp(interface: &str) -> Result<()> {
+        let interface = interface.to_string();
+        offload(move || super::renew_dhcp(&interface)).await
+    }
+
+    /// `utils::bounce_interface`
+    pub async fn bounce_interface(interface: &str) -> Result<()> {
+        let interface = interface.to_string();
+        offload(move || super::bounce_interface(&interface)).await
+    }
+
+    /// `utils::set_cloned_mac`
+    pub async fn set_cloned_mac(ssid: &str, mac: &str) -> Result<()> {
+        let (ssid, mac) = (ssid.to_string(), mac.to_string());
+        offload(move || super::set_cloned_mac(&ssid, &mac)).await
+    }
+}
+
+/// FreeBSD WiFi backend: `wpa_cli` where wpa_supplicant runs, `ifconfig`
+/// otherwise, and `route` for the default gateway
+mod freebsd {
+    use anyhow::{Context, Result};
+    use std::net::IpAddr;
+    use std::process::Command;
+
+    /// `(interface, ssid)` of every associated wlan interface
+    pub fn associations() -> Result<Vec<(String, String)>> {
+        let output = Command::new("ifconfig")
+            .args(["-l"])
+            .output()
+            .context("Failed to run ifconfig")?;
+        let interfaces = String::from_utf8_lossy(&output.stdout).to_string();
+
+        Ok(interfaces
+            .split_whitespace()
+            .filter(|name| name.starts_with("wlan"))
+            .filter_map(|name| Some((name.to_string(), associated_ssid(name)?)))
+            .collect())
+    }
+
+    /// BSSID from `wpa_cli status`; `ifconfig` prints it too
+    pub fn bssid(interface: &str) -> Option<String> {
+        let wpa = Command::new("wpa_cli")
+            .args(["-i", interface, "status"])
+            .output()
+            .ok()
+            .filter(|o| o.status.success());
+        let status = match wpa {
+            Some(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+            None => {
+                let output = Command::new("ifconfig").arg(interface).output().ok()?;
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            }
+        };
+        parse_bssid(&status)
+    }
+
+    /// Band and channel from `ifconfig wlanN`, which has no link rate
+    pub fn radio(interface: &str) -> Option<super::Radio> {
+        let output = Command::new("ifconfig").arg(interface).output().ok()?;
+        parse_radio(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// `... channel 36 (5180 MHz 11a ht/40+) ...`, while associated
+    pub fn parse_radio(output: &str) -> Option<super::Radio> {
+        if !output.contains("status: associated") {
+            return None;
+        }
+        let mut words = output.split_whitespace();
+        words.find(|w| *w == "channel")?;
+        let channel: u32 = words.next()?.parse().ok()?;
+        let mhz = words.next().and_then(|w| w.strip_prefix('(')?.parse().ok());
+        mhz.and_then(|mhz| super::Radio::from_frequency(mhz, None))
+            .or_else(|| super::Radio::from_channel(channel, None, None))
+    }
+
+    /// `bssid=02:00:...` (wpa_cli) or `... bssid 02:00:... ` (ifconfig)
+    pub fn parse_bssid(output: &str) -> Option<String> {
+        let mut words = output.split(|c: char| c.is_whitespace() || c == '=');
+        words.find(|w| *w == "bssid")?;
+        super::normalize_mac(words.next()?)
+    }
+
+    fn associated_ssid(interface: &str) -> Option<String> {
+        let wpa = Command::new("wpa_cli")
+            .args(["-i", interface, "status"])
+            .output()
+            .ok()
+            .filter(|o| o.status.success());
+        if let Some(output) = wpa {
+            return parse_wpa_status(&String::from_utf8_lossy(&output.stdout));
+        }
+
+        let output = Command::new("ifconfig").arg(interface).output().ok()?;
+        parse_ifconfig(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// SSID from `wpa_cli status`, if the supplicant completed association
+    pub fn parse_wpa_status(status: &str) -> Option<String> {
+        let field = |key: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        };
+        (field("wpa_state")? == "COMPLETED")
+            .then(|| field("ssid"))
+            .flatten()
+            .map(str::to_string)
+    }
+
+    /// SSID from `ifconfig wlanN`, if the interface is associated
+    ///
+    /// SSIDs with spaces are printed quoted:
+    /// `ssid "Free Wi-MESH" channel 6 (2437 MHz 11g) bssid ...`
+    pub fn parse_ifconfig(output: &str) -> Option<String> {
+        if !output.contains("status: associated") {
+            return None;
+        }
+        let line = output
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with("ssid "))?;
+        let rest = &line["ssid ".len()..];
+        let ssid = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next()?,
+            None => rest.split_whitespace().next()?,
+        };
+        Some(ssid.to_string())
+    }
+
+    pub fn default_route_interface() -> Option<String> {
+        let output = Command::new("route")
+            .args(["-n", "get", "default"])
+            .output()
+            .ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("interface:"))
+            .map(|interface| interface.trim().to_string())
+    }
+
+    pub fn default_gateway(interface: Option<&str>) -> Option<IpAddr> {
+        let output = Command::new("route")
+            .args(["-n", "get", "default"])
+            .output()
+            .ok()?;
+        parse_route_get(&String::from_utf8_lossy(&output.stdout), interface)
+    }
+
+    /// Gateway from `route -n get default`, if it leaves through `interface`
+    pub fn parse_route_get(output: &str, interface: Option<&str>) -> Option<IpAddr> {
+        let field = |key: &str| {
+            output
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+        if let Some(interface) = interface {
+            if field("interface")? != interface {
+                return None;
+            }
+        }
+        field("gateway")?.parse().ok()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_wpa_status() {
+            let status = "bssid=02:00:00:aa:bb:01\nssid=1.Free Wi-MESH\nwpa_state=COMPLETED\n";
+            assert_eq!(parse_wpa_status(status).as_deref(), Some("1.Free Wi-MESH"));
+            let scanning = "wpa_state=SCANNING\n";
+            assert_eq!(parse_wpa_status(scanning), None);
+            assert_eq!(parse_bssid(status).as_deref(), Some("02:00:00:AA:BB:01"));
+        }
+
+        #[test]
+        fn test_parse_ifconfig() {
+            let output = concat!(
+                "wlan0: flags=8843<UP,BROADCAST,RUNNING,SIMPLEX,MULTICAST> metric 0 mtu 1500\n",
+                "\tinet 10.20.30.41 netmask 0xffffff00 broadcast 10.20.30.255\n",
+                "\tssid \"1.Free Wi-MESH\" channel 6 (2437 MHz 11g) bssid 02:00:00:aa:bb:01\n",
+                "\tstatus: associated\n",
+            );
+            assert_eq!(parse_ifconfig(output).as_deref(), Some("1.Free Wi-MESH"));
+            assert_eq!(parse_bssid(output).as_deref(), Some("02:00:00:AA:BB:01"));
+            assert_eq!(
+                parse_ifconfig("\tssid Cafe channel 1\n\tstatus: associated\n").as_deref(),
+                Some("Cafe")
+            );
+            assert_eq!(parse_ifconfig("\tssid Cafe channel 1\n\tstatus: no carrier\n"), None);
+            let radio = parse_radio(output).unwrap();
+            assert_eq!((radio.band, radio.channel), (super::super::Band::Ghz24, 6));
+        }
+
+        #[test]
+        fn test_parse_route_get() {
+            let output = concat!(
+                "   route to: default\n",
+                "destination: default\n",
+                "    gateway: 10.20.30.1\n",
+                "  interface: wlan0\n",
+            );
+            let gateway = "10.20.30.1".parse().ok();
+            assert_eq!(parse_route_get(output, None), gateway);
+            assert_eq!(parse_route_get(output, Some("wlan0")), gateway);
+            assert_eq!(parse_route_get(output, Some("em0")), None);
+        }
+    }
+}
+
+/// macOS WiFi backend: `airport -I` for the SSID and BSSID where the tool
+/// is still there (it went away in macOS 14.4), `networksetup` otherwise.
+/// CoreWLAN would need Objective-C bindings; these ship with every Mac.
+mod macos {
+    use anyhow::{Context, Result};
+    use std::process::Command;
+
+    const AIRPORT: &str = "/System/Library/PrivateFrameworks/Apple80211.framework/Versions/\
+                           Current/Resources/airport";
+
+    /// `(interface, ssid)` of the associated Wi-Fi interfaces
+    pub fn associations() -> Result<Vec<(String, String)>> {
+        let output = Command::new("networksetup")
+            .arg("-listallhardwareports")
+            .output()
+            .context("Failed to run networksetup")?;
+        let devices = parse_hardware_ports(&String::from_utf8_lossy(&output.stdout));
+        Ok(devices
+            .into_iter()
+            .filter_map(|device| Some((device.clone(), associated_ssid(&device)?)))
+            .collect())
+    }
+
+    /// BSSID from `airport -I`, of the primary Wi-Fi interface whichever
+    /// `interface` is; `networksetup` does not print it
+    pub fn bssid(interface: &str) -> Option<String> {
+        let _ = interface;
+        airport_field("BSSID").and_then(|bssid| padded_mac(&bssid))
+    }
+
+    /// Channel and last transmit rate from `airport -I`, of the primary
+    /// Wi-Fi interface
+    pub fn radio(interface: &str) -> Option<super::Radio> {
+        let _ = interface;
+        let output = Command::new(AIRPORT).arg("-I").output().ok()?;
+        parse_airport_radio(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// `channel: 36,80` (the primary channel, then the width) and
+    /// `lastTxRate: 866`
+    pub fn parse_airport_radio(output: &str) -> Option<super::Radio> {
+        let channel = parse_airport_info(output, "channel")?;
+        let channel = channel.split(',').next()?.trim().parse().ok()?;
+        let rate = parse_airport_info(output, "lastTxRate")
+            .and_then(|rate| rate.parse().ok())
+            .filter(|&rate| rate > 0);
+        super::Radio::from_channel(channel, None, rate)
+    }
+
+    fn associated_ssid(device: &str) -> Option<String> {
+        if let Some(ssid) = airport_field("SSID") {
+            return Some(ssid);
+        }
+        let output = Command::new("networksetup")
+            .args(["-getairportnetwork", device])
+            .output()
+            .ok()?;
+        parse_airport_network(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// A field of `airport -I`, which only knows the primary interface
+    fn airport_field(key: &str) -> Option<String> {
+        let output = Command::new(AIRPORT).arg("-I").output().ok()?;
+        parse_airport_info(&String::from_utf8_lossy(&output.stdout), key)
+    }
+
+    /// Devices of the `Wi-Fi` (`AirPort` before 10.7) hardware ports:
+    /// `Hardware Port: Wi-Fi` followed by `Device: en0`
+    pub fn parse_hardware_ports(output: &str) -> Vec<String> {
+        let mut devices = Vec::new();
+        let mut wifi = false;
+        for line in output.lines() {
+            if let Some(port) = line.strip_prefix("Hardware Port:") {
+                wifi = matches!(port.trim(), "Wi-Fi" | "AirPort");
+            } else if let Some(device) = line.strip_prefix("Device:") {
+                if wifi {
+                    devices.push(device.trim().to_string());
+                }
+                wifi = false;
+            }
+        }
+        devices
+    }
+
+    /// `key` of `airport -I` output (`           SSID: Free Wi-MESH`), while
+    /// associated
+    pub fn parse_airport_info(output: &str, key: &str) -> Option<String> {
+        let field = |key: &str| {
+            output.lines().find_map(|line| {
+                let (k, value) = line.split_once(':')?;
+                (k.trim() == key).then(|| value.trim().to_string())
+            })
+        };
+        if field("state")? != "running" {
+            return None;
+        }
+        field(key).filter(|value| !value.is_empty())
+    }
+
+    /// SSID of `Current Wi-Fi Network: Free Wi-MESH`; the other answer is
+    /// "You are not associated with an AirPort network."
+    pub fn parse_airport_network(output: &str) -> Option<String> {
+        let ssid = output.lines().find_map(|line| line.strip_prefix("Current Wi-Fi Network:"))?;
+        Some(ssid.trim().to_string()).filter(|ssid| !ssid.is_empty())
+    }
+
+    /// `airport` drops leading zeros of the octets (`2:0:0:aa:bb:1`)
+    fn padded_mac(mac: &str) -> Option<String> {
+        let octets: Vec<String> = mac.split(':').map(|octet| format!("{:0>2}", octet)).collect();
+        super::normalize_mac(&octets.join(":"))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_macos_wifi() {
+            let ports = concat!(
+                "\nHardware Port: Ethernet\nDevice: en1\nEthernet Address: 02:00:00:00:00:02\n",
+                "\nHardware Port: Wi-Fi\nDevice: en0\nEthernet Address: 02:00:00:00:00:01\n",
+            );
+            assert_eq!(parse_hardware_ports(ports), ["en0"]);
+
+            let info = concat!(
+                "     agrCtlRSSI: -55\n",
+                "          state: running\n",
+                "        op mode: station \n",
+                "          BSSID: 2:0:0:aa:bb:1\n",
+                "           SSID: 1.Free Wi-MESH\n",
+            );
+            assert_eq!(parse_airport_info(info, "SSID").as_deref(), Some("1.Free Wi-MESH"));
+            let bssid = parse_airport_info(info, "BSSID").and_then(|b| padded_mac(&b));
+            assert_eq!(bssid.as_deref(), Some("02:00:00:AA:BB:01"));
+            assert_eq!(parse_airport_info("AirPort: Off\n", "SSID"), None);
+            let radio = format!("{}    lastTxRate: 400\n  channel: 149,80\n", info);
+            let radio = parse_airport_radio(&radio);
+            assert_eq!(radio.map(|r| r.describe()).as_deref(), Some("5GHz ch 149, 400 Mbit/s"));
+
+            let network = "Current Wi-Fi Network: Cafe 5G\n";
+            assert_eq!(parse_airport_network(network).as_deref(), Some("Cafe 5G"));
+            let none = "You are not associated with an AirPort network.\n";
+            assert_eq!(parse_airport_network(none), None);
+        }
+    }
+}
+
+/// Windows WiFi backend: `netsh wlan show interfaces`, which every
+/// Windows 10/11 install has, instead of binding WlanAPI
+mod windows {
+    use anyhow::{Context, Result};
+    use std::process::Command;
+
+    /// Flag of `CreateProcess` that keeps `netsh` from flashing a console
+    /// window when wimesh runs without one (a scheduled task, say)
+    #[cfg(windows)]
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    /// A connected interface of `netsh wlan show interfaces`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Interface {
+        pub name: String,
+        /// The interface's own MAC address
+        pub mac: Option<String>,
+        pub ssid: String,
+        pub bssid: Option<String>,
+        pub channel: Option<u32>,
+        pub band: Option<super::Band>,
+        pub rate_mbps: Option<u32>,
+    }
+
+    fn show_interfaces() -> Result<String> {
+        let mut command = Command::new("netsh");
+        command.args(["wlan", "show", "interfaces"]);
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        let output = command.output().context("Failed to run netsh")?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// `(interface, ssid)` of every connected WiFi interface
+    pub fn associations() -> Result<Vec<(String, String)>> {
+        Ok(parse_interfaces(&show_interfaces()?)
+            .into_iter()
+            .map(|interface| (interface.name, interface.ssid))
+            .collect())
+    }
+
+    /// BSSID of `interface`, if it is connected
+    pub fn bssid(interface: &str) -> Option<String> {
+        parse_interfaces(&show_interfaces().ok()?)
+            .into_iter()
+            .find(|i| i.name == interface)?
+            .bssid
+    }
+
+    /// MAC address of `interface`, if it is connected
+    pub fn mac(interface: &str) -> Option<String> {
+        parse_interfaces(&show_interfaces().ok()?)
+            .into_iter()
+            .find(|i| i.name == interface)?
+            .mac
+    }
+
+    /// Channel, band and receive rate of `interface`, if it is connected
+    pub fn radio(interface: &str) -> Option<super::Radio> {
+        let interface = parse_interfaces(&show_interfaces().ok()?)
+            .into_iter()
+            .find(|i| i.name == interface)?;
+        super::Radio::from_channel(interface.channel?, interface.band, interface.rate_mbps)
+    }
+
+    /// The connected interfaces in `netsh wlan show interfaces` output: a
+    /// block of `Key : value` lines per interface, starting with `Name`.
+    /// Windows 11 calls the BSSID `AP BSSID`. The keys and states are the
+    /// English ones; other display languages translate them.
+    pub fn parse_interfaces(output: &str) -> Vec<Interface> {
+        // Each with whether it is connected
+        let mut interfaces: Vec<(Interface, bool)> = Vec::new();
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            if key.trim() == "Name" {
+                let interface = Interface {
+                    name: value,
+                    mac: None,
+                    ssid: String::new(),
+                    bssid: None,
+                    channel: None,
+                    band: None,
+                    rate_mbps: None,
+                };
+                interfaces.push((interface, false));
+                continue;
+            }
+            let Some((interface, connected)) = interfaces.last_mut() else {
+                continue;
+            };
+            match key.trim() {
+                "State" => *connected = value.eq_ignore_ascii_case("connected"),
+                "Physical address" => interface.mac = super::normalize_mac(&value),
+                "SSID" => interface.ssid = value,
+                "BSSID" | "AP BSSID" => interface.bssid = super::normalize_mac(&value),
+                "Channel" => interface.channel = value.parse().ok(),
+                // Windows 11: `5 GHz`
+                "Band" => {
+                    interface.band = match value.split_whitespace().next() {
+                        Some("2.4") => Some(super::Band::Ghz24),
+                        Some("5") => Some(super::Band::Ghz5),
+                        Some("6") => Some(super::Band::Ghz6),
+                        _ => None,
+                    }
+                }
+                "Receive rate (Mbps)" => {
+                    interface.rate_mbps = value.parse::<f64>().ok().map(|rate| rate as u32)
+                }
+                _ => {}
+            }
+        }
+        interfaces
+            .into_iter()
+            .filter(|(interface, connected)| *connected && !interface.ssid.is_empty())
+            .map(|(interface, _)| interface)
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse_netsh_interfaces() {
+            let output = concat!(
+                "\r\nThere are 2 interfaces on the system:\r\n\r\n",
+                "    Name                   : Wi-Fi\r\n",
+                "    Description            : Intel(R) Wi-Fi 6 AX201 160MHz\r\n",
+                "    Physical address       : 02:00:00:00:00:01\r\n",
+                "    State                  : connected\r\n",
+                "    SSID                   : Cafe: 5G\r\n",
+                "    AP BSSID               : 02:00:00:aa:bb:01\r\n",
+                "    Network type           : Infrastructure\r\n",
+                "    Band                   : 5 GHz\r\n",
+                "    Channel                : 36\r\n",
+                "    Receive rate (Mbps)    : 866.7\r\n",
+                "\r\n",
+                "    Name                   : Wi-Fi 2\r\n",
+                "    State                  : disconnected\r\n",
+            );
+            assert_eq!(
+                parse_interfaces(output),
+                [Interface {
+                    name: "Wi-Fi".to_string(),
+                    mac: Some("02:00:00:00:00:01".to_string()),
+                    ssid: "Cafe: 5G".to_string(),
+                    bssid: Some("02:00:00:AA:BB:01".to_string()),
+                    channel: Some(36),
+                    band: Some(super::super::Band::Ghz5),
+                    rate_mbps: Some(866),
+                }]
+            );
+            assert!(parse_interfaces("There is no wireless interface on the system.").is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nmcli_active_lists_every_adapter() {
+        let stdout = concat!(
+            "no:wlan0:Other\n",
+            "yes:wlan0:Free Wi-MESH\n",
+            "yes:wlan1:Cafe\\:5G\n",
+            "yes:wlan1:Cafe\\:5G\n",
+        );
+        assert_eq!(
+            parse_nmcli_active(stdout),
+            [
+                ("wlan0".to_string(), "Free Wi-MESH".to_string()),
+                ("wlan1".to_string(), "Cafe:5G".to_string()),
+            ]
+        );
+
+        let stdout = "no:02\\:00\\:00\\:AA\\:BB\\:02\nyes:02\\:00\\:00\\:aa\\:bb\\:01\n";
+        assert_eq!(parse_nmcli_bssid(stdout).as_deref(), Some("02:00:00:AA:BB:01"));
+
+        assert!(parse_nmcli_metered("GENERAL.METERED:yes (guessed)\n"));
+        assert!(!parse_nmcli_metered("GENERAL.METERED:no (guessed)\n"));
+        assert!(!parse_nmcli_metered(""));
+
+        let stdout = concat!(
+            "DHCP4.OPTION[1]:broadcast_address = 10.20.31.255\n",
+            "DHCP4.OPTION[2]:captive_portal = https\\://portal.example/api\n",
+        );
+        assert_eq!(parse_nmcli_capport(stdout).as_deref(), Some("https://portal.example/api"));
+        assert_eq!(parse_nmcli_capport("DHCP4.OPTION[1]:domain_name = lan\n"), None);
+    }
+
+    #[test]
+    fn test_radio() {
+        let radio = Radio::from_frequency(5180, Some(866)).unwrap();
+        assert_eq!(radio.describe(), "5GHz ch 36, 866 Mbit/s");
+        assert_eq!(Radio::from_frequency(2484, None).unwrap().channel, 14);
+        assert_eq!(Radio::from_frequency(5975, None).unwrap().band, Band::Ghz6);
+        assert_eq!(Radio::from_frequency(60480, None), None);
+        assert_eq!(
+            serde_json::to_string(&Radio::from_frequency(2437, None)).unwrap(),
+            r#"{"band":"2.4GHz","channel":6}"#
+        );
+
+        let stdout = "no:1:2412 MHz:54 Mbit/s\nyes:149:5745 MHz:270 Mbit/s\n";
+        assert_eq!(
+            parse_nmcli_radio(stdout).map(|r| r.describe()).as_deref(),
+            Some("5GHz ch 149, 270 Mbit/s")
+        );
+        assert_eq!(parse_nmcli_radio("no:1:2412 MHz:54 Mbit/s\n"), None);
+    }
+
+    #[test]
+    fn test_address_problem() {
+        let ip = concat!(
+            "3: wlan0    inet 169.254.7.9/16 brd 169.254.255.255 scope link wlan0\\",
+            "       valid_lft forever preferred_lft forever\n",
+        );
+        let link_local = parse_inet_addrs(ip);
+        assert_eq!(link_local, ["169.254.7.9".parse::<Ipv4Addr>().unwrap()]);
+        let ifconfig = "\tinet 10.20.30.41 netmask 0xffffff00 broadcast 10.20.30.255\n";
+        let addrs = parse_inet_addrs(ifconfig);
+        assert_eq!(addrs, ["10.20.30.41".parse::<Ipv4Addr>().unwrap()]);
+
+        let now = 1_760_000_000;
+        assert!(address_problem(&[], None, now).unwrap().contains("no IPv4 address"));
+        assert!(address_problem(&link_local, None, now).unwrap().contains("link-local"));
+        assert!(address_problem(&[Ipv4Addr::UNSPECIFIED], None, now).is_some());
+        assert_eq!(address_problem(&addrs, Some(now + 600), now), None);
+        assert_eq!(address_problem(&addrs, None, now), None);
+
+        let nmcli = "DHCP4.OPTION[3]:dhcp_lease_time = 3600\nDHCP4.OPTION[4]:expiry = 1759999000\n";
+        let expiry = parse_lease_expiry(nmcli);
+        assert_eq!(expiry, Some(1_759_999_000));
+        assert_eq!(
+            address_problem(&addrs, expiry, now).as_deref(),
+            Some("the DHCP lease expired 1000s ago")
+        );
+    }
+
+    #[test]
+    fn test_parse_neighbors() {
+        let ip = concat!(
+            "10.20.30.1 lladdr 02:00:00:00:00:01 REACHABLE\n",
+            "10.20.30.57 lladdr 02:00:00:aa:bb:57 STALE\n",
+            "10.20.30.60 FAILED\n",
+        );
+        assert_eq!(
+            parse_neighbors(ip),
+            vec![
+                (Ipv4Addr::new(10, 20, 30, 1), "02:00:00:00:00:01".to_string()),
+                (Ipv4Addr::new(10, 20, 30, 57), "02:00:00:AA:BB:57".to_string()),
+            ]
+        );
+        let arp = concat!(
+            "? (10.20.30.57) at 02:00:00:aa:bb:57 on wlan0 expires in 1191 seconds [ethernet]\n",
+            "? (10.20.30.60) at (incomplete) on wlan0 expired [ethernet]\n",
+        );
+        assert_eq!(
+            parse_neighbors(arp),
+            vec![(Ipv4Addr::new(10, 20, 30, 57), "02:00:00:AA:BB:57".to_string())]
+        );
+        assert_eq!(normalize_mac("02-00-00-aa-bb-57").as_deref(), Some("02:00:00:AA:BB:57"));
+        assert_eq!(normalize_mac("0200.00aa.bb57").as_deref(), Some("02:00:00:AA:BB:57"));
+        assert_eq!(normalize_mac("02:00:00:aa:bb"), None);
+
+        let ifconfig = concat!(
+            "en0: flags=8863<UP,BROADCAST,SMART,RUNNING,SIMPLEX,MULTICAST> mtu 1500\n",
+            "\tether 02:00:00:aa:bb:01\n",
+            "\tinet 10.20.30.41 netmask 0xffffff00 broadcast 10.20.30.255\n",
+        );
+        assert_eq!(parse_ifconfig_ether(ifconfig).as_deref(), Some("02:00:00:AA:BB:01"));
+        assert_eq!(parse_ifconfig_ether("lo0: flags=8049<UP,LOOPBACK>\n"), None);
+    }
+
+    #[test]
+    fn test_route_problem() {
+        let routes = concat!(
+            "default via 192.168.42.129 dev usb0 proto dhcp metric 100\n",
+            "default via 10.20.30.1 dev wlan0 proto dhcp metric 600\n",
+        );
+        let route = parse_default_routes(routes);
+        assert_eq!(route.as_deref(), Some("usb0"));
+        assert_eq!(parse_default_routes("default dev wg0 scope link\n").as_deref(), Some("wg0"));
+
+        let hint = route_problem("wlan0", route.as_deref(), &["wlan0"]).unwrap();
+        assert!(hint.contains("goes through usb0"), "{}", hint);
+        assert!(route_problem("wlan0", None, &["wlan0"]).is_some());
+        // Another logged-in WiFi adapter carrying the traffic is fine
+        assert_eq!(route_problem("wlan1", Some("wlan0"), &["wlan0", "wlan1"]), None);
+        assert_eq!(route_problem("wlan0", Some("wlan0"), &[]), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_local_utc_offset() {
+        let offset = local_utc_offset().unwrap();
+        assert!(offset.abs() <= 14 * 3600, "{}", offset);
+        assert_eq!(utc_offset(), offset);
+    }
+}
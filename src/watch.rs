@@ -0,0 +1,98 @@
+//! Waking the daemon when the network changes
+//!
+//! Between checks the daemon sleeps `check_interval`, so a fresh
+//! association waits for the next check. `NetworkWatch` listens to
+//! NetworkManager's signals on the system bus (`dbus::watch`) on a thread
+//! of its own, and wakes the daemon on each a moment later, so a burst of
+//! them (associated, address, connectivity) makes one check. The interval
+//! stays as the fallback: without a system bus or NetworkManager the daemon
+//! polls as before, trying the bus again now and then.
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How long after a signal the daemon checks, for the ones following it
+pub const SETTLE: Duration = Duration::from_millis(300);
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// NetworkManager's signals, as wake-ups of the daemon
+pub struct NetworkWatch {
+    notify: Arc<Notify>,
+}
+
+impl NetworkWatch {
+    /// Watch on a thread that ends with this; NetworkManager only runs on
+    /// Linux, so elsewhere this never wakes anyone
+    pub fn start() -> Self {
+        let notify = Arc::new(Notify::new());
+        if cfg!(target_os = "linux") {
+            let weak = Arc::downgrade(&notify);
+            let spawned = std::thread::Builder::new()
+                .name("wimesh-watch".to_string())
+                .spawn(move || run(weak));
+            if let Err(e) = spawned {
+                tracing::warn!("Not watching NetworkManager, no thread for it: {}", e);
+            }
+        }
+        Self { notify }
+    }
+
+    /// Wait for NetworkManager to report a change, and `SETTLE`
+    pub async fn changed(&self) {
+        self.notify.notified().await;
+        tokio::time::sleep(SETTLE).await;
+    }
+}
+
+fn run(notify: Weak<Notify>) {
+    let mut failures = 0;
+    loop {
+        let mut delivered = false;
+        let result = crate::dbus::watch(|| match notify.upgrade() {
+            Some(notify) => {
+                notify.notify_one();
+                delivered = true;
+                true
+            }
+            None => false,
+        });
+        let Err(e) = result else {
+            return;
+        };
+        if delivered {
+            failures = 0;
+        }
+        if failures == 0 {
+            tracing::info!("Not watching NetworkManager, polling only: {:#}", e);
+        } else {
+            tracing::debug!("Watching NetworkManager failed again: {:#}", e);
+        }
+        failures += 1;
+        std::thread::sleep(retry_delay(failures));
+        if notify.strong_count() == 0 {
+            return;
+        }
+    }
+}
+
+/// Wait before watching again after `failures` failures in a row
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_MIN
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(RETRY_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), RETRY_MIN);
+        assert_eq!(retry_delay(2), RETRY_MIN * 2);
+        assert_eq!(retry_delay(7), RETRY_MAX);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX);
+    }
+}
@@ -0,0 +1,273 @@
+//! Running as a Windows service (`wimesh service install`)
+//!
+//! `wimesh service install` registers the daemon with the service control
+//! manager as `wimesh`, started at boot and restarted when it fails, so it
+//! runs in the background without a console window; `uninstall`, `start`
+//! and `stop` do what they say, through `sc.exe`. The service runs `wimesh
+//! --config <file> service run`, which hands the process to the service
+//! control manager and runs the daemon until a stop or shutdown arrives,
+//! seen by `tasks::stop_signal`. A service has no console, so its log is
+//! `[logging] log_file`'s. On other platforms these refuse, pointing at the
+//! platform's own service definitions.
+
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::Path;
+use std::process::Command;
+use tokio::sync::Notify;
+
+/// What the service is registered as
+pub const NAME: &str = "wimesh";
+const DISPLAY_NAME: &str = "Wimesh Auto-Login";
+const DESCRIPTION: &str = "Keeps captive portal WiFi logged in";
+
+/// Set when the service control manager asks the service to stop
+static STOP: Notify = Notify::const_new();
+
+/// Wait until the service control manager asks the service to stop; never,
+/// outside a service
+pub async fn stop_requested() {
+    STOP.notified().await
+}
+
+/// Register the service, running `binary` with the config at `config`
+pub fn install(binary: &Path, config: &Path) -> Result<()> {
+    windows_only()?;
+    let command = format!(
+        "\"{}\" --config \"{}\" service run",
+        binary.display(),
+        config.display()
+    );
+    sc(&[
+        "create",
+        NAME,
+        "binPath=",
+        &command,
+        "start=",
+        "auto",
+        "DisplayName=",
+        DISPLAY_NAME,
+    ])?;
+    sc(&["description", NAME, DESCRIPTION])?;
+    // Restarted 10s after it crashes or exits with an error, the count
+    // starting over after a day
+    sc(&[
+        "failure",
+        NAME,
+        "reset=",
+        "86400",
+        "actions=",
+        "restart/10000/restart/10000/restart/60000",
+    ])?;
+    sc(&["failureflag", NAME, "1"])
+}
+
+/// Stop the service if it runs, and remove it
+pub fn uninstall() -> Result<()> {
+    windows_only()?;
+    let _ = sc(&["stop", NAME]);
+    sc(&["delete", NAME])
+}
+
+pub fn start() -> Result<()> {
+    windows_only()?;
+    sc(&["start", NAME])
+}
+
+pub fn stop() -> Result<()> {
+    windows_only()?;
+    sc(&["stop", NAME])
+}
+
+/// An error unless this is Windows
+pub fn windows_only() -> Result<()> {
+    if !cfg!(windows) {
+        anyhow::bail!(
+            "Windows services are for Windows; `wimesh service` prints this platform's service \
+             definition"
+        );
+    }
+    Ok(())
+}
+
+fn sc(args: &[&str]) -> Result<()> {
+    let output = Command::new("sc.exe")
+        .args(args)
+        .output()
+        .context("Failed to run sc.exe")?;
+    if !output.status.success() {
+        // sc.exe explains on stdout
+        anyhow::bail!(
+            "sc.exe {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Run `daemon` as the service, reporting to the service control manager
+/// until it returns
+pub async fn run<F>(daemon: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    platform::run(daemon).await
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{NAME, STOP};
+    use anyhow::{Context, Result};
+    use std::ffi::c_void;
+    use std::future::Future;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+    use std::sync::{Condvar, Mutex};
+    use tokio::sync::oneshot;
+    use windows_sys::core::PWSTR;
+    use windows_sys::Win32::System::Services::{
+        RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_STATUS,
+        SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    const NO_ERROR: u32 = 0;
+    const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+    const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+
+    /// Told once the service control manager has started the service
+    static STARTED: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
+    /// Whether the daemon succeeded, once it returned
+    static DONE: (Mutex<Option<bool>>, Condvar) = (Mutex::new(None), Condvar::new());
+    /// Where the service's status is reported
+    static HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+    pub async fn run<F>(daemon: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let (started, on_start) = oneshot::channel();
+        *STARTED.lock().unwrap_or_else(|e| e.into_inner()) = Some(started);
+        // The dispatcher holds its thread until the service has stopped
+        let mut dispatcher = tokio::task::spawn_blocking(dispatch);
+        tokio::select! {
+            _ = on_start => {}
+            dispatched = &mut dispatcher => {
+                dispatched??;
+                anyhow::bail!("The service control manager did not start the service");
+            }
+        }
+
+        let result = daemon.await;
+        let (done, finished) = &DONE;
+        *done.lock().unwrap_or_else(|e| e.into_inner()) = Some(result.is_ok());
+        finished.notify_all();
+        dispatcher.await??;
+        result
+    }
+
+    fn dispatch() -> Result<()> {
+        let mut name = wide(NAME);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: std::ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // SAFETY: the table is terminated by the null entry and outlives
+        // the call, which returns once the service has stopped
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Not started as a service; `wimesh service start` starts it");
+        }
+        Ok(())
+    }
+
+    /// The service's thread: reports it running, then stopped once the
+    /// daemon has returned
+    unsafe extern "system" fn service_main(_: u32, _: *mut PWSTR) {
+        let name = wide(NAME);
+        // SAFETY: `name` is NUL-terminated, and `handler` needs no context
+        let handle = unsafe {
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), std::ptr::null())
+        };
+        if handle.is_null() {
+            return;
+        }
+        HANDLE.store(handle, Ordering::Release);
+        report(SERVICE_RUNNING, true);
+        if let Some(started) = STARTED.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = started.send(());
+        }
+
+        let (done, finished) = &DONE;
+        let mut succeeded = done.lock().unwrap_or_else(|e| e.into_inner());
+        while succeeded.is_none() {
+            succeeded = finished.wait(succeeded).unwrap_or_else(|e| e.into_inner());
+        }
+        report(SERVICE_STOPPED, succeeded.unwrap_or_default());
+    }
+
+    unsafe extern "system" fn handler(control: u32, _: u32, _: *mut c_void, _: *mut c_void) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                report(SERVICE_STOP_PENDING, true);
+                STOP.notify_one();
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    fn report(state: u32, succeeded: bool) {
+        let handle = HANDLE.load(Ordering::Acquire);
+        if handle.is_null() {
+            return;
+        }
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: match state {
+                SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+                _ => 0,
+            },
+            dwWin32ExitCode: if succeeded {
+                NO_ERROR
+            } else {
+                ERROR_SERVICE_SPECIFIC_ERROR
+            },
+            dwServiceSpecificExitCode: u32::from(!succeeded),
+            dwCheckPoint: 0,
+            // Parts get `tasks::GRACE` each to stop
+            dwWaitHint: match state {
+                SERVICE_STOP_PENDING => 30_000,
+                _ => 0,
+            },
+        };
+        // SAFETY: `handle` came from RegisterServiceCtrlHandlerExW
+        unsafe { SetServiceStatus(handle, &status) };
+    }
+
+    fn wide(text: &str) -> Vec<u16> {
+        text.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use anyhow::Result;
+    use std::future::Future;
+
+    pub async fn run<F>(_: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        super::windows_only()
+    }
+}